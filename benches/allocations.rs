@@ -0,0 +1,70 @@
+//! Measures the peak heap usage while converting a synthetic Lua 5.1 chunk of
+//! roughly five megabytes with a lot of string constants.
+//!
+//! Run with `cargo bench --bench allocations`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use lunify::{unify, BitWidth, Format};
+
+mod common;
+
+use common::synthetic_chunk_with_strings;
+
+const STRING_COUNT: usize = 200_000;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocator that keeps track of the number of allocations and the peak number
+/// of allocated bytes.
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let pointer = System.alloc(layout);
+
+        if !pointer.is_null() {
+            let current = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(current, Ordering::Relaxed);
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+
+        pointer
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+        System.dealloc(pointer, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn main() {
+    let input_bytes = synthetic_chunk_with_strings(1024, STRING_COUNT);
+    let output_format = Format {
+        size_t_width: BitWidth::Bit32,
+        ..Default::default()
+    };
+    let settings = Default::default();
+
+    // Only measure the conversion itself, not the input.
+    let baseline = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(baseline, Ordering::Relaxed);
+    ALLOCATIONS.store(0, Ordering::Relaxed);
+
+    let output_bytes = unify(&input_bytes, &output_format, &settings).unwrap();
+
+    println!(
+        "convert {} KiB chunk: {} KiB peak, {} allocations",
+        input_bytes.len() / 1024,
+        (PEAK.load(Ordering::Relaxed) - baseline) / 1024,
+        ALLOCATIONS.load(Ordering::Relaxed)
+    );
+
+    drop(output_bytes);
+}
@@ -0,0 +1,253 @@
+//! Helpers for building synthetic Lua 5.0 and Lua 5.1 chunks.
+
+#![allow(dead_code)]
+
+pub fn integer(bytes: &mut Vec<u8>, value: i32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn size_t(bytes: &mut Vec<u8>, value: i64) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+/// A function without a source file, local variables or upvalue names.
+/// Every chunk built from it is little endian with a 64 bit `size_t`.
+pub struct SyntheticFunction<'a> {
+    /// The upvalue count, the parameter count, the variadic flag and the
+    /// maximum stack size.
+    pub header: [u8; 4],
+    pub instructions: &'a [i32],
+    /// Number constants, which come before the string constants.
+    pub numbers: &'a [f64],
+    /// String constants, including the terminating zero.
+    pub strings: &'a [String],
+    /// The bytes of the nested functions, encoded for the same version.
+    pub functions: &'a [Vec<u8>],
+    /// How many consecutive instructions share a line.
+    pub instructions_per_line: usize,
+}
+
+impl Default for SyntheticFunction<'_> {
+    fn default() -> Self {
+        Self {
+            header: [0, 0, 2, 2],
+            instructions: &[],
+            numbers: &[],
+            strings: &[],
+            functions: &[],
+            instructions_per_line: 1,
+        }
+    }
+}
+
+impl SyntheticFunction<'_> {
+    /// Encode the function like Lua 5.0 does.
+    pub fn lua50_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        size_t(&mut bytes, 0);
+        integer(&mut bytes, 0);
+        bytes.extend_from_slice(&self.header);
+        self.write_debug_information(&mut bytes);
+        self.write_constants(&mut bytes);
+        self.write_functions(&mut bytes);
+        self.write_instructions(&mut bytes);
+        bytes
+    }
+
+    /// Encode the function like Lua 5.1 does.
+    pub fn lua51_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        size_t(&mut bytes, 0);
+        integer(&mut bytes, 0);
+        integer(&mut bytes, 0);
+        bytes.extend_from_slice(&self.header);
+        self.write_instructions(&mut bytes);
+        self.write_constants(&mut bytes);
+        self.write_functions(&mut bytes);
+        self.write_debug_information(&mut bytes);
+        bytes
+    }
+
+    fn write_instructions(&self, bytes: &mut Vec<u8>) {
+        integer(bytes, self.instructions.len() as i32);
+        self.instructions.iter().for_each(|instruction| integer(bytes, *instruction));
+    }
+
+    fn write_constants(&self, bytes: &mut Vec<u8>) {
+        integer(bytes, (self.numbers.len() + self.strings.len()) as i32);
+
+        for number in self.numbers {
+            bytes.push(3);
+            bytes.extend_from_slice(&number.to_le_bytes());
+        }
+
+        for string in self.strings {
+            bytes.push(4);
+            size_t(bytes, string.len() as i64);
+            bytes.extend_from_slice(string.as_bytes());
+        }
+    }
+
+    fn write_functions(&self, bytes: &mut Vec<u8>) {
+        integer(bytes, self.functions.len() as i32);
+        self.functions.iter().for_each(|function| bytes.extend_from_slice(function));
+    }
+
+    /// Write the line info and empty lists of local variables and upvalue
+    /// names.
+    fn write_debug_information(&self, bytes: &mut Vec<u8>) {
+        integer(bytes, self.instructions.len() as i32);
+        for line in 0..self.instructions.len() {
+            integer(bytes, (line / self.instructions_per_line) as i32);
+        }
+        integer(bytes, 0);
+        integer(bytes, 0);
+    }
+}
+
+/// Build a Lua 5.0 chunk with the given main function.
+pub fn lua50_chunk(main_function: &SyntheticFunction) -> Vec<u8> {
+    let mut bytes = b"\x1bLua".to_vec();
+    bytes.extend_from_slice(&[0x50, 1, 4, 8, 4, 6, 8, 9, 9, 8]);
+    bytes.extend_from_slice(&31415926.535897933f64.to_le_bytes());
+    bytes.extend_from_slice(&main_function.lua50_bytes());
+    bytes
+}
+
+/// Build a Lua 5.1 chunk with the given main function.
+pub fn lua51_chunk(main_function: &SyntheticFunction) -> Vec<u8> {
+    let mut bytes = b"\x1bLua".to_vec();
+    bytes.extend_from_slice(&[0x51, 0, 1, 4, 8, 4, 8, 0]);
+    bytes.extend_from_slice(&main_function.lua51_bytes());
+    bytes
+}
+
+/// `count` distinct strings with the given prefix, each terminated by a zero.
+fn strings(prefix: &str, count: usize) -> Vec<String> {
+    (0..count).map(|index| format!("{prefix}string_{index:08}\0")).collect()
+}
+
+/// Build a little endian Lua 5.1 chunk with a 64 bit `size_t` that consists of
+/// a single function with `instruction_count` instructions.
+pub fn synthetic_chunk(instruction_count: usize) -> Vec<u8> {
+    synthetic_chunk_with_strings(instruction_count, 0)
+}
+
+/// Same as [`synthetic_chunk`], but the function also has `string_count` string
+/// constants.
+pub fn synthetic_chunk_with_strings(instruction_count: usize, string_count: usize) -> Vec<u8> {
+    // `LOADK 0 0` followed by a final `RETURN 0 1`
+    let mut instructions = vec![1; instruction_count - 1];
+    instructions.push(30 | (1 << 23));
+
+    lua51_chunk(&SyntheticFunction {
+        instructions: &instructions,
+        numbers: &[9.0],
+        strings: &strings("constant_", string_count),
+        instructions_per_line: 4,
+        ..Default::default()
+    })
+}
+
+/// Build a little endian Lua 5.0 chunk with a 64 bit `size_t` that consists of
+/// a single function with `loop_count` empty generic `for` loops and
+/// `string_count` string constants.
+pub fn synthetic_lua50_loops(loop_count: usize, string_count: usize) -> Vec<u8> {
+    // `TFORPREP 0 0`, `TFORLOOP 0 1` and `JMP -2` for every loop, followed by a
+    // final `RETURN 0 1`
+    let mut instructions = Vec::with_capacity(loop_count * 3 + 1);
+    for _ in 0..loop_count {
+        instructions.push(30 | (131071 << 6));
+        instructions.push(29 | (1 << 6));
+        instructions.push(20 | ((131071 - 2) << 6));
+    }
+    instructions.push(27 | (1 << 15));
+
+    lua50_chunk(&SyntheticFunction {
+        header: [0, 0, 0, 8],
+        instructions: &instructions,
+        strings: &strings("constant_", string_count),
+        instructions_per_line: 3,
+        ..Default::default()
+    })
+}
+
+/// Build a little endian Lua 5.1 chunk with a 64 bit `size_t` whose main
+/// function creates `prototype_count` nested functions. The first nested
+/// function fills a table with `table_size` elements, the others consist of
+/// `instruction_count` instructions.
+pub fn synthetic_chunk_with_prototypes(prototype_count: usize, instruction_count: usize, table_size: usize) -> Vec<u8> {
+    // `NEWTABLE 0 0 0` followed by `LOADK` instructions with a `SETLIST` after
+    // every 50 elements and a final `RETURN 0 1`
+    let mut table_instructions = vec![10];
+    for index in 0..table_size {
+        let register = index % 50 + 1;
+        table_instructions.push(1 | (register << 6) as i32);
+
+        if register == 50 || index + 1 == table_size {
+            table_instructions.push(34 | (register << 23) as i32 | ((index / 50 + 1) << 14) as i32);
+        }
+    }
+    table_instructions.push(30 | (1 << 23));
+
+    // `LOADK 0 0` instructions followed by a final `RETURN 0 1`
+    let mut instructions = vec![1; instruction_count - 1];
+    instructions.push(30 | (1 << 23));
+
+    let functions: Vec<Vec<u8>> = (0..prototype_count)
+        .map(|index| {
+            let (maximum_stack_size, instructions) = match index {
+                0 => (51, table_instructions.as_slice()),
+                _ => (2, instructions.as_slice()),
+            };
+
+            SyntheticFunction {
+                header: [0, 0, 2, maximum_stack_size],
+                instructions,
+                numbers: &[9.0],
+                instructions_per_line: 4,
+                ..Default::default()
+            }
+            .lua51_bytes()
+        })
+        .collect();
+
+    // `CLOSURE 0 index` for every nested function followed by a final `RETURN 0 1`
+    let mut main_instructions: Vec<i32> = (0..prototype_count).map(|index| 36 | (index << 14) as i32).collect();
+    main_instructions.push(30 | (1 << 23));
+
+    lua51_chunk(&SyntheticFunction {
+        instructions: &main_instructions,
+        numbers: &[9.0],
+        functions: &functions,
+        instructions_per_line: 4,
+        ..Default::default()
+    })
+}
+
+/// Build a little endian Lua 5.1 chunk with a 64 bit `size_t` whose main
+/// function creates `prototype_count` nested functions, each of which has
+/// `string_count` string constants.
+pub fn synthetic_chunk_with_prototype_strings(prototype_count: usize, string_count: usize) -> Vec<u8> {
+    // `LOADK 0 0` followed by a final `RETURN 0 1`
+    let functions: Vec<Vec<u8>> = (0..prototype_count)
+        .map(|prototype| {
+            SyntheticFunction {
+                instructions: &[1, 30 | (1 << 23)],
+                strings: &strings(&format!("prototype_{prototype:06}_"), string_count),
+                ..Default::default()
+            }
+            .lua51_bytes()
+        })
+        .collect();
+
+    // `CLOSURE 0 index` for every nested function followed by a final `RETURN 0 1`
+    let mut main_instructions: Vec<i32> = (0..prototype_count).map(|index| 36 | (index << 14) as i32).collect();
+    main_instructions.push(30 | (1 << 23));
+
+    lua51_chunk(&SyntheticFunction {
+        instructions: &main_instructions,
+        functions: &functions,
+        ..Default::default()
+    })
+}
@@ -0,0 +1,41 @@
+//! Measures the time it takes to convert a synthetic Lua 5.0 chunk with a lot
+//! of constants and loops, each of which adds constants during conversion.
+//!
+//! Run with `cargo bench --bench constants`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use lunify::{unify, BitWidth, Format};
+
+mod common;
+
+use common::synthetic_lua50_loops;
+
+const LOOP_COUNT: usize = 5_000;
+const STRING_COUNT: usize = 50_000;
+const ITERATIONS: u32 = 10;
+
+fn main() {
+    let input_bytes = synthetic_lua50_loops(LOOP_COUNT, STRING_COUNT);
+    let output_format = Format {
+        size_t_width: BitWidth::Bit32,
+        ..Default::default()
+    };
+    let settings = Default::default();
+
+    // Warm up.
+    unify(&input_bytes, &output_format, &settings).unwrap();
+
+    let mut total = Duration::ZERO;
+    for _ in 0..ITERATIONS {
+        let start = Instant::now();
+        black_box(unify(black_box(&input_bytes), &output_format, &settings).unwrap());
+        total += start.elapsed();
+    }
+
+    println!(
+        "convert {LOOP_COUNT} loops with {STRING_COUNT} constants: {:?} per iteration",
+        total / ITERATIONS
+    );
+}
@@ -0,0 +1,52 @@
+//! Measures the time it takes to convert a lot of small synthetic Lua 5.1
+//! chunks with the same settings, once with [`unify`] and once with a
+//! [`Converter`] that is created up front.
+//!
+//! Run with `cargo bench --bench converter`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use lunify::{unify, BitWidth, Converter, Format, Settings};
+
+mod common;
+
+use common::synthetic_chunk;
+
+const CHUNK_COUNT: usize = 10_000;
+const INSTRUCTION_COUNT: usize = 16;
+
+fn measure(mut convert: impl FnMut(&[u8])) -> Duration {
+    let input_bytes = synthetic_chunk(INSTRUCTION_COUNT);
+
+    // Warm up.
+    convert(&input_bytes);
+
+    let start = Instant::now();
+    for _ in 0..CHUNK_COUNT {
+        convert(black_box(&input_bytes));
+    }
+    start.elapsed() / CHUNK_COUNT as u32
+}
+
+fn main() {
+    let output_format = Format {
+        size_t_width: BitWidth::Bit32,
+        ..Default::default()
+    };
+    let settings = Settings::default();
+    let converter = Converter::new(settings).unwrap();
+
+    let unify_time = measure(|input_bytes| {
+        black_box(unify(input_bytes, &output_format, &settings).unwrap());
+    });
+
+    let converter_time = measure(|input_bytes| {
+        black_box(converter.unify(input_bytes, &output_format).unwrap());
+    });
+
+    println!(
+        "convert {CHUNK_COUNT} chunks with {INSTRUCTION_COUNT} instructions: {unify_time:?} per chunk with unify, \
+         {converter_time:?} with a converter"
+    );
+}
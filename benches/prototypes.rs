@@ -0,0 +1,45 @@
+//! Measures the time it takes to convert a synthetic Lua 5.1 chunk with fifty
+//! nested functions to a different `LFIELDS_PER_FLUSH`. Only one of the
+//! functions has a table constructor, so the others are copied as is.
+//!
+//! Run with `cargo bench --bench prototypes`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use lunify::{unify, BitWidth, Format, Settings};
+
+mod common;
+
+use common::synthetic_chunk_with_prototypes;
+
+const PROTOTYPE_COUNT: usize = 50;
+const INSTRUCTION_COUNT: usize = 4 * 1024;
+const TABLE_SIZE: usize = 4 * 1024;
+const ITERATIONS: u32 = 50;
+
+fn main() {
+    let input_bytes = synthetic_chunk_with_prototypes(PROTOTYPE_COUNT, INSTRUCTION_COUNT, TABLE_SIZE);
+    let output_format = Format {
+        size_t_width: BitWidth::Bit64,
+        ..Default::default()
+    };
+    let mut settings = Settings::default();
+    settings.output.fields_per_flush = 64;
+
+    // Warm up.
+    unify(&input_bytes, &output_format, &settings).unwrap();
+
+    let mut total = Duration::ZERO;
+    for _ in 0..ITERATIONS {
+        let start = Instant::now();
+        black_box(unify(black_box(&input_bytes), &output_format, &settings).unwrap());
+        total += start.elapsed();
+    }
+
+    println!(
+        "convert {} KiB chunk with {PROTOTYPE_COUNT} functions: {:?} per iteration",
+        input_bytes.len() / 1024,
+        total / ITERATIONS
+    );
+}
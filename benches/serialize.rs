@@ -0,0 +1,46 @@
+//! Measures converting a synthetic Lua 5.1 chunk of roughly one megabyte to a
+//! different format, and the write phase of that conversion on its own.
+//!
+//! Run with `cargo bench --bench serialize --features test-utils`.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use lunify::testing::prepare_write;
+use lunify::{unify, BitWidth, Format, Settings};
+
+mod common;
+
+use common::synthetic_chunk;
+
+const INSTRUCTION_COUNT: usize = 128 * 1024;
+
+fn serialize(criterion: &mut Criterion) {
+    let input_bytes = synthetic_chunk(INSTRUCTION_COUNT);
+    let output_format = Format {
+        size_t_width: BitWidth::Bit32,
+        ..Default::default()
+    };
+    let settings = Settings::default();
+
+    let mut group = criterion.benchmark_group("serialize");
+    group.throughput(Throughput::Bytes(input_bytes.len() as u64));
+
+    group.bench_function("convert", |bencher| {
+        bencher.iter(|| unify(black_box(&input_bytes), &output_format, &settings).unwrap());
+    });
+
+    // Decoding and converting happens in the setup, so only writing is measured.
+    group.bench_function("write", |bencher| {
+        bencher.iter_batched(
+            || prepare_write(&input_bytes, &output_format, &settings).unwrap(),
+            |write| write().unwrap(),
+            BatchSize::LargeInput,
+        );
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, serialize);
+criterion_main!(benches);
@@ -0,0 +1,67 @@
+//! Measures the peak heap usage while converting a synthetic Lua 5.1 chunk with
+//! a lot of nested functions, each with a big constant table. Nested functions
+//! are written as soon as they are converted, so the peak should stay close to
+//! the size of the output.
+//!
+//! Run with `cargo bench --bench streaming`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use lunify::{unify, BitWidth, Format};
+
+mod common;
+
+use common::synthetic_chunk_with_prototype_strings;
+
+const PROTOTYPE_COUNT: usize = 2000;
+const STRING_COUNT: usize = 500;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocator that keeps track of the peak number of allocated bytes.
+struct PeakAllocator;
+
+unsafe impl GlobalAlloc for PeakAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let pointer = System.alloc(layout);
+
+        if !pointer.is_null() {
+            let current = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(current, Ordering::Relaxed);
+        }
+
+        pointer
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+        System.dealloc(pointer, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: PeakAllocator = PeakAllocator;
+
+fn main() {
+    let input_bytes = synthetic_chunk_with_prototype_strings(PROTOTYPE_COUNT, STRING_COUNT);
+    let output_format = Format {
+        size_t_width: BitWidth::Bit32,
+        ..Default::default()
+    };
+    let settings = Default::default();
+
+    // Only measure the conversion itself, not the input.
+    let baseline = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(baseline, Ordering::Relaxed);
+
+    let output_bytes = unify(&input_bytes, &output_format, &settings).unwrap();
+
+    println!(
+        "convert {} KiB chunk with {PROTOTYPE_COUNT} functions to {} KiB: {} KiB peak",
+        input_bytes.len() / 1024,
+        output_bytes.len() / 1024,
+        (PEAK.load(Ordering::Relaxed) - baseline) / 1024,
+    );
+}
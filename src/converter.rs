@@ -0,0 +1,141 @@
+use std::borrow::Cow;
+
+use crate::{convert, output_reading_settings, read_header, ConversionReport, Format, FunctionError, FunctionPath, LunifyError, Settings};
+
+/// Converts Lua byte code with a fixed set of [`Settings`] that are validated
+/// once up front, so converting many chunks with the same settings doesn't
+/// repeat the checks for every chunk.
+///
+/// # Example
+///
+/// ```rust
+/// use lunify::{BitWidth, Converter, Format, LunifyError, Settings};
+///
+/// # fn main() -> Result<(), LunifyError> {
+/// let converter = Converter::new(Settings::default())?;
+/// let output_format = Format {
+///     size_t_width: BitWidth::Bit64,
+///     ..Default::default()
+/// };
+///
+/// let input_bytes: &[&[u8]] = &[/* ... */];
+/// for input_bytes in input_bytes {
+///     let _output_bytes = converter.unify(input_bytes, &output_format)?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Converter<'a> {
+    settings: Settings<'a>,
+}
+
+impl<'a> Converter<'a> {
+    /// Create a converter from the given settings. Returns
+    /// [`InvalidSettings`](LunifyError::InvalidSettings) if the settings can't
+    /// be used for conversion.
+    ///
+    /// ```rust
+    /// use lunify::{Converter, LunifyError, Settings};
+    ///
+    /// let mut settings = Settings::default();
+    /// settings.output.fields_per_flush = 0;
+    ///
+    /// let result = Converter::new(settings);
+    /// assert_eq!(result, Err(LunifyError::InvalidSettings("output.fields_per_flush")));
+    /// ```
+    pub fn new(settings: Settings<'a>) -> Result<Self, LunifyError> {
+        settings.validate()?;
+        Ok(Self { settings })
+    }
+
+    /// The settings used by the converter.
+    pub fn settings(&self) -> &Settings<'a> {
+        &self.settings
+    }
+
+    /// Same as [`unify`](crate::unify), using the settings of the converter.
+    pub fn unify(&self, input_bytes: impl AsRef<[u8]>, output_format: &Format) -> Result<Vec<u8>, LunifyError> {
+        self.unify_cow(input_bytes.as_ref(), output_format).map(Cow::into_owned)
+    }
+
+    /// Same as [`unify_cow`](crate::unify_cow), using the settings of the
+    /// converter.
+    pub fn unify_cow<'b>(
+        &self,
+        input_bytes: &'b (impl AsRef<[u8]> + ?Sized),
+        output_format: &Format,
+    ) -> Result<Cow<'b, [u8]>, LunifyError> {
+        convert(input_bytes.as_ref(), output_format, &self.settings, true, None).map(|(output_bytes, _)| output_bytes)
+    }
+
+    /// Same as [`unify_body`](crate::unify_body), using the settings of the
+    /// converter.
+    pub fn unify_body(&self, input_bytes: impl AsRef<[u8]>, output_format: &Format) -> Result<Vec<u8>, LunifyError> {
+        let output_bytes = self.unify_cow(input_bytes.as_ref(), output_format)?;
+
+        // The input is returned as is if it is already in the output format, so the
+        // header is skipped the same way as when reading the input.
+        let (byte_stream, ..) = read_header(&output_bytes, &output_reading_settings(&self.settings))?;
+        Ok(byte_stream.remaining().to_vec())
+    }
+
+    /// Same as [`unify_with_report`](crate::unify_with_report), using the
+    /// settings of the converter.
+    pub fn unify_with_report(
+        &self,
+        input_bytes: impl AsRef<[u8]>,
+        output_format: &Format,
+    ) -> Result<(Vec<u8>, ConversionReport), LunifyError> {
+        let (output_bytes, report) = convert(input_bytes.as_ref(), output_format, &self.settings, false, None)?;
+        Ok((output_bytes.into_owned(), report))
+    }
+
+    /// Same as [`unify_lenient`](crate::unify_lenient), using the settings of
+    /// the converter.
+    pub fn unify_lenient(&self, input_bytes: impl AsRef<[u8]>, output_format: &Format) -> (Option<Vec<u8>>, Vec<FunctionError>) {
+        let mut errors = Vec::new();
+
+        match convert(input_bytes.as_ref(), output_format, &self.settings, false, Some(&mut errors)) {
+            Ok((output_bytes, _)) => (Some(output_bytes.into_owned()), errors),
+            Err(error) => {
+                errors.push(FunctionError {
+                    path: FunctionPath::root(),
+                    error,
+                });
+                (None, errors)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Converter;
+    use crate::{unify, Format, LunifyError, Settings};
+
+    #[test]
+    fn matches_unify() -> Result<(), LunifyError> {
+        let converter = Converter::new(Settings::default())?;
+        let inputs: [&[u8]; 2] = [include_bytes!("../test_files/lua50.luab"), include_bytes!("../test_files/32bit.luab")];
+
+        for input_bytes in inputs {
+            let expected_bytes = unify(input_bytes, &Format::default(), &Settings::default())?;
+            assert_eq!(converter.unify(input_bytes, &Format::default())?, expected_bytes);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let mut settings = Settings::default();
+        settings.lua50.fields_per_flush = 0;
+
+        let input_bytes = include_bytes!("../test_files/lua50.luab");
+        let result = unify(input_bytes, &Format::default(), &settings);
+
+        assert_eq!(Converter::new(settings), Err(LunifyError::InvalidSettings("lua50.fields_per_flush")));
+        assert_eq!(result, Err(LunifyError::InvalidSettings("lua50.fields_per_flush")));
+    }
+}
@@ -0,0 +1,385 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{lua50, lua51, Format, InstructionLayout, LuaVersion, Settings};
+
+/// The resolved numeric values of [`Settings`], including the ones that are
+/// derived from them, as returned by [`Settings::describe`]. Build systems can
+/// use it to generate headers that agree with the output of Lunify.
+///
+/// With the `serde` feature, this serializes to a stable JSON schema. Fields
+/// are only ever added, never renamed, removed or changed in meaning. The
+/// default settings serialize to (with `lua51` left out, since it's the same
+/// as `output`):
+///
+/// ```json
+/// {
+///   "lua50": {
+///     "binary_signature": "\u001bLua",
+///     "stack_limit": 250,
+///     "fields_per_flush": 32,
+///     "layout": {
+///       "opcode": { "size": 6, "position": 0, "maximum_value": 63 },
+///       "a": { "size": 8, "position": 24, "maximum_value": 255 },
+///       "b": { "size": 9, "position": 15, "maximum_value": 511 },
+///       "c": { "size": 9, "position": 6, "maximum_value": 511 },
+///       "bx": { "size": 18, "position": 6, "maximum_value": 262143 },
+///       "signed_offset": 131071
+///     },
+///     "maximum_constant_index": 261
+///   },
+///   "output": {
+///     "binary_signature": "\u001bLua",
+///     "stack_limit": 250,
+///     "fields_per_flush": 50,
+///     "layout": {
+///       "opcode": { "size": 6, "position": 0, "maximum_value": 63 },
+///       "a": { "size": 8, "position": 6, "maximum_value": 255 },
+///       "b": { "size": 9, "position": 23, "maximum_value": 511 },
+///       "c": { "size": 9, "position": 14, "maximum_value": 511 },
+///       "bx": { "size": 18, "position": 14, "maximum_value": 262143 },
+///       "signed_offset": 131071
+///     },
+///     "constant_bit": 256,
+///     "maximum_constant_index": 255
+///   }
+/// }
+/// ```
+///
+/// # Example
+///
+/// ```rust
+/// use lunify::Settings;
+///
+/// let description = Settings::default().describe();
+/// assert_eq!(description.output.constant_bit, 256);
+/// assert_eq!(description.output.layout.b.position, 23);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct SettingsDescription {
+    /// Lua 5.0 input compile constants.
+    pub lua50: Lua50Description,
+    /// Lua 5.1 input compile constants.
+    pub lua51: Lua51Description,
+    /// Lua 5.1 output compile constants.
+    pub output: Lua51Description,
+}
+
+impl SettingsDescription {
+    pub(crate) fn new(settings: &Settings) -> Self {
+        Self {
+            lua50: Lua50Description::new(&settings.lua50),
+            lua51: Lua51Description::new(&settings.lua51),
+            output: Lua51Description::new(&settings.output),
+        }
+    }
+}
+
+/// The resolved Lua 5.0 compile constants of a [`SettingsDescription`].
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct Lua50Description {
+    /// The signature at the start of the byte code (`LUA_SIGNATURE`).
+    pub binary_signature: String,
+    /// Maximum number of elements on the stack (`MAXSTACK`).
+    pub stack_limit: u64,
+    /// Number of table elements set by one `SETLIST` (`LFIELDS_PER_FLUSH`).
+    pub fields_per_flush: u64,
+    /// Memory layout of instructions.
+    pub layout: LayoutDescription,
+    /// Largest constant index that fits every RK operand, which encodes
+    /// constants as values from `MAXSTACK` upwards.
+    pub maximum_constant_index: u64,
+}
+
+impl Lua50Description {
+    fn new(settings: &lua50::Settings) -> Self {
+        let maximum_operand = u64::min(settings.layout.b.bit_mask, settings.layout.c.bit_mask);
+
+        Self {
+            binary_signature: settings.binary_signature.to_owned(),
+            stack_limit: settings.stack_limit,
+            fields_per_flush: settings.fields_per_flush,
+            layout: LayoutDescription::new(&settings.layout),
+            maximum_constant_index: maximum_operand.saturating_sub(settings.stack_limit),
+        }
+    }
+}
+
+/// The resolved Lua 5.1 compile constants of a [`SettingsDescription`].
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct Lua51Description {
+    /// The signature at the start of the byte code (`LUA_SIGNATURE`).
+    pub binary_signature: String,
+    /// Maximum number of elements on the stack (`MAXSTACK`).
+    pub stack_limit: u64,
+    /// Number of table elements set by one `SETLIST` (`LFIELDS_PER_FLUSH`).
+    pub fields_per_flush: u64,
+    /// Memory layout of instructions.
+    pub layout: LayoutDescription,
+    /// Bit that marks RK operands as constants (`BITRK`).
+    pub constant_bit: u64,
+    /// Largest constant index that fits an RK operand (`MAXINDEXRK`).
+    pub maximum_constant_index: u64,
+}
+
+impl Lua51Description {
+    fn new(settings: &lua51::Settings) -> Self {
+        Self {
+            binary_signature: settings.binary_signature.to_owned(),
+            stack_limit: settings.stack_limit,
+            fields_per_flush: settings.fields_per_flush,
+            layout: LayoutDescription::new(&settings.layout),
+            constant_bit: settings.get_constant_bit(),
+            maximum_constant_index: settings.get_maximum_constant_index(),
+        }
+    }
+}
+
+/// The resolved memory layout of instructions (`SIZE_*`, `POS_*`,
+/// `MAXARG_*`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct LayoutDescription {
+    /// The opcode.
+    pub opcode: OperandDescription,
+    /// The A operand.
+    pub a: OperandDescription,
+    /// The B operand.
+    pub b: OperandDescription,
+    /// The C operand.
+    pub c: OperandDescription,
+    /// The Bx operand, which spans both B and C.
+    pub bx: OperandDescription,
+    /// The value that is subtracted from Bx to get the signed sBx
+    /// (`MAXARG_sBx`).
+    pub signed_offset: i64,
+}
+
+impl LayoutDescription {
+    fn new(layout: &InstructionLayout) -> Self {
+        let operand = |size, position, bit_mask| OperandDescription {
+            size,
+            position,
+            maximum_value: bit_mask,
+        };
+
+        Self {
+            opcode: operand(layout.opcode.size, layout.opcode.position, layout.opcode.bit_mask),
+            a: operand(layout.a.size, layout.a.position, layout.a.bit_mask),
+            b: operand(layout.b.size, layout.b.position, layout.b.bit_mask),
+            c: operand(layout.c.size, layout.c.position, layout.c.bit_mask),
+            bx: operand(layout.bx.size, layout.bx.position, layout.bx.bit_mask),
+            signed_offset: layout.signed_offset,
+        }
+    }
+}
+
+/// The position and size of an opcode or operand inside an instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct OperandDescription {
+    /// The number of bits (`SIZE_*`).
+    pub size: u64,
+    /// The position of the lowest bit (`POS_*`).
+    pub position: u64,
+    /// The largest value that fits (`MAXARG_*`).
+    pub maximum_value: u64,
+}
+
+/// The resolved values of a [`Format`], as returned by [`Format::describe`].
+/// With the `serde` feature, this serializes to a stable JSON schema like
+/// [`SettingsDescription`].
+///
+/// # Example
+///
+/// ```rust
+/// use lunify::{BitWidth, Format};
+///
+/// let format = Format {
+///     size_t_width: BitWidth::Bit32,
+///     ..Format::default()
+/// };
+///
+/// let description = format.describe();
+/// assert_eq!(description.size_t_size, 4);
+/// assert_eq!(description.lua51_header_size, 12);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct FormatDescription {
+    /// The format of the compiler.
+    pub format: u8,
+    /// The endianness as stored in the header, 0 for big endian and 1 for
+    /// little endian.
+    pub endianness: u8,
+    /// The number of bytes of an integer.
+    pub integer_size: u8,
+    /// The number of bytes of a `size_t`.
+    pub size_t_size: u8,
+    /// The number of bytes of an instruction.
+    pub instruction_size: u8,
+    /// The number of bytes of a Lua number.
+    pub number_size: u8,
+    /// If a Lua number is stored as an integer.
+    pub is_number_integral: bool,
+    /// The number of bytes of a Lua 5.0 header.
+    pub lua50_header_size: usize,
+    /// The number of bytes of a Lua 5.1 header.
+    pub lua51_header_size: usize,
+}
+
+impl FormatDescription {
+    pub(crate) fn new(format: &Format) -> Self {
+        Self {
+            format: format.format,
+            endianness: format.endianness.into(),
+            integer_size: format.integer_width.into(),
+            size_t_size: format.size_t_width.into(),
+            instruction_size: format.instruction_width.into(),
+            number_size: format.number_width.into(),
+            is_number_integral: format.is_number_integral,
+            lua50_header_size: format.byte_size_of_header(LuaVersion::Lua50),
+            lua51_header_size: format.byte_size_of_header(LuaVersion::Lua51),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FormatDescription, LayoutDescription, Lua50Description, Lua51Description, OperandDescription, SettingsDescription};
+    use crate::{BitWidth, Endianness, Format, InstructionLayout, LunifyError, OperandType, Settings};
+
+    fn operand(size: u64, position: u64) -> OperandDescription {
+        OperandDescription {
+            size,
+            position,
+            maximum_value: (1 << size) - 1,
+        }
+    }
+
+    #[test]
+    fn describe_default_settings() {
+        let output = Lua51Description {
+            binary_signature: "\x1bLua".to_owned(),
+            stack_limit: 250,
+            fields_per_flush: 50,
+            layout: LayoutDescription {
+                opcode: operand(6, 0),
+                a: operand(8, 6),
+                b: operand(9, 23),
+                c: operand(9, 14),
+                bx: operand(18, 14),
+                signed_offset: 131071,
+            },
+            constant_bit: 256,
+            maximum_constant_index: 255,
+        };
+
+        let expected = SettingsDescription {
+            lua50: Lua50Description {
+                binary_signature: "\x1bLua".to_owned(),
+                stack_limit: 250,
+                fields_per_flush: 32,
+                layout: LayoutDescription {
+                    opcode: operand(6, 0),
+                    a: operand(8, 24),
+                    b: operand(9, 15),
+                    c: operand(9, 6),
+                    bx: operand(18, 6),
+                    signed_offset: 131071,
+                },
+                maximum_constant_index: 261,
+            },
+            lua51: output.clone(),
+            output,
+        };
+
+        assert_eq!(Settings::default().describe(), expected);
+    }
+
+    #[test]
+    fn describe_derived_values() -> Result<(), LunifyError> {
+        let layout =
+            InstructionLayout::from_specification([OperandType::Opcode(7), OperandType::A(9), OperandType::C(8), OperandType::B(8)])?;
+        let settings = Settings::builder().output_layout(layout).build()?;
+        let description = settings.describe();
+
+        assert_eq!(description.output.constant_bit, settings.output.get_constant_bit());
+        assert_eq!(description.output.constant_bit, 128);
+        assert_eq!(
+            description.output.maximum_constant_index,
+            settings.output.get_maximum_constant_index()
+        );
+        assert_eq!(description.output.layout.signed_offset, layout.signed_offset);
+        assert_eq!(description.output.layout.signed_offset, 32767);
+        assert_eq!(description.output.layout.b, operand(8, 24));
+        assert_eq!(description.output.layout.c, operand(8, 16));
+        assert_eq!(description.output.layout.bx, operand(16, 16));
+        Ok(())
+    }
+
+    #[test]
+    fn describe_format() {
+        let format = Format {
+            format: 0,
+            endianness: Endianness::Big,
+            integer_width: BitWidth::Bit32,
+            size_t_width: BitWidth::Bit64,
+            instruction_width: BitWidth::Bit32,
+            number_width: BitWidth::Bit64,
+            is_number_integral: false,
+        };
+
+        let expected = FormatDescription {
+            format: 0,
+            endianness: 0,
+            integer_size: 4,
+            size_t_size: 8,
+            instruction_size: 4,
+            number_size: 8,
+            is_number_integral: false,
+            lua50_header_size: 22,
+            lua51_header_size: 12,
+        };
+
+        assert_eq!(format.describe(), expected);
+    }
+
+    #[cfg(feature = "metadata")]
+    #[test]
+    fn describe_json_schema() {
+        let json = serde_json::to_value(Settings::default().describe()).unwrap();
+        let expected = serde_json::json!({
+            "binary_signature": "\x1bLua",
+            "stack_limit": 250,
+            "fields_per_flush": 50,
+            "layout": {
+                "opcode": { "size": 6, "position": 0, "maximum_value": 63 },
+                "a": { "size": 8, "position": 6, "maximum_value": 255 },
+                "b": { "size": 9, "position": 23, "maximum_value": 511 },
+                "c": { "size": 9, "position": 14, "maximum_value": 511 },
+                "bx": { "size": 18, "position": 14, "maximum_value": 262143 },
+                "signed_offset": 131071
+            },
+            "constant_bit": 256,
+            "maximum_constant_index": 255
+        });
+
+        assert_eq!(json["output"], expected);
+        assert_eq!(json["lua51"], expected);
+        assert_eq!(json["lua50"]["maximum_constant_index"], 261);
+        assert_eq!(
+            json["lua50"]["layout"]["a"],
+            serde_json::json!({ "size": 8, "position": 24, "maximum_value": 255 })
+        );
+    }
+}
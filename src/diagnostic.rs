@@ -0,0 +1,161 @@
+use std::fmt::{Debug, Formatter};
+use std::hash::{Hash, Hasher};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{FunctionPath, Settings};
+
+/// How likely a [`Diagnostic`] points at a problem.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Severity {
+    /// The byte code was converted, but probably not the way it was meant to
+    /// be.
+    Warning,
+    /// Something unusual that is most likely harmless.
+    Note,
+}
+
+/// What a [`Diagnostic`] is about. New codes are added in minor versions, so
+/// matching on them needs a wildcard arm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+pub enum DiagnosticCode {
+    /// An instruction has bits set in a field that it doesn't use. The bits
+    /// are ignored because `strict_decoding` is unset in the settings.
+    UnexpectedOperandBits,
+    /// A line number doesn't fit into the integers of the output format, so it
+    /// was changed according to the `line_number_overflow` policy.
+    LineNumberOverflow,
+    /// A string constant in the input looks like one that Lunify creates while
+    /// converting, for example because the byte code was already converted.
+    LunifyConstant,
+    /// The input instructions of a Lua 5.0 function use more registers than
+    /// its declared stack size. The stack size is raised to fit them.
+    DeclaredStackTooSmall,
+    /// A global or field in `rename_globals` of the output settings couldn't be
+    /// renamed, because the constant holding the new name doesn't fit into the
+    /// operand of the instruction.
+    GlobalNotRenamed,
+    /// The input has padding after the main function, which is accepted
+    /// because of `allow_trailing_padding` in the settings.
+    TrailingPadding,
+    /// A Lua 5.0 function doesn't end with a `RETURN`, so the VM might run off
+    /// the end of its instructions. Only reported if `ensure_final_return` is
+    /// unset in the output settings, otherwise a `RETURN` is appended.
+    MissingFinalReturn,
+    /// A local variable in the debug information was repaired or dropped,
+    /// because `repair_debug_info` is set in the settings.
+    LocalVariableRepaired,
+    /// The input is decoded with a different endianness than the one declared
+    /// in its header, because `endianness_override` or
+    /// `auto_detect_endianness` is set in the settings.
+    EndiannessMismatch,
+}
+
+/// A problem that doesn't prevent the conversion, passed to the
+/// [`DiagnosticCallback`] in the settings.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Diagnostic {
+    /// How likely the diagnostic points at a problem.
+    pub severity: Severity,
+    /// What the diagnostic is about.
+    pub code: DiagnosticCode,
+    /// Indices of the nested functions that lead to the function, like the
+    /// paths returned by [`list_functions`](crate::list_functions).
+    pub function_path: FunctionPath,
+    /// The program counter of the instruction, if the diagnostic is about a
+    /// single instruction. This is the program counter in the input, unless
+    /// the diagnostic is about a converted instruction.
+    pub program_counter: Option<usize>,
+    /// Human readable description of the problem.
+    pub message: String,
+}
+
+/// User supplied function that is called with every [`Diagnostic`] of a
+/// conversion. Callbacks are compared and hashed by address.
+///
+/// # Example
+///
+/// ```rust
+/// use lunify::{unify, Diagnostic, DiagnosticCallback, Format, LunifyError, Settings};
+///
+/// # fn main() -> Result<(), LunifyError> {
+/// let report = |diagnostic: Diagnostic| eprintln!("{:?}: {}", diagnostic.code, diagnostic.message);
+/// let settings = Settings::builder().diagnostics(Some(DiagnosticCallback(&report))).build()?;
+///
+/// let input_bytes = include_bytes!("../test_files/lua50.luab");
+/// let _output_bytes = unify(input_bytes, &Format::default(), &settings)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Copy)]
+pub struct DiagnosticCallback<'a>(pub &'a dyn Fn(Diagnostic));
+
+impl DiagnosticCallback<'_> {
+    fn address(&self) -> usize {
+        self.0 as *const _ as *const () as usize
+    }
+}
+
+impl Debug for DiagnosticCallback<'_> {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(formatter, "DiagnosticCallback({:#x})", self.address())
+    }
+}
+
+impl PartialEq for DiagnosticCallback<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.address() == other.address()
+    }
+}
+
+impl Eq for DiagnosticCallback<'_> {}
+
+impl PartialOrd for DiagnosticCallback<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DiagnosticCallback<'_> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.address().cmp(&other.address())
+    }
+}
+
+impl Hash for DiagnosticCallback<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.address().hash(state);
+    }
+}
+
+/// Call the diagnostic callback of the settings, if there is one. The message
+/// is only created if somebody is listening.
+pub(crate) fn report_diagnostic(
+    settings: &Settings,
+    code: DiagnosticCode,
+    path: &[usize],
+    program_counter: Option<usize>,
+    message: impl FnOnce() -> String,
+) {
+    let Some(callback) = settings.diagnostics else {
+        return;
+    };
+
+    let severity = match code {
+        DiagnosticCode::LunifyConstant | DiagnosticCode::TrailingPadding => Severity::Note,
+        _ => Severity::Warning,
+    };
+
+    (callback.0)(Diagnostic {
+        severity,
+        code,
+        function_path: path.into(),
+        program_counter,
+        message: message(),
+    });
+}
@@ -0,0 +1,252 @@
+use crate::number::Number;
+use crate::serialization::{ByteStream, ByteWriter};
+use crate::{BitWidth, Format, LunifyError};
+
+fn byte_stream<'a>(bytes: &'a [u8], format: &Format) -> ByteStream<'a> {
+    let mut byte_stream = ByteStream::new(bytes);
+    byte_stream.set_format(*format);
+    byte_stream
+}
+
+fn finish<T>(byte_stream: ByteStream, value: T) -> Result<T, LunifyError> {
+    match byte_stream.is_empty() {
+        true => Ok(value),
+        false => Err(LunifyError::InputTooLong),
+    }
+}
+
+/// Encode a Lua number exactly like it is stored in byte code of the given
+/// [`Format`].
+///
+/// If the format uses integral numbers, the value needs to be a whole number,
+/// otherwise [`LunifyError::FloatPrecisionLoss`] is returned, and it needs to
+/// fit into the number width, otherwise [`LunifyError::IntegerOverflow`] is
+/// returned. If the format uses 32 bit floating point numbers, the value is
+/// rounded to the nearest `f32`, which may lose precision.
+///
+/// ```rust
+/// use lunify::{encode_number, Format};
+///
+/// let bytes = encode_number(9.0, &Format::default())?;
+/// assert_eq!(bytes, 9f64.to_le_bytes());
+/// # Ok::<(), lunify::LunifyError>(())
+/// ```
+pub fn encode_number(value: f64, format: &Format) -> Result<Vec<u8>, LunifyError> {
+    let number = match format.is_number_integral {
+        true => {
+            let value = Number::Float(value).as_integer()?;
+            fits_width(value, format.number_width)?;
+            Number::Integer(value)
+        }
+        false => Number::Float(value),
+    };
+
+    let mut byte_writer = ByteWriter::new(format);
+    byte_writer.number(number)?;
+    Ok(byte_writer.finalize())
+}
+
+/// Encode an integer exactly like it is stored in byte code of the given
+/// [`Format`], for example the line numbers of a function. If the value
+/// doesn't fit into the integer width, [`LunifyError::IntegerOverflow`] is
+/// returned.
+pub fn encode_integer(value: i64, format: &Format) -> Result<Vec<u8>, LunifyError> {
+    fits_width(value, format.integer_width)?;
+
+    let mut byte_writer = ByteWriter::new(format);
+    byte_writer.integer(value)?;
+    Ok(byte_writer.finalize())
+}
+
+/// Encode a string exactly like a string constant is stored in byte code of
+/// the given [`Format`]. Like Lua, a terminating NUL is appended and included
+/// in the length, so `bytes` should not be terminated already. If the length
+/// doesn't fit into the size_t width,
+/// [`LunifyError::ValueTooBigForWidth`] is returned.
+///
+/// ```rust
+/// use lunify::{encode_string, BitWidth, Format};
+///
+/// let format = Format {
+///     size_t_width: BitWidth::Bit32,
+///     ..Format::default()
+/// };
+///
+/// assert_eq!(encode_string(b"1.2", &format).unwrap(), b"\x04\0\0\x001.2\0");
+/// ```
+pub fn encode_string(bytes: &[u8], format: &Format) -> Result<Vec<u8>, LunifyError> {
+    let mut byte_writer = ByteWriter::new(format);
+    byte_writer.size_t(bytes.len() as u64 + 1)?;
+    byte_writer.slice(bytes);
+    byte_writer.byte(0);
+    Ok(byte_writer.finalize())
+}
+
+/// Decode a Lua number that was encoded with the given [`Format`]. `bytes`
+/// needs to contain exactly one number.
+///
+/// Integral numbers are returned as `f64`, which can't represent integers
+/// bigger than 2^53 exactly.
+pub fn decode_number(bytes: &[u8], format: &Format) -> Result<f64, LunifyError> {
+    let mut byte_stream = byte_stream(bytes, format);
+    let value = byte_stream.number()?.as_float()?;
+    finish(byte_stream, value)
+}
+
+/// Decode an integer that was encoded with the given [`Format`]. `bytes` needs
+/// to contain exactly one integer.
+pub fn decode_integer(bytes: &[u8], format: &Format) -> Result<i64, LunifyError> {
+    let mut byte_stream = byte_stream(bytes, format);
+    let value = byte_stream.integer()?;
+    finish(byte_stream, value)
+}
+
+/// Decode a string that was encoded with the given [`Format`]. `bytes` needs to
+/// contain exactly one string. The terminating NUL is not part of the returned
+/// slice, so this is the inverse of [`encode_string`].
+pub fn decode_string<'a>(bytes: &'a [u8], format: &Format) -> Result<&'a [u8], LunifyError> {
+    let mut byte_stream = byte_stream(bytes, format);
+    let value = byte_stream.string_slice()?;
+    let value = value.strip_suffix(&[0]).unwrap_or(value);
+    finish(byte_stream, value)
+}
+
+fn fits_width(value: i64, width: BitWidth) -> Result<(), LunifyError> {
+    match width == BitWidth::Bit64 || i32::try_from(value).is_ok() {
+        true => Ok(()),
+        false => Err(LunifyError::IntegerOverflow),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_integer, decode_number, decode_string, encode_integer, encode_number, encode_string};
+    use crate::{BitWidth, Endianness, Format, LunifyError};
+
+    fn format(endianness: Endianness, width: BitWidth, is_number_integral: bool) -> Format {
+        Format {
+            endianness,
+            integer_width: width,
+            size_t_width: width,
+            instruction_width: width,
+            number_width: width,
+            is_number_integral,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn integer() -> Result<(), LunifyError> {
+        let configurations: [(_, _, &[u8]); 4] = [
+            (Endianness::Little, BitWidth::Bit32, &[9, 0, 0, 0]),
+            (Endianness::Big, BitWidth::Bit32, &[0, 0, 0, 9]),
+            (Endianness::Little, BitWidth::Bit64, &[9, 0, 0, 0, 0, 0, 0, 0]),
+            (Endianness::Big, BitWidth::Bit64, &[0, 0, 0, 0, 0, 0, 0, 9]),
+        ];
+
+        for (endianness, width, expected) in configurations {
+            let format = format(endianness, width, false);
+            assert_eq!(encode_integer(9, &format)?, expected);
+            assert_eq!(decode_integer(expected, &format)?, 9);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn integer_negative() -> Result<(), LunifyError> {
+        let format = format(Endianness::Little, BitWidth::Bit32, false);
+        let bytes = encode_integer(-9, &format)?;
+        assert_eq!(decode_integer(&bytes, &format)?, -9);
+        Ok(())
+    }
+
+    #[test]
+    fn integer_overflow() {
+        let format = format(Endianness::Little, BitWidth::Bit32, false);
+        assert_eq!(encode_integer(i32::MAX as i64 + 1, &format), Err(LunifyError::IntegerOverflow));
+    }
+
+    #[test]
+    fn number() -> Result<(), LunifyError> {
+        let configurations: [(_, _, _, &[u8]); 8] = [
+            // Integer
+            (Endianness::Little, BitWidth::Bit32, true, &[9, 0, 0, 0]),
+            (Endianness::Big, BitWidth::Bit32, true, &[0, 0, 0, 9]),
+            (Endianness::Little, BitWidth::Bit64, true, &[9, 0, 0, 0, 0, 0, 0, 0]),
+            (Endianness::Big, BitWidth::Bit64, true, &[0, 0, 0, 0, 0, 0, 0, 9]),
+            // Float
+            (Endianness::Little, BitWidth::Bit32, false, &[0, 0, 16, 65]),
+            (Endianness::Big, BitWidth::Bit32, false, &[65, 16, 0, 0]),
+            (Endianness::Little, BitWidth::Bit64, false, &[0, 0, 0, 0, 0, 0, 34, 64]),
+            (Endianness::Big, BitWidth::Bit64, false, &[64, 34, 0, 0, 0, 0, 0, 0]),
+        ];
+
+        for (endianness, width, is_number_integral, expected) in configurations {
+            let format = format(endianness, width, is_number_integral);
+            assert_eq!(encode_number(9.0, &format)?, expected);
+            assert_eq!(decode_number(expected, &format)?, 9.0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn number_precision_loss() {
+        let format = format(Endianness::Little, BitWidth::Bit64, true);
+        assert_eq!(encode_number(9.5, &format), Err(LunifyError::FloatPrecisionLoss));
+    }
+
+    #[test]
+    fn number_overflow() {
+        let format = format(Endianness::Little, BitWidth::Bit32, true);
+        assert_eq!(encode_number(1e10, &format), Err(LunifyError::IntegerOverflow));
+    }
+
+    #[test]
+    fn number_single_precision() -> Result<(), LunifyError> {
+        let format = format(Endianness::Little, BitWidth::Bit32, false);
+        let bytes = encode_number(0.1, &format)?;
+        assert_eq!(decode_number(&bytes, &format)?, 0.1f32 as f64);
+        Ok(())
+    }
+
+    #[test]
+    fn string() -> Result<(), LunifyError> {
+        let configurations: [(_, _, &[u8]); 4] = [
+            (Endianness::Little, BitWidth::Bit32, &[4, 0, 0, 0, b'L', b'U', b'A', 0]),
+            (Endianness::Big, BitWidth::Bit32, &[0, 0, 0, 4, b'L', b'U', b'A', 0]),
+            (Endianness::Little, BitWidth::Bit64, &[4, 0, 0, 0, 0, 0, 0, 0, b'L', b'U', b'A', 0]),
+            (Endianness::Big, BitWidth::Bit64, &[0, 0, 0, 0, 0, 0, 0, 4, b'L', b'U', b'A', 0]),
+        ];
+
+        for (endianness, width, expected) in configurations {
+            let format = format(endianness, width, false);
+            assert_eq!(encode_string(b"LUA", &format)?, expected);
+            assert_eq!(decode_string(expected, &format)?, b"LUA");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn string_empty() -> Result<(), LunifyError> {
+        let format = format(Endianness::Little, BitWidth::Bit32, false);
+        assert_eq!(encode_string(b"", &format)?, [1, 0, 0, 0, 0]);
+        assert_eq!(decode_string(&[0, 0, 0, 0], &format)?, b"");
+        Ok(())
+    }
+
+    #[test]
+    fn decode_too_short() {
+        let format = format(Endianness::Little, BitWidth::Bit32, false);
+        assert_eq!(decode_integer(&[9, 0, 0], &format), Err(LunifyError::InputTooShort));
+        assert_eq!(decode_string(&[4, 0, 0, 0, b'L'], &format), Err(LunifyError::InputTooShort));
+    }
+
+    #[test]
+    fn decode_too_long() {
+        let format = format(Endianness::Little, BitWidth::Bit32, false);
+        assert_eq!(decode_integer(&[9, 0, 0, 0, 0], &format), Err(LunifyError::InputTooLong));
+    }
+}
@@ -1,9 +1,51 @@
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
-/// Error during [unify](super::unify).
+use crate::{BitWidth, FunctionPath};
+
+/// Reason why Lunify inserted or modified an instruction during conversion.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum InsertionReason {
+    /// Saving and restoring RA+3 around a Lua 5.0 `FORLOOP`.
+    ForLoopPreserve,
+    /// Emulating a Lua 5.0 `TFORLOOP` or `TFORPREP` instruction.
+    TForLoopExpansion,
+    /// Creating the `arg` table for a Lua 5.0 variadic function.
+    VariadicPrologue,
+    /// Moving table elements to match the output `LFIELDS_PER_FLUSH`.
+    SetListRewrite,
+    /// Replacing an instruction that is disallowed in the output settings.
+    OpcodeLowering,
+    /// Loading a constant into a register because it can't be encoded in an
+    /// operand.
+    ConstantSpill,
+    /// Closing the upvalues of a jump with a nonzero A operand.
+    JumpUpvalueClose,
+    /// Calling the global power function instead of a Lua 5.0 `POW`
+    /// instruction.
+    PowerCall,
+    /// Ending a Lua 5.0 function that doesn't end with a `RETURN`.
+    FinalReturn,
+}
+
+/// Field of an instruction.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum InstructionField {
+    /// The opcode.
+    Opcode,
+    /// The A operand.
+    A,
+    /// The B operand.
+    B,
+    /// The C operand.
+    C,
+}
+
+/// Error during [unify](super::unify).
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum LunifyError {
     /// The specified instruction layout is not valid. This can happen when size
     /// limitations are not respected, when operand types are specified more
@@ -40,7 +82,8 @@ pub enum LunifyError {
     /// represented when `is_number_integral` is set to true.
     FloatPrecisionLoss,
     /// The byte code contains an integral value that is too big to be
-    /// represented when `is_number_integral` is set to false.
+    /// represented when `is_number_integral` is set to false, or a value that
+    /// doesn't fit into the integer or number width of the format.
     IntegerOverflow,
     /// The byte code is truncated.
     InputTooShort,
@@ -49,10 +92,238 @@ pub enum LunifyError {
     /// The byte code generated by converting is using stack values that are
     /// bigger than Lua 5.1 `MAXSTACK`.
     StackTooLarge(u64),
+    /// The byte code generated by converting is using stack values that are
+    /// bigger than Lua 5.1 `MAXSTACK` because of instructions that were
+    /// inserted or modified by Lunify.
+    StackTooLargeDetailed {
+        /// The stack size that would have been required.
+        size: u64,
+        /// The line number of the first instruction exceeding the limit.
+        line: i64,
+        /// Reasons for the inserted or modified instructions that exceed the
+        /// limit, most frequent first.
+        causes: Vec<InsertionReason>,
+    },
     /// The byte code generated by converting to Lua 5.1 needs to store a value
     /// in an operand that exceed the maximum possible value.
     ValueTooBigForOperand,
+    /// A value in the output doesn't fit into the width it is written with, for
+    /// example a count that is bigger than `i32::MAX` with 32 bit integers.
+    ValueTooBigForWidth {
+        /// The value that should have been written.
+        value: i128,
+        /// The width of the value in the output format.
+        width: BitWidth,
+    },
+    /// A function references more constants than the output can address.
+    /// Either the constants referenced through Bx operands don't fit the Bx
+    /// operand of the output instruction layout, even when moved to the lowest
+    /// indices, or a constant referenced through an RK operand is above
+    /// `MAXINDEXRK` and there is no register left to load it into.
+    TooManyConstants {
+        /// The path of the function, like the one passed to
+        /// [`extract`](crate::extract).
+        path: FunctionPath,
+        /// The number of constants of the function.
+        constant_count: usize,
+    },
+    /// An instruction in the input references a constant that doesn't exist.
+    /// This is not checked if `skip_input_validation` is set in the settings.
+    InvalidConstantReference {
+        /// The program counter of the instruction in the input.
+        program_counter: usize,
+        /// The index of the constant.
+        index: u64,
+        /// The number of constants of the function.
+        available: usize,
+    },
+    /// A `CLOSURE` instruction in the input references a nested function that
+    /// doesn't exist. This is not checked if `skip_input_validation` is set in
+    /// the settings.
+    InvalidFunctionReference {
+        /// The program counter of the instruction in the input.
+        program_counter: usize,
+        /// The index of the nested function.
+        index: u64,
+        /// The number of nested functions of the function.
+        available: usize,
+    },
+    /// An instruction in the input references an upvalue that doesn't exist.
+    /// This is not checked if `skip_input_validation` is set in the settings.
+    InvalidUpvalueReference {
+        /// The program counter of the instruction in the input.
+        program_counter: usize,
+        /// The index of the upvalue.
+        index: u64,
+        /// The number of upvalues of the function.
+        available: usize,
+    },
+    /// A jump in the byte code lands outside of its function.
+    JumpOutOfBounds,
     /// The Lua 5.0 `FORLOOP` instruction specified a positive jump, even though
     /// we expect it to always be negative.
     UnexpectedForwardJump,
+    /// The byte code contains a `SETLIST` instruction that stores more values
+    /// than `LFIELDS_PER_FLUSH`, has no page, or has pages out of order.
+    MalformedSetList,
+    /// The byte code contains a Lua 5.0 `TFORLOOP` instruction that isn't
+    /// followed by a `JMP` back to the start of the loop body.
+    MalformedTForLoop {
+        /// The program counter of the `TFORLOOP` instruction in the input.
+        program_counter: usize,
+    },
+    /// The byte code generated by converting needs a `SETLIST` page that
+    /// doesn't fit into the C operand of the output instruction layout.
+    UnsupportedSetListExtension,
+    /// An instruction accesses a range of stack values that would need to be
+    /// only partially moved when rewriting `SETLIST` instructions.
+    UnshiftableInstruction,
+    /// The converted byte code is bigger than the `max_output_size` specified
+    /// in the output settings.
+    OutputTooLarge {
+        /// The size of the converted byte code.
+        size: usize,
+        /// The maximum size specified in the settings.
+        limit: usize,
+        /// Paths and sizes of the biggest functions, biggest first. The sizes
+        /// don't include nested functions.
+        largest_functions: Vec<(FunctionPath, usize)>,
+    },
+    /// A function trailer doesn't have the length required by the
+    /// [`FunctionTrailerSpec`](crate::FunctionTrailerSpec) of the output.
+    InvalidFunctionTrailer,
+    /// An instruction has bits set in a field that it doesn't use, or the opcode
+    /// doesn't exist. This is only checked if `strict_decoding` is set in the
+    /// settings.
+    UnexpectedOperandBits {
+        /// The program counter of the instruction in the input.
+        program_counter: usize,
+        /// The opcode of the instruction.
+        opcode: u64,
+        /// The field with the unexpected bits.
+        field: InstructionField,
+    },
+    /// An opcode name passed to
+    /// [`OpcodeSet::from_names`](crate::lua51::OpcodeSet::from_names) is not a
+    /// Lua 5.1 opcode.
+    UnknownOpcode(String),
+    /// The converted byte code contains an instruction that is disallowed in
+    /// the output settings and can't be lowered to other instructions.
+    DisallowedOpcode {
+        /// The name of the opcode.
+        name: &'static str,
+        /// The program counter of the instruction in the input. For Lua 5.0
+        /// byte code this is the program counter in the up-cast function.
+        program_counter: usize,
+    },
+    /// The converted byte code didn't pass [`validate`](crate::validate). This
+    /// can only happen if `verify` is set in the output settings.
+    InvalidOutput(Vec<crate::ValidationIssue>),
+    /// Lunify ended up in an inconsistent state while converting. This is a
+    /// bug in Lunify, but it is reported as an error rather than a panic since
+    /// the byte code might come from an untrusted source.
+    InternalInconsistency(&'static str),
+    /// The byte code exceeds one of the [`ConversionLimits`](crate::ConversionLimits)
+    /// in the settings. Contains the name of the limit.
+    LimitExceeded(&'static str),
+    /// The path passed to [`extract`](crate::extract),
+    /// [`set_constant`](crate::set_constant) or
+    /// [`insert_function`](crate::insert_function) doesn't lead to a function.
+    /// Contains the path up to the first index that doesn't exist.
+    InvalidFunctionPath(FunctionPath),
+    /// The function passed to [`extract`](crate::extract) captures upvalues,
+    /// which can't be provided by a standalone chunk. This can be allowed with
+    /// `extract_closures` in the settings.
+    CannotExtractClosure {
+        /// The number of upvalues the function captures.
+        upvalue_count: u8,
+    },
+    /// A setting can't be used for conversion. Contains the name of the
+    /// setting, e.g. `output.fields_per_flush`.
+    InvalidSettings(&'static str),
+    /// The output settings can't be used with the output [`Format`](crate::Format),
+    /// for example because the instruction layout is wider than the instruction
+    /// width. Contains a description of the problem.
+    IncompatibleOutputConfiguration(&'static str),
+    /// A string couldn't be parsed as a [`FunctionPath`]. Contains the string.
+    MalformedFunctionPath(String),
+    /// The constant passed to [`set_constant`](crate::set_constant) doesn't
+    /// exist.
+    InvalidConstantIndex {
+        /// The index of the constant.
+        index: usize,
+        /// The number of constants of the function.
+        available: usize,
+    },
+    /// The index passed to [`insert_function`](crate::insert_function) is
+    /// bigger than the number of nested functions of the parent.
+    InvalidFunctionIndex {
+        /// The index the function should have been inserted at.
+        index: usize,
+        /// The number of nested functions of the parent.
+        available: usize,
+    },
+    /// The value passed to [`set_constant`](crate::set_constant) has a
+    /// different type than the constant it replaces. This can be allowed with
+    /// `allow_constant_type_change` in the settings. Contains the index of the
+    /// constant.
+    ConstantTypeMismatch(usize),
+    /// The value passed to [`set_constant`](crate::set_constant) has a
+    /// different type than the constant it replaces, and the constant is
+    /// compared by an instruction.
+    ComparedConstantTypeChange {
+        /// The index of the constant.
+        index: usize,
+        /// The program counter of the comparison in the output.
+        program_counter: usize,
+    },
+    /// A local variable in the debug information has a range that doesn't fit
+    /// the instructions of its function, or that starts after it ends. This is
+    /// repaired instead if `repair_debug_info` is set in the settings.
+    InvalidLocalVariable {
+        /// The index of the local variable in the input.
+        index: usize,
+        /// The program counter where the local variable becomes active.
+        start_program_counter: i64,
+        /// The program counter where the local variable stops being active.
+        end_program_counter: i64,
+    },
+    /// More than `LUAI_MAXVARS` (200) local variables in the debug information
+    /// are active at the same instruction. This is repaired instead if
+    /// `repair_debug_info` is set in the settings. Contains the program counter
+    /// of the instruction.
+    TooManyLocalVariables(i64),
+}
+
+/// A function that failed to convert with
+/// [unify_lenient](super::unify_lenient).
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 'static")))]
+pub struct FunctionError {
+    /// Indices of the nested functions that lead to the function, like the
+    /// paths returned by [`list_functions`](crate::list_functions). Errors that
+    /// affect the whole chunk have the path of the main function, which is
+    /// empty.
+    pub path: FunctionPath,
+    /// The reason the function couldn't be converted.
+    pub error: LunifyError,
+}
+
+impl From<LunifyError> for std::io::Error {
+    fn from(error: LunifyError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{error:?}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::LunifyError;
+
+    #[test]
+    fn into_io_error() {
+        let error: std::io::Error = LunifyError::InputTooShort.into();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "InputTooShort");
+    }
 }
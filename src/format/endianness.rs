@@ -1,18 +1,50 @@
+use std::fmt::Display;
+
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
 use crate::LunifyError;
 
-/// The endianness of Lua-internal types.
+/// The endianness of Lua-internal types. In the header of the byte code, big
+/// endian is stored as 0 and little endian as 1.
+///
+/// ```rust
+/// use lunify::{Endianness, LunifyError};
+///
+/// assert_eq!(Endianness::try_from(1), Ok(Endianness::Little));
+/// assert_eq!(Endianness::try_from(2), Err(LunifyError::InvaildEndianness(2)));
+/// assert_eq!(u8::from(Endianness::Big), 0);
+/// assert_eq!(Endianness::Big.to_string(), "big endian");
+/// ```
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Endianness {
-    /// Least significant byte first.
-    Big,
     /// Most significant byte first.
+    Big,
+    /// Least significant byte first.
     Little,
 }
 
+impl Endianness {
+    /// The endianness of the target system.
+    ///
+    /// ```rust
+    /// use lunify::Endianness;
+    ///
+    /// let expected = match cfg!(target_endian = "big") {
+    ///     true => Endianness::Big,
+    ///     false => Endianness::Little,
+    /// };
+    /// assert_eq!(Endianness::native(), expected);
+    /// ```
+    pub const fn native() -> Self {
+        match cfg!(target_endian = "big") {
+            true => Endianness::Big,
+            false => Endianness::Little,
+        }
+    }
+}
+
 impl TryFrom<u8> for Endianness {
     type Error = LunifyError;
 
@@ -34,6 +66,15 @@ impl From<Endianness> for u8 {
     }
 }
 
+impl Display for Endianness {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Endianness::Big => write!(f, "big endian"),
+            Endianness::Little => write!(f, "little endian"),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::{Endianness, LunifyError};
@@ -52,4 +93,13 @@ mod tests {
     fn unsupported_endianness() {
         assert_eq!(Endianness::try_from(2), Err(LunifyError::InvaildEndianness(2)));
     }
+
+    #[test]
+    fn native_endianness() {
+        let expected = match 1u32.to_ne_bytes()[0] {
+            0 => Endianness::Big,
+            _ => Endianness::Little,
+        };
+        assert_eq!(Endianness::native(), expected);
+    }
 }
@@ -0,0 +1,18 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use super::LuaVersion;
+
+/// Facts about a chunk of Lua byte code that decide which parts of its
+/// [`Format`](crate::Format) matter, found by
+/// [chunk_facts](crate::chunk_facts). Used by
+/// [`Format::compatible_with`](crate::Format::compatible_with).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct ChunkFacts {
+    /// The Lua version of the chunk.
+    pub version: LuaVersion,
+    /// Whether any function of the chunk has a number constant.
+    pub has_number_constants: bool,
+}
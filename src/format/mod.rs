@@ -2,15 +2,18 @@
 use serde::{Deserialize, Serialize};
 
 mod endianness;
+mod facts;
 mod version;
 mod width;
 
 pub use endianness::Endianness;
-pub(crate) use version::LuaVersion;
+pub use facts::ChunkFacts;
+pub use version::LuaVersion;
 pub use width::BitWidth;
 
+use crate::diagnostic::report_diagnostic;
 use crate::serialization::{ByteStream, ByteWriter};
-use crate::{LunifyError, Settings};
+use crate::{DiagnosticCode, FormatDescription, LunifyError, Settings};
 
 /// Lua byte code format.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -40,16 +43,10 @@ impl Default for Format {
             false => BitWidth::Bit32,
         };
 
-        // By default we get the endianness of the target system.
-        let endianness = match unsafe { *(&1u32 as *const u32 as *const u8) } {
-            0 => Endianness::Big,
-            1 => Endianness::Little,
-            _ => unreachable!(),
-        };
-
         Self {
             format: 0,
-            endianness,
+            // By default we get the endianness of the target system.
+            endianness: Endianness::native(),
             integer_width: BitWidth::Bit32,
             size_t_width,
             instruction_width: BitWidth::Bit32,
@@ -66,7 +63,8 @@ impl Format {
             LuaVersion::Lua50 => 0,
         };
 
-        let endianness = byte_stream.byte()?.try_into()?;
+        let declared_endianness: Endianness = byte_stream.byte()?.try_into()?;
+        let endianness = settings.endianness_override.unwrap_or(declared_endianness);
         let integer_width = byte_stream.byte()?.try_into().map_err(LunifyError::UnsupportedIntegerWidth)?;
         let size_t_width = byte_stream.byte()?.try_into().map_err(LunifyError::UnsupportedSizeTWidth)?;
         let instruction_width = byte_stream.byte()?.try_into().map_err(LunifyError::UnsupportedInstructionWidth)?;
@@ -98,6 +96,12 @@ impl Format {
             }
         };
 
+        if endianness != declared_endianness {
+            report_diagnostic(settings, DiagnosticCode::EndiannessMismatch, &[], None, || {
+                format!("header declares {declared_endianness} but the input is decoded as {endianness}")
+            });
+        }
+
         #[cfg(feature = "debug")]
         {
             println!("format: {format}");
@@ -120,6 +124,104 @@ impl Format {
         })
     }
 
+    /// The number of bytes the header of byte code in this format occupies in
+    /// the given Lua version, including the standard four byte signature
+    /// (`\x1bLua`). Lua 5.0 stores the layout of instructions and a test
+    /// number in the header as well, so its header is longer.
+    ///
+    /// ```rust
+    /// use lunify::{Format, LuaVersion};
+    ///
+    /// assert_eq!(Format::default().byte_size_of_header(LuaVersion::Lua51), 12);
+    /// assert_eq!(Format::default().byte_size_of_header(LuaVersion::Lua50), 22);
+    /// ```
+    pub fn byte_size_of_header(&self, version: LuaVersion) -> usize {
+        // Signature, version, endianness and the widths of integer, size_t,
+        // instruction and number.
+        let common_size = 4 + 1 + 1 + 4;
+
+        match version {
+            // Format and number type.
+            LuaVersion::Lua51 => common_size + 2,
+            // Instruction layout and test number.
+            LuaVersion::Lua50 => common_size + 4 + self.number_width.byte_count(),
+        }
+    }
+
+    /// Describe the resolved values of the format, including the sizes of its
+    /// headers. See [`FormatDescription`].
+    pub fn describe(&self) -> FormatDescription {
+        FormatDescription::new(self)
+    }
+
+    /// Check if values are encoded the same way in both formats. The compiler
+    /// format is only part of the header, so it is ignored.
+    pub(crate) fn has_same_encoding(&self, other: &Format) -> bool {
+        Format { format: other.format, ..*self } == *other
+    }
+
+    /// Check if a chunk with the given [`ChunkFacts`] in this format would be
+    /// encoded the same way in the `other` format, apart from the header. Every
+    /// chunk has integers, strings and instructions, so their widths and the
+    /// endianness always need to match, but the number type only matters if the
+    /// chunk has number constants. Lua 5.0 chunks always need to be converted.
+    ///
+    /// The Lua 5.1 loader rejects chunks whose header doesn't match exactly, so
+    /// the header still needs to be written in the `other` format. Settings
+    /// like the instruction layout can require a conversion as well.
+    ///
+    /// ```rust
+    /// use lunify::{chunk_facts, BitWidth, Format, LunifyError, Settings};
+    ///
+    /// # fn main() -> Result<(), LunifyError> {
+    /// let input_bytes = include_bytes!("../../test_files/32bit.luab");
+    /// let facts = chunk_facts(input_bytes, &Settings::default())?;
+    /// let input_format = Format {
+    ///     size_t_width: BitWidth::Bit32,
+    ///     ..Default::default()
+    /// };
+    ///
+    /// let output_format = Format {
+    ///     size_t_width: BitWidth::Bit64,
+    ///     ..input_format
+    /// };
+    ///
+    /// assert!(input_format.compatible_with(&input_format, &facts));
+    /// assert!(!input_format.compatible_with(&output_format, &facts));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn compatible_with(&self, other: &Format, chunk_facts: &ChunkFacts) -> bool {
+        let is_number_compatible = !chunk_facts.has_number_constants
+            || (self.number_width == other.number_width && self.is_number_integral == other.is_number_integral);
+
+        chunk_facts.version == LuaVersion::Lua51
+            && self.endianness == other.endianness
+            && self.integer_width == other.integer_width
+            && self.size_t_width == other.size_t_width
+            && self.instruction_width == other.instruction_width
+            && is_number_compatible
+    }
+
+    /// The Lua 5.1 header that [`unify`](crate::unify) writes in front of the
+    /// main function, starting with the given binary signature. Together with
+    /// the output of [`unify_body`](crate::unify_body), it makes up the output
+    /// of [`unify`](crate::unify).
+    ///
+    /// ```rust
+    /// use lunify::Format;
+    ///
+    /// let header = Format::default().lua51_header_bytes("\x1bLua");
+    /// assert_eq!(&header[..5], b"\x1bLua\x51");
+    /// ```
+    pub fn lua51_header_bytes(&self, binary_signature: &str) -> Vec<u8> {
+        let mut byte_writer = ByteWriter::new(self);
+        byte_writer.slice(binary_signature.as_bytes());
+        byte_writer.byte(LuaVersion::Lua51.into());
+        self.write(&mut byte_writer);
+        byte_writer.finalize()
+    }
+
     pub(crate) fn write(&self, byte_writer: &mut ByteWriter) {
         byte_writer.byte(self.format);
         byte_writer.byte(self.endianness.into());
@@ -135,7 +237,7 @@ impl Format {
 mod tests {
     use super::LuaVersion;
     use crate::serialization::{ByteStream, ByteWriter};
-    use crate::{BitWidth, Endianness, Format, LunifyError, Settings};
+    use crate::{BitWidth, ChunkFacts, Endianness, Format, LunifyError, Settings};
 
     const EXPECTED_FORMAT: Format = Format {
         format: 0,
@@ -178,6 +280,29 @@ mod tests {
         assert_eq!(byter_writer.finalize(), [0, 1, 4, 8, 4, 8, 0]);
     }
 
+    #[test]
+    fn lua51_header_bytes() {
+        let header = EXPECTED_FORMAT.lua51_header_bytes("\x1bLua");
+        assert_eq!(header, b"\x1bLua\x51\x00\x01\x04\x08\x04\x08\x00");
+        assert_eq!(header.len(), EXPECTED_FORMAT.byte_size_of_header(LuaVersion::Lua51));
+    }
+
+    #[test]
+    fn byte_size_of_header() -> Result<(), LunifyError> {
+        let inputs: [&[u8]; 3] = [
+            include_bytes!("../../test_files/lua50.luab"),
+            include_bytes!("../../test_files/32bit.luab"),
+            include_bytes!("../../test_files/little_endian.luab"),
+        ];
+
+        for input_bytes in inputs {
+            let (byte_stream, version, format) = crate::read_header(input_bytes, &Settings::default())?;
+            assert_eq!(format.byte_size_of_header(version), byte_stream.offset());
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn lua50_unsupported_instruction_format() {
         let result = from_test_data(LuaVersion::Lua50, &[1, 4, 8, 4, 6, 9, 8, 9]);
@@ -207,4 +332,73 @@ mod tests {
         let result = from_test_data(LuaVersion::Lua51, &[0, 1, 4, 8, 4, 6]);
         assert_eq!(result, Err(LunifyError::UnsupportedNumberWidth(6)));
     }
+
+    #[test]
+    fn compatible_with() {
+        let facts = |version, has_number_constants| ChunkFacts {
+            version,
+            has_number_constants,
+        };
+
+        // The other format, and if it is compatible without and with number constants.
+        let cases = [
+            (EXPECTED_FORMAT, true, true),
+            (Format { format: 1, ..EXPECTED_FORMAT }, true, true),
+            (
+                Format {
+                    endianness: Endianness::Big,
+                    ..EXPECTED_FORMAT
+                },
+                false,
+                false,
+            ),
+            (
+                Format {
+                    integer_width: BitWidth::Bit64,
+                    ..EXPECTED_FORMAT
+                },
+                false,
+                false,
+            ),
+            (
+                Format {
+                    size_t_width: BitWidth::Bit32,
+                    ..EXPECTED_FORMAT
+                },
+                false,
+                false,
+            ),
+            (
+                Format {
+                    instruction_width: BitWidth::Bit64,
+                    ..EXPECTED_FORMAT
+                },
+                false,
+                false,
+            ),
+            (
+                Format {
+                    number_width: BitWidth::Bit32,
+                    ..EXPECTED_FORMAT
+                },
+                true,
+                false,
+            ),
+            (
+                Format {
+                    is_number_integral: true,
+                    ..EXPECTED_FORMAT
+                },
+                true,
+                false,
+            ),
+        ];
+
+        for (other, without_numbers, with_numbers) in cases {
+            assert_eq!(EXPECTED_FORMAT.compatible_with(&other, &facts(LuaVersion::Lua51, false)), without_numbers);
+            assert_eq!(EXPECTED_FORMAT.compatible_with(&other, &facts(LuaVersion::Lua51, true)), with_numbers);
+            assert_eq!(other.compatible_with(&EXPECTED_FORMAT, &facts(LuaVersion::Lua51, true)), with_numbers);
+            assert!(!EXPECTED_FORMAT.compatible_with(&other, &facts(LuaVersion::Lua50, false)));
+        }
+    }
 }
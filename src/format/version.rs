@@ -1,10 +1,14 @@
 use std::fmt::Display;
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
 use crate::LunifyError;
 
 /// List of supported Lua versions.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub(crate) enum LuaVersion {
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum LuaVersion {
     /// Lua 5.0.*
     Lua50,
     /// Lua 5.1.*
@@ -3,7 +3,17 @@ use std::fmt::Display;
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
-/// The width of Lua-internal types in bits.
+/// The width of Lua-internal types in bits. In the header of the byte code,
+/// the width is stored as a number of bytes.
+///
+/// ```rust
+/// use lunify::BitWidth;
+///
+/// assert_eq!(BitWidth::try_from(4), Ok(BitWidth::Bit32));
+/// assert_eq!(BitWidth::try_from(6), Err(6));
+/// assert_eq!(u8::from(BitWidth::Bit64), 8);
+/// assert_eq!(BitWidth::Bit64.to_string(), "64 bit");
+/// ```
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum BitWidth {
@@ -13,6 +23,33 @@ pub enum BitWidth {
     Bit64,
 }
 
+impl BitWidth {
+    /// The number of bytes of a value with this width.
+    ///
+    /// ```rust
+    /// use lunify::BitWidth;
+    ///
+    /// assert_eq!(BitWidth::Bit32.byte_count(), 4);
+    /// ```
+    pub const fn byte_count(self) -> usize {
+        match self {
+            BitWidth::Bit32 => 4,
+            BitWidth::Bit64 => 8,
+        }
+    }
+
+    /// The number of bits of a value with this width.
+    ///
+    /// ```rust
+    /// use lunify::BitWidth;
+    ///
+    /// assert_eq!(BitWidth::Bit64.bit_count(), 64);
+    /// ```
+    pub const fn bit_count(self) -> usize {
+        self.byte_count() * 8
+    }
+}
+
 impl TryFrom<u8> for BitWidth {
     type Error = u8;
 
@@ -71,4 +108,12 @@ mod tests {
     fn format_bit64() {
         assert_eq!(format!("{}", BitWidth::Bit64).as_str(), "64 bit");
     }
+
+    #[test]
+    fn counts_match_header_byte() {
+        for width in [BitWidth::Bit32, BitWidth::Bit64] {
+            assert_eq!(width.byte_count(), u8::from(width) as usize);
+            assert_eq!(width.bit_count(), width.byte_count() * 8);
+        }
+    }
 }
@@ -0,0 +1,298 @@
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{Converter, Format, LunifyError, Settings};
+
+/// Error of [`unify_file`] and [`unify_dir`].
+#[derive(Debug)]
+pub enum LunifyFileError {
+    /// A file or directory couldn't be read or written.
+    Io {
+        /// The path of the file or directory.
+        path: PathBuf,
+        /// The error returned by the file system.
+        error: std::io::Error,
+    },
+    /// The byte code couldn't be converted.
+    Lunify(LunifyError),
+}
+
+impl Display for LunifyFileError {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LunifyFileError::Io { path, error } => write!(formatter, "{}: {error}", path.display()),
+            LunifyFileError::Lunify(error) => write!(formatter, "{error:?}"),
+        }
+    }
+}
+
+impl std::error::Error for LunifyFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LunifyFileError::Io { error, .. } => Some(error),
+            LunifyFileError::Lunify(_) => None,
+        }
+    }
+}
+
+impl From<LunifyError> for LunifyFileError {
+    fn from(error: LunifyError) -> Self {
+        LunifyFileError::Lunify(error)
+    }
+}
+
+/// Sizes of a file converted by [`unify_file`] or [`unify_dir`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UnifySummary {
+    /// The number of bytes of the input file.
+    pub input_size: usize,
+    /// The number of bytes written to the output file.
+    pub output_size: usize,
+    /// The input was already in the output format and was written as is.
+    pub is_unchanged: bool,
+}
+
+/// What [`unify_dir`] did with a file.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 'static")))]
+pub enum FileOutcome {
+    /// The file was converted and written to the output directory.
+    Converted(UnifySummary),
+    /// The file doesn't start with one of the binary signatures in the
+    /// settings, so it was left alone.
+    Skipped,
+    /// The file looks like Lua byte code but couldn't be converted. Nothing is
+    /// written for it.
+    Failed(LunifyError),
+}
+
+/// A file found by [`unify_dir`].
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "'de: 'static")))]
+pub struct FileSummary {
+    /// The path of the input file.
+    pub path: PathBuf,
+    /// What was done with the file.
+    pub outcome: FileOutcome,
+}
+
+/// Reads Lua byte code from the `input` file, converts it with [`unify`](crate::unify)
+/// and writes it to the `output` file, which may be the same file.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use lunify::{unify_file, Format, LunifyFileError, Settings};
+///
+/// # fn main() -> Result<(), LunifyFileError> {
+/// let summary = unify_file("input.luac", "output.luac", &Format::default(), &Settings::default())?;
+/// println!("{} bytes written", summary.output_size);
+/// # Ok(())
+/// # }
+/// ```
+pub fn unify_file(
+    input: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+    output_format: &Format,
+    settings: &Settings,
+) -> Result<UnifySummary, LunifyFileError> {
+    let input_bytes = read(input.as_ref())?;
+    let output_bytes = Converter::new(*settings)?.unify_cow(&input_bytes, output_format)?;
+    write(output.as_ref(), &input_bytes, output_bytes)
+}
+
+/// Converts every file in the `input` directory that starts with one of the
+/// binary signatures in the settings and writes it to the same relative path
+/// in the `output` directory, which may be the same directory. Other files are
+/// skipped. If `recursive` is set, subdirectories are converted as well,
+/// otherwise they are ignored.
+///
+/// Files that can't be converted don't stop the conversion of the other files,
+/// but errors reading or writing files do. Returns a [`FileSummary`] for every
+/// file, sorted by path.
+pub fn unify_dir(
+    input: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+    recursive: bool,
+    output_format: &Format,
+    settings: &Settings,
+) -> Result<Vec<FileSummary>, LunifyFileError> {
+    let converter = Converter::new(*settings)?;
+    let mut summaries = Vec::new();
+    visit_dir(input.as_ref(), output.as_ref(), recursive, &mut |input_path, output_path| {
+        let input_bytes = read(input_path)?;
+
+        let outcome = match converter.unify_cow(&input_bytes, output_format) {
+            Ok(output_bytes) => FileOutcome::Converted(write(output_path, &input_bytes, output_bytes)?),
+            Err(LunifyError::IncorrectSignature) => FileOutcome::Skipped,
+            Err(error) => FileOutcome::Failed(error),
+        };
+
+        summaries.push(FileSummary {
+            path: input_path.to_owned(),
+            outcome,
+        });
+        Ok(())
+    })?;
+
+    summaries.sort();
+    Ok(summaries)
+}
+
+fn visit_dir(
+    input: &Path,
+    output: &Path,
+    recursive: bool,
+    visitor: &mut dyn FnMut(&Path, &Path) -> Result<(), LunifyFileError>,
+) -> Result<(), LunifyFileError> {
+    let entries = std::fs::read_dir(input).map_err(io_error(input))?;
+
+    for entry in entries {
+        let entry = entry.map_err(io_error(input))?;
+        let input_path = entry.path();
+        let output_path = output.join(entry.file_name());
+        let file_type = entry.file_type().map_err(io_error(&input_path))?;
+
+        if file_type.is_dir() {
+            if recursive {
+                visit_dir(&input_path, &output_path, recursive, visitor)?;
+            }
+        } else if file_type.is_file() {
+            visitor(&input_path, &output_path)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn io_error(path: &Path) -> impl FnOnce(std::io::Error) -> LunifyFileError + '_ {
+    move |error| LunifyFileError::Io { path: path.to_owned(), error }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, LunifyFileError> {
+    std::fs::read(path).map_err(io_error(path))
+}
+
+fn write(path: &Path, input_bytes: &[u8], output_bytes: Cow<[u8]>) -> Result<UnifySummary, LunifyFileError> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent).map_err(io_error(path))?;
+    }
+    std::fs::write(path, &output_bytes).map_err(io_error(path))?;
+
+    Ok(UnifySummary {
+        input_size: input_bytes.len(),
+        output_size: output_bytes.len(),
+        is_unchanged: matches!(output_bytes, Cow::Borrowed(_)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{unify_dir, unify_file, FileOutcome, LunifyFileError, UnifySummary};
+    use crate::{unify, Format, LunifyError, Settings};
+
+    /// A fresh directory for a test that is removed again when dropped.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("lunify-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn file(&self, name: &str, bytes: &[u8]) -> PathBuf {
+            let path = self.0.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, bytes).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    const LUA50_BYTES: &[u8] = include_bytes!("../test_files/lua50.luab");
+    const LUA51_BYTES: &[u8] = include_bytes!("../test_files/32bit.luab");
+
+    #[test]
+    fn file() -> Result<(), LunifyFileError> {
+        let directory = TestDir::new("file");
+        let input = directory.file("input.luab", LUA50_BYTES);
+        let output = directory.0.join("output/output.luab");
+
+        let summary = unify_file(&input, &output, &Format::default(), &Settings::default())?;
+        let expected_bytes = unify(LUA50_BYTES, &Format::default(), &Settings::default())?;
+
+        assert_eq!(summary, UnifySummary {
+            input_size: LUA50_BYTES.len(),
+            output_size: expected_bytes.len(),
+            is_unchanged: false,
+        });
+        assert_eq!(std::fs::read(&output).unwrap(), expected_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn missing_file() {
+        let directory = TestDir::new("missing_file");
+        let input = directory.0.join("missing.luab");
+
+        let result = unify_file(&input, directory.0.join("output.luab"), &Format::default(), &Settings::default());
+        assert!(matches!(result, Err(LunifyFileError::Io { path, .. }) if path == input));
+    }
+
+    #[test]
+    fn dir() -> Result<(), LunifyFileError> {
+        let directory = TestDir::new("dir");
+        let input = directory.0.join("input");
+        let output = directory.0.join("output");
+
+        directory.file("input/lua50.luab", LUA50_BYTES);
+        directory.file("input/readme.txt", b"not byte code");
+        directory.file("input/corrupt.luab", &LUA51_BYTES[..LUA51_BYTES.len() / 2]);
+        directory.file("input/nested/lua51.luab", LUA51_BYTES);
+
+        let outcomes = |summaries: Vec<super::FileSummary>| -> Vec<(PathBuf, FileOutcome)> {
+            let relative = |path: &Path| path.strip_prefix(&input).unwrap().to_owned();
+            summaries.into_iter().map(|summary| (relative(&summary.path), summary.outcome)).collect()
+        };
+        let converted = |input_bytes: &[u8]| -> Result<FileOutcome, LunifyError> {
+            let output_bytes = unify(input_bytes, &Format::default(), &Settings::default())?;
+            Ok(FileOutcome::Converted(UnifySummary {
+                input_size: input_bytes.len(),
+                output_size: output_bytes.len(),
+                is_unchanged: false,
+            }))
+        };
+
+        let summaries = unify_dir(&input, &output, false, &Format::default(), &Settings::default())?;
+        assert_eq!(outcomes(summaries), [
+            (PathBuf::from("corrupt.luab"), FileOutcome::Failed(LunifyError::InputTooShort)),
+            (PathBuf::from("lua50.luab"), converted(LUA50_BYTES)?),
+            (PathBuf::from("readme.txt"), FileOutcome::Skipped),
+        ]);
+        assert!(output.join("lua50.luab").is_file());
+        assert!(!output.join("corrupt.luab").exists());
+        assert!(!output.join("readme.txt").exists());
+        assert!(!output.join("nested").exists());
+
+        let summaries = unify_dir(&input, &output, true, &Format::default(), &Settings::default())?;
+        assert_eq!(outcomes(summaries).get(2), Some(&(PathBuf::from("nested/lua51.luab"), converted(LUA51_BYTES)?)));
+        assert!(output.join("nested/lua51.luab").is_file());
+        Ok(())
+    }
+}
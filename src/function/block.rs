@@ -0,0 +1,157 @@
+use super::instruction::{lua50, lua51, SignedBx, BC};
+
+/// How an instruction passes control to the instructions after it.
+enum Flow {
+    /// Always continues with the next instruction.
+    Next,
+    /// Might continue at the given offset from the next instruction.
+    Jump(i64),
+    /// Might skip the next instruction.
+    Skip,
+    /// Leaves the function.
+    Exit,
+}
+
+/// Find the instructions that start a basic block of Lua 5.0 byte code. These
+/// are the first instruction, the destinations of jumps and every instruction
+/// after one that doesn't always continue with the next.
+pub(super) fn lua50_leaders(instructions: &[lua50::Instruction]) -> Vec<bool> {
+    let flows = instructions.iter().map(|instruction| match *instruction {
+        lua50::Instruction::Jump { mode: SignedBx(offset), .. }
+        | lua50::Instruction::ForLoop { mode: SignedBx(offset), .. }
+        | lua50::Instruction::TForPrep { mode: SignedBx(offset), .. } => Flow::Jump(offset),
+        lua50::Instruction::LoadBool { mode: BC(_, c), .. } if c.0 != 0 => Flow::Skip,
+        lua50::Instruction::Equals { .. }
+        | lua50::Instruction::LessThan { .. }
+        | lua50::Instruction::LessEquals { .. }
+        | lua50::Instruction::Test { .. }
+        | lua50::Instruction::TForLoop { .. } => Flow::Skip,
+        lua50::Instruction::Return { .. } | lua50::Instruction::TailCall { .. } => Flow::Exit,
+        _ => Flow::Next,
+    });
+
+    leaders(flows.map(|flow| (flow, 1)).collect())
+}
+
+/// Find the instructions that start a basic block of Lua 5.1 byte code. An
+/// extended `SETLIST` at one of the `extended_instructions` takes up two
+/// instruction slots, which jumps count as well.
+pub(super) fn lua51_leaders(instructions: &[lua51::Instruction], extended_instructions: &[usize]) -> Vec<bool> {
+    let flows = instructions.iter().map(|instruction| match *instruction {
+        lua51::Instruction::Jump { mode: SignedBx(offset), .. }
+        | lua51::Instruction::ForLoop { mode: SignedBx(offset), .. }
+        | lua51::Instruction::ForPrep { mode: SignedBx(offset), .. } => Flow::Jump(offset),
+        lua51::Instruction::LoadBool { mode: BC(_, c), .. } if c.0 != 0 => Flow::Skip,
+        lua51::Instruction::Equals { .. }
+        | lua51::Instruction::LessThan { .. }
+        | lua51::Instruction::LessEquals { .. }
+        | lua51::Instruction::Test { .. }
+        | lua51::Instruction::TestSet { .. }
+        | lua51::Instruction::TForLoop { .. } => Flow::Skip,
+        lua51::Instruction::Return { .. } | lua51::Instruction::TailCall { .. } => Flow::Exit,
+        _ => Flow::Next,
+    });
+
+    let slots = |index: usize| 1 + extended_instructions.contains(&index) as usize;
+    leaders(flows.enumerate().map(|(index, flow)| (flow, slots(index))).collect())
+}
+
+/// Mark the leaders given the flow of every instruction and the number of
+/// instruction slots it takes up. Destinations that don't land on the start of
+/// an instruction are ignored, they are rejected elsewhere.
+fn leaders(flows: Vec<(Flow, usize)>) -> Vec<bool> {
+    let starts: Vec<usize> = flows
+        .iter()
+        .scan(0, |slot, (_, slots)| {
+            let start = *slot;
+            *slot += slots;
+            Some(start)
+        })
+        .collect();
+
+    let mut leaders = vec![false; flows.len()];
+    let mut mark = |slot: i64| {
+        let index = usize::try_from(slot).ok().and_then(|slot| starts.binary_search(&slot).ok());
+        if let Some(leader) = index.and_then(|index| leaders.get_mut(index)) {
+            *leader = true;
+        }
+    };
+
+    mark(0);
+
+    for ((flow, slots), start) in flows.iter().zip(&starts) {
+        let next = (start + slots) as i64;
+
+        match flow {
+            Flow::Next => {}
+            Flow::Jump(offset) => {
+                mark(next);
+                mark(next + offset);
+            }
+            Flow::Skip => {
+                mark(next);
+                mark(next + 1);
+            }
+            Flow::Exit => mark(next),
+        }
+    }
+
+    leaders
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lua50_leaders, lua51_leaders};
+    use crate::function::instruction::{ConstantIndex, ConstantRegister, Generic, SignedBx, Unused, BC};
+    use crate::{lua50, lua51};
+
+    #[test]
+    fn lua51_condition() {
+        // `x = y == 1 and 2 or 3`
+        let instructions = [
+            lua51::Instruction::Equals {
+                a: 0,
+                mode: BC(ConstantRegister(0, false), ConstantRegister(0, true)),
+            },
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(2) },
+            lua51::Instruction::LoadK { a: 1, mode: ConstantIndex(1) },
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(1) },
+            lua51::Instruction::LoadK { a: 1, mode: ConstantIndex(2) },
+            lua51::Instruction::SetGlobal { a: 1, mode: ConstantIndex(3) },
+        ];
+
+        assert_eq!(lua51_leaders(&instructions, &[]), [true, true, true, false, true, true]);
+    }
+
+    #[test]
+    fn lua51_extended_set_list() {
+        // The jump skips the extended `SETLIST`, which takes up two slots.
+        let instructions = [
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(2) },
+            lua51::Instruction::SetList {
+                a: 0,
+                mode: BC(Generic(1), Generic(600)),
+            },
+            lua51::Instruction::Return {
+                a: 0,
+                mode: BC(Generic(1), Unused),
+            },
+        ];
+
+        assert_eq!(lua51_leaders(&instructions, &[1]), [true, true, true]);
+        assert_eq!(lua51_leaders(&instructions, &[]), [true, true, false]);
+    }
+
+    #[test]
+    fn lua50_for_loop() {
+        let instructions = [
+            lua50::Instruction::LoadK { a: 0, mode: ConstantIndex(0) },
+            lua50::Instruction::Jump { a: 0, mode: SignedBx(1) },
+            lua50::Instruction::GetGlobal { a: 3, mode: ConstantIndex(1) },
+            lua50::Instruction::ForLoop { a: 0, mode: SignedBx(-2) },
+            lua50::Instruction::GetGlobal { a: 3, mode: ConstantIndex(1) },
+        ];
+
+        assert_eq!(lua50_leaders(&instructions), [true, false, true, true, true]);
+    }
+}
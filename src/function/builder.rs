@@ -1,6 +1,14 @@
+// Lunify processes untrusted input, so a bug in the conversion should result in an
+// error rather than a panic.
+#![deny(clippy::unwrap_used, clippy::indexing_slicing)]
+
+use super::instruction::{ConstantIndex, ConstantRegister, Generic, LuaInstruction, Register, Unused, BC};
 use super::Settings;
 use crate::lua51::Instruction;
-use crate::LunifyError;
+use crate::{FunctionPath, InsertionReason, LunifyError};
+
+/// Converted instructions together with their line info and padding.
+pub(super) type BuiltInstructions = (Vec<Instruction>, Vec<i64>, Vec<u64>);
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 struct InstructionContext {
@@ -8,6 +16,21 @@ struct InstructionContext {
     line_weight: i64,
     final_offset: i64,
     is_fixed: bool,
+    reason: Option<InsertionReason>,
+    /// The instruction was added during conversion. Instructions of the input
+    /// that were only changed in place have a reason, but aren't inserted.
+    is_inserted: bool,
+    padding: u64,
+    /// The instruction is a `MOVE` or `GETUPVAL` following a `CLOSURE` that
+    /// only describes where an upvalue of the new function is captured from.
+    is_closure_upvalue: bool,
+    /// The instruction starts a basic block of the input, so it might be
+    /// reached from somewhere other than the instruction before it.
+    is_leader: bool,
+    /// The instruction follows a `TAILCALL`, which Lua 5.1 requires to be
+    /// followed directly by its `RETURN`, so nothing may be inserted in front
+    /// of it.
+    is_glued: bool,
 }
 
 impl InstructionContext {
@@ -17,24 +40,92 @@ impl InstructionContext {
             line_weight: 0,
             final_offset: 0,
             is_fixed: false,
+            reason: None,
+            is_inserted: false,
+            padding: 0,
+            is_closure_upvalue: false,
+            is_leader: false,
+            is_glued: false,
         }
     }
 
-    pub fn new_extra(instruction: Instruction) -> Self {
+    pub fn new_extra(instruction: Instruction, reason: InsertionReason) -> Self {
         Self {
             instruction,
             line_weight: 1,
             final_offset: 0,
             is_fixed: false,
+            reason: Some(reason),
+            is_inserted: true,
+            padding: 0,
+            is_closure_upvalue: false,
+            is_leader: false,
+            is_glued: false,
         }
     }
 }
 
+/// Check if the instruction conditionally skips the instruction after it.
+pub(super) fn skips_next(instruction: &Instruction) -> bool {
+    match *instruction {
+        Instruction::LoadBool { mode: BC(_, c), .. } => c.0 != 0,
+        Instruction::Equals { .. }
+        | Instruction::LessThan { .. }
+        | Instruction::LessEquals { .. }
+        | Instruction::Test { .. }
+        | Instruction::TestSet { .. }
+        | Instruction::TForLoop { .. } => true,
+        _ => false,
+    }
+}
+
+/// Check if the instruction might continue anywhere but the instruction after
+/// it.
+fn is_control_flow(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Jump { .. }
+            | Instruction::ForLoop { .. }
+            | Instruction::ForPrep { .. }
+            | Instruction::Return { .. }
+            | Instruction::TailCall { .. }
+    ) || skips_next(instruction)
+}
+
+/// Check if the instruction overwrites the register without reading it first.
+pub(super) fn overwrites(instruction: &Instruction, register: u64) -> bool {
+    matches!(
+        instruction,
+        Instruction::Move { .. }
+            | Instruction::LoadK { .. }
+            | Instruction::LoadBool { .. }
+            | Instruction::LoadNil { .. }
+            | Instruction::GetUpValue { .. }
+            | Instruction::GetGlobal { .. }
+            | Instruction::GetTable { .. }
+            | Instruction::NewTable { .. }
+            | Instruction::Add { .. }
+            | Instruction::Subtract { .. }
+            | Instruction::Multiply { .. }
+            | Instruction::Divide { .. }
+            | Instruction::Modulo { .. }
+            | Instruction::Power { .. }
+            | Instruction::Unary { .. }
+            | Instruction::Not { .. }
+            | Instruction::Length { .. }
+            | Instruction::Concatinate { .. }
+    ) && instruction.writes_register(register)
+        && !instruction.reads_register(register)
+}
+
 #[derive(Default)]
 pub(super) struct FunctionBuilder {
     contexts: Vec<InstructionContext>,
     line_info: Vec<i64>,
     line_number: i64,
+    padding: u64,
+    is_leader: bool,
+    is_glued: bool,
 }
 
 impl FunctionBuilder {
@@ -42,45 +133,347 @@ impl FunctionBuilder {
         self.line_number = line_number;
     }
 
+    /// Set the bits outside of the instruction layout that are kept for the
+    /// following instructions, as long as they are not modified.
+    pub(super) fn set_padding(&mut self, padding: u64) {
+        self.padding = padding;
+    }
+
+    /// Mark the next instruction added with [`instruction`](Self::instruction)
+    /// as the start of a basic block.
+    pub(super) fn mark_leader(&mut self) {
+        self.is_leader = true;
+    }
+
+    /// Mark the next instruction added with [`instruction`](Self::instruction)
+    /// as glued to the instruction before it, so no instruction can be inserted
+    /// between the two.
+    pub(super) fn glue_next(&mut self) {
+        self.is_glued = true;
+    }
+
     pub(super) fn instruction(&mut self, instruction: Instruction) {
-        self.contexts.push(InstructionContext::new(instruction));
+        self.contexts.push(InstructionContext {
+            padding: self.padding,
+            is_leader: std::mem::take(&mut self.is_leader),
+            is_glued: std::mem::take(&mut self.is_glued),
+            ..InstructionContext::new(instruction)
+        });
         self.line_info.push(self.line_number);
     }
 
-    pub(super) fn extra_instruction(&mut self, instruction: Instruction) {
-        self.contexts.push(InstructionContext::new_extra(instruction));
+    pub(super) fn extra_instruction(&mut self, instruction: Instruction, reason: InsertionReason) {
+        self.contexts.push(InstructionContext::new_extra(instruction, reason));
         self.line_info.push(self.line_number);
     }
 
-    pub(super) fn insert_extra_instruction(&mut self, index: usize, instruction: Instruction) {
-        let line_number = self.line_info[index];
-        self.contexts.insert(index, InstructionContext::new_extra(instruction));
+    /// Add an instruction, but load every constant that can't be encoded in one
+    /// of its RK operands into a register first and use that register instead.
+    /// The registers start at `scratch`, or above the registers accessed by the
+    /// instruction if that is higher. The first `LOADK` takes the place of the
+    /// instruction, so jumps to it land on the start of the sequence. If there
+    /// is no register left below the stack limit,
+    /// [`TooManyConstants`](LunifyError::TooManyConstants) is returned with the
+    /// given number of constants.
+    pub(super) fn instruction_with_spills(
+        &mut self,
+        mut instruction: Instruction,
+        scratch: u64,
+        constant_count: usize,
+        settings: &Settings,
+    ) -> Result<(), LunifyError> {
+        let accesses = [instruction.stack_destination(), instruction.stack_source()];
+        let mut register = accesses.into_iter().flatten().map(|range| range.end + 1).fold(scratch, u64::max);
+        let mut is_spilled = false;
+
+        for operand in instruction.constant_operands_mut() {
+            if !operand.1 || operand.0 <= settings.output.get_maximum_constant_index() {
+                continue;
+            }
+
+            if register >= settings.output.stack_limit {
+                return Err(LunifyError::TooManyConstants {
+                    path: FunctionPath::root(),
+                    constant_count,
+                });
+            }
+
+            let load = Instruction::LoadK {
+                a: register,
+                mode: ConstantIndex(operand.0),
+            };
+
+            match is_spilled {
+                true => self.extra_instruction(load, InsertionReason::ConstantSpill),
+                false => {
+                    self.instruction(load);
+                    self.last_instruction_reason(InsertionReason::ConstantSpill)?;
+                }
+            }
+
+            *operand = ConstantRegister(register, false);
+            register += 1;
+            is_spilled = true;
+        }
+
+        match is_spilled {
+            true => self.extra_instruction(instruction, InsertionReason::ConstantSpill),
+            false => self.instruction(instruction),
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn insert_extra_instruction(
+        &mut self,
+        index: usize,
+        instruction: Instruction,
+        reason: InsertionReason,
+    ) -> Result<(), LunifyError> {
+        let line_number = *self
+            .line_info
+            .get(index)
+            .ok_or(LunifyError::InternalInconsistency("instruction inserted out of bounds"))?;
+
+        if self.context(index)?.is_glued {
+            return Err(LunifyError::InternalInconsistency("instruction inserted after a TAILCALL"));
+        }
+
+        self.contexts.insert(index, InstructionContext::new_extra(instruction, reason));
         self.line_info.insert(index, line_number);
+        Ok(())
     }
 
-    pub(super) fn remove_instruction(&mut self, index: usize) {
+    pub(super) fn remove_instruction(&mut self, index: usize) -> Result<(), LunifyError> {
+        // The line weight of the removed instruction is carried over to the next
+        // instruction, so there has to be one.
+        if index + 1 >= self.contexts.len() {
+            return Err(LunifyError::InternalInconsistency("instruction removed out of bounds"));
+        }
+
+        // Jumps to the removed instruction land on the next one instead.
         let removed = self.contexts.remove(index);
         self.line_info.remove(index);
-        self.contexts[index].line_weight += removed.line_weight - 1;
+        let next = self.context_mut(index)?;
+        next.line_weight += removed.line_weight - 1;
+        next.is_leader |= removed.is_leader;
+        next.is_glued |= removed.is_glued;
+        Ok(())
+    }
+
+    fn context(&self, index: usize) -> Result<&InstructionContext, LunifyError> {
+        self.contexts
+            .get(index)
+            .ok_or(LunifyError::InternalInconsistency("instruction index out of bounds"))
+    }
+
+    fn context_mut(&mut self, index: usize) -> Result<&mut InstructionContext, LunifyError> {
+        self.contexts
+            .get_mut(index)
+            .ok_or(LunifyError::InternalInconsistency("instruction index out of bounds"))
+    }
+
+    fn last_context_mut(&mut self) -> Result<&mut InstructionContext, LunifyError> {
+        self.contexts
+            .last_mut()
+            .ok_or(LunifyError::InternalInconsistency("no previous instruction"))
+    }
+
+    pub(super) fn last_instruction_fixed(&mut self) -> Result<(), LunifyError> {
+        self.last_context_mut()?.is_fixed = true;
+        Ok(())
+    }
+
+    pub(super) fn last_instruction_closure_upvalue(&mut self) -> Result<(), LunifyError> {
+        self.last_context_mut()?.is_closure_upvalue = true;
+        Ok(())
+    }
+
+    pub(super) fn is_marked_closure_upvalue(&self, index: usize) -> bool {
+        self.contexts.get(index).is_some_and(|context| context.is_closure_upvalue)
+    }
+
+    pub(super) fn last_instruction_offset(&mut self, final_offset: i64) -> Result<(), LunifyError> {
+        self.last_context_mut()?.final_offset = final_offset;
+        Ok(())
+    }
+
+    pub(super) fn last_instruction_reason(&mut self, reason: InsertionReason) -> Result<(), LunifyError> {
+        let context = self.last_context_mut()?;
+        context.reason = Some(reason);
+        context.is_inserted = true;
+        Ok(())
     }
 
-    pub(super) fn last_instruction_fixed(&mut self) {
-        self.contexts.last_mut().unwrap().is_fixed = true;
+    pub(super) fn last_instruction_merged(&mut self) -> Result<(), LunifyError> {
+        self.last_context_mut()?.line_weight -= 1;
+        Ok(())
+    }
+
+    pub(super) fn last_instruction(&self) -> Option<&Instruction> {
+        self.contexts.last().map(|context| &context.instruction)
     }
 
-    pub(super) fn last_instruction_offset(&mut self, final_offset: i64) {
-        self.contexts.last_mut().unwrap().final_offset = final_offset;
+    pub(super) fn get_instruction(&mut self, index: usize) -> Result<&mut Instruction, LunifyError> {
+        Ok(&mut self.context_mut(index)?.instruction)
     }
 
-    pub(super) fn get_instruction(&mut self, index: usize) -> &mut Instruction {
-        &mut self.contexts[index].instruction
+    pub(super) fn move_stack_accesses(&mut self, index: usize, stack_start: u64, offset: i64) -> Result<(), LunifyError> {
+        let context = self.context_mut(index)?;
+
+        // Instructions that access a range of stack values need to be moved as a whole,
+        // otherwise the range would be inverted or truncated.
+        if matches!(context.instruction.stack_range(), Some(range) if range.start < stack_start && range.end > stack_start) {
+            return Err(LunifyError::UnshiftableInstruction);
+        }
+
+        context.instruction.move_stack_accesses(stack_start, offset);
+        context.reason.get_or_insert(InsertionReason::SetListRewrite);
+
+        // Only B of an upvalue pseudo-instruction refers to the stack, A is always zero.
+        if context.is_closure_upvalue {
+            if let Instruction::Move { a, .. } | Instruction::GetUpValue { a, .. } = &mut context.instruction {
+                *a = 0;
+            }
+        }
+
+        Ok(())
     }
 
     pub(super) fn get_program_counter(&self) -> usize {
         self.contexts.len()
     }
 
-    fn jump_destination(&self, context_index: usize, mut destination: i64, final_offset: i64) -> i64 {
+    /// Check if the instructions from `start` up to the next instruction form a
+    /// single basic block, meaning that they always execute in order. Moving
+    /// the stack accesses of only some of them is only safe if they do.
+    pub(super) fn is_basic_block(&self, start: usize) -> bool {
+        !self.is_leader
+            && self
+                .contexts
+                .iter()
+                .skip(start)
+                .all(|context| !context.is_leader && !is_control_flow(&context.instruction))
+    }
+
+    /// Check if the instruction at the given index might be executed right
+    /// after the one in front of it, either by falling through or because a
+    /// jump in front of it is skipped. The first instruction is entered when
+    /// the function is called.
+    pub(super) fn is_entered_sequentially(&self, index: usize) -> bool {
+        let instruction = |index: usize| self.contexts.get(index).map(|context| &context.instruction);
+
+        let Some(previous) = index.checked_sub(1) else {
+            return true;
+        };
+
+        let is_jump = matches!(
+            instruction(previous),
+            Some(Instruction::Jump { .. } | Instruction::ForPrep { .. } | Instruction::Return { .. })
+        );
+        let is_skipped = previous.checked_sub(1).and_then(instruction).is_some_and(skips_next);
+
+        !is_jump || is_skipped
+    }
+
+    /// Check if the value of the register might be read when executing from
+    /// `start`. Only the instructions up to the first one that changes the
+    /// control flow are followed, so this returns `true` if in doubt.
+    pub(super) fn is_register_live(&self, start: usize, register: u64) -> bool {
+        for context in self.contexts.iter().skip(start) {
+            let instruction = &context.instruction;
+
+            // Upvalue pseudo-instructions only read B.
+            if context.is_closure_upvalue {
+                match instruction {
+                    Instruction::Move { mode: BC(b, _), .. } if b.0 == register => return true,
+                    _ => continue,
+                }
+            }
+
+            if overwrites(instruction, register) {
+                return false;
+            }
+
+            if instruction.reads_register(register) || instruction.writes_register(register) || is_control_flow(instruction) {
+                return true;
+            }
+        }
+
+        true
+    }
+
+    /// Store the elements of a table constructor that doesn't form a single
+    /// basic block after its `SETLIST` at `start` was removed. Every stack
+    /// access above `a` from `start` on is moved up by `previous_count`, so the
+    /// elements of the removed `SETLIST` stay in place and all elements are on
+    /// the stack in order when the new `SETLIST` is reached. There they are
+    /// stored one page at a time, moving the elements of the next page down
+    /// in between. `flat_index` is the index of the last element, or `None` for
+    /// an open `SETLIST`. Returns the page of the last `SETLIST`.
+    pub(super) fn repack_set_list(
+        &mut self,
+        start: usize,
+        a: u64,
+        previous_count: u64,
+        previous_page: u64,
+        flat_index: Option<u64>,
+        settings: &Settings,
+    ) -> Result<u64, LunifyError> {
+        for index in start..self.contexts.len() {
+            self.move_stack_accesses(index, a + 1, previous_count as i64)?;
+        }
+
+        // An open `SETLIST` stores everything up to the top of the stack, no matter
+        // how many elements that are.
+        let Some(flat_index) = flat_index else {
+            self.instruction(Instruction::SetList {
+                a,
+                mode: BC(Generic(0), Generic(previous_page)),
+            });
+            return Ok(previous_page);
+        };
+
+        let mut remaining = flat_index
+            .checked_sub(previous_page.saturating_sub(1) * settings.output.fields_per_flush)
+            .filter(|&remaining| remaining > 0)
+            .ok_or(LunifyError::MalformedSetList)?;
+        let mut stored = 0;
+        let mut page = previous_page;
+
+        loop {
+            let count = u64::min(remaining, settings.output.fields_per_flush);
+            let set_list = Instruction::SetList {
+                a,
+                mode: BC(Generic(count), Generic(page)),
+            };
+
+            // The first `SETLIST` takes the place of the original one, so jumps to it
+            // land on the start of the sequence.
+            match stored {
+                0 => self.instruction(set_list),
+                _ => self.extra_instruction(set_list, InsertionReason::SetListRewrite),
+            }
+
+            stored += count;
+            remaining -= count;
+
+            if remaining == 0 {
+                return Ok(page);
+            }
+
+            for offset in 0..u64::min(remaining, settings.output.fields_per_flush) {
+                self.extra_instruction(Instruction::Move {
+                    a: a + 1 + offset,
+                    mode: BC(Register(a + 1 + stored + offset), Unused),
+                }, InsertionReason::SetListRewrite);
+            }
+
+            page += 1;
+        }
+    }
+
+    fn jump_destination(&self, context_index: usize, mut destination: i64, final_offset: i64) -> Result<i64, LunifyError> {
         let (mut steps, mut offset) = match destination.is_positive() {
             true => (destination + 1, 1),
             false => (destination.abs(), 0),
@@ -88,17 +481,19 @@ impl FunctionBuilder {
 
         while steps != 0 {
             let index = match destination.is_positive() {
-                true => context_index + offset,
-                false => context_index - offset,
+                true => context_index.checked_add(offset),
+                false => context_index.checked_sub(offset),
             };
-            let context = self.contexts[index];
+            let context = index
+                .and_then(|index| self.contexts.get(index))
+                .ok_or(LunifyError::JumpOutOfBounds)?;
 
             destination += context.line_weight * destination.signum();
             steps += context.line_weight - 1;
             offset += 1;
         }
 
-        destination + final_offset
+        Ok(destination + final_offset)
     }
 
     pub(super) fn adjusted_jump_destination(&self, bx: i64) -> Result<usize, LunifyError> {
@@ -107,18 +502,208 @@ impl FunctionBuilder {
         }
 
         let program_counter = self.get_program_counter();
-        let new_bx = self.jump_destination(program_counter - 1, bx, 0);
+        let context_index = program_counter
+            .checked_sub(1)
+            .ok_or(LunifyError::InternalInconsistency("no previous instruction"))?;
+        let new_bx = self.jump_destination(context_index, bx, 0)?;
+
+        usize::try_from(program_counter as i64 + new_bx).map_err(|_| LunifyError::JumpOutOfBounds)
+    }
+
+    fn stack_too_large(&self, context_index: usize, size: u64, settings: &Settings) -> LunifyError {
+        let mut causes: Vec<(InsertionReason, usize)> = Vec::new();
+
+        // Collect the reasons of all inserted or modified instructions that exceed the
+        // stack limit, so the user knows which construct caused the error.
+        for context in &self.contexts {
+            let Some(reason) = context.reason else {
+                continue;
+            };
+
+            let accesses = [context.instruction.stack_destination(), context.instruction.stack_source()];
+            if accesses.into_iter().flatten().any(|access| access.end + 1 > settings.output.stack_limit) {
+                match causes.iter_mut().find(|(cause, _)| *cause == reason) {
+                    Some((_, count)) => *count += 1,
+                    None => causes.push((reason, 1)),
+                }
+            }
+        }
+
+        // If none of the instructions we inserted or modified are at fault, the input
+        // was already using too many stack values.
+        if causes.is_empty() {
+            return LunifyError::StackTooLarge(size);
+        }
+
+        causes.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+
+        LunifyError::StackTooLargeDetailed {
+            size,
+            line: self.line_info.get(context_index).copied().unwrap_or_default(),
+            causes: causes.into_iter().map(|(cause, _)| cause).collect(),
+        }
+    }
+
+    /// Check if the instruction at the given index describes an upvalue of a
+    /// preceding `CLOSURE` instruction rather than being executed on its own.
+    fn is_closure_upvalue(&self, index: usize) -> bool {
+        if self.is_marked_closure_upvalue(index) {
+            return true;
+        }
+
+        for context in self.contexts.iter().take(index).rev() {
+            match context.instruction {
+                Instruction::Closure { .. } => return true,
+                Instruction::Move { .. } | Instruction::GetUpValue { .. } => {}
+                _ => return false,
+            }
+        }
+
+        false
+    }
+
+    /// Remove redundant instructions. Instructions are only removed if no jump
+    /// lands on them and they are not conditionally skipped. Since removing
+    /// instructions changes the jump distances, every jump is resolved to the
+    /// index of its destination beforehand and fixed afterwards.
+    fn peephole(&mut self) -> Result<(), LunifyError> {
+        let mut destinations = Vec::with_capacity(self.contexts.len());
+        let mut is_jump_target = vec![false; self.contexts.len()];
+
+        for (context_index, context) in self.contexts.iter().enumerate() {
+            let destination = match context.instruction {
+                Instruction::Jump { mode, .. } | Instruction::ForLoop { mode, .. } | Instruction::ForPrep { mode, .. } => {
+                    let bx = match context.is_fixed {
+                        true => mode.0,
+                        false => self.jump_destination(context_index, mode.0, context.final_offset)?,
+                    };
+
+                    let destination = usize::try_from(context_index as i64 + 1 + bx)
+                        .map_err(|_| LunifyError::JumpOutOfBounds)?;
+
+                    if let Some(is_jump_target) = is_jump_target.get_mut(destination) {
+                        *is_jump_target = true;
+                    }
+
+                    Some(destination)
+                }
+                _ => None,
+            };
+
+            destinations.push(destination);
+        }
+
+        // Go over the instructions back to front, so that removing an instruction
+        // doesn't change the index of the instructions we still need to look at.
+        for index in (0..self.contexts.len()).rev() {
+            let (Some(context), Some(next)) = (self.contexts.get(index), self.contexts.get(index + 1)) else {
+                continue;
+            };
+
+            let is_skipped = index
+                .checked_sub(1)
+                .and_then(|index| self.contexts.get(index))
+                .is_some_and(|context| skips_next(&context.instruction));
+            let is_target = |index: usize| is_jump_target.get(index).copied().unwrap_or(true);
+
+            if is_skipped {
+                continue;
+            }
+
+            let removed_index = match (context.instruction, next.instruction) {
+                (Instruction::Move { a, mode: BC(b, _) }, _) if a == b.0 && !is_target(index) && !self.is_closure_upvalue(index) => index,
+                (Instruction::LoadNil { a, mode: BC(b, _) }, Instruction::LoadNil { a: next_a, mode: BC(next_b, _) })
+                    if next_a <= b.0 + 1 && a <= next_b.0 + 1 && !is_target(index + 1) =>
+                {
+                    let context = self.context_mut(index)?;
+                    context.instruction = Instruction::LoadNil {
+                        a: a.min(next_a),
+                        mode: BC(Register(b.0.max(next_b.0)), Unused),
+                    };
+                    context.padding = 0;
+                    index + 1
+                }
+                // Reading a global from the input might call an `__index` metamethod, so only
+                // the ones we inserted ourselves are removed, even if one from the input was
+                // moved.
+                (Instruction::GetGlobal { a, .. }, next)
+                    if context.is_inserted && overwrites(&next, a) && !is_target(index) && !is_target(index + 1) =>
+                {
+                    index
+                }
+                _ => continue,
+            };
+
+            let removed = self.contexts.remove(removed_index);
+            self.line_info.remove(removed_index);
+            destinations.remove(removed_index);
+            is_jump_target.remove(removed_index);
+
+            for destination in destinations.iter_mut().flatten() {
+                if *destination > removed_index {
+                    *destination -= 1;
+                }
+            }
+
+            if let Some(next) = self.contexts.get_mut(removed_index) {
+                next.is_glued |= removed.is_glued;
+            }
+        }
+
+        for (context_index, (context, destination)) in self.contexts.iter_mut().zip(destinations).enumerate() {
+            let (Instruction::Jump { mode, .. } | Instruction::ForLoop { mode, .. } | Instruction::ForPrep { mode, .. }) =
+                &mut context.instruction
+            else {
+                continue;
+            };
+
+            if let Some(destination) = destination {
+                mode.0 = destination as i64 - context_index as i64 - 1;
+                context.is_fixed = true;
+                context.final_offset = 0;
+            }
+        }
+
+        Ok(())
+    }
 
-        Ok(((program_counter as i64) + new_bx) as usize)
+    /// The stack size needed by the instructions that were neither inserted nor
+    /// modified.
+    pub(super) fn input_stack_size(&self) -> u64 {
+        self.contexts
+            .iter()
+            .filter(|context| context.reason.is_none())
+            .flat_map(|context| [context.instruction.stack_destination(), context.instruction.stack_source()])
+            .flatten()
+            .map(|access| access.end + 1)
+            .max()
+            .unwrap_or(0)
     }
 
     pub(super) fn finalize(
         mut self,
         maximum_stack_size: &mut u8,
         settings: &Settings,
-    ) -> Result<(Vec<Instruction>, Vec<i64>), LunifyError> {
-        #[cfg(feature = "debug")]
-        println!("\n======== Output ========");
+    ) -> Result<BuiltInstructions, LunifyError> {
+        // Converting can add instructions, so the limit needs to be checked again.
+        if self.contexts.len() as u64 > settings.limits.max_instructions_per_function {
+            return Err(LunifyError::LimitExceeded("max_instructions_per_function"));
+        }
+
+        if settings.output.peephole {
+            self.peephole()?;
+        }
+
+        // Lua 5.1 expects every `TAILCALL` to be followed by the instruction that
+        // followed it in the input.
+        for (index, context) in self.contexts.iter().enumerate() {
+            let previous = index.checked_sub(1).and_then(|index| self.contexts.get(index));
+            let follows_tail_call = matches!(previous.map(|context| context.instruction), Some(Instruction::TailCall { .. }));
+
+            if context.is_glued && !follows_tail_call {
+                return Err(LunifyError::InternalInconsistency("instruction separated from its TAILCALL"));
+            }
+        }
 
         for context_index in 0..self.contexts.len() {
             // The stack positions might have changed significantly, so go over every
@@ -127,67 +712,79 @@ impl FunctionBuilder {
             // an error. We also know that values on the stack will only be used
             // after they have been put there by anther instruction, meaning if
             // we make space for the instructions that push the values onto the
-            // stack, the stack will never overflow.
-            if let Some(destination) = self.contexts[context_index].instruction.stack_destination() {
-                let new_stack_size = destination.end + 1;
+            // stack, the stack will never overflow. Since some of the instructions we
+            // insert read registers that no instruction writes to in the builder's
+            // view, we need to make space for the sources as well.
+            let instruction = &self.context(context_index)?.instruction;
+            let accesses = [instruction.stack_destination(), instruction.stack_source()];
+
+            for access in accesses.into_iter().flatten() {
+                let new_stack_size = access.end + 1;
                 match new_stack_size <= settings.output.stack_limit {
                     true => *maximum_stack_size = (*maximum_stack_size).max(new_stack_size as u8),
-                    false => return Err(LunifyError::StackTooLarge(new_stack_size)),
+                    false => return Err(self.stack_too_large(context_index, new_stack_size, settings)),
                 }
             }
 
             let new_bx = {
-                let context = &self.contexts[context_index];
+                let context = self.context(context_index)?;
                 let is_fixed = context.is_fixed;
                 let final_offset = context.final_offset;
 
                 match &context.instruction {
                     Instruction::Jump { mode, .. } | Instruction::ForLoop { mode, .. } | Instruction::ForPrep { mode, .. } if !is_fixed => {
-                        Some(self.jump_destination(context_index, mode.0, final_offset))
+                        Some(self.jump_destination(context_index, mode.0, final_offset)?)
                     }
                     _ => None,
                 }
             };
 
             if let Some(bx) = new_bx {
-                match &mut self.contexts[context_index].instruction {
-                    Instruction::Jump { mode, .. } | Instruction::ForLoop { mode, .. } | Instruction::ForPrep { mode, .. } => {
-                        mode.0 = bx;
-                    }
-                    _ => unreachable!(),
+                if let Instruction::Jump { mode, .. } | Instruction::ForLoop { mode, .. } | Instruction::ForPrep { mode, .. } =
+                    &mut self.context_mut(context_index)?.instruction
+                {
+                    mode.0 = bx;
                 }
             }
-
-            #[cfg(feature = "debug")]
-            {
-                let context = &self.contexts[context_index];
-                println!("[{}] {:?}", context_index, context.instruction);
-                println!(" -> {:?}", context.line_weight);
-                println!();
-            }
         }
 
+        // Padding is only kept for instructions that are not modified or inserted.
+        let padding = self
+            .contexts
+            .iter()
+            .map(|context| match context.reason {
+                None => context.padding,
+                Some(_) => 0,
+            })
+            .collect();
         let instructions = self.contexts.into_iter().map(|context| context.instruction).collect();
-        Ok((instructions, self.line_info))
+        Ok((instructions, self.line_info, padding))
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
 mod tests {
     use super::FunctionBuilder;
     use crate::function::builder::InstructionContext;
-    use crate::function::instruction::{Bx, SignedBx};
-    use crate::{lua51, LunifyError};
+    use crate::function::instruction::{ConstantIndex, ConstantRegister, Generic, PrototypeIndex, Register, SignedBx, Unused, BC};
+    use crate::{lua51, InsertionReason, LunifyError, Settings};
 
     #[test]
     fn instruction_context_new() {
-        let instruction = lua51::Instruction::LoadK { a: 0, mode: Bx(1) };
+        let instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) };
         let context = InstructionContext::new(instruction);
         let expected = InstructionContext {
             instruction,
             line_weight: 0,
             final_offset: 0,
             is_fixed: false,
+            reason: None,
+            is_inserted: false,
+            padding: 0,
+            is_closure_upvalue: false,
+            is_leader: false,
+            is_glued: false,
         };
 
         assert_eq!(context, expected);
@@ -195,13 +792,19 @@ mod tests {
 
     #[test]
     fn instruction_context_new_extra() {
-        let instruction = lua51::Instruction::LoadK { a: 0, mode: Bx(1) };
-        let context = InstructionContext::new_extra(instruction);
+        let instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) };
+        let context = InstructionContext::new_extra(instruction, InsertionReason::ForLoopPreserve);
         let expected = InstructionContext {
             instruction,
             line_weight: 1,
             final_offset: 0,
             is_fixed: false,
+            reason: Some(InsertionReason::ForLoopPreserve),
+            is_inserted: true,
+            padding: 0,
+            is_closure_upvalue: false,
+            is_leader: false,
+            is_glued: false,
         };
 
         assert_eq!(context, expected);
@@ -219,7 +822,7 @@ mod tests {
     #[test]
     fn line_number_applies() {
         let mut builder = FunctionBuilder::default();
-        let instruction = lua51::Instruction::LoadK { a: 0, mode: Bx(1) };
+        let instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) };
 
         builder.set_line_number(9);
         builder.instruction(instruction);
@@ -230,7 +833,7 @@ mod tests {
     #[test]
     fn instruction() {
         let mut builder = FunctionBuilder::default();
-        let instruction = lua51::Instruction::LoadK { a: 0, mode: Bx(1) };
+        let instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) };
 
         builder.instruction(instruction);
 
@@ -241,45 +844,85 @@ mod tests {
     #[test]
     fn extra_instruction() {
         let mut builder = FunctionBuilder::default();
-        let instruction = lua51::Instruction::LoadK { a: 0, mode: Bx(1) };
+        let instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) };
 
-        builder.extra_instruction(instruction);
+        builder.extra_instruction(instruction, InsertionReason::ForLoopPreserve);
 
-        assert_eq!(&builder.contexts[..], &[InstructionContext::new_extra(instruction)]);
+        assert_eq!(&builder.contexts[..], &[InstructionContext::new_extra(instruction, InsertionReason::ForLoopPreserve)]);
         assert_eq!(&builder.line_info[..], &[0]);
     }
 
     #[test]
-    fn insert_extra_instruction() {
+    fn insert_extra_instruction() -> Result<(), LunifyError> {
         let mut builder = FunctionBuilder::default();
-        let instruction = lua51::Instruction::LoadK { a: 0, mode: Bx(1) };
-        let extra_instruction = lua51::Instruction::LoadK { a: 0, mode: Bx(10) };
+        let instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) };
+        let extra_instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(10) };
 
         builder.instruction(instruction);
         builder.set_line_number(9);
         builder.instruction(instruction);
-        builder.insert_extra_instruction(1, extra_instruction);
+        builder.insert_extra_instruction(1, extra_instruction, InsertionReason::ForLoopPreserve)?;
 
         let expected = [
             InstructionContext::new(instruction),
-            InstructionContext::new_extra(extra_instruction),
+            InstructionContext::new_extra(extra_instruction, InsertionReason::ForLoopPreserve),
             InstructionContext::new(instruction),
         ];
 
         assert_eq!(&builder.contexts[..], &expected);
         assert_eq!(&builder.line_info[..], &[0, 9, 9]);
+        Ok(())
+    }
+
+    #[test]
+    fn instruction_with_spills() -> Result<(), LunifyError> {
+        let mut builder = FunctionBuilder::default();
+        let settings = Settings::default();
+        let instruction = lua51::Instruction::_Self {
+            a: 2,
+            mode: BC(Register(2), ConstantRegister(300, true)),
+        };
+
+        // The scratch register has to be above A+1, which `SELF` writes to.
+        builder.instruction_with_spills(instruction, 1, 301, &settings)?;
+
+        let mut load = InstructionContext::new(lua51::Instruction::LoadK { a: 4, mode: ConstantIndex(300) });
+        load.reason = Some(InsertionReason::ConstantSpill);
+        load.is_inserted = true;
+        let spilled = lua51::Instruction::_Self {
+            a: 2,
+            mode: BC(Register(2), ConstantRegister(4, false)),
+        };
+        let expected = [load, InstructionContext::new_extra(spilled, InsertionReason::ConstantSpill)];
+
+        assert_eq!(&builder.contexts[..], &expected);
+        Ok(())
+    }
+
+    #[test]
+    fn instruction_without_spills() -> Result<(), LunifyError> {
+        let mut builder = FunctionBuilder::default();
+        let instruction = lua51::Instruction::Add {
+            a: 0,
+            mode: BC(ConstantRegister(255, true), ConstantRegister(1, false)),
+        };
+
+        builder.instruction_with_spills(instruction, 2, 256, &Settings::default())?;
+
+        assert_eq!(&builder.contexts[..], &[InstructionContext::new(instruction)]);
+        Ok(())
     }
 
     #[test]
-    fn remove_instruction() {
+    fn remove_instruction() -> Result<(), LunifyError> {
         let mut builder = FunctionBuilder::default();
-        let instruction = lua51::Instruction::LoadK { a: 0, mode: Bx(1) };
-        let removed_instruction = lua51::Instruction::LoadK { a: 0, mode: Bx(10) };
+        let instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) };
+        let removed_instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(10) };
 
         builder.instruction(instruction);
         builder.instruction(removed_instruction);
         builder.instruction(instruction);
-        builder.remove_instruction(1);
+        builder.remove_instruction(1)?;
 
         let expected = [InstructionContext::new(instruction), InstructionContext {
             line_weight: -1,
@@ -288,101 +931,200 @@ mod tests {
 
         assert_eq!(&builder.contexts[..], &expected);
         assert_eq!(&builder.line_info[..], &[0, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn is_register_live() {
+        let mut builder = FunctionBuilder::default();
+        builder.instruction(lua51::Instruction::Closure { a: 0, mode: PrototypeIndex(0) });
+        builder.instruction(lua51::Instruction::Move {
+            a: 0,
+            mode: BC(Register(2), Unused),
+        });
+        builder.last_instruction_closure_upvalue().unwrap();
+        builder.instruction(lua51::Instruction::LoadK { a: 3, mode: ConstantIndex(0) });
+        builder.instruction(lua51::Instruction::Jump { a: 0, mode: SignedBx(-4) });
+
+        // The upvalue pseudo-instruction reads B, but doesn't write A.
+        assert!(!builder.is_register_live(0, 3));
+        assert!(builder.is_register_live(0, 2));
+
+        // Nothing overwrites the register before the jump.
+        assert!(builder.is_register_live(3, 3));
     }
 
     #[test]
-    fn remove_extra_instruction() {
+    fn remove_extra_instruction() -> Result<(), LunifyError> {
         let mut builder = FunctionBuilder::default();
-        let instruction = lua51::Instruction::LoadK { a: 0, mode: Bx(1) };
-        let removed_instruction = lua51::Instruction::LoadK { a: 0, mode: Bx(10) };
+        let instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) };
+        let removed_instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(10) };
 
         builder.instruction(instruction);
-        builder.extra_instruction(removed_instruction);
+        builder.extra_instruction(removed_instruction, InsertionReason::ForLoopPreserve);
         builder.instruction(instruction);
-        builder.remove_instruction(1);
+        builder.remove_instruction(1)?;
 
         let expected = [InstructionContext::new(instruction), InstructionContext::new(instruction)];
         assert_eq!(&builder.contexts[..], &expected);
         assert_eq!(&builder.line_info[..], &[0, 0]);
+        Ok(())
     }
 
     #[test]
-    fn last_instruction_fixed() {
+    fn insert_extra_instruction_out_of_bounds() {
         let mut builder = FunctionBuilder::default();
-        let instruction = lua51::Instruction::LoadK { a: 0, mode: Bx(1) };
+        let instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) };
 
         builder.instruction(instruction);
-        builder.last_instruction_fixed();
+
+        let result = builder.insert_extra_instruction(1, instruction, InsertionReason::ForLoopPreserve);
+        assert!(matches!(result, Err(LunifyError::InternalInconsistency(_))));
+    }
+
+    fn tail_call_builder() -> FunctionBuilder {
+        let mut builder = FunctionBuilder::default();
+        builder.instruction(lua51::Instruction::TailCall {
+            a: 0,
+            mode: BC(Generic(1), Generic(0)),
+        });
+        builder.glue_next();
+        builder.instruction(lua51::Instruction::Return {
+            a: 0,
+            mode: BC(Generic(0), Unused),
+        });
+        builder
+    }
+
+    #[test]
+    fn insert_extra_instruction_after_tail_call() {
+        let mut builder = tail_call_builder();
+        let instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) };
+
+        let result = builder.insert_extra_instruction(1, instruction, InsertionReason::ForLoopPreserve);
+        assert!(matches!(result, Err(LunifyError::InternalInconsistency(_))));
+        assert!(builder.insert_extra_instruction(0, instruction, InsertionReason::ForLoopPreserve).is_ok());
+    }
+
+    #[test]
+    fn finalize_tail_call_separated() {
+        let mut builder = tail_call_builder();
+        builder.remove_instruction(0).unwrap();
+
+        let result = builder.finalize(&mut 2, &Settings::default());
+        assert!(matches!(result, Err(LunifyError::InternalInconsistency(_))));
+        assert!(tail_call_builder().finalize(&mut 2, &Settings::default()).is_ok());
+    }
+
+    #[test]
+    fn remove_last_instruction() {
+        let mut builder = FunctionBuilder::default();
+        let instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) };
+
+        builder.instruction(instruction);
+
+        let result = builder.remove_instruction(0);
+        assert!(matches!(result, Err(LunifyError::InternalInconsistency(_))));
+    }
+
+    #[test]
+    fn last_instruction_empty() {
+        let mut builder = FunctionBuilder::default();
+        let result = builder.last_instruction_fixed();
+        assert!(matches!(result, Err(LunifyError::InternalInconsistency(_))));
+    }
+
+    #[test]
+    fn last_instruction_fixed() -> Result<(), LunifyError> {
+        let mut builder = FunctionBuilder::default();
+        let instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) };
+
+        builder.instruction(instruction);
+        builder.last_instruction_fixed()?;
 
         assert!(builder.contexts.last().unwrap().is_fixed);
+        Ok(())
     }
 
     #[test]
-    fn last_instruction_offset() {
+    fn last_instruction_offset() -> Result<(), LunifyError> {
         let mut builder = FunctionBuilder::default();
         let instruction = lua51::Instruction::Jump { a: 0, mode: SignedBx(-4) };
 
         builder.instruction(instruction);
-        builder.last_instruction_offset(-9);
+        builder.last_instruction_offset(-9)?;
 
         assert_eq!(builder.contexts.last().unwrap().final_offset, -9);
+        Ok(())
+    }
+
+    #[test]
+    fn last_instruction_merged() -> Result<(), LunifyError> {
+        let mut builder = FunctionBuilder::default();
+        let instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) };
+
+        builder.instruction(instruction);
+        builder.last_instruction_merged()?;
+
+        assert_eq!(builder.contexts.last().unwrap().line_weight, -1);
+        Ok(())
     }
 
     #[test]
     fn jump_destination_negative() {
         let mut builder = FunctionBuilder::default();
-        let instruction = lua51::Instruction::LoadK { a: 0, mode: Bx(1) };
+        let instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) };
 
         builder.instruction(instruction);
-        builder.extra_instruction(instruction);
+        builder.extra_instruction(instruction, InsertionReason::ForLoopPreserve);
 
         let result = builder.jump_destination(builder.get_program_counter() - 1, -1, 0);
-        assert_eq!(result, -2);
+        assert_eq!(result, Ok(-2));
     }
 
     #[test]
     fn jump_destination_zero() {
         let mut builder = FunctionBuilder::default();
-        let instruction = lua51::Instruction::LoadK { a: 0, mode: Bx(1) };
+        let instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) };
 
-        builder.extra_instruction(instruction);
+        builder.extra_instruction(instruction, InsertionReason::ForLoopPreserve);
 
         let result = builder.jump_destination(0, 0, 0);
-        assert_eq!(result, 0);
+        assert_eq!(result, Ok(0));
     }
 
     #[test]
     fn jump_destination_positive() {
         let mut builder = FunctionBuilder::default();
-        let instruction = lua51::Instruction::LoadK { a: 0, mode: Bx(1) };
+        let instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) };
 
-        builder.extra_instruction(instruction);
+        builder.extra_instruction(instruction, InsertionReason::ForLoopPreserve);
         builder.instruction(instruction);
         builder.instruction(instruction);
 
         let result = builder.jump_destination(0, 1, 0);
-        assert_eq!(result, 1);
+        assert_eq!(result, Ok(1));
     }
 
     #[test]
     fn jump_destination_offset_applies() {
         let mut builder = FunctionBuilder::default();
-        let instruction = lua51::Instruction::LoadK { a: 0, mode: Bx(1) };
+        let instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) };
 
         builder.instruction(instruction);
         builder.instruction(instruction);
 
         let result = builder.jump_destination(builder.get_program_counter() - 1, -2, 2);
-        assert_eq!(result, 0);
+        assert_eq!(result, Ok(0));
     }
 
     #[test]
     fn adjusted_jump_destination() {
         let mut builder = FunctionBuilder::default();
-        let instruction = lua51::Instruction::LoadK { a: 0, mode: Bx(1) };
+        let instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) };
 
         builder.instruction(instruction);
-        builder.extra_instruction(instruction);
+        builder.extra_instruction(instruction, InsertionReason::ForLoopPreserve);
         builder.instruction(instruction);
 
         assert_eq!(builder.adjusted_jump_destination(-2), Ok(0));
@@ -391,10 +1133,10 @@ mod tests {
     #[test]
     fn adjusted_jump_destination_positive() {
         let mut builder = FunctionBuilder::default();
-        let instruction = lua51::Instruction::LoadK { a: 0, mode: Bx(1) };
+        let instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) };
 
         builder.instruction(instruction);
-        builder.extra_instruction(instruction);
+        builder.extra_instruction(instruction, InsertionReason::ForLoopPreserve);
         builder.instruction(instruction);
 
         assert_eq!(builder.adjusted_jump_destination(1), Err(LunifyError::UnexpectedForwardJump));
@@ -403,7 +1145,7 @@ mod tests {
     #[test]
     fn finalize_expands_stack() -> Result<(), LunifyError> {
         let mut builder = FunctionBuilder::default();
-        let instruction = lua51::Instruction::LoadK { a: 10, mode: Bx(1) };
+        let instruction = lua51::Instruction::LoadK { a: 10, mode: ConstantIndex(1) };
         let mut maximum_stack_size = 0;
 
         builder.instruction(instruction);
@@ -413,10 +1155,86 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn finalize_expands_stack_for_sources() -> Result<(), LunifyError> {
+        let mut builder = FunctionBuilder::default();
+        let instruction = lua51::Instruction::Concatinate {
+            a: 0,
+            mode: BC(Register(2), Register(12)),
+        };
+        let mut maximum_stack_size = 2;
+
+        builder.instruction(instruction);
+        builder.finalize(&mut maximum_stack_size, &Default::default())?;
+
+        assert_eq!(maximum_stack_size, 13);
+        Ok(())
+    }
+
+    #[test]
+    fn finalize_expands_stack_for_call_arguments() -> Result<(), LunifyError> {
+        let mut builder = FunctionBuilder::default();
+        let instruction = lua51::Instruction::Call {
+            a: 5,
+            mode: BC(Generic(3), Generic(1)),
+        };
+        let mut maximum_stack_size = 2;
+
+        builder.instruction(instruction);
+        builder.finalize(&mut maximum_stack_size, &Default::default())?;
+
+        assert_eq!(maximum_stack_size, 8);
+        Ok(())
+    }
+
+    #[test]
+    fn finalize_expands_stack_for_set_list() -> Result<(), LunifyError> {
+        let mut builder = FunctionBuilder::default();
+        let instruction = lua51::Instruction::SetList {
+            a: 2,
+            mode: BC(Generic(200), Generic(1)),
+        };
+        let mut maximum_stack_size = 3;
+
+        builder.instruction(instruction);
+        builder.finalize(&mut maximum_stack_size, &Default::default())?;
+
+        assert_eq!(maximum_stack_size, 203);
+        Ok(())
+    }
+
+    #[test]
+    fn finalize_set_list_too_large() {
+        let mut builder = FunctionBuilder::default();
+        let instruction = lua51::Instruction::SetList {
+            a: 2,
+            mode: BC(Generic(248), Generic(1)),
+        };
+
+        builder.instruction(instruction);
+
+        let result = builder.finalize(&mut 3, &Default::default());
+        assert_eq!(result, Err(LunifyError::StackTooLarge(251)));
+    }
+
+    #[test]
+    fn finalize_too_many_instructions() {
+        let mut builder = FunctionBuilder::default();
+        let mut settings = Settings::default();
+        settings.limits.max_instructions_per_function = 2;
+
+        for _ in 0..3 {
+            builder.instruction(lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) });
+        }
+
+        let result = builder.finalize(&mut 0, &settings);
+        assert_eq!(result, Err(LunifyError::LimitExceeded("max_instructions_per_function")));
+    }
+
     #[test]
     fn finalize_expands_stack_too_large() {
         let mut builder = FunctionBuilder::default();
-        let instruction = lua51::Instruction::LoadK { a: 250, mode: Bx(1) };
+        let instruction = lua51::Instruction::LoadK { a: 250, mode: ConstantIndex(1) };
         let mut maximum_stack_size = 0;
 
         builder.instruction(instruction);
@@ -428,13 +1246,13 @@ mod tests {
     #[test]
     fn finalize_adjusts_jump_destinations() -> Result<(), LunifyError> {
         let mut builder = FunctionBuilder::default();
-        let instruction = lua51::Instruction::LoadK { a: 0, mode: Bx(1) };
+        let instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) };
         let jump_instruction = lua51::Instruction::Jump { a: 0, mode: SignedBx(-1) };
 
         builder.instruction(instruction);
-        builder.extra_instruction(instruction);
-        builder.extra_instruction(jump_instruction);
-        let (instructions, _) = builder.finalize(&mut 0, &Default::default())?;
+        builder.extra_instruction(instruction, InsertionReason::ForLoopPreserve);
+        builder.extra_instruction(jump_instruction, InsertionReason::ForLoopPreserve);
+        let (instructions, ..) = builder.finalize(&mut 0, &Default::default())?;
 
         let lua51::Instruction::Jump { mode, .. } = instructions.last().unwrap() else {
             panic!()
@@ -442,4 +1260,295 @@ mod tests {
         assert_eq!(mode.0, -3);
         Ok(())
     }
+
+    fn peephole(instructions: &[lua51::Instruction]) -> Result<Vec<lua51::Instruction>, LunifyError> {
+        let mut builder = FunctionBuilder::default();
+        let mut settings = Settings::default();
+        settings.output.peephole = true;
+
+        for instruction in instructions {
+            builder.instruction(*instruction);
+        }
+
+        builder.finalize(&mut 0, &settings).map(|(instructions, ..)| instructions)
+    }
+
+    #[test]
+    fn peephole_disabled() -> Result<(), LunifyError> {
+        let mut builder = FunctionBuilder::default();
+        let instructions = [
+            lua51::Instruction::Move {
+                a: 1,
+                mode: BC(Register(1), Unused),
+            },
+            lua51::Instruction::Return {
+                a: 0,
+                mode: BC(Generic(1), Unused),
+            },
+        ];
+
+        for instruction in instructions {
+            builder.instruction(instruction);
+        }
+
+        let (output, ..) = builder.finalize(&mut 0, &Default::default())?;
+        assert_eq!(output, instructions);
+        Ok(())
+    }
+
+    #[test]
+    fn peephole_move_to_self() -> Result<(), LunifyError> {
+        let load_instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) };
+        let return_instruction = lua51::Instruction::Return {
+            a: 0,
+            mode: BC(Generic(1), Unused),
+        };
+        let move_instruction = lua51::Instruction::Move {
+            a: 1,
+            mode: BC(Register(1), Unused),
+        };
+
+        let output = peephole(&[load_instruction, move_instruction, return_instruction])?;
+        assert_eq!(output, [load_instruction, return_instruction]);
+        Ok(())
+    }
+
+    #[test]
+    fn peephole_closure_upvalue() -> Result<(), LunifyError> {
+        let instructions = [
+            lua51::Instruction::Closure { a: 1, mode: PrototypeIndex(0) },
+            lua51::Instruction::Move {
+                a: 0,
+                mode: BC(Register(0), Unused),
+            },
+            lua51::Instruction::Return {
+                a: 0,
+                mode: BC(Generic(1), Unused),
+            },
+        ];
+
+        let output = peephole(&instructions)?;
+        assert_eq!(output, instructions);
+        Ok(())
+    }
+
+    #[test]
+    fn peephole_load_nil() -> Result<(), LunifyError> {
+        let return_instruction = lua51::Instruction::Return {
+            a: 0,
+            mode: BC(Generic(1), Unused),
+        };
+
+        let output = peephole(&[
+            lua51::Instruction::LoadNil {
+                a: 0,
+                mode: BC(Register(1), Unused),
+            },
+            lua51::Instruction::LoadNil {
+                a: 2,
+                mode: BC(Register(3), Unused),
+            },
+            lua51::Instruction::LoadNil {
+                a: 3,
+                mode: BC(Register(5), Unused),
+            },
+            return_instruction,
+        ])?;
+
+        let expected = [
+            lua51::Instruction::LoadNil {
+                a: 0,
+                mode: BC(Register(5), Unused),
+            },
+            return_instruction,
+        ];
+
+        assert_eq!(output, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn peephole_load_nil_gap() -> Result<(), LunifyError> {
+        let instructions = [
+            lua51::Instruction::LoadNil {
+                a: 0,
+                mode: BC(Register(1), Unused),
+            },
+            lua51::Instruction::LoadNil {
+                a: 3,
+                mode: BC(Register(4), Unused),
+            },
+            lua51::Instruction::Return {
+                a: 0,
+                mode: BC(Generic(1), Unused),
+            },
+        ];
+
+        let output = peephole(&instructions)?;
+        assert_eq!(output, instructions);
+        Ok(())
+    }
+
+    #[test]
+    fn peephole_overwritten_get_global() -> Result<(), LunifyError> {
+        let mut builder = FunctionBuilder::default();
+        let mut settings = Settings::default();
+        settings.output.peephole = true;
+
+        let load_instruction = lua51::Instruction::LoadK { a: 2, mode: ConstantIndex(1) };
+        let return_instruction = lua51::Instruction::Return {
+            a: 0,
+            mode: BC(Generic(1), Unused),
+        };
+
+        builder.extra_instruction(
+            lua51::Instruction::GetGlobal { a: 2, mode: ConstantIndex(0) },
+            InsertionReason::ForLoopPreserve,
+        );
+        builder.instruction(load_instruction);
+        builder.instruction(return_instruction);
+
+        let (output, ..) = builder.finalize(&mut 0, &settings)?;
+        assert_eq!(output, [load_instruction, return_instruction]);
+        Ok(())
+    }
+
+    #[test]
+    fn peephole_input_get_global() -> Result<(), LunifyError> {
+        // The global might have an `__index` metamethod with side effects, like the
+        // error raised by `strict.lua`.
+        let instructions = [
+            lua51::Instruction::GetGlobal { a: 2, mode: ConstantIndex(0) },
+            lua51::Instruction::LoadK { a: 2, mode: ConstantIndex(1) },
+            lua51::Instruction::Return {
+                a: 0,
+                mode: BC(Generic(1), Unused),
+            },
+        ];
+
+        let output = peephole(&instructions)?;
+        assert_eq!(output, instructions);
+        Ok(())
+    }
+
+    #[test]
+    fn peephole_moved_input_get_global() -> Result<(), LunifyError> {
+        let mut builder = FunctionBuilder::default();
+        let mut settings = Settings::default();
+        settings.output.peephole = true;
+
+        builder.instruction(lua51::Instruction::GetGlobal { a: 2, mode: ConstantIndex(0) });
+        builder.instruction(lua51::Instruction::LoadK { a: 2, mode: ConstantIndex(1) });
+        builder.instruction(lua51::Instruction::Return {
+            a: 0,
+            mode: BC(Generic(1), Unused),
+        });
+
+        // Moving the stack accesses, like when repacking a table constructor, gives
+        // the instructions a reason, but they are still from the input.
+        for index in 0..2 {
+            builder.move_stack_accesses(index, 1, 1)?;
+        }
+
+        let (output, ..) = builder.finalize(&mut 0, &settings)?;
+        assert_eq!(output, [
+            lua51::Instruction::GetGlobal { a: 3, mode: ConstantIndex(0) },
+            lua51::Instruction::LoadK { a: 3, mode: ConstantIndex(1) },
+            lua51::Instruction::Return {
+                a: 0,
+                mode: BC(Generic(1), Unused),
+            },
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn peephole_read_get_global() -> Result<(), LunifyError> {
+        let instructions = [
+            lua51::Instruction::GetGlobal { a: 2, mode: ConstantIndex(0) },
+            lua51::Instruction::Unary {
+                a: 2,
+                mode: BC(Register(2), ConstantRegister(0, false)),
+            },
+            lua51::Instruction::Return {
+                a: 0,
+                mode: BC(Generic(1), Unused),
+            },
+        ];
+
+        let output = peephole(&instructions)?;
+        assert_eq!(output, instructions);
+        Ok(())
+    }
+
+    #[test]
+    fn peephole_jump_target() -> Result<(), LunifyError> {
+        let mut builder = FunctionBuilder::default();
+        let mut settings = Settings::default();
+        settings.output.peephole = true;
+
+        let instructions = [
+            lua51::Instruction::GetGlobal { a: 2, mode: ConstantIndex(0) },
+            lua51::Instruction::LoadK { a: 2, mode: ConstantIndex(1) },
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(-3) },
+        ];
+
+        // The jump lands on the inserted `GETGLOBAL` in front of the `LOADK`.
+        builder.extra_instruction(instructions[0], InsertionReason::ForLoopPreserve);
+        builder.instruction(instructions[1]);
+        builder.instruction(lua51::Instruction::Jump { a: 0, mode: SignedBx(-2) });
+        builder.last_instruction_offset(-1)?;
+
+        let (output, ..) = builder.finalize(&mut 0, &settings)?;
+        assert_eq!(output, instructions);
+        Ok(())
+    }
+
+    #[test]
+    fn peephole_conditionally_skipped() -> Result<(), LunifyError> {
+        let instructions = [
+            lua51::Instruction::Test {
+                a: 0,
+                mode: BC(Register(0), Generic(0)),
+            },
+            lua51::Instruction::Move {
+                a: 1,
+                mode: BC(Register(1), Unused),
+            },
+            lua51::Instruction::Return {
+                a: 0,
+                mode: BC(Generic(1), Unused),
+            },
+        ];
+
+        let output = peephole(&instructions)?;
+        assert_eq!(output, instructions);
+        Ok(())
+    }
+
+    #[test]
+    fn peephole_adjusts_jump_destinations() -> Result<(), LunifyError> {
+        let load_instruction = lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) };
+        let move_instruction = lua51::Instruction::Move {
+            a: 1,
+            mode: BC(Register(1), Unused),
+        };
+
+        let output = peephole(&[
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(1) },
+            move_instruction,
+            load_instruction,
+            move_instruction,
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(-3) },
+        ])?;
+
+        let expected = [
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(0) },
+            load_instruction,
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(-2) },
+        ];
+
+        assert_eq!(output, expected);
+        Ok(())
+    }
 }
@@ -1,107 +1,324 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use super::instruction::{lua51, Settings};
 use crate::number::Number;
+use crate::{FunctionPath, LunifyError};
 
 #[derive(Debug, PartialEq)]
-pub(crate) enum Constant {
+pub(crate) enum Constant<'a> {
     Nil,
     Boolean(bool),
     Number(Number),
-    String(String),
+    /// Strings are borrowed from the input byte code, unless they were created
+    /// during conversion.
+    String(Cow<'a, [u8]>),
+}
+
+/// Number of bytes of a string constant that are shown by
+/// [`describe`](Constant::describe) before it is truncated.
+const DESCRIBED_STRING_LENGTH: usize = 32;
+
+impl Constant<'_> {
+    /// Human readable representation of the constant for debug output. Strings
+    /// are quoted without their terminating zero, truncated and have
+    /// non-printable bytes escaped.
+    pub(crate) fn describe(&self) -> String {
+        match self {
+            Constant::Nil => "nil".to_owned(),
+            Constant::Boolean(boolean) => boolean.to_string(),
+            Constant::Number(Number::Integer(value)) => value.to_string(),
+            Constant::Number(Number::Float(value)) => format!("{value:?}"),
+            Constant::String(string) => {
+                let string = string.strip_suffix(&[0]).unwrap_or(string);
+                let shown = &string[..string.len().min(DESCRIBED_STRING_LENGTH)];
+                let escaped: String = shown.iter().flat_map(|byte| byte.escape_ascii()).map(char::from).collect();
+
+                match shown.len() < string.len() {
+                    true => format!("\"{escaped}\"..."),
+                    false => format!("\"{escaped}\""),
+                }
+            }
+        }
+    }
 }
 
-pub(super) struct ConstantManager<'a> {
-    pub(super) constants: &'a mut Vec<Constant>,
+/// Describe an index into the constants of a function together with the value
+/// of the constant, like `K7("table")`.
+pub(crate) fn describe_constant_index(constants: &[Constant], index: u64) -> String {
+    match constants.get(index as usize) {
+        Some(constant) => format!("K{index}({})", constant.describe()),
+        None => format!("K{index}(?)"),
+    }
 }
 
-impl<'a> ConstantManager<'a> {
-    pub(super) fn create_unique(&mut self, program_counter: usize) -> u64 {
+/// Position of the first occurrence of every constant that the
+/// [`ConstantManager`] looks up.
+#[derive(Default)]
+struct ConstantPositions {
+    strings: HashMap<Vec<u8>, u64>,
+    /// Numbers are looked up by their bits, so `0.0` and `-0.0` are different
+    /// constants.
+    numbers: HashMap<(bool, u64), u64>,
+    nil: Option<u64>,
+}
+
+impl ConstantPositions {
+    fn insert(&mut self, constant: &Constant, constant_index: u64) {
+        match constant {
+            Constant::String(string) => {
+                self.strings.entry(string.to_vec()).or_insert(constant_index);
+            }
+            Constant::Number(number) => {
+                self.numbers.entry(number_key(*number)).or_insert(constant_index);
+            }
+            Constant::Nil => {
+                self.nil.get_or_insert(constant_index);
+            }
+            _ => {}
+        }
+    }
+}
+
+pub(super) struct ConstantManager<'a, 'b> {
+    constants: &'a mut Vec<Constant<'b>>,
+    /// Built on first use, so functions that never add constants don't pay for
+    /// it.
+    index: Option<ConstantPositions>,
+}
+
+fn number_key(number: Number) -> (bool, u64) {
+    match number {
+        Number::Float(value) => (false, value.to_bits()),
+        Number::Integer(value) => (true, value as u64),
+    }
+}
+
+impl<'a, 'b> ConstantManager<'a, 'b> {
+    pub(super) fn new(constants: &'a mut Vec<Constant<'b>>) -> Self {
+        Self { constants, index: None }
+    }
+
+    pub(super) fn constants(&self) -> &[Constant<'b>] {
+        self.constants
+    }
+
+    fn index(&mut self) -> &mut ConstantPositions {
+        let constants = &*self.constants;
+
+        self.index.get_or_insert_with(|| {
+            let mut index = ConstantPositions::default();
+            for (constant_index, constant) in constants.iter().enumerate() {
+                index.insert(constant, constant_index as u64);
+            }
+            index
+        })
+    }
+
+    fn push(&mut self, constant: Constant<'b>) -> u64 {
         let constant_index = self.constants.len() as u64;
+        self.index().insert(&constant, constant_index);
+        self.constants.push(constant);
+        constant_index
+    }
+
+    pub(super) fn create_unique(&mut self, program_counter: usize) -> u64 {
         let mut index = 0;
 
-        let constant = loop {
+        let constant_name = loop {
             let constant_name = format!("__%lunify%__temp{program_counter}_{index}\0");
-            let constant = Constant::String(constant_name);
 
-            if !self.constants.contains(&constant) {
-                break constant;
+            if !self.index().strings.contains_key(constant_name.as_bytes()) {
+                break constant_name;
             }
 
             index += 1;
         };
 
-        self.constants.push(constant);
-        constant_index
+        self.push(Constant::String(Cow::Owned(constant_name.into_bytes())))
     }
 
-    pub(super) fn constant_for_str(&mut self, constant_str: &'static str) -> u64 {
+    pub(super) fn constant_for_str(&mut self, constant_str: &str) -> u64 {
         let zero_terminated = format!("{constant_str}\0");
 
         // If the constant already exists we don't need to add it again.
-        let matches = |constant: &_| matches!(constant, Constant::String(string) if string == zero_terminated.as_str());
-        if let Some(index) = self.constants.iter().position(matches) {
-            return index as u64;
+        if let Some(&constant_index) = self.index().strings.get(zero_terminated.as_bytes()) {
+            return constant_index;
         }
 
-        let constant_index = self.constants.len() as u64;
-        self.constants.push(Constant::String(zero_terminated));
-        constant_index
+        self.push(Constant::String(Cow::Owned(zero_terminated.into_bytes())))
+    }
+
+    pub(super) fn constant_for_number(&mut self, number: Number) -> u64 {
+        // If the constant already exists we don't need to add it again.
+        if let Some(&constant_index) = self.index().numbers.get(&number_key(number)) {
+            return constant_index;
+        }
+
+        self.push(Constant::Number(number))
     }
 
     pub(super) fn constant_nil(&mut self) -> u64 {
         // If the constant already exists we don't need to add it again.
-        let matches = |constant: &_| matches!(constant, Constant::Nil);
-        if let Some(index) = self.constants.iter().position(matches) {
-            return index as u64;
+        if let Some(constant_index) = self.index().nil {
+            return constant_index;
         }
 
-        let constant_index = self.constants.len() as u64;
-        self.constants.push(Constant::Nil);
-        constant_index
+        self.push(Constant::Nil)
     }
 }
 
+/// Move the constants that were created during conversion, which start at
+/// `original_count`, to where the output settings want them and update every
+/// instruction that references a constant.
+pub(super) fn arrange_synthetic_constants(
+    instructions: &mut [lua51::Instruction],
+    constants: &mut Vec<Constant>,
+    original_count: usize,
+    settings: &Settings,
+) {
+    let synthetic_count = constants.len() - original_count;
+    if synthetic_count == 0 || (settings.output.synthetic_constants_last && !settings.output.canonicalize_constants) {
+        return;
+    }
+
+    let mut order: Vec<usize> = (original_count..constants.len()).collect();
+
+    // Only nil, numbers and strings are ever created, so nil and numbers are sorted in
+    // front of the strings in the order they were created in.
+    if settings.output.canonicalize_constants {
+        order.sort_by_key(|&index| match &constants[index] {
+            Constant::String(string) => Some(string.as_ref()),
+            _ => None,
+        });
+    }
+
+    match settings.output.synthetic_constants_last {
+        true => {
+            order.splice(0..0, 0..original_count);
+        }
+        false => order.extend(0..original_count),
+    }
+
+    reorder_constants(instructions, constants, &order);
+}
+
+/// Move constants to the lowest indices if any constant index doesn't fit
+/// into the Bx operand of the output layout. Constants used by RK operands
+/// stay in front since those operands are even smaller, followed by the ones
+/// loaded through Bx, like the names used by `GETGLOBAL` and `SETGLOBAL`.
+pub(super) fn fit_constant_indices(
+    instructions: &mut [lua51::Instruction],
+    constants: &mut Vec<Constant>,
+    settings: &Settings,
+) -> Result<(), LunifyError> {
+    // Indices that are out of bounds are reported by validation instead.
+    let maximum_index = settings.output.layout.bx.bit_mask;
+    let constant_count = constants.len() as u64;
+    let exceeds_bx = |instructions: &mut [lua51::Instruction]| {
+        let mut exceeds = false;
+        let mut check = |index: &mut u64| exceeds |= *index > maximum_index && *index < constant_count;
+        instructions.iter_mut().for_each(|instruction| instruction.for_each_constant_index(&mut check));
+        exceeds
+    };
+
+    if !exceeds_bx(instructions) {
+        return Ok(());
+    }
+
+    let mut priorities = vec![2; constants.len()];
+    for instruction in instructions.iter_mut() {
+        instruction.for_each_constant_index(&mut |index| {
+            if let Some(priority) = priorities.get_mut(*index as usize) {
+                *priority = 1;
+            }
+        });
+
+        for operand in instruction.constant_operands_mut() {
+            if let Some(priority) = priorities.get_mut(operand.0 as usize).filter(|_| operand.1) {
+                *priority = 0;
+            }
+        }
+    }
+
+    let mut order: Vec<usize> = (0..constants.len()).collect();
+    order.sort_by_key(|&index| priorities[index]);
+    reorder_constants(instructions, constants, &order);
+
+    match exceeds_bx(instructions) {
+        true => Err(LunifyError::TooManyConstants {
+            path: FunctionPath::root(),
+            constant_count: constants.len(),
+        }),
+        false => Ok(()),
+    }
+}
+
+/// Put the constants into the given order, where `order` contains every old
+/// index once, and update every instruction that references a constant.
+fn reorder_constants(instructions: &mut [lua51::Instruction], constants: &mut Vec<Constant>, order: &[usize]) {
+    let mut new_indices = vec![0; constants.len()];
+    for (new_index, &old_index) in order.iter().enumerate() {
+        new_indices[old_index] = new_index as u64;
+    }
+
+    let mut old_constants: Vec<Option<Constant>> = constants.drain(..).map(Some).collect();
+    constants.extend(order.iter().filter_map(|&old_index| old_constants[old_index].take()));
+
+    // Indices that are out of bounds are left alone, so they are still reported by
+    // validation.
+    let mut remap = |index: &mut u64| *index = new_indices.get(*index as usize).copied().unwrap_or(*index);
+    instructions.iter_mut().for_each(|instruction| instruction.for_each_constant_index(&mut remap));
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{Constant, ConstantManager};
+    use std::borrow::Cow;
+
+    use super::{arrange_synthetic_constants, fit_constant_indices, Constant, ConstantManager};
+    use crate::function::instruction::{lua51, ConstantIndex, ConstantRegister, BC};
+    use crate::number::Number;
+    use crate::{FunctionPath, LunifyError, Settings};
 
     #[test]
     fn create_unique() {
         let mut constants = Vec::new();
-        let mut constant_manager = ConstantManager { constants: &mut constants };
+        let mut constant_manager = ConstantManager::new(&mut constants);
 
         assert_eq!(constant_manager.create_unique(9), 0);
-        assert_eq!(&constants[0], &Constant::String("__%lunify%__temp9_0\0".to_owned()));
+        assert_eq!(&constants[0], &Constant::String(Cow::Borrowed(b"__%lunify%__temp9_0\0")));
     }
 
     #[test]
     fn create_unique_twice() {
-        let mut constants = vec![Constant::String("__%lunify%__temp9_0\0".to_owned())];
-        let mut constant_manager = ConstantManager { constants: &mut constants };
+        let mut constants = vec![Constant::String(Cow::Borrowed(b"__%lunify%__temp9_0\0"))];
+        let mut constant_manager = ConstantManager::new(&mut constants);
 
         assert_eq!(constant_manager.create_unique(9), 1);
-        assert_eq!(&constants[1], &Constant::String("__%lunify%__temp9_1\0".to_owned()));
+        assert_eq!(&constants[1], &Constant::String(Cow::Borrowed(b"__%lunify%__temp9_1\0")));
     }
 
     #[test]
     fn constant_for_str() {
-        let mut constants = vec![Constant::String("constant".to_owned())];
-        let mut constant_manager = ConstantManager { constants: &mut constants };
+        let mut constants = vec![Constant::String(Cow::Borrowed(b"constant"))];
+        let mut constant_manager = ConstantManager::new(&mut constants);
 
         assert_eq!(constant_manager.constant_for_str("test"), 1);
-        assert_eq!(&constants[1], &Constant::String("test\0".to_owned()));
+        assert_eq!(&constants[1], &Constant::String(Cow::Borrowed(b"test\0")));
     }
 
     #[test]
     fn constant_for_str_duplicate() {
-        let mut constants = vec![Constant::String("test\0".to_owned()), Constant::String("constant".to_owned())];
-        let mut constant_manager = ConstantManager { constants: &mut constants };
+        let mut constants = vec![Constant::String(Cow::Borrowed(b"test\0")), Constant::String(Cow::Borrowed(b"constant"))];
+        let mut constant_manager = ConstantManager::new(&mut constants);
 
         assert_eq!(constant_manager.constant_for_str("test"), 0);
     }
 
     #[test]
     fn constant_nil() {
-        let mut constants = vec![Constant::String("constant".to_owned())];
-        let mut constant_manager = ConstantManager { constants: &mut constants };
+        let mut constants = vec![Constant::String(Cow::Borrowed(b"constant"))];
+        let mut constant_manager = ConstantManager::new(&mut constants);
 
         assert_eq!(constant_manager.constant_nil(), 1);
         assert_eq!(&constants[1], &Constant::Nil);
@@ -109,9 +326,299 @@ mod tests {
 
     #[test]
     fn constant_nil_duplicate() {
-        let mut constants = vec![Constant::Nil, Constant::String("constant".to_owned())];
-        let mut constant_manager = ConstantManager { constants: &mut constants };
+        let mut constants = vec![Constant::Nil, Constant::String(Cow::Borrowed(b"constant"))];
+        let mut constant_manager = ConstantManager::new(&mut constants);
 
         assert_eq!(constant_manager.constant_nil(), 0);
     }
+
+    #[test]
+    fn constant_for_number() {
+        let mut constants = vec![Constant::Number(Number::Float(-0.0)), Constant::Number(Number::Integer(3))];
+        let mut constant_manager = ConstantManager::new(&mut constants);
+
+        assert_eq!(constant_manager.constant_for_number(Number::Integer(3)), 1);
+        assert_eq!(constant_manager.constant_for_number(Number::Float(0.0)), 2);
+        assert_eq!(constant_manager.constant_for_number(Number::Float(3.0)), 3);
+        assert_eq!(&constants[2], &Constant::Number(Number::Float(0.0)));
+    }
+
+    /// The linear scans that the [`ConstantManager`] used before it had an
+    /// index.
+    enum LinearOperation<'a> {
+        Unique(usize),
+        Str(&'a str),
+        Nil,
+    }
+
+    fn linear(constants: &mut Vec<Constant>, operation: &LinearOperation) -> u64 {
+        let constant = match *operation {
+            LinearOperation::Unique(program_counter) => (0..)
+                .map(|index| Constant::String(Cow::Owned(format!("__%lunify%__temp{program_counter}_{index}\0").into_bytes())))
+                .find(|constant| !constants.contains(constant))
+                .unwrap(),
+            LinearOperation::Str(constant_str) => {
+                let constant = Constant::String(Cow::Owned(format!("{constant_str}\0").into_bytes()));
+                if let Some(index) = constants.iter().position(|existing| *existing == constant) {
+                    return index as u64;
+                }
+                constant
+            }
+            LinearOperation::Nil => {
+                if let Some(index) = constants.iter().position(|existing| *existing == Constant::Nil) {
+                    return index as u64;
+                }
+                Constant::Nil
+            }
+        };
+
+        constants.push(constant);
+        constants.len() as u64 - 1
+    }
+
+    #[test]
+    fn matches_linear() {
+        let initial_constants = || {
+            vec![
+                Constant::String(Cow::Borrowed(b"table\0")),
+                Constant::Boolean(false),
+                Constant::String(Cow::Borrowed(b"__%lunify%__temp3_0\0")),
+                Constant::String(Cow::Borrowed(b"table\0")),
+                Constant::Nil,
+                Constant::Nil,
+            ]
+        };
+
+        let operations = [
+            LinearOperation::Str("type"),
+            LinearOperation::Unique(3),
+            LinearOperation::Str("table"),
+            LinearOperation::Unique(3),
+            LinearOperation::Nil,
+            LinearOperation::Str("type"),
+            LinearOperation::Str("__%lunify%__temp4_0"),
+            LinearOperation::Unique(4),
+            LinearOperation::Str(""),
+        ];
+
+        let mut expected_constants = initial_constants();
+        let mut constants = initial_constants();
+        let mut constant_manager = ConstantManager::new(&mut constants);
+
+        for operation in &operations {
+            let expected = linear(&mut expected_constants, operation);
+            let index = match *operation {
+                LinearOperation::Unique(program_counter) => constant_manager.create_unique(program_counter),
+                LinearOperation::Str(constant_str) => constant_manager.constant_for_str(constant_str),
+                LinearOperation::Nil => constant_manager.constant_nil(),
+            };
+            assert_eq!(index, expected);
+        }
+
+        assert_eq!(constants, expected_constants);
+    }
+
+    #[test]
+    fn constant_nil_after_push() {
+        let mut constants = vec![Constant::String(Cow::Borrowed(b"constant"))];
+        let mut constant_manager = ConstantManager::new(&mut constants);
+
+        assert_eq!(constant_manager.constant_for_str("test"), 1);
+        assert_eq!(constant_manager.constant_nil(), 2);
+        assert_eq!(constant_manager.constant_nil(), 2);
+        assert_eq!(constant_manager.constant_for_str("test"), 1);
+    }
+
+    enum Synthetic {
+        Str(&'static str),
+        Nil,
+    }
+
+    /// Convert a function that uses the constants `type`, `next` and nil, which
+    /// are created in the given order, and a constant of the input.
+    fn arranged(creation_order: &[Synthetic], settings: &Settings) -> (Vec<lua51::Instruction>, Vec<Constant<'static>>) {
+        let mut constants = vec![Constant::String(Cow::Borrowed(b"result\0")), Constant::Number(Number::Float(9.0))];
+        let mut constant_manager = ConstantManager::new(&mut constants);
+
+        for synthetic in creation_order {
+            match synthetic {
+                Synthetic::Str(constant_str) => constant_manager.constant_for_str(constant_str),
+                Synthetic::Nil => constant_manager.constant_nil(),
+            };
+        }
+
+        let type_constant = constant_manager.constant_for_str("type");
+        let next_constant = constant_manager.constant_for_str("next");
+        let nil_constant = constant_manager.constant_nil();
+
+        let mut instructions = vec![
+            lua51::Instruction::GetGlobal {
+                a: 0,
+                mode: ConstantIndex(type_constant),
+            },
+            lua51::Instruction::LoadK {
+                a: 1,
+                mode: ConstantIndex(next_constant),
+            },
+            lua51::Instruction::Equals {
+                a: 0,
+                mode: BC(ConstantRegister(nil_constant, true), ConstantRegister(1, false)),
+            },
+            lua51::Instruction::SetGlobal { a: 0, mode: ConstantIndex(0) },
+        ];
+
+        arrange_synthetic_constants(&mut instructions, &mut constants, 2, settings);
+        (instructions, constants)
+    }
+
+    #[test]
+    fn synthetic_constants_last() {
+        let (instructions, constants) = arranged(&[Synthetic::Str("next"), Synthetic::Nil], &Settings::default());
+
+        // Without canonicalization, the constants stay in the order they were created in.
+        assert_eq!(&constants[2..], [
+            Constant::String(Cow::Borrowed(b"next\0")),
+            Constant::Nil,
+            Constant::String(Cow::Borrowed(b"type\0")),
+        ]);
+        assert_eq!(instructions[0], lua51::Instruction::GetGlobal { a: 0, mode: ConstantIndex(4) });
+    }
+
+    #[test]
+    fn canonicalize_constants() {
+        let mut settings = Settings::default();
+        settings.output.canonicalize_constants = true;
+
+        let first = arranged(&[Synthetic::Str("type"), Synthetic::Str("next"), Synthetic::Nil], &settings);
+        let second = arranged(&[Synthetic::Nil, Synthetic::Str("next"), Synthetic::Str("type")], &settings);
+        assert_eq!(first, second);
+
+        let (instructions, constants) = first;
+        assert_eq!(constants, [
+            Constant::String(Cow::Borrowed(b"result\0")),
+            Constant::Number(Number::Float(9.0)),
+            Constant::Nil,
+            Constant::String(Cow::Borrowed(b"next\0")),
+            Constant::String(Cow::Borrowed(b"type\0")),
+        ]);
+        assert_eq!(instructions, [
+            lua51::Instruction::GetGlobal { a: 0, mode: ConstantIndex(4) },
+            lua51::Instruction::LoadK { a: 1, mode: ConstantIndex(3) },
+            lua51::Instruction::Equals {
+                a: 0,
+                mode: BC(ConstantRegister(2, true), ConstantRegister(1, false)),
+            },
+            lua51::Instruction::SetGlobal { a: 0, mode: ConstantIndex(0) },
+        ]);
+    }
+
+    #[test]
+    fn synthetic_constants_first() {
+        let mut settings = Settings::default();
+        settings.output.synthetic_constants_last = false;
+        settings.output.canonicalize_constants = true;
+
+        let (instructions, constants) = arranged(&[], &settings);
+
+        assert_eq!(&constants[3..], [Constant::String(Cow::Borrowed(b"result\0")), Constant::Number(Number::Float(9.0))]);
+        assert_eq!(instructions[0], lua51::Instruction::GetGlobal { a: 0, mode: ConstantIndex(2) });
+        assert_eq!(instructions[3], lua51::Instruction::SetGlobal { a: 0, mode: ConstantIndex(3) });
+    }
+
+    /// Settings with a Bx operand that can only address 16384 constants.
+    fn small_bx_settings() -> Settings<'static> {
+        let mut settings = Settings::default();
+        settings.output.layout.bx.size = 14;
+        settings.output.layout.bx.bit_mask = (1 << 14) - 1;
+        settings
+    }
+
+    #[test]
+    fn global_names_get_low_indices() -> Result<(), LunifyError> {
+        let mut constants: Vec<Constant> = (0..20_000).map(|index| Constant::Number(Number::Integer(index))).collect();
+        let mut instructions = vec![
+            lua51::Instruction::GetGlobal {
+                a: 0,
+                mode: ConstantIndex(19_000),
+            },
+            lua51::Instruction::Add {
+                a: 0,
+                mode: BC(ConstantRegister(0, false), ConstantRegister(5, true)),
+            },
+            lua51::Instruction::LoadK {
+                a: 1,
+                mode: ConstantIndex(17_000),
+            },
+            lua51::Instruction::SetGlobal {
+                a: 0,
+                mode: ConstantIndex(19_999),
+            },
+        ];
+
+        fit_constant_indices(&mut instructions, &mut constants, &small_bx_settings())?;
+
+        // The RK constant comes first, followed by the constants loaded through Bx.
+        assert_eq!(constants.len(), 20_000);
+        assert_eq!(&constants[..5], [
+            Constant::Number(Number::Integer(5)),
+            Constant::Number(Number::Integer(17_000)),
+            Constant::Number(Number::Integer(19_000)),
+            Constant::Number(Number::Integer(19_999)),
+            Constant::Number(Number::Integer(0)),
+        ]);
+        assert_eq!(instructions, [
+            lua51::Instruction::GetGlobal { a: 0, mode: ConstantIndex(2) },
+            lua51::Instruction::Add {
+                a: 0,
+                mode: BC(ConstantRegister(0, false), ConstantRegister(0, true)),
+            },
+            lua51::Instruction::LoadK { a: 1, mode: ConstantIndex(1) },
+            lua51::Instruction::SetGlobal { a: 0, mode: ConstantIndex(3) },
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn constant_indices_that_fit_are_kept() -> Result<(), LunifyError> {
+        let mut constants: Vec<Constant> = (0..20_000).map(|index| Constant::Number(Number::Integer(index))).collect();
+        let mut instructions = vec![lua51::Instruction::GetGlobal {
+            a: 0,
+            mode: ConstantIndex(16_383),
+        }];
+
+        fit_constant_indices(&mut instructions, &mut constants, &small_bx_settings())?;
+
+        assert_eq!(constants[0], Constant::Number(Number::Integer(0)));
+        assert_eq!(instructions[0], lua51::Instruction::GetGlobal {
+            a: 0,
+            mode: ConstantIndex(16_383),
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn too_many_constants() {
+        let mut constants: Vec<Constant> = (0..20_000).map(|index| Constant::Number(Number::Integer(index))).collect();
+        let mut instructions: Vec<lua51::Instruction> = (0..20_000)
+            .map(|index| lua51::Instruction::LoadK {
+                a: 0,
+                mode: ConstantIndex(index),
+            })
+            .collect();
+
+        let result = fit_constant_indices(&mut instructions, &mut constants, &small_bx_settings());
+        let expected = LunifyError::TooManyConstants {
+            path: FunctionPath::root(),
+            constant_count: 20_000,
+        };
+        assert_eq!(result, Err(expected));
+    }
+
+    #[test]
+    fn describe_truncates_long_strings() {
+        let constant = Constant::String(Cow::Borrowed(b"abcdefghijklmnopqrstuvwxyz0123456789\0"));
+        assert_eq!(constant.describe(), r#""abcdefghijklmnopqrstuvwxyz012345"..."#);
+        assert_eq!(Constant::Boolean(true).describe(), "true");
+        assert_eq!(Constant::Nil.describe(), "nil");
+    }
 }
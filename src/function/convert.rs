@@ -1,104 +1,84 @@
-use super::builder::FunctionBuilder;
-use crate::function::instruction::{Generic, LuaInstruction, BC};
-use crate::{lua51, LunifyError, Settings};
+use super::block::lua51_leaders;
+use super::builder::{BuiltInstructions, FunctionBuilder};
+use super::constant::{Constant, ConstantManager};
+#[cfg(feature = "debug")]
+use super::instruction::LuaInstruction;
+use crate::function::instruction::{ConstantIndex, ConstantRegister, Generic, Register, Unused, BC};
+use crate::{lua51, InsertionReason, LunifyError, Settings};
 
 pub(crate) fn convert(
     instructions: Vec<lua51::Instruction>,
     line_info: Vec<i64>,
+    padding: Vec<u64>,
+    constants: &mut Vec<Constant>,
+    extended_instructions: &[usize],
     maximum_stack_size: &mut u8,
     settings: &Settings,
-) -> Result<(Vec<lua51::Instruction>, Vec<i64>), LunifyError> {
-    // If `fields_per_flush` is the same, there is nothing to convert, so return
-    // early.
-    if settings.lua51.fields_per_flush == settings.output.fields_per_flush {
-        return Ok((instructions, line_info));
+) -> Result<BuiltInstructions, LunifyError> {
+    // If `fields_per_flush` is the same or there are no `SETLIST` instructions that
+    // it would affect, there are no extended instructions that need to be
+    // collapsed and no opcodes are disallowed, there is nothing to convert, so
+    // return early.
+    let has_set_list = || instructions.iter().any(|instruction| matches!(instruction, lua51::Instruction::SetList { .. }));
+    if (settings.lua51.fields_per_flush == settings.output.fields_per_flush || !has_set_list())
+        && extended_instructions.is_empty()
+        && settings.output.disallowed_opcodes.is_empty()
+    {
+        return Ok((instructions, line_info, padding));
     }
 
+    let leaders = lua51_leaders(&instructions, extended_instructions);
     let mut builder = FunctionBuilder::default();
+    let mut constant_manager = ConstantManager::new(constants);
 
-    for (instruction, line_number) in instructions.into_iter().zip(line_info) {
+    // Registers above the original stack are never live, so lowered instructions
+    // can use them as scratch space.
+    let scratch = *maximum_stack_size as u64;
+
+    for (program_counter, ((instruction, line_number), is_leader)) in instructions.into_iter().zip(line_info).zip(leaders).enumerate() {
         #[cfg(feature = "debug")]
-        println!("[{}] {:?}", builder.get_program_counter(), instruction);
+        println!("[{}] {}", builder.get_program_counter(), instruction.describe(constant_manager.constants()));
 
         builder.set_line_number(line_number);
+        builder.set_padding(padding.get(program_counter).copied().unwrap_or(0));
+
+        if is_leader {
+            builder.mark_leader();
+        }
 
         match instruction {
             lua51::Instruction::SetList { a, mode: BC(b, c) } => {
-                let flat_index = b.0 + (settings.lua51.fields_per_flush * (c.0 - 1));
-                let page = flat_index / settings.output.fields_per_flush;
-                let offset = flat_index % settings.output.fields_per_flush;
-
-                // If b was 0 before, we need to keep it that way.
-                let b = match b.0 {
-                    0 => 0,
-                    _ => offset,
-                };
-
-                // Good case: we are on the first page and the number of entries is smaller than
-                // either `LFIELDS_PER_FLUSH`, meaning we can just insert a `SETLIST`
-                // instruction without any modification to the previous code.
-                if page == 0 && flat_index <= u64::min(settings.lua51.fields_per_flush, settings.output.fields_per_flush) {
-                    builder.instruction(lua51::Instruction::SetList {
-                        a,
-                        mode: BC(Generic(b), Generic(1)),
-                    });
-                    continue;
+                // We accept untrusted input, so make sure that the `SETLIST` instruction is
+                // sane before doing any arithmetic with it. An extended `SETLIST` has
+                // already been resolved at this point, so C will always hold the actual
+                // page.
+                if b.0 > settings.lua51.fields_per_flush || c.0 == 0 {
+                    return Err(LunifyError::MalformedSetList);
                 }
 
-                // Go back until we find some instruction that moves data to a stack position
-                // that is the same as our A, because that is where the setup starts.
-                for instruction_index in (0..(builder.get_program_counter() - 1)).rev() {
-                    let instruction = builder.get_instruction(instruction_index);
-
-                    // It might technically be possible for the element on slot A to be on the stack
-                    // already before any instructions if it is a parameter to a function call. So
-                    // we make sure that at least the first instruction will always match.
-                    // I am unsure that code like this can actually be emitted by the Lua compiler,
-                    // because any assignment of a table should start with a `NEWTABLE` instruction,
-                    // but better safe than sorry.
-                    if matches!(instruction.stack_destination(), Some(destination) if destination.start == a) || instruction_index == 0 {
-                        // Should either be `NEWTABLE` or `SETLIST`.
-                        if let lua51::Instruction::SetList { mode: BC(b, c), .. } = *instruction {
-                            let mut offset = b.0 as i64;
-                            let mut page = c.0;
-
-                            // Remove the `SETLIST` instruction.
-                            builder.remove_instruction(instruction_index);
-
-                            // Go back up the stack and update the stack positions.
-                            let mut instruction_index = instruction_index;
-                            while instruction_index < builder.get_program_counter() {
-                                let instruction = builder.get_instruction(instruction_index);
-
-                                if let Some(stack_destination) = instruction.stack_destination() {
-                                    if offset + stack_destination.start as i64 - 1 == (a + settings.output.fields_per_flush) as i64 {
-                                        // Add a new `SETLIST` instruction.
-                                        builder.insert_extra_instruction(instruction_index, lua51::Instruction::SetList {
-                                            a,
-                                            mode: BC(Generic(settings.output.fields_per_flush), Generic(page)),
-                                        });
-
-                                        offset -= settings.output.fields_per_flush as i64;
-                                        page += 1;
-                                        instruction_index += 1;
-                                        continue;
-                                    }
-                                }
-
-                                builder.get_instruction(instruction_index).move_stack_accesses(a, offset);
-                                instruction_index += 1;
-                            }
-                        }
-
-                        break;
+                let page = match settings.lua51.fields_per_flush == settings.output.fields_per_flush {
+                    true => {
+                        builder.instruction(instruction);
+                        c.0
                     }
+                    false => {
+                        builder.set_padding(0);
+                        convert_set_list(&mut builder, a, b.0, c.0, settings)?
+                    }
+                };
+
+                if page > settings.output.layout.c.bit_mask {
+                    return Err(LunifyError::UnsupportedSetListExtension);
                 }
 
-                // Append the original instruction.
-                builder.instruction(lua51::Instruction::SetList {
-                    a,
-                    mode: BC(Generic(b), Generic(page + 1)),
-                });
+                // An extended `SETLIST` used two instruction slots in the input, so we need to
+                // account for that when adjusting jump destinations.
+                if extended_instructions.contains(&program_counter) {
+                    builder.last_instruction_merged()?;
+                }
+            }
+            instruction if settings.output.disallowed_opcodes.contains_instruction(&instruction) => {
+                lower_instruction(&mut builder, &mut constant_manager, instruction, scratch, program_counter, settings)?;
             }
             instruction => builder.instruction(instruction),
         };
@@ -107,11 +87,211 @@ pub(crate) fn convert(
     builder.finalize(maximum_stack_size, settings)
 }
 
+/// Replace an instruction that is disallowed in the output settings with
+/// equivalent instructions that are supported by the target VM.
+fn lower_instruction(
+    builder: &mut FunctionBuilder,
+    constant_manager: &mut ConstantManager,
+    instruction: lua51::Instruction,
+    scratch: u64,
+    program_counter: usize,
+    settings: &Settings,
+) -> Result<(), LunifyError> {
+    let instructions = match instruction {
+        // R(A) := #R(B) becomes R(A) := shim(R(B)).
+        lua51::Instruction::Length { a, mode: BC(b, _) } => {
+            let shim_constant = constant_manager.constant_for_str(settings.output.length_shim);
+
+            vec![
+                lua51::Instruction::GetGlobal {
+                    a: scratch,
+                    mode: ConstantIndex(shim_constant),
+                },
+                lua51::Instruction::Move {
+                    a: scratch + 1,
+                    mode: BC(b, Unused),
+                },
+                lua51::Instruction::Call {
+                    a: scratch,
+                    mode: BC(Generic(2), Generic(2)),
+                },
+                lua51::Instruction::Move {
+                    a,
+                    mode: BC(Register(scratch), Unused),
+                },
+            ]
+        }
+        // R(A) := RK(B) % RK(C) becomes R(A) := RK(B) - math.floor(RK(B) / RK(C)) * RK(C),
+        // which is how Lua 5.1 defines the modulo. The key "floor" is kept in a
+        // register, so we don't need to worry about the constant index being too
+        // large for an RK operand.
+        lua51::Instruction::Modulo { a, mode: BC(b, c) } => {
+            let math_constant = constant_manager.constant_for_str("math");
+            let floor_constant = constant_manager.constant_for_str("floor");
+
+            vec![
+                lua51::Instruction::GetGlobal {
+                    a: scratch,
+                    mode: ConstantIndex(math_constant),
+                },
+                lua51::Instruction::LoadK {
+                    a: scratch + 1,
+                    mode: ConstantIndex(floor_constant),
+                },
+                lua51::Instruction::GetTable {
+                    a: scratch,
+                    mode: BC(Register(scratch), ConstantRegister(scratch + 1, false)),
+                },
+                lua51::Instruction::Divide { a: scratch + 1, mode: BC(b, c) },
+                lua51::Instruction::Call {
+                    a: scratch,
+                    mode: BC(Generic(2), Generic(2)),
+                },
+                lua51::Instruction::Multiply {
+                    a: scratch,
+                    mode: BC(ConstantRegister(scratch, false), c),
+                },
+                lua51::Instruction::Subtract {
+                    a,
+                    mode: BC(b, ConstantRegister(scratch, false)),
+                },
+            ]
+        }
+        instruction => {
+            return Err(LunifyError::DisallowedOpcode {
+                name: instruction.name(),
+                program_counter,
+            })
+        }
+    };
+
+    // The first instruction takes the place of the original one, so jumps to it
+    // land on the start of the lowered sequence.
+    for (index, instruction) in instructions.into_iter().enumerate() {
+        match index {
+            0 => {
+                builder.instruction(instruction);
+                builder.last_instruction_reason(InsertionReason::OpcodeLowering)?;
+            }
+            _ => builder.extra_instruction(instruction, InsertionReason::OpcodeLowering),
+        }
+    }
+
+    Ok(())
+}
+
+fn convert_set_list(builder: &mut FunctionBuilder, a: u64, b: u64, c: u64, settings: &Settings) -> Result<u64, LunifyError> {
+    let flat_index = b + (settings.lua51.fields_per_flush * (c - 1));
+    let is_open = b == 0;
+
+    // If the last page is full, the offset would be zero, which has the behavior of
+    // an open `SETLIST`. So we flush a full page instead.
+    let (page, offset) = match flat_index % settings.output.fields_per_flush {
+        0 if !is_open => (flat_index / settings.output.fields_per_flush - 1, settings.output.fields_per_flush),
+        offset => (flat_index / settings.output.fields_per_flush, offset),
+    };
+
+    // If b was 0 before, we need to keep it that way.
+    let b = match b {
+        0 => 0,
+        _ => offset,
+    };
+
+    // Good case: we are on the first page and the number of entries is smaller than
+    // either `LFIELDS_PER_FLUSH`, meaning we can just insert a `SETLIST`
+    // instruction without any modification to the previous code.
+    if page == 0 && flat_index <= u64::min(settings.lua51.fields_per_flush, settings.output.fields_per_flush) {
+        builder.instruction(lua51::Instruction::SetList {
+            a,
+            mode: BC(Generic(b), Generic(1)),
+        });
+        return Ok(1);
+    }
+
+    // Go back until we find some instruction that moves data to a stack position
+    // that is the same as our A, because that is where the setup starts.
+    for (depth, instruction_index) in (0..builder.get_program_counter().saturating_sub(1)).rev().enumerate() {
+        // Crafted byte code could make us scan far back for every `SETLIST`. Giving up
+        // silently would leave the table constructor half rewritten, so this is an error.
+        if depth as u64 >= settings.limits.max_setlist_scan_depth {
+            return Err(LunifyError::LimitExceeded("max_setlist_scan_depth"));
+        }
+
+        let instruction = builder.get_instruction(instruction_index)?;
+
+        // It might technically be possible for the element on slot A to be on the stack
+        // already before any instructions if it is a parameter to a function call. So
+        // we make sure that at least the first instruction will always match.
+        // I am unsure that code like this can actually be emitted by the Lua compiler,
+        // because any assignment of a table should start with a `NEWTABLE` instruction,
+        // but better safe than sorry.
+        if matches!(instruction.stack_destination(), Some(destination) if destination.start == a) || instruction_index == 0 {
+            // Should either be `NEWTABLE` or `SETLIST`.
+            if let lua51::Instruction::SetList { mode: BC(b, c), .. } = *instruction {
+                // The previous `SETLIST` has to store fewer elements than this one, otherwise
+                // the pages are out of order and we would remove the wrong instruction.
+                let previous_index = b.0 + settings.output.fields_per_flush * c.0.saturating_sub(1);
+                if previous_index > flat_index || (previous_index == flat_index && !is_open) {
+                    return Err(LunifyError::MalformedSetList);
+                }
+
+                let mut offset = b.0 as i64;
+                let mut page = c.0;
+
+                // Remove the `SETLIST` instruction.
+                builder.remove_instruction(instruction_index)?;
+
+                // Inserting a `SETLIST` in the middle of the elements is only safe if they are
+                // always executed in order, otherwise we move all of them up as a whole.
+                if !builder.is_basic_block(instruction_index) {
+                    let flat_index = (!is_open).then_some(flat_index);
+                    return builder.repack_set_list(instruction_index, a, b.0, c.0, flat_index, settings);
+                }
+
+                // Go back up the stack and update the stack positions.
+                let mut instruction_index = instruction_index;
+                while instruction_index < builder.get_program_counter() {
+                    let instruction = builder.get_instruction(instruction_index)?;
+
+                    if let Some(stack_destination) = instruction.stack_destination() {
+                        if offset + stack_destination.start as i64 - 1 == (a + settings.output.fields_per_flush) as i64 {
+                            // Add a new `SETLIST` instruction.
+                            builder.insert_extra_instruction(instruction_index, lua51::Instruction::SetList {
+                                a,
+                                mode: BC(Generic(settings.output.fields_per_flush), Generic(page)),
+                            }, InsertionReason::SetListRewrite)?;
+
+                            offset -= settings.output.fields_per_flush as i64;
+                            page += 1;
+                            instruction_index += 1;
+                            continue;
+                        }
+                    }
+
+                    builder.move_stack_accesses(instruction_index, a, offset)?;
+                    instruction_index += 1;
+                }
+            }
+
+            break;
+        }
+    }
+
+    // Append the original instruction.
+    builder.instruction(lua51::Instruction::SetList {
+        a,
+        mode: BC(Generic(b), Generic(page + 1)),
+    });
+
+    Ok(page + 1)
+}
+
 #[cfg(test)]
 mod tests {
     use super::{lua51, BC};
     use crate::function::convert;
-    use crate::function::instruction::{Bx, Generic, Unused};
+    use crate::function::constant::Constant;
+    use crate::function::instruction::{ConstantIndex, ConstantRegister, Generic, Register, SignedBx, Unused};
     use crate::{lua50, LunifyError, Settings};
 
     fn test_settings() -> Settings<'static> {
@@ -127,13 +307,18 @@ mod tests {
             ..lua51::Settings::default()
         };
 
-        Settings { lua50, lua51, output }
+        Settings {
+            lua50,
+            lua51,
+            output,
+            ..Default::default()
+        }
     }
 
     fn lua51_setlist(size: u64, settings: Settings) -> Vec<lua51::Instruction> {
         let mut instructions = vec![lua51::Instruction::NewTable {
             a: 0,
-            mode: BC(Unused, Unused),
+            mode: BC(Generic(0), Generic(0)),
         }];
 
         for index in 0..size {
@@ -142,7 +327,7 @@ mod tests {
 
             instructions.push(lua51::Instruction::LoadK {
                 a: stack_position,
-                mode: Bx(0),
+                mode: ConstantIndex(0),
             });
 
             if stack_position == settings.lua51.fields_per_flush || index + 1 == size {
@@ -159,7 +344,7 @@ mod tests {
     fn output_setlist(size: u64, settings: Settings) -> Vec<lua51::Instruction> {
         let mut instructions = vec![lua51::Instruction::NewTable {
             a: 0,
-            mode: BC(Unused, Unused),
+            mode: BC(Generic(0), Generic(0)),
         }];
 
         for index in 0..size {
@@ -168,7 +353,7 @@ mod tests {
 
             instructions.push(lua51::Instruction::LoadK {
                 a: stack_position,
-                mode: Bx(0),
+                mode: ConstantIndex(0),
             });
 
             if stack_position == settings.output.fields_per_flush || index + 1 == size {
@@ -187,7 +372,7 @@ mod tests {
         let instructions = lua51_setlist(count, settings);
         let instruction_count = instructions.len();
 
-        let (instructions, _) = convert(instructions, vec![0; instruction_count], &mut 2, &settings)?;
+        let (instructions, ..) = convert(instructions, vec![0; instruction_count], Vec::new(), &mut Vec::new(), &[], &mut 2, &settings)?;
         let expected = output_setlist(count, settings);
 
         assert_eq!(instructions, expected);
@@ -214,26 +399,49 @@ mod tests {
         set_list_test(20)
     }
 
+    #[test]
+    fn convert_set_list_exactly_output_flush() -> Result<(), LunifyError> {
+        set_list_test(8)
+    }
+
+    #[test]
+    fn convert_set_list_exact_multiple_of_output_flush() -> Result<(), LunifyError> {
+        set_list_test(16)
+    }
+
+    #[test]
+    fn convert_set_list_scan_depth_exceeded() {
+        let mut settings = test_settings();
+        settings.limits.max_setlist_scan_depth = 4;
+
+        // The second `SETLIST` needs to look back five instructions to find the first one.
+        let instructions = lua51_setlist(10, settings);
+        let instruction_count = instructions.len();
+
+        let result = convert(instructions, vec![0; instruction_count], Vec::new(), &mut Vec::new(), &[], &mut 2, &settings);
+        assert_eq!(result, Err(LunifyError::LimitExceeded("max_setlist_scan_depth")));
+    }
+
     #[test]
     fn convert_set_list_from_parameters_bigger_than_50_flush() -> Result<(), LunifyError> {
         let settings = test_settings();
         let instructions = vec![
-            lua51::Instruction::LoadK { a: 5, mode: Bx(0) },
+            lua51::Instruction::LoadK { a: 5, mode: ConstantIndex(0) },
             lua51::Instruction::SetList {
                 a: 0,
                 mode: BC(Generic(5), Generic(1)),
             },
-            lua51::Instruction::LoadK { a: 1, mode: Bx(0) },
+            lua51::Instruction::LoadK { a: 1, mode: ConstantIndex(0) },
             lua51::Instruction::SetList {
                 a: 0,
                 mode: BC(Generic(1), Generic(2)),
             },
         ];
 
-        let (instructions, _) = convert(instructions, vec![0; 12], &mut 2, &settings)?;
+        let (instructions, ..) = convert(instructions, vec![0; 12], Vec::new(), &mut Vec::new(), &[], &mut 2, &settings)?;
         let expected = vec![
-            lua51::Instruction::LoadK { a: 5, mode: Bx(0) },
-            lua51::Instruction::LoadK { a: 6, mode: Bx(0) },
+            lua51::Instruction::LoadK { a: 5, mode: ConstantIndex(0) },
+            lua51::Instruction::LoadK { a: 6, mode: ConstantIndex(0) },
             lua51::Instruction::SetList {
                 a: 0,
                 mode: BC(Generic(6), Generic(1)),
@@ -243,4 +451,368 @@ mod tests {
         assert_eq!(instructions, expected);
         Ok(())
     }
+
+    #[test]
+    fn convert_set_list_across_basic_blocks() -> Result<(), LunifyError> {
+        let settings = test_settings();
+        let load = |a: u64| lua51::Instruction::LoadK { a, mode: ConstantIndex(0) };
+        let set_list = |b: u64, c: u64| lua51::Instruction::SetList {
+            a: 0,
+            mode: BC(Generic(b), Generic(c)),
+        };
+        let condition = |a: u64| {
+            [
+                lua51::Instruction::Equals {
+                    a: 0,
+                    mode: BC(ConstantRegister(0, true), ConstantRegister(0, true)),
+                },
+                lua51::Instruction::Jump { a: 0, mode: SignedBx(2) },
+                load(a),
+                lua51::Instruction::Jump { a: 0, mode: SignedBx(1) },
+                lua51::Instruction::LoadBool {
+                    a,
+                    mode: BC(Generic(0), Generic(0)),
+                },
+            ]
+        };
+
+        // `{ 9, 9, 9, 9, 9, 9, 9, 9, 9 == 9 and 9 or false }`, where the last element
+        // is loaded in two branches. Inserting a `SETLIST` before the first write to
+        // its stack position would only execute it in one of them.
+        let instructions = [
+            vec![load(1), load(2), load(3), load(4), load(5), set_list(5, 1), load(1), load(2), load(3)],
+            condition(4).to_vec(),
+            vec![set_list(4, 2)],
+        ]
+        .concat();
+        let instruction_count = instructions.len();
+
+        let (instructions, ..) = convert(instructions, vec![0; instruction_count], Vec::new(), &mut Vec::new(), &[], &mut 6, &settings)?;
+        let expected = [
+            vec![load(1), load(2), load(3), load(4), load(5), load(6), load(7), load(8)],
+            condition(9).to_vec(),
+            vec![set_list(8, 1), lua51::Instruction::Move {
+                a: 1,
+                mode: BC(Register(9), Unused),
+            }, set_list(1, 2)],
+        ]
+        .concat();
+
+        assert_eq!(instructions, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn convert_set_list_b_too_big() {
+        let settings = test_settings();
+        let instructions = vec![lua51::Instruction::SetList {
+            a: 0,
+            mode: BC(Generic(6), Generic(1)),
+        }];
+
+        let result = convert(instructions, vec![0; 1], Vec::new(), &mut Vec::new(), &[], &mut 2, &settings);
+        assert_eq!(result, Err(LunifyError::MalformedSetList));
+    }
+
+    #[test]
+    fn convert_set_list_page_zero() {
+        let settings = test_settings();
+        let instructions = vec![lua51::Instruction::SetList {
+            a: 0,
+            mode: BC(Generic(1), Generic(0)),
+        }];
+
+        let result = convert(instructions, vec![0; 1], Vec::new(), &mut Vec::new(), &[], &mut 2, &settings);
+        assert_eq!(result, Err(LunifyError::MalformedSetList));
+    }
+
+    #[test]
+    fn convert_set_list_pages_out_of_order() {
+        let settings = test_settings();
+        let instructions = vec![
+            lua51::Instruction::NewTable {
+                a: 0,
+                mode: BC(Generic(0), Generic(0)),
+            },
+            lua51::Instruction::LoadK { a: 1, mode: ConstantIndex(0) },
+            lua51::Instruction::SetList {
+                a: 0,
+                mode: BC(Generic(5), Generic(3)),
+            },
+            lua51::Instruction::LoadK { a: 1, mode: ConstantIndex(0) },
+            lua51::Instruction::SetList {
+                a: 0,
+                mode: BC(Generic(5), Generic(2)),
+            },
+        ];
+
+        let result = convert(instructions, vec![0; 5], Vec::new(), &mut Vec::new(), &[], &mut 2, &settings);
+        assert_eq!(result, Err(LunifyError::MalformedSetList));
+    }
+
+    #[test]
+    fn convert_set_list_unshiftable_concatinate() {
+        let settings = test_settings();
+        let instructions = vec![
+            lua51::Instruction::SetList {
+                a: 2,
+                mode: BC(Generic(5), Generic(1)),
+            },
+            lua51::Instruction::Concatinate {
+                a: 3,
+                mode: BC(Register(1), Register(3)),
+            },
+            lua51::Instruction::SetList {
+                a: 2,
+                mode: BC(Generic(1), Generic(2)),
+            },
+        ];
+
+        let result = convert(instructions, vec![0; 3], Vec::new(), &mut Vec::new(), &[], &mut 2, &settings);
+        assert_eq!(result, Err(LunifyError::UnshiftableInstruction));
+    }
+
+    #[test]
+    fn convert_set_list_shift_concatinate() -> Result<(), LunifyError> {
+        let settings = test_settings();
+        let instructions = vec![
+            lua51::Instruction::SetList {
+                a: 0,
+                mode: BC(Generic(5), Generic(1)),
+            },
+            lua51::Instruction::Concatinate {
+                a: 1,
+                mode: BC(Register(1), Register(2)),
+            },
+            lua51::Instruction::SetList {
+                a: 0,
+                mode: BC(Generic(1), Generic(2)),
+            },
+        ];
+
+        let (instructions, ..) = convert(instructions, vec![0; 3], Vec::new(), &mut Vec::new(), &[], &mut 2, &settings)?;
+        let expected = vec![
+            lua51::Instruction::Concatinate {
+                a: 6,
+                mode: BC(Register(6), Register(7)),
+            },
+            lua51::Instruction::SetList {
+                a: 0,
+                mode: BC(Generic(6), Generic(1)),
+            },
+        ];
+
+        assert_eq!(instructions, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn convert_set_list_shift_load_nil() -> Result<(), LunifyError> {
+        let settings = test_settings();
+        let instructions = vec![
+            lua51::Instruction::NewTable {
+                a: 0,
+                mode: BC(Generic(0), Generic(0)),
+            },
+            lua51::Instruction::SetList {
+                a: 0,
+                mode: BC(Generic(5), Generic(1)),
+            },
+            // `local a, b` inside of the table constructor.
+            lua51::Instruction::LoadNil {
+                a: 1,
+                mode: BC(Register(2), Unused),
+            },
+            lua51::Instruction::SetList {
+                a: 0,
+                mode: BC(Generic(2), Generic(2)),
+            },
+        ];
+
+        let (instructions, ..) = convert(instructions, vec![0; 4], Vec::new(), &mut Vec::new(), &[], &mut 4, &settings)?;
+        let expected = vec![
+            lua51::Instruction::NewTable {
+                a: 0,
+                mode: BC(Generic(0), Generic(0)),
+            },
+            lua51::Instruction::LoadNil {
+                a: 6,
+                mode: BC(Register(7), Unused),
+            },
+            lua51::Instruction::SetList {
+                a: 0,
+                mode: BC(Generic(7), Generic(1)),
+            },
+        ];
+
+        assert_eq!(instructions, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn convert_set_list_unshiftable_load_nil() {
+        let settings = test_settings();
+        let instructions = vec![
+            lua51::Instruction::SetList {
+                a: 2,
+                mode: BC(Generic(5), Generic(1)),
+            },
+            lua51::Instruction::LoadNil {
+                a: 1,
+                mode: BC(Register(3), Unused),
+            },
+            lua51::Instruction::SetList {
+                a: 2,
+                mode: BC(Generic(1), Generic(2)),
+            },
+        ];
+
+        let result = convert(instructions, vec![0; 3], Vec::new(), &mut Vec::new(), &[], &mut 4, &settings);
+        assert_eq!(result, Err(LunifyError::UnshiftableInstruction));
+    }
+
+    #[test]
+    fn convert_set_list_extended() -> Result<(), LunifyError> {
+        let settings = Settings::default();
+        let instructions = vec![
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(2) },
+            lua51::Instruction::SetList {
+                a: 0,
+                mode: BC(Generic(1), Generic(2)),
+            },
+            lua51::Instruction::LoadK { a: 1, mode: ConstantIndex(0) },
+        ];
+
+        let (instructions, line_info, _) = convert(instructions, vec![0; 3], Vec::new(), &mut Vec::new(), &[1], &mut 2, &settings)?;
+        let expected = vec![
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(1) },
+            lua51::Instruction::SetList {
+                a: 0,
+                mode: BC(Generic(1), Generic(2)),
+            },
+            lua51::Instruction::LoadK { a: 1, mode: ConstantIndex(0) },
+        ];
+
+        assert_eq!(instructions, expected);
+        assert_eq!(line_info.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn convert_set_list_extended_too_big() {
+        let settings = Settings::default();
+        let instructions = vec![lua51::Instruction::SetList {
+            a: 0,
+            mode: BC(Generic(1), Generic(600)),
+        }];
+
+        let result = convert(instructions, vec![0; 1], Vec::new(), &mut Vec::new(), &[0], &mut 2, &settings);
+        assert_eq!(result, Err(LunifyError::UnsupportedSetListExtension));
+    }
+
+    fn lowering_settings(names: &[&str]) -> Result<Settings<'static>, LunifyError> {
+        let mut settings = Settings::default();
+        settings.output.disallowed_opcodes = lua51::OpcodeSet::from_names(names)?;
+        Ok(settings)
+    }
+
+    #[test]
+    fn convert_lower_length() -> Result<(), LunifyError> {
+        let settings = lowering_settings(&["LEN"])?;
+        let instructions = vec![lua51::Instruction::Length {
+            a: 0,
+            mode: BC(Register(1), Unused),
+        }];
+
+        let mut constants = Vec::new();
+        let mut maximum_stack_size = 2;
+        let (instructions, ..) = convert(instructions, vec![0; 1], Vec::new(), &mut constants, &[], &mut maximum_stack_size, &settings)?;
+
+        let expected = vec![
+            lua51::Instruction::GetGlobal { a: 2, mode: ConstantIndex(0) },
+            lua51::Instruction::Move {
+                a: 3,
+                mode: BC(Register(1), Unused),
+            },
+            lua51::Instruction::Call {
+                a: 2,
+                mode: BC(Generic(2), Generic(2)),
+            },
+            lua51::Instruction::Move {
+                a: 0,
+                mode: BC(Register(2), Unused),
+            },
+        ];
+
+        assert_eq!(instructions, expected);
+        assert_eq!(constants, [Constant::String(b"__len_shim\0".as_slice().into())]);
+        assert_eq!(maximum_stack_size, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn convert_lower_modulo() -> Result<(), LunifyError> {
+        let settings = lowering_settings(&["MOD"])?;
+        let instructions = vec![
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(0) },
+            lua51::Instruction::Modulo {
+                a: 0,
+                mode: BC(ConstantRegister(0, false), ConstantRegister(0, true)),
+            },
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(-3) },
+        ];
+
+        let mut constants = Vec::new();
+        let (instructions, ..) = convert(instructions, vec![0; 3], Vec::new(), &mut constants, &[], &mut 1, &settings)?;
+
+        let expected = vec![
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(0) },
+            lua51::Instruction::GetGlobal { a: 1, mode: ConstantIndex(0) },
+            lua51::Instruction::LoadK { a: 2, mode: ConstantIndex(1) },
+            lua51::Instruction::GetTable {
+                a: 1,
+                mode: BC(Register(1), ConstantRegister(2, false)),
+            },
+            lua51::Instruction::Divide {
+                a: 2,
+                mode: BC(ConstantRegister(0, false), ConstantRegister(0, true)),
+            },
+            lua51::Instruction::Call {
+                a: 1,
+                mode: BC(Generic(2), Generic(2)),
+            },
+            lua51::Instruction::Multiply {
+                a: 1,
+                mode: BC(ConstantRegister(1, false), ConstantRegister(0, true)),
+            },
+            lua51::Instruction::Subtract {
+                a: 0,
+                mode: BC(ConstantRegister(0, false), ConstantRegister(1, false)),
+            },
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(-9) },
+        ];
+
+        assert_eq!(instructions, expected);
+        assert_eq!(constants.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn convert_disallowed_opcode() -> Result<(), LunifyError> {
+        let settings = lowering_settings(&["VARARG"])?;
+        let instructions = vec![
+            lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(0) },
+            lua51::Instruction::VarArg {
+                a: 0,
+                mode: BC(Generic(0), Unused),
+            },
+        ];
+
+        let result = convert(instructions, vec![0; 2], Vec::new(), &mut Vec::new(), &[], &mut 2, &settings);
+        assert_eq!(result, Err(LunifyError::DisallowedOpcode {
+            name: "VARARG",
+            program_counter: 1,
+        }));
+        Ok(())
+    }
 }
@@ -0,0 +1,102 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use super::source::{output_source_file, ParentSource};
+use super::{Function, Settings};
+use crate::format::LuaVersion;
+use crate::serialization::ByteStream;
+use crate::{FunctionPath, LunifyError};
+
+/// Information about a function found by
+/// [list_functions](crate::list_functions).
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FunctionInfo {
+    /// Indices of the nested functions that lead to this function. The main
+    /// function has an empty path. This can be passed to
+    /// [extract](crate::extract).
+    pub path: FunctionPath,
+    /// The line the function is defined on.
+    pub line_defined: i64,
+    /// The last line of the function. Lua 5.0 doesn't store it, so it is the
+    /// same as `line_defined` for Lua 5.0 byte code.
+    pub last_line_defined: i64,
+    /// The number of upvalues the function captures.
+    pub upvalue_count: u8,
+    /// The number of fixed parameters of the function.
+    pub parameter_count: u8,
+    /// Whether the function takes a variable number of arguments.
+    pub is_variadic: bool,
+    /// The number of functions nested directly inside of this function.
+    pub function_count: usize,
+    /// The number of number constants of the function.
+    pub number_constant_count: usize,
+}
+
+impl<'a> Function<'a> {
+    /// Collect information about a function and all of its nested functions in
+    /// depth-first order without decoding them.
+    pub(crate) fn list(
+        byte_stream: &mut ByteStream<'a>,
+        version: LuaVersion,
+        settings: &Settings,
+    ) -> Result<Vec<FunctionInfo>, LunifyError> {
+        let mut functions = Vec::new();
+        Self::skip(byte_stream, version, settings, &mut Vec::new(), Some(&mut functions))?;
+        Ok(functions)
+    }
+
+    /// Parse and convert the nested function at `path` and its own nested
+    /// functions, so it can be written as the main function of a new chunk.
+    /// Everything around it is skipped without being decoded.
+    pub(crate) fn extract(
+        byte_stream: &mut ByteStream<'a>,
+        version: LuaVersion,
+        settings: &Settings,
+        path: &[usize],
+    ) -> Result<Self, LunifyError> {
+        let mut parent_source: Option<String> = None;
+
+        for (depth, &index) in path.iter().enumerate() {
+            let (source_file, info) = Self::skip_to_functions(byte_stream, version)?;
+
+            if index >= info.function_count {
+                return Err(LunifyError::InvalidFunctionPath(FunctionPath::from(&path[..=depth])));
+            }
+
+            // Nested functions with an empty source file inherit the one of their parent,
+            // so we need to keep track of it like the loader does.
+            if !source_file.is_empty() || parent_source.is_none() {
+                parent_source = Some(source_file.iter().map(|&byte| byte as char).collect());
+            }
+
+            for _index in 0..index {
+                Self::skip(byte_stream, version, settings, &mut Vec::new(), None)?;
+            }
+        }
+
+        let parent_output_source = parent_source.as_deref().map(|parent_source| output_source_file(parent_source, false, settings));
+        let parent_source = parent_source
+            .as_deref()
+            .zip(parent_output_source.as_deref())
+            .map(|(input, output)| ParentSource { input, output });
+
+        let mut function = Self::parse(byte_stream, version, settings, parent_source, &mut path.to_vec(), false, false)?;
+
+        if function.upvalue_count > 0 && !settings.extract_closures {
+            return Err(LunifyError::CannotExtractClosure {
+                upvalue_count: function.upvalue_count,
+            });
+        }
+
+        // The main function of a chunk can't inherit its source file, so it needs to
+        // be written even if it is the same as the one of the parent. This also means
+        // the function can't be copied from the input as is.
+        if function.is_source_shared {
+            function.is_source_shared = false;
+            function.original = None;
+        }
+
+        Ok(function)
+    }
+}
@@ -0,0 +1,474 @@
+use super::builder::{overwrites, skips_next};
+use super::constant::{Constant, ConstantManager};
+use crate::function::instruction::{ConstantIndex, ConstantRegister, BC};
+use crate::lua51::Instruction;
+use crate::number::Number;
+use crate::LunifyError;
+
+/// Largest magnitude of an integral number that every output number type
+/// represents exactly, including 32-bit floats.
+const MAXIMUM_EXACT_INTEGER: f64 = (1 << 24) as f64;
+
+/// Replace arithmetic on two number constants with a `LOADK` of the result.
+/// This applies to instructions with two constant operands as well as to
+/// instructions whose operands are loaded by the two `LOADK` instructions in
+/// front of them, as long as those registers are overwritten before they are
+/// read again. Returns the number of folded instructions.
+///
+/// The output format isn't known yet, so only results that every number type
+/// represents exactly are folded. Folded instructions lose their padding.
+pub(super) fn fold_constants(
+    instructions: &mut Vec<Instruction>,
+    line_info: &mut Vec<i64>,
+    padding: &mut Vec<u64>,
+    constants: &mut Vec<Constant>,
+) -> Result<usize, LunifyError> {
+    let mut destinations = Vec::with_capacity(instructions.len());
+    let mut is_jump_target = vec![false; instructions.len() + 1];
+
+    for (program_counter, instruction) in instructions.iter().enumerate() {
+        let destination = match *instruction {
+            Instruction::Jump { mode, .. } | Instruction::ForLoop { mode, .. } | Instruction::ForPrep { mode, .. } => {
+                let destination = usize::try_from(program_counter as i64 + 1 + mode.0).map_err(|_| LunifyError::JumpOutOfBounds)?;
+
+                if let Some(is_jump_target) = is_jump_target.get_mut(destination) {
+                    *is_jump_target = true;
+                }
+
+                Some(destination)
+            }
+            _ => None,
+        };
+
+        destinations.push(destination);
+    }
+
+    // Writing to a register that a closure captured changes the upvalue, so those
+    // registers are never treated as dead.
+    let mut captured_registers = Vec::new();
+    for (program_counter, instruction) in instructions.iter().enumerate() {
+        if let Instruction::Closure { .. } = instruction {
+            let upvalues = instructions.iter().skip(program_counter + 1);
+            for upvalue in upvalues.take_while(|upvalue| matches!(upvalue, Instruction::Move { .. } | Instruction::GetUpValue { .. })) {
+                if let Instruction::Move { mode: BC(b, _), .. } = upvalue {
+                    captured_registers.push(b.0);
+                }
+            }
+        }
+    }
+
+    let has_line_info = line_info.len() == instructions.len();
+    let has_padding = padding.len() == instructions.len();
+    let mut constant_manager = ConstantManager::new(constants);
+    let mut folded_count = 0;
+    let mut program_counter = 0;
+
+    while let Some(&instruction) = instructions.get(program_counter) {
+        let Some((a, BC(b, c))) = arithmetic_operands(&instruction) else {
+            program_counter += 1;
+            continue;
+        };
+
+        let number = |operand: ConstantRegister, constants: &[Constant]| match constants.get(operand.0 as usize) {
+            Some(Constant::Number(number)) if operand.1 => Some(*number),
+            _ => None,
+        };
+
+        // Both operands are constants, so the instruction can be replaced in place.
+        if let (Some(left), Some(right)) = (number(b, constant_manager.constants()), number(c, constant_manager.constants())) {
+            if let Some(result) = evaluate(&instruction, left, right) {
+                let constant_index = constant_manager.constant_for_number(result);
+                instructions[program_counter] = Instruction::LoadK {
+                    a,
+                    mode: ConstantIndex(constant_index),
+                };
+                if has_padding {
+                    padding[program_counter] = 0;
+                }
+                folded_count += 1;
+            }
+
+            program_counter += 1;
+            continue;
+        }
+
+        let loads = program_counter.checked_sub(2).and_then(|start| instructions.get(start..program_counter));
+        let Some(&[Instruction::LoadK { a: x, mode: x_constant }, Instruction::LoadK { a: y, mode: y_constant }]) = loads else {
+            program_counter += 1;
+            continue;
+        };
+
+        let start = program_counter - 2;
+        let load = |operand: ConstantRegister| match operand {
+            ConstantRegister(register, false) if register == y => Some(ConstantRegister(y_constant.0, true)),
+            ConstantRegister(register, false) if register == x => Some(ConstantRegister(x_constant.0, true)),
+            _ => None,
+        };
+
+        // Jumps may only land on the first `LOADK`, which is replaced by the result, and
+        // if the first `LOADK` is skipped conditionally, the other instructions are
+        // still executed.
+        let is_foldable = x != y
+            && [b.0, c.0].contains(&x)
+            && [b.0, c.0].contains(&y)
+            && !is_jump_target[start + 1]
+            && !is_jump_target[program_counter]
+            && !start.checked_sub(1).is_some_and(|index| skips_next(&instructions[index]))
+            && [x, y].iter().all(|register| *register == a || is_dead(instructions, &captured_registers, program_counter + 1, *register));
+
+        let result = match (load(b), load(c)) {
+            (Some(b), Some(c)) if is_foldable => {
+                let constants = constant_manager.constants();
+                number(b, constants).zip(number(c, constants)).and_then(|(left, right)| evaluate(&instruction, left, right))
+            }
+            _ => None,
+        };
+
+        let Some(result) = result else {
+            program_counter += 1;
+            continue;
+        };
+
+        let constant_index = constant_manager.constant_for_number(result);
+        instructions[start] = Instruction::LoadK {
+            a,
+            mode: ConstantIndex(constant_index),
+        };
+        if has_padding {
+            padding[start] = 0;
+        }
+
+        for removed_index in [program_counter, start + 1] {
+            instructions.remove(removed_index);
+            destinations.remove(removed_index);
+            is_jump_target.remove(removed_index);
+
+            if has_line_info {
+                line_info.remove(removed_index);
+            }
+
+            if has_padding {
+                padding.remove(removed_index);
+            }
+
+            for destination in destinations.iter_mut().flatten() {
+                if *destination > removed_index {
+                    *destination -= 1;
+                }
+            }
+        }
+
+        folded_count += 1;
+        program_counter = start + 1;
+    }
+
+    for (program_counter, (instruction, destination)) in instructions.iter_mut().zip(destinations).enumerate() {
+        if let (Instruction::Jump { mode, .. } | Instruction::ForLoop { mode, .. } | Instruction::ForPrep { mode, .. }, Some(destination)) =
+            (instruction, destination)
+        {
+            mode.0 = destination as i64 - program_counter as i64 - 1;
+        }
+    }
+
+    Ok(folded_count)
+}
+
+fn arithmetic_operands(instruction: &Instruction) -> Option<(u64, BC<ConstantRegister, ConstantRegister>)> {
+    match *instruction {
+        Instruction::Add { a, mode }
+        | Instruction::Subtract { a, mode }
+        | Instruction::Multiply { a, mode }
+        | Instruction::Divide { a, mode }
+        | Instruction::Power { a, mode } => Some((a, mode)),
+        _ => None,
+    }
+}
+
+/// Check if the register is overwritten before it is read again, starting at
+/// `program_counter`. Only the instructions up to the next jump or conditional
+/// skip are considered, so this returns `false` if in doubt.
+fn is_dead(instructions: &[Instruction], captured_registers: &[u64], program_counter: usize, register: u64) -> bool {
+    if captured_registers.contains(&register) {
+        return false;
+    }
+
+    for instruction in instructions.iter().skip(program_counter) {
+        if instruction.reads_register(register) {
+            return false;
+        }
+
+        match instruction {
+            Instruction::Return { .. } | Instruction::TailCall { .. } => return true,
+            Instruction::Jump { .. } | Instruction::ForLoop { .. } | Instruction::ForPrep { .. } => return false,
+            instruction if skips_next(instruction) => return false,
+            instruction if overwrites(instruction, register) => return true,
+            _ => {}
+        }
+    }
+
+    true
+}
+
+/// Compute the result of an arithmetic instruction. Returns `None` if the
+/// result might differ between the number types of the output.
+fn evaluate(instruction: &Instruction, left: Number, right: Number) -> Option<Number> {
+    let (left_value, right_value) = (left.as_float().ok()?, right.as_float().ok()?);
+
+    let (result, is_correctly_rounded) = match instruction {
+        Instruction::Add { .. } => (left_value + right_value, true),
+        Instruction::Subtract { .. } => (left_value - right_value, true),
+        Instruction::Multiply { .. } => (left_value * right_value, true),
+        Instruction::Divide { .. } => (left_value / right_value, true),
+        Instruction::Power { .. } => (left_value.powf(right_value), false),
+        _ => return None,
+    };
+
+    if !result.is_finite() {
+        return None;
+    }
+
+    let is_integral = |value: f64| value.fract() == 0.0 && value.abs() <= MAXIMUM_EXACT_INTEGER;
+    let is_exact_float = |value: f64| value as f32 as f64 == value;
+
+    let is_foldable = match is_integral(left_value) && is_integral(right_value) {
+        // VMs with integral numbers compute with integers, so the result needs to be
+        // an integer as well.
+        true => is_integral(result),
+        // Fractions can only be written with float numbers. Arithmetic on 32-bit
+        // floats rounds the exact result once, so it matches our result if that
+        // can be represented.
+        false => is_correctly_rounded && is_exact_float(left_value) && is_exact_float(right_value) && is_exact_float(result),
+    };
+
+    match (is_foldable, left, right) {
+        (false, ..) => None,
+        (true, Number::Integer(_), Number::Integer(_)) => Some(Number::Integer(result as i64)),
+        (true, ..) => Some(Number::Float(result)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fold_constants;
+    use crate::function::constant::Constant;
+    use crate::function::instruction::{ConstantIndex, ConstantRegister, Generic, Register, SignedBx, Unused, BC};
+    use crate::lua51::Instruction;
+    use crate::number::Number;
+    use crate::LunifyError;
+
+    fn constants() -> Vec<Constant<'static>> {
+        vec![Constant::Number(Number::Float(6.0)), Constant::Number(Number::Float(3.0))]
+    }
+
+    fn load(a: u64, constant_index: u64) -> Instruction {
+        Instruction::LoadK {
+            a,
+            mode: ConstantIndex(constant_index),
+        }
+    }
+
+    fn registers(b: u64, c: u64) -> BC<ConstantRegister, ConstantRegister> {
+        BC(ConstantRegister(b, false), ConstantRegister(c, false))
+    }
+
+    fn return_register(a: u64) -> Instruction {
+        Instruction::Return {
+            a,
+            mode: BC(Generic(2), Unused),
+        }
+    }
+
+    fn fold(mut instructions: Vec<Instruction>, constants: &mut Vec<Constant>) -> Result<(Vec<Instruction>, usize), LunifyError> {
+        let mut line_info = (0..instructions.len() as i64).collect();
+        let mut padding = vec![1; instructions.len()];
+        let folded_count = fold_constants(&mut instructions, &mut line_info, &mut padding, constants)?;
+        assert_eq!(line_info.len(), instructions.len());
+        assert_eq!(padding.len(), instructions.len());
+        Ok((instructions, folded_count))
+    }
+
+    #[test]
+    fn fold_arithmetic() -> Result<(), LunifyError> {
+        let mode = registers(0, 1);
+        let operations = [
+            (Instruction::Add { a: 2, mode }, 9.0),
+            (Instruction::Subtract { a: 2, mode }, 3.0),
+            (Instruction::Multiply { a: 2, mode }, 18.0),
+            (Instruction::Divide { a: 2, mode }, 2.0),
+            (Instruction::Power { a: 2, mode }, 216.0),
+        ];
+
+        for (operation, expected) in operations {
+            let mut constants = constants();
+            let instructions = vec![load(0, 0), load(1, 1), operation, return_register(2)];
+            let (instructions, folded_count) = fold(instructions, &mut constants)?;
+
+            let [Instruction::LoadK { a: 2, mode }, Instruction::Return { .. }] = instructions.as_slice() else {
+                panic!("not folded: {instructions:?}");
+            };
+            assert_eq!(constants[mode.0 as usize], Constant::Number(Number::Float(expected)));
+            assert_eq!(folded_count, 1);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn fold_constant_operands() -> Result<(), LunifyError> {
+        let mut constants = constants();
+        let instructions = vec![
+            Instruction::Subtract {
+                a: 0,
+                mode: BC(ConstantRegister(1, true), ConstantRegister(0, true)),
+            },
+            return_register(0),
+        ];
+        let (instructions, folded_count) = fold(instructions, &mut constants)?;
+
+        assert_eq!(instructions, [load(0, 2), return_register(0)]);
+        assert_eq!(constants[2], Constant::Number(Number::Float(-3.0)));
+        assert_eq!(folded_count, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn fold_reuses_constant() -> Result<(), LunifyError> {
+        let mut constants = constants();
+        let instructions = vec![load(0, 1), load(1, 1), Instruction::Add { a: 0, mode: registers(0, 1) }, return_register(0)];
+        let (instructions, _) = fold(instructions, &mut constants)?;
+
+        assert_eq!(instructions, [load(0, 0), return_register(0)]);
+        assert_eq!(constants.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn fold_register_read_later() -> Result<(), LunifyError> {
+        // R1 is returned as well, so its `LOADK` has to stay.
+        let instructions = vec![
+            load(0, 0),
+            load(1, 1),
+            Instruction::Add { a: 2, mode: registers(0, 1) },
+            Instruction::Return {
+                a: 1,
+                mode: BC(Generic(3), Unused),
+            },
+        ];
+        let (output, folded_count) = fold(instructions.clone(), &mut constants())?;
+
+        assert_eq!(output, instructions);
+        assert_eq!(folded_count, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn fold_register_overwritten_later() -> Result<(), LunifyError> {
+        let instructions = vec![
+            load(0, 0),
+            load(1, 1),
+            Instruction::Add { a: 2, mode: registers(0, 1) },
+            Instruction::Move {
+                a: 1,
+                mode: BC(Register(2), Unused),
+            },
+            return_register(1),
+        ];
+        let (output, folded_count) = fold(instructions, &mut constants())?;
+
+        assert_eq!(output[0], load(2, 2));
+        assert_eq!(output.len(), 3);
+        assert_eq!(folded_count, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn fold_register_live_across_jump() -> Result<(), LunifyError> {
+        let instructions = vec![
+            load(0, 0),
+            load(1, 1),
+            Instruction::Add { a: 2, mode: registers(0, 1) },
+            Instruction::Jump { a: 0, mode: SignedBx(0) },
+            return_register(1),
+        ];
+        let (output, folded_count) = fold(instructions.clone(), &mut constants())?;
+
+        assert_eq!(output, instructions);
+        assert_eq!(folded_count, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn fold_adjusts_jumps() -> Result<(), LunifyError> {
+        let instructions = vec![
+            Instruction::Jump { a: 0, mode: SignedBx(3) },
+            load(0, 0),
+            load(1, 1),
+            Instruction::Multiply { a: 0, mode: registers(0, 1) },
+            load(1, 0),
+            Instruction::Jump { a: 0, mode: SignedBx(-5) },
+        ];
+        let (output, folded_count) = fold(instructions, &mut constants())?;
+
+        assert_eq!(output, [
+            Instruction::Jump { a: 0, mode: SignedBx(1) },
+            load(0, 2),
+            load(1, 0),
+            Instruction::Jump { a: 0, mode: SignedBx(-3) },
+        ]);
+        assert_eq!(folded_count, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn fold_jump_into_pattern() -> Result<(), LunifyError> {
+        let instructions = vec![
+            Instruction::Jump { a: 0, mode: SignedBx(1) },
+            load(0, 0),
+            load(1, 1),
+            Instruction::Add { a: 0, mode: registers(0, 1) },
+            return_register(0),
+        ];
+        let (output, folded_count) = fold(instructions.clone(), &mut constants())?;
+
+        assert_eq!(output, instructions);
+        assert_eq!(folded_count, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn fold_inexact_results() -> Result<(), LunifyError> {
+        // A fraction of two integers, a result that a 32-bit float can't represent
+        // and a division by zero.
+        let mut constants = vec![
+            Constant::Number(Number::Integer(7)),
+            Constant::Number(Number::Integer(2)),
+            Constant::Number(Number::Float(0.1)),
+            Constant::Number(Number::Integer(0)),
+        ];
+        let operands = [(0, 1), (2, 2), (0, 3)];
+
+        for (b, c) in operands {
+            let instruction = Instruction::Divide {
+                a: 0,
+                mode: BC(ConstantRegister(b, true), ConstantRegister(c, true)),
+            };
+            let (output, folded_count) = fold(vec![instruction, return_register(0)], &mut constants)?;
+
+            assert_eq!(output[0], instruction);
+            assert_eq!(folded_count, 0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn fold_integers() -> Result<(), LunifyError> {
+        let mut constants = vec![Constant::Number(Number::Integer(7)), Constant::Number(Number::Integer(2))];
+        let instructions = vec![load(0, 0), load(1, 1), Instruction::Subtract { a: 0, mode: registers(1, 0) }, return_register(0)];
+        let (instructions, _) = fold(instructions, &mut constants)?;
+
+        assert_eq!(instructions, [load(0, 2), return_register(0)]);
+        assert_eq!(constants[2], Constant::Number(Number::Integer(-5)));
+        Ok(())
+    }
+}
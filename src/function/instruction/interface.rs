@@ -1,9 +1,44 @@
 use super::InstructionLayout;
+use crate::function::constant::Constant;
 use crate::serialization::ByteStream;
 use crate::{LunifyError, Settings};
 
 pub(crate) trait LuaInstruction: Sized {
-    fn from_byte_stream(byte_stream: &mut ByteStream, settings: &Settings, layout: &InstructionLayout) -> Result<Self, LunifyError>;
+    /// Read and decode an instruction. Also returns the bits of the instruction
+    /// that are not part of the layout.
+    fn from_byte_stream(
+        byte_stream: &mut ByteStream,
+        settings: &Settings,
+        layout: &InstructionLayout,
+        program_counter: usize,
+    ) -> Result<(Self, u64), LunifyError> {
+        let value = byte_stream.instruction()?;
+        let padding = value & !layout.bit_mask();
+        let instruction = Self::from_u64(value, settings, layout, program_counter)?;
+        Ok((instruction, padding))
+    }
+    /// Decode a single instruction, with the instruction translator of the
+    /// settings if there is one. Bits that are not part of the layout are
+    /// ignored.
+    fn from_u64(value: u64, settings: &Settings, layout: &InstructionLayout, program_counter: usize) -> Result<Self, LunifyError>;
     fn move_stack_accesses(&mut self, stack_start: u64, offset: i64);
     fn to_u64(&self, settings: &Settings) -> Result<u64, LunifyError>;
+    /// Human readable representation of the instruction for debug output, with
+    /// constant operands resolved against `constants`, like
+    /// `LessThan A=0 B=R2 C=K7("table")`.
+    #[cfg_attr(not(feature = "debug"), allow(dead_code))]
+    fn describe(&self, constants: &[Constant]) -> String;
+}
+
+pub(crate) trait InstructionTranslate: Sized {
+    /// Decode the instruction with a user supplied translator. Returns `None`
+    /// if there is none, so the instruction is decoded as usual.
+    fn translate(
+        _value: u64,
+        _settings: &Settings,
+        _layout: &InstructionLayout,
+        _program_counter: usize,
+    ) -> Option<Result<Self, LunifyError>> {
+        None
+    }
 }
@@ -1,8 +1,15 @@
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
-use super::operand::{Bx, Generic, Opcode, SignedBx, A, BC};
-use super::{ConstantRegister, InstructionLayout, OperandType, Register, Unused};
+use super::luaconf::{Luaconf, LuaconfReport};
+#[cfg(not(feature = "custom-input"))]
+use super::operand::{Bx, ConstantIndex, ConstantRegister, Generic, PrototypeIndex, Register, SignedBx, Unused, BC};
+#[cfg(feature = "custom-input")]
+pub use super::operand::{Bx, ConstantIndex, ConstantRegister, Generic, PrototypeIndex, Register, SignedBx, Unused, BC};
+use super::operand::{Opcode, A};
+#[cfg(feature = "custom-input")]
+pub use super::translator::{InstructionTranslator, RawInstruction};
+use super::{InstructionLayout, InstructionTranslate, OperandType};
 use crate::LunifyError;
 
 /// Lua 5.0 compile constants. The Lua interpreter is compiled with certain
@@ -10,8 +17,12 @@ use crate::LunifyError;
 /// structure represents a small subset of the constants that are relevant for
 /// Lunify. If the byte code you are trying to modify was complied with
 /// non-standard constants, you can use these settings to make it compatible.
+///
+/// Like [`crate::Settings`], these can't be constructed with a struct
+/// expression outside of Lunify.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
 pub struct Settings<'a> {
     /// Maximum number of elements that can be on the stack at the same time
     /// (`MAXSTACK`).
@@ -24,6 +35,19 @@ pub struct Settings<'a> {
     /// Memory layout of instructions inside the Lua byte code (`SIZE_*`,
     /// `POS_*`).
     pub layout: InstructionLayout,
+    /// Detect `fields_per_flush` from the input byte code and use it instead
+    /// of the specified value if the detection is successful. See
+    /// [detect_lua50_fields_per_flush](crate::detect_lua50_fields_per_flush).
+    pub auto_detect_fields_per_flush: bool,
+    /// Invert the condition of `EQ`, `LT`, `LE` and `TEST` instructions. Some
+    /// non-standard compilers skip the following jump when the comparison
+    /// matches the polarity operand instead of when it doesn't.
+    pub inverted_test_polarity: bool,
+    /// Decode every instruction with a user supplied [`InstructionTranslator`]
+    /// instead of the built in opcode table. This is not serialized.
+    #[cfg(feature = "custom-input")]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub instruction_translator: Option<InstructionTranslator<'a>>,
 }
 
 impl<'a> Default for Settings<'a> {
@@ -39,22 +63,62 @@ impl<'a> Default for Settings<'a> {
                 OperandType::A(8),
             ])
             .unwrap(),
+            auto_detect_fields_per_flush: false,
+            inverted_test_polarity: false,
+            #[cfg(feature = "custom-input")]
+            instruction_translator: None,
         }
     }
 }
 
+impl<'a> Settings<'a> {
+    /// Derive settings from the text of `llimits.h`, `lopcodes.h` and
+    /// `lundump.h`, for example from the SDK of a modified interpreter. The
+    /// text is scanned for `MAXSTACK`, `LFIELDS_PER_FLUSH`, `LUA_SIGNATURE`
+    /// and `SIZE_*` definitions, everything else is taken from the default
+    /// settings. The operands keep the stock order, so `POS_*` definitions are
+    /// ignored. Returns the settings together with a [`LuaconfReport`] of
+    /// which definitions were used.
+    pub fn from_luaconf(text: &'a str) -> Result<(Self, LuaconfReport), LunifyError> {
+        let default = Self::default();
+        let mut luaconf = Luaconf::new(text);
+
+        let stack_limit = luaconf.integer(&["MAXSTACK", "LUAI_MAXSTACK"], default.stack_limit);
+        let fields_per_flush = luaconf.integer(&["LFIELDS_PER_FLUSH"], default.fields_per_flush);
+        let binary_signature = luaconf.signature(default.binary_signature);
+        let [opcode, a, b, c] = luaconf.operand_sizes(&default.layout);
+        let layout = InstructionLayout::from_specification([
+            OperandType::Opcode(opcode),
+            OperandType::C(c),
+            OperandType::B(b),
+            OperandType::A(a),
+        ])?;
+
+        let settings = Self {
+            stack_limit,
+            fields_per_flush,
+            binary_signature,
+            layout,
+            ..default
+        };
+
+        Ok((settings, luaconf.finalize()))
+    }
+}
+
 lua_instructions! {
+    custom_input;
     Move(BC<Register, Unused>, true),
-    LoadK(Bx, true),
+    LoadK(ConstantIndex, true),
     LoadBool(BC<Generic, Generic>, true),
     LoadNil(BC<Register, Unused>, true),
     GetUpValue(BC<Generic, Unused>, true),
-    GetGlobal(Bx, true),
+    GetGlobal(ConstantIndex, true),
     GetTable(BC<Register, ConstantRegister>, true),
-    SetGlobal(Bx, true),
+    SetGlobal(ConstantIndex, true),
     SetUpValue(BC<Generic, Unused>, true),
     SetTable(BC<ConstantRegister, ConstantRegister>, true),
-    NewTable(BC<Unused, Unused>, true),
+    NewTable(BC<Generic, Generic>, true),
     _Self(BC<Register, ConstantRegister>, true),
     Add(BC<ConstantRegister, ConstantRegister>, true),
     Subtract(BC<ConstantRegister, ConstantRegister>, true),
@@ -78,5 +142,70 @@ lua_instructions! {
     SetList(Bx, true),
     SetListO(Bx, true),
     Close(BC<Unused, Unused>, true),
-    Closure(Bx, true),
+    Closure(PrototypeIndex, true),
+}
+
+impl InstructionTranslate for Instruction {
+    #[cfg(feature = "custom-input")]
+    fn translate(
+        value: u64,
+        settings: &crate::Settings,
+        layout: &InstructionLayout,
+        program_counter: usize,
+    ) -> Option<Result<Self, LunifyError>> {
+        let translator = settings.lua50.instruction_translator?;
+        Some((translator.0)(RawInstruction::new(value, settings, layout, program_counter)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Instruction;
+    use crate::function::instruction::{Bx, ConstantRegister, PrototypeIndex, Register, SignedBx, Unused, BC};
+    use crate::{LunifyError, Settings};
+
+    fn decode(instruction: u64) -> Result<Instruction, LunifyError> {
+        let settings = Settings::default();
+        Instruction::decode(instruction, &settings, &settings.lua50.layout, 0)
+    }
+
+    #[test]
+    fn decode_constant_register() -> Result<(), LunifyError> {
+        // `ADD 0 250 3`, where B is the first constant, since it is not below the stack
+        // limit of Lua 5.0.
+        let expected = Instruction::Add {
+            a: 0,
+            mode: BC(ConstantRegister(0, true), ConstantRegister(3, false)),
+        };
+        assert_eq!(decode(12 | (3 << 6) | (250 << 15))?, expected);
+
+        // `ADD 0 249 253`
+        let expected = Instruction::Add {
+            a: 0,
+            mode: BC(ConstantRegister(249, false), ConstantRegister(3, true)),
+        };
+        assert_eq!(decode(12 | (253 << 6) | (249 << 15))?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn decode_opcodes_without_equivalent() -> Result<(), LunifyError> {
+        // Lua 5.0 has no `MOD`, `LEN`, `FORPREP` and `VARARG`, so the opcodes after
+        // `POW`, `CONCAT` and `FORLOOP` differ from Lua 5.1.
+        let expected = Instruction::Not {
+            a: 1,
+            mode: BC(Register(2), Unused),
+        };
+        assert_eq!(decode(18 | (2 << 15) | (1 << 24))?, expected);
+
+        // `TFORPREP 0 3`
+        let expected = Instruction::TForPrep { a: 0, mode: SignedBx(3) };
+        assert_eq!(decode(30 | ((3 + 131071) << 6))?, expected);
+
+        // `SETLISTO 0 5` and `CLOSURE 1 0`
+        assert_eq!(decode(32 | (5 << 6))?, Instruction::SetListO { a: 0, mode: Bx(5) });
+        assert_eq!(decode(34 | (1 << 24))?, Instruction::Closure { a: 1, mode: PrototypeIndex(0) });
+        assert_eq!(decode(35), Err(LunifyError::InvalidOpcode(35)));
+        Ok(())
+    }
 }
@@ -3,20 +3,27 @@ use std::ops::Range;
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
-use super::operand::{Bx, ConstantRegister, Generic, Opcode, Register, SignedBx, Unused, A, BC};
-use super::{InstructionLayout, OperandType};
-use crate::LunifyError;
+use super::operand::{ConstantIndex, ConstantRegister, Generic, Opcode, PrototypeIndex, Register, SignedBx, Unused, A, BC};
+use super::luaconf::{Luaconf, LuaconfReport};
+use super::{InstructionLayout, InstructionTranslate, OperandType};
+use crate::{FunctionTrailerMode, FunctionTrailerSpec, LineOverflowPolicy, LunifyError, SourceRewrite};
 
 /// Lua 5.1 compile constants. The Lua interpreter is compiled with certain
 /// predefined constants that affect how the byte code is generated. This
 /// structure represents a small subset of the constants that are relevant for
 /// Lunify. If the byte code you are trying to modify was complied with
 /// non-standard constants, you can use these settings to make it compatible.
+///
+/// Like [`crate::Settings`], these can't be constructed with a struct
+/// expression outside of Lunify.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
 pub struct Settings<'a> {
     /// Maximum number of elements that can be on the stack at the same time
-    /// (`MAXSTACK`).
+    /// (`MAXSTACK`). The output stack limit can be at most 255, since the stack
+    /// size of a function is stored in a single byte, and every register needs
+    /// to fit into A.
     pub stack_limit: u64,
     /// Number of elements to put on the stack before inserting a `SETLIST`
     /// instruction (`LFIELDS_PER_FLUSH`).
@@ -26,6 +33,150 @@ pub struct Settings<'a> {
     /// Memory layout of instructions inside the Lua byte code (`SIZE_*`,
     /// `POS_*`).
     pub layout: InstructionLayout,
+    /// Rewrite applied to the source file name of every function. This is only
+    /// used in the output settings.
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    pub rewrite_source: Option<SourceRewrite<'a>>,
+    /// Set the field `n` of the `arg` table created for variadic Lua 5.0
+    /// functions to the number of arguments, like Lua 5.0 does. This relies on
+    /// the global `select` function. This is only used in the output settings.
+    pub emit_vararg_count: bool,
+    /// Remove redundant instructions from the converted byte code. This only
+    /// applies rewrites that are known to be safe, like removing moves of a
+    /// register onto itself or fusing adjacent `LOADNIL` instructions. This is
+    /// only used in the output settings.
+    pub peephole: bool,
+    /// Additional data stored after the upvalue names of every function. In the
+    /// output settings, this is the encoding of the written trailer, which
+    /// defaults to the encoding of the input.
+    pub function_trailer: Option<FunctionTrailerSpec>,
+    /// What to do with the function trailers when writing the output. This is
+    /// only used in the output settings.
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    pub function_trailer_mode: FunctionTrailerMode<'a>,
+    /// Maximum size of the output in bytes. This is only used in the output
+    /// settings.
+    pub max_output_size: Option<usize>,
+    /// Opcodes that are not supported by the target VM. `LEN` and `MOD` are
+    /// lowered to equivalent instructions, any other opcode in this set results
+    /// in an error. This is only used in the output settings.
+    pub disallowed_opcodes: OpcodeSet,
+    /// Name of the global function that a lowered `LEN` instruction calls to get
+    /// the length of a value. This is only used in the output settings.
+    pub length_shim: &'a str,
+    /// Check the converted byte code with [`validate`](crate::validate) before
+    /// returning it. This is only used in the output settings.
+    pub verify: bool,
+    /// Let the VM create the `arg` table of variadic Lua 5.0 functions by
+    /// setting `VARARG_NEEDSARG` instead of inserting instructions that create
+    /// it. This requires the VM to be compiled with `LUA_COMPAT_VARARG`. This is
+    /// only used in the output settings.
+    pub use_needsarg_flag: bool,
+    /// Write an empty source file name for nested functions that have the same
+    /// source file as their parent, like `luac` does. The loader inherits the
+    /// source file of the parent in that case. Disable this to write the source
+    /// file of every function for tools that don't inherit it. This is only used
+    /// in the output settings.
+    pub deduplicate_source: bool,
+    /// Write the byte order mark and the first line starting with `#` that were
+    /// skipped in front of the input signature, like a shebang line, in front
+    /// of the output. This is only used in the output settings.
+    pub preserve_prefix: bool,
+    /// Write the padding that was accepted after the main function of the
+    /// input because of `allow_trailing_padding`, after the output. This is
+    /// only used in the output settings.
+    pub preserve_padding: bool,
+    /// Name of the global function that the expansion of the Lua 5.0
+    /// `TFORPREP` instruction calls to check if the value of a generic for
+    /// loop is a table. This is only used in the output settings.
+    pub tforprep_type_global: &'a str,
+    /// Name of the global function that the expansion of the Lua 5.0
+    /// `TFORPREP` instruction uses to iterate over a table. This is only used
+    /// in the output settings.
+    pub tforprep_next_global: &'a str,
+    /// Skip the type check when expanding the Lua 5.0 `TFORPREP` instruction
+    /// and always iterate with the `next` function, so the `type` function
+    /// isn't needed. This is only correct if every generic for loop iterates
+    /// over a table directly (`for k, v in t do`) and never calls an iterator
+    /// function like `pairs(t)`. This is only used in the output settings.
+    pub tforprep_assume_table: bool,
+    /// Place the constants that are created during conversion, like temporary
+    /// globals or the names of functions called by inserted instructions,
+    /// after the constants of the input function, so the input constants keep
+    /// their index. This is guaranteed to stay the default. If disabled, they
+    /// are placed in front of the input constants instead, which fails with
+    /// [`ValueTooBigForOperand`](crate::LunifyError::ValueTooBigForOperand) if
+    /// a shifted input constant no longer fits into an RK operand. This is only
+    /// used in the output settings.
+    pub synthetic_constants_last: bool,
+    /// Sort the constants that are created during conversion by their value
+    /// instead of keeping the order they were created in, so the output
+    /// doesn't change if a different version of Lunify creates them in a
+    /// different order. The input constants always keep their order. This is
+    /// only used in the output settings.
+    pub canonicalize_constants: bool,
+    /// Keep the A operand of jumps for VMs where a nonzero A makes `JMP` close
+    /// all upvalues from register A - 1 upwards, like Lua 5.2 does. Stock Lua
+    /// 5.1 ignores A, so by default a jump with a nonzero A is converted to a
+    /// `CLOSE` in front of a jump with A set to zero. This is only used in the
+    /// output settings.
+    pub jump_closes_upvalues: bool,
+    /// Source file name written for the main function instead of the one in
+    /// the input, for example to name a chunk that was compiled from several
+    /// scripts. A trailing null byte is added if it is missing. Nested
+    /// functions keep their source file, so functions that inherited the
+    /// source file of the main function write it explicitly. This is only used
+    /// in the output settings.
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    pub root_source_override: Option<&'a str>,
+    /// Set the line the main function is defined on and its last line to zero.
+    /// Nested functions keep their lines. This is only used in the output
+    /// settings.
+    pub zero_root_lines: bool,
+    /// What to do with line numbers that don't fit into the integers of the
+    /// output format. This applies to the lines a function is defined on and
+    /// to the line of every instruction. This is only used in the output
+    /// settings.
+    pub line_number_overflow: LineOverflowPolicy,
+    /// Replace `ADD`, `SUB`, `MUL`, `DIV` and `POW` instructions on two number
+    /// constants with a `LOADK` of the result. If the operands are loaded by
+    /// the two `LOADK` instructions right in front of the instruction, those
+    /// are removed as well, as long as their registers are overwritten before
+    /// being read again. Only results that every number type represents
+    /// exactly are folded. This is only used in the output settings.
+    pub fold_constants: bool,
+    /// Replace every Lua 5.0 `POW` instruction with a call to the global
+    /// function named by `power_global`, like the Lua 5.0 VM does. Lua 5.1
+    /// computes the power natively and never calls the global, so this is
+    /// needed if the global is replaced, for example for fixed-point math.
+    /// This is only used in the output settings.
+    pub lower_power_to_call: bool,
+    /// Name of the global function that a Lua 5.0 `POW` instruction calls if
+    /// `lower_power_to_call` is set. This is only used in the output settings.
+    pub power_global: &'a str,
+    /// Append a `RETURN` to Lua 5.0 functions that don't end with one, so the
+    /// VM never runs off the end of the instructions. Functions created by the
+    /// Lua 5.0 compiler always end with a `RETURN`, so this only affects byte
+    /// code that was assembled by hand. If unset, a diagnostic is reported
+    /// instead. This is only used in the output settings.
+    pub ensure_final_return: bool,
+    /// Globals and fields of global tables that are renamed when they are read,
+    /// for example because a function of the standard library was renamed
+    /// between Lua 5.0 and Lua 5.1. The first name of every pair is either the
+    /// name of a global like `"gcinfo"`, or the name of a global table and one
+    /// of its fields like `"string.gfind"`. The second name is the new name of
+    /// the global or field, like `"gmatch"`. Only `GETGLOBAL` and `GETTABLE`
+    /// instructions reading the global table directly are changed. See
+    /// [`lua50_stdlib_renames`](Self::lua50_stdlib_renames) for a preset. This
+    /// is only used in the output settings and is not serialized.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub rename_globals: &'a [(&'a str, &'a str)],
+    /// Append a [`Metadata`](crate::Metadata) record with the version of Lunify
+    /// and a digest of the settings after the main function. The Lua 5.1 loader
+    /// stops reading after the main function and Lunify skips the metadata
+    /// when reading its own output. This is only used in the output settings.
+    #[cfg(feature = "metadata")]
+    pub append_metadata: bool,
 }
 
 impl<'a> Default for Settings<'a> {
@@ -41,11 +192,84 @@ impl<'a> Default for Settings<'a> {
                 OperandType::B(9),
             ])
             .unwrap(),
+            rewrite_source: None,
+            emit_vararg_count: true,
+            peephole: false,
+            function_trailer: None,
+            function_trailer_mode: FunctionTrailerMode::Keep,
+            max_output_size: None,
+            disallowed_opcodes: OpcodeSet::default(),
+            length_shim: "__len_shim",
+            verify: false,
+            use_needsarg_flag: false,
+            deduplicate_source: true,
+            preserve_prefix: false,
+            preserve_padding: false,
+            tforprep_type_global: "type",
+            tforprep_next_global: "next",
+            tforprep_assume_table: false,
+            synthetic_constants_last: true,
+            canonicalize_constants: false,
+            jump_closes_upvalues: false,
+            root_source_override: None,
+            zero_root_lines: false,
+            line_number_overflow: LineOverflowPolicy::Error,
+            fold_constants: false,
+            lower_power_to_call: false,
+            power_global: "__pow",
+            ensure_final_return: true,
+            rename_globals: &[],
+            #[cfg(feature = "metadata")]
+            append_metadata: false,
         }
     }
 }
 
 impl<'a> Settings<'a> {
+    /// Derive settings from the text of `luaconf.h`, `llimits.h`, `lopcodes.h`
+    /// and `lua.h`, for example from the SDK of a modified interpreter. The
+    /// text is scanned for `MAXSTACK` or `LUAI_MAXSTACK`, `LFIELDS_PER_FLUSH`,
+    /// `LUA_SIGNATURE` and `SIZE_*` definitions, everything else is taken from
+    /// the default settings. The operands keep the stock order, so `POS_*`
+    /// definitions are ignored. Returns the settings together with a
+    /// [`LuaconfReport`] of which definitions were used.
+    pub fn from_luaconf(text: &'a str) -> Result<(Self, LuaconfReport), LunifyError> {
+        let default = Self::default();
+        let mut luaconf = Luaconf::new(text);
+
+        let stack_limit = luaconf.integer(&["MAXSTACK", "LUAI_MAXSTACK"], default.stack_limit);
+        let fields_per_flush = luaconf.integer(&["LFIELDS_PER_FLUSH"], default.fields_per_flush);
+        let binary_signature = luaconf.signature(default.binary_signature);
+        let [opcode, a, b, c] = luaconf.operand_sizes(&default.layout);
+        let layout = InstructionLayout::from_specification([
+            OperandType::Opcode(opcode),
+            OperandType::A(a),
+            OperandType::C(c),
+            OperandType::B(b),
+        ])?;
+
+        let settings = Self {
+            stack_limit,
+            fields_per_flush,
+            binary_signature,
+            layout,
+            ..default
+        };
+
+        Ok((settings, luaconf.finalize()))
+    }
+
+    /// Renames for [`rename_globals`](Self::rename_globals) of the functions of
+    /// the standard library that were renamed in Lua 5.1 and are only
+    /// available under their Lua 5.0 name if the VM is compiled with
+    /// compatibility options. `string.gfind` becomes `string.gmatch` and
+    /// `math.mod` becomes `math.fmod`. `table.getn` becomes `table.maxn`,
+    /// which returns the same length for tables without holes that don't set
+    /// their size with `table.setn`.
+    pub fn lua50_stdlib_renames() -> &'static [(&'static str, &'static str)] {
+        &[("string.gfind", "gmatch"), ("math.mod", "fmod"), ("table.getn", "maxn")]
+    }
+
     pub(crate) fn get_constant_bit(&self) -> u64 {
         1 << (self.layout.b.size - 1)
     }
@@ -55,18 +279,62 @@ impl<'a> Settings<'a> {
     }
 }
 
+/// Names of the Lua 5.1 opcodes, indexed by opcode.
+pub(super) const OPCODE_NAMES: [&str; 38] = [
+    "MOVE", "LOADK", "LOADBOOL", "LOADNIL", "GETUPVAL", "GETGLOBAL", "GETTABLE", "SETGLOBAL", "SETUPVAL", "SETTABLE", "NEWTABLE", "SELF",
+    "ADD", "SUB", "MUL", "DIV", "MOD", "POW", "UNM", "NOT", "LEN", "CONCAT", "JMP", "EQ", "LT", "LE", "TEST", "TESTSET", "CALL",
+    "TAILCALL", "RETURN", "FORLOOP", "FORPREP", "TFORLOOP", "SETLIST", "CLOSE", "CLOSURE", "VARARG",
+];
+
+/// A set of Lua 5.1 opcodes, identified by the names used in the Lua source
+/// code, e.g. `MOD` or `LEN`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct OpcodeSet(u64);
+
+impl OpcodeSet {
+    /// Create a set from a list of opcode names. Returns
+    /// [`UnknownOpcode`](LunifyError::UnknownOpcode) if any of the names is not
+    /// a Lua 5.1 opcode.
+    pub fn from_names(names: &[&str]) -> Result<Self, LunifyError> {
+        names.iter().try_fold(Self::default(), |set, name| {
+            match OPCODE_NAMES.iter().position(|opcode_name| opcode_name.eq_ignore_ascii_case(name)) {
+                Some(opcode) => Ok(Self(set.0 | 1 << opcode)),
+                None => Err(LunifyError::UnknownOpcode(name.to_string())),
+            }
+        })
+    }
+
+    /// Check if the set contains the opcode with the given name.
+    pub fn contains(&self, name: &str) -> bool {
+        OPCODE_NAMES
+            .iter()
+            .position(|opcode_name| opcode_name.eq_ignore_ascii_case(name))
+            .is_some_and(|opcode| self.0 & 1 << opcode != 0)
+    }
+
+    /// Check if the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub(crate) fn contains_instruction(&self, instruction: &Instruction) -> bool {
+        self.0 & 1 << instruction.opcode() != 0
+    }
+}
+
 lua_instructions! {
     Move(BC<Register, Unused>, true),
-    LoadK(Bx, true),
+    LoadK(ConstantIndex, true),
     LoadBool(BC<Generic, Generic>, true),
     LoadNil(BC<Register, Unused>, true),
     GetUpValue(BC<Generic, Unused>, true),
-    GetGlobal(Bx, true),
+    GetGlobal(ConstantIndex, true),
     GetTable(BC<Register, ConstantRegister>, true),
-    SetGlobal(Bx, true),
+    SetGlobal(ConstantIndex, true),
     SetUpValue(BC<Generic, Unused>, true),
     SetTable(BC<ConstantRegister, ConstantRegister>, true),
-    NewTable(BC<Unused, Unused>, true),
+    NewTable(BC<Generic, Generic>, true),
     _Self(BC<Register, ConstantRegister>, true),
     Add(BC<ConstantRegister, ConstantRegister>, true),
     Subtract(BC<ConstantRegister, ConstantRegister>, true),
@@ -92,11 +360,48 @@ lua_instructions! {
     TForLoop(BC<Unused, Generic>, true),
     SetList(BC<Generic, Generic>, true),
     Close(BC<Unused, Unused>, true),
-    Closure(Bx, true),
+    Closure(PrototypeIndex, true),
     VarArg(BC<Generic, Unused>, true),
 }
 
+impl InstructionTranslate for Instruction {}
+
 impl Instruction {
+    /// Get the name of the opcode as it is used in the Lua source code.
+    pub(crate) fn name(&self) -> &'static str {
+        OPCODE_NAMES.get(self.opcode() as usize).copied().unwrap_or("UNKNOWN")
+    }
+
+    /// Get the operand that is stored in the next instruction slot if the
+    /// instruction is extended. In Lua 5.1 this is only the case for `SETLIST`
+    /// instructions where C is zero.
+    pub(crate) fn extended_argument(&mut self) -> Option<&mut u64> {
+        match self {
+            Instruction::SetList { mode: BC(_, c), .. } if c.0 == 0 => Some(&mut c.0),
+            _ => None,
+        }
+    }
+
+    /// Get the operands that are read as RK, so they can hold either a register
+    /// or a constant index.
+    pub(crate) fn constant_operands_mut(&mut self) -> Vec<&mut ConstantRegister> {
+        match self {
+            Instruction::GetTable { mode: BC(_, c), .. } | Instruction::_Self { mode: BC(_, c), .. } => vec![c],
+            Instruction::SetTable { mode: BC(b, c), .. }
+            | Instruction::Add { mode: BC(b, c), .. }
+            | Instruction::Subtract { mode: BC(b, c), .. }
+            | Instruction::Multiply { mode: BC(b, c), .. }
+            | Instruction::Divide { mode: BC(b, c), .. }
+            | Instruction::Modulo { mode: BC(b, c), .. }
+            | Instruction::Power { mode: BC(b, c), .. }
+            | Instruction::Equals { mode: BC(b, c), .. }
+            | Instruction::LessThan { mode: BC(b, c), .. }
+            | Instruction::LessEquals { mode: BC(b, c), .. } => vec![b, c],
+            Instruction::TestSet { mode: BC(b, _), .. } => vec![b],
+            _ => Vec::new(),
+        }
+    }
+
     /// Get the stack index that a given instruction will move data into.
     /// `SetTable` and `SetList` are technically not moving any data, but rather
     /// modifying it, but we need this behavior for detecting the correct
@@ -131,7 +436,7 @@ impl Instruction {
             Instruction::LessEquals { .. } => None,
             Instruction::Test { .. } => None,
             Instruction::TestSet { a, .. } => Some(a..a),
-            Instruction::Call { a, mode: BC(_, c) } => Some(a..a + c.0 - 1),
+            Instruction::Call { a, mode: BC(_, c) } => Some(a..a + c.0.saturating_sub(1)),
             Instruction::TailCall { .. } => None,
             Instruction::Return { .. } => None,
             Instruction::ForLoop { a, .. } => Some(a..a + 3),
@@ -143,11 +448,218 @@ impl Instruction {
             Instruction::VarArg { a, mode: BC(b, _) } => Some(a..a.max((a + b.0).saturating_sub(1))),
         }
     }
+
+    /// Get the stack indices that a given instruction will read data from. Like
+    /// with [`stack_destination`](Self::stack_destination), the end of the
+    /// range is inclusive. Constants are not part of the range and a count of
+    /// zero, meaning up to the top of the stack, only covers the first
+    /// register.
+    pub(crate) fn stack_source(&self) -> Option<Range<u64>> {
+        fn register(operand: ConstantRegister) -> Option<u64> {
+            (!operand.1).then_some(operand.0)
+        }
+
+        fn span(registers: impl IntoIterator<Item = Option<u64>>) -> Option<Range<u64>> {
+            registers
+                .into_iter()
+                .flatten()
+                .fold(None, |range: Option<Range<u64>>, register| match range {
+                    Some(range) => Some(range.start.min(register)..range.end.max(register)),
+                    None => Some(register..register),
+                })
+        }
+
+        match *self {
+            Instruction::Move { mode: BC(b, _), .. } => Some(b.0..b.0),
+            Instruction::LoadK { .. } => None,
+            Instruction::LoadBool { .. } => None,
+            Instruction::LoadNil { .. } => None,
+            Instruction::GetUpValue { .. } => None,
+            Instruction::GetGlobal { .. } => None,
+            Instruction::GetTable { mode: BC(b, c), .. } => span([Some(b.0), register(c)]),
+            Instruction::SetGlobal { a, .. } => Some(a..a),
+            Instruction::SetUpValue { a, .. } => Some(a..a),
+            Instruction::SetTable { a, mode: BC(b, c) } => span([Some(a), register(b), register(c)]),
+            Instruction::NewTable { .. } => None,
+            Instruction::_Self { mode: BC(b, c), .. } => span([Some(b.0), register(c)]),
+            Instruction::Add { mode: BC(b, c), .. }
+            | Instruction::Subtract { mode: BC(b, c), .. }
+            | Instruction::Multiply { mode: BC(b, c), .. }
+            | Instruction::Divide { mode: BC(b, c), .. }
+            | Instruction::Modulo { mode: BC(b, c), .. }
+            | Instruction::Power { mode: BC(b, c), .. }
+            | Instruction::Equals { mode: BC(b, c), .. }
+            | Instruction::LessThan { mode: BC(b, c), .. }
+            | Instruction::LessEquals { mode: BC(b, c), .. } => span([register(b), register(c)]),
+            Instruction::Unary { mode: BC(b, _), .. } => Some(b.0..b.0),
+            Instruction::Not { mode: BC(b, _), .. } => Some(b.0..b.0),
+            Instruction::Length { mode: BC(b, _), .. } => Some(b.0..b.0),
+            Instruction::Concatinate { mode: BC(b, c), .. } => Some(b.0..c.0),
+            Instruction::Jump { .. } => None,
+            Instruction::Test { a, .. } => Some(a..a),
+            Instruction::TestSet { mode: BC(b, _), .. } => register(b).map(|b| b..b),
+            Instruction::Call { a, mode: BC(b, _) } | Instruction::TailCall { a, mode: BC(b, _) } => Some(a..a + b.0.saturating_sub(1)),
+            Instruction::Return { a, mode: BC(b, _) } => match b.0 {
+                0 => Some(a..a),
+                1 => None,
+                b => Some(a..a + b - 2),
+            },
+            Instruction::ForLoop { a, .. } => Some(a..a + 2),
+            Instruction::ForPrep { a, .. } => Some(a..a + 2),
+            Instruction::TForLoop { a, .. } => Some(a..a + 2),
+            Instruction::SetList { a, mode: BC(b, _) } => Some(a..a + b.0),
+            Instruction::Close { .. } => None,
+            Instruction::Closure { .. } => None,
+            Instruction::VarArg { .. } => None,
+        }
+    }
+
+    /// Get the range of stack values that a given instruction accesses as a
+    /// whole. The end of the range is exclusive. B and C of `Call`, `Return`, `VarArg` and `SetList` are counts
+    /// rather than registers, and a count of zero means that the range extends
+    /// to the top of the stack.
+    pub(crate) fn stack_range(&self) -> Option<Range<u64>> {
+        match *self {
+            Instruction::Concatinate { mode: BC(b, c), .. } => Some(b.0..c.0 + 1),
+            Instruction::LoadNil { a, mode: BC(b, _) } => Some(a..b.0 + 1),
+            Instruction::Call { a, mode: BC(b, _) } | Instruction::TailCall { a, mode: BC(b, _) } => match b.0 {
+                0 => Some(a..u64::MAX),
+                b => Some(a..a + b),
+            },
+            Instruction::Return { a, mode: BC(b, _) } | Instruction::VarArg { a, mode: BC(b, _) } => match b.0 {
+                0 => Some(a..u64::MAX),
+                b => Some(a..a + b - 1),
+            },
+            Instruction::SetList { a, mode: BC(b, _) } => match b.0 {
+                0 => Some(a..u64::MAX),
+                b => Some(a..a + b + 1),
+            },
+            Instruction::TForLoop { a, mode: BC(_, c) } => Some(a..a + c.0 + 3),
+            _ => None,
+        }
+    }
+
+    /// Check if the instruction might read the register. Unlike
+    /// [`stack_source`](Self::stack_source), a count of zero covers every
+    /// register up to the top of the stack.
+    pub(crate) fn reads_register(&self, register: u64) -> bool {
+        match *self {
+            Instruction::Call { a, mode: BC(Generic(0), _) }
+            | Instruction::TailCall { a, mode: BC(Generic(0), _) }
+            | Instruction::Return { a, mode: BC(Generic(0), _) }
+            | Instruction::SetList { a, mode: BC(Generic(0), _) } => register >= a,
+            _ => self.stack_source().is_some_and(|range| range.start <= register && register <= range.end),
+        }
+    }
+
+    /// Check if the instruction might write the register. Unlike
+    /// [`stack_destination`](Self::stack_destination), `SetTable` and
+    /// `SetList` don't write any register, and a count of zero covers every
+    /// register up to the top of the stack.
+    pub(crate) fn writes_register(&self, register: u64) -> bool {
+        match *self {
+            Instruction::SetTable { .. } | Instruction::SetList { .. } => false,
+            Instruction::Call { a, mode: BC(_, Generic(0)) } | Instruction::VarArg { a, mode: BC(Generic(0), _) } => register >= a,
+            _ => self.stack_destination().is_some_and(|range| range.start <= register && register <= range.end),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Settings;
+    use super::{Instruction, OpcodeSet, Settings};
+    use crate::function::instruction::{
+        ConstantIndex, ConstantRegister, Generic, LuaInstruction, PrototypeIndex, Register, SignedBx, Unused, BC,
+    };
+    use crate::function::constant::Constant;
+    use crate::number::Number;
+    use crate::serialization::{ByteStream, ByteWriter};
+    use crate::{Format, InstructionField, LunifyError};
+
+    fn decode(instruction: u64, strict_decoding: bool) -> Result<Instruction, LunifyError> {
+        let format = Format::default();
+        let mut byte_writer = ByteWriter::new(&format);
+        byte_writer.instruction(instruction);
+
+        let bytes = byte_writer.finalize();
+        let mut byte_stream = ByteStream::new(&bytes);
+        byte_stream.set_format(format);
+
+        let settings = crate::Settings {
+            strict_decoding,
+            ..Default::default()
+        };
+        Instruction::from_byte_stream(&mut byte_stream, &settings, &settings.lua51.layout, 7).map(|(instruction, _)| instruction)
+    }
+
+    #[test]
+    fn decode_new_table_size_hint() -> Result<(), LunifyError> {
+        // `NEWTABLE 0 3 0`
+        let expected = Instruction::NewTable {
+            a: 0,
+            mode: BC(Generic(3), Generic(0)),
+        };
+        assert_eq!(decode(10 | (3 << 23), false)?, expected);
+        assert_eq!(decode(10 | (3 << 23), true)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn decode_unused_bits() -> Result<(), LunifyError> {
+        // `MOVE 0 1` with C set to 5.
+        let expected = Instruction::Move {
+            a: 0,
+            mode: BC(Register(1), Unused),
+        };
+        assert_eq!(decode((1 << 23) | (5 << 14), false)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn decode_unused_bits_strict() {
+        // `MOVE 0 1` with C set to 5.
+        let result = decode((1 << 23) | (5 << 14), true);
+        assert_eq!(result, Err(LunifyError::UnexpectedOperandBits {
+            program_counter: 7,
+            opcode: 0,
+            field: InstructionField::C,
+        }));
+    }
+
+    #[test]
+    fn decode_invalid_opcode_strict() {
+        assert_eq!(decode(40, false), Err(LunifyError::InvalidOpcode(40)));
+        assert_eq!(decode(40, true), Err(LunifyError::UnexpectedOperandBits {
+            program_counter: 7,
+            opcode: 40,
+            field: InstructionField::Opcode,
+        }));
+    }
+
+    #[test]
+    fn opcode_set() -> Result<(), LunifyError> {
+        let set = OpcodeSet::from_names(&["MOD", "len"])?;
+        assert!(set.contains("MOD"));
+        assert!(set.contains("LEN"));
+        assert!(!set.contains("VARARG"));
+        assert!(!set.contains("NOPE"));
+        Ok(())
+    }
+
+    #[test]
+    fn opcode_set_unknown() {
+        let result = OpcodeSet::from_names(&["MOD", "NOPE"]);
+        assert_eq!(result, Err(LunifyError::UnknownOpcode("NOPE".to_owned())));
+    }
+
+    #[test]
+    fn name() {
+        let instruction = Instruction::VarArg {
+            a: 0,
+            mode: BC(Generic(0), Unused),
+        };
+        assert_eq!(instruction.name(), "VARARG");
+    }
 
     #[test]
     fn settings_get_constant_bit() {
@@ -160,4 +672,236 @@ mod tests {
         let settings = Settings::default();
         assert_eq!(settings.get_maximum_constant_index(), (1 << 8) - 1);
     }
+
+    #[test]
+    fn extended_argument() {
+        let mut instruction = Instruction::SetList {
+            a: 0,
+            mode: BC(Generic(1), Generic(0)),
+        };
+        assert_eq!(instruction.extended_argument(), Some(&mut 0));
+    }
+
+    #[test]
+    fn extended_argument_not_extended() {
+        let mut instruction = Instruction::SetList {
+            a: 0,
+            mode: BC(Generic(1), Generic(1)),
+        };
+        assert_eq!(instruction.extended_argument(), None);
+    }
+
+    #[test]
+    fn stack_range_concatinate() {
+        let instruction = Instruction::Concatinate {
+            a: 0,
+            mode: BC(Register(2), Register(4)),
+        };
+        assert_eq!(instruction.stack_range(), Some(2..5));
+    }
+
+    #[test]
+    fn stack_range_load_nil() {
+        let instruction = Instruction::LoadNil {
+            a: 2,
+            mode: BC(Register(4), Unused),
+        };
+        assert_eq!(instruction.stack_range(), Some(2..5));
+    }
+
+    #[test]
+    fn stack_range_call_to_top() {
+        let instruction = Instruction::Call {
+            a: 3,
+            mode: BC(Generic(0), Generic(1)),
+        };
+        assert_eq!(instruction.stack_range(), Some(3..u64::MAX));
+    }
+
+    #[test]
+    fn stack_source_constants() {
+        let instruction = Instruction::Add {
+            a: 0,
+            mode: BC(ConstantRegister(6, false), ConstantRegister(200, true)),
+        };
+        assert_eq!(instruction.stack_source(), Some(6..6));
+    }
+
+    #[test]
+    fn stack_source_concatinate() {
+        let instruction = Instruction::Concatinate {
+            a: 0,
+            mode: BC(Register(2), Register(4)),
+        };
+        assert_eq!(instruction.stack_source(), Some(2..4));
+    }
+
+    #[test]
+    fn stack_source_call() {
+        let instruction = Instruction::Call {
+            a: 3,
+            mode: BC(Generic(3), Generic(1)),
+        };
+        assert_eq!(instruction.stack_source(), Some(3..5));
+    }
+
+    #[test]
+    fn stack_source_return_nothing() {
+        let instruction = Instruction::Return {
+            a: 0,
+            mode: BC(Generic(1), Unused),
+        };
+        assert_eq!(instruction.stack_source(), None);
+    }
+
+    #[test]
+    fn stack_destination_call_multiple_results() {
+        let instruction = Instruction::Call {
+            a: 0,
+            mode: BC(Generic(1), Generic(0)),
+        };
+        assert_eq!(instruction.stack_destination(), Some(0..0));
+    }
+
+    #[test]
+    fn reads_register_to_top() {
+        let instruction = Instruction::Return {
+            a: 2,
+            mode: BC(Generic(0), Unused),
+        };
+        assert!(!instruction.reads_register(1));
+        assert!(instruction.reads_register(7));
+    }
+
+    #[test]
+    fn writes_register() {
+        let set_table = Instruction::SetTable {
+            a: 0,
+            mode: BC(ConstantRegister(1, false), ConstantRegister(2, false)),
+        };
+        assert!(!set_table.writes_register(0));
+
+        let call = Instruction::Call {
+            a: 1,
+            mode: BC(Generic(1), Generic(0)),
+        };
+        assert!(!call.writes_register(0));
+        assert!(call.writes_register(4));
+    }
+
+    #[test]
+    fn move_stack_accesses_close() {
+        let mut instruction = Instruction::Close {
+            a: 3,
+            mode: BC(Unused, Unused),
+        };
+        instruction.move_stack_accesses(2, 4);
+
+        let expected = Instruction::Close {
+            a: 7,
+            mode: BC(Unused, Unused),
+        };
+        assert_eq!(instruction, expected);
+    }
+
+    fn remap_constants(instruction: &mut Instruction) -> Vec<u64> {
+        let mut visited = Vec::new();
+        instruction.for_each_constant_index(&mut |constant| {
+            visited.push(*constant);
+            *constant += 10;
+        });
+        visited
+    }
+
+    #[test]
+    fn for_each_constant_index_bx() {
+        let mut instruction = Instruction::GetGlobal {
+            a: 0,
+            mode: ConstantIndex(2),
+        };
+        assert_eq!(remap_constants(&mut instruction), vec![2]);
+        assert_eq!(instruction, Instruction::GetGlobal {
+            a: 0,
+            mode: ConstantIndex(12),
+        });
+    }
+
+    #[test]
+    fn for_each_constant_index_rk() {
+        let mut instruction = Instruction::Add {
+            a: 0,
+            mode: BC(ConstantRegister(1, false), ConstantRegister(3, true)),
+        };
+        assert_eq!(remap_constants(&mut instruction), vec![3]);
+        assert_eq!(instruction, Instruction::Add {
+            a: 0,
+            mode: BC(ConstantRegister(1, false), ConstantRegister(13, true)),
+        });
+    }
+
+    #[test]
+    fn for_each_constant_index_closure() {
+        let mut instruction = Instruction::Closure {
+            a: 0,
+            mode: PrototypeIndex(2),
+        };
+        assert!(remap_constants(&mut instruction).is_empty());
+        assert_eq!(instruction, Instruction::Closure {
+            a: 0,
+            mode: PrototypeIndex(2),
+        });
+    }
+
+    #[test]
+    fn for_each_constant_index_jump() {
+        let mut instruction = Instruction::Jump { a: 0, mode: SignedBx(2) };
+        assert!(remap_constants(&mut instruction).is_empty());
+        assert_eq!(instruction, Instruction::Jump { a: 0, mode: SignedBx(2) });
+    }
+
+    #[test]
+    fn describe() {
+        let constants = [
+            Constant::String(b"table\0".as_slice().into()),
+            Constant::Number(Number::Float(1.5)),
+            Constant::String(b"\x1bLua\n\0".as_slice().into()),
+        ];
+        let instructions = [
+            (
+                Instruction::LessThan {
+                    a: 0,
+                    mode: BC(ConstantRegister(2, false), ConstantRegister(0, true)),
+                },
+                r#"LessThan A=0 B=R2 C=K0("table")"#,
+            ),
+            (
+                Instruction::Add {
+                    a: 1,
+                    mode: BC(ConstantRegister(1, true), ConstantRegister(7, true)),
+                },
+                "Add A=1 B=K1(1.5) C=K7(?)",
+            ),
+            (Instruction::GetGlobal { a: 3, mode: ConstantIndex(2) }, r#"GetGlobal A=3 Bx=K2("\x1bLua\n")"#),
+            (
+                Instruction::Move {
+                    a: 0,
+                    mode: BC(Register(1), Unused),
+                },
+                "Move A=0 B=R1",
+            ),
+            (
+                Instruction::Call {
+                    a: 0,
+                    mode: BC(Generic(2), Generic(1)),
+                },
+                "Call A=0 B=2 C=1",
+            ),
+            (Instruction::Jump { a: 0, mode: SignedBx(-3) }, "Jump A=0 sBx=-3"),
+            (Instruction::Closure { a: 4, mode: PrototypeIndex(1) }, "Closure A=4 Bx=F1"),
+        ];
+
+        for (instruction, expected) in instructions {
+            assert_eq!(instruction.describe(&constants), expected);
+        }
+    }
 }
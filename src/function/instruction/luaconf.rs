@@ -0,0 +1,345 @@
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use super::InstructionLayout;
+
+/// Definitions that were looked up by
+/// [`lua50::Settings::from_luaconf`](super::lua50::Settings::from_luaconf) or
+/// [`lua51::Settings::from_luaconf`](super::lua51::Settings::from_luaconf).
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct LuaconfReport {
+    /// Definitions that were found and used.
+    pub found: Vec<&'static str>,
+    /// Definitions that were not found, so the default value is used.
+    pub defaulted: Vec<&'static str>,
+    /// Definitions that were found but couldn't be interpreted, so the default
+    /// value is used. This happens for values that are arbitrary expressions
+    /// and for signatures with escape sequences that don't match the default
+    /// signature, since they can't be borrowed from the text.
+    pub unsupported: Vec<&'static str>,
+}
+
+/// Maximum number of macros that are followed when resolving a value like
+/// `#define MAXSTACK LUAI_MAXSTACK`.
+const MAXIMUM_INDIRECTION: usize = 8;
+
+pub(crate) struct Luaconf<'a> {
+    text: &'a str,
+    report: LuaconfReport,
+}
+
+impl<'a> Luaconf<'a> {
+    pub(crate) fn new(text: &'a str) -> Self {
+        Self {
+            text,
+            report: LuaconfReport::default(),
+        }
+    }
+
+    /// Find the value of the first macro with the given name. Function-like
+    /// macros are ignored, and so are comments after the value.
+    fn definition(&self, name: &str) -> Option<&'a str> {
+        self.text.lines().find_map(|line| {
+            let line = line.trim_start().strip_prefix('#')?.trim_start().strip_prefix("define")?;
+            let line = line.strip_prefix([' ', '\t'])?.trim_start();
+            let value = line.strip_prefix(name)?;
+
+            if !value.is_empty() && !value.starts_with([' ', '\t']) {
+                return None;
+            }
+
+            let end = [value.find("/*"), value.find("//")].into_iter().flatten().min().unwrap_or(value.len());
+            Some(value[..end].trim())
+        })
+    }
+
+    fn resolve_integer(&self, value: &str, depth: usize) -> Option<u64> {
+        let mut value = value.trim();
+        while let Some(inner) = value.strip_prefix('(').and_then(|value| value.strip_suffix(')')) {
+            value = inner.trim();
+        }
+
+        if value.starts_with(|character: char| character.is_ascii_alphabetic() || character == '_') {
+            let definition = self.definition(value).filter(|_| depth < MAXIMUM_INDIRECTION)?;
+            return self.resolve_integer(definition, depth + 1);
+        }
+
+        let value = value.trim_end_matches(['u', 'U', 'l', 'L']);
+        match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => value.parse().ok(),
+        }
+    }
+
+    /// Look up an integer value that may be defined under any of the given
+    /// names. The first name is reported if none of them is defined.
+    pub(crate) fn integer(&mut self, names: &[&'static str], default: u64) -> u64 {
+        let Some((name, definition)) = names.iter().find_map(|name| Some((*name, self.definition(name)?))) else {
+            self.report.defaulted.push(names[0]);
+            return default;
+        };
+
+        match self.resolve_integer(definition, 0) {
+            Some(value) => {
+                self.report.found.push(name);
+                value
+            }
+            None => {
+                self.report.unsupported.push(name);
+                default
+            }
+        }
+    }
+
+    /// Look up `LUA_SIGNATURE`.
+    pub(crate) fn signature(&mut self, default: &'a str) -> &'a str {
+        let Some(definition) = self.definition("LUA_SIGNATURE") else {
+            self.report.defaulted.push("LUA_SIGNATURE");
+            return default;
+        };
+
+        let literal = definition.strip_prefix('"').and_then(|literal| literal.strip_suffix('"'));
+        let signature = match literal {
+            Some(literal) if !literal.contains('\\') => Some(literal),
+            Some(literal) => unescape(literal).filter(|signature| signature == default).map(|_| default),
+            None => None,
+        };
+
+        match signature {
+            Some(signature) => {
+                self.report.found.push("LUA_SIGNATURE");
+                signature
+            }
+            None => {
+                self.report.unsupported.push("LUA_SIGNATURE");
+                default
+            }
+        }
+    }
+
+    /// Look up the operand sizes, returned in the order opcode, A, B, C.
+    pub(crate) fn operand_sizes(&mut self, default: &InstructionLayout) -> [u64; 4] {
+        [
+            self.integer(&["SIZE_OP"], default.opcode.size),
+            self.integer(&["SIZE_A"], default.a.size),
+            self.integer(&["SIZE_B"], default.b.size),
+            self.integer(&["SIZE_C"], default.c.size),
+        ]
+    }
+
+    pub(crate) fn finalize(self) -> LuaconfReport {
+        self.report
+    }
+}
+
+/// Decode the escape sequences of a C string literal.
+fn unescape(literal: &str) -> Option<String> {
+    let mut characters = literal.chars().peekable();
+    let mut output = String::new();
+
+    while let Some(character) = characters.next() {
+        if character != '\\' {
+            output.push(character);
+            continue;
+        }
+
+        let escaped = match characters.next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            'a' => '\x07',
+            'b' => '\x08',
+            'f' => '\x0c',
+            'v' => '\x0b',
+            'x' => {
+                let mut value = 0;
+                while let Some(digit) = characters.peek().and_then(|character| character.to_digit(16)) {
+                    value = value * 16 + digit;
+                    characters.next();
+                }
+                char::from_u32(value)?
+            }
+            digit @ '0'..='7' => {
+                let mut value = digit.to_digit(8)?;
+                for _ in 0..2 {
+                    let Some(digit) = characters.peek().and_then(|character| character.to_digit(8)) else {
+                        break;
+                    };
+                    value = value * 8 + digit;
+                    characters.next();
+                }
+                char::from_u32(value)?
+            }
+            other => other,
+        };
+
+        output.push(escaped);
+    }
+
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{unescape, Luaconf, LuaconfReport};
+    use crate::{lua50, lua51, LunifyError};
+
+    // Excerpts of `llimits.h`, `lopcodes.h` and `lundump.h` of Lua 5.0.3.
+    const LUA50_EXCERPT: &str = r#"
+/* maximum stack for a Lua function */
+#define MAXSTACK        250
+
+/*
+** size and position of opcode arguments.
+*/
+#define SIZE_C		9
+#define SIZE_B		9
+#define SIZE_Bx		(SIZE_C + SIZE_B)
+#define SIZE_A		8
+
+#define SIZE_OP		6
+
+#define POS_C		SIZE_OP
+#define POS_B		(POS_C + SIZE_C)
+#define POS_Bx		POS_C
+#define POS_A		(POS_B + SIZE_B)
+
+/* number of list items to accumulate before a SETLIST instruction */
+/* (must be a power of 2) */
+#define LFIELDS_PER_FLUSH	32
+
+/* for header of binary files -- this is Lua 5.0 */
+#define	VERSION		0x50
+#define	VERSION0	0x50
+#define	LUA_SIGNATURE	"\033Lua"	/* binary files start with "<esc>Lua" */
+"#;
+
+    // Excerpts of `llimits.h`, `lopcodes.h`, `lua.h` and `luaconf.h` of Lua 5.1.5.
+    const LUA51_EXCERPT: &str = r#"
+/* maximum stack for a Lua function */
+#define MAXSTACK	250
+
+/*
+** size and position of opcode arguments.
+*/
+#define SIZE_C		9
+#define SIZE_B		9
+#define SIZE_Bx		(SIZE_C + SIZE_B)
+#define SIZE_A		8
+
+#define SIZE_OP		6
+
+#define POS_OP		0
+#define POS_A		(POS_OP + SIZE_OP)
+#define POS_C		(POS_A + SIZE_A)
+#define POS_B		(POS_C + SIZE_C)
+#define POS_Bx		POS_C
+
+/* number of list items to accumulate before a SETLIST instruction */
+#define LFIELDS_PER_FLUSH	50
+
+/* mark for precompiled code (`<esc>Lua') */
+#define	LUA_SIGNATURE	"\033Lua"
+
+/*
+@@ LUAI_MAXCSTACK limits the number of Lua stack slots that a C function
+@* can use.
+*/
+#define LUAI_MAXCSTACK	8000
+"#;
+
+    fn stock_report() -> LuaconfReport {
+        LuaconfReport {
+            found: vec!["MAXSTACK", "LFIELDS_PER_FLUSH", "LUA_SIGNATURE", "SIZE_OP", "SIZE_A", "SIZE_B", "SIZE_C"],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn lua50_stock() -> Result<(), LunifyError> {
+        let (settings, report) = lua50::Settings::from_luaconf(LUA50_EXCERPT)?;
+        assert_eq!(settings, lua50::Settings::default());
+        assert_eq!(report, stock_report());
+        Ok(())
+    }
+
+    #[test]
+    fn lua51_stock() -> Result<(), LunifyError> {
+        let (settings, report) = lua51::Settings::from_luaconf(LUA51_EXCERPT)?;
+        assert_eq!(settings, lua51::Settings::default());
+        assert_eq!(report, stock_report());
+        Ok(())
+    }
+
+    #[test]
+    fn lua51_modified_size_b() -> Result<(), LunifyError> {
+        let text = LUA51_EXCERPT.replace("#define SIZE_B\t\t9", "#define SIZE_B\t\t10");
+        let (settings, report) = lua51::Settings::from_luaconf(&text)?;
+
+        assert_eq!(settings.layout.b.size, 10);
+        assert_eq!(settings.layout.b.position, 23);
+        assert_eq!(settings.layout.bx.size, 19);
+        assert_eq!(settings.get_maximum_constant_index(), 511);
+        assert_eq!(report, stock_report());
+        Ok(())
+    }
+
+    #[test]
+    fn lua51_empty() -> Result<(), LunifyError> {
+        let (settings, report) = lua51::Settings::from_luaconf("")?;
+        let defaulted = vec!["MAXSTACK", "LFIELDS_PER_FLUSH", "LUA_SIGNATURE", "SIZE_OP", "SIZE_A", "SIZE_B", "SIZE_C"];
+
+        assert_eq!(settings, lua51::Settings::default());
+        assert_eq!(report.defaulted, defaulted);
+        Ok(())
+    }
+
+    #[test]
+    fn lua51_invalid_layout() {
+        let text = LUA51_EXCERPT.replace("#define SIZE_A\t\t8", "#define SIZE_A\t\t4");
+        assert_eq!(lua51::Settings::from_luaconf(&text), Err(LunifyError::InvalidInstructionLayout));
+    }
+
+    #[test]
+    fn indirect_stack_limit() -> Result<(), LunifyError> {
+        let text = "#define LUAI_MAXSTACK\t(1000)\n#define MAXSTACK LUAI_MAXSTACK\n";
+        let (settings, report) = lua51::Settings::from_luaconf(text)?;
+
+        assert_eq!(settings.stack_limit, 1000);
+        assert_eq!(report.found, ["MAXSTACK"]);
+        Ok(())
+    }
+
+    #[test]
+    fn unsupported_values() -> Result<(), LunifyError> {
+        let text = "#define LFIELDS_PER_FLUSH (16 * 2)\n#define LUA_SIGNATURE \"\\033Lul\"\n";
+        let (settings, report) = lua51::Settings::from_luaconf(text)?;
+
+        assert_eq!(settings.fields_per_flush, 50);
+        assert_eq!(settings.binary_signature, "\x1bLua");
+        assert_eq!(report.unsupported, ["LFIELDS_PER_FLUSH", "LUA_SIGNATURE"]);
+        Ok(())
+    }
+
+    #[test]
+    fn signature_without_escapes() -> Result<(), LunifyError> {
+        let (settings, _) = lua51::Settings::from_luaconf("#define LUA_SIGNATURE \"\x1bLul\"")?;
+        assert_eq!(settings.binary_signature, "\x1bLul");
+        Ok(())
+    }
+
+    #[test]
+    fn definition_prefix() {
+        let luaconf = Luaconf::new("#define SIZE_Bx 18\n#define SIZE_B(x) x\n# define  SIZE_B 9 /* comment */\n");
+        assert_eq!(luaconf.definition("SIZE_B"), Some("9"));
+    }
+
+    #[test]
+    fn unescape_sequences() {
+        assert_eq!(unescape(r"\033Lua").as_deref(), Some("\x1bLua"));
+        assert_eq!(unescape(r"\x1bLua\n").as_deref(), Some("\x1bLua\n"));
+        assert_eq!(unescape(r"\0").as_deref(), Some("\0"));
+        assert_eq!(unescape(r"\"), None);
+    }
+}
@@ -1,29 +1,136 @@
 macro_rules! lua_instructions {
-    ($($vname:ident ( $mode:ty, $move_a:literal ),)*) => {
+    (@enum $visibility:vis, $($vname:ident ( $mode:ty, $move_a:literal ),)*) => {
+        /// Decoded instruction. The operands are the same as in the byte code,
+        /// except that registers and constants of `B` and `C` are told apart.
         #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-        pub(crate) enum Instruction {
-            $($vname { a: u64, mode: $mode },)*
+        $visibility enum Instruction {
+            $(
+                #[doc = concat!("The `", stringify!($vname), "` instruction.")]
+                $vname {
+                    /// Operand A.
+                    a: u64,
+                    /// The remaining operands.
+                    mode: $mode,
+                },
+            )*
         }
+    };
 
-        impl super::LuaInstruction for Instruction {
-            // Needed because the compiler sees these functions as never being used and index as never being read.
+    // The instructions are public with the `custom-input` feature, so they can be
+    // created by an instruction translator.
+    (custom_input; $($instructions:tt)*) => {
+        #[cfg(feature = "custom-input")]
+        lua_instructions!(@enum pub, $($instructions)*);
+        #[cfg(not(feature = "custom-input"))]
+        lua_instructions!(@enum pub(crate), $($instructions)*);
+        lua_instructions!(@impl $($instructions)*);
+    };
+
+    (@impl $($vname:ident ( $mode:ty, $move_a:literal ),)*) => {
+        impl Instruction {
+            /// The number of opcodes of this version, so tests can check that they
+            /// cover every opcode.
+            #[allow(dead_code)]
+            pub(crate) const VARIANT_COUNT: usize = [$(stringify!($vname)),*].len();
+
+            // Needed because the compiler sees this function as never being used for Lua 5.0.
             #[allow(dead_code, unused_assignments)]
-            fn from_byte_stream(byte_stream: &mut crate::ByteStream, settings: &super::settings::Settings, layout: &InstructionLayout) -> Result<Self, crate::LunifyError> {
+            pub(crate) fn opcode(&self) -> u64 {
+                let mut index = 0;
+
+                $(
+                    if let Instruction::$vname { .. } = self {
+                        return index;
+                    }
+                    index += 1;
+                )*
+
+                index
+            }
+
+            /// Decode an instruction with the opcode table of this version.
+            // Needed because index is never read for the last instruction.
+            #[allow(unused_assignments)]
+            pub(crate) fn decode(
+                value: u64,
+                settings: &super::settings::Settings,
+                layout: &InstructionLayout,
+                program_counter: usize,
+            ) -> Result<Self, crate::LunifyError> {
                 use super::operand::OperandGet;
 
-                let value = byte_stream.instruction()?;
-                let opcode: Opcode = OperandGet::<Self>::get(value, settings, layout);
-                let a: A = OperandGet::<Self>::get(value, settings, layout);
+                let unexpected_bits = |opcode: u64| {
+                    move |field| crate::LunifyError::UnexpectedOperandBits { program_counter, opcode, field }
+                };
+
+                let opcode: Opcode = OperandGet::<Self>::get(value, settings, layout).map_err(unexpected_bits(0))?;
+                let a: A = OperandGet::<Self>::get(value, settings, layout).map_err(unexpected_bits(opcode.0))?;
                 let mut index = 0;
 
                 $(
                     if opcode.0 == index {
-                        return Ok(Instruction::$vname { a: a.0, mode: OperandGet::<Self>::get(value, settings, layout) });
+                        let mode = OperandGet::<Self>::get(value, settings, layout).map_err(unexpected_bits(opcode.0))?;
+                        return Ok(Instruction::$vname { a: a.0, mode });
                     }
                     index += 1;
                 )*
 
-                Err(crate::LunifyError::InvalidOpcode(opcode.0))
+                // An opcode that fits into the opcode field but doesn't exist is treated like
+                // any other unexpected bits when decoding strictly.
+                match settings.strict_decoding {
+                    true => Err(unexpected_bits(opcode.0)(crate::InstructionField::Opcode)),
+                    false => Err(crate::LunifyError::InvalidOpcode(opcode.0)),
+                }
+            }
+
+            /// Mirror the instruction as a public [`InstructionView`](crate::InstructionView).
+            #[allow(dead_code)]
+            pub(crate) fn view(&self) -> crate::InstructionView {
+                use super::operand::OperandView;
+
+                let mut view = crate::InstructionView {
+                    opcode: self.opcode() as u8,
+                    a: 0,
+                    b: None,
+                    c: None,
+                    bx: None,
+                    sbx: None,
+                };
+
+                match self {
+                    $(Self::$vname { a, mode } => {
+                        view.a = *a;
+                        mode.view(&mut view);
+                    },)*
+                }
+
+                view
+            }
+
+            /// Calls the visitor with every index into the constants of the function
+            /// that the instruction holds, so constants can be remapped without
+            /// knowing the operands of every instruction.
+            #[allow(dead_code)]
+            pub(crate) fn for_each_constant_index(&mut self, visitor: &mut dyn FnMut(&mut u64)) {
+                use super::operand::OperandConstants;
+
+                match self {
+                    $(Self::$vname { mode, .. } => mode.constant_indices(visitor),)*
+                }
+            }
+        }
+
+        impl super::LuaInstruction for Instruction {
+            fn from_u64(
+                value: u64,
+                settings: &super::settings::Settings,
+                layout: &InstructionLayout,
+                program_counter: usize,
+            ) -> Result<Self, crate::LunifyError> {
+                match <Self as super::InstructionTranslate>::translate(value, settings, layout, program_counter) {
+                    Some(instruction) => instruction,
+                    None => Self::decode(value, settings, layout, program_counter),
+                }
             }
 
             #[allow(dead_code)]
@@ -52,16 +159,37 @@ macro_rules! lua_instructions {
                 $(
                     if let Instruction::$vname { a, mode } = self {
                         let mut instruction = 0;
-                        instruction |= Opcode(index).put( settings)?;
-                        instruction |= A(*a).put( settings)?;
-                        instruction |= mode.put( settings)?;
+                        instruction |= OperandPut::<Self>::put(Opcode(index), settings)?;
+                        instruction |= OperandPut::<Self>::put(A(*a), settings)?;
+                        instruction |= OperandPut::<Self>::put(*mode, settings)?;
                         return Ok(instruction);
                     }
                     index += 1;
                 )*
 
-                unreachable!()
+                Err(LunifyError::InternalInconsistency("instruction has no opcode"))
+            }
+
+            #[allow(dead_code)]
+            fn describe(&self, constants: &[crate::function::constant::Constant]) -> String {
+                use super::operand::OperandDescribe;
+
+                let mut operands = Vec::new();
+                let name = match self {
+                    $(Self::$vname { a, mode } => {
+                        operands.push(format!("A={a}"));
+                        mode.describe(constants, &mut operands);
+                        stringify!($vname)
+                    },)*
+                };
+
+                format!("{name} {}", operands.join(" "))
             }
         }
-    }
+    };
+
+    ($($instructions:tt)*) => {
+        lua_instructions!(@enum pub(crate), $($instructions)*);
+        lua_instructions!(@impl $($instructions)*);
+    };
 }
@@ -5,10 +5,18 @@ mod interface;
 pub mod lua50;
 /// Lua 5.1 settings.
 pub mod lua51;
+mod luaconf;
 mod operand;
 mod settings;
+mod transcode;
+#[cfg(feature = "custom-input")]
+mod translator;
+mod view;
 
-pub(crate) use self::interface::LuaInstruction;
-pub(crate) use self::operand::{Bx, ConstantRegister, Generic, Register, SignedBx, Unused, BC};
+pub(crate) use self::interface::{InstructionTranslate, LuaInstruction};
+pub(crate) use self::operand::{Bx, ConstantIndex, ConstantRegister, Generic, PrototypeIndex, Register, SignedBx, Unused, BC};
+pub use self::luaconf::LuaconfReport;
 pub use self::operand::{InstructionLayout, OperandType};
-pub use self::settings::Settings;
+pub use self::settings::{validate_output, ConversionLimits, Settings, SettingsBuilder};
+pub use self::transcode::transcode_instruction;
+pub use self::view::{InstructionView, OperandValue};
@@ -1,7 +1,7 @@
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
-use crate::LunifyError;
+use crate::{InstructionField, LunifyError};
 
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
@@ -12,9 +12,16 @@ pub(crate) struct OperandLayout {
 }
 
 impl OperandLayout {
-    pub(crate) fn new(size: u64, position: u64) -> Self {
+    /// Operands can't be empty or wider than an instruction. The upper limit is
+    /// the width of the instruction word rather than 32 bits, since Bx spans
+    /// both B and C.
+    pub(crate) fn new(size: u64, position: u64) -> Result<Self, LunifyError> {
+        if !(1..=u64::BITS as u64).contains(&size) {
+            return Err(LunifyError::InvalidInstructionLayout);
+        }
+
         let bit_mask = !0 >> (64 - size);
-        Self { size, position, bit_mask }
+        Ok(Self { size, position, bit_mask })
     }
 
     pub(crate) fn get(&self, value: u64) -> u64 {
@@ -22,9 +29,7 @@ impl OperandLayout {
     }
 
     pub(crate) fn put(&self, value: u64) -> Result<u64, LunifyError> {
-        let maximum_value = (1 << self.size) - 1;
-
-        if value > maximum_value {
+        if value > self.bit_mask {
             return Err(LunifyError::ValueTooBigForOperand);
         }
 
@@ -89,7 +94,7 @@ impl InstructionLayout {
                         return Err(LunifyError::InvalidInstructionLayout);
                     }
 
-                    opcode = Some(OperandLayout::new(size, offset));
+                    opcode = Some(OperandLayout::new(size, offset)?);
                     offset += size;
                 }
                 OperandType::A(size) => {
@@ -99,7 +104,7 @@ impl InstructionLayout {
                         return Err(LunifyError::InvalidInstructionLayout);
                     }
 
-                    a = Some(OperandLayout::new(size, offset));
+                    a = Some(OperandLayout::new(size, offset)?);
                     offset += size;
                 }
                 OperandType::B(size) => {
@@ -109,7 +114,7 @@ impl InstructionLayout {
                         return Err(LunifyError::InvalidInstructionLayout);
                     }
 
-                    b = Some(OperandLayout::new(size, offset));
+                    b = Some(OperandLayout::new(size, offset)?);
                     offset += size;
                 }
                 OperandType::C(size) => {
@@ -119,7 +124,7 @@ impl InstructionLayout {
                         return Err(LunifyError::InvalidInstructionLayout);
                     }
 
-                    c = Some(OperandLayout::new(size, offset));
+                    c = Some(OperandLayout::new(size, offset)?);
                     offset += size;
                 }
             }
@@ -137,7 +142,7 @@ impl InstructionLayout {
 
         let bx_size = b.size + c.size;
         let bx_position = u64::min(b.position, c.position);
-        let bx = OperandLayout::new(bx_size, bx_position);
+        let bx = OperandLayout::new(bx_size, bx_position)?;
         let signed_offset = (!0u64 >> (64 - bx_size + 1)) as i64;
 
         Ok(Self {
@@ -149,16 +154,43 @@ impl InstructionLayout {
             signed_offset,
         })
     }
+
+    /// Get the value of an opcode or operand field of an instruction.
+    pub fn get_field(&self, instruction: u64, field: InstructionField) -> u64 {
+        match field {
+            InstructionField::Opcode => self.opcode.get(instruction),
+            InstructionField::A => self.a.get(instruction),
+            InstructionField::B => self.b.get(instruction),
+            InstructionField::C => self.c.get(instruction),
+        }
+    }
+
+    /// Get the Bx operand of an instruction, which spans both B and C.
+    pub fn get_bx(&self, instruction: u64) -> u64 {
+        self.bx.get(instruction)
+    }
+
+    /// Get the signed Bx operand of an instruction, like the offset of a jump.
+    pub fn get_signed_bx(&self, instruction: u64) -> i64 {
+        self.bx.get(instruction) as i64 - self.signed_offset
+    }
+
+    /// Get a mask of all the bits that are used by the operands.
+    pub(crate) fn bit_mask(&self) -> u64 {
+        [self.opcode, self.a, self.b, self.c]
+            .iter()
+            .fold(0, |bit_mask, operand| bit_mask | (operand.bit_mask << operand.position))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::function::instruction::operand::OperandLayout;
-    use crate::{InstructionLayout, LunifyError, OperandType};
+    use crate::{InstructionField, InstructionLayout, LunifyError, OperandType};
 
     #[test]
     fn layout_new() {
-        let layout = OperandLayout::new(8, 6);
+        let layout = OperandLayout::new(8, 6).unwrap();
         let expected = OperandLayout {
             size: 8,
             position: 6,
@@ -170,22 +202,42 @@ mod tests {
 
     #[test]
     fn layout_get() {
-        let layout = OperandLayout::new(2, 2);
+        let layout = OperandLayout::new(2, 2).unwrap();
         assert_eq!(layout.get(0b11100), 0b11);
     }
 
     #[test]
     fn layout_put() {
-        let layout = OperandLayout::new(2, 2);
+        let layout = OperandLayout::new(2, 2).unwrap();
         assert_eq!(layout.put(0b11), Ok(0b1100));
     }
 
     #[test]
     fn layout_put_out_of_bounds() {
-        let layout = OperandLayout::new(2, 2);
+        let layout = OperandLayout::new(2, 2).unwrap();
         assert_eq!(layout.put(0b111), Err(LunifyError::ValueTooBigForOperand));
     }
 
+    #[test]
+    fn layout_put_wide() {
+        for size in [31, 32, 63] {
+            let layout = OperandLayout::new(size, 1).unwrap();
+            let maximum_value = !0 >> (64 - size);
+
+            assert_eq!(layout.put(maximum_value), Ok(maximum_value << 1));
+            assert_eq!(layout.put(maximum_value + 1), Err(LunifyError::ValueTooBigForOperand));
+        }
+
+        let layout = OperandLayout::new(64, 0).unwrap();
+        assert_eq!(layout.put(u64::MAX), Ok(u64::MAX));
+    }
+
+    #[test]
+    fn layout_new_invalid_size() {
+        assert_eq!(OperandLayout::new(0, 0), Err(LunifyError::InvalidInstructionLayout));
+        assert_eq!(OperandLayout::new(65, 0), Err(LunifyError::InvalidInstructionLayout));
+    }
+
     #[test]
     fn from_specification() -> Result<(), LunifyError> {
         let layout =
@@ -198,6 +250,25 @@ mod tests {
         assert_eq!(layout.bx.position, 6);
         assert_eq!(layout.bx.size, layout.b.size + layout.c.size);
         assert_eq!(layout.signed_offset, 131071);
+        assert_eq!(layout.bit_mask(), u32::MAX as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn get_fields() -> Result<(), LunifyError> {
+        let layout =
+            InstructionLayout::from_specification([OperandType::Opcode(6), OperandType::C(9), OperandType::B(9), OperandType::A(8)])?;
+
+        // `EQ 1 2 3` and `JMP -2` in Lua 5.0.
+        let equals = 21 | (3 << 6) | (2 << 15) | (1 << 24);
+        assert_eq!(layout.get_field(equals, InstructionField::Opcode), 21);
+        assert_eq!(layout.get_field(equals, InstructionField::A), 1);
+        assert_eq!(layout.get_field(equals, InstructionField::B), 2);
+        assert_eq!(layout.get_field(equals, InstructionField::C), 3);
+        assert_eq!(layout.get_bx(equals), (2 << 9) | 3);
+
+        let jump = 20 | ((131071 - 2) << 6);
+        assert_eq!(layout.get_signed_bx(jump), -2);
         Ok(())
     }
 
@@ -1,18 +1,20 @@
-use crate::{lua50, lua51, LunifyError, Settings};
+use crate::function::constant::{describe_constant_index, Constant};
+use crate::{lua50, lua51, InstructionField, InstructionView, LunifyError, OperandValue, Settings};
 
 mod layout;
 mod mode;
 
 pub(crate) use self::layout::OperandLayout;
 pub use self::layout::{InstructionLayout, OperandType};
-pub(crate) use self::mode::{ConstantRegister, Generic, Register, Unused};
-use self::mode::{ModeGet, ModeOffset, ModePut};
+pub use self::mode::{ConstantRegister, Generic, Register, Unused};
+use self::mode::{ModeConstants, ModeDescribe, ModeGet, ModeOffset, ModePut, ModeView};
 
-pub(crate) trait OperandGet<T> {
-    fn get(value: u64, settings: &Settings, layout: &InstructionLayout) -> Self;
+pub(crate) trait OperandGet<T>: Sized {
+    /// Returns the field that has unexpected bits set when decoding strictly.
+    fn get(value: u64, settings: &Settings, layout: &InstructionLayout) -> Result<Self, InstructionField>;
 }
 
-pub(crate) trait OperandPut {
+pub(crate) trait OperandPut<T> {
     fn put(self, settings: &Settings) -> Result<u64, LunifyError>;
 }
 
@@ -20,16 +22,31 @@ pub(crate) trait OperandOffset {
     fn offset(&mut self, _stack_start: u64, _offset: i64) {}
 }
 
+pub(crate) trait OperandConstants {
+    /// Calls the visitor with every index into the constants of the function.
+    fn constant_indices(&mut self, _visitor: &mut dyn FnMut(&mut u64)) {}
+}
+
+pub(crate) trait OperandDescribe {
+    /// Pushes the description of every operand that is shown, like `B=R2`.
+    fn describe(&self, constants: &[Constant], operands: &mut Vec<String>);
+}
+
+pub(crate) trait OperandView {
+    /// Sets the operands of the view that are used.
+    fn view(&self, view: &mut InstructionView);
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub(crate) struct Opcode(pub u64);
 
 impl<T> OperandGet<T> for Opcode {
-    fn get(value: u64, _settings: &Settings, layout: &InstructionLayout) -> Self {
-        Self(layout.opcode.get(value))
+    fn get(value: u64, _settings: &Settings, layout: &InstructionLayout) -> Result<Self, InstructionField> {
+        Ok(Self(layout.opcode.get(value)))
     }
 }
 
-impl OperandPut for Opcode {
+impl<T> OperandPut<T> for Opcode {
     fn put(self, settings: &Settings) -> Result<u64, LunifyError> {
         settings.output.layout.opcode.put(self.0)
     }
@@ -39,29 +56,30 @@ impl OperandPut for Opcode {
 pub(crate) struct A(pub u64);
 
 impl<T> OperandGet<T> for A {
-    fn get(value: u64, _settings: &Settings, layout: &InstructionLayout) -> Self {
-        Self(layout.a.get(value))
+    fn get(value: u64, _settings: &Settings, layout: &InstructionLayout) -> Result<Self, InstructionField> {
+        Ok(Self(layout.a.get(value)))
     }
 }
 
-impl OperandPut for A {
+impl<T> OperandPut<T> for A {
     fn put(self, settings: &Settings) -> Result<u64, LunifyError> {
         settings.output.layout.a.put(self.0)
     }
 }
 
+/// `B` and `C` operands.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub(crate) struct BC<B, C>(pub B, pub C);
+pub struct BC<B, C>(pub B, pub C);
 
 impl<B, C> OperandGet<lua50::Instruction> for BC<B, C>
 where
     B: ModeGet<lua50::Instruction>,
     C: ModeGet<lua50::Instruction>,
 {
-    fn get(value: u64, settings: &Settings, layout: &InstructionLayout) -> Self {
-        let b = B::get(value, settings, &layout.b);
-        let c = C::get(value, settings, &layout.c);
-        Self(b, c)
+    fn get(value: u64, settings: &Settings, layout: &InstructionLayout) -> Result<Self, InstructionField> {
+        let b = B::get(value, settings, &layout.b).ok_or(InstructionField::B)?;
+        let c = C::get(value, settings, &layout.c).ok_or(InstructionField::C)?;
+        Ok(Self(b, c))
     }
 }
 
@@ -70,17 +88,17 @@ where
     B: ModeGet<lua51::Instruction>,
     C: ModeGet<lua51::Instruction>,
 {
-    fn get(value: u64, settings: &Settings, layout: &InstructionLayout) -> Self {
-        let b = B::get(value, settings, &layout.b);
-        let c = C::get(value, settings, &layout.c);
-        Self(b, c)
+    fn get(value: u64, settings: &Settings, layout: &InstructionLayout) -> Result<Self, InstructionField> {
+        let b = B::get(value, settings, &layout.b).ok_or(InstructionField::B)?;
+        let c = C::get(value, settings, &layout.c).ok_or(InstructionField::C)?;
+        Ok(Self(b, c))
     }
 }
 
-impl<B, C> OperandPut for BC<B, C>
+impl<T, B, C> OperandPut<T> for BC<B, C>
 where
-    B: ModePut,
-    C: ModePut,
+    B: ModePut<T>,
+    C: ModePut<T>,
 {
     fn put(self, settings: &Settings) -> Result<u64, LunifyError> {
         let b = self.0.put(settings, &settings.output.layout.b)?;
@@ -100,16 +118,50 @@ where
     }
 }
 
+impl<B, C> OperandConstants for BC<B, C>
+where
+    B: ModeConstants,
+    C: ModeConstants,
+{
+    fn constant_indices(&mut self, visitor: &mut dyn FnMut(&mut u64)) {
+        self.0.constant_indices(visitor);
+        self.1.constant_indices(visitor);
+    }
+}
+
+impl<B, C> OperandDescribe for BC<B, C>
+where
+    B: ModeDescribe,
+    C: ModeDescribe,
+{
+    fn describe(&self, constants: &[Constant], operands: &mut Vec<String>) {
+        operands.extend(self.0.describe(constants).map(|b| format!("B={b}")));
+        operands.extend(self.1.describe(constants).map(|c| format!("C={c}")));
+    }
+}
+
+impl<B, C> OperandView for BC<B, C>
+where
+    B: ModeView,
+    C: ModeView,
+{
+    fn view(&self, view: &mut InstructionView) {
+        view.b = self.0.view();
+        view.c = self.1.view();
+    }
+}
+
+/// Bx operand that holds a plain value.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub(crate) struct Bx(pub u64);
+pub struct Bx(pub u64);
 
 impl<T> OperandGet<T> for Bx {
-    fn get(value: u64, _settings: &Settings, layout: &InstructionLayout) -> Self {
-        Self(layout.bx.get(value))
+    fn get(value: u64, _settings: &Settings, layout: &InstructionLayout) -> Result<Self, InstructionField> {
+        Ok(Self(layout.bx.get(value)))
     }
 }
 
-impl OperandPut for Bx {
+impl<T> OperandPut<T> for Bx {
     fn put(self, settings: &Settings) -> Result<u64, LunifyError> {
         settings.output.layout.bx.put(self.0)
     }
@@ -117,16 +169,99 @@ impl OperandPut for Bx {
 
 impl OperandOffset for Bx {}
 
+impl OperandConstants for Bx {}
+
+impl OperandDescribe for Bx {
+    fn describe(&self, _constants: &[Constant], operands: &mut Vec<String>) {
+        operands.push(format!("Bx={}", self.0));
+    }
+}
+
+impl OperandView for Bx {
+    fn view(&self, view: &mut InstructionView) {
+        view.bx = Some(OperandValue::Value(self.0));
+    }
+}
+
+/// Bx operand that holds the index of a constant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConstantIndex(pub u64);
+
+impl<T> OperandGet<T> for ConstantIndex {
+    fn get(value: u64, _settings: &Settings, layout: &InstructionLayout) -> Result<Self, InstructionField> {
+        Ok(Self(layout.bx.get(value)))
+    }
+}
+
+impl<T> OperandPut<T> for ConstantIndex {
+    fn put(self, settings: &Settings) -> Result<u64, LunifyError> {
+        settings.output.layout.bx.put(self.0)
+    }
+}
+
+impl OperandOffset for ConstantIndex {}
+
+impl OperandConstants for ConstantIndex {
+    fn constant_indices(&mut self, visitor: &mut dyn FnMut(&mut u64)) {
+        visitor(&mut self.0);
+    }
+}
+
+impl OperandDescribe for ConstantIndex {
+    fn describe(&self, constants: &[Constant], operands: &mut Vec<String>) {
+        operands.push(format!("Bx={}", describe_constant_index(constants, self.0)));
+    }
+}
+
+impl OperandView for ConstantIndex {
+    fn view(&self, view: &mut InstructionView) {
+        view.bx = Some(OperandValue::Constant(self.0));
+    }
+}
+
+/// Bx operand that holds the index of a nested function.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub(crate) struct SignedBx(pub i64);
+pub struct PrototypeIndex(pub u64);
+
+impl<T> OperandGet<T> for PrototypeIndex {
+    fn get(value: u64, _settings: &Settings, layout: &InstructionLayout) -> Result<Self, InstructionField> {
+        Ok(Self(layout.bx.get(value)))
+    }
+}
+
+impl<T> OperandPut<T> for PrototypeIndex {
+    fn put(self, settings: &Settings) -> Result<u64, LunifyError> {
+        settings.output.layout.bx.put(self.0)
+    }
+}
+
+impl OperandOffset for PrototypeIndex {}
+
+impl OperandConstants for PrototypeIndex {}
+
+impl OperandDescribe for PrototypeIndex {
+    fn describe(&self, _constants: &[Constant], operands: &mut Vec<String>) {
+        operands.push(format!("Bx=F{}", self.0));
+    }
+}
+
+impl OperandView for PrototypeIndex {
+    fn view(&self, view: &mut InstructionView) {
+        view.bx = Some(OperandValue::Function(self.0));
+    }
+}
+
+/// Bx operand that holds a signed value, like the offset of a jump.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SignedBx(pub i64);
 
 impl<T> OperandGet<T> for SignedBx {
-    fn get(value: u64, _settings: &Settings, layout: &InstructionLayout) -> Self {
-        Self(layout.bx.get(value) as i64 - layout.signed_offset)
+    fn get(value: u64, _settings: &Settings, layout: &InstructionLayout) -> Result<Self, InstructionField> {
+        Ok(Self(layout.bx.get(value) as i64 - layout.signed_offset))
     }
 }
 
-impl OperandPut for SignedBx {
+impl<T> OperandPut<T> for SignedBx {
     fn put(self, settings: &Settings) -> Result<u64, LunifyError> {
         settings
             .output
@@ -138,26 +273,40 @@ impl OperandPut for SignedBx {
 
 impl OperandOffset for SignedBx {}
 
+impl OperandConstants for SignedBx {}
+
+impl OperandDescribe for SignedBx {
+    fn describe(&self, _constants: &[Constant], operands: &mut Vec<String>) {
+        operands.push(format!("sBx={}", self.0));
+    }
+}
+
+impl OperandView for SignedBx {
+    fn view(&self, view: &mut InstructionView) {
+        view.sbx = Some(self.0);
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{Generic, Opcode, OperandGet, OperandOffset, OperandPut, Register, A};
-    use crate::function::instruction::{Bx, ConstantRegister, SignedBx, BC};
-    use crate::{lua50, lua51, InstructionLayout, Settings};
+    use super::{ConstantIndex, Generic, Opcode, OperandGet, OperandOffset, OperandPut, PrototypeIndex, Register, A};
+    use crate::function::instruction::{Bx, ConstantRegister, SignedBx, Unused, BC};
+    use crate::{lua50, lua51, InstructionField, InstructionLayout, Settings};
 
     fn operand_test<T>(operand: T, value: u64)
     where
-        T: OperandGet<lua51::Instruction> + OperandPut + Eq + std::fmt::Debug,
+        T: OperandGet<lua51::Instruction> + OperandPut<lua51::Instruction> + Copy + Eq + std::fmt::Debug,
     {
         let settings = Settings::default();
-        assert_eq!(T::get(value, &settings, &settings.lua51.layout), operand);
+        assert_eq!(T::get(value, &settings, &settings.lua51.layout), Ok(operand));
         assert_eq!(operand.put(&settings), Ok(value));
     }
 
     fn asymmetric_operand_test<T, L>(settings: &Settings, layout: &InstructionLayout, operand: T, input_value: u64, output_value: u64)
     where
-        T: OperandGet<L> + OperandPut + Eq + std::fmt::Debug,
+        T: OperandGet<L> + OperandPut<lua51::Instruction> + Copy + Eq + std::fmt::Debug,
     {
-        assert_eq!(T::get(input_value, settings, layout), operand);
+        assert_eq!(T::get(input_value, settings, layout), Ok(operand));
         assert_eq!(operand.put(settings), Ok(output_value));
     }
 
@@ -194,6 +343,16 @@ mod tests {
         operand_test(BC(Generic(0), Generic(1)), 1 << 14);
     }
 
+    #[test]
+    fn c_unused_strict() {
+        let settings = Settings {
+            strict_decoding: true,
+            ..Default::default()
+        };
+        let result: Result<BC<Generic, Unused>, _> = OperandGet::<lua51::Instruction>::get(1 << 14, &settings, &settings.lua51.layout);
+        assert_eq!(result, Err(InstructionField::C));
+    }
+
     #[test]
     fn c_const_lua50() {
         let settings = Settings::default();
@@ -234,6 +393,16 @@ mod tests {
         operand_test_offset(Bx(10), 5, Bx(10));
     }
 
+    #[test]
+    fn constant_index() {
+        operand_test(ConstantIndex(1), 1 << 14);
+    }
+
+    #[test]
+    fn prototype_index() {
+        operand_test(PrototypeIndex(1), 1 << 14);
+    }
+
     #[test]
     fn signed_bx() {
         operand_test(SignedBx(1), 131072 << 14);
@@ -1,11 +1,14 @@
 use super::OperandLayout;
-use crate::{lua50, lua51, LunifyError, Settings};
+use crate::function::constant::{describe_constant_index, Constant};
+use crate::{lua50, lua51, LunifyError, OperandValue, Settings};
 
-pub(crate) trait ModeGet<T> {
-    fn get(value: u64, settings: &Settings, layout: &OperandLayout) -> Self;
+pub(crate) trait ModeGet<T>: Sized {
+    /// Returns `None` if the operand has bits set that have to be zero when
+    /// decoding strictly.
+    fn get(value: u64, settings: &Settings, layout: &OperandLayout) -> Option<Self>;
 }
 
-pub(crate) trait ModePut {
+pub(crate) trait ModePut<T> {
     fn put(&self, settings: &Settings, layout: &OperandLayout) -> Result<u64, LunifyError>;
 }
 
@@ -13,16 +16,35 @@ pub(crate) trait ModeOffset {
     fn offset(&mut self, _stack_start: u64, _offset: i64) {}
 }
 
+pub(crate) trait ModeConstants {
+    fn constant_indices(&mut self, _visitor: &mut dyn FnMut(&mut u64)) {}
+}
+
+pub(crate) trait ModeDescribe {
+    /// Returns `None` if the operand is not shown.
+    fn describe(&self, _constants: &[Constant]) -> Option<String> {
+        None
+    }
+}
+
+pub(crate) trait ModeView {
+    /// Returns `None` if the operand is not used.
+    fn view(&self) -> Option<OperandValue> {
+        None
+    }
+}
+
+/// Operand that is not used by the instruction.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub(crate) struct Unused;
+pub struct Unused;
 
 impl<T> ModeGet<T> for Unused {
-    fn get(_value: u64, _settings: &Settings, _layout: &OperandLayout) -> Self {
-        Unused
+    fn get(value: u64, settings: &Settings, layout: &OperandLayout) -> Option<Self> {
+        (!settings.strict_decoding || layout.get(value) == 0).then_some(Unused)
     }
 }
 
-impl ModePut for Unused {
+impl<T> ModePut<T> for Unused {
     fn put(&self, _settings: &Settings, _layout: &OperandLayout) -> Result<u64, LunifyError> {
         Ok(0)
     }
@@ -30,16 +52,23 @@ impl ModePut for Unused {
 
 impl ModeOffset for Unused {}
 
+impl ModeConstants for Unused {}
+
+impl ModeDescribe for Unused {}
+
+impl ModeView for Unused {}
+
+/// Operand that holds a plain value, like a count or an upvalue index.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub(crate) struct Generic(pub u64);
+pub struct Generic(pub u64);
 
 impl<T> ModeGet<T> for Generic {
-    fn get(value: u64, _settings: &Settings, layout: &OperandLayout) -> Self {
-        Generic(layout.get(value))
+    fn get(value: u64, _settings: &Settings, layout: &OperandLayout) -> Option<Self> {
+        Some(Generic(layout.get(value)))
     }
 }
 
-impl ModePut for Generic {
+impl<T> ModePut<T> for Generic {
     fn put(&self, _settings: &Settings, layout: &OperandLayout) -> Result<u64, LunifyError> {
         layout.put(self.0)
     }
@@ -47,16 +76,31 @@ impl ModePut for Generic {
 
 impl ModeOffset for Generic {}
 
+impl ModeConstants for Generic {}
+
+impl ModeDescribe for Generic {
+    fn describe(&self, _constants: &[Constant]) -> Option<String> {
+        Some(self.0.to_string())
+    }
+}
+
+impl ModeView for Generic {
+    fn view(&self) -> Option<OperandValue> {
+        Some(OperandValue::Value(self.0))
+    }
+}
+
+/// Operand that holds a register.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub(crate) struct Register(pub u64);
+pub struct Register(pub u64);
 
 impl<T> ModeGet<T> for Register {
-    fn get(value: u64, _settings: &Settings, layout: &OperandLayout) -> Self {
-        Register(layout.get(value))
+    fn get(value: u64, _settings: &Settings, layout: &OperandLayout) -> Option<Self> {
+        Some(Register(layout.get(value)))
     }
 }
 
-impl ModePut for Register {
+impl<T> ModePut<T> for Register {
     fn put(&self, _settings: &Settings, layout: &OperandLayout) -> Result<u64, LunifyError> {
         layout.put(self.0)
     }
@@ -70,11 +114,27 @@ impl ModeOffset for Register {
     }
 }
 
+impl ModeConstants for Register {}
+
+impl ModeDescribe for Register {
+    fn describe(&self, _constants: &[Constant]) -> Option<String> {
+        Some(format!("R{}", self.0))
+    }
+}
+
+impl ModeView for Register {
+    fn view(&self) -> Option<OperandValue> {
+        Some(OperandValue::Register(self.0))
+    }
+}
+
+/// Operand that holds either a register or the index of a constant (`RK`).
+/// The second field is `true` for constants.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub(crate) struct ConstantRegister(pub u64, pub bool);
+pub struct ConstantRegister(pub u64, pub bool);
 
 impl ModeGet<lua50::Instruction> for ConstantRegister {
-    fn get(value: u64, settings: &Settings, layout: &OperandLayout) -> Self {
+    fn get(value: u64, settings: &Settings, layout: &OperandLayout) -> Option<Self> {
         let mut value = layout.get(value);
         let is_constant = value >= settings.lua50.stack_limit;
 
@@ -82,12 +142,12 @@ impl ModeGet<lua50::Instruction> for ConstantRegister {
             value -= settings.lua50.stack_limit;
         }
 
-        ConstantRegister(value, is_constant)
+        Some(ConstantRegister(value, is_constant))
     }
 }
 
 impl ModeGet<lua51::Instruction> for ConstantRegister {
-    fn get(value: u64, settings: &Settings, layout: &OperandLayout) -> Self {
+    fn get(value: u64, settings: &Settings, layout: &OperandLayout) -> Option<Self> {
         let mut value = layout.get(value);
         let constant_bit = settings.lua51.get_constant_bit();
         let is_constant = value & constant_bit != 0;
@@ -96,11 +156,24 @@ impl ModeGet<lua51::Instruction> for ConstantRegister {
             value ^= constant_bit;
         }
 
-        ConstantRegister(value, is_constant)
+        Some(ConstantRegister(value, is_constant))
     }
 }
 
-impl ModePut for ConstantRegister {
+impl ModePut<lua50::Instruction> for ConstantRegister {
+    fn put(&self, settings: &Settings, layout: &OperandLayout) -> Result<u64, LunifyError> {
+        // Registers at or above the stack limit would be read back as constants.
+        let value = match self.1 {
+            true => self.0.checked_add(settings.lua50.stack_limit).ok_or(LunifyError::ValueTooBigForOperand)?,
+            false if self.0 >= settings.lua50.stack_limit => return Err(LunifyError::ValueTooBigForOperand),
+            false => self.0,
+        };
+
+        layout.put(value)
+    }
+}
+
+impl ModePut<lua51::Instruction> for ConstantRegister {
     fn put(&self, settings: &Settings, layout: &OperandLayout) -> Result<u64, LunifyError> {
         if self.0 > settings.output.get_maximum_constant_index() {
             return Err(LunifyError::ValueTooBigForOperand);
@@ -123,6 +196,32 @@ impl ModeOffset for ConstantRegister {
     }
 }
 
+impl ModeConstants for ConstantRegister {
+    fn constant_indices(&mut self, visitor: &mut dyn FnMut(&mut u64)) {
+        if self.1 {
+            visitor(&mut self.0);
+        }
+    }
+}
+
+impl ModeDescribe for ConstantRegister {
+    fn describe(&self, constants: &[Constant]) -> Option<String> {
+        match self.1 {
+            true => Some(describe_constant_index(constants, self.0)),
+            false => Some(format!("R{}", self.0)),
+        }
+    }
+}
+
+impl ModeView for ConstantRegister {
+    fn view(&self) -> Option<OperandValue> {
+        match self.1 {
+            true => Some(OperandValue::Constant(self.0)),
+            false => Some(OperandValue::Register(self.0)),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::{ConstantRegister, Generic, ModeGet, ModeOffset, Register, Unused};
@@ -134,13 +233,13 @@ mod tests {
         T: ModeGet<L> + Eq + std::fmt::Debug,
     {
         let settings = Settings::default();
-        let result: T = ModeGet::<L>::get(value << 6, &settings, &settings.lua50.layout.c);
-        assert_eq!(result, expected);
+        let result: Option<T> = ModeGet::<L>::get(value << 6, &settings, &settings.lua50.layout.c);
+        assert_eq!(result, Some(expected));
     }
 
     fn mode_test_put<T>(value: T, expected: Result<u64, LunifyError>)
     where
-        T: ModePut + Eq + std::fmt::Debug,
+        T: ModePut<lua51::Instruction> + Eq + std::fmt::Debug,
     {
         let settings = Settings::default();
         let result = value.put(&settings, &settings.lua50.layout.c);
@@ -160,6 +259,18 @@ mod tests {
         mode_test_get::<_, ()>(0, Unused);
     }
 
+    #[test]
+    fn unused_get_strict() {
+        let settings = Settings {
+            strict_decoding: true,
+            ..Default::default()
+        };
+        let layout = &settings.lua50.layout.c;
+
+        assert_eq!(ModeGet::<()>::get(0, &settings, layout), Some(Unused));
+        assert_eq!(ModeGet::<()>::get(1 << 6, &settings, layout), None::<Unused>);
+    }
+
     #[test]
     fn unused_put() {
         mode_test_put(Unused, Ok(0));
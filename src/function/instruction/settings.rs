@@ -1,15 +1,28 @@
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
-use super::{lua50, lua51};
+#[cfg(feature = "custom-input")]
+use super::translator::InstructionTranslator;
+use super::{lua50, lua51, InstructionLayout};
+use crate::lua51::OpcodeSet;
+use crate::{
+    DiagnosticCallback, Endianness, Format, FunctionTrailerMode, FunctionTrailerSpec, LineOverflowPolicy, LunifyError, ProgressCallback,
+    SettingsDescription, SourceRewrite, TrailingPadding,
+};
 
 /// Lua 5.0 and Lua 5.1 compile constants. The Lua interpreter is compiled with
 /// certain predefined constants that affect how the byte code is generated.
 /// This structure represents a small subset of the constants that are relevant
 /// for Lunify. If the byte code you are trying to modify was complied with
 /// non-standard constants, you can use these settings to make it compatible.
-#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+///
+/// New settings are added in minor versions, so the settings can't be
+/// constructed with a struct expression outside of Lunify. Use
+/// [`Settings::builder`] or change the fields of [`Settings::default`]
+/// instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
 pub struct Settings<'a> {
     /// Lua 5.0 input compile constants.
     #[cfg_attr(feature = "serde", serde(borrow))]
@@ -20,4 +33,621 @@ pub struct Settings<'a> {
     /// Emitted Lua 5.1 compile constants.
     #[cfg_attr(feature = "serde", serde(borrow))]
     pub output: lua51::Settings<'a>,
+    /// Return an error if an instruction has bits set in an operand that it
+    /// doesn't use. Such bits usually mean that the instruction layout doesn't
+    /// match the byte code.
+    pub strict_decoding: bool,
+    /// Keep the bits of Lua 5.1 instructions that are outside of the
+    /// instruction layout, for example flags that a modified VM stores in the
+    /// upper half of 64-bit instructions. Instructions that are inserted or
+    /// modified during conversion have all of those bits cleared. Lua 5.0
+    /// instructions are always re-encoded without them.
+    pub preserve_instruction_padding: bool,
+    /// Limits that bound the time spent converting byte code from an untrusted
+    /// source.
+    pub limits: ConversionLimits,
+    /// Don't check that the constants, nested functions and upvalues referenced
+    /// by the instructions of the input exist. The Lua loader doesn't check
+    /// them either, so byte code with invalid references crashes the VM once
+    /// it runs. Only set this to convert such byte code deliberately.
+    pub skip_input_validation: bool,
+    /// Bytes after the main function of the input that are accepted as
+    /// padding instead of returning
+    /// [`InputTooLong`](LunifyError::InputTooLong). The padding is dropped,
+    /// unless `preserve_padding` is set in the output settings.
+    pub allow_trailing_padding: TrailingPadding,
+    /// Decode the input with this endianness instead of the one declared in its
+    /// header, for byte code where only the header was patched. The output
+    /// always uses the endianness of the output [`Format`].
+    pub endianness_override: Option<Endianness>,
+    /// Decode the input with the opposite endianness of the one declared in its
+    /// header if the start of the main function only makes sense that way, for
+    /// example because its source file would be longer than the input. This is
+    /// reported as a [`Diagnostic`](crate::Diagnostic). Ignored if
+    /// [`endianness_override`](Settings::endianness_override) is set.
+    pub auto_detect_endianness: bool,
+    /// Repair local variables in the debug information whose ranges don't fit
+    /// the instructions of their function, which strict Lua 5.1 loaders
+    /// reject. Ranges are clamped to the instructions, local variables whose
+    /// range starts after it ends are dropped, and so are local variables that
+    /// would exceed `LUAI_MAXVARS` (200) active local variables. Every repair
+    /// is reported as a [`Diagnostic`](crate::Diagnostic). If unset,
+    /// [`InvalidLocalVariable`](LunifyError::InvalidLocalVariable) or
+    /// [`TooManyLocalVariables`](LunifyError::TooManyLocalVariables) is
+    /// returned instead. Functions that are copied as is aren't checked.
+    pub repair_debug_info: bool,
+    /// Allow [extract](crate::extract) to extract functions that capture
+    /// upvalues. Lua 5.1 gives the main function of a chunk a new upvalue
+    /// holding `nil` for every upvalue it declares, so the function can be
+    /// loaded, but reading any of the upvalues returns `nil` rather than the
+    /// captured value.
+    pub extract_closures: bool,
+    /// Allow [set_constant](crate::set_constant) to replace a constant with a
+    /// value of a different type. Constants compared by an `EQ`, `LT` or `LE`
+    /// instruction still keep their type, since the comparison would silently
+    /// change its result or raise an error at runtime.
+    pub allow_constant_type_change: bool,
+    /// Keep the converted instructions of every function, so they are listed
+    /// as [`InstructionView`](crate::InstructionView)s in the
+    /// [`FunctionReport`](crate::FunctionReport)s of
+    /// [unify_with_report](crate::unify_with_report). This costs memory for
+    /// every instruction, so it is off by default.
+    pub retain_typed_instructions: bool,
+    /// Paths of functions, as returned by [`list_functions`](crate::list_functions),
+    /// that are copied to the output as they are in the input, together with
+    /// their nested functions. Their instructions aren't decoded, so this can
+    /// be used for hand-written byte code that a conversion would change. The
+    /// input needs to be Lua 5.1 byte code with the encoding and instruction
+    /// layout of the output, otherwise converting returns
+    /// [`IncompatibleOutputConfiguration`](LunifyError::IncompatibleOutputConfiguration).
+    /// This is not serialized.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub skip_function_paths: &'a [&'a [usize]],
+    /// Called with the [`Progress`](crate::Progress) of the conversion, for
+    /// example to update a progress bar. The functions of the chunk are counted
+    /// up front if this is set. This is not serialized.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub progress: Option<ProgressCallback<'a>>,
+    /// Called with every [`Diagnostic`](crate::Diagnostic) of the conversion,
+    /// like instructions with bits set in unused fields when `strict_decoding`
+    /// is unset. This is not serialized.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub diagnostics: Option<DiagnosticCallback<'a>>,
+}
+
+impl Default for Settings<'_> {
+    fn default() -> Self {
+        Self {
+            lua50: Default::default(),
+            lua51: Default::default(),
+            output: Default::default(),
+            strict_decoding: false,
+            preserve_instruction_padding: false,
+            limits: ConversionLimits::default(),
+            skip_input_validation: false,
+            allow_trailing_padding: TrailingPadding::None,
+            endianness_override: None,
+            auto_detect_endianness: false,
+            repair_debug_info: true,
+            extract_closures: false,
+            allow_constant_type_change: false,
+            retain_typed_instructions: false,
+            skip_function_paths: &[],
+            progress: None,
+            diagnostics: None,
+        }
+    }
+}
+
+impl<'a> Settings<'a> {
+    /// Describe the resolved numeric values of the settings, including the
+    /// ones derived from them. See [`SettingsDescription`].
+    pub fn describe(&self) -> SettingsDescription {
+        SettingsDescription::new(self)
+    }
+
+    /// Create a [`SettingsBuilder`] starting from the default settings.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use lunify::{unify, BitWidth, Format, LunifyError, Settings};
+    ///
+    /// # fn main() -> Result<(), LunifyError> {
+    /// // Lua 5.0 byte code from a 32 bit machine, converted for a 64 bit machine
+    /// // with a custom signature.
+    /// let settings = Settings::builder()
+    ///     .lua50_fields_per_flush(32)
+    ///     .output_binary_signature("\x1bLul")
+    ///     .output_verify(true)
+    ///     .build()?;
+    ///
+    /// let output_format = Format {
+    ///     size_t_width: BitWidth::Bit64,
+    ///     ..Format::default()
+    /// };
+    ///
+    /// let input_bytes = include_bytes!("../../../test_files/lua50.luab");
+    /// let output_bytes = unify(input_bytes, &output_format, &settings)?;
+    /// assert!(output_bytes.starts_with(b"\x1bLul"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn builder() -> SettingsBuilder<'a> {
+        SettingsBuilder::default()
+    }
+
+    /// Check that the settings can be used for conversion. Returns
+    /// [`InvalidSettings`](LunifyError::InvalidSettings) with the name of the
+    /// first setting that can't be used. This is checked once by
+    /// [`Converter::new`](crate::Converter::new).
+    pub fn validate(&self) -> Result<(), LunifyError> {
+        if self.lua50.fields_per_flush == 0 {
+            return Err(LunifyError::InvalidSettings("lua50.fields_per_flush"));
+        }
+
+        if self.lua51.fields_per_flush == 0 {
+            return Err(LunifyError::InvalidSettings("lua51.fields_per_flush"));
+        }
+
+        if self.output.fields_per_flush == 0 {
+            return Err(LunifyError::InvalidSettings("output.fields_per_flush"));
+        }
+
+        // The stack size is stored in a single byte and every register needs to be
+        // addressable by the A operand.
+        let maximum_stack_limit = (self.output.layout.a.bit_mask + 1).min(u8::MAX as u64);
+        if self.output.stack_limit == 0 || self.output.stack_limit > maximum_stack_limit {
+            return Err(LunifyError::InvalidSettings("output.stack_limit"));
+        }
+
+        // A flush reads the table and all of its fields from the stack. Since B can hold
+        // any register, this also makes sure that the number of fields fits into B of
+        // the `SETLIST` instruction.
+        if self.output.fields_per_flush >= self.output.stack_limit {
+            return Err(LunifyError::InvalidSettings("output.fields_per_flush"));
+        }
+
+        // Only a global or a field of a global table can be renamed, and the new name
+        // replaces the last part.
+        let is_name = |name: &str| !name.is_empty() && !name.contains('.');
+        let is_valid_rename = |(name, new_name): &(&str, &str)| {
+            let mut parts = name.splitn(2, '.');
+            parts.all(is_name) && is_name(new_name)
+        };
+        if !self.output.rename_globals.iter().all(is_valid_rename) {
+            return Err(LunifyError::InvalidSettings("output.rename_globals"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Check that byte code converted with the output settings can be loaded with
+/// the output [`Format`]. Returns
+/// [`IncompatibleOutputConfiguration`](LunifyError::IncompatibleOutputConfiguration)
+/// describing the first problem that was found. This is checked by every
+/// function that converts byte code.
+pub fn validate_output(format: &Format, output: &lua51::Settings) -> Result<(), LunifyError> {
+    let layout = &output.layout;
+    let layout_width = layout.opcode.size + layout.a.size + layout.b.size + layout.c.size;
+    if layout_width > u8::from(format.instruction_width) as u64 * 8 {
+        return Err(LunifyError::IncompatibleOutputConfiguration(
+            "the instruction layout is wider than the instruction width",
+        ));
+    }
+
+    // RK operands use the constant bit of B for both B and C.
+    let constant_bit = output.get_constant_bit();
+    if constant_bit > layout.c.bit_mask {
+        return Err(LunifyError::IncompatibleOutputConfiguration(
+            "the constant bit of RK operands doesn't fit into C",
+        ));
+    }
+
+    if output.stack_limit > constant_bit {
+        return Err(LunifyError::IncompatibleOutputConfiguration(
+            "registers below the stack limit overlap the constant bit of RK operands",
+        ));
+    }
+
+    if output.fields_per_flush == 0 {
+        return Err(LunifyError::IncompatibleOutputConfiguration("fields per flush is zero"));
+    }
+
+    if output.fields_per_flush > layout.b.bit_mask {
+        return Err(LunifyError::IncompatibleOutputConfiguration(
+            "fields per flush doesn't fit into B of SETLIST",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Limits that bound the work done while converting, so crafted byte code
+/// can't make a conversion take arbitrarily long. Exceeding any of them
+/// results in [`LimitExceeded`](crate::LunifyError::LimitExceeded).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct ConversionLimits {
+    /// Maximum number of instructions of a single function, both in the input
+    /// and after conversion.
+    pub max_instructions_per_function: u64,
+    /// Maximum number of instructions of all functions in the input combined.
+    pub max_total_instructions: u64,
+    /// Maximum number of instructions to look back from a `SETLIST` for the
+    /// start of the table constructor when it needs to be rewritten.
+    pub max_setlist_scan_depth: u64,
+}
+
+impl Default for ConversionLimits {
+    fn default() -> Self {
+        Self {
+            max_instructions_per_function: 1 << 20,
+            max_total_instructions: 1 << 24,
+            max_setlist_scan_depth: 1 << 16,
+        }
+    }
+}
+
+/// Generates a method of [`SettingsBuilder`] for every listed field of one part
+/// of the settings.
+macro_rules! builder_methods {
+    ($section:ident: $path:literal { $($(#[$attribute:meta])* $method:ident => $field:ident: $type:ty,)* }) => {
+        $(
+            $(#[$attribute])*
+            #[doc = concat!("Set [`", stringify!($section), ".", stringify!($field), "`](", $path, "::", stringify!($field), ").")]
+            pub fn $method(mut self, $field: $type) -> Self {
+                self.settings.$section.$field = $field;
+                self
+            }
+        )*
+    };
+}
+
+/// Builds [`Settings`] one setting at a time, starting from the default
+/// settings. Every setting can be changed through a method named after the
+/// part of the settings it belongs to and its field, so adding new settings
+/// doesn't break code using the builder.
+///
+/// ```rust
+/// use lunify::{LunifyError, Settings};
+///
+/// let result = Settings::builder().output_fields_per_flush(0).build();
+/// assert_eq!(result, Err(LunifyError::InvalidSettings("output.fields_per_flush")));
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SettingsBuilder<'a> {
+    settings: Settings<'a>,
+}
+
+impl<'a> SettingsBuilder<'a> {
+    /// Check the settings with [`Settings::validate`] and return them.
+    pub fn build(self) -> Result<Settings<'a>, LunifyError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+
+    /// Replace all Lua 5.0 input settings.
+    pub fn lua50(mut self, lua50: lua50::Settings<'a>) -> Self {
+        self.settings.lua50 = lua50;
+        self
+    }
+
+    /// Replace all Lua 5.1 input settings.
+    pub fn lua51(mut self, lua51: lua51::Settings<'a>) -> Self {
+        self.settings.lua51 = lua51;
+        self
+    }
+
+    /// Replace all output settings.
+    pub fn output(mut self, output: lua51::Settings<'a>) -> Self {
+        self.settings.output = output;
+        self
+    }
+
+    /// Replace all [`ConversionLimits`].
+    pub fn limits(mut self, limits: ConversionLimits) -> Self {
+        self.settings.limits = limits;
+        self
+    }
+
+    /// Set [`strict_decoding`](Settings::strict_decoding).
+    pub fn strict_decoding(mut self, strict_decoding: bool) -> Self {
+        self.settings.strict_decoding = strict_decoding;
+        self
+    }
+
+    /// Set [`preserve_instruction_padding`](Settings::preserve_instruction_padding).
+    pub fn preserve_instruction_padding(mut self, preserve_instruction_padding: bool) -> Self {
+        self.settings.preserve_instruction_padding = preserve_instruction_padding;
+        self
+    }
+
+    /// Set [`skip_input_validation`](Settings::skip_input_validation).
+    pub fn skip_input_validation(mut self, skip_input_validation: bool) -> Self {
+        self.settings.skip_input_validation = skip_input_validation;
+        self
+    }
+
+    /// Set [`allow_trailing_padding`](Settings::allow_trailing_padding).
+    pub fn allow_trailing_padding(mut self, allow_trailing_padding: TrailingPadding) -> Self {
+        self.settings.allow_trailing_padding = allow_trailing_padding;
+        self
+    }
+
+    /// Set [`endianness_override`](Settings::endianness_override).
+    pub fn endianness_override(mut self, endianness_override: Option<Endianness>) -> Self {
+        self.settings.endianness_override = endianness_override;
+        self
+    }
+
+    /// Set [`auto_detect_endianness`](Settings::auto_detect_endianness).
+    pub fn auto_detect_endianness(mut self, auto_detect_endianness: bool) -> Self {
+        self.settings.auto_detect_endianness = auto_detect_endianness;
+        self
+    }
+
+    /// Set [`repair_debug_info`](Settings::repair_debug_info).
+    pub fn repair_debug_info(mut self, repair_debug_info: bool) -> Self {
+        self.settings.repair_debug_info = repair_debug_info;
+        self
+    }
+
+    /// Set [`extract_closures`](Settings::extract_closures).
+    pub fn extract_closures(mut self, extract_closures: bool) -> Self {
+        self.settings.extract_closures = extract_closures;
+        self
+    }
+
+    /// Set [`allow_constant_type_change`](Settings::allow_constant_type_change).
+    pub fn allow_constant_type_change(mut self, allow_constant_type_change: bool) -> Self {
+        self.settings.allow_constant_type_change = allow_constant_type_change;
+        self
+    }
+
+    /// Set [`retain_typed_instructions`](Settings::retain_typed_instructions).
+    pub fn retain_typed_instructions(mut self, retain_typed_instructions: bool) -> Self {
+        self.settings.retain_typed_instructions = retain_typed_instructions;
+        self
+    }
+
+    /// Set [`skip_function_paths`](Settings::skip_function_paths).
+    pub fn skip_function_paths(mut self, skip_function_paths: &'a [&'a [usize]]) -> Self {
+        self.settings.skip_function_paths = skip_function_paths;
+        self
+    }
+
+    /// Set [`progress`](Settings::progress).
+    pub fn progress(mut self, progress: Option<ProgressCallback<'a>>) -> Self {
+        self.settings.progress = progress;
+        self
+    }
+
+    /// Set [`diagnostics`](Settings::diagnostics).
+    pub fn diagnostics(mut self, diagnostics: Option<DiagnosticCallback<'a>>) -> Self {
+        self.settings.diagnostics = diagnostics;
+        self
+    }
+
+    builder_methods!(lua50: "lua50::Settings" {
+        lua50_stack_limit => stack_limit: u64,
+        lua50_fields_per_flush => fields_per_flush: u64,
+        lua50_binary_signature => binary_signature: &'a str,
+        lua50_layout => layout: InstructionLayout,
+        lua50_auto_detect_fields_per_flush => auto_detect_fields_per_flush: bool,
+        lua50_inverted_test_polarity => inverted_test_polarity: bool,
+        #[cfg(feature = "custom-input")]
+        lua50_instruction_translator => instruction_translator: Option<InstructionTranslator<'a>>,
+    });
+
+    // Most Lua 5.1 settings are only used in the output settings, so only the ones
+    // that affect reading the input have a method.
+    builder_methods!(lua51: "lua51::Settings" {
+        lua51_stack_limit => stack_limit: u64,
+        lua51_fields_per_flush => fields_per_flush: u64,
+        lua51_binary_signature => binary_signature: &'a str,
+        lua51_layout => layout: InstructionLayout,
+        lua51_function_trailer => function_trailer: Option<FunctionTrailerSpec>,
+    });
+
+    builder_methods!(output: "lua51::Settings" {
+        output_stack_limit => stack_limit: u64,
+        output_fields_per_flush => fields_per_flush: u64,
+        output_binary_signature => binary_signature: &'a str,
+        output_layout => layout: InstructionLayout,
+        output_rewrite_source => rewrite_source: Option<SourceRewrite<'a>>,
+        output_emit_vararg_count => emit_vararg_count: bool,
+        output_peephole => peephole: bool,
+        output_function_trailer => function_trailer: Option<FunctionTrailerSpec>,
+        output_function_trailer_mode => function_trailer_mode: FunctionTrailerMode<'a>,
+        output_max_output_size => max_output_size: Option<usize>,
+        output_disallowed_opcodes => disallowed_opcodes: OpcodeSet,
+        output_length_shim => length_shim: &'a str,
+        output_verify => verify: bool,
+        output_use_needsarg_flag => use_needsarg_flag: bool,
+        output_deduplicate_source => deduplicate_source: bool,
+        output_preserve_prefix => preserve_prefix: bool,
+        output_preserve_padding => preserve_padding: bool,
+        output_tforprep_type_global => tforprep_type_global: &'a str,
+        output_tforprep_next_global => tforprep_next_global: &'a str,
+        output_tforprep_assume_table => tforprep_assume_table: bool,
+        output_synthetic_constants_last => synthetic_constants_last: bool,
+        output_canonicalize_constants => canonicalize_constants: bool,
+        output_jump_closes_upvalues => jump_closes_upvalues: bool,
+        output_root_source_override => root_source_override: Option<&'a str>,
+        output_zero_root_lines => zero_root_lines: bool,
+        output_line_number_overflow => line_number_overflow: LineOverflowPolicy,
+        output_fold_constants => fold_constants: bool,
+        output_lower_power_to_call => lower_power_to_call: bool,
+        output_power_global => power_global: &'a str,
+        output_ensure_final_return => ensure_final_return: bool,
+        output_rename_globals => rename_globals: &'a [(&'a str, &'a str)],
+        #[cfg(feature = "metadata")]
+        output_append_metadata => append_metadata: bool,
+    });
+
+    builder_methods!(limits: "ConversionLimits" {
+        max_instructions_per_function => max_instructions_per_function: u64,
+        max_total_instructions => max_total_instructions: u64,
+        max_setlist_scan_depth => max_setlist_scan_depth: u64,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate_output, ConversionLimits, Settings};
+    use crate::{lua50, lua51, BitWidth, Format, InstructionLayout, LunifyError, OperandType};
+
+    #[test]
+    fn validate_default() {
+        assert_eq!(Settings::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_fields_per_flush() {
+        let mut settings = Settings::default();
+        settings.output.fields_per_flush = 0;
+        assert_eq!(settings.validate(), Err(LunifyError::InvalidSettings("output.fields_per_flush")));
+    }
+
+    #[test]
+    fn validate_fields_per_flush_stack_limit() {
+        let mut settings = Settings::default();
+        settings.output.fields_per_flush = 249;
+        assert_eq!(settings.validate(), Ok(()));
+
+        settings.output.fields_per_flush = 250;
+        assert_eq!(settings.validate(), Err(LunifyError::InvalidSettings("output.fields_per_flush")));
+    }
+
+    #[test]
+    fn builder_matches_struct() -> Result<(), LunifyError> {
+        let settings = Settings::builder()
+            .lua50_fields_per_flush(16)
+            .lua51_binary_signature("\x1bLul")
+            .output_verify(true)
+            .output_stack_limit(200)
+            .max_total_instructions(1000)
+            .strict_decoding(true)
+            .build()?;
+
+        let expected = Settings {
+            lua50: lua50::Settings {
+                fields_per_flush: 16,
+                ..Default::default()
+            },
+            lua51: lua51::Settings {
+                binary_signature: "\x1bLul",
+                ..Default::default()
+            },
+            output: lua51::Settings {
+                verify: true,
+                stack_limit: 200,
+                ..Default::default()
+            },
+            limits: ConversionLimits {
+                max_total_instructions: 1000,
+                ..Default::default()
+            },
+            strict_decoding: true,
+            ..Default::default()
+        };
+
+        assert_eq!(settings, expected);
+        assert_eq!(Settings::builder().build()?, Settings::default());
+        Ok(())
+    }
+
+    #[test]
+    fn builder_validates() {
+        let result = Settings::builder().output_stack_limit(0).build();
+        assert_eq!(result, Err(LunifyError::InvalidSettings("output.stack_limit")));
+    }
+
+    #[test]
+    fn validate_stack_limit() {
+        let mut settings = Settings::default();
+        settings.output.stack_limit = 256;
+        assert_eq!(settings.validate(), Err(LunifyError::InvalidSettings("output.stack_limit")));
+    }
+
+    fn with_layout(a: u64, c: u64, b: u64) -> Result<lua51::Settings<'static>, LunifyError> {
+        let specification = [OperandType::Opcode(6), OperandType::A(a), OperandType::C(c), OperandType::B(b)];
+        Ok(lua51::Settings {
+            layout: InstructionLayout::from_specification(specification)?,
+            ..Default::default()
+        })
+    }
+
+    fn incompatible(description: &'static str) -> Result<(), LunifyError> {
+        Err(LunifyError::IncompatibleOutputConfiguration(description))
+    }
+
+    #[test]
+    fn validate_output_default() {
+        assert_eq!(validate_output(&Format::default(), &lua51::Settings::default()), Ok(()));
+    }
+
+    #[test]
+    fn validate_output_layout_width() -> Result<(), LunifyError> {
+        let output = with_layout(10, 12, 12)?;
+
+        let format = Format {
+            instruction_width: BitWidth::Bit64,
+            ..Default::default()
+        };
+
+        assert_eq!(validate_output(&format, &output), Ok(()));
+        assert_eq!(
+            validate_output(&Format::default(), &output),
+            incompatible("the instruction layout is wider than the instruction width")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn validate_output_constant_bit() -> Result<(), LunifyError> {
+        let output = with_layout(8, 8, 10)?;
+
+        assert_eq!(
+            validate_output(&Format::default(), &output),
+            incompatible("the constant bit of RK operands doesn't fit into C")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn validate_output_stack_limit() -> Result<(), LunifyError> {
+        // The default stack limit is 250.
+        let mut output = with_layout(10, 8, 8)?;
+
+        assert_eq!(
+            validate_output(&Format::default(), &output),
+            incompatible("registers below the stack limit overlap the constant bit of RK operands")
+        );
+
+        output.stack_limit = 128;
+        assert_eq!(validate_output(&Format::default(), &output), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn validate_output_fields_per_flush() {
+        let mut output = lua51::Settings {
+            fields_per_flush: 0,
+            ..Default::default()
+        };
+        assert_eq!(validate_output(&Format::default(), &output), incompatible("fields per flush is zero"));
+
+        output.fields_per_flush = 512;
+        assert_eq!(
+            validate_output(&Format::default(), &output),
+            incompatible("fields per flush doesn't fit into B of SETLIST")
+        );
+
+        output.fields_per_flush = 511;
+        assert_eq!(validate_output(&Format::default(), &output), Ok(()));
+    }
 }
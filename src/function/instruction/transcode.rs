@@ -0,0 +1,419 @@
+use super::{lua50, lua51, InstructionLayout, LuaInstruction, Settings};
+use crate::{LuaVersion, LunifyError};
+
+/// Re-encodes a single instruction of the given version from one
+/// [`InstructionLayout`] to another, without converting it to Lua 5.1. The
+/// instruction is decoded with the opcodes and operands of its version, so
+/// constants in `RK` operands and the offsets of jumps keep their meaning.
+///
+/// Bits that are not part of the `from` layout are dropped. Returns the same
+/// errors as decoding and encoding the instruction in a chunk, like
+/// [`LunifyError::InvalidOpcode`] or [`LunifyError::ValueTooBigForOperand`].
+///
+/// # Example
+///
+/// ```rust
+/// use lunify::{lua50, lua51, transcode_instruction, LuaVersion, LunifyError, Settings};
+///
+/// # fn main() -> Result<(), LunifyError> {
+/// let settings = Settings::default();
+/// let lua50_layout = lua50::Settings::default().layout;
+/// let lua51_layout = lua51::Settings::default().layout;
+///
+/// // `MOVE 1 2` in the Lua 5.0 layout.
+/// let value = transcode_instruction(1 << 24 | 2 << 15, &lua50_layout, LuaVersion::Lua50, &lua51_layout, &settings)?;
+/// assert_eq!(value, 1 << 6 | 2 << 23);
+/// # Ok(())
+/// # }
+/// ```
+pub fn transcode_instruction(
+    value: u64,
+    from: &InstructionLayout,
+    version: LuaVersion,
+    to: &InstructionLayout,
+    settings: &Settings,
+) -> Result<u64, LunifyError> {
+    // Instructions are decoded with the layout of the input settings and
+    // encoded with the layout of the output settings.
+    let mut settings = *settings;
+    settings.lua50.layout = *from;
+    settings.lua51.layout = *from;
+    settings.output.layout = *to;
+
+    match version {
+        LuaVersion::Lua50 => lua50::Instruction::from_u64(value, &settings, from, 0)?.to_u64(&settings),
+        LuaVersion::Lua51 => lua51::Instruction::from_u64(value, &settings, from, 0)?.to_u64(&settings),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::transcode_instruction;
+    use crate::function::instruction::{
+        lua50, lua51, Bx, ConstantIndex, ConstantRegister, Generic, LuaInstruction, PrototypeIndex, Register, SignedBx, Unused, BC,
+    };
+    use crate::{InstructionLayout, LuaVersion, LunifyError, OperandType, Settings};
+
+    fn layouts() -> [InstructionLayout; 3] {
+        let custom =
+            InstructionLayout::from_specification([OperandType::Opcode(6), OperandType::A(8), OperandType::C(8), OperandType::B(10)]);
+        [
+            lua50::Settings::default().layout,
+            lua51::Settings::default().layout,
+            custom.unwrap(),
+        ]
+    }
+
+    fn encode<T: LuaInstruction>(instruction: &T, layout: &InstructionLayout) -> u64 {
+        let mut settings = Settings::default();
+        settings.output.layout = *layout;
+        instruction.to_u64(&settings).unwrap()
+    }
+
+    fn transcode_all<T: LuaInstruction>(instructions: &[T], version: LuaVersion) {
+        let settings = Settings::default();
+
+        for instruction in instructions {
+            for from in &layouts() {
+                for to in &layouts() {
+                    let value = encode(instruction, from);
+                    assert_eq!(
+                        transcode_instruction(value, from, version, to, &settings),
+                        Ok(encode(instruction, to))
+                    );
+                }
+            }
+        }
+    }
+
+    fn rk(value: u64, is_constant: bool) -> ConstantRegister {
+        ConstantRegister(value, is_constant)
+    }
+
+    #[test]
+    fn every_lua50_opcode() {
+        use lua50::Instruction;
+
+        let instructions = [
+            Instruction::Move {
+                a: 1,
+                mode: BC(Register(2), Unused),
+            },
+            Instruction::LoadK {
+                a: 1,
+                mode: ConstantIndex(300),
+            },
+            Instruction::LoadBool {
+                a: 1,
+                mode: BC(Generic(1), Generic(1)),
+            },
+            Instruction::LoadNil {
+                a: 1,
+                mode: BC(Register(3), Unused),
+            },
+            Instruction::GetUpValue {
+                a: 1,
+                mode: BC(Generic(2), Unused),
+            },
+            Instruction::GetGlobal {
+                a: 1,
+                mode: ConstantIndex(2),
+            },
+            Instruction::GetTable {
+                a: 1,
+                mode: BC(Register(2), rk(3, true)),
+            },
+            Instruction::SetGlobal {
+                a: 1,
+                mode: ConstantIndex(2),
+            },
+            Instruction::SetUpValue {
+                a: 1,
+                mode: BC(Generic(2), Unused),
+            },
+            Instruction::SetTable {
+                a: 1,
+                mode: BC(rk(2, true), rk(3, false)),
+            },
+            Instruction::NewTable {
+                a: 1,
+                mode: BC(Generic(4), Generic(5)),
+            },
+            Instruction::_Self {
+                a: 1,
+                mode: BC(Register(2), rk(3, true)),
+            },
+            Instruction::Add {
+                a: 1,
+                mode: BC(rk(2, false), rk(3, true)),
+            },
+            Instruction::Subtract {
+                a: 1,
+                mode: BC(rk(2, true), rk(3, false)),
+            },
+            Instruction::Multiply {
+                a: 1,
+                mode: BC(rk(2, true), rk(3, true)),
+            },
+            Instruction::Divide {
+                a: 1,
+                mode: BC(rk(2, false), rk(3, false)),
+            },
+            Instruction::Power {
+                a: 1,
+                mode: BC(rk(2, false), rk(3, true)),
+            },
+            Instruction::Unary {
+                a: 1,
+                mode: BC(Register(2), rk(0, false)),
+            },
+            Instruction::Not {
+                a: 1,
+                mode: BC(Register(2), Unused),
+            },
+            Instruction::Concatinate {
+                a: 1,
+                mode: BC(Register(2), Register(3)),
+            },
+            Instruction::Jump { a: 0, mode: SignedBx(-5) },
+            Instruction::Equals {
+                a: 1,
+                mode: BC(rk(2, false), rk(3, true)),
+            },
+            Instruction::LessThan {
+                a: 0,
+                mode: BC(rk(2, true), rk(3, false)),
+            },
+            Instruction::LessEquals {
+                a: 1,
+                mode: BC(rk(2, false), rk(3, false)),
+            },
+            Instruction::Test {
+                a: 1,
+                mode: BC(Register(2), Generic(1)),
+            },
+            Instruction::Call {
+                a: 1,
+                mode: BC(Generic(2), Generic(0)),
+            },
+            Instruction::TailCall {
+                a: 1,
+                mode: BC(Generic(0), Generic(0)),
+            },
+            Instruction::Return {
+                a: 1,
+                mode: BC(Generic(2), Unused),
+            },
+            Instruction::ForLoop { a: 1, mode: SignedBx(-3) },
+            Instruction::TForLoop {
+                a: 1,
+                mode: BC(Unused, Generic(2)),
+            },
+            Instruction::TForPrep { a: 1, mode: SignedBx(4) },
+            Instruction::SetList { a: 1, mode: Bx(31) },
+            Instruction::SetListO { a: 1, mode: Bx(63) },
+            Instruction::Close {
+                a: 1,
+                mode: BC(Unused, Unused),
+            },
+            Instruction::Closure {
+                a: 1,
+                mode: PrototypeIndex(2),
+            },
+        ];
+
+        assert!(instructions.iter().map(Instruction::opcode).eq(0..35));
+        transcode_all(&instructions, LuaVersion::Lua50);
+    }
+
+    #[test]
+    fn every_lua51_opcode() {
+        use lua51::Instruction;
+
+        // The constant bit of the custom layout doesn't fit into C, so constants
+        // are only read from B.
+        let instructions = [
+            Instruction::Move {
+                a: 1,
+                mode: BC(Register(2), Unused),
+            },
+            Instruction::LoadK {
+                a: 1,
+                mode: ConstantIndex(300),
+            },
+            Instruction::LoadBool {
+                a: 1,
+                mode: BC(Generic(1), Generic(1)),
+            },
+            Instruction::LoadNil {
+                a: 1,
+                mode: BC(Register(3), Unused),
+            },
+            Instruction::GetUpValue {
+                a: 1,
+                mode: BC(Generic(2), Unused),
+            },
+            Instruction::GetGlobal {
+                a: 1,
+                mode: ConstantIndex(2),
+            },
+            Instruction::GetTable {
+                a: 1,
+                mode: BC(Register(2), rk(3, false)),
+            },
+            Instruction::SetGlobal {
+                a: 1,
+                mode: ConstantIndex(2),
+            },
+            Instruction::SetUpValue {
+                a: 1,
+                mode: BC(Generic(2), Unused),
+            },
+            Instruction::SetTable {
+                a: 1,
+                mode: BC(rk(2, true), rk(3, false)),
+            },
+            Instruction::NewTable {
+                a: 1,
+                mode: BC(Generic(4), Generic(5)),
+            },
+            Instruction::_Self {
+                a: 1,
+                mode: BC(Register(2), rk(3, false)),
+            },
+            Instruction::Add {
+                a: 1,
+                mode: BC(rk(2, true), rk(3, false)),
+            },
+            Instruction::Subtract {
+                a: 1,
+                mode: BC(rk(2, false), rk(3, false)),
+            },
+            Instruction::Multiply {
+                a: 1,
+                mode: BC(rk(2, true), rk(3, false)),
+            },
+            Instruction::Divide {
+                a: 1,
+                mode: BC(rk(2, false), rk(3, false)),
+            },
+            Instruction::Modulo {
+                a: 1,
+                mode: BC(rk(2, true), rk(3, false)),
+            },
+            Instruction::Power {
+                a: 1,
+                mode: BC(rk(2, true), rk(3, false)),
+            },
+            Instruction::Unary {
+                a: 1,
+                mode: BC(Register(2), rk(0, false)),
+            },
+            Instruction::Not {
+                a: 1,
+                mode: BC(Register(2), Unused),
+            },
+            Instruction::Length {
+                a: 1,
+                mode: BC(Register(2), Unused),
+            },
+            Instruction::Concatinate {
+                a: 1,
+                mode: BC(Register(2), Register(3)),
+            },
+            Instruction::Jump { a: 0, mode: SignedBx(-5) },
+            Instruction::Equals {
+                a: 1,
+                mode: BC(rk(2, true), rk(3, false)),
+            },
+            Instruction::LessThan {
+                a: 0,
+                mode: BC(rk(2, false), rk(3, false)),
+            },
+            Instruction::LessEquals {
+                a: 1,
+                mode: BC(rk(2, true), rk(3, false)),
+            },
+            Instruction::Test {
+                a: 1,
+                mode: BC(Register(2), Generic(1)),
+            },
+            Instruction::TestSet {
+                a: 1,
+                mode: BC(rk(2, false), Generic(0)),
+            },
+            Instruction::Call {
+                a: 1,
+                mode: BC(Generic(2), Generic(0)),
+            },
+            Instruction::TailCall {
+                a: 1,
+                mode: BC(Generic(0), Generic(0)),
+            },
+            Instruction::Return {
+                a: 1,
+                mode: BC(Generic(2), Unused),
+            },
+            Instruction::ForLoop { a: 1, mode: SignedBx(-3) },
+            Instruction::ForPrep { a: 1, mode: SignedBx(4) },
+            Instruction::TForLoop {
+                a: 1,
+                mode: BC(Unused, Generic(2)),
+            },
+            Instruction::SetList {
+                a: 1,
+                mode: BC(Generic(50), Generic(1)),
+            },
+            Instruction::Close {
+                a: 1,
+                mode: BC(Unused, Unused),
+            },
+            Instruction::Closure {
+                a: 1,
+                mode: PrototypeIndex(2),
+            },
+            Instruction::VarArg {
+                a: 1,
+                mode: BC(Generic(0), Unused),
+            },
+        ];
+
+        assert!(instructions.iter().map(Instruction::opcode).eq(0..38));
+        transcode_all(&instructions, LuaVersion::Lua51);
+    }
+
+    #[test]
+    fn errors() {
+        let settings = Settings::default();
+        let [lua50_layout, lua51_layout, custom_layout] = layouts();
+
+        // Opcode 40 doesn't exist in Lua 5.1.
+        assert_eq!(
+            transcode_instruction(40, &lua51_layout, LuaVersion::Lua51, &lua50_layout, &settings),
+            Err(LunifyError::InvalidOpcode(40))
+        );
+
+        // `ADD 1 2 K3` has the constant bit of the custom layout in C.
+        let add = lua51::Instruction::Add {
+            a: 1,
+            mode: BC(rk(2, false), rk(3, true)),
+        };
+        let value = encode(&add, &lua51_layout);
+        assert_eq!(
+            transcode_instruction(value, &lua51_layout, LuaVersion::Lua51, &custom_layout, &settings),
+            Err(LunifyError::ValueTooBigForOperand)
+        );
+    }
+
+    #[test]
+    fn padding_is_dropped() {
+        let settings = Settings::default();
+        let layout = lua51::Settings::default().layout;
+        let value = 1 << 6 | 2 << 23 | 1 << 40;
+
+        assert_eq!(
+            transcode_instruction(value, &layout, LuaVersion::Lua51, &layout, &settings),
+            Ok(1 << 6 | 2 << 23)
+        );
+    }
+}
@@ -0,0 +1,230 @@
+use std::fmt::{Debug, Formatter};
+use std::hash::{Hash, Hasher};
+
+use super::lua50::Instruction;
+use super::InstructionLayout;
+use crate::{LunifyError, Settings};
+
+/// Lua 5.0 instruction as it is stored in the byte code, together with its
+/// operands decoded according to the configured
+/// [`InstructionLayout`](crate::InstructionLayout). Passed to the
+/// [`InstructionTranslator`].
+#[derive(Clone, Copy)]
+pub struct RawInstruction<'a> {
+    /// The instruction as it is stored in the byte code.
+    pub value: u64,
+    /// The opcode.
+    pub opcode: u64,
+    /// Operand A.
+    pub a: u64,
+    /// Operand B.
+    pub b: u64,
+    /// Operand C.
+    pub c: u64,
+    /// Operand Bx, which overlaps B and C.
+    pub bx: u64,
+    /// Index of the instruction in the function.
+    pub program_counter: usize,
+    settings: &'a Settings<'a>,
+    layout: &'a InstructionLayout,
+}
+
+impl<'a> RawInstruction<'a> {
+    pub(crate) fn new(value: u64, settings: &'a Settings<'a>, layout: &'a InstructionLayout, program_counter: usize) -> Self {
+        Self {
+            value,
+            opcode: layout.opcode.get(value),
+            a: layout.a.get(value),
+            b: layout.b.get(value),
+            c: layout.c.get(value),
+            bx: layout.bx.get(value),
+            program_counter,
+            settings,
+            layout,
+        }
+    }
+
+    /// Decode the instruction like it is decoded without a translator.
+    pub fn decode(&self) -> Result<Instruction, LunifyError> {
+        Instruction::decode(self.value, self.settings, self.layout, self.program_counter)
+    }
+
+    /// Decode the instruction as if it had a different opcode, for example to
+    /// undo a shift of the opcode table.
+    pub fn decode_as(&self, opcode: u64) -> Result<Instruction, LunifyError> {
+        let opcode_layout = &self.layout.opcode;
+        let value = self.value & !(opcode_layout.bit_mask << opcode_layout.position) | opcode_layout.put(opcode)?;
+        Instruction::decode(value, self.settings, self.layout, self.program_counter)
+    }
+}
+
+impl Debug for RawInstruction<'_> {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        formatter
+            .debug_struct("RawInstruction")
+            .field("value", &self.value)
+            .field("opcode", &self.opcode)
+            .field("a", &self.a)
+            .field("b", &self.b)
+            .field("c", &self.c)
+            .field("bx", &self.bx)
+            .field("program_counter", &self.program_counter)
+            .finish_non_exhaustive()
+    }
+}
+
+/// User supplied function that decodes every Lua 5.0 instruction instead of
+/// the built in opcode table, for input from compilers with a modified opcode
+/// table. Translators are compared and hashed by address.
+///
+/// # Example
+///
+/// A compiler that folds `NOT` into `UNM`, using `C` to tell them apart, so
+/// every opcode after `UNM` is shifted down by one.
+///
+/// ```rust
+/// use lunify::lua50::{BC, Instruction, InstructionTranslator, RawInstruction, Register, Unused};
+/// use lunify::{LunifyError, Settings};
+///
+/// const UNM: u64 = 17;
+///
+/// let translate = |raw: RawInstruction| -> Result<Instruction, LunifyError> {
+///     match raw.opcode {
+///         UNM if raw.c == 1 => Ok(Instruction::Not {
+///             a: raw.a,
+///             mode: BC(Register(raw.b), Unused),
+///         }),
+///         UNM => raw.decode_as(UNM),
+///         opcode if opcode > UNM => raw.decode_as(opcode + 1),
+///         _ => raw.decode(),
+///     }
+/// };
+///
+/// let settings = Settings::builder()
+///     .lua50_instruction_translator(Some(InstructionTranslator(&translate)))
+///     .build()?;
+/// # let _ = settings;
+/// # Ok::<(), LunifyError>(())
+/// ```
+#[derive(Clone, Copy)]
+pub struct InstructionTranslator<'a>(pub &'a dyn Fn(RawInstruction) -> Result<Instruction, LunifyError>);
+
+impl InstructionTranslator<'_> {
+    fn address(&self) -> usize {
+        self.0 as *const _ as *const () as usize
+    }
+}
+
+impl Debug for InstructionTranslator<'_> {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(formatter, "InstructionTranslator({:#x})", self.address())
+    }
+}
+
+impl PartialEq for InstructionTranslator<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.address() == other.address()
+    }
+}
+
+impl Eq for InstructionTranslator<'_> {}
+
+impl PartialOrd for InstructionTranslator<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InstructionTranslator<'_> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.address().cmp(&other.address())
+    }
+}
+
+impl Hash for InstructionTranslator<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.address().hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InstructionTranslator, RawInstruction};
+    use crate::function::instruction::{LuaInstruction, Register, Unused, BC};
+    use crate::serialization::{ByteStream, ByteWriter};
+    use crate::{lua50, Format, LunifyError, Settings};
+
+    const UNM: u64 = 17;
+
+    // A compiler that folds `NOT` into `UNM` and shifts the following opcodes.
+    fn merged_not(raw: RawInstruction) -> Result<lua50::Instruction, LunifyError> {
+        match raw.opcode {
+            UNM if raw.c == 1 => Ok(lua50::Instruction::Not {
+                a: raw.a,
+                mode: BC(Register(raw.b), Unused),
+            }),
+            UNM => raw.decode_as(UNM),
+            opcode if opcode > UNM => raw.decode_as(opcode + 1),
+            _ => raw.decode(),
+        }
+    }
+
+    fn decode(values: &[u64], settings: &Settings) -> Result<Vec<lua50::Instruction>, LunifyError> {
+        let format = Format::default();
+        let mut byte_writer = ByteWriter::new(&format);
+        values.iter().for_each(|value| byte_writer.instruction(*value));
+
+        let bytes = byte_writer.finalize();
+        let mut byte_stream = ByteStream::new(&bytes);
+
+        (0..values.len())
+            .map(|program_counter| {
+                lua50::Instruction::from_byte_stream(&mut byte_stream, settings, &settings.lua50.layout, program_counter)
+                    .map(|(instruction, _)| instruction)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn merged_unary_and_not() -> Result<(), LunifyError> {
+        let settings = Settings {
+            lua50: lua50::Settings {
+                instruction_translator: Some(InstructionTranslator(&merged_not)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // `UNM 1 2`, merged `NOT 1 2`, `RETURN 0 1` with a shifted opcode and `MOVE 1 2`.
+        let values = [UNM | (2 << 15) | (1 << 24), UNM | (1 << 6) | (2 << 15) | (1 << 24), 26 | (1 << 15), (2 << 15) | (1 << 24)];
+        let instructions = decode(&values, &settings)?;
+
+        assert!(matches!(instructions[0], lua50::Instruction::Unary { a: 1, mode: BC(Register(2), _) }));
+        assert!(matches!(instructions[1], lua50::Instruction::Not { a: 1, mode: BC(Register(2), Unused) }));
+        assert!(matches!(instructions[2], lua50::Instruction::Return { a: 0, .. }));
+        assert!(matches!(instructions[3], lua50::Instruction::Move { a: 1, mode: BC(Register(2), _) }));
+        Ok(())
+    }
+
+    #[test]
+    fn translator_error() {
+        let translate = |_: RawInstruction| Err(LunifyError::InvalidOpcode(9));
+        let settings = Settings {
+            lua50: lua50::Settings {
+                instruction_translator: Some(InstructionTranslator(&translate)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert_eq!(decode(&[0], &settings), Err(LunifyError::InvalidOpcode(9)));
+    }
+
+    #[test]
+    fn raw_operands() {
+        let settings = Settings::default();
+        let raw = RawInstruction::new(5 | (3 << 6) | (2 << 15) | (1 << 24), &settings, &settings.lua50.layout, 0);
+
+        assert_eq!((raw.opcode, raw.a, raw.b, raw.c, raw.bx), (5, 1, 2, 3, (2 << 9) | 3));
+    }
+}
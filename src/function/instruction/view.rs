@@ -0,0 +1,130 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use super::lua51::OPCODE_NAMES;
+
+/// A converted Lua 5.1 instruction, as it was right before being encoded. See
+/// [`retain_typed_instructions`](crate::Settings::retain_typed_instructions).
+///
+/// Only the operands that the instruction uses are set, so a `MOVE` has `b`
+/// but neither `c`, `bx` nor `sbx`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct InstructionView {
+    /// The Lua 5.1 opcode, as defined in `lopcodes.h`.
+    pub opcode: u8,
+    /// Operand A.
+    pub a: u64,
+    /// Operand B.
+    pub b: Option<OperandValue>,
+    /// Operand C.
+    pub c: Option<OperandValue>,
+    /// Operand Bx.
+    pub bx: Option<OperandValue>,
+    /// Operand sBx, the offset of a jump.
+    pub sbx: Option<i64>,
+}
+
+impl InstructionView {
+    /// The name of the opcode, like `MOVE` or `FORPREP`.
+    pub fn name(&self) -> &'static str {
+        OPCODE_NAMES.get(self.opcode as usize).copied().unwrap_or("UNKNOWN")
+    }
+}
+
+/// What an operand of an [`InstructionView`] refers to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum OperandValue {
+    /// A register of the function.
+    Register(u64),
+    /// An index into the constants of the function.
+    Constant(u64),
+    /// An index into the nested functions of the function.
+    Function(u64),
+    /// Any other value, like a number of results or an upvalue index.
+    Value(u64),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InstructionView, OperandValue};
+    use crate::function::instruction::{ConstantIndex, ConstantRegister, Generic, PrototypeIndex, Register, SignedBx, Unused, BC};
+    use crate::lua51::Instruction;
+
+    fn view(opcode: u8, a: u64) -> InstructionView {
+        InstructionView {
+            opcode,
+            a,
+            b: None,
+            c: None,
+            bx: None,
+            sbx: None,
+        }
+    }
+
+    #[test]
+    fn for_loop() {
+        // The up-cast of a Lua 5.0 `FORLOOP` that preserves RA+3.
+        let instructions = [
+            Instruction::GetGlobal { a: 3, mode: ConstantIndex(0) },
+            Instruction::SetGlobal { a: 3, mode: ConstantIndex(0) },
+            Instruction::ForLoop { a: 0, mode: SignedBx(-3) },
+        ];
+
+        let views: Vec<InstructionView> = instructions.iter().map(Instruction::view).collect();
+        assert_eq!(views, [
+            InstructionView {
+                bx: Some(OperandValue::Constant(0)),
+                ..view(5, 3)
+            },
+            InstructionView {
+                bx: Some(OperandValue::Constant(0)),
+                ..view(7, 3)
+            },
+            InstructionView {
+                sbx: Some(-3),
+                ..view(31, 0)
+            },
+        ]);
+        assert_eq!(views[2].name(), "FORLOOP");
+    }
+
+    #[test]
+    fn operands() {
+        let add = Instruction::Add {
+            a: 0,
+            mode: BC(ConstantRegister(1, false), ConstantRegister(2, true)),
+        };
+        assert_eq!(add.view(), InstructionView {
+            b: Some(OperandValue::Register(1)),
+            c: Some(OperandValue::Constant(2)),
+            ..view(12, 0)
+        });
+
+        let call = Instruction::Call {
+            a: 1,
+            mode: BC(Generic(2), Generic(0)),
+        };
+        assert_eq!(call.view(), InstructionView {
+            b: Some(OperandValue::Value(2)),
+            c: Some(OperandValue::Value(0)),
+            ..view(28, 1)
+        });
+
+        let unary = Instruction::Not {
+            a: 0,
+            mode: BC(Register(1), Unused),
+        };
+        assert_eq!(unary.view(), InstructionView {
+            b: Some(OperandValue::Register(1)),
+            ..view(19, 0)
+        });
+
+        let closure = Instruction::Closure { a: 2, mode: PrototypeIndex(1) };
+        assert_eq!(closure.view(), InstructionView {
+            bx: Some(OperandValue::Function(1)),
+            ..view(36, 2)
+        });
+    }
+}
@@ -0,0 +1,64 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{BitWidth, Format};
+
+/// What to do with line numbers that don't fit into the integers of the output
+/// format, for example when converting byte code with 64-bit integers to byte
+/// code with 32-bit integers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum LineOverflowPolicy {
+    /// Return [`ValueTooBigForWidth`](crate::LunifyError::ValueTooBigForWidth).
+    #[default]
+    Error,
+    /// Write the closest line number that fits instead.
+    Clamp,
+    /// Write zero instead, like for a line that is unknown.
+    Zero,
+}
+
+impl LineOverflowPolicy {
+    /// Get the line number that is written to the output. Line numbers that fit
+    /// are always written as is.
+    pub(crate) fn apply(self, line: i64, format: &Format) -> i64 {
+        match self {
+            _ if !is_overflowing(line, format) => line,
+            LineOverflowPolicy::Error => line,
+            LineOverflowPolicy::Clamp => line.clamp(i32::MIN as i64, i32::MAX as i64),
+            LineOverflowPolicy::Zero => 0,
+        }
+    }
+}
+
+pub(crate) fn is_overflowing(line: i64, format: &Format) -> bool {
+    format.integer_width == BitWidth::Bit32 && i32::try_from(line).is_err()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LineOverflowPolicy;
+    use crate::{BitWidth, Format};
+
+    #[test]
+    fn fitting_lines_are_kept() {
+        let wide_format = Format {
+            integer_width: BitWidth::Bit64,
+            ..Format::default()
+        };
+
+        for policy in [LineOverflowPolicy::Error, LineOverflowPolicy::Clamp, LineOverflowPolicy::Zero] {
+            assert_eq!(policy.apply(i32::MAX as i64, &Format::default()), i32::MAX as i64);
+            assert_eq!(policy.apply(1 << 33, &wide_format), 1 << 33);
+        }
+    }
+
+    #[test]
+    fn overflowing_lines() {
+        let format = Format::default();
+        assert_eq!(LineOverflowPolicy::Error.apply(1 << 33, &format), 1 << 33);
+        assert_eq!(LineOverflowPolicy::Clamp.apply(1 << 33, &format), i32::MAX as i64);
+        assert_eq!(LineOverflowPolicy::Clamp.apply(-(1 << 33), &format), i32::MIN as i64);
+        assert_eq!(LineOverflowPolicy::Zero.apply(1 << 33, &format), 0);
+    }
+}
@@ -1,5 +1,254 @@
-pub(crate) struct LocalVariable {
-    pub(crate) name: String,
+use crate::diagnostic::report_diagnostic;
+use crate::{DiagnosticCode, LunifyError, Settings};
+
+/// Maximum number of local variables that can be active at the same time
+/// (`LUAI_MAXVARS`).
+const MAXIMUM_ACTIVE_LOCAL_VARIABLES: usize = 200;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct LocalVariable<'a> {
+    pub(crate) name: &'a [u8],
     pub(crate) start_program_counter: i64,
     pub(crate) end_program_counter: i64,
 }
+
+impl LocalVariable<'_> {
+    /// Whether this is the `arg` local that Lua 5.0 declares for variadic
+    /// functions right after the parameters.
+    fn is_implicit_arg(&self) -> bool {
+        self.start_program_counter == 0 && self.name.strip_suffix(&[0]).unwrap_or(self.name) == b"arg"
+    }
+
+    /// The name without the terminating null byte, for diagnostics.
+    fn display_name(&self) -> String {
+        String::from_utf8_lossy(self.name.strip_suffix(&[0]).unwrap_or(self.name)).into_owned()
+    }
+}
+
+/// Find the register of the implicit `arg` local of a variadic Lua 5.0
+/// function. Locals are assigned registers in the order they are declared, so
+/// the register is the number of locals declared before it at the start of the
+/// function. If the debug information is stripped, `arg` is assumed to follow
+/// the parameters.
+pub(crate) fn implicit_arg_register(local_variables: &[LocalVariable], parameter_count: u8) -> u64 {
+    local_variables
+        .iter()
+        .filter(|local_variable| local_variable.start_program_counter == 0)
+        .position(LocalVariable::is_implicit_arg)
+        .map_or(parameter_count as u64, |register| register as u64)
+}
+
+/// The implicit `arg` local of a variadic Lua 5.0 function is only set once
+/// the prologue ran, so its entry starts after the prologue. Any other entry of
+/// the implicit local is dropped, so debuggers only show it once.
+pub(crate) fn move_implicit_arg(local_variables: &mut Vec<LocalVariable>, prologue_length: i64) {
+    let Some(index) = local_variables.iter().position(LocalVariable::is_implicit_arg) else {
+        return;
+    };
+
+    let mut implicit_arg = local_variables[index];
+    implicit_arg.start_program_counter = prologue_length;
+    implicit_arg.end_program_counter += prologue_length;
+
+    local_variables.retain(|local_variable| !local_variable.is_implicit_arg());
+    local_variables.insert(index, implicit_arg);
+}
+
+/// Make sure the ranges of the local variables fit the instructions of the
+/// function and that no more than `LUAI_MAXVARS` local variables are active at
+/// the same time. Depending on
+/// [`repair_debug_info`](Settings::repair_debug_info), local variables that
+/// don't are either repaired or an error is returned. Returns whether any local
+/// variable was changed.
+pub(crate) fn repair_local_variables(
+    local_variables: &mut Vec<LocalVariable>,
+    instruction_count: usize,
+    path: &[usize],
+    settings: &Settings,
+) -> Result<bool, LunifyError> {
+    let instruction_count = instruction_count as i64;
+    let mut is_repaired = false;
+    let mut index = 0;
+
+    // Local variables that start after they end are dropped after clamping, since
+    // they don't describe any instruction.
+    while index < local_variables.len() {
+        let local_variable = local_variables[index];
+        let start_program_counter = local_variable.start_program_counter.clamp(0, instruction_count);
+        let end_program_counter = local_variable.end_program_counter.clamp(0, instruction_count);
+        let is_inverted = start_program_counter > end_program_counter;
+
+        if !is_inverted
+            && start_program_counter == local_variable.start_program_counter
+            && end_program_counter == local_variable.end_program_counter
+        {
+            index += 1;
+            continue;
+        }
+
+        if !settings.repair_debug_info {
+            return Err(LunifyError::InvalidLocalVariable {
+                index,
+                start_program_counter: local_variable.start_program_counter,
+                end_program_counter: local_variable.end_program_counter,
+            });
+        }
+
+        report_diagnostic(settings, DiagnosticCode::LocalVariableRepaired, path, None, || {
+            let action = match is_inverted {
+                true => "dropped",
+                false => "clamped",
+            };
+            format!(
+                "{action} range {}..{} of local variable {:?}",
+                local_variable.start_program_counter,
+                local_variable.end_program_counter,
+                local_variable.display_name()
+            )
+        });
+
+        is_repaired = true;
+        match is_inverted {
+            true => {
+                local_variables.remove(index);
+            }
+            false => {
+                local_variables[index].start_program_counter = start_program_counter;
+                local_variables[index].end_program_counter = end_program_counter;
+                index += 1;
+            }
+        }
+    }
+
+    // Sweep over the local variables in the order they become active. Once there
+    // are too many, the one declared last is dropped.
+    let mut order: Vec<usize> = (0..local_variables.len()).collect();
+    order.sort_by_key(|&index| local_variables[index].start_program_counter);
+
+    let mut active: Vec<usize> = Vec::new();
+    let mut dropped = Vec::new();
+
+    for index in order {
+        let LocalVariable {
+            start_program_counter,
+            end_program_counter,
+            ..
+        } = local_variables[index];
+
+        if start_program_counter == end_program_counter {
+            continue;
+        }
+
+        active.retain(|&active_index| local_variables[active_index].end_program_counter > start_program_counter);
+        active.push(index);
+
+        if active.len() > MAXIMUM_ACTIVE_LOCAL_VARIABLES {
+            if !settings.repair_debug_info {
+                return Err(LunifyError::TooManyLocalVariables(start_program_counter));
+            }
+
+            let position = (0..active.len()).max_by_key(|&position| active[position]).unwrap();
+            let dropped_index = active.swap_remove(position);
+
+            let program_counter = Some(start_program_counter as usize);
+            report_diagnostic(settings, DiagnosticCode::LocalVariableRepaired, path, program_counter, || {
+                let name = local_variables[dropped_index].display_name();
+                format!("dropped local variable {name:?} because more than {MAXIMUM_ACTIVE_LOCAL_VARIABLES} local variables are active")
+            });
+
+            dropped.push(dropped_index);
+        }
+    }
+
+    let mut index = 0;
+    local_variables.retain(|_| {
+        index += 1;
+        !dropped.contains(&(index - 1))
+    });
+
+    Ok(is_repaired || !dropped.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::{implicit_arg_register, move_implicit_arg, repair_local_variables, LocalVariable};
+    use crate::{Diagnostic, DiagnosticCallback, DiagnosticCode, LunifyError, Settings};
+
+    fn local(name: &'static str, start_program_counter: i64, end_program_counter: i64) -> LocalVariable<'static> {
+        LocalVariable {
+            name: name.as_bytes(),
+            start_program_counter,
+            end_program_counter,
+        }
+    }
+
+    #[test]
+    fn arg_register() {
+        let local_variables = [local("self\0", 0, 5), local("x\0", 0, 5), local("arg\0", 0, 5), local("arg\0", 2, 5)];
+        assert_eq!(implicit_arg_register(&local_variables, 1), 2);
+        assert_eq!(implicit_arg_register(&local_variables[3..], 1), 1);
+        assert_eq!(implicit_arg_register(&[], 3), 3);
+    }
+
+    #[test]
+    fn move_arg() {
+        let mut local_variables = vec![local("x\0", 0, 5), local("arg\0", 0, 5), local("arg\0", 0, 5), local("arg\0", 2, 5)];
+        move_implicit_arg(&mut local_variables, 4);
+        assert_eq!(local_variables, [local("x\0", 0, 5), local("arg\0", 4, 9), local("arg\0", 2, 5)]);
+    }
+
+    #[test]
+    fn repair_ranges() {
+        let diagnostics = RefCell::new(Vec::new());
+        let record = |diagnostic: Diagnostic| diagnostics.borrow_mut().push(diagnostic.code);
+        let settings = Settings::builder().diagnostics(Some(DiagnosticCallback(&record))).build().unwrap();
+
+        let mut local_variables = vec![local("a\0", 0, 4), local("b\0", -2, 9), local("c\0", 3, 1), local("d\0", 6, 8)];
+        assert_eq!(repair_local_variables(&mut local_variables, 5, &[], &settings), Ok(true));
+        assert_eq!(local_variables, [local("a\0", 0, 4), local("b\0", 0, 5), local("d\0", 5, 5)]);
+        assert_eq!(diagnostics.borrow().as_slice(), [DiagnosticCode::LocalVariableRepaired; 3]);
+
+        assert_eq!(repair_local_variables(&mut local_variables, 5, &[], &settings), Ok(false));
+    }
+
+    #[test]
+    fn repair_ranges_disabled() {
+        let settings = Settings::builder().repair_debug_info(false).build().unwrap();
+
+        let mut local_variables = vec![local("a\0", 0, 4), local("b\0", 0, 9)];
+        let expected = LunifyError::InvalidLocalVariable {
+            index: 1,
+            start_program_counter: 0,
+            end_program_counter: 9,
+        };
+        assert_eq!(repair_local_variables(&mut local_variables, 5, &[], &settings), Err(expected));
+
+        let mut local_variables = vec![local("a\0", 3, 1)];
+        let expected = LunifyError::InvalidLocalVariable {
+            index: 0,
+            start_program_counter: 3,
+            end_program_counter: 1,
+        };
+        assert_eq!(repair_local_variables(&mut local_variables, 5, &[], &settings), Err(expected));
+    }
+
+    #[test]
+    fn repair_active_count() {
+        let mut local_variables: Vec<_> = (0..202).map(|index| local("x\0", index / 100, 10)).collect();
+        local_variables.push(local("y\0", 0, 1));
+
+        let settings = Settings::builder().repair_debug_info(false).build().unwrap();
+        assert_eq!(
+            repair_local_variables(&mut local_variables.clone(), 10, &[], &settings),
+            Err(LunifyError::TooManyLocalVariables(2))
+        );
+
+        let settings = Settings::default();
+        assert_eq!(repair_local_variables(&mut local_variables, 10, &[], &settings), Ok(true));
+        assert_eq!(local_variables.len(), 201);
+        assert_eq!(local_variables[199], local("x\0", 1, 10));
+        assert_eq!(local_variables[200], local("y\0", 0, 1));
+    }
+}
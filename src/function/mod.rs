@@ -1,65 +1,246 @@
+mod block;
 mod builder;
 mod constant;
 mod convert;
+mod extract;
+mod fold;
 mod instruction;
+mod line;
 mod local;
+mod patch;
+mod raw;
+mod reference;
+mod rename;
+mod source;
+mod trailer;
 mod upcast;
+mod validate;
 
-use std::fmt::Debug;
+use std::borrow::Cow;
+use std::ops::Range;
 
-use self::constant::Constant;
+use self::constant::{arrange_synthetic_constants, fit_constant_indices, Constant};
 use self::convert::convert;
-use self::instruction::LuaInstruction;
-pub use self::instruction::{lua50, lua51, InstructionLayout, OperandType, Settings};
-use self::local::LocalVariable;
-use self::upcast::upcast;
+pub use self::extract::FunctionInfo;
+use self::fold::fold_constants;
+use self::instruction::{Bx, Generic, LuaInstruction, Unused, BC};
+pub use self::instruction::{
+    lua50, lua51, transcode_instruction, validate_output, ConversionLimits, InstructionLayout, InstructionView, LuaconfReport, OperandType,
+    OperandValue, Settings, SettingsBuilder,
+};
+pub use self::line::LineOverflowPolicy;
+use self::line::is_overflowing;
+use self::local::{move_implicit_arg, repair_local_variables, LocalVariable};
+pub use self::patch::ConstantValue;
+pub use self::raw::{RawChunk, RawFunction};
+use self::reference::{validate_lua50_references, validate_lua51_references, Available};
+use self::rename::rename_globals;
+use self::source::{output_source_file, ParentSource};
+pub use self::source::SourceRewrite;
+pub use self::trailer::{FunctionTrailerMode, FunctionTrailerSpec};
+use self::upcast::{upcast, variadic_prologue_length, FunctionContext};
+pub use self::validate::ValidationIssue;
 use crate::format::LuaVersion;
-use crate::serialization::{ByteStream, ByteWriter};
-use crate::LunifyError;
+use crate::diagnostic::report_diagnostic;
+use crate::progress::report_progress;
+use crate::serialization::{fnv1a_hash, ByteStream, ByteWriter};
+use crate::{DiagnosticCode, Format, FunctionError, FunctionPath, FunctionReport, FunctionSpan, LunifyError, Phase};
+
+// Flags of the `is_vararg` byte in Lua 5.1.
+const VARARG_HASARG: u8 = 1;
+const VARARG_ISVARARG: u8 = 2;
+const VARARG_NEEDSARG: u8 = 4;
+
+/// Decoded instructions, the indices of the ones with an extended argument and
+/// the padding of every instruction if it is preserved.
+type DecodedInstructions<T> = (Vec<T>, Vec<usize>, Vec<u64>);
+
+/// Print a section of instructions with their constant operands resolved.
+#[cfg(feature = "debug")]
+fn print_instructions(title: &str, instructions: &[impl LuaInstruction], constants: &[Constant]) {
+    println!("\n======== {title} ========");
+
+    for (program_counter, instruction) in instructions.iter().enumerate() {
+        println!("[{program_counter}] {}", instruction.describe(constants));
+    }
+}
 
-pub(crate) struct Function {
+pub(crate) struct Function<'a> {
     source_file: String,
     line_defined: i64,
     last_line_defined: i64,
+    upvalue_count: u8,
     parameter_count: u8,
     is_variadic: u8,
     maximum_stack_size: u8,
     instructions: Vec<u64>,
-    constants: Vec<Constant>,
-    functions: Vec<Function>,
-    local_variables: Vec<LocalVariable>,
+    constants: Vec<Constant<'a>>,
+    functions: Vec<Function<'a>>,
+    local_variables: Vec<LocalVariable<'a>>,
     line_info: Vec<i64>,
-    upvalues: Vec<String>,
+    upvalues: Vec<Cow<'a, [u8]>>,
+    trailer: Option<(FunctionTrailerSpec, Cow<'a, [u8]>)>,
+    line_number_overflow: LineOverflowPolicy,
+    folded_constants: usize,
+    /// The converted instructions if `retain_typed_instructions` is set.
+    instruction_views: Vec<InstructionView>,
+    is_modified: bool,
+    /// The error that caused the function to be replaced by a stub if nested
+    /// functions are isolated.
+    isolated_error: Option<LunifyError>,
+    /// The bytes of the function and its nested functions in the input, if
+    /// none of them changed. These can be copied to the output as is if the
+    /// output uses the same encoding as the input.
+    original: Option<(Format, &'a [u8])>,
+    /// The bytes of the function and its nested functions in the byte stream,
+    /// unless the function is a stub.
+    input_span: Option<Range<usize>>,
+    /// The source file is the same as the one of the parent, so an empty string
+    /// is written instead.
+    is_source_shared: bool,
+    /// Position of the nested functions in the input if they were skipped
+    /// rather than parsed.
+    nested_functions: Option<NestedFunctions>,
+}
+
+/// Nested functions that were skipped while parsing their parent, so they can
+/// be converted one by one by [`convert_function`](Function::convert_function).
+struct NestedFunctions {
+    count: usize,
+    /// Offset of the first nested function in the input.
+    start_offset: usize,
+    /// Offset right after the last nested function in the input.
+    end_offset: usize,
+    /// The source file that the nested functions inherit.
+    source_file: String,
+    /// The source file that is written for the function the nested functions
+    /// are nested in.
+    output_source_file: String,
+    /// The number of upvalues of every nested function.
+    upvalue_counts: Vec<u8>,
 }
 
-impl Function {
-    fn get_instructions<T>(byte_stream: &mut ByteStream, settings: &Settings, layout: &InstructionLayout) -> Result<Vec<T>, LunifyError>
+impl<'a> Function<'a> {
+    fn get_instructions<T>(
+        byte_stream: &mut ByteStream,
+        settings: &Settings,
+        layout: &InstructionLayout,
+        extended_argument: fn(&mut T) -> Option<&mut u64>,
+        path: Option<&[usize]>,
+    ) -> Result<DecodedInstructions<T>, LunifyError>
     where
-        T: LuaInstruction + Debug,
+        T: LuaInstruction,
     {
-        let instruction_count = byte_stream.integer()?;
+        let instruction_count = Self::instruction_count(byte_stream, settings)?;
         let mut instructions = Vec::new();
+        let mut extended_instructions = Vec::new();
+        let mut padding = Vec::new();
+        let mut slot = 0;
 
         #[cfg(feature = "debug")]
         println!("instruction_count: {instruction_count}");
 
-        #[cfg(feature = "debug")]
-        println!("\n======== Instructions ========");
+        while slot < instruction_count {
+            let offset = byte_stream.offset();
+            let (mut instruction, instruction_padding) = T::from_byte_stream(byte_stream, settings, layout, instructions.len())?;
+            slot += 1;
 
-        for _program_counter in 0..instruction_count as usize {
-            let instruction = T::from_byte_stream(byte_stream, settings, layout)?;
+            if let Some(path) = path.filter(|_| settings.diagnostics.is_some() && !settings.strict_decoding) {
+                Self::report_unexpected_bits::<T>(byte_stream, offset, settings, layout, instructions.len(), path);
+            }
 
-            #[cfg(feature = "debug")]
-            println!("[{_program_counter}] {instruction:?}");
+            if settings.preserve_instruction_padding {
+                padding.push(instruction_padding);
+            }
+
+            // Some instructions store an argument that doesn't fit into the operand in the
+            // following instruction slot. We read it here and store it directly in the
+            // instruction, so the raw value is never decoded as an instruction.
+            if let Some(argument) = extended_argument(&mut instruction) {
+                if slot >= instruction_count {
+                    return Err(LunifyError::MalformedSetList);
+                }
+
+                *argument = byte_stream.instruction()?;
+                extended_instructions.push(instructions.len());
+                slot += 1;
+            }
 
             instructions.push(instruction);
         }
 
-        Ok(instructions)
+        Ok((instructions, extended_instructions, padding))
+    }
+
+    /// Decode the instruction at `offset` again as if `strict_decoding` was set,
+    /// and report the bits that were ignored because it isn't.
+    fn report_unexpected_bits<T>(
+        byte_stream: &ByteStream,
+        offset: usize,
+        settings: &Settings,
+        layout: &InstructionLayout,
+        program_counter: usize,
+        path: &[usize],
+    ) where
+        T: LuaInstruction,
+    {
+        let strict_settings = Settings {
+            strict_decoding: true,
+            ..*settings
+        };
+        let mut byte_stream = byte_stream.clone();
+        byte_stream.set_offset(offset);
+
+        if let Err(LunifyError::UnexpectedOperandBits { opcode, field, .. }) =
+            T::from_byte_stream(&mut byte_stream, &strict_settings, layout, program_counter)
+        {
+            report_diagnostic(settings, DiagnosticCode::UnexpectedOperandBits, path, Some(program_counter), || {
+                format!("instruction with opcode {opcode} has bits set in its unused {field:?} field")
+            });
+        }
+    }
+
+    /// Report string constants of the input that look like the ones Lunify
+    /// creates while converting.
+    fn report_lunify_constants(constants: &[Constant], path: &[usize], settings: &Settings) {
+        if settings.diagnostics.is_none() {
+            return;
+        }
+
+        for (index, constant) in constants.iter().enumerate() {
+            if matches!(constant, Constant::String(string) if string.starts_with(b"__%lunify%__")) {
+                report_diagnostic(settings, DiagnosticCode::LunifyConstant, path, None, || {
+                    format!("constant {index} looks like a constant created by Lunify")
+                });
+            }
+        }
+    }
+
+    /// Read the instructions of a function that is copied to the output as is,
+    /// without decoding them.
+    fn get_raw_instructions(byte_stream: &mut ByteStream, settings: &Settings) -> Result<Vec<u64>, LunifyError> {
+        let instruction_count = Self::instruction_count(byte_stream, settings)?;
+        (0..instruction_count).map(|_| byte_stream.instruction()).collect()
+    }
+
+    /// Read the number of instructions of a function and check it against the
+    /// limits.
+    fn instruction_count(byte_stream: &mut ByteStream, settings: &Settings) -> Result<u64, LunifyError> {
+        let instruction_count = byte_stream.count()?;
+
+        if instruction_count > settings.limits.max_instructions_per_function {
+            return Err(LunifyError::LimitExceeded("max_instructions_per_function"));
+        }
+
+        if byte_stream.add_instructions(instruction_count) > settings.limits.max_total_instructions {
+            return Err(LunifyError::LimitExceeded("max_total_instructions"));
+        }
+
+        Ok(instruction_count)
     }
 
-    fn get_constants(byte_stream: &mut ByteStream) -> Result<Vec<Constant>, LunifyError> {
-        let constant_count = byte_stream.integer()?;
+    fn get_constants(byte_stream: &mut ByteStream<'a>) -> Result<Vec<Constant<'a>>, LunifyError> {
+        let constant_count = byte_stream.count()?;
         let mut constants = Vec::new();
 
         #[cfg(feature = "debug")]
@@ -98,12 +279,12 @@ impl Function {
                 }
 
                 4 => {
-                    let string = byte_stream.string()?;
+                    let string = byte_stream.string_slice()?;
 
                     #[cfg(feature = "debug")]
-                    println!("constant[{}] (string) ({}): {:?}", _index, string.len(), string);
+                    println!("constant[{}] (string) ({}): {:?}", _index, string.len(), String::from_utf8_lossy(string));
 
-                    constants.push(Constant::String(string));
+                    constants.push(Constant::String(Cow::Borrowed(string)));
                 }
 
                 invalid => return Err(LunifyError::InvalidConstantType(invalid)),
@@ -113,23 +294,225 @@ impl Function {
         Ok(constants)
     }
 
-    fn get_functions(byte_stream: &mut ByteStream, version: LuaVersion, settings: &Settings) -> Result<Vec<Function>, LunifyError> {
-        let function_count = byte_stream.integer()?;
+    /// Parse the nested functions, or skip over them if `is_streaming` is set and
+    /// return their position instead. If `is_lenient` is set, nested functions
+    /// that fail to parse or convert are replaced by a [`stub`](Self::stub).
+    fn get_functions(
+        byte_stream: &mut ByteStream<'a>,
+        version: LuaVersion,
+        settings: &Settings,
+        parent_source: ParentSource,
+        path: &mut Vec<usize>,
+        is_streaming: bool,
+        is_lenient: bool,
+    ) -> Result<(Vec<Function<'a>>, Option<NestedFunctions>), LunifyError> {
+        let function_count = byte_stream.count()?;
         let mut functions = Vec::new();
 
         #[cfg(feature = "debug")]
         println!("\nfunction_count: {function_count}");
 
-        for _index in 0..function_count as usize {
-            let function = Function::from_byte_stream(byte_stream, version, settings)?;
+        if is_streaming {
+            let start_offset = byte_stream.offset();
+            let mut upvalue_counts = Vec::new();
+            for _index in 0..function_count {
+                upvalue_counts.push(Self::skip(byte_stream, version, settings, &mut Vec::new(), None)?);
+            }
+
+            let nested_functions = NestedFunctions {
+                count: function_count as usize,
+                start_offset,
+                end_offset: byte_stream.offset(),
+                source_file: parent_source.input.to_owned(),
+                output_source_file: parent_source.output.to_owned(),
+                upvalue_counts,
+            };
+
+            return Ok((functions, Some(nested_functions)));
+        }
+
+        for index in 0..function_count as usize {
+            let start_offset = byte_stream.offset();
+            let functions_done = byte_stream.functions_done();
+
+            path.push(index);
+            let result = Function::parse(byte_stream, version, settings, Some(parent_source), path, false, is_lenient);
+            path.pop();
+            let function = match result {
+                Err(error) if is_lenient => {
+                    // Nested functions that were parsed before the error are counted again
+                    // by the stub.
+                    byte_stream.set_offset(start_offset);
+                    byte_stream.set_functions_done(functions_done);
+                    Self::stub(byte_stream, version, settings, parent_source, error)?
+                }
+                result => result.map_err(|error| match error {
+                    LunifyError::TooManyConstants { path, constant_count } => LunifyError::TooManyConstants {
+                        path: path.prepend(index),
+                        constant_count,
+                    },
+                    error => error,
+                })?,
+            };
             functions.push(function);
         }
 
-        Ok(functions)
+        Ok((functions, None))
+    }
+
+    /// Skip over a nested function that failed to parse or convert and create a
+    /// function in its place that returns right away, so the rest of the chunk
+    /// can still be loaded. The stub keeps the number of upvalues and
+    /// parameters, since the parent relies on them. If the function can't be
+    /// skipped either, `error` is returned.
+    fn stub(
+        byte_stream: &mut ByteStream<'a>,
+        version: LuaVersion,
+        settings: &Settings,
+        parent_source: ParentSource,
+        error: LunifyError,
+    ) -> Result<Self, LunifyError> {
+        let mut functions = Vec::new();
+        if Self::skip(byte_stream, version, settings, &mut Vec::new(), Some(&mut functions)).is_err() {
+            return Err(error);
+        }
+
+        // The nested functions are skipped with the stub, so they count as converted.
+        byte_stream.add_functions(functions.len());
+        report_progress(byte_stream, Phase::ConvertingFunction, settings);
+
+        let Some(info) = functions.into_iter().next() else {
+            return Err(LunifyError::InternalInconsistency("skipped function has no information"));
+        };
+
+        let return_instruction = lua51::Instruction::Return {
+            a: 0,
+            mode: BC(Generic(1), Unused),
+        };
+
+        // The trailer of the input is skipped, so the best we can do is to write an
+        // empty one.
+        let trailer_spec = settings.output.function_trailer.or(settings.lua51.function_trailer);
+        let trailer = match (trailer_spec, settings.output.function_trailer_mode) {
+            (Some(spec), FunctionTrailerMode::Replace(trailer)) => Some((spec, Cow::Owned(trailer.to_vec()))),
+            (Some(spec @ FunctionTrailerSpec::FixedLength(length)), FunctionTrailerMode::Keep) => Some((spec, Cow::Owned(vec![0; length]))),
+            (Some(spec), FunctionTrailerMode::Keep) => Some((spec, Cow::Owned(Vec::new()))),
+            _ => None,
+        };
+
+        Ok(Self {
+            source_file: parent_source.output.to_owned(),
+            line_defined: info.line_defined,
+            last_line_defined: info.last_line_defined,
+            upvalue_count: info.upvalue_count,
+            parameter_count: info.parameter_count,
+            is_variadic: if info.is_variadic { VARARG_ISVARARG } else { 0 },
+            maximum_stack_size: u8::max(2, info.parameter_count.saturating_add(1)),
+            instructions: Self::strip_instructions(vec![return_instruction], &[], settings)?,
+            constants: Vec::new(),
+            functions: Vec::new(),
+            local_variables: Vec::new(),
+            line_info: Vec::new(),
+            upvalues: Vec::new(),
+            trailer,
+            line_number_overflow: settings.output.line_number_overflow,
+            folded_constants: 0,
+            instruction_views: Vec::new(),
+            is_modified: true,
+            isolated_error: Some(error),
+            original: None,
+            input_span: None,
+            is_source_shared: settings.output.deduplicate_source,
+            nested_functions: None,
+        })
+    }
+
+    /// Read the header of a function and skip everything up to and including the
+    /// count of its nested functions. Returns the source file as it is stored in
+    /// the input and information about the function with an empty path.
+    fn skip_to_functions(byte_stream: &mut ByteStream<'a>, version: LuaVersion) -> Result<(&'a [u8], FunctionInfo), LunifyError> {
+        let source_file = byte_stream.string_slice()?;
+        let line_defined = byte_stream.integer()?;
+
+        let last_line_defined = match version {
+            LuaVersion::Lua51 => byte_stream.integer()?,
+            LuaVersion::Lua50 => line_defined,
+        };
+
+        let upvalue_count = byte_stream.byte()?;
+        let parameter_count = byte_stream.byte()?;
+        let is_variadic = byte_stream.byte()? != 0;
+        let _maximum_stack_size = byte_stream.byte()?;
+
+        let constants = if version == LuaVersion::Lua51 {
+            for _index in 0..byte_stream.count()? {
+                byte_stream.instruction()?;
+            }
+            Self::get_constants(byte_stream)?
+        } else {
+            Self::get_line_info(byte_stream)?;
+            Self::get_local_variables(byte_stream)?;
+            Self::get_upvalues(byte_stream)?;
+            Self::get_constants(byte_stream)?
+        };
+
+        let info = FunctionInfo {
+            path: FunctionPath::root(),
+            line_defined,
+            last_line_defined,
+            upvalue_count,
+            parameter_count,
+            is_variadic,
+            function_count: byte_stream.count()? as usize,
+            number_constant_count: constants.iter().filter(|constant| matches!(constant, Constant::Number(_))).count(),
+        };
+
+        Ok((source_file, info))
+    }
+
+    /// Skip over a function and its nested functions without decoding them. If
+    /// `functions` is given, information about every function is pushed to it in
+    /// depth-first order. Returns the number of upvalues of the function.
+    fn skip(
+        byte_stream: &mut ByteStream<'a>,
+        version: LuaVersion,
+        settings: &Settings,
+        path: &mut Vec<usize>,
+        mut functions: Option<&mut Vec<FunctionInfo>>,
+    ) -> Result<u8, LunifyError> {
+        let (_, info) = Self::skip_to_functions(byte_stream, version)?;
+        let function_count = info.function_count;
+        let upvalue_count = info.upvalue_count;
+
+        if let Some(functions) = functions.as_deref_mut() {
+            functions.push(FunctionInfo { path: path.as_slice().into(), ..info });
+        }
+
+        for index in 0..function_count {
+            path.push(index);
+            Self::skip(byte_stream, version, settings, path, functions.as_deref_mut())?;
+            path.pop();
+        }
+
+        if version == LuaVersion::Lua51 {
+            Self::get_line_info(byte_stream)?;
+            Self::get_local_variables(byte_stream)?;
+            Self::get_upvalues(byte_stream)?;
+
+            if let Some(spec) = settings.lua51.function_trailer {
+                spec.read(byte_stream)?;
+            }
+        } else {
+            for _index in 0..byte_stream.count()? {
+                byte_stream.instruction()?;
+            }
+        }
+
+        Ok(upvalue_count)
     }
 
-    fn get_local_variables(byte_stream: &mut ByteStream) -> Result<Vec<LocalVariable>, LunifyError> {
-        let local_variable_count = byte_stream.integer()?;
+    fn get_local_variables(byte_stream: &mut ByteStream<'a>) -> Result<Vec<LocalVariable<'a>>, LunifyError> {
+        let local_variable_count = byte_stream.count()?;
         let mut local_variables = Vec::new();
 
         #[cfg(feature = "debug")]
@@ -139,12 +522,15 @@ impl Function {
         }
 
         for _index in 0..local_variable_count as usize {
-            let name = byte_stream.string()?;
+            let name = byte_stream.string_slice()?;
             let start_program_counter = byte_stream.integer()?;
             let end_program_counter = byte_stream.integer()?;
 
             #[cfg(feature = "debug")]
-            println!("local variable[{_index}] ({start_program_counter} - {end_program_counter}): {name:?}");
+            println!(
+                "local variable[{_index}] ({start_program_counter} - {end_program_counter}): {:?}",
+                String::from_utf8_lossy(name)
+            );
 
             let local_variable = LocalVariable {
                 name,
@@ -159,7 +545,7 @@ impl Function {
     }
 
     fn get_line_info(byte_stream: &mut ByteStream) -> Result<Vec<i64>, LunifyError> {
-        let line_info_count = byte_stream.integer()?;
+        let line_info_count = byte_stream.count()?;
         let mut line_info = Vec::new();
 
         #[cfg(feature = "debug")]
@@ -173,42 +559,106 @@ impl Function {
         Ok(line_info)
     }
 
-    fn get_upvalues(byte_stream: &mut ByteStream) -> Result<Vec<String>, LunifyError> {
-        let upvalue_count = byte_stream.integer()?;
+    fn get_upvalues(byte_stream: &mut ByteStream<'a>) -> Result<Vec<Cow<'a, [u8]>>, LunifyError> {
+        let upvalue_count = byte_stream.count()?;
         let mut upvalues = Vec::new();
 
         #[cfg(feature = "debug")]
         println!("\nupvalue_count: {upvalue_count}");
 
         for _index in 0..upvalue_count as usize {
-            let upvalue = byte_stream.string()?;
+            let upvalue = byte_stream.string_slice()?;
 
             #[cfg(feature = "debug")]
-            println!("upvalue[{_index}]: {upvalue:?}");
+            println!("upvalue[{_index}]: {:?}", String::from_utf8_lossy(upvalue));
 
-            upvalues.push(upvalue);
+            upvalues.push(Cow::Borrowed(upvalue));
         }
 
         Ok(upvalues)
     }
 
-    fn strip_instructions(instructions: Vec<impl LuaInstruction>, settings: &Settings) -> Result<Vec<u64>, LunifyError> {
-        instructions.into_iter().map(|instruction| instruction.to_u64(settings)).collect()
+    /// Encode the instructions, putting back the padding of every instruction
+    /// that has some. Padding above the instruction width of the output is
+    /// lost when writing.
+    fn strip_instructions(instructions: Vec<impl LuaInstruction>, padding: &[u64], settings: &Settings) -> Result<Vec<u64>, LunifyError> {
+        let padding_mask = !settings.output.layout.bit_mask();
+
+        instructions
+            .into_iter()
+            .enumerate()
+            .map(|(index, instruction)| {
+                let padding = padding.get(index).copied().unwrap_or(0) & padding_mask;
+                instruction.to_u64(settings).map(|value| value | padding)
+            })
+            .collect()
     }
 
-    pub(crate) fn from_byte_stream(byte_stream: &mut ByteStream, version: LuaVersion, settings: &Settings) -> Result<Self, LunifyError> {
-        let source_file = byte_stream.string()?;
-        let line_defined = byte_stream.integer()?;
+    /// Parse the main function and all of its nested functions.
+    pub(crate) fn from_byte_stream(
+        byte_stream: &mut ByteStream<'a>,
+        version: LuaVersion,
+        settings: &Settings,
+    ) -> Result<Self, LunifyError> {
+        Self::parse(byte_stream, version, settings, None, &mut Vec::new(), false, false)
+    }
 
-        let last_line_defined = match version {
+    /// Parse the main function and all of its nested functions, replacing nested
+    /// functions that fail to parse or convert by a [`stub`](Self::stub). Use
+    /// [`isolated_errors`](Self::isolated_errors) to get the errors.
+    pub(crate) fn from_byte_stream_lenient(
+        byte_stream: &mut ByteStream<'a>,
+        version: LuaVersion,
+        settings: &Settings,
+    ) -> Result<Self, LunifyError> {
+        Self::parse(byte_stream, version, settings, None, &mut Vec::new(), false, true)
+    }
+
+    fn parse(
+        byte_stream: &mut ByteStream<'a>,
+        version: LuaVersion,
+        settings: &Settings,
+        parent_source: Option<ParentSource>,
+        path: &mut Vec<usize>,
+        is_streaming: bool,
+        is_lenient: bool,
+    ) -> Result<Self, LunifyError> {
+        let start_offset = byte_stream.offset();
+        let mut source_file = byte_stream.string()?;
+
+        // Nested functions only store their source file if it's different from the one
+        // of their parent, otherwise the loader inherits it.
+        let is_source_inherited = source_file.is_empty() && parent_source.is_some();
+        if let Some(parent_source) = parent_source.filter(|_| is_source_inherited) {
+            source_file = parent_source.input.to_owned();
+        }
+
+        // Nested functions inherit the source file of the input, but they are compared
+        // to the source file that is actually written.
+        let is_main_function = parent_source.is_none();
+        let output_source_file = output_source_file(&source_file, is_main_function, settings);
+        let nested_source = ParentSource {
+            input: &source_file,
+            output: &output_source_file,
+        };
+
+        let mut line_defined = byte_stream.integer()?;
+        let mut last_line_defined = match version {
             LuaVersion::Lua51 => byte_stream.integer()?,
             LuaVersion::Lua50 => line_defined,
         };
 
-        let _upvalue_count = byte_stream.byte()?;
+        let is_main_rewritten = is_main_function && (settings.output.root_source_override.is_some() || settings.output.zero_root_lines);
+        if is_main_function && settings.output.zero_root_lines {
+            line_defined = 0;
+            last_line_defined = 0;
+        }
+
+        let upvalue_count = byte_stream.byte()?;
         let parameter_count = byte_stream.byte()?;
-        let mut is_variadic = byte_stream.byte()?;
+        let is_variadic = byte_stream.byte()?;
         let mut maximum_stack_size = byte_stream.byte()?;
+        let declared_stack_size = maximum_stack_size;
 
         #[cfg(feature = "debug")]
         {
@@ -216,60 +666,283 @@ impl Function {
             println!("source_file: {source_file}");
             println!("line_defined: {line_defined}");
             println!("last_line_defined: {last_line_defined}");
-            println!("upvalue_count: {_upvalue_count}");
+            println!("upvalue_count: {upvalue_count}");
             println!("parameter_count: {parameter_count}");
             println!("is_variadic: {is_variadic}");
             println!("maximum_stack_size: {maximum_stack_size}");
         }
 
-        let (instructions, constants, functions, line_info, local_variables, upvalues) = if version == LuaVersion::Lua51 {
-            let instructions = Self::get_instructions(byte_stream, settings, &settings.lua51.layout)?;
+        // Lua 5.0 only stores if the function is variadic, but Lua 5.1 uses a bit field.
+        // Variadic Lua 5.0 functions always have the `arg` parameter, which is either
+        // created by our prologue or by the VM if `VARARG_NEEDSARG` is set.
+        let is_variadic = match (version, is_variadic != 0, settings.output.use_needsarg_flag) {
+            (LuaVersion::Lua51, ..) => is_variadic,
+            (LuaVersion::Lua50, false, _) => 0,
+            (LuaVersion::Lua50, true, false) => VARARG_HASARG | VARARG_ISVARARG,
+            (LuaVersion::Lua50, true, true) => VARARG_HASARG | VARARG_ISVARARG | VARARG_NEEDSARG,
+        };
+
+        // Skipped functions are copied from the input as is, so their instructions are
+        // read without being decoded. Nested functions of a skipped function are
+        // skipped as well.
+        let is_skipped = version == LuaVersion::Lua51 && settings.skip_function_paths.iter().any(|skipped| path.starts_with(skipped));
+
+        let mut input_trailer = None;
+        let mut has_extended_instructions = false;
+        let mut folded_constants = 0;
+        let mut instruction_views = Vec::new();
+        let nested_functions;
+        let (instructions, constants, functions, line_info, mut local_variables, mut upvalues, is_modified) = if is_skipped {
+            let instructions = Self::get_raw_instructions(byte_stream, settings)?;
             let constants = Self::get_constants(byte_stream)?;
-            let functions = Self::get_functions(byte_stream, version, settings)?;
+            let (functions, nested) = Self::get_functions(byte_stream, version, settings, nested_source, path, is_streaming, is_lenient)?;
+            nested_functions = nested;
             let line_info = Self::get_line_info(byte_stream)?;
             let local_variables = Self::get_local_variables(byte_stream)?;
             let upvalues = Self::get_upvalues(byte_stream)?;
 
+            if let Some(spec) = settings.lua51.function_trailer {
+                input_trailer = Some(spec.read(byte_stream)?);
+            }
+
+            (instructions, constants, functions, line_info, local_variables, upvalues, false)
+        } else if version == LuaVersion::Lua51 {
+            let (instructions, extended_instructions, padding) = Self::get_instructions(
+                byte_stream,
+                settings,
+                &settings.lua51.layout,
+                lua51::Instruction::extended_argument,
+                Some(path),
+            )?;
+            let mut constants = Self::get_constants(byte_stream)?;
+            Self::report_lunify_constants(&constants, path, settings);
+
+            #[cfg(feature = "debug")]
+            print_instructions("Instructions", &instructions, &constants);
+
+            let (functions, nested) = Self::get_functions(byte_stream, version, settings, nested_source, path, is_streaming, is_lenient)?;
+
+            if !settings.skip_input_validation {
+                let available = Available {
+                    constants: constants.len(),
+                    functions: nested.as_ref().map_or(functions.len(), |nested| nested.upvalue_counts.len()),
+                    upvalues: upvalue_count,
+                };
+                validate_lua51_references(&instructions, &available)?;
+            }
+
+            nested_functions = nested;
+            let mut line_info = Self::get_line_info(byte_stream)?;
+            let local_variables = Self::get_local_variables(byte_stream)?;
+            let upvalues = Self::get_upvalues(byte_stream)?;
+
+            if let Some(spec) = settings.lua51.function_trailer {
+                input_trailer = Some(spec.read(byte_stream)?);
+            }
+
+            // The line info has an entry for every instruction slot, including the ones
+            // holding extended arguments, so we need to remove those to keep the line info
+            // aligned with our instructions.
+            for program_counter in &extended_instructions {
+                if program_counter + 1 < line_info.len() {
+                    line_info.remove(program_counter + 1);
+                }
+            }
+
             // Convert from the input Lua 5.1 byte code to the desired output Lua 5.1
-            // byte code.
-            let (instructions, line_info) = convert(instructions, line_info, &mut maximum_stack_size, settings)?;
-            let instructions = Self::strip_instructions(instructions, settings)?;
+            // byte code. We keep the input instructions around so we can tell if the
+            // function was passed through verbatim.
+            let input_instructions = instructions.clone();
+            let original_constant_count = constants.len();
+            let (mut instructions, mut line_info, mut padding) = convert(
+                instructions,
+                line_info,
+                padding,
+                &mut constants,
+                &extended_instructions,
+                &mut maximum_stack_size,
+                settings,
+            )?;
+            if settings.output.fold_constants {
+                folded_constants = fold_constants(&mut instructions, &mut line_info, &mut padding, &mut constants)?;
+            }
+            let is_renamed = rename_globals(&mut instructions, &mut constants, path, settings);
+            arrange_synthetic_constants(&mut instructions, &mut constants, original_constant_count, settings);
+            fit_constant_indices(&mut instructions, &mut constants, settings)?;
+
+            #[cfg(feature = "debug")]
+            print_instructions("Output", &instructions, &constants);
+            let is_modified = is_renamed || instructions != input_instructions;
+            if settings.retain_typed_instructions {
+                instruction_views = instructions.iter().map(lua51::Instruction::view).collect();
+            }
+            let instructions = Self::strip_instructions(instructions, &padding, settings)?;
+            has_extended_instructions = !extended_instructions.is_empty();
 
-            (instructions, constants, functions, line_info, local_variables, upvalues)
+            (instructions, constants, functions, line_info, local_variables, upvalues, is_modified)
         } else {
             let line_info = Self::get_line_info(byte_stream)?;
-            let local_variables = Self::get_local_variables(byte_stream)?;
+            let mut local_variables = Self::get_local_variables(byte_stream)?;
             let upvalues = Self::get_upvalues(byte_stream)?;
             let mut constants = Self::get_constants(byte_stream)?;
-            let functions = Self::get_functions(byte_stream, version, settings)?;
-            let instructions = Self::get_instructions(byte_stream, settings, &settings.lua50.layout)?;
+            Self::report_lunify_constants(&constants, path, settings);
+            let (functions, nested) = Self::get_functions(byte_stream, version, settings, nested_source, path, is_streaming, is_lenient)?;
+            let upvalue_counts: Vec<u8> = match &nested {
+                Some(nested) => nested.upvalue_counts.clone(),
+                None => functions.iter().map(|function| function.upvalue_count).collect(),
+            };
+            nested_functions = nested;
+            let (instructions, ..) = Self::get_instructions(byte_stream, settings, &settings.lua50.layout, |_| None, Some(path))?;
 
-            if is_variadic != 0 {
-                // Lua 5.1 uses an addition flag called `VARARG_ISVARARG` for variadic functions
-                // which we need to set, otherwise the function will be invalid.
-                is_variadic |= 2;
+            #[cfg(feature = "debug")]
+            print_instructions("Instructions", &instructions, &constants);
+
+            if !settings.skip_input_validation {
+                let available = Available {
+                    constants: constants.len(),
+                    functions: upvalue_counts.len(),
+                    upvalues: upvalue_count,
+                };
+                validate_lua50_references(&instructions, &available)?;
             }
 
+            // Stripped byte code has no line info, but the up-cast needs a line for every
+            // instruction, including the ones it inserts. We use line 0 for all of them
+            // and strip the line info of the output again afterwards.
+            let is_stripped = line_info.is_empty() && !instructions.is_empty();
+            let line_info = match is_stripped {
+                true => vec![0; instructions.len()],
+                false => line_info,
+            };
+
             // Up-cast instructions from Lua 5.0 to Lua 5.1.
-            let (instructions, line_info) = upcast(
+            let original_constant_count = constants.len();
+            let function = FunctionContext {
+                parameter_count,
+                is_variadic: is_variadic != 0,
+                upvalue_counts: &upvalue_counts,
+                local_variables: &local_variables,
+                path,
+            };
+            let prologue_length = variadic_prologue_length(&function, settings);
+            let (mut instructions, mut line_info) = upcast(
                 instructions,
                 line_info,
                 &mut constants,
                 &mut maximum_stack_size,
-                parameter_count,
-                is_variadic != 0,
+                &function,
                 settings,
             )?;
+            if is_variadic != 0 {
+                move_implicit_arg(&mut local_variables, prologue_length as i64);
+            }
+            if settings.output.fold_constants {
+                folded_constants = fold_constants(&mut instructions, &mut line_info, &mut Vec::new(), &mut constants)?;
+            }
+            rename_globals(&mut instructions, &mut constants, path, settings);
+            arrange_synthetic_constants(&mut instructions, &mut constants, original_constant_count, settings);
+            fit_constant_indices(&mut instructions, &mut constants, settings)?;
+
+            #[cfg(feature = "debug")]
+            print_instructions("Output", &instructions, &constants);
+
+            // Lua 5.0 has neither `LEN` nor `MOD`, so there is nothing to lower and any
+            // disallowed opcode in the up-cast instructions is an error.
+            let disallowed_opcodes = settings.output.disallowed_opcodes;
+            let disallowed = instructions
+                .iter()
+                .enumerate()
+                .find(|(_, instruction)| disallowed_opcodes.contains_instruction(instruction));
+
+            if let Some((program_counter, instruction)) = disallowed {
+                return Err(LunifyError::DisallowedOpcode {
+                    name: instruction.name(),
+                    program_counter,
+                });
+            }
+
+            if settings.retain_typed_instructions {
+                instruction_views = instructions.iter().map(lua51::Instruction::view).collect();
+            }
+            let instructions = Self::strip_instructions(instructions, &[], settings)?;
+
+            if is_stripped {
+                line_info.clear();
+            }
 
-            let instructions = Self::strip_instructions(instructions, settings)?;
+            // Up-casting always changes the instructions.
+            (instructions, constants, functions, line_info, local_variables, upvalues, true)
+        };
 
-            (instructions, constants, functions, line_info, local_variables, upvalues)
+        // Our own remapping keeps the local variables valid, but the input might not
+        // have, so this is a safety net for the Lua 5.1 loader.
+        let is_repaired = !is_skipped && repair_local_variables(&mut local_variables, instructions.len(), path, settings)?;
+        let is_modified = is_modified || is_repaired;
+
+        // Hand-modified byte code sometimes declares a stack size of 0 or one that is
+        // bigger than `MAXSTACK`. The Lua 5.1 loader needs at least two registers and
+        // space for every parameter, so we raise the stack size to that minimum, but a
+        // stack size that is too big can't be repaired without knowing which registers
+        // are actually used.
+        let minimum_stack_size = u64::max(2, parameter_count as u64 + 1);
+        let required_stack_size = u64::max(maximum_stack_size as u64, minimum_stack_size);
+        if required_stack_size > settings.output.stack_limit {
+            return Err(LunifyError::StackTooLarge(required_stack_size));
+        }
+        let maximum_stack_size = required_stack_size as u8;
+
+        // The trailer is written with the output encoding if there is one, otherwise we
+        // keep the input encoding.
+        let trailer = match settings.output.function_trailer_mode {
+            FunctionTrailerMode::Keep => input_trailer.map(Cow::Borrowed),
+            FunctionTrailerMode::Drop => None,
+            FunctionTrailerMode::Replace(trailer) => Some(Cow::Owned(trailer.to_vec())),
         };
+        let trailer_spec = settings.output.function_trailer.or(settings.lua51.function_trailer);
+        let trailer = trailer_spec.zip(trailer);
+
+        // An inherited source file is only written as an empty string again if it is
+        // deduplicated, and a source file that matches the one written for the parent
+        // is only written as is if it isn't.
+        let is_source_shared =
+            settings.output.deduplicate_source && parent_source.is_some_and(|parent_source| parent_source.output == output_source_file);
+        let is_source_unchanged = is_source_inherited == is_source_shared;
+
+        // A function can only be copied as is if nothing but the encoding could change
+        // its bytes. Instructions holding extended arguments are re-encoded into a
+        // single slot, so functions with those are always written again.
+        let is_untouched = version == LuaVersion::Lua51
+            && !is_modified
+            && !has_extended_instructions
+            && maximum_stack_size == declared_stack_size
+            && (upvalues.is_empty() || upvalues.len() == upvalue_count as usize)
+            && settings.lua51.layout == settings.output.layout
+            && settings.output.rewrite_source.is_none()
+            && !is_main_rewritten
+            && is_source_unchanged
+            && settings.output.function_trailer_mode == FunctionTrailerMode::Keep
+            && settings.output.function_trailer.is_none_or(|spec| Some(spec) == settings.lua51.function_trailer)
+            && functions.iter().all(|function| function.original.is_some());
+        let original = (is_untouched || is_skipped).then(|| (byte_stream.format(), byte_stream.slice_from(start_offset)));
+
+        // The upvalue count in the header is the source of truth, since the upvalue names
+        // are debug information and will be missing if the byte code was stripped. An
+        // empty list of names is accepted by Lua 5.1, but if only some of the names are
+        // present, we need to fill in the rest so the loader doesn't reject the function.
+        if !upvalues.is_empty() {
+            for index in upvalues.len()..upvalue_count as usize {
+                upvalues.push(Cow::Owned(format!("__lunify_upval_{index}\0").into_bytes()));
+            }
+        }
+
+        byte_stream.add_functions(1);
+        report_progress(byte_stream, Phase::ConvertingFunction, settings);
 
         Ok(Self {
-            source_file,
+            source_file: output_source_file,
             line_defined,
             last_line_defined,
+            upvalue_count,
             parameter_count,
             is_variadic,
             maximum_stack_size,
@@ -279,28 +952,154 @@ impl Function {
             local_variables,
             line_info,
             upvalues,
+            trailer,
+            line_number_overflow: settings.output.line_number_overflow,
+            folded_constants,
+            instruction_views,
+            is_modified,
+            isolated_error: None,
+            original,
+            input_span: Some(start_offset..byte_stream.offset()),
+            is_source_shared,
+            nested_functions,
         })
     }
 
-    pub(crate) fn write(self, byte_writer: &mut ByteWriter) -> Result<(), LunifyError> {
-        // function
-        byte_writer.string(&self.source_file);
-        byte_writer.integer(self.line_defined);
-        byte_writer.integer(self.last_line_defined);
-        byte_writer.byte(self.upvalues.len() as u8);
+    /// Collect the size of the first `SETLIST` flush of every Lua 5.0 table
+    /// constructor that uses more than one flush. Since only the last flush of
+    /// a constructor can be partial, this is the `LFIELDS_PER_FLUSH` the byte
+    /// code was compiled with.
+    pub(crate) fn lua50_flush_sizes(byte_stream: &mut ByteStream<'a>, settings: &Settings, flush_sizes: &mut Vec<u64>) -> Result<(), LunifyError> {
+        // Skip the function header.
+        byte_stream.string_slice()?;
+        byte_stream.integer()?;
+        byte_stream.slice(4)?;
+
+        Self::get_line_info(byte_stream)?;
+        Self::get_local_variables(byte_stream)?;
+        Self::get_upvalues(byte_stream)?;
+        Self::get_constants(byte_stream)?;
+
+        let function_count = byte_stream.count()?;
+        for _index in 0..function_count {
+            Self::lua50_flush_sizes(byte_stream, settings, flush_sizes)?;
+        }
+
+        let (instructions, ..) = Self::get_instructions(byte_stream, settings, &settings.lua50.layout, |_| None, None)?;
+        let mut first_flushes: Vec<(u64, u64)> = Vec::new();
+
+        for instruction in instructions {
+            match instruction {
+                lua50::Instruction::NewTable { a, .. } => first_flushes.retain(|(register, _)| *register != a),
+                lua50::Instruction::SetList { a, mode: Bx(bx) } | lua50::Instruction::SetListO { a, mode: Bx(bx) } => {
+                    match first_flushes.iter().find(|(register, _)| *register == a) {
+                        Some((_, first_bx)) if bx > *first_bx => flush_sizes.push(first_bx + 1),
+                        Some(_) => {}
+                        None => first_flushes.push((a, bx)),
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn report(
+        &self,
+        format: &Format,
+        path: &mut Vec<usize>,
+        reports: &mut Vec<FunctionReport>,
+        settings: &Settings,
+    ) -> Result<(), LunifyError> {
+        reports.push(FunctionReport {
+            path: path.as_slice().into(),
+            maximum_stack_size: self.maximum_stack_size,
+            is_modified: self.is_modified,
+            content_hash: self.content_hash(format)?,
+            overflowing_lines: self.overflowing_lines(format, path, settings),
+            folded_constants: self.folded_constants,
+            instructions: self.instruction_views.clone(),
+            // Filled in once the function is written.
+            size: 0,
+            span: Default::default(),
+        });
+
+        for (index, function) in self.functions.iter().enumerate() {
+            path.push(index);
+            function.report(format, path, reports, settings)?;
+            path.pop();
+        }
+
+        Ok(())
+    }
+
+    /// Push the error of every function that was replaced by a stub while
+    /// parsing to `errors`, in depth-first order.
+    pub(crate) fn isolated_errors(&self, path: &mut Vec<usize>, errors: &mut Vec<FunctionError>) {
+        if let Some(error) = &self.isolated_error {
+            errors.push(FunctionError {
+                path: path.as_slice().into(),
+                error: error.clone(),
+            });
+        }
+
+        for (index, function) in self.functions.iter().enumerate() {
+            path.push(index);
+            function.isolated_errors(path, errors);
+            path.pop();
+        }
+    }
+
+    /// Whether the function and all of its nested functions are copied from the
+    /// input as is when written.
+    pub(crate) fn is_untouched(&self) -> bool {
+        self.original.is_some()
+    }
+
+    /// 64-bit FNV-1a hash of the serialized function body. Debug information
+    /// (source file, line info, local variables and upvalue names) and nested
+    /// functions are not part of the hash, so it only changes if the behavior
+    /// of the function changes.
+    pub(crate) fn content_hash(&self, format: &Format) -> Result<u64, LunifyError> {
+        let mut byte_writer = ByteWriter::new(format);
+        self.write_body(&mut byte_writer)?;
+        Ok(fnv1a_hash(&byte_writer.finalize()))
+    }
+
+    /// The number of line numbers that don't fit into the integers of the
+    /// output format. They are reported as a diagnostic unless the line number
+    /// overflow policy makes writing them fail anyway.
+    fn overflowing_lines(&self, format: &Format, path: &[usize], settings: &Settings) -> usize {
+        let overflowing_lines = [self.line_defined, self.last_line_defined]
+            .iter()
+            .chain(&self.line_info)
+            .filter(|line| is_overflowing(**line, format))
+            .count();
+
+        if overflowing_lines > 0 && self.line_number_overflow != LineOverflowPolicy::Error {
+            report_diagnostic(settings, DiagnosticCode::LineNumberOverflow, path, None, || {
+                let policy = format!("{:?}", self.line_number_overflow).to_lowercase();
+                format!("{overflowing_lines} line numbers don't fit into the output format and were written with the {policy} policy")
+            });
+        }
+
+        overflowing_lines
+    }
+
+    fn write_body(&self, byte_writer: &mut ByteWriter) -> Result<(), LunifyError> {
+        byte_writer.byte(self.upvalue_count);
         byte_writer.byte(self.parameter_count);
         byte_writer.byte(self.is_variadic);
         byte_writer.byte(self.maximum_stack_size);
 
         // instructions
-        byte_writer.integer(self.instructions.len() as i64);
-        for instruction in self.instructions {
-            byte_writer.instruction(instruction);
-        }
+        byte_writer.count(self.instructions.len())?;
+        byte_writer.instruction_batch(&self.instructions);
 
         // constants
-        byte_writer.integer(self.constants.len() as i64);
-        for constant in self.constants {
+        byte_writer.count(self.constants.len())?;
+        for constant in &self.constants {
             match constant {
                 Constant::Nil => {
                     byte_writer.byte(0);
@@ -308,64 +1107,244 @@ impl Function {
 
                 Constant::Boolean(boolean) => {
                     byte_writer.byte(1);
-                    byte_writer.byte(boolean as u8);
+                    byte_writer.byte(*boolean as u8);
                 }
 
                 Constant::Number(number) => {
                     byte_writer.byte(3);
-                    byte_writer.number(number)?;
+                    byte_writer.number(*number)?;
                 }
 
                 Constant::String(string) => {
                     byte_writer.byte(4);
-                    byte_writer.string(&string);
+                    byte_writer.string(string)?;
                 }
             }
         }
 
+        Ok(())
+    }
+
+    /// Same as the sizes collected by [`write`](Self::write), but for a function
+    /// that is copied from the input as is, starting at `offset` in the output.
+    fn original_sizes(&self, offset: usize, sizes: &mut Vec<(usize, FunctionSpan)>) {
+        let Some((_, bytes)) = self.original else {
+            return;
+        };
+
+        let functions_size: usize = self.functions.iter().filter_map(|function| function.original).map(|(_, bytes)| bytes.len()).sum();
+        let span = FunctionSpan {
+            input: self.input_span.clone(),
+            output: offset..offset + bytes.len(),
+        };
+        sizes.push((bytes.len() - functions_size, span));
+
+        // The bytes of the nested functions are part of the bytes of this function, so
+        // their position in the output is the same as their position in the input.
+        for function in &self.functions {
+            if let Some((_, function_bytes)) = function.original {
+                function.original_sizes(offset + (function_bytes.as_ptr() as usize - bytes.as_ptr() as usize), sizes);
+            }
+        }
+    }
+
+    /// Write the function to the byte writer. The number of bytes written for
+    /// every function, excluding its nested functions, and the span of the
+    /// function in the output are pushed to `sizes` in depth-first order.
+    pub(crate) fn write(mut self, byte_writer: &mut ByteWriter, sizes: &mut Vec<(usize, FunctionSpan)>) -> Result<(), LunifyError> {
+        if let Some((_, bytes)) = self.original.filter(|(format, _)| format.has_same_encoding(byte_writer.format())) {
+            self.original_sizes(byte_writer.offset(), sizes);
+            byte_writer.slice(bytes);
+            return Ok(());
+        }
+
+        let start_offset = byte_writer.offset();
+        let size_index = sizes.len();
+        sizes.push(Default::default());
+
+        self.write_header(byte_writer)?;
+
         // functions
-        byte_writer.integer(self.functions.len() as i64);
-        for function in self.functions {
-            function.write(byte_writer)?;
+        byte_writer.count(self.functions.len())?;
+        let functions_offset = byte_writer.offset();
+        for function in std::mem::take(&mut self.functions) {
+            function.write(byte_writer, sizes)?;
+        }
+        let functions_size = byte_writer.offset() - functions_offset;
+
+        self.write_debug_information(byte_writer)?;
+
+        if let Some(size) = sizes.get_mut(size_index) {
+            let span = FunctionSpan {
+                input: self.input_span.clone(),
+                output: start_offset..byte_writer.offset(),
+            };
+            *size = (span.output.len() - functions_size, span);
+        }
+
+        Ok(())
+    }
+
+    /// Write everything that comes before the nested functions.
+    fn write_header(&self, byte_writer: &mut ByteWriter) -> Result<(), LunifyError> {
+        match self.is_source_shared {
+            true => byte_writer.size_t(0)?,
+            false => byte_writer.string(&self.source_file)?,
         }
+        let format = *byte_writer.format();
+        byte_writer.integer(self.line_number_overflow.apply(self.line_defined, &format))?;
+        byte_writer.integer(self.line_number_overflow.apply(self.last_line_defined, &format))?;
+        self.write_body(byte_writer)
+    }
 
+    /// Write everything that comes after the nested functions.
+    fn write_debug_information(&self, byte_writer: &mut ByteWriter) -> Result<(), LunifyError> {
         // line info
-        byte_writer.integer(self.line_info.len() as i64);
-        for line_info in self.line_info {
-            byte_writer.integer(line_info);
+        byte_writer.count(self.line_info.len())?;
+        let format = *byte_writer.format();
+        match self.line_info.iter().any(|line| is_overflowing(*line, &format)) {
+            true => {
+                let line_info: Vec<i64> = self.line_info.iter().map(|line| self.line_number_overflow.apply(*line, &format)).collect();
+                byte_writer.integer_batch(&line_info)?;
+            }
+            false => byte_writer.integer_batch(&self.line_info)?,
         }
 
         // local variables
-        byte_writer.integer(self.local_variables.len() as i64);
-        for local_variable in self.local_variables {
-            byte_writer.string(&local_variable.name);
-            byte_writer.integer(local_variable.start_program_counter);
-            byte_writer.integer(local_variable.end_program_counter);
+        byte_writer.count(self.local_variables.len())?;
+        for local_variable in &self.local_variables {
+            byte_writer.string(local_variable.name)?;
+            byte_writer.integer(local_variable.start_program_counter)?;
+            byte_writer.integer(local_variable.end_program_counter)?;
         }
 
         // upvalues
-        byte_writer.integer(self.upvalues.len() as i64);
-        for upvalue in self.upvalues {
-            byte_writer.string(&upvalue);
+        byte_writer.count(self.upvalues.len())?;
+        for upvalue in &self.upvalues {
+            byte_writer.string(upvalue)?;
+        }
+
+        // trailer
+        if let Some((spec, trailer)) = &self.trailer {
+            spec.write(byte_writer, trailer)?;
+        }
+
+        Ok(())
+    }
+
+    /// Parse, convert and write a function in a single pass. Nested functions
+    /// are skipped while parsing and converted one by one afterwards, so only
+    /// the function and its ancestors are kept in memory rather than the whole
+    /// tree. A report for every function is pushed to `reports` in depth-first
+    /// order.
+    pub(crate) fn convert_function(
+        byte_stream: &mut ByteStream<'a>,
+        byte_writer: &mut ByteWriter,
+        version: LuaVersion,
+        settings: &Settings,
+        parent_source: Option<ParentSource>,
+        path: &mut Vec<usize>,
+        reports: &mut Vec<FunctionReport>,
+    ) -> Result<(), LunifyError> {
+        let start_offset = byte_stream.offset();
+        let function = Self::parse(byte_stream, version, settings, parent_source, path, true, false).map_err(|error| match error {
+            LunifyError::TooManyConstants { constant_count, .. } => LunifyError::TooManyConstants {
+                path: path.as_slice().into(),
+                constant_count,
+            },
+            error => error,
+        })?;
+        let end_offset = byte_stream.offset();
+
+        let Some(nested_functions) = &function.nested_functions else {
+            return Err(LunifyError::InternalInconsistency("nested functions were parsed while streaming"));
+        };
+
+        let report_index = reports.len();
+        reports.push(FunctionReport {
+            path: path.as_slice().into(),
+            maximum_stack_size: function.maximum_stack_size,
+            is_modified: function.is_modified,
+            content_hash: function.content_hash(byte_writer.format())?,
+            overflowing_lines: function.overflowing_lines(byte_writer.format(), path, settings),
+            folded_constants: function.folded_constants,
+            instructions: function.instruction_views.clone(),
+            // Filled in once the function is written.
+            size: 0,
+            span: Default::default(),
+        });
+
+        // Nested functions are converted on their own, so an untouched function is
+        // copied in two parts, leaving out the nested functions in the middle.
+        let original = function.original.filter(|(format, _)| format.has_same_encoding(byte_writer.format()));
+        let output_offset = byte_writer.offset();
+
+        match original {
+            Some((_, bytes)) => byte_writer.slice(&bytes[..nested_functions.start_offset - start_offset]),
+            None => {
+                function.write_header(byte_writer)?;
+                byte_writer.count(nested_functions.count)?;
+            }
+        }
+
+        let functions_offset = byte_writer.offset();
+        byte_stream.set_offset(nested_functions.start_offset);
+
+        for index in 0..nested_functions.count {
+            path.push(index);
+            Self::convert_function(
+                byte_stream,
+                byte_writer,
+                version,
+                settings,
+                Some(ParentSource {
+                    input: &nested_functions.source_file,
+                    output: &nested_functions.output_source_file,
+                }),
+                path,
+                reports,
+            )?;
+            path.pop();
+        }
+
+        let functions_size = byte_writer.offset() - functions_offset;
+        byte_stream.set_offset(end_offset);
+
+        match original {
+            Some((_, bytes)) => byte_writer.slice(&bytes[nested_functions.end_offset - start_offset..]),
+            None => function.write_debug_information(byte_writer)?,
         }
 
+        reports[report_index].size = byte_writer.offset() - output_offset - functions_size;
+        reports[report_index].span = FunctionSpan {
+            input: function.input_span.clone(),
+            output: output_offset..byte_writer.offset(),
+        };
         Ok(())
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::cell::RefCell;
+
+    use super::Constant;
+    use crate::format::LuaVersion;
     use crate::function::Function;
+    use crate::number::Number;
     use crate::serialization::{ByteStream, ByteWriter};
-    use crate::{Format, LunifyError};
+    use crate::{
+        lua51, BitWidth, Diagnostic, DiagnosticCallback, DiagnosticCode, Format, FunctionPath, LineOverflowPolicy, LunifyError, Settings,
+        Severity, SourceRewrite,
+    };
 
     #[test]
-    fn get_constants_invalid() {
+    fn get_constants_invalid() -> Result<(), LunifyError> {
         let format = Format::default();
         let mut byte_writer = ByteWriter::new(&format);
 
         // Constant count.
-        byte_writer.integer(1);
+        byte_writer.integer(1)?;
         // Invalid type.
         byte_writer.byte(5);
 
@@ -375,5 +1354,719 @@ mod test {
         let result = Function::get_constants(&mut byte_stream);
         assert_eq!(result, Err(LunifyError::InvalidConstantType(5)));
         assert!(byte_stream.is_empty());
+        Ok(())
+    }
+
+    fn write_lua50_closure(
+        byte_writer: &mut ByteWriter,
+        source_file: &str,
+        upvalue_count: u8,
+        upvalues: &[&str],
+    ) -> Result<(), LunifyError> {
+        write_lua50_closure_with_source(byte_writer, source_file, source_file, upvalue_count, upvalues)
+    }
+
+    fn write_lua50_closure_with_source(
+        byte_writer: &mut ByteWriter,
+        source_file: &str,
+        closure_source_file: &str,
+        upvalue_count: u8,
+        upvalues: &[&str],
+    ) -> Result<(), LunifyError> {
+        byte_writer.string(source_file)?;
+        byte_writer.integer(0)?;
+        byte_writer.slice(&[0, 0, 0, 2]);
+
+        // Line info, local variables, upvalues, constants and functions.
+        byte_writer.integer(0)?;
+        byte_writer.integer(0)?;
+        byte_writer.integer(0)?;
+        byte_writer.integer(0)?;
+        byte_writer.integer(1)?;
+
+        // Closure capturing locals.
+        byte_writer.string(closure_source_file)?;
+        byte_writer.integer(0)?;
+        byte_writer.slice(&[upvalue_count, 0, 0, 2]);
+        byte_writer.integer(0)?;
+        byte_writer.integer(0)?;
+        byte_writer.integer(upvalues.len() as i64)?;
+        for upvalue in upvalues {
+            byte_writer.string(upvalue)?;
+        }
+        byte_writer.integer(0)?;
+        byte_writer.integer(0)?;
+
+        // `GETUPVAL 0 0`, `RETURN 0 2`.
+        byte_writer.integer(2)?;
+        byte_writer.instruction(4);
+        byte_writer.instruction(27 | (2 << 15));
+
+        // `CLOSURE 1 0`, `MOVE 0 0`, `RETURN 0 1`.
+        byte_writer.integer(3)?;
+        byte_writer.instruction(34 | (1 << 24));
+        byte_writer.instruction(0);
+        byte_writer.instruction(27 | (1 << 15));
+        Ok(())
+    }
+
+    fn lua50_variadic_flag(is_variadic: u8, use_needsarg_flag: bool) -> Result<u8, LunifyError> {
+        let format = Format::default();
+        let mut byte_writer = ByteWriter::new(&format);
+
+        byte_writer.string("")?;
+        byte_writer.integer(0)?;
+        byte_writer.slice(&[0, 0, is_variadic, 2]);
+        byte_writer.integer(1)?;
+        byte_writer.integer(1)?;
+        byte_writer.integer(0)?;
+        byte_writer.integer(0)?;
+        byte_writer.integer(0)?;
+        byte_writer.integer(0)?;
+
+        // `RETURN 0 1`.
+        byte_writer.integer(1)?;
+        byte_writer.instruction(27 | (1 << 15));
+
+        let bytes = byte_writer.finalize();
+        let mut byte_stream = ByteStream::new(&bytes);
+        byte_stream.set_format(format);
+
+        let mut settings = Settings::default();
+        settings.output.use_needsarg_flag = use_needsarg_flag;
+
+        let function = Function::from_byte_stream(&mut byte_stream, LuaVersion::Lua50, &settings)?;
+        Ok(function.is_variadic)
+    }
+
+    /// A variadic Lua 5.0 function with one parameter and the given local
+    /// variables, which executes `LOADNIL 2 2` and `RETURN 0 1`.
+    fn lua50_variadic_bytes(local_variables: &[(&str, i64, i64)]) -> Result<Vec<u8>, LunifyError> {
+        let format = Format::default();
+        let mut byte_writer = ByteWriter::new(&format);
+
+        byte_writer.string("")?;
+        byte_writer.integer(0)?;
+        byte_writer.slice(&[0, 1, 1, 4]);
+        byte_writer.integer(0)?;
+        byte_writer.integer(local_variables.len() as i64)?;
+        for (name, start_program_counter, end_program_counter) in local_variables {
+            byte_writer.string(format!("{name}\0"))?;
+            byte_writer.integer(*start_program_counter)?;
+            byte_writer.integer(*end_program_counter)?;
+        }
+        byte_writer.integer(0)?;
+        byte_writer.integer(0)?;
+        byte_writer.integer(0)?;
+
+        byte_writer.integer(2)?;
+        byte_writer.instruction(3 | (2 << 15) | (2 << 24));
+        byte_writer.instruction(27 | (1 << 15));
+        Ok(byte_writer.finalize())
+    }
+
+    fn lua50_variadic_function(bytes: &[u8]) -> Result<Function<'_>, LunifyError> {
+        let mut byte_stream = ByteStream::new(bytes);
+        byte_stream.set_format(Format::default());
+
+        let mut settings = Settings::default();
+        settings.output.emit_vararg_count = false;
+        Function::from_byte_stream(&mut byte_stream, LuaVersion::Lua50, &settings)
+    }
+
+    fn local_variables<'a>(function: &'a Function) -> Vec<(&'a [u8], i64, i64)> {
+        let local_variables = function.local_variables.iter();
+        local_variables.map(|local| (local.name, local.start_program_counter, local.end_program_counter)).collect()
+    }
+
+    #[test]
+    fn variadic_shadowed_arg() -> Result<(), LunifyError> {
+        let bytes = lua50_variadic_bytes(&[("a", 0, 2), ("arg", 0, 2), ("arg", 1, 2)])?;
+        let function = lua50_variadic_function(&bytes)?;
+
+        // `MOVE 1 2` at the end of the prologue.
+        assert_eq!(function.instructions[3], (1 << 6) | (2 << 23));
+        assert_eq!(local_variables(&function), [
+            (&b"a\0"[..], 0, 2),
+            (&b"arg\0"[..], 4, 6),
+            (&b"arg\0"[..], 1, 2)
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn variadic_stripped_arg() -> Result<(), LunifyError> {
+        let bytes = lua50_variadic_bytes(&[])?;
+        let function = lua50_variadic_function(&bytes)?;
+
+        assert_eq!(function.instructions[3], (1 << 6) | (2 << 23));
+        assert!(function.local_variables.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn variadic_flag() -> Result<(), LunifyError> {
+        assert_eq!(lua50_variadic_flag(0, false)?, 0);
+        assert_eq!(lua50_variadic_flag(0, true)?, 0);
+        assert_eq!(lua50_variadic_flag(1, false)?, 3);
+        assert_eq!(lua50_variadic_flag(1, true)?, 7);
+        Ok(())
+    }
+
+    #[test]
+    fn variadic_flag_not_one() -> Result<(), LunifyError> {
+        assert_eq!(lua50_variadic_flag(2, false)?, 3);
+        assert_eq!(lua50_variadic_flag(2, true)?, 7);
+        Ok(())
+    }
+
+    fn get_lua51_instructions(byte_stream: &mut ByteStream, settings: &Settings) -> Result<Vec<lua51::Instruction>, LunifyError> {
+        let layout = &settings.lua51.layout;
+        let (instructions, ..) = Function::get_instructions(byte_stream, settings, layout, lua51::Instruction::extended_argument, None)?;
+        Ok(instructions)
+    }
+
+    #[test]
+    fn get_instructions_too_many() -> Result<(), LunifyError> {
+        let format = Format::default();
+        let mut byte_writer = ByteWriter::new(&format);
+
+        // An instruction count that is far bigger than the actual input.
+        byte_writer.integer(i32::MAX as i64)?;
+
+        let bytes = byte_writer.finalize();
+        let mut byte_stream = ByteStream::new(&bytes);
+
+        let result = get_lua51_instructions(&mut byte_stream, &Settings::default());
+        assert_eq!(result, Err(LunifyError::LimitExceeded("max_instructions_per_function")));
+        Ok(())
+    }
+
+    #[test]
+    fn get_instructions_too_many_in_total() -> Result<(), LunifyError> {
+        let format = Format::default();
+        let mut byte_writer = ByteWriter::new(&format);
+
+        // Two functions with two `RETURN 0 1` each.
+        for _ in 0..2 {
+            byte_writer.integer(2)?;
+            byte_writer.instruction(30 | (1 << 23));
+            byte_writer.instruction(30 | (1 << 23));
+        }
+
+        let bytes = byte_writer.finalize();
+        let mut byte_stream = ByteStream::new(&bytes);
+        let mut settings = Settings::default();
+        settings.limits.max_total_instructions = 3;
+
+        assert_eq!(get_lua51_instructions(&mut byte_stream, &settings)?.len(), 2);
+        assert_eq!(
+            get_lua51_instructions(&mut byte_stream, &settings),
+            Err(LunifyError::LimitExceeded("max_total_instructions"))
+        );
+        Ok(())
+    }
+
+    fn lua51_stack_size(maximum_stack_size: u8, parameter_count: u8) -> Result<u8, LunifyError> {
+        let format = Format::default();
+        let mut byte_writer = ByteWriter::new(&format);
+
+        byte_writer.string("")?;
+        byte_writer.integer(0)?;
+        byte_writer.integer(0)?;
+        byte_writer.slice(&[0, parameter_count, 0, maximum_stack_size]);
+
+        // `RETURN 0 1`.
+        byte_writer.integer(1)?;
+        byte_writer.instruction(30 | (1 << 23));
+
+        // Constants, functions, line info, local variables and upvalues.
+        byte_writer.integer(0)?;
+        byte_writer.integer(0)?;
+        byte_writer.integer(1)?;
+        byte_writer.integer(1)?;
+        byte_writer.integer(0)?;
+        byte_writer.integer(0)?;
+
+        let bytes = byte_writer.finalize();
+        let mut byte_stream = ByteStream::new(&bytes);
+        byte_stream.set_format(format);
+
+        let function = Function::from_byte_stream(&mut byte_stream, LuaVersion::Lua51, &Settings::default())?;
+        Ok(function.maximum_stack_size)
+    }
+
+    #[test]
+    fn stack_size_empty_function() -> Result<(), LunifyError> {
+        assert_eq!(lua51_stack_size(0, 0)?, 2);
+        assert_eq!(lua51_stack_size(0, 3)?, 4);
+        assert_eq!(lua51_stack_size(9, 3)?, 9);
+        Ok(())
+    }
+
+    #[test]
+    fn stack_size_too_large() {
+        assert_eq!(lua51_stack_size(255, 0), Err(LunifyError::StackTooLarge(255)));
+        assert_eq!(lua51_stack_size(0, 250), Err(LunifyError::StackTooLarge(251)));
+    }
+
+    #[test]
+    fn stripped_upvalues() -> Result<(), LunifyError> {
+        let format = Format::default();
+        let mut byte_writer = ByteWriter::new(&format);
+        write_lua50_closure(&mut byte_writer, "", 1, &[])?;
+
+        let bytes = byte_writer.finalize();
+        let mut byte_stream = ByteStream::new(&bytes);
+        byte_stream.set_format(format);
+
+        let function = Function::from_byte_stream(&mut byte_stream, LuaVersion::Lua50, &Settings::default())?;
+        let closure = &function.functions[0];
+
+        assert_eq!(closure.upvalue_count, 1);
+        assert!(closure.upvalues.is_empty());
+        assert!(byte_stream.is_empty());
+
+        let mut byte_writer = ByteWriter::new(&format);
+        function.write(&mut byte_writer, &mut Vec::new())?;
+
+        // Read back the converted function to make sure the upvalue count was written.
+        let bytes = byte_writer.finalize();
+        let mut byte_stream = ByteStream::new(&bytes);
+        byte_stream.set_format(format);
+
+        let function = Function::from_byte_stream(&mut byte_stream, LuaVersion::Lua51, &Settings::default())?;
+        assert_eq!(function.functions[0].upvalue_count, 1);
+        assert!(function.functions[0].upvalues.is_empty());
+        Ok(())
+    }
+
+    fn inherited_source(deduplicate_source: bool) -> Result<usize, LunifyError> {
+        let format = Format::default();
+        let mut byte_writer = ByteWriter::new(&format);
+        write_lua50_closure_with_source(&mut byte_writer, "@foo.lua\0", "", 1, &[])?;
+
+        let bytes = byte_writer.finalize();
+        let mut byte_stream = ByteStream::new(&bytes);
+        byte_stream.set_format(format);
+
+        let mut settings = Settings::default();
+        settings.output.deduplicate_source = deduplicate_source;
+
+        let function = Function::from_byte_stream(&mut byte_stream, LuaVersion::Lua50, &settings)?;
+        assert_eq!(function.source_file, "@foo.lua\0");
+        assert_eq!(function.functions[0].source_file, "@foo.lua\0");
+
+        let mut byte_writer = ByteWriter::new(&format);
+        function.write(&mut byte_writer, &mut Vec::new())?;
+
+        // Read back the converted function to make sure the source file is inherited.
+        let bytes = byte_writer.finalize();
+        let mut byte_stream = ByteStream::new(&bytes);
+        byte_stream.set_format(format);
+
+        let function = Function::from_byte_stream(&mut byte_stream, LuaVersion::Lua51, &settings)?;
+        assert_eq!(function.functions[0].source_file, "@foo.lua\0");
+
+        Ok(bytes.windows(8).filter(|window| window == b"@foo.lua").count())
+    }
+
+    #[test]
+    fn inherited_source_deduplicated() -> Result<(), LunifyError> {
+        assert_eq!(inherited_source(true)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn inherited_source_written() -> Result<(), LunifyError> {
+        assert_eq!(inherited_source(false)?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn different_source_not_deduplicated() -> Result<(), LunifyError> {
+        let format = Format::default();
+        let mut byte_writer = ByteWriter::new(&format);
+        write_lua50_closure_with_source(&mut byte_writer, "@foo.lua\0", "@bar.lua\0", 1, &[])?;
+
+        let bytes = byte_writer.finalize();
+        let mut byte_stream = ByteStream::new(&bytes);
+        byte_stream.set_format(format);
+
+        let function = Function::from_byte_stream(&mut byte_stream, LuaVersion::Lua50, &Settings::default())?;
+        assert_eq!(function.functions[0].source_file, "@bar.lua\0");
+        assert!(!function.functions[0].is_source_shared);
+        Ok(())
+    }
+
+    /// Returns the source file of the main function and its nested function
+    /// and whether the nested function shares the source file.
+    fn overridden_root_source(root_source_override: &str) -> Result<(String, String, bool), LunifyError> {
+        let format = Format::default();
+        let mut byte_writer = ByteWriter::new(&format);
+        write_lua50_closure_with_source(&mut byte_writer, "@foo.lua\0", "", 1, &[])?;
+
+        let bytes = byte_writer.finalize();
+        let mut byte_stream = ByteStream::new(&bytes);
+        byte_stream.set_format(format);
+
+        let mut settings = Settings::default();
+        settings.output.root_source_override = Some(root_source_override);
+
+        let mut function = Function::from_byte_stream(&mut byte_stream, LuaVersion::Lua50, &settings)?;
+        let closure = function.functions.remove(0);
+        Ok((function.source_file, closure.source_file, closure.is_source_shared))
+    }
+
+    #[test]
+    fn root_source_override() -> Result<(), LunifyError> {
+        let (source_file, closure_source_file, is_source_shared) = overridden_root_source("@bundle:build-1234")?;
+        assert_eq!(source_file, "@bundle:build-1234\0");
+
+        // The nested function inherited the source file of the input, so it can't
+        // inherit the new one.
+        assert_eq!(closure_source_file, "@foo.lua\0");
+        assert!(!is_source_shared);
+        Ok(())
+    }
+
+    #[test]
+    fn root_source_override_terminated() -> Result<(), LunifyError> {
+        let (source_file, ..) = overridden_root_source("=bundle\0")?;
+        assert_eq!(source_file, "=bundle\0");
+        Ok(())
+    }
+
+    #[test]
+    fn zero_root_lines() -> Result<(), LunifyError> {
+        let format = Format::default();
+        let mut byte_writer = ByteWriter::new(&format);
+        write_lua50_closure(&mut byte_writer, "@foo.lua\0", 1, &[])?;
+
+        let bytes = byte_writer.finalize();
+        let mut byte_stream = ByteStream::new(&bytes);
+        byte_stream.set_format(format);
+
+        // Lua 5.0 doesn't store the last line, so the lines are set before converting
+        // again.
+        let mut function = Function::from_byte_stream(&mut byte_stream, LuaVersion::Lua50, &Settings::default())?;
+        (function.line_defined, function.last_line_defined) = (7, 9);
+        (function.functions[0].line_defined, function.functions[0].last_line_defined) = (3, 4);
+
+        let mut byte_writer = ByteWriter::new(&format);
+        function.write(&mut byte_writer, &mut Vec::new())?;
+
+        let bytes = byte_writer.finalize();
+        let mut byte_stream = ByteStream::new(&bytes);
+        byte_stream.set_format(format);
+
+        let mut settings = Settings::default();
+        settings.output.zero_root_lines = true;
+
+        let function = Function::from_byte_stream(&mut byte_stream, LuaVersion::Lua51, &settings)?;
+        assert_eq!((function.line_defined, function.last_line_defined), (0, 0));
+        assert_eq!((function.functions[0].line_defined, function.functions[0].last_line_defined), (3, 4));
+        assert!(function.original.is_none());
+        Ok(())
+    }
+
+    /// Convert a function with lines that don't fit into 32-bit integers and
+    /// parse the output again. The reported number of overflowing lines is
+    /// returned as well.
+    fn convert_overflowing_lines(
+        policy: LineOverflowPolicy,
+        diagnostics: Option<DiagnosticCallback>,
+    ) -> Result<(Vec<i64>, usize), LunifyError> {
+        let input_format = Format {
+            integer_width: BitWidth::Bit64,
+            ..Format::default()
+        };
+        let mut byte_writer = ByteWriter::new(&input_format);
+        write_lua50_closure(&mut byte_writer, "@foo.lua\0", 1, &[])?;
+
+        let bytes = byte_writer.finalize();
+        let mut byte_stream = ByteStream::new(&bytes);
+        byte_stream.set_format(input_format);
+
+        let mut settings = Settings::default();
+        settings.output.line_number_overflow = policy;
+        settings.diagnostics = diagnostics;
+
+        let mut function = Function::from_byte_stream(&mut byte_stream, LuaVersion::Lua50, &settings)?;
+        (function.line_defined, function.last_line_defined) = (1 << 33, 1 << 33);
+        function.line_info = vec![1 << 33; function.instructions.len()];
+
+        let output_format = Format::default();
+        let mut reports = Vec::new();
+        function.report(&output_format, &mut Vec::new(), &mut reports, &settings)?;
+
+        let mut byte_writer = ByteWriter::new(&output_format);
+        function.write(&mut byte_writer, &mut Vec::new())?;
+
+        let bytes = byte_writer.finalize();
+        let mut byte_stream = ByteStream::new(&bytes);
+        byte_stream.set_format(output_format);
+
+        let function = Function::from_byte_stream(&mut byte_stream, LuaVersion::Lua51, &Settings::default())?;
+        let lines = [function.line_defined, function.last_line_defined].into_iter().chain(function.line_info).collect();
+        Ok((lines, reports[0].overflowing_lines))
+    }
+
+    #[test]
+    fn line_number_overflow_error() {
+        let result = convert_overflowing_lines(LineOverflowPolicy::Error, None);
+        assert_eq!(result, Err(LunifyError::ValueTooBigForWidth {
+            value: 1 << 33,
+            width: BitWidth::Bit32,
+        }));
+    }
+
+    #[test]
+    fn line_number_overflow_clamp() -> Result<(), LunifyError> {
+        let (lines, overflowing_lines) = convert_overflowing_lines(LineOverflowPolicy::Clamp, None)?;
+        assert!(lines.iter().all(|line| *line == i32::MAX as i64));
+        assert_eq!(overflowing_lines, lines.len());
+        Ok(())
+    }
+
+    #[test]
+    fn line_number_overflow_zero() -> Result<(), LunifyError> {
+        let (lines, overflowing_lines) = convert_overflowing_lines(LineOverflowPolicy::Zero, None)?;
+        assert!(lines.iter().all(|line| *line == 0));
+        assert_eq!(overflowing_lines, lines.len());
+        Ok(())
+    }
+
+    #[test]
+    fn line_number_overflow_diagnostic() -> Result<(), LunifyError> {
+        let diagnostics = RefCell::new(Vec::new());
+        let record = |diagnostic: Diagnostic| diagnostics.borrow_mut().push(diagnostic);
+        convert_overflowing_lines(LineOverflowPolicy::Clamp, Some(DiagnosticCallback(&record)))?;
+
+        let diagnostics = diagnostics.into_inner();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, DiagnosticCode::LineNumberOverflow);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(diagnostics[0].function_path, FunctionPath::root());
+        Ok(())
+    }
+
+    #[test]
+    fn partial_upvalue_names() -> Result<(), LunifyError> {
+        let format = Format::default();
+        let mut byte_writer = ByteWriter::new(&format);
+        write_lua50_closure(&mut byte_writer, "", 2, &["table\0"])?;
+
+        let bytes = byte_writer.finalize();
+        let mut byte_stream = ByteStream::new(&bytes);
+        byte_stream.set_format(format);
+
+        let function = Function::from_byte_stream(&mut byte_stream, LuaVersion::Lua50, &Settings::default())?;
+        let closure = &function.functions[0];
+
+        assert_eq!(closure.upvalue_count, 2);
+        assert_eq!(closure.upvalues, [b"table\0".as_slice(), b"__lunify_upval_1\0".as_slice()]);
+        Ok(())
+    }
+
+    #[test]
+    fn content_hash_ignores_debug_information() -> Result<(), LunifyError> {
+        let format = Format::default();
+        let mut hashes = Vec::new();
+
+        for (source_file, upvalues) in [("", [].as_slice()), ("@foo.lua\0", ["table\0"].as_slice())] {
+            let mut byte_writer = ByteWriter::new(&format);
+            write_lua50_closure(&mut byte_writer, source_file, 1, upvalues)?;
+
+            let bytes = byte_writer.finalize();
+            let mut byte_stream = ByteStream::new(&bytes);
+            byte_stream.set_format(format);
+
+            let function = Function::from_byte_stream(&mut byte_stream, LuaVersion::Lua50, &Settings::default())?;
+            hashes.push(function.functions[0].content_hash(&format)?);
+        }
+
+        assert_eq!(hashes[0], hashes[1]);
+        Ok(())
+    }
+
+    /// The number of instructions and temporary globals created by Lunify in a
+    /// function and all of its nested functions.
+    fn count_instructions_and_temporaries(function: &Function) -> (usize, usize) {
+        let temporaries = function
+            .constants
+            .iter()
+            .filter(|constant| matches!(constant, Constant::String(string) if string.starts_with(b"__%lunify%__temp")))
+            .count();
+
+        function
+            .functions
+            .iter()
+            .map(count_instructions_and_temporaries)
+            .fold((function.instructions.len(), temporaries), |(instructions, temporaries), counts| {
+                (instructions + counts.0, temporaries + counts.1)
+            })
+    }
+
+    #[test]
+    fn reconvert_for_loops() -> Result<(), LunifyError> {
+        // The Lua 5.1 input path never preserves the loop index again, so converting
+        // our own output with a different `LFIELDS_PER_FLUSH` keeps the saving and
+        // restoring of RA+3 as is instead of layering another one around it.
+        let counts = |bytes: &[u8], settings: &Settings| -> Result<(usize, usize), LunifyError> {
+            let (mut byte_stream, version, _) = crate::read_header(bytes, settings)?;
+            let function = Function::from_byte_stream(&mut byte_stream, version, settings)?;
+            Ok(count_instructions_and_temporaries(&function))
+        };
+
+        let inputs: [(&[u8], usize); 2] = [
+            (include_bytes!("../../test_files/lua50.luab"), 1),
+            (include_bytes!("../../test_files/for_loop.luab"), 4),
+        ];
+
+        for (input_bytes, temporary_count) in inputs {
+            let output_bytes = crate::unify(input_bytes, &Format::default(), &Settings::default())?;
+            let expected = counts(&output_bytes, &Settings::default())?;
+
+            let mut settings = Settings::default();
+            settings.output.fields_per_flush = 100;
+            let reconverted_bytes = crate::unify(&output_bytes, &Format::default(), &settings)?;
+
+            settings.lua51.fields_per_flush = 100;
+            assert_eq!(counts(&reconverted_bytes, &settings)?, expected);
+            assert_eq!(expected.1, temporary_count);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn content_hash_instruction_changed() -> Result<(), LunifyError> {
+        let format = Format::default();
+        let mut hashes = Vec::new();
+
+        // `LOADK 0 0`, `RETURN 0 1` and `LOADK 1 0`, `RETURN 0 1`.
+        for load_constant in [1, 1 | (1 << 6)] {
+            let mut byte_writer = ByteWriter::new(&format);
+            byte_writer.string("")?;
+            byte_writer.integer(0)?;
+            byte_writer.integer(0)?;
+            byte_writer.slice(&[0, 0, 2, 2]);
+            byte_writer.integer(2)?;
+            byte_writer.instruction(load_constant);
+            byte_writer.instruction(30 | (1 << 23));
+
+            // Constants, functions, line info, local variables and upvalues.
+            byte_writer.integer(1)?;
+            byte_writer.byte(3);
+            byte_writer.number(Number::Float(9.0))?;
+            byte_writer.integer(0)?;
+            byte_writer.integer(2)?;
+            byte_writer.integer_batch(&[1, 1])?;
+            byte_writer.integer(0)?;
+            byte_writer.integer(0)?;
+
+            let bytes = byte_writer.finalize();
+            let mut byte_stream = ByteStream::new(&bytes);
+            byte_stream.set_format(format);
+
+            let function = Function::from_byte_stream(&mut byte_stream, LuaVersion::Lua51, &Settings::default())?;
+            assert_eq!(function.instructions.len(), 2);
+            assert_eq!(function.content_hash(&format)?, function.content_hash(&format)?);
+            hashes.push(function.content_hash(&format)?);
+        }
+
+        assert_ne!(hashes[0], hashes[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn rewrite_source_nested() -> Result<(), LunifyError> {
+        let format = Format::default();
+        let mut byte_writer = ByteWriter::new(&format);
+        write_lua50_closure(&mut byte_writer, "@C:\\build\\foo.lua\0", 1, &[])?;
+
+        let bytes = byte_writer.finalize();
+        let mut byte_stream = ByteStream::new(&bytes);
+        byte_stream.set_format(format);
+
+        let settings = Settings {
+            output: lua51::Settings {
+                rewrite_source: Some(SourceRewrite::ReplaceAll { from: "\\", to: "/" }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let function = Function::from_byte_stream(&mut byte_stream, LuaVersion::Lua50, &settings)?;
+
+        assert_eq!(function.source_file, "@C:/build/foo.lua\0");
+        assert_eq!(function.functions[0].source_file, "@C:/build/foo.lua\0");
+        Ok(())
+    }
+
+    #[test]
+    fn extended_set_list() -> Result<(), LunifyError> {
+        let format = Format::default();
+        let mut byte_writer = ByteWriter::new(&format);
+
+        byte_writer.string("")?;
+        byte_writer.integer(0)?;
+        byte_writer.integer(0)?;
+        byte_writer.slice(&[0, 0, 0, 2]);
+
+        // `SETLIST 0 1 0` followed by the page in the next instruction slot and a `RETURN 0 1`.
+        byte_writer.integer(3)?;
+        byte_writer.instruction(34 | (1 << 23));
+        byte_writer.instruction(2);
+        byte_writer.instruction(30 | (1 << 23));
+
+        // Constants and functions.
+        byte_writer.integer(0)?;
+        byte_writer.integer(0)?;
+
+        // Line info.
+        byte_writer.integer(3)?;
+        byte_writer.integer(1)?;
+        byte_writer.integer(1)?;
+        byte_writer.integer(2)?;
+
+        // Local variables and upvalues.
+        byte_writer.integer(0)?;
+        byte_writer.integer(0)?;
+
+        let bytes = byte_writer.finalize();
+        let mut byte_stream = ByteStream::new(&bytes);
+        byte_stream.set_format(format);
+
+        let function = Function::from_byte_stream(&mut byte_stream, LuaVersion::Lua51, &Settings::default())?;
+
+        assert_eq!(function.instructions, [34 | (1 << 23) | (2 << 14), 30 | (1 << 23)]);
+        assert_eq!(function.line_info, [1, 2]);
+        assert!(byte_stream.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn extended_set_list_truncated() -> Result<(), LunifyError> {
+        let format = Format::default();
+        let mut byte_writer = ByteWriter::new(&format);
+
+        byte_writer.string("")?;
+        byte_writer.integer(0)?;
+        byte_writer.integer(0)?;
+        byte_writer.slice(&[0, 0, 0, 2]);
+
+        // `SETLIST 0 1 0` without an instruction slot holding the page.
+        byte_writer.integer(1)?;
+        byte_writer.instruction(34 | (1 << 23));
+
+        let bytes = byte_writer.finalize();
+        let mut byte_stream = ByteStream::new(&bytes);
+        byte_stream.set_format(format);
+
+        let result = Function::from_byte_stream(&mut byte_stream, LuaVersion::Lua51, &Settings::default());
+        assert!(matches!(result, Err(LunifyError::MalformedSetList)));
+        Ok(())
     }
 }
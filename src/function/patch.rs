@@ -0,0 +1,335 @@
+use std::borrow::Cow;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use super::constant::Constant;
+use super::instruction::{lua51, LuaInstruction, PrototypeIndex, BC};
+use super::{Function, Settings};
+use crate::number::Number;
+use crate::{FunctionPath, LunifyError};
+
+/// New value of a constant passed to [`set_constant`](crate::set_constant).
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ConstantValue {
+    /// `nil`.
+    Nil,
+    /// `true` or `false`.
+    Boolean(bool),
+    /// A number. Output formats with integral numbers can only hold whole
+    /// numbers.
+    Number(f64),
+    /// A string, without the terminating zero that Lua stores in the byte
+    /// code.
+    String(Vec<u8>),
+}
+
+impl ConstantValue {
+    pub(super) fn from_constant(constant: &Constant) -> Self {
+        match constant {
+            Constant::Nil => ConstantValue::Nil,
+            Constant::Boolean(boolean) => ConstantValue::Boolean(*boolean),
+            Constant::Number(Number::Float(value)) => ConstantValue::Number(*value),
+            Constant::Number(Number::Integer(value)) => ConstantValue::Number(*value as f64),
+            Constant::String(string) => ConstantValue::String(string.strip_suffix(&[0]).unwrap_or(string).to_vec()),
+        }
+    }
+
+    fn into_constant<'a>(self) -> Constant<'a> {
+        match self {
+            ConstantValue::Nil => Constant::Nil,
+            ConstantValue::Boolean(boolean) => Constant::Boolean(boolean),
+            ConstantValue::Number(value) => Constant::Number(Number::Float(value)),
+            ConstantValue::String(mut string) => {
+                string.push(0);
+                Constant::String(Cow::Owned(string))
+            }
+        }
+    }
+}
+
+impl<'a> Function<'a> {
+    /// Replace the constant at `index` of the nested function at `path`.
+    pub(crate) fn set_constant(
+        &mut self,
+        path: &[usize],
+        index: usize,
+        value: ConstantValue,
+        settings: &Settings,
+    ) -> Result<(), LunifyError> {
+        let function = self.modified_function(path)?;
+        let constant = function.constants.get(index).ok_or(LunifyError::InvalidConstantIndex {
+            index,
+            available: function.constants.len(),
+        })?;
+        let value = value.into_constant();
+
+        if std::mem::discriminant(constant) != std::mem::discriminant(&value) {
+            if !settings.allow_constant_type_change {
+                return Err(LunifyError::ConstantTypeMismatch(index));
+            }
+
+            if let Some(program_counter) = function.comparison_of_constant(index as u64, settings)? {
+                return Err(LunifyError::ComparedConstantTypeChange { index, program_counter });
+            }
+        }
+
+        function.constants[index] = value;
+        function.is_modified = true;
+        Ok(())
+    }
+
+    /// Insert `function` as the nested function at `index` of the function at
+    /// `path`. `CLOSURE` instructions of the parent that reference a nested
+    /// function at or after `index` are updated, so they keep referencing the
+    /// same function.
+    pub(crate) fn insert_function(
+        &mut self,
+        path: &[usize],
+        index: usize,
+        function: Function<'a>,
+        settings: &Settings,
+    ) -> Result<(), LunifyError> {
+        let parent = self.modified_function(path)?;
+
+        if index > parent.functions.len() {
+            return Err(LunifyError::InvalidFunctionIndex {
+                index,
+                available: parent.functions.len(),
+            });
+        }
+
+        let padding_mask = !settings.output.layout.bit_mask();
+        for (program_counter, value) in parent.instructions.iter_mut().enumerate() {
+            let instruction = lua51::Instruction::decode(*value, settings, &settings.output.layout, program_counter)?;
+
+            if let lua51::Instruction::Closure { a, mode: PrototypeIndex(prototype) } = instruction {
+                if prototype >= index as u64 {
+                    let instruction = lua51::Instruction::Closure {
+                        a,
+                        mode: PrototypeIndex(prototype + 1),
+                    };
+                    *value = instruction.to_u64(settings)? | (*value & padding_mask);
+                }
+            }
+        }
+
+        parent.functions.insert(index, function);
+        parent.is_modified = true;
+        Ok(())
+    }
+
+    /// Get the nested function at `path`. The function is written again, so
+    /// neither it nor any of the functions leading to it can be copied from the
+    /// input.
+    fn modified_function(&mut self, path: &[usize]) -> Result<&mut Self, LunifyError> {
+        let mut function = self;
+
+        for (depth, &function_index) in path.iter().enumerate() {
+            function.original = None;
+            function = function
+                .functions
+                .get_mut(function_index)
+                .ok_or_else(|| LunifyError::InvalidFunctionPath(FunctionPath::from(&path[..=depth])))?;
+        }
+        function.original = None;
+
+        Ok(function)
+    }
+
+    /// Find the first instruction that compares the constant at `index` through
+    /// one of its RK operands.
+    fn comparison_of_constant(&self, index: u64, settings: &Settings) -> Result<Option<usize>, LunifyError> {
+        for (program_counter, &value) in self.instructions.iter().enumerate() {
+            let instruction = lua51::Instruction::decode(value, settings, &settings.output.layout, program_counter)?;
+
+            if let lua51::Instruction::Equals { mode: BC(b, c), .. }
+            | lua51::Instruction::LessThan { mode: BC(b, c), .. }
+            | lua51::Instruction::LessEquals { mode: BC(b, c), .. } = instruction
+            {
+                if [b, c].iter().any(|operand| operand.1 && operand.0 == index) {
+                    return Ok(Some(program_counter));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::super::constant::Constant;
+    use super::super::Function;
+    use crate::number::Number;
+    use crate::tests::{lua51_abc, lua51_abx, lua51_chunk_bytes, TestConstant, TestFunction};
+    use crate::{insert_function, read_header, set_constant, ConstantValue, Format, FunctionPath, LunifyError, RawChunk, Settings};
+
+    const FOR_LOOP_BYTES: &[u8] = include_bytes!("../../test_files/for_loop.luab");
+    /// Big endian byte code that sets `result` to 9.
+    const BIG_ENDIAN_BYTES: &[u8] = include_bytes!("../../test_files/big_endian.luab");
+
+    /// Lua 5.1 byte code that calls the same nested function twice, which
+    /// increments `result`.
+    fn increment_bytes() -> Result<Vec<u8>, LunifyError> {
+        let format = Format::default();
+
+        // `GETGLOBAL 0 0`, `ADD 0 0 K1`, `SETGLOBAL 0 0` and `RETURN 0 1`.
+        let increment_function = TestFunction {
+            source_file: "@increment.lua",
+            header: [0, 0, 2, 2],
+            instructions: &[lua51_abx(5, 0, 0), lua51_abc(12, 0, 0, 257), lua51_abx(7, 0, 0), lua51_abc(30, 0, 1, 0)],
+            constants: &[TestConstant::String("result"), TestConstant::Number(1.0)],
+            ..Default::default()
+        };
+
+        // `CLOSURE 0 0`, `CALL 0 1 1`, `CLOSURE 0 0`, `CALL 0 1 1` and `RETURN 0 1`.
+        let call = [lua51_abx(36, 0, 0), lua51_abc(28, 0, 1, 1)];
+        lua51_chunk_bytes(&format, &TestFunction {
+            source_file: "@increment.lua",
+            header: [0, 0, 2, 2],
+            instructions: &[&call[..], &call, &[lua51_abc(30, 0, 1, 0)]].concat(),
+            functions: &[&increment_function.lua51_bytes(&format)?],
+            ..Default::default()
+        })
+    }
+
+    fn insert_big_endian_function(parent: &FunctionPath, index: usize) -> Result<Vec<u8>, LunifyError> {
+        insert_function(increment_bytes()?, parent, index, BIG_ENDIAN_BYTES, &Format::default(), &Settings::default())
+    }
+
+    /// The nested functions that the `CLOSURE` instructions of the main function
+    /// reference.
+    fn closures(output_bytes: &[u8]) -> Result<Vec<u64>, LunifyError> {
+        let chunk = RawChunk::parse(output_bytes, &Settings::default())?;
+        let closures = chunk.main.instructions.iter().filter(|&&instruction| instruction & 0x3f == 36);
+        Ok(closures.map(|&instruction| instruction >> 14).collect())
+    }
+
+    fn set_for_loop_constant(index: usize, value: ConstantValue, settings: &Settings) -> Result<Vec<u8>, LunifyError> {
+        set_constant(FOR_LOOP_BYTES, &FunctionPath::root(), index, value, &Format::default(), settings)
+    }
+
+    fn constant(output_bytes: &[u8], index: usize) -> Result<Constant<'_>, LunifyError> {
+        let settings = Settings::default();
+        let (mut byte_stream, version, _) = read_header(output_bytes, &settings)?;
+        let mut function = Function::from_byte_stream(&mut byte_stream, version, &settings)?;
+        Ok(function.constants.remove(index))
+    }
+
+    #[test]
+    fn number() -> Result<(), LunifyError> {
+        let output_bytes = set_for_loop_constant(1, ConstantValue::Number(10.0), &Settings::default())?;
+        assert_eq!(constant(&output_bytes, 1)?, Constant::Number(Number::Float(10.0)));
+
+        // `result` starts at 10 instead of 0.
+        #[cfg(feature = "integration")]
+        {
+            use mlua::prelude::*;
+
+            let lua = Lua::new();
+            lua.load(&output_bytes).exec().unwrap();
+            assert_eq!(lua.globals().get::<_, LuaNumber>("result").unwrap(), 19.0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn string() -> Result<(), LunifyError> {
+        let output_bytes = set_for_loop_constant(0, ConstantValue::String(b"total".to_vec()), &Settings::default())?;
+        assert_eq!(constant(&output_bytes, 0)?, Constant::String(Cow::Borrowed(b"total\0")));
+        assert_eq!(output_bytes.len() + 1, crate::unify(FOR_LOOP_BYTES, &Format::default(), &Settings::default())?.len());
+
+        #[cfg(feature = "integration")]
+        {
+            use mlua::prelude::*;
+
+            let lua = Lua::new();
+            lua.load(&output_bytes).exec().unwrap();
+            assert_eq!(lua.globals().get::<_, LuaNumber>("total").unwrap(), 9.0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn type_change() -> Result<(), LunifyError> {
+        let result = set_for_loop_constant(1, ConstantValue::Nil, &Settings::default());
+        assert_eq!(result, Err(LunifyError::ConstantTypeMismatch(1)));
+
+        let settings = Settings::builder().allow_constant_type_change(true).build()?;
+        let output_bytes = set_for_loop_constant(1, ConstantValue::Nil, &settings)?;
+        assert_eq!(constant(&output_bytes, 1)?, Constant::Nil);
+        Ok(())
+    }
+
+    #[test]
+    fn compared_type_change() {
+        let input_bytes = include_bytes!("../../test_files/lua50.luab");
+        let settings = Settings::builder().allow_constant_type_change(true).build().unwrap();
+        let path = FunctionPath::from(vec![1]);
+
+        let result = set_constant(input_bytes, &path, 1, ConstantValue::Boolean(true), &Format::default(), &settings);
+        assert_eq!(result, Err(LunifyError::ComparedConstantTypeChange {
+            index: 1,
+            program_counter: 5,
+        }));
+    }
+
+    #[test]
+    fn invalid_constant() {
+        // Up-casting adds constants, so there are more than in the input.
+        let result = set_for_loop_constant(100, ConstantValue::Nil, &Settings::default());
+        assert!(matches!(result, Err(LunifyError::InvalidConstantIndex { index: 100, available }) if available > 6));
+
+        let path = FunctionPath::from(vec![0]);
+        let result = set_constant(FOR_LOOP_BYTES, &path, 0, ConstantValue::Nil, &Format::default(), &Settings::default());
+        assert_eq!(result, Err(LunifyError::InvalidFunctionPath(path)));
+    }
+
+    #[test]
+    fn insert_before() -> Result<(), LunifyError> {
+        let output_bytes = insert_big_endian_function(&FunctionPath::root(), 0)?;
+        assert_eq!(closures(&output_bytes)?, [1, 1]);
+
+        // Create a closure of the inserted function with the first `CLOSURE`
+        // instruction, so `result` is set to 9 before it is incremented.
+        #[cfg(feature = "integration")]
+        {
+            use mlua::prelude::*;
+
+            let closure = (36u32 | (1 << 14)).to_le_bytes();
+            let position = output_bytes.windows(4).position(|window| window == closure).unwrap();
+            let mut output_bytes = output_bytes;
+            output_bytes[position..position + 4].copy_from_slice(&36u32.to_le_bytes());
+            assert_eq!(crate::validate(&output_bytes, &Settings::default()), Ok(()));
+
+            let lua = Lua::new();
+            lua.load(&output_bytes).exec().unwrap();
+            assert_eq!(lua.globals().get::<_, LuaNumber>("result").unwrap(), 10.0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn insert_after() -> Result<(), LunifyError> {
+        let output_bytes = insert_big_endian_function(&FunctionPath::root(), 1)?;
+        assert_eq!(closures(&output_bytes)?, [0, 0]);
+        assert_eq!(RawChunk::parse(&output_bytes, &Settings::default())?.main.children.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_insertion() {
+        let result = insert_big_endian_function(&FunctionPath::root(), 2);
+        assert_eq!(result, Err(LunifyError::InvalidFunctionIndex { index: 2, available: 1 }));
+
+        let path = FunctionPath::from(vec![0, 0]);
+        assert_eq!(insert_big_endian_function(&path, 0), Err(LunifyError::InvalidFunctionPath(path)));
+    }
+}
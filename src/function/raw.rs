@@ -0,0 +1,292 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use super::{ConstantValue, Function, InstructionLayout, Settings};
+use crate::format::LuaVersion;
+use crate::serialization::ByteStream;
+use crate::padding::trailing_padding;
+use crate::{read_header, Format, LunifyError};
+
+/// Lua byte code in a supported format, parsed without being converted. See
+/// [`RawChunk::parse`].
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RawChunk {
+    /// The format of the input.
+    pub format: Format,
+    /// The Lua version of the input.
+    pub version: LuaVersion,
+    /// The layout of the instructions, taken from the Lua 5.0 or Lua 5.1
+    /// settings depending on the version.
+    pub layout: InstructionLayout,
+    /// The main function.
+    pub main: RawFunction,
+}
+
+/// A function of a [`RawChunk`].
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RawFunction {
+    /// The source file, without the terminating zero. Nested functions of Lua
+    /// 5.1 byte code usually have an empty source file, meaning that it is the
+    /// same as the one of their parent.
+    pub source_file: Vec<u8>,
+    /// The line the function is defined on.
+    pub line_defined: i64,
+    /// The last line of the function. Lua 5.0 doesn't store it, so it is the
+    /// same as `line_defined` for Lua 5.0 byte code.
+    pub last_line_defined: i64,
+    /// The number of upvalues the function captures.
+    pub upvalue_count: u8,
+    /// The number of fixed parameters of the function.
+    pub parameter_count: u8,
+    /// The `is_vararg` byte as it is stored. In Lua 5.1 it holds the
+    /// `VARARG_*` flags.
+    pub is_variadic: u8,
+    /// The `maxstacksize` of the function.
+    pub maximum_stack_size: u8,
+    /// The instructions as they are stored, read with the endianness and
+    /// instruction width of the format. Extended arguments, like the page of a
+    /// Lua 5.1 `SETLIST` with C set to zero, take up an instruction of their own.
+    pub instructions: Vec<u64>,
+    /// The constants. Numbers of formats with integral numbers are converted to
+    /// floats.
+    pub constants: Vec<ConstantValue>,
+    /// The functions nested directly inside of this function.
+    pub children: Vec<RawFunction>,
+}
+
+impl RawChunk {
+    /// Parse Lua byte code in a supported format, but don't convert it. The
+    /// instructions are kept as they are, so they can be matched or decoded
+    /// with the [`layout`](Self::layout) without going through the
+    /// [`lua50`](crate::lua50) and [`lua51`](crate::lua51) instruction types.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use lunify::{InstructionField, LunifyError, RawChunk, Settings};
+    ///
+    /// # fn main() -> Result<(), LunifyError> {
+    /// # let input_bytes = include_bytes!("../../test_files/lua50.luab");
+    /// let chunk = RawChunk::parse(input_bytes, &Settings::default())?;
+    ///
+    /// for instruction in &chunk.main.instructions {
+    ///     println!("opcode {}", chunk.layout.get_field(*instruction, InstructionField::Opcode));
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn parse(input_bytes: impl AsRef<[u8]>, settings: &Settings) -> Result<Self, LunifyError> {
+        let (byte_stream, version, format) = read_header(input_bytes.as_ref(), settings)?;
+        Self::parse_functions(byte_stream, version, format, settings)
+    }
+
+    /// Same as [`parse`](Self::parse), but for byte code without a header,
+    /// like the output of [`unify_body`](crate::unify_body). The format and
+    /// the Lua version that would be in the header are passed instead.
+    pub fn parse_body(
+        input_bytes: impl AsRef<[u8]>,
+        format: &Format,
+        version: LuaVersion,
+        settings: &Settings,
+    ) -> Result<Self, LunifyError> {
+        let mut byte_stream = ByteStream::new(input_bytes.as_ref());
+        byte_stream.set_format(*format);
+        Self::parse_functions(byte_stream, version, *format, settings)
+    }
+
+    fn parse_functions(mut byte_stream: ByteStream, version: LuaVersion, format: Format, settings: &Settings) -> Result<Self, LunifyError> {
+        let main = Function::raw(&mut byte_stream, version, settings)?;
+
+        trailing_padding(&byte_stream, settings)?;
+
+        let layout = match version {
+            LuaVersion::Lua50 => settings.lua50.layout,
+            LuaVersion::Lua51 => settings.lua51.layout,
+        };
+
+        Ok(Self {
+            format,
+            version,
+            layout,
+            main,
+        })
+    }
+}
+
+impl<'a> Function<'a> {
+    /// Read a function and its nested functions without decoding the
+    /// instructions.
+    fn raw(byte_stream: &mut ByteStream<'a>, version: LuaVersion, settings: &Settings) -> Result<RawFunction, LunifyError> {
+        let source_file = byte_stream.string_slice()?;
+        let line_defined = byte_stream.integer()?;
+
+        let last_line_defined = match version {
+            LuaVersion::Lua51 => byte_stream.integer()?,
+            LuaVersion::Lua50 => line_defined,
+        };
+
+        let upvalue_count = byte_stream.byte()?;
+        let parameter_count = byte_stream.byte()?;
+        let is_variadic = byte_stream.byte()?;
+        let maximum_stack_size = byte_stream.byte()?;
+
+        let (instructions, constants, children) = match version {
+            LuaVersion::Lua51 => {
+                let instructions = Self::get_raw_instructions(byte_stream, settings)?;
+                let constants = Self::get_constants(byte_stream)?;
+                let children = Self::raw_children(byte_stream, version, settings)?;
+
+                Self::get_line_info(byte_stream)?;
+                Self::get_local_variables(byte_stream)?;
+                Self::get_upvalues(byte_stream)?;
+
+                if let Some(spec) = settings.lua51.function_trailer {
+                    spec.read(byte_stream)?;
+                }
+
+                (instructions, constants, children)
+            }
+            LuaVersion::Lua50 => {
+                Self::get_line_info(byte_stream)?;
+                Self::get_local_variables(byte_stream)?;
+                Self::get_upvalues(byte_stream)?;
+
+                let constants = Self::get_constants(byte_stream)?;
+                let children = Self::raw_children(byte_stream, version, settings)?;
+                let instructions = Self::get_raw_instructions(byte_stream, settings)?;
+
+                (instructions, constants, children)
+            }
+        };
+
+        Ok(RawFunction {
+            source_file: source_file.strip_suffix(&[0]).unwrap_or(source_file).to_vec(),
+            line_defined,
+            last_line_defined,
+            upvalue_count,
+            parameter_count,
+            is_variadic,
+            maximum_stack_size,
+            instructions,
+            constants: constants.iter().map(ConstantValue::from_constant).collect(),
+            children,
+        })
+    }
+
+    fn raw_children(byte_stream: &mut ByteStream<'a>, version: LuaVersion, settings: &Settings) -> Result<Vec<RawFunction>, LunifyError> {
+        (0..byte_stream.count()?).map(|_| Self::raw(byte_stream, version, settings)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RawChunk, RawFunction};
+    use crate::format::LuaVersion;
+    use crate::tests::{lua51_chunk_bytes, TestConstant, TestFunction};
+    use crate::{lua51, BitWidth, ConstantValue, Endianness, Format, InstructionField, LunifyError, Settings};
+
+    const BIG_ENDIAN_FORMAT: Format = Format {
+        format: 0,
+        endianness: Endianness::Big,
+        integer_width: BitWidth::Bit32,
+        size_t_width: BitWidth::Bit32,
+        instruction_width: BitWidth::Bit32,
+        number_width: BitWidth::Bit64,
+        is_number_integral: false,
+    };
+
+    /// Big endian Lua 5.1 byte code for `return function() return "a" end`.
+    fn nested_bytes() -> Result<Vec<u8>, LunifyError> {
+        // `LOADK 0 0`, `RETURN 0 2` and `RETURN 0 1`.
+        let child_function = TestFunction {
+            line_defined: 1,
+            last_line_defined: 2,
+            header: [0, 0, 2, 2],
+            instructions: &[0x01, 0x0100001e, 0x0080001e],
+            constants: &[TestConstant::String("a")],
+            ..Default::default()
+        };
+
+        // `CLOSURE 0 0`, `RETURN 0 2` and `RETURN 0 1`.
+        lua51_chunk_bytes(&BIG_ENDIAN_FORMAT, &TestFunction {
+            source_file: "@main.lua",
+            line_defined: 1,
+            last_line_defined: 2,
+            header: [0, 0, 2, 2],
+            instructions: &[0x24, 0x0080001e, 0x0080001e],
+            functions: &[&child_function.lua51_bytes(&BIG_ENDIAN_FORMAT)?],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn parse() -> Result<(), LunifyError> {
+        let chunk = RawChunk::parse(nested_bytes()?, &Settings::default())?;
+
+        let child = RawFunction {
+            source_file: Vec::new(),
+            line_defined: 1,
+            last_line_defined: 2,
+            upvalue_count: 0,
+            parameter_count: 0,
+            is_variadic: 2,
+            maximum_stack_size: 2,
+            instructions: vec![0x01, 0x0100001e, 0x0080001e],
+            constants: vec![ConstantValue::String(b"a".to_vec())],
+            children: Vec::new(),
+        };
+
+        assert_eq!(chunk, RawChunk {
+            format: BIG_ENDIAN_FORMAT,
+            version: LuaVersion::Lua51,
+            layout: lua51::Settings::default().layout,
+            main: RawFunction {
+                source_file: b"@main.lua".to_vec(),
+                instructions: vec![0x24, 0x0080001e, 0x0080001e],
+                constants: Vec::new(),
+                children: vec![child.clone()],
+                ..child
+            },
+        });
+
+        // `RETURN 0 2` of the nested function.
+        let instruction = chunk.main.children[0].instructions[1];
+        assert_eq!(chunk.layout.get_field(instruction, InstructionField::Opcode), 30);
+        assert_eq!(chunk.layout.get_field(instruction, InstructionField::B), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_lua50() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../../test_files/lua50.luab");
+        let chunk = RawChunk::parse(input_bytes, &Settings::default())?;
+
+        assert_eq!(chunk.version, LuaVersion::Lua50);
+        assert_eq!(chunk.layout, crate::lua50::Settings::default().layout);
+
+        // The main function ends with `RETURN 0 1`.
+        assert_eq!(chunk.main.instructions.last(), Some(&(27 | (1 << 15))));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_body() -> Result<(), LunifyError> {
+        let input_bytes = nested_bytes()?;
+        let body = &input_bytes[BIG_ENDIAN_FORMAT.byte_size_of_header(LuaVersion::Lua51)..];
+
+        let chunk = RawChunk::parse_body(body, &BIG_ENDIAN_FORMAT, LuaVersion::Lua51, &Settings::default())?;
+        assert_eq!(chunk, RawChunk::parse(&input_bytes, &Settings::default())?);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_too_long() -> Result<(), LunifyError> {
+        let mut input_bytes = nested_bytes()?;
+        input_bytes.push(0);
+
+        assert_eq!(RawChunk::parse(input_bytes, &Settings::default()), Err(LunifyError::InputTooLong));
+        Ok(())
+    }
+}
@@ -0,0 +1,207 @@
+use super::instruction::{lua50, lua51, Generic, PrototypeIndex, BC};
+use crate::LunifyError;
+
+/// The number of constants, nested functions and upvalues of a function that
+/// its instructions can reference.
+pub(super) struct Available {
+    pub(super) constants: usize,
+    pub(super) functions: usize,
+    pub(super) upvalues: u8,
+}
+
+/// Everything a single instruction references.
+#[derive(Default)]
+struct References {
+    constants: Vec<u64>,
+    function: Option<u64>,
+    upvalue: Option<u64>,
+}
+
+/// Check that every constant, nested function and upvalue referenced by the
+/// Lua 5.0 instructions exists.
+pub(super) fn validate_lua50_references(instructions: &[lua50::Instruction], available: &Available) -> Result<(), LunifyError> {
+    validate_references(instructions, available, |instruction| {
+        let mut references = References::default();
+        let mut operands = *instruction;
+        operands.for_each_constant_index(&mut |index| references.constants.push(*index));
+
+        match *instruction {
+            lua50::Instruction::Closure {
+                mode: PrototypeIndex(index), ..
+            } => references.function = Some(index),
+            lua50::Instruction::GetUpValue {
+                mode: BC(Generic(index), _), ..
+            }
+            | lua50::Instruction::SetUpValue {
+                mode: BC(Generic(index), _), ..
+            } => references.upvalue = Some(index),
+            _ => {}
+        }
+
+        references
+    })
+}
+
+/// Check that every constant, nested function and upvalue referenced by the
+/// Lua 5.1 instructions exists.
+pub(super) fn validate_lua51_references(instructions: &[lua51::Instruction], available: &Available) -> Result<(), LunifyError> {
+    validate_references(instructions, available, |instruction| {
+        let mut references = References::default();
+        let mut operands = *instruction;
+        operands.for_each_constant_index(&mut |index| references.constants.push(*index));
+
+        match *instruction {
+            lua51::Instruction::Closure {
+                mode: PrototypeIndex(index), ..
+            } => references.function = Some(index),
+            lua51::Instruction::GetUpValue {
+                mode: BC(Generic(index), _), ..
+            }
+            | lua51::Instruction::SetUpValue {
+                mode: BC(Generic(index), _), ..
+            } => references.upvalue = Some(index),
+            _ => {}
+        }
+
+        references
+    })
+}
+
+fn validate_references<T>(instructions: &[T], available: &Available, references: impl Fn(&T) -> References) -> Result<(), LunifyError> {
+    for (program_counter, instruction) in instructions.iter().enumerate() {
+        let references = references(instruction);
+
+        if let Some(&index) = references.constants.iter().find(|&&index| index as usize >= available.constants) {
+            return Err(LunifyError::InvalidConstantReference {
+                program_counter,
+                index,
+                available: available.constants,
+            });
+        }
+
+        if let Some(index) = references.function.filter(|&index| index as usize >= available.functions) {
+            return Err(LunifyError::InvalidFunctionReference {
+                program_counter,
+                index,
+                available: available.functions,
+            });
+        }
+
+        if let Some(index) = references.upvalue.filter(|&index| index >= available.upvalues as u64) {
+            return Err(LunifyError::InvalidUpvalueReference {
+                program_counter,
+                index,
+                available: available.upvalues as usize,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate_lua50_references, validate_lua51_references, Available};
+    use crate::function::instruction::{ConstantIndex, ConstantRegister, Generic, PrototypeIndex, Register, Unused, BC};
+    use crate::{lua50, lua51, LunifyError};
+
+    const AVAILABLE: Available = Available {
+        constants: 2,
+        functions: 1,
+        upvalues: 1,
+    };
+
+    fn validate_lua51(instruction: lua51::Instruction) -> Result<(), LunifyError> {
+        let instructions = [
+            lua51::Instruction::Move {
+                a: 0,
+                mode: BC(Register(1), Unused),
+            },
+            instruction,
+        ];
+        validate_lua51_references(&instructions, &AVAILABLE)
+    }
+
+    #[test]
+    fn valid_references() -> Result<(), LunifyError> {
+        validate_lua51(lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(1) })?;
+        validate_lua51(lua51::Instruction::Closure { a: 0, mode: PrototypeIndex(0) })?;
+        validate_lua51(lua51::Instruction::SetUpValue {
+            a: 0,
+            mode: BC(Generic(0), Unused),
+        })?;
+        validate_lua51(lua51::Instruction::Add {
+            a: 0,
+            mode: BC(ConstantRegister(1, true), ConstantRegister(5, false)),
+        })
+    }
+
+    #[test]
+    fn invalid_constant_index() {
+        let result = validate_lua51(lua51::Instruction::GetGlobal { a: 0, mode: ConstantIndex(2) });
+        assert_eq!(result, Err(LunifyError::InvalidConstantReference {
+            program_counter: 1,
+            index: 2,
+            available: 2,
+        }));
+    }
+
+    #[test]
+    fn invalid_constant_register() {
+        let result = validate_lua51(lua51::Instruction::LessThan {
+            a: 0,
+            mode: BC(ConstantRegister(3, false), ConstantRegister(3, true)),
+        });
+        assert_eq!(result, Err(LunifyError::InvalidConstantReference {
+            program_counter: 1,
+            index: 3,
+            available: 2,
+        }));
+    }
+
+    #[test]
+    fn invalid_function() {
+        let result = validate_lua51(lua51::Instruction::Closure { a: 0, mode: PrototypeIndex(1) });
+        assert_eq!(result, Err(LunifyError::InvalidFunctionReference {
+            program_counter: 1,
+            index: 1,
+            available: 1,
+        }));
+    }
+
+    #[test]
+    fn invalid_upvalue() {
+        let result = validate_lua51(lua51::Instruction::GetUpValue {
+            a: 0,
+            mode: BC(Generic(1), Unused),
+        });
+        assert_eq!(result, Err(LunifyError::InvalidUpvalueReference {
+            program_counter: 1,
+            index: 1,
+            available: 1,
+        }));
+    }
+
+    #[test]
+    fn invalid_lua50_references() {
+        let instructions = [lua50::Instruction::SetTable {
+            a: 0,
+            mode: BC(ConstantRegister(0, true), ConstantRegister(4, true)),
+        }];
+        assert_eq!(validate_lua50_references(&instructions, &AVAILABLE), Err(LunifyError::InvalidConstantReference {
+            program_counter: 0,
+            index: 4,
+            available: 2,
+        }));
+
+        let instructions = [lua50::Instruction::SetUpValue {
+            a: 0,
+            mode: BC(Generic(2), Unused),
+        }];
+        assert_eq!(validate_lua50_references(&instructions, &AVAILABLE), Err(LunifyError::InvalidUpvalueReference {
+            program_counter: 0,
+            index: 2,
+            available: 1,
+        }));
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+
+use super::block::lua51_leaders;
+use super::constant::{Constant, ConstantManager};
+use super::instruction::{lua51, ConstantIndex, ConstantRegister, Register, Settings, BC};
+use crate::diagnostic::report_diagnostic;
+use crate::DiagnosticCode;
+
+/// A global or field of a global table that is renamed, see
+/// [`rename_globals`](lua51::Settings::rename_globals).
+struct Rename<'a> {
+    table: Option<&'a [u8]>,
+    name: &'a [u8],
+    new_name: &'a str,
+}
+
+/// A constant operand holding a name that is renamed.
+struct Occurrence<'a> {
+    program_counter: usize,
+    constant_index: u64,
+    new_name: &'a str,
+}
+
+/// Apply the [`rename_globals`](lua51::Settings::rename_globals) of the output
+/// settings. Registers are only known to hold a global table until they are
+/// written to or the basic block ends. Constants that are only used by renamed
+/// operands are changed in place, otherwise the renamed operands are moved to
+/// a constant holding the new name. Returns whether anything was renamed.
+pub(super) fn rename_globals(
+    instructions: &mut [lua51::Instruction],
+    constants: &mut Vec<Constant>,
+    path: &[usize],
+    settings: &Settings,
+) -> bool {
+    if settings.output.rename_globals.is_empty() {
+        return false;
+    }
+
+    let renames: Vec<Rename> = settings
+        .output
+        .rename_globals
+        .iter()
+        .map(|(name, new_name)| match name.split_once('.') {
+            Some((table, name)) => Rename {
+                table: Some(table.as_bytes()),
+                name: name.as_bytes(),
+                new_name,
+            },
+            None => Rename {
+                table: None,
+                name: name.as_bytes(),
+                new_name,
+            },
+        })
+        .collect();
+
+    let occurrences = find_occurrences(instructions, constants, &renames);
+    if occurrences.is_empty() {
+        return false;
+    }
+
+    let mut use_counts = vec![0; constants.len()];
+    for instruction in instructions.iter_mut() {
+        instruction.for_each_constant_index(&mut |index| {
+            if let Some(use_count) = use_counts.get_mut(*index as usize) {
+                *use_count += 1;
+            }
+        });
+    }
+
+    // A constant that is also used as something else, like a string that happens
+    // to be the name of a library function, keeps its value for those uses.
+    let mut shared = Vec::new();
+    for occurrence in &occurrences {
+        let index = occurrence.constant_index;
+        let uses: Vec<&Occurrence> = occurrences.iter().filter(|other| other.constant_index == index).collect();
+        let is_exclusive = uses.len() == use_counts[index as usize] && uses.iter().all(|other| other.new_name == occurrence.new_name);
+
+        match is_exclusive {
+            true => constants[index as usize] = Constant::String(format!("{}\0", occurrence.new_name).into_bytes().into()),
+            false => shared.push(occurrence),
+        }
+    }
+
+    let maximum_constant_index = settings.output.get_maximum_constant_index();
+    let mut constant_manager = ConstantManager::new(constants);
+    let mut is_renamed = shared.len() < occurrences.len();
+
+    for occurrence in shared {
+        let constant_index = constant_manager.constant_for_str(occurrence.new_name);
+
+        match &mut instructions[occurrence.program_counter] {
+            lua51::Instruction::GetGlobal { mode, .. } => mode.0 = constant_index,
+            lua51::Instruction::GetTable { mode: BC(_, key), .. } if constant_index <= maximum_constant_index => key.0 = constant_index,
+            _ => {
+                report_diagnostic(settings, DiagnosticCode::GlobalNotRenamed, path, Some(occurrence.program_counter), || {
+                    format!("the constant {:?} doesn't fit into an RK operand", occurrence.new_name)
+                });
+                continue;
+            }
+        }
+
+        is_renamed = true;
+    }
+
+    is_renamed
+}
+
+/// Find the `GETGLOBAL` and `GETTABLE` instructions that read a renamed global
+/// or field.
+fn find_occurrences<'a>(instructions: &[lua51::Instruction], constants: &[Constant], renames: &[Rename<'a>]) -> Vec<Occurrence<'a>> {
+    let string = |index: u64| match constants.get(index as usize) {
+        Some(Constant::String(string)) => Some(string.strip_suffix(&[0]).unwrap_or(string)),
+        _ => None,
+    };
+    let find = |table: Option<&[u8]>, index: u64| {
+        let name = string(index)?;
+        renames.iter().find(|rename| rename.table == table && rename.name == name)
+    };
+
+    let leaders = lua51_leaders(instructions, &[]);
+    let mut occurrences = Vec::new();
+    // The global table held by a register.
+    let mut tables: HashMap<u64, &[u8]> = HashMap::new();
+
+    for (program_counter, instruction) in instructions.iter().enumerate() {
+        if leaders[program_counter] {
+            tables.clear();
+        }
+
+        let rename = match *instruction {
+            lua51::Instruction::GetGlobal { mode: ConstantIndex(index), .. } => find(None, index).map(|rename| (rename, index)),
+            lua51::Instruction::GetTable {
+                mode: BC(Register(table), ConstantRegister(index, true)),
+                ..
+            } => tables.get(&table).and_then(|&table| find(Some(table), index)).map(|rename| (rename, index)),
+            _ => None,
+        };
+
+        if let Some((rename, constant_index)) = rename {
+            occurrences.push(Occurrence {
+                program_counter,
+                constant_index,
+                new_name: rename.new_name,
+            });
+        }
+
+        // Calls and `VARARG` can write any number of registers.
+        match *instruction {
+            lua51::Instruction::Call { a, .. } | lua51::Instruction::VarArg { a, .. } => tables.retain(|register, _| *register < a),
+            _ => {
+                if let Some(destination) = instruction.stack_destination() {
+                    tables.retain(|register, _| !(destination.start..=destination.end).contains(register));
+                }
+            }
+        }
+
+        if let lua51::Instruction::GetGlobal { a, mode: ConstantIndex(index) } = *instruction {
+            if let Some(table) = string(index) {
+                tables.insert(a, table);
+            }
+        }
+    }
+
+    occurrences
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::rename_globals;
+    use crate::function::constant::Constant;
+    use crate::function::instruction::{ConstantIndex, ConstantRegister, Generic, Register, SignedBx, Unused, BC};
+    use crate::lua51::Instruction;
+    use crate::Settings;
+
+    fn string(string: &'static str) -> Constant<'static> {
+        Constant::String(Cow::Owned(format!("{string}\0").into_bytes()))
+    }
+
+    fn rename(instructions: &mut [Instruction], constants: &mut Vec<Constant>) -> bool {
+        let mut settings = Settings::default();
+        settings.output.rename_globals = &[("string.gfind", "gmatch"), ("gcinfo", "count")];
+        rename_globals(instructions, constants, &[], &settings)
+    }
+
+    fn get_field(a: u64, table: u64, constant_index: u64) -> Instruction {
+        Instruction::GetTable {
+            a,
+            mode: BC(Register(table), ConstantRegister(constant_index, true)),
+        }
+    }
+
+    #[test]
+    fn in_place() {
+        let mut instructions = [
+            Instruction::GetGlobal { a: 0, mode: ConstantIndex(0) },
+            get_field(0, 0, 1),
+            Instruction::GetGlobal { a: 1, mode: ConstantIndex(2) },
+        ];
+        let mut constants = vec![string("string"), string("gfind"), string("gcinfo")];
+
+        assert!(rename(&mut instructions, &mut constants));
+        assert_eq!(constants, [string("string"), string("gmatch"), string("count")]);
+    }
+
+    #[test]
+    fn shared_constant() {
+        // The name of the field is also stored in a global.
+        let mut instructions = [
+            Instruction::GetGlobal { a: 0, mode: ConstantIndex(0) },
+            get_field(0, 0, 1),
+            Instruction::LoadK { a: 1, mode: ConstantIndex(1) },
+        ];
+        let mut constants = vec![string("string"), string("gfind")];
+
+        assert!(rename(&mut instructions, &mut constants));
+        assert_eq!(constants, [string("string"), string("gfind"), string("gmatch")]);
+        assert_eq!(instructions[1], get_field(0, 0, 2));
+        assert_eq!(instructions[2], Instruction::LoadK { a: 1, mode: ConstantIndex(1) });
+    }
+
+    #[test]
+    fn unknown_table() {
+        // The register holding the global table is overwritten, and the field is
+        // read from another table after a jump destination.
+        let mut instructions = [
+            Instruction::GetGlobal { a: 0, mode: ConstantIndex(0) },
+            Instruction::LoadK { a: 0, mode: ConstantIndex(0) },
+            get_field(1, 0, 1),
+            Instruction::GetGlobal { a: 0, mode: ConstantIndex(0) },
+            Instruction::Jump { a: 0, mode: SignedBx(0) },
+            get_field(1, 0, 1),
+            Instruction::Return {
+                a: 0,
+                mode: BC(Generic(1), Unused),
+            },
+        ];
+        let mut constants = vec![string("string"), string("gfind")];
+
+        assert!(!rename(&mut instructions, &mut constants));
+        assert_eq!(constants, [string("string"), string("gfind")]);
+    }
+}
@@ -0,0 +1,138 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use super::Settings;
+
+/// Rewrite rule for the source file name stored in every function of the byte
+/// code. This is useful for removing build paths or normalizing path separators
+/// so that output from different machines can be compared.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum SourceRewrite<'a> {
+    /// Remove the given prefix if the source file name starts with it.
+    StripPrefix(&'a str),
+    /// Replace every occurrence of `from` with `to`.
+    ReplaceAll {
+        /// The pattern to replace.
+        from: &'a str,
+        /// The replacement.
+        to: &'a str,
+    },
+    /// Replace the source file name with a fixed string.
+    Fixed(&'a str),
+    /// Apply multiple rewrites in order.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Chain(&'a [SourceRewrite<'a>]),
+}
+
+impl SourceRewrite<'_> {
+    fn apply(&self, source_file: &str) -> String {
+        match *self {
+            SourceRewrite::StripPrefix(prefix) => source_file.strip_prefix(prefix).unwrap_or(source_file).to_owned(),
+            SourceRewrite::ReplaceAll { from: "", .. } => source_file.to_owned(),
+            SourceRewrite::ReplaceAll { from, to } => source_file.replace(from, to),
+            SourceRewrite::Fixed(source_file) => source_file.to_owned(),
+            SourceRewrite::Chain(rewrites) => rewrites
+                .iter()
+                .fold(source_file.to_owned(), |source_file, rewrite| rewrite.apply(&source_file)),
+        }
+    }
+
+    pub(crate) fn rewrite(&self, source_file: String) -> String {
+        // Lua 5.1 writes an empty string for functions that have the same source file as
+        // their parent, so we need to keep it empty.
+        if source_file.is_empty() {
+            return source_file;
+        }
+
+        // Strings in the byte code include the trailing null byte, so we remove it before
+        // applying the rewrite and add it back afterwards.
+        let (source_file, terminator) = match source_file.strip_suffix('\0') {
+            Some(source_file) => (source_file, "\0"),
+            None => (source_file.as_str(), ""),
+        };
+
+        self.apply(source_file) + terminator
+    }
+}
+
+/// Source file of the parent of a function.
+#[derive(Clone, Copy)]
+pub(crate) struct ParentSource<'s> {
+    /// The source file in the input, which nested functions with an empty
+    /// source file inherit.
+    pub(crate) input: &'s str,
+    /// The source file that is written for the parent, which the loader gives
+    /// to nested functions with an empty source file.
+    pub(crate) output: &'s str,
+}
+
+/// The source file that is written for a function, given its source file in
+/// the input.
+pub(crate) fn output_source_file(source_file: &str, is_main_function: bool, settings: &Settings) -> String {
+    match settings.output.root_source_override.filter(|_| is_main_function) {
+        Some(source_override) if source_override.ends_with('\0') => source_override.to_owned(),
+        Some(source_override) => format!("{source_override}\0"),
+        None => match settings.output.rewrite_source {
+            Some(rewrite) => rewrite.rewrite(source_file.to_owned()),
+            None => source_file.to_owned(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SourceRewrite;
+
+    #[test]
+    fn strip_prefix() {
+        let rewrite = SourceRewrite::StripPrefix("@C:\\build\\");
+        assert_eq!(rewrite.rewrite("@C:\\build\\foo.lua\0".to_owned()), "foo.lua\0");
+    }
+
+    #[test]
+    fn strip_prefix_missing() {
+        let rewrite = SourceRewrite::StripPrefix("@C:\\build\\");
+        assert_eq!(rewrite.rewrite("@foo.lua\0".to_owned()), "@foo.lua\0");
+    }
+
+    #[test]
+    fn replace_all() {
+        let rewrite = SourceRewrite::ReplaceAll { from: "\\", to: "/" };
+        assert_eq!(rewrite.rewrite("@C:\\build\\foo.lua\0".to_owned()), "@C:/build/foo.lua\0");
+    }
+
+    #[test]
+    fn replace_all_empty_pattern() {
+        let rewrite = SourceRewrite::ReplaceAll { from: "", to: "/" };
+        assert_eq!(rewrite.rewrite("@foo.lua\0".to_owned()), "@foo.lua\0");
+    }
+
+    #[test]
+    fn fixed() {
+        let rewrite = SourceRewrite::Fixed("=main");
+        assert_eq!(rewrite.rewrite("@foo.lua\0".to_owned()), "=main\0");
+    }
+
+    #[test]
+    fn chain() {
+        let rewrites = [
+            SourceRewrite::ReplaceAll { from: "\\", to: "/" },
+            SourceRewrite::StripPrefix("@C:/build/"),
+        ];
+        let rewrite = SourceRewrite::Chain(&rewrites);
+        assert_eq!(rewrite.rewrite("@C:\\build\\scripts\\foo.lua\0".to_owned()), "scripts/foo.lua\0");
+    }
+
+    #[test]
+    fn without_terminator() {
+        let rewrite = SourceRewrite::Fixed("=main");
+        assert_eq!(rewrite.rewrite("@foo.lua".to_owned()), "=main");
+    }
+
+    #[test]
+    fn empty_source() {
+        let rewrite = SourceRewrite::Fixed("=main");
+        assert_eq!(rewrite.rewrite(String::new()), "");
+    }
+}
@@ -0,0 +1,96 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::serialization::{ByteStream, ByteWriter};
+use crate::LunifyError;
+
+/// Encoding of additional data that some modified compilers append to every
+/// function after the upvalue names, for example a checksum of the source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum FunctionTrailerSpec {
+    /// The trailer always has the given number of bytes.
+    FixedLength(usize),
+    /// The trailer starts with its length in bytes. The length is stored as a
+    /// `size_t` if `size_t` is set, otherwise as an integer.
+    LengthPrefixed {
+        /// If the length is stored as a `size_t` rather than an integer.
+        size_t: bool,
+    },
+}
+
+impl FunctionTrailerSpec {
+    pub(crate) fn read<'a>(&self, byte_stream: &mut ByteStream<'a>) -> Result<&'a [u8], LunifyError> {
+        let length = match *self {
+            FunctionTrailerSpec::FixedLength(length) => length,
+            FunctionTrailerSpec::LengthPrefixed { size_t: true } => byte_stream.size_t()? as usize,
+            FunctionTrailerSpec::LengthPrefixed { size_t: false } => byte_stream.count()? as usize,
+        };
+
+        byte_stream.slice(length)
+    }
+
+    pub(crate) fn write(&self, byte_writer: &mut ByteWriter, trailer: &[u8]) -> Result<(), LunifyError> {
+        match *self {
+            FunctionTrailerSpec::FixedLength(length) if length != trailer.len() => return Err(LunifyError::InvalidFunctionTrailer),
+            FunctionTrailerSpec::FixedLength(_) => {}
+            FunctionTrailerSpec::LengthPrefixed { size_t: true } => byte_writer.size_t(trailer.len() as u64)?,
+            FunctionTrailerSpec::LengthPrefixed { size_t: false } => byte_writer.count(trailer.len())?,
+        }
+
+        byte_writer.slice(trailer);
+        Ok(())
+    }
+}
+
+/// What to do with the trailer of every function when writing the output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum FunctionTrailerMode<'a> {
+    /// Write the trailer that was read from the input.
+    #[default]
+    Keep,
+    /// Don't write any trailer.
+    Drop,
+    /// Write the given trailer for every function.
+    Replace(&'a [u8]),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FunctionTrailerSpec;
+    use crate::serialization::{ByteStream, ByteWriter};
+    use crate::{Format, LunifyError};
+
+    #[test]
+    fn fixed_length() -> Result<(), LunifyError> {
+        let mut byte_stream = ByteStream::new(&[1, 2, 3, 4, 5]);
+        assert_eq!(FunctionTrailerSpec::FixedLength(4).read(&mut byte_stream)?, &[1, 2, 3, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn length_prefixed() -> Result<(), LunifyError> {
+        let format = Format::default();
+        let spec = FunctionTrailerSpec::LengthPrefixed { size_t: true };
+
+        let mut byte_writer = ByteWriter::new(&format);
+        spec.write(&mut byte_writer, &[7, 8, 9])?;
+
+        let bytes = byte_writer.finalize();
+        let mut byte_stream = ByteStream::new(&bytes);
+        byte_stream.set_format(format);
+
+        assert_eq!(spec.read(&mut byte_stream)?, &[7, 8, 9]);
+        assert!(byte_stream.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn fixed_length_mismatch() {
+        let format = Format::default();
+        let mut byte_writer = ByteWriter::new(&format);
+        let result = FunctionTrailerSpec::FixedLength(4).write(&mut byte_writer, &[7, 8, 9]);
+        assert_eq!(result, Err(LunifyError::InvalidFunctionTrailer));
+    }
+}
@@ -1,23 +1,171 @@
+use super::block::lua50_leaders;
 use super::builder::FunctionBuilder;
 use super::constant::{Constant, ConstantManager};
-use super::instruction::{lua50, lua51, Bx, ConstantRegister, Generic, LuaInstruction, Register, Settings, SignedBx, Unused, BC};
-use crate::LunifyError;
+#[cfg(feature = "debug")]
+use super::instruction::LuaInstruction;
+use super::instruction::{lua50, lua51, Bx, ConstantIndex, ConstantRegister, Generic, Register, Settings, SignedBx, Unused, BC};
+use super::local::{implicit_arg_register, LocalVariable};
+use crate::diagnostic::report_diagnostic;
+use crate::{DiagnosticCode, InsertionReason, LunifyError};
+
+/// Information about the function that is up-cast, apart from its
+/// instructions.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct FunctionContext<'a> {
+    pub(crate) parameter_count: u8,
+    pub(crate) is_variadic: bool,
+    /// The number of upvalues of every nested function, which is the number of
+    /// `MOVE` and `GETUPVAL` instructions following the `CLOSURE` creating it.
+    pub(crate) upvalue_counts: &'a [u8],
+    /// The local variables from the debug information, which are empty if the
+    /// byte code was stripped.
+    pub(crate) local_variables: &'a [LocalVariable<'a>],
+    /// Indices of the nested functions that lead to the function, for
+    /// diagnostics.
+    pub(crate) path: &'a [usize],
+}
+
+/// The number of instructions that [`upcast`] inserts in front of a variadic
+/// function to create the `arg` table.
+pub(crate) fn variadic_prologue_length(function: &FunctionContext, settings: &Settings) -> usize {
+    match function.is_variadic && !settings.output.use_needsarg_flag {
+        true if settings.output.emit_vararg_count => 10,
+        true => 4,
+        false => 0,
+    }
+}
+
+/// Lua 5.0 `TFORLOOP` either skips the next instruction or jumps to its
+/// destination, so the next instruction has to be the `JMP` back to the start of
+/// the loop body. Anything else would make the up-cast loop skip or jump to
+/// arbitrary code, so we return
+/// [`MalformedTForLoop`](LunifyError::MalformedTForLoop) instead.
+fn validate_t_for_loops(instructions: &[lua50::Instruction], settings: &Settings) -> Result<(), LunifyError> {
+    for (program_counter, instruction) in instructions.iter().enumerate() {
+        if !matches!(instruction, lua50::Instruction::TForLoop { .. }) {
+            continue;
+        }
+
+        // A jump that closes upvalues is up-cast to two instructions, so only the
+        // `CLOSE` would be skipped.
+        let is_back_edge = match instructions.get(program_counter + 1) {
+            Some(lua50::Instruction::Jump { a, mode: SignedBx(offset) }) => {
+                *offset < 0 && (*a == 0 || settings.output.jump_closes_upvalues)
+            }
+            _ => false,
+        };
+
+        if !is_back_edge {
+            return Err(LunifyError::MalformedTForLoop { program_counter });
+        }
+    }
+
+    Ok(())
+}
+
+/// Lua 5.0 prepares a numeric for loop by subtracting the step from the initial
+/// value with `SUB A A A+2`, followed by a `JMP` to the `FORLOOP`. Lua 5.1
+/// `FORPREP` does exactly the same, so those pairs are merged into a single
+/// `FORPREP`. Returns A of the loop for every jump that is merged. If anything
+/// jumps to or skips the jump, it starts a basic block and is kept as is.
+fn for_preparations(instructions: &[lua50::Instruction], leaders: &[bool]) -> Vec<Option<u64>> {
+    let for_preparation = |program_counter: usize| {
+        let lua50::Instruction::Jump { a: 0, mode: SignedBx(offset) } = *instructions.get(program_counter)? else {
+            return None;
+        };
+
+        let lua50::Instruction::Subtract { a, mode: BC(b, c) } = *instructions.get(program_counter.checked_sub(1)?)? else {
+            return None;
+        };
+
+        let destination = usize::try_from(program_counter as i64 + 1 + offset).ok()?;
+        let is_loop = matches!(instructions.get(destination), Some(lua50::Instruction::ForLoop { a: loop_a, .. }) if *loop_a == a);
+        let is_step_subtracted = b == ConstantRegister(a, false) && c == ConstantRegister(a + 2, false);
+
+        (is_loop && is_step_subtracted && !leaders.get(program_counter).copied().unwrap_or(true)).then_some(a)
+    };
+
+    (0..instructions.len()).map(for_preparation).collect()
+}
 
 pub(crate) fn upcast(
     instructions: Vec<lua50::Instruction>,
     line_info: Vec<i64>,
-    constants: &mut Vec<Constant>,
+    constants: &mut Vec<Constant<'_>>,
     maximum_stack_size: &mut u8,
-    parameter_count: u8,
-    is_variadic: bool,
+    function: &FunctionContext,
     settings: &Settings,
 ) -> Result<(Vec<lua51::Instruction>, Vec<i64>), LunifyError> {
     let mut builder = FunctionBuilder::default();
-    let mut constant_manager = ConstantManager { constants };
+    let mut constant_manager = ConstantManager::new(constants);
+
+    // The comparison instructions store the expected result of the comparison in A,
+    // and `TEST` stores it in C. If the result doesn't match, the next instruction
+    // (always a `JMP`) is skipped. This is the same in Lua 5.0 and Lua 5.1, so we
+    // copy the operand as is unless the polarity is inverted.
+    let polarity = |value: u64| match settings.lua50.inverted_test_polarity {
+        true => (value == 0) as u64,
+        false => value,
+    };
+
+    // Lua 5.0 can address more constants in RK operands than Lua 5.1, so constants
+    // that don't fit are loaded into registers above the original stack, which are
+    // never live.
+    let scratch = *maximum_stack_size as u64;
+
+    validate_t_for_loops(&instructions, settings)?;
+
+    // Every upvalue of a new function is described by a `MOVE` or `GETUPVAL` after
+    // the `CLOSURE`, of which only B is used. The Lua 5.0 compiler sets A to zero,
+    // but the loader doesn't check it, while Lua 5.1 builds with API checks
+    // enabled reject anything else.
+    let mut closure_upvalues = 0;
+
+    let leaders = lua50_leaders(&instructions);
+    let for_preparations = for_preparations(&instructions, &leaders);
+
+    let instructions = instructions.into_iter().zip(line_info).zip(leaders).zip(for_preparations);
+
+    for (((instruction, line_number), is_leader), for_preparation) in instructions {
+        #[cfg(feature = "debug")]
+        println!("[{}] {}", builder.get_program_counter(), instruction.describe(constant_manager.constants()));
 
-    for (instruction, line_number) in instructions.into_iter().zip(line_info) {
         builder.set_line_number(line_number);
 
+        if is_leader {
+            builder.mark_leader();
+        }
+
+        if closure_upvalues > 0 {
+            closure_upvalues -= 1;
+
+            let upvalue_instruction = match instruction {
+                lua50::Instruction::Move { mode, .. } => Some(lua51::Instruction::Move { a: 0, mode }),
+                lua50::Instruction::GetUpValue { mode, .. } => Some(lua51::Instruction::GetUpValue { a: 0, mode }),
+                _ => None,
+            };
+
+            if let Some(upvalue_instruction) = upvalue_instruction {
+                builder.instruction(upvalue_instruction);
+                builder.last_instruction_closure_upvalue()?;
+                continue;
+            }
+
+            closure_upvalues = 0;
+        }
+
+        if let (Some(a), lua50::Instruction::Jump { mode, .. }) = (for_preparation, instruction) {
+            // The `FORPREP` takes the place of both the `SUB` and the jump, so jumps that
+            // land on the `SUB` prepare the loop as well.
+            builder.instruction(lua51::Instruction::ForPrep { a, mode });
+            builder.remove_instruction(builder.get_program_counter() - 2)?;
+            continue;
+        }
+
+        // Spilling constants needs free registers, so if there are none left the
+        // function simply has too many constants for Lua 5.1.
+        let constant_count = constant_manager.constants().len();
+
         match instruction {
             lua50::Instruction::Move { a, mode } => builder.instruction(lua51::Instruction::Move { a, mode }),
             lua50::Instruction::LoadK { a, mode } => builder.instruction(lua51::Instruction::LoadK { a, mode }),
@@ -25,68 +173,209 @@ pub(crate) fn upcast(
             lua50::Instruction::LoadNil { a, mode } => builder.instruction(lua51::Instruction::LoadNil { a, mode }),
             lua50::Instruction::GetUpValue { a, mode } => builder.instruction(lua51::Instruction::GetUpValue { a, mode }),
             lua50::Instruction::GetGlobal { a, mode } => builder.instruction(lua51::Instruction::GetGlobal { a, mode }),
-            lua50::Instruction::GetTable { a, mode } => builder.instruction(lua51::Instruction::GetTable { a, mode }),
+            lua50::Instruction::GetTable { a, mode } => {
+                builder.instruction_with_spills(lua51::Instruction::GetTable { a, mode }, scratch, constant_count, settings)?
+            }
             lua50::Instruction::SetGlobal { a, mode } => builder.instruction(lua51::Instruction::SetGlobal { a, mode }),
             lua50::Instruction::SetUpValue { a, mode } => builder.instruction(lua51::Instruction::SetUpValue { a, mode }),
-            lua50::Instruction::SetTable { a, mode } => builder.instruction(lua51::Instruction::SetTable { a, mode }),
-            lua50::Instruction::NewTable { a, mode } => builder.instruction(lua51::Instruction::NewTable { a, mode }),
-            lua50::Instruction::_Self { a, mode } => builder.instruction(lua51::Instruction::_Self { a, mode }),
-            lua50::Instruction::Add { a, mode } => builder.instruction(lua51::Instruction::Add { a, mode }),
-            lua50::Instruction::Subtract { a, mode } => builder.instruction(lua51::Instruction::Subtract { a, mode }),
-            lua50::Instruction::Multiply { a, mode } => builder.instruction(lua51::Instruction::Multiply { a, mode }),
-            lua50::Instruction::Divide { a, mode } => builder.instruction(lua51::Instruction::Divide { a, mode }),
-            lua50::Instruction::Power { a, mode } => builder.instruction(lua51::Instruction::Power { a, mode }),
+            lua50::Instruction::SetTable { a, mode } => {
+                builder.instruction_with_spills(lua51::Instruction::SetTable { a, mode }, scratch, constant_count, settings)?
+            }
+            lua50::Instruction::NewTable { a, .. } => {
+                // The size hints are encoded differently in Lua 5.0 and only affect how much
+                // memory is allocated up front, so we don't carry them over.
+                builder.instruction(lua51::Instruction::NewTable {
+                    a,
+                    mode: BC(Generic(0), Generic(0)),
+                });
+            }
+            lua50::Instruction::_Self { a, mode } => {
+                builder.instruction_with_spills(lua51::Instruction::_Self { a, mode }, scratch, constant_count, settings)?
+            }
+            lua50::Instruction::Add { a, mode } => {
+                builder.instruction_with_spills(lua51::Instruction::Add { a, mode }, scratch, constant_count, settings)?
+            }
+            lua50::Instruction::Subtract { a, mode } => {
+                builder.instruction_with_spills(lua51::Instruction::Subtract { a, mode }, scratch, constant_count, settings)?
+            }
+            lua50::Instruction::Multiply { a, mode } => {
+                builder.instruction_with_spills(lua51::Instruction::Multiply { a, mode }, scratch, constant_count, settings)?
+            }
+            lua50::Instruction::Divide { a, mode } => {
+                builder.instruction_with_spills(lua51::Instruction::Divide { a, mode }, scratch, constant_count, settings)?
+            }
+            lua50::Instruction::Power { a, mode: BC(b, c) } if settings.output.lower_power_to_call => {
+                // Lua 5.0 doesn't have a native power operation, `^` always calls the global
+                // `__pow` function. Lua 5.1 never consults the global, so code that replaces
+                // it would silently change its behavior. So we call the global function
+                // directly with the equivalent of `R(A) := __pow(RK(B), RK(C))`.
+                let power_constant = constant_manager.constant_for_str(settings.output.power_global);
+
+                let load_operand = |operand: ConstantRegister, register: u64| match operand.1 {
+                    true => lua51::Instruction::LoadK {
+                        a: register,
+                        mode: ConstantIndex(operand.0),
+                    },
+                    false => lua51::Instruction::Move {
+                        a: register,
+                        mode: BC(Register(operand.0), Unused),
+                    },
+                };
+
+                builder.instruction(lua51::Instruction::GetGlobal {
+                    a: scratch,
+                    mode: ConstantIndex(power_constant),
+                });
+                builder.last_instruction_reason(InsertionReason::PowerCall)?;
+                builder.extra_instruction(load_operand(b, scratch + 1), InsertionReason::PowerCall);
+                builder.extra_instruction(load_operand(c, scratch + 2), InsertionReason::PowerCall);
+                builder.extra_instruction(lua51::Instruction::Call {
+                    a: scratch,
+                    mode: BC(Generic(3), Generic(2)),
+                }, InsertionReason::PowerCall);
+                builder.extra_instruction(lua51::Instruction::Move {
+                    a,
+                    mode: BC(Register(scratch), Unused),
+                }, InsertionReason::PowerCall);
+            }
+            lua50::Instruction::Power { a, mode } => {
+                builder.instruction_with_spills(lua51::Instruction::Power { a, mode }, scratch, constant_count, settings)?
+            }
             lua50::Instruction::Unary { a, mode } => builder.instruction(lua51::Instruction::Unary { a, mode }),
             lua50::Instruction::Not { a, mode } => builder.instruction(lua51::Instruction::Not { a, mode }),
             lua50::Instruction::Concatinate { a, mode } => builder.instruction(lua51::Instruction::Concatinate { a, mode }),
+            lua50::Instruction::Jump { a, mode } if a > 0 && !settings.output.jump_closes_upvalues => {
+                // The `CLOSE` takes the place of the jump, so jumps that land on the jump
+                // close the upvalues as well.
+                builder.instruction(lua51::Instruction::Close {
+                    a: a - 1,
+                    mode: BC(Unused, Unused),
+                });
+                builder.last_instruction_reason(InsertionReason::JumpUpvalueClose)?;
+                builder.extra_instruction(lua51::Instruction::Jump { a: 0, mode }, InsertionReason::JumpUpvalueClose);
+            }
             lua50::Instruction::Jump { a, mode } => builder.instruction(lua51::Instruction::Jump { a, mode }),
-            lua50::Instruction::Equals { a, mode } => builder.instruction(lua51::Instruction::Equals { a, mode }),
-            lua50::Instruction::LessThan { a, mode } => builder.instruction(lua51::Instruction::LessThan { a, mode }),
-            lua50::Instruction::LessEquals { a, mode } => builder.instruction(lua51::Instruction::LessEquals { a, mode }),
+            lua50::Instruction::Equals { a, mode } => builder.instruction_with_spills(
+                lua51::Instruction::Equals { a: polarity(a), mode },
+                scratch,
+                constant_count,
+                settings,
+            )?,
+            lua50::Instruction::LessThan { a, mode } => builder.instruction_with_spills(
+                lua51::Instruction::LessThan { a: polarity(a), mode },
+                scratch,
+                constant_count,
+                settings,
+            )?,
+            lua50::Instruction::LessEquals { a, mode } => builder.instruction_with_spills(
+                lua51::Instruction::LessEquals { a: polarity(a), mode },
+                scratch,
+                constant_count,
+                settings,
+            )?,
+            // Lua 5.0 `TEST` copies R(B) to R(A) if the test succeeds, just like Lua 5.1
+            // `TESTSET`. If A and B are the same, the copy does nothing, which is what the
+            // Lua 5.0 compiler emits for conditions whose value isn't needed, so we use
+            // the cheaper `TEST` for those. The `MOVE` that might follow the `JMP` only
+            // runs if the test failed, so it is never redundant.
+            lua50::Instruction::Test { a, mode: BC(b, c) } if a == b.0 => builder.instruction(lua51::Instruction::Test {
+                a,
+                mode: BC(Register(0), Generic(polarity(c.0))),
+            }),
             lua50::Instruction::Test { a, mode: BC(b, c) } => builder.instruction(lua51::Instruction::TestSet {
                 a,
-                mode: BC(ConstantRegister(b.0, false), c),
+                mode: BC(ConstantRegister(b.0, false), Generic(polarity(c.0))),
             }),
             lua50::Instruction::Call { a, mode } => builder.instruction(lua51::Instruction::Call { a, mode }),
-            lua50::Instruction::TailCall { a, mode } => builder.instruction(lua51::Instruction::TailCall { a, mode }),
+            lua50::Instruction::TailCall { a, mode } => {
+                // Lua 5.1 expects the `RETURN` right after the `TAILCALL`, so nothing may be
+                // inserted in between.
+                builder.instruction(lua51::Instruction::TailCall { a, mode });
+                builder.glue_next();
+            }
             lua50::Instruction::Return { a, mode } => builder.instruction(lua51::Instruction::Return { a, mode }),
             lua50::Instruction::ForLoop { a, mode } => {
                 // Lua 5.1 additionally saves the loop index in RA+3, which Lua 5.0 does
-                // not. Therefore we save RA+3 to a global value and restore it afterwards.
+                // not. Therefore we save RA+3 to a global value and restore it afterwards,
+                // unless the loop body overwrites it anyway.
+                builder.instruction(lua51::Instruction::ForLoop { a, mode });
+                let loop_position = builder.get_program_counter() - 1;
+
+                // Get the *adjusted* position of the instruction we want to
+                // jump to. It is very important that we take the adjusted position because
+                // we might have added or remove instructions inside the for loop, which would
+                // make the old Bx invalid.
+                let destination = builder.adjusted_jump_destination(mode.0)?;
+                let mut position = destination;
+
+                // If the destination closes upvalues, those need to be closed before we restore
+                // RA+3, so we insert our instruction after any `CLOSE` instructions and keep
+                // jumping to the original destination.
+                let closes_upvalues = matches!(builder.get_instruction(position)?, lua51::Instruction::Close { .. });
+                while matches!(builder.get_instruction(position)?, lua51::Instruction::Close { .. }) {
+                    position += 1;
+                }
+
+                // The Lua 5.0 compiler puts the first local variable of the loop body into
+                // RA+3, so most loop bodies start by assigning it.
+                if !builder.is_register_live(position, a + 3) {
+                    continue;
+                }
 
                 // Create a new constant to hold an identifier to the global that saves the
                 // value in RA+3.
-                let global_constant = constant_manager.create_unique(builder.get_program_counter());
+                let global_constant = constant_manager.create_unique(loop_position);
 
-                // Instruction to save RA+3.
-                builder.instruction(lua51::Instruction::SetGlobal {
+                // Instruction to save RA+3. It takes the place of the `FORLOOP`, so the jump
+                // entering the loop saves RA+3 as well.
+                *builder.get_instruction(loop_position)? = lua51::Instruction::SetGlobal {
                     a: a + 3,
-                    mode: Bx(global_constant),
-                });
+                    mode: ConstantIndex(global_constant),
+                };
+                builder.last_instruction_reason(InsertionReason::ForLoopPreserve)?;
 
                 // Original instruction, but since we will insert another instruction before the
                 // destination of our jump, we also pass it an offset that will be applied after
                 // adjusting the jump position.
-                builder.extra_instruction(lua51::Instruction::ForLoop { a, mode });
-                builder.last_instruction_offset(-1);
+                builder.extra_instruction(lua51::Instruction::ForLoop { a, mode }, InsertionReason::ForLoopPreserve);
 
-                // Get the *adjusted* position of the instruction we want to
-                // jump to. It is very important that we take the adjusted position because
-                // we might have added or remove instructions inside the for loop, which would
-                // make the old Bx invalid.
-                let position = builder.adjusted_jump_destination(mode.0)?;
-
-                // Instruction to restore RA+3 if we take the jump.
-                // This instruction is actually inserted *before* the `SETGLOBAL` instruction,
-                // but this works because of the way that for loops are
-                // generated in Lua 5.0. There is an initial `JMP` instruction
-                // that moves the program counter to the `FORLOOP` instruction,
-                // meaning this `GetGlobal` will *always* be called after we
-                // already saved RA+3 with our `SETGLOBAL` instruction.
-                builder.insert_extra_instruction(position, lua51::Instruction::GetGlobal {
+                let restore = lua51::Instruction::GetGlobal {
                     a: a + 3,
-                    mode: Bx(global_constant),
-                });
+                    mode: ConstantIndex(global_constant),
+                };
+
+                // If the loop body is empty, the jump entering the loop lands right after
+                // itself, which is where the instruction restoring RA+3 would be, so it would
+                // run before RA+3 was saved. Instead, the `FORLOOP` jumps to itself and RA+3
+                // is restored once the loop is done.
+                if position == loop_position && !closes_upvalues {
+                    builder.last_instruction_offset(1)?;
+                    builder.extra_instruction(restore, InsertionReason::ForLoopPreserve);
+                    continue;
+                }
+
+                if !closes_upvalues {
+                    builder.last_instruction_offset(-1)?;
+                }
+
+                // Instruction to restore RA+3 if we take the jump. It comes before the
+                // `SETGLOBAL` in the byte code, which works for loops generated by the Lua 5.0
+                // compiler, since their initial `JMP`, up-cast to a `FORPREP`, moves the
+                // program counter to the `FORLOOP` and the `GETGLOBAL` is only reached after
+                // RA+3 was saved.
+                builder.insert_extra_instruction(position, restore, InsertionReason::ForLoopPreserve)?;
+
+                // If the loop body can be entered without a jump, for example because it
+                // starts at the beginning of the function, RA+3 is saved on the way in as
+                // well, so restoring it doesn't overwrite it with a value that was never
+                // saved. Jumps to the destination land after this instruction.
+                if builder.is_entered_sequentially(destination) {
+                    let save = lua51::Instruction::SetGlobal {
+                        a: a + 3,
+                        mode: ConstantIndex(global_constant),
+                    };
+                    builder.insert_extra_instruction(destination, save, InsertionReason::ForLoopPreserve)?;
+                }
             }
             lua50::Instruction::TForLoop { a, mode: BC(_, c) } => {
                 // The `TFORLOOP` instruction in Lua 5.0 can move multiple results to the stack
@@ -94,7 +383,8 @@ pub(crate) fn upcast(
                 // the subsequent moves are done by `MOVE` instructions.
 
                 // If the argument count is 1 (`argument count = c - 1`), we can just map
-                // directly to Lua 5.1 `TFORLOOP`.
+                // directly to Lua 5.1 `TFORLOOP`. Both read the destination from the `JMP`
+                // that follows, which is checked by `validate_t_for_loops`.
                 if c.0 == 0 {
                     builder.instruction(lua51::Instruction::TForLoop {
                         a,
@@ -114,20 +404,21 @@ pub(crate) fn upcast(
                         a: call_base,
                         mode: BC(Register(a), Unused),
                     });
+                    builder.last_instruction_reason(InsertionReason::TForLoopExpansion)?;
                     builder.extra_instruction(lua51::Instruction::Move {
                         a: call_base + 1,
                         mode: BC(Register(a + 1), Unused),
-                    });
+                    }, InsertionReason::TForLoopExpansion);
                     builder.extra_instruction(lua51::Instruction::Move {
                         a: call_base + 2,
                         mode: BC(Register(a + 2), Unused),
-                    });
+                    }, InsertionReason::TForLoopExpansion);
 
                     // Call to iterator function (e.g. `ipairs`).
                     builder.extra_instruction(lua51::Instruction::Call {
                         a: call_base,
                         mode: BC(Generic(3), Generic(variable_count + 1)),
-                    });
+                    }, InsertionReason::TForLoopExpansion);
 
                     // Move the results of our call back to our control variables. After the call,
                     // our results will be at the call base and upwards and our control variables
@@ -136,7 +427,7 @@ pub(crate) fn upcast(
                         builder.extra_instruction(lua51::Instruction::Move {
                             a: a + offset + 2,
                             mode: BC(Register(call_base + offset), Unused),
-                        });
+                        }, InsertionReason::TForLoopExpansion);
                     }
 
                     // Instead of using the the constant nil in the `EQ` instruction directly, we
@@ -144,104 +435,131 @@ pub(crate) fn upcast(
                     // the maximum constant index for the B and C registers.
                     builder.extra_instruction(lua51::Instruction::LoadK {
                         a: call_base,
-                        mode: Bx(constant_nil),
-                    });
+                        mode: ConstantIndex(constant_nil),
+                    }, InsertionReason::TForLoopExpansion);
 
                     // The control variable for the key/index is located at A+2, so as soon as it
                     // is nil, we are done with the iteration. If it is not nil we jump back and
                     // iterate again. It's not obvious from the code here but following this
                     // instruction will always be a `JMP` instruction that specifies the destination
-                    // of the jump. That `JMP` instruction doesn't need any modification here.
+                    // of the jump, since `validate_t_for_loops` checks it. That `JMP` instruction
+                    // doesn't need any modification here.
                     builder.extra_instruction(lua51::Instruction::Equals {
                         a: 0,
                         mode: BC(ConstantRegister(a + 2, false), ConstantRegister(call_base, false)),
-                    });
+                    }, InsertionReason::TForLoopExpansion);
                 }
             }
+            lua50::Instruction::TForPrep { a, mode } if settings.output.tforprep_assume_table => {
+                let next_global_constant = constant_manager.constant_for_str(settings.output.tforprep_next_global);
+
+                // Without the type check RA+1 and RA+2 are never used as temporaries, so we
+                // can do exactly what `TForPrep` does for a table.
+                builder.instruction(lua51::Instruction::Move {
+                    a: a + 1,
+                    mode: BC(Register(a), Unused),
+                });
+                builder.last_instruction_reason(InsertionReason::TForLoopExpansion)?;
+                builder.extra_instruction(lua51::Instruction::GetGlobal {
+                    a,
+                    mode: ConstantIndex(next_global_constant),
+                }, InsertionReason::TForLoopExpansion);
+                builder.extra_instruction(lua51::Instruction::Jump { a: 0, mode }, InsertionReason::TForLoopExpansion);
+            }
             lua50::Instruction::TForPrep { a, mode } => {
                 // Globals for saving RA+1 and RA+2.
                 let ra1_constant = constant_manager.create_unique(builder.get_program_counter());
                 let ra2_constant = constant_manager.create_unique(builder.get_program_counter() + 1);
 
-                let type_global_constant = constant_manager.constant_for_str("type");
+                let type_global_constant = constant_manager.constant_for_str(settings.output.tforprep_type_global);
                 let table_global_constant = constant_manager.constant_for_str("table");
-                let next_global_constant = constant_manager.constant_for_str("next");
+                let next_global_constant = constant_manager.constant_for_str(settings.output.tforprep_next_global);
 
                 // Instructions to save RA+1 and RA+2.
                 builder.instruction(lua51::Instruction::SetGlobal {
                     a: a + 1,
-                    mode: Bx(ra1_constant),
+                    mode: ConstantIndex(ra1_constant),
                 });
+                builder.last_instruction_reason(InsertionReason::TForLoopExpansion)?;
                 builder.extra_instruction(lua51::Instruction::SetGlobal {
                     a: a + 2,
-                    mode: Bx(ra2_constant),
-                });
+                    mode: ConstantIndex(ra2_constant),
+                }, InsertionReason::TForLoopExpansion);
 
                 // Prepare arguments and call the "type" function on the value in RA.
                 builder.extra_instruction(lua51::Instruction::GetGlobal {
                     a: a + 1,
-                    mode: Bx(type_global_constant),
-                });
+                    mode: ConstantIndex(type_global_constant),
+                }, InsertionReason::TForLoopExpansion);
                 builder.extra_instruction(lua51::Instruction::Move {
                     a: a + 2,
                     mode: BC(Register(a), Unused),
-                });
+                }, InsertionReason::TForLoopExpansion);
                 builder.extra_instruction(lua51::Instruction::Call {
                     a: a + 1,
                     mode: BC(Generic(2), Generic(2)),
-                });
+                }, InsertionReason::TForLoopExpansion);
 
                 // Load the string "table" to compare the result of the previous type to.
                 builder.extra_instruction(lua51::Instruction::LoadK {
                     a: a + 2,
-                    mode: Bx(table_global_constant),
-                });
+                    mode: ConstantIndex(table_global_constant),
+                }, InsertionReason::TForLoopExpansion);
 
                 // If it's not a table we want to restore RA+1 and RA+2, so we jump to that
                 // instruction.
                 builder.extra_instruction(lua51::Instruction::Equals {
                     a: 0,
                     mode: BC(ConstantRegister(a + 1, false), ConstantRegister(a + 2, false)),
-                });
+                }, InsertionReason::TForLoopExpansion);
                 // Because of the way the builder works, the jump destination in Bx would be
                 // moved when re-emitting the instructions. Therefore we fix the jump
                 // destination so we land on the correct instruction.
-                builder.extra_instruction(lua51::Instruction::Jump { a, mode: SignedBx(2) });
-                builder.last_instruction_fixed();
+                builder.extra_instruction(lua51::Instruction::Jump { a: 0, mode: SignedBx(2) }, InsertionReason::TForLoopExpansion);
+                builder.last_instruction_fixed()?;
 
                 // Move RA to RA+1 and put the global "next" into RA, exactly like `TForPrep`
                 // does. Since we restore RA+1 from `ra1_constant` afterwards, we don't move the
                 // value to the stack directly but rather to `ra1_constant`.
-                builder.extra_instruction(lua51::Instruction::SetGlobal { a, mode: Bx(ra1_constant) });
+                builder.extra_instruction(lua51::Instruction::SetGlobal {
+                    a,
+                    mode: ConstantIndex(ra1_constant),
+                }, InsertionReason::TForLoopExpansion);
                 builder.extra_instruction(lua51::Instruction::GetGlobal {
                     a,
-                    mode: Bx(next_global_constant),
-                });
+                    mode: ConstantIndex(next_global_constant),
+                }, InsertionReason::TForLoopExpansion);
 
                 // Restore RA+1 and RA+2.
                 builder.extra_instruction(lua51::Instruction::GetGlobal {
                     a: a + 1,
-                    mode: Bx(ra1_constant),
-                });
+                    mode: ConstantIndex(ra1_constant),
+                }, InsertionReason::TForLoopExpansion);
                 builder.extra_instruction(lua51::Instruction::GetGlobal {
                     a: a + 2,
-                    mode: Bx(ra2_constant),
-                });
+                    mode: ConstantIndex(ra2_constant),
+                }, InsertionReason::TForLoopExpansion);
 
                 // Technically this jump could be removed if it lands on the very next
                 // instruction, which will happen it the next instruction is a
                 // `TForLoop`. But I think it's better to keep this here for
                 // simplicity.
-                builder.extra_instruction(lua51::Instruction::Jump { a, mode });
+                builder.extra_instruction(lua51::Instruction::Jump { a: 0, mode }, InsertionReason::TForLoopExpansion);
             }
             lua50::Instruction::SetList { a, mode: Bx(bx) } | lua50::Instruction::SetListO { a, mode: Bx(bx) } => {
                 let flat_index = bx + 1;
-                let page = flat_index / settings.output.fields_per_flush;
-                let offset = flat_index % settings.output.fields_per_flush;
+                let is_open = matches!(instruction, lua50::Instruction::SetListO { .. });
+
+                // If the last page is full, the offset would be zero, which has the behavior of
+                // `SETLISTO` in Lua 5.1. So we flush a full page instead.
+                let (page, offset) = match flat_index % settings.output.fields_per_flush {
+                    0 if !is_open => (flat_index / settings.output.fields_per_flush - 1, settings.output.fields_per_flush),
+                    offset => (flat_index / settings.output.fields_per_flush, offset),
+                };
 
                 // In Lua 5.1 `SETLISTO` and `SETLIST` became a single instruction. The behavior
                 // of `SETLISTO` is used when b is equal to zero.
-                let b = match matches!(instruction, lua50::Instruction::SetListO { .. }) {
+                let b = match is_open {
                     true => 0,
                     false => offset,
                 };
@@ -257,10 +575,24 @@ pub(crate) fn upcast(
                     continue;
                 }
 
+                let mut is_repacked = false;
+
                 // Go back until we find some instruction that moves data to a stack position
                 // that is the same as our A, because that is where the setup starts.
-                for instruction_index in (0..(builder.get_program_counter() - 1)).rev() {
-                    let instruction = builder.get_instruction(instruction_index);
+                for (depth, instruction_index) in (0..builder.get_program_counter().saturating_sub(1)).rev().enumerate() {
+                    // Crafted byte code could make us scan far back for every `SETLIST`. Giving up
+                    // silently would leave the table constructor half rewritten, so this is an error.
+                    if depth as u64 >= settings.limits.max_setlist_scan_depth {
+                        return Err(LunifyError::LimitExceeded("max_setlist_scan_depth"));
+                    }
+
+                    // Upvalue pseudo-instructions don't write to the stack, even though A is
+                    // zero.
+                    if builder.is_marked_closure_upvalue(instruction_index) {
+                        continue;
+                    }
+
+                    let instruction = builder.get_instruction(instruction_index)?;
 
                     // It might technically be possible for the element on slot A to be on the stack
                     // already before any instructions if it is a parameter to a function call. So
@@ -275,20 +607,30 @@ pub(crate) fn upcast(
                             let mut page = c.0;
 
                             // Remove the `SETLIST` instruction.
-                            builder.remove_instruction(instruction_index);
+                            builder.remove_instruction(instruction_index)?;
+
+                            // Inserting a `SETLIST` in the middle of the elements is only safe if they
+                            // are always executed in order, otherwise we move all of them up as a whole.
+                            if !builder.is_basic_block(instruction_index) {
+                                let flat_index = (!is_open).then_some(flat_index);
+                                builder.repack_set_list(instruction_index, a, b.0, c.0, flat_index, settings)?;
+                                is_repacked = true;
+                                break;
+                            }
 
                             // Go back up the stack and update the stack positions.
                             let mut instruction_index = instruction_index;
                             while instruction_index < builder.get_program_counter() {
-                                let instruction = builder.get_instruction(instruction_index);
+                                let is_closure_upvalue = builder.is_marked_closure_upvalue(instruction_index);
+                                let instruction = builder.get_instruction(instruction_index)?;
 
-                                if let Some(stack_destination) = instruction.stack_destination() {
+                                if let Some(stack_destination) = instruction.stack_destination().filter(|_| !is_closure_upvalue) {
                                     if offset + stack_destination.start as i64 - 1 == (a + settings.output.fields_per_flush) as i64 {
                                         // Add a new `SETLIST` instruction.
                                         builder.insert_extra_instruction(instruction_index, lua51::Instruction::SetList {
                                             a,
                                             mode: BC(Generic(settings.output.fields_per_flush), Generic(page)),
-                                        });
+                                        }, InsertionReason::SetListRewrite)?;
 
                                         offset -= settings.output.fields_per_flush as i64;
                                         page += 1;
@@ -297,7 +639,7 @@ pub(crate) fn upcast(
                                     }
                                 }
 
-                                builder.get_instruction(instruction_index).move_stack_accesses(a, offset);
+                                builder.move_stack_accesses(instruction_index, a, offset)?;
                                 instruction_index += 1;
                             }
                         }
@@ -306,14 +648,19 @@ pub(crate) fn upcast(
                     }
                 }
 
-                // Append the original instruction.
-                builder.instruction(lua51::Instruction::SetList {
-                    a,
-                    mode: BC(Generic(b), Generic(page + 1)),
-                });
+                // Append the original instruction, unless the elements were repacked.
+                if !is_repacked {
+                    builder.instruction(lua51::Instruction::SetList {
+                        a,
+                        mode: BC(Generic(b), Generic(page + 1)),
+                    });
+                }
             }
             lua50::Instruction::Close { a, mode } => builder.instruction(lua51::Instruction::Close { a, mode }),
-            lua50::Instruction::Closure { a, mode } => builder.instruction(lua51::Instruction::Closure { a, mode }),
+            lua50::Instruction::Closure { a, mode } => {
+                builder.instruction(lua51::Instruction::Closure { a, mode });
+                closure_upvalues = function.upvalue_counts.get(mode.0 as usize).copied().unwrap_or(0);
+            }
         };
     }
 
@@ -325,45 +672,133 @@ pub(crate) fn upcast(
     // arguments. Lua 5.1 has a flag called `VARARG_NEEDSARG` that can be set on the
     // function header to achieve the same result, but it is behind a
     // compatibility feature flag. Even though that feature should be turned on
-    // most of the time, I chose this approach because it will always work.
-    if is_variadic {
-        let arg_stack_position = parameter_count as u64;
-
-        // Create a new empty table to hold our arguments.
-        builder.insert_extra_instruction(0, lua51::Instruction::NewTable {
-            a: arg_stack_position + 1,
-            mode: BC(Unused, Unused),
-        });
-
-        // Push all variadic arguments onto the stack.
-        builder.insert_extra_instruction(1, lua51::Instruction::VarArg {
-            a: arg_stack_position + 2,
-            mode: BC(Generic(0), Unused),
-        });
+    // most of the time, we only rely on it if `use_needsarg_flag` is set, because
+    // this approach will always work.
+    if function.is_variadic && !settings.output.use_needsarg_flag {
+        let arg_stack_position = implicit_arg_register(function.local_variables, function.parameter_count);
+        let table_stack_position = arg_stack_position + 1;
+
+        let mut prologue = vec![
+            // Create a new empty table to hold our arguments.
+            lua51::Instruction::NewTable {
+                a: table_stack_position,
+                mode: BC(Generic(0), Generic(0)),
+            },
+            // Push all variadic arguments onto the stack.
+            lua51::Instruction::VarArg {
+                a: table_stack_position + 1,
+                mode: BC(Generic(0), Unused),
+            },
+            // Add all values from the stack to the table.
+            lua51::Instruction::SetList {
+                a: table_stack_position,
+                mode: BC(Generic(0), Generic(1)),
+            },
+        ];
 
-        // Add all values from the stack to the table.
-        builder.insert_extra_instruction(2, lua51::Instruction::SetList {
-            a: arg_stack_position + 1,
-            mode: BC(Generic(0), Generic(1)),
-        });
+        // The `arg` table in Lua 5.0 also holds the number of arguments in the field
+        // `n`, so we set it with the equivalent of `arg.n = select('#', ...)`. The
+        // key and the value are kept in registers, so we don't need to worry about
+        // the constant index being too large for an RK operand.
+        if settings.output.emit_vararg_count {
+            let select_constant = constant_manager.constant_for_str("select");
+            let count_constant = constant_manager.constant_for_str("#");
+            let key_constant = constant_manager.constant_for_str("n");
+
+            prologue.extend([
+                lua51::Instruction::GetGlobal {
+                    a: table_stack_position + 1,
+                    mode: ConstantIndex(select_constant),
+                },
+                lua51::Instruction::LoadK {
+                    a: table_stack_position + 2,
+                    mode: ConstantIndex(count_constant),
+                },
+                lua51::Instruction::VarArg {
+                    a: table_stack_position + 3,
+                    mode: BC(Generic(0), Unused),
+                },
+                lua51::Instruction::Call {
+                    a: table_stack_position + 1,
+                    mode: BC(Generic(0), Generic(2)),
+                },
+                lua51::Instruction::LoadK {
+                    a: table_stack_position + 2,
+                    mode: ConstantIndex(key_constant),
+                },
+                lua51::Instruction::SetTable {
+                    a: table_stack_position,
+                    mode: BC(
+                        ConstantRegister(table_stack_position + 2, false),
+                        ConstantRegister(table_stack_position + 1, false),
+                    ),
+                },
+            ]);
+        }
 
         // Move the table to the location of the argument.
-        builder.insert_extra_instruction(3, lua51::Instruction::Move {
+        prologue.push(lua51::Instruction::Move {
             a: arg_stack_position,
-            mode: BC(Register(arg_stack_position + 1), Unused),
+            mode: BC(Register(table_stack_position), Unused),
+        });
+
+        // The prologue is inserted in front of everything else, so it moves all
+        // instructions by the same amount and jumps (even the ones to the first
+        // instruction) keep their relative destinations. Inserting it before
+        // converting would expose it to the backwards scan of `SETLIST`. Execution
+        // falls through from the prologue into the first instruction, which is
+        // why loops starting there save RA+3 on entry.
+        for (index, instruction) in prologue.into_iter().enumerate() {
+            builder.insert_extra_instruction(index, instruction, InsertionReason::VariadicPrologue)?;
+        }
+    }
+
+    // The Lua 5.0 compiler ends every function with a `RETURN`, but byte code
+    // assembled by hand might not. The appended `RETURN` counts as an instruction
+    // of the input, so jumps to the end of the function land on it.
+    if !matches!(builder.last_instruction(), Some(lua51::Instruction::Return { .. })) {
+        match settings.output.ensure_final_return {
+            true => {
+                builder.instruction(lua51::Instruction::Return {
+                    a: 0,
+                    mode: BC(Generic(1), Unused),
+                });
+                builder.last_instruction_reason(InsertionReason::FinalReturn)?;
+            }
+            false => report_diagnostic(settings, DiagnosticCode::MissingFinalReturn, function.path, None, || {
+                "function doesn't end with a RETURN".to_owned()
+            }),
+        }
+    }
+
+    // The stack size is raised to fit every instruction when finalizing, but if the
+    // input instructions don't fit the declared stack size, the byte code was most
+    // likely modified by hand.
+    let input_stack_size = builder.input_stack_size();
+    if input_stack_size > scratch {
+        report_diagnostic(settings, DiagnosticCode::DeclaredStackTooSmall, function.path, None, || {
+            format!("declared stack size is {scratch} but the instructions need {input_stack_size}")
         });
     }
 
-    builder.finalize(maximum_stack_size, settings)
+    // Every instruction gets a new opcode when up-casting, so the padding of the
+    // input is never kept.
+    let (instructions, line_info, _) = builder.finalize(maximum_stack_size, settings)?;
+    Ok((instructions, line_info))
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{lua50, lua51, Bx, BC};
+    use std::borrow::Cow;
+    use std::cell::RefCell;
+
+    use super::{lua50, lua51, variadic_prologue_length, Bx, ConstantIndex, FunctionContext, BC};
     use crate::function::constant::Constant;
-    use crate::function::instruction::{ConstantRegister, Generic, Register, SignedBx, Unused};
+    use crate::function::instruction::{ConstantRegister, Generic, PrototypeIndex, Register, SignedBx, Unused};
+    use crate::function::local::LocalVariable;
     use crate::function::upcast;
-    use crate::{LunifyError, Settings};
+    use crate::number::Number;
+    use crate::{Diagnostic, DiagnosticCallback, DiagnosticCode, FunctionPath, InsertionReason, LunifyError, Settings};
 
     fn test_settings() -> Settings<'static> {
         let lua50 = lua50::Settings {
@@ -373,18 +808,25 @@ mod tests {
 
         let lua51 = lua51::Settings::default();
 
+        // Most tests convert fragments of a function, which don't end with a `RETURN`.
         let output = lua51::Settings {
             fields_per_flush: 8,
+            ensure_final_return: false,
             ..lua51::Settings::default()
         };
 
-        Settings { lua50, lua51, output }
+        Settings {
+            lua50,
+            lua51,
+            output,
+            ..Default::default()
+        }
     }
 
     fn lua50_setlist(size: u64, settings: Settings) -> Vec<lua50::Instruction> {
         let mut instructions = vec![lua50::Instruction::NewTable {
             a: 0,
-            mode: BC(Unused, Unused),
+            mode: BC(Generic(0), Generic(0)),
         }];
 
         for index in 0..size {
@@ -392,7 +834,7 @@ mod tests {
 
             instructions.push(lua50::Instruction::LoadK {
                 a: stack_position,
-                mode: Bx(0),
+                mode: ConstantIndex(0),
             });
 
             if stack_position == settings.lua50.fields_per_flush || index + 1 == size {
@@ -406,7 +848,7 @@ mod tests {
     fn output_setlist(size: u64, settings: Settings) -> Vec<lua51::Instruction> {
         let mut instructions = vec![lua51::Instruction::NewTable {
             a: 0,
-            mode: BC(Unused, Unused),
+            mode: BC(Generic(0), Generic(0)),
         }];
 
         for index in 0..size {
@@ -415,7 +857,7 @@ mod tests {
 
             instructions.push(lua51::Instruction::LoadK {
                 a: stack_position,
-                mode: Bx(0),
+                mode: ConstantIndex(0),
             });
 
             if stack_position == settings.output.fields_per_flush || index + 1 == size {
@@ -434,15 +876,7 @@ mod tests {
         let instructions = lua50_setlist(count, settings);
         let instruction_count = instructions.len();
 
-        let (instructions, _) = upcast(
-            instructions,
-            vec![0; instruction_count],
-            &mut Vec::new(),
-            &mut 2,
-            0,
-            false,
-            &settings,
-        )?;
+        let (instructions, _) = upcast(instructions, vec![0; instruction_count], &mut Vec::new(), &mut 2, &Default::default(), &settings)?;
 
         let expected = output_setlist(count, settings);
 
@@ -458,10 +892,10 @@ mod tests {
             mode: BC(Register(0), Generic(0)),
         }];
 
-        let (instructions, _) = upcast(instructions, vec![0; 1], &mut Vec::new(), &mut 2, 0, false, &settings)?;
-        let expected = vec![lua51::Instruction::TestSet {
+        let (instructions, _) = upcast(instructions, vec![0; 1], &mut Vec::new(), &mut 2, &Default::default(), &settings)?;
+        let expected = vec![lua51::Instruction::Test {
             a: 0,
-            mode: BC(ConstantRegister(0, false), Generic(0)),
+            mode: BC(Register(0), Generic(0)),
         }];
 
         assert_eq!(instructions, expected);
@@ -469,15 +903,43 @@ mod tests {
     }
 
     #[test]
-    fn upcast_for_loop() -> Result<(), LunifyError> {
+    fn upcast_closure_upvalues() -> Result<(), LunifyError> {
         let settings = test_settings();
-        let instructions = vec![lua50::Instruction::ForLoop { a: 0, mode: SignedBx(-1) }];
+        let function = FunctionContext {
+            upvalue_counts: &[2],
+            ..Default::default()
+        };
+        let instructions = vec![
+            lua50::Instruction::Closure { a: 2, mode: PrototypeIndex(0) },
+            lua50::Instruction::Move {
+                a: 2,
+                mode: BC(Register(0), Unused),
+            },
+            lua50::Instruction::GetUpValue {
+                a: 2,
+                mode: BC(Generic(1), Unused),
+            },
+            lua50::Instruction::Move {
+                a: 3,
+                mode: BC(Register(2), Unused),
+            },
+        ];
 
-        let (instructions, _) = upcast(instructions, vec![0; 1], &mut Vec::new(), &mut 2, 0, false, &settings)?;
+        let (instructions, _) = upcast(instructions, vec![0; 4], &mut Vec::new(), &mut 4, &function, &settings)?;
         let expected = vec![
-            lua51::Instruction::GetGlobal { a: 3, mode: Bx(0) },
-            lua51::Instruction::SetGlobal { a: 3, mode: Bx(0) },
-            lua51::Instruction::ForLoop { a: 0, mode: SignedBx(-3) },
+            lua51::Instruction::Closure { a: 2, mode: PrototypeIndex(0) },
+            lua51::Instruction::Move {
+                a: 0,
+                mode: BC(Register(0), Unused),
+            },
+            lua51::Instruction::GetUpValue {
+                a: 0,
+                mode: BC(Generic(1), Unused),
+            },
+            lua51::Instruction::Move {
+                a: 3,
+                mode: BC(Register(2), Unused),
+            },
         ];
 
         assert_eq!(instructions, expected);
@@ -485,105 +947,635 @@ mod tests {
     }
 
     #[test]
-    fn upcast_t_for_loop() -> Result<(), LunifyError> {
+    fn upcast_closure_upvalues_in_set_list() -> Result<(), LunifyError> {
         let settings = test_settings();
-        let instructions = vec![lua50::Instruction::TForLoop {
-            a: 0,
-            mode: BC(Unused, Generic(0)),
-        }];
+        let function = FunctionContext {
+            upvalue_counts: &[1],
+            ..Default::default()
+        };
 
-        let (instructions, _) = upcast(instructions, vec![0; 1], &mut Vec::new(), &mut 2, 0, false, &settings)?;
-        let expected = vec![lua51::Instruction::TForLoop {
-            a: 0,
-            mode: BC(Unused, Generic(1)),
-        }];
+        // A table constructor with a closure capturing an upvalue as the sixth
+        // element, so the closure is moved up when the flushes are merged.
+        let mut instructions = lua50_setlist(5, settings);
+        instructions.extend([
+            lua50::Instruction::Closure { a: 1, mode: PrototypeIndex(0) },
+            lua50::Instruction::GetUpValue {
+                a: 1,
+                mode: BC(Generic(0), Unused),
+            },
+            lua50::Instruction::LoadK {
+                a: 2,
+                mode: ConstantIndex(0),
+            },
+            lua50::Instruction::SetList { a: 0, mode: Bx(6) },
+        ]);
+        let instruction_count = instructions.len();
+
+        let (instructions, _) = upcast(instructions, vec![0; instruction_count], &mut Vec::new(), &mut 8, &function, &settings)?;
+
+        let mut expected = output_setlist(5, settings);
+        expected.pop();
+        expected.extend([
+            lua51::Instruction::Closure { a: 6, mode: PrototypeIndex(0) },
+            lua51::Instruction::GetUpValue {
+                a: 0,
+                mode: BC(Generic(0), Unused),
+            },
+            lua51::Instruction::LoadK {
+                a: 7,
+                mode: ConstantIndex(0),
+            },
+            lua51::Instruction::SetList {
+                a: 0,
+                mode: BC(Generic(7), Generic(1)),
+            },
+        ]);
 
         assert_eq!(instructions, expected);
         Ok(())
     }
 
     #[test]
-    fn upcast_t_for_loop_c_bigger_zero() -> Result<(), LunifyError> {
+    fn upcast_test_set() -> Result<(), LunifyError> {
         let settings = test_settings();
-        let instructions = vec![lua50::Instruction::TForLoop {
-            a: 0,
-            mode: BC(Unused, Generic(1)),
-        }];
-        let mut constants = Vec::new();
-
-        let (instructions, _) = upcast(instructions, vec![0; 1], &mut constants, &mut 2, 0, false, &settings)?;
-        let expected = vec![
-            lua51::Instruction::Move {
-                a: 4,
-                mode: BC(Register(0), Unused),
+        let instructions = vec![
+            lua50::Instruction::Test {
+                a: 0,
+                mode: BC(Register(1), Generic(1)),
             },
-            lua51::Instruction::Move {
-                a: 5,
+            lua50::Instruction::Jump { a: 0, mode: SignedBx(1) },
+            lua50::Instruction::Move {
+                a: 0,
                 mode: BC(Register(1), Unused),
             },
-            lua51::Instruction::Move {
-                a: 6,
-                mode: BC(Register(2), Unused),
-            },
-            lua51::Instruction::Call {
-                a: 4,
-                mode: BC(Generic(3), Generic(3)),
+            lua50::Instruction::Return {
+                a: 0,
+                mode: BC(Generic(1), Unused),
             },
-            lua51::Instruction::Move {
-                a: 3,
-                mode: BC(Register(5), Unused),
+        ];
+
+        let (instructions, _) = upcast(instructions, vec![0; 4], &mut Vec::new(), &mut 2, &Default::default(), &settings)?;
+        let expected = vec![
+            lua51::Instruction::TestSet {
+                a: 0,
+                mode: BC(ConstantRegister(1, false), Generic(1)),
             },
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(1) },
             lua51::Instruction::Move {
-                a: 2,
-                mode: BC(Register(4), Unused),
+                a: 0,
+                mode: BC(Register(1), Unused),
             },
-            lua51::Instruction::LoadK { a: 4, mode: Bx(0) },
-            lua51::Instruction::Equals {
+            lua51::Instruction::Return {
                 a: 0,
-                mode: BC(ConstantRegister(2, false), ConstantRegister(4, false)),
+                mode: BC(Generic(1), Unused),
             },
         ];
 
         assert_eq!(instructions, expected);
-        assert_eq!(&constants, [Constant::Nil].as_slice());
         Ok(())
     }
 
     #[test]
-    fn upcast_t_for_prep() -> Result<(), LunifyError> {
+    fn upcast_for_loop() -> Result<(), LunifyError> {
         let settings = test_settings();
-        let instructions = vec![lua50::Instruction::TForPrep { a: 0, mode: SignedBx(-1) }];
-        let mut constants = Vec::new();
+        let instructions = vec![lua50::Instruction::ForLoop { a: 0, mode: SignedBx(-1) }];
 
-        let (instructions, _) = upcast(instructions, vec![0; 1], &mut constants, &mut 2, 0, false, &settings)?;
+        // The loop body is empty, so restoring RA+3 at the destination of the `FORLOOP`
+        // would also restore it when entering the loop, before it was saved.
+        let (instructions, _) = upcast(instructions, vec![0; 1], &mut Vec::new(), &mut 2, &Default::default(), &settings)?;
         let expected = vec![
-            lua51::Instruction::SetGlobal { a: 1, mode: Bx(0) },
-            lua51::Instruction::SetGlobal { a: 2, mode: Bx(1) },
-            lua51::Instruction::GetGlobal { a: 1, mode: Bx(2) },
-            lua51::Instruction::Move {
-                a: 2,
-                mode: BC(Register(0), Unused),
-            },
-            lua51::Instruction::Call {
-                a: 1,
-                mode: BC(Generic(2), Generic(2)),
-            },
-            lua51::Instruction::LoadK { a: 2, mode: Bx(3) },
-            lua51::Instruction::Equals {
-                a: 0,
-                mode: BC(ConstantRegister(1, false), ConstantRegister(2, false)),
-            },
-            lua51::Instruction::Jump { a: 0, mode: SignedBx(2) },
-            lua51::Instruction::SetGlobal { a: 0, mode: Bx(0) },
-            lua51::Instruction::GetGlobal { a: 0, mode: Bx(4) },
-            lua51::Instruction::GetGlobal { a: 1, mode: Bx(0) },
-            lua51::Instruction::GetGlobal { a: 2, mode: Bx(1) },
-            lua51::Instruction::Jump { a: 0, mode: SignedBx(-13) },
-        ];
-        let expected_constants = [
-            Constant::String("type\0".to_owned()),
-            Constant::String("table\0".to_owned()),
-            Constant::String("next\0".to_owned()),
+            lua51::Instruction::SetGlobal { a: 3, mode: ConstantIndex(0) },
+            lua51::Instruction::ForLoop { a: 0, mode: SignedBx(-1) },
+            lua51::Instruction::GetGlobal { a: 3, mode: ConstantIndex(0) },
+        ];
+
+        assert_eq!(instructions, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn upcast_for_loop_overwritten_index() -> Result<(), LunifyError> {
+        let settings = test_settings();
+        let instructions = vec![
+            lua50::Instruction::LoadK { a: 3, mode: ConstantIndex(0) },
+            lua50::Instruction::ForLoop { a: 0, mode: SignedBx(-2) },
+        ];
+
+        // The loop body assigns RA+3 before reading it, so it doesn't need to be
+        // preserved.
+        let mut constants = vec![Constant::Number(Number::Float(9.0))];
+        let (instructions, _) = upcast(instructions, vec![0; 2], &mut constants, &mut 4, &Default::default(), &settings)?;
+        let expected = vec![
+            lua51::Instruction::LoadK { a: 3, mode: ConstantIndex(0) },
+            lua51::Instruction::ForLoop { a: 0, mode: SignedBx(-2) },
+        ];
+
+        assert_eq!(instructions, expected);
+        assert_eq!(constants.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn upcast_for_loop_out_of_bounds() {
+        let settings = test_settings();
+        let instructions = vec![lua50::Instruction::ForLoop { a: 0, mode: SignedBx(-5) }];
+
+        let result = upcast(instructions, vec![0; 1], &mut Vec::new(), &mut 2, &Default::default(), &settings);
+        assert_eq!(result, Err(LunifyError::JumpOutOfBounds));
+    }
+
+    #[test]
+    fn upcast_for_loop_close_at_destination() -> Result<(), LunifyError> {
+        let settings = test_settings();
+        let instructions = vec![
+            lua50::Instruction::Close {
+                a: 0,
+                mode: BC(Unused, Unused),
+            },
+            lua50::Instruction::ForLoop { a: 0, mode: SignedBx(-2) },
+        ];
+
+        // The loop is entered by falling through, so RA+3 is saved in front of the
+        // `CLOSE`, where the `FORLOOP` doesn't go.
+        let (instructions, _) = upcast(instructions, vec![0; 2], &mut Vec::new(), &mut 2, &Default::default(), &settings)?;
+        let expected = vec![
+            lua51::Instruction::SetGlobal { a: 3, mode: ConstantIndex(0) },
+            lua51::Instruction::Close {
+                a: 0,
+                mode: BC(Unused, Unused),
+            },
+            lua51::Instruction::GetGlobal { a: 3, mode: ConstantIndex(0) },
+            lua51::Instruction::SetGlobal { a: 3, mode: ConstantIndex(0) },
+            lua51::Instruction::ForLoop { a: 0, mode: SignedBx(-4) },
+        ];
+
+        assert_eq!(instructions, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn upcast_for_loop_tail_call_at_destination() -> Result<(), LunifyError> {
+        let settings = test_settings();
+        let instructions = vec![
+            lua50::Instruction::Subtract {
+                a: 0,
+                mode: BC(ConstantRegister(0, false), ConstantRegister(2, false)),
+            },
+            lua50::Instruction::Jump { a: 0, mode: SignedBx(2) },
+            lua50::Instruction::TailCall {
+                a: 3,
+                mode: BC(Generic(1), Generic(0)),
+            },
+            lua50::Instruction::Return {
+                a: 3,
+                mode: BC(Generic(0), Unused),
+            },
+            lua50::Instruction::ForLoop { a: 0, mode: SignedBx(-3) },
+        ];
+
+        // RA+3 is restored in front of the `TAILCALL`, which has to be followed
+        // directly by its `RETURN`.
+        let (instructions, _) = upcast(instructions, vec![0; 5], &mut Vec::new(), &mut 4, &Default::default(), &settings)?;
+        let expected = vec![
+            lua51::Instruction::ForPrep { a: 0, mode: SignedBx(3) },
+            lua51::Instruction::GetGlobal { a: 3, mode: ConstantIndex(0) },
+            lua51::Instruction::TailCall {
+                a: 3,
+                mode: BC(Generic(1), Generic(0)),
+            },
+            lua51::Instruction::Return {
+                a: 3,
+                mode: BC(Generic(0), Unused),
+            },
+            lua51::Instruction::SetGlobal { a: 3, mode: ConstantIndex(0) },
+            lua51::Instruction::ForLoop { a: 0, mode: SignedBx(-5) },
+        ];
+
+        assert_eq!(instructions, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn upcast_final_return() -> Result<(), LunifyError> {
+        let settings = Settings::builder().output_ensure_final_return(true).build()?;
+        let instructions = vec![lua50::Instruction::Move {
+            a: 0,
+            mode: BC(Register(1), Unused),
+        }];
+
+        let (instructions, line_info) = upcast(instructions, vec![4], &mut Vec::new(), &mut 2, &Default::default(), &settings)?;
+        let expected = vec![
+            lua51::Instruction::Move {
+                a: 0,
+                mode: BC(Register(1), Unused),
+            },
+            lua51::Instruction::Return {
+                a: 0,
+                mode: BC(Generic(1), Unused),
+            },
+        ];
+
+        assert_eq!(instructions, expected);
+        assert_eq!(line_info, [4, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn upcast_final_return_jump_target() -> Result<(), LunifyError> {
+        let settings = Settings::builder().output_ensure_final_return(true).build()?;
+        let instructions = vec![
+            lua50::Instruction::Test {
+                a: 0,
+                mode: BC(Register(0), Generic(0)),
+            },
+            lua50::Instruction::Jump { a: 0, mode: SignedBx(2) },
+            lua50::Instruction::Move {
+                a: 1,
+                mode: BC(Register(0), Unused),
+            },
+            lua50::Instruction::Jump { a: 0, mode: SignedBx(-4) },
+        ];
+
+        // The loop jumps to the end of the function, which is where the `RETURN` is
+        // appended.
+        let (instructions, _) = upcast(instructions, vec![0; 4], &mut Vec::new(), &mut 2, &Default::default(), &settings)?;
+        let expected = vec![
+            lua51::Instruction::Test {
+                a: 0,
+                mode: BC(Register(0), Generic(0)),
+            },
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(2) },
+            lua51::Instruction::Move {
+                a: 1,
+                mode: BC(Register(0), Unused),
+            },
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(-4) },
+            lua51::Instruction::Return {
+                a: 0,
+                mode: BC(Generic(1), Unused),
+            },
+        ];
+
+        assert_eq!(instructions, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn upcast_missing_final_return() -> Result<(), LunifyError> {
+        let diagnostics = RefCell::new(Vec::new());
+        let record = |diagnostic: Diagnostic| diagnostics.borrow_mut().push(diagnostic.code);
+        let settings = Settings::builder()
+            .output_ensure_final_return(false)
+            .diagnostics(Some(DiagnosticCallback(&record)))
+            .build()?;
+        let instructions = vec![lua50::Instruction::TailCall {
+            a: 0,
+            mode: BC(Generic(1), Generic(0)),
+        }];
+
+        let (instructions, _) = upcast(instructions, vec![0; 1], &mut Vec::new(), &mut 2, &Default::default(), &settings)?;
+
+        assert_eq!(instructions.len(), 1);
+        assert_eq!(diagnostics.into_inner(), [DiagnosticCode::MissingFinalReturn]);
+        Ok(())
+    }
+
+    /// A numeric for loop as the Lua 5.0 compiler generates it, optionally
+    /// without subtracting the step from the initial value.
+    fn numeric_for_loop(is_step_subtracted: bool) -> Vec<lua50::Instruction> {
+        let subtract = lua50::Instruction::Subtract {
+            a: 0,
+            mode: BC(ConstantRegister(0, false), ConstantRegister(2, false)),
+        };
+
+        is_step_subtracted
+            .then_some(subtract)
+            .into_iter()
+            .chain([
+                lua50::Instruction::Jump { a: 0, mode: SignedBx(1) },
+                lua50::Instruction::Move {
+                    a: 4,
+                    mode: BC(Register(0), Unused),
+                },
+                lua50::Instruction::ForLoop { a: 0, mode: SignedBx(-2) },
+            ])
+            .collect()
+    }
+
+    #[test]
+    fn upcast_for_preparation() -> Result<(), LunifyError> {
+        let settings = test_settings();
+        let instructions = numeric_for_loop(true);
+
+        let (instructions, _) = upcast(instructions, vec![0; 4], &mut Vec::new(), &mut 5, &Default::default(), &settings)?;
+        let expected = vec![
+            lua51::Instruction::ForPrep { a: 0, mode: SignedBx(2) },
+            lua51::Instruction::GetGlobal { a: 3, mode: ConstantIndex(0) },
+            lua51::Instruction::Move {
+                a: 4,
+                mode: BC(Register(0), Unused),
+            },
+            lua51::Instruction::SetGlobal { a: 3, mode: ConstantIndex(0) },
+            lua51::Instruction::ForLoop { a: 0, mode: SignedBx(-4) },
+        ];
+
+        assert_eq!(instructions, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn upcast_for_preparation_without_subtract() -> Result<(), LunifyError> {
+        let settings = test_settings();
+        let instructions = numeric_for_loop(false);
+
+        // Without the `SUB`, a `FORPREP` would change the first value of the index.
+        let (instructions, _) = upcast(instructions, vec![0; 3], &mut Vec::new(), &mut 5, &Default::default(), &settings)?;
+        assert_eq!(instructions[0], lua51::Instruction::Jump { a: 0, mode: SignedBx(2) });
+        Ok(())
+    }
+
+    /// A loop that jumps back to a jump that closes upvalues from register 1.
+    fn closing_jump() -> Vec<lua50::Instruction> {
+        vec![
+            lua50::Instruction::Jump { a: 2, mode: SignedBx(1) },
+            lua50::Instruction::Move {
+                a: 0,
+                mode: BC(Register(1), Unused),
+            },
+            lua50::Instruction::Jump { a: 0, mode: SignedBx(-3) },
+        ]
+    }
+
+    #[test]
+    fn upcast_jump_close_upvalues() -> Result<(), LunifyError> {
+        let settings = test_settings();
+
+        let (instructions, _) = upcast(closing_jump(), vec![0; 3], &mut Vec::new(), &mut 2, &Default::default(), &settings)?;
+        let expected = vec![
+            lua51::Instruction::Close {
+                a: 1,
+                mode: BC(Unused, Unused),
+            },
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(1) },
+            lua51::Instruction::Move {
+                a: 0,
+                mode: BC(Register(1), Unused),
+            },
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(-4) },
+        ];
+
+        assert_eq!(instructions, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn upcast_jump_closes_upvalues() -> Result<(), LunifyError> {
+        let mut settings = test_settings();
+        settings.output.jump_closes_upvalues = true;
+
+        let (instructions, _) = upcast(closing_jump(), vec![0; 3], &mut Vec::new(), &mut 2, &Default::default(), &settings)?;
+        let expected = vec![
+            lua51::Instruction::Jump { a: 2, mode: SignedBx(1) },
+            lua51::Instruction::Move {
+                a: 0,
+                mode: BC(Register(1), Unused),
+            },
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(-3) },
+        ];
+
+        assert_eq!(instructions, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn upcast_t_for_loop() -> Result<(), LunifyError> {
+        let settings = test_settings();
+        let instructions = vec![
+            lua50::Instruction::TForLoop {
+                a: 0,
+                mode: BC(Unused, Generic(0)),
+            },
+            lua50::Instruction::Jump { a: 0, mode: SignedBx(-2) },
+        ];
+
+        let (instructions, _) = upcast(instructions, vec![0; 2], &mut Vec::new(), &mut 2, &Default::default(), &settings)?;
+        let expected = vec![
+            lua51::Instruction::TForLoop {
+                a: 0,
+                mode: BC(Unused, Generic(1)),
+            },
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(-2) },
+        ];
+
+        assert_eq!(instructions, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn upcast_t_for_loop_c_bigger_zero() -> Result<(), LunifyError> {
+        let settings = test_settings();
+        let instructions = vec![
+            lua50::Instruction::TForLoop {
+                a: 0,
+                mode: BC(Unused, Generic(1)),
+            },
+            lua50::Instruction::Jump { a: 0, mode: SignedBx(-2) },
+        ];
+        let mut constants = Vec::new();
+
+        let (instructions, _) = upcast(instructions, vec![0; 2], &mut constants, &mut 2, &Default::default(), &settings)?;
+        let expected = vec![
+            lua51::Instruction::Move {
+                a: 4,
+                mode: BC(Register(0), Unused),
+            },
+            lua51::Instruction::Move {
+                a: 5,
+                mode: BC(Register(1), Unused),
+            },
+            lua51::Instruction::Move {
+                a: 6,
+                mode: BC(Register(2), Unused),
+            },
+            lua51::Instruction::Call {
+                a: 4,
+                mode: BC(Generic(3), Generic(3)),
+            },
+            lua51::Instruction::Move {
+                a: 3,
+                mode: BC(Register(5), Unused),
+            },
+            lua51::Instruction::Move {
+                a: 2,
+                mode: BC(Register(4), Unused),
+            },
+            lua51::Instruction::LoadK { a: 4, mode: ConstantIndex(0) },
+            lua51::Instruction::Equals {
+                a: 0,
+                mode: BC(ConstantRegister(2, false), ConstantRegister(4, false)),
+            },
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(-9) },
+        ];
+
+        assert_eq!(instructions, expected);
+        assert_eq!(&constants, [Constant::Nil].as_slice());
+        Ok(())
+    }
+
+    #[test]
+    fn upcast_t_for_loop_stack_too_large() {
+        let settings = test_settings();
+        let instructions = vec![
+            lua50::Instruction::TForLoop {
+                a: 246,
+                mode: BC(Unused, Generic(1)),
+            },
+            lua50::Instruction::Jump { a: 0, mode: SignedBx(-2) },
+        ];
+
+        let result = upcast(instructions, vec![7; 2], &mut Vec::new(), &mut 2, &Default::default(), &settings);
+        let expected = LunifyError::StackTooLargeDetailed {
+            size: 251,
+            line: 7,
+            causes: vec![InsertionReason::TForLoopExpansion],
+        };
+
+        assert_eq!(result, Err(expected));
+    }
+
+    fn many_constants() -> Vec<Constant<'static>> {
+        (0..300).map(|index| Constant::Number(Number::Integer(index))).collect()
+    }
+
+    #[test]
+    fn upcast_many_constants() -> Result<(), LunifyError> {
+        let settings = test_settings();
+        let mut constants = many_constants();
+        let instructions = vec![
+            lua50::Instruction::LoadK { a: 0, mode: ConstantIndex(299) },
+            lua50::Instruction::GetGlobal { a: 1, mode: ConstantIndex(298) },
+            lua50::Instruction::Add {
+                a: 0,
+                mode: BC(ConstantRegister(255, true), ConstantRegister(1, false)),
+            },
+        ];
+
+        let (instructions, _) = upcast(instructions, vec![0; 3], &mut constants, &mut 2, &Default::default(), &settings)?;
+        let expected = vec![
+            lua51::Instruction::LoadK { a: 0, mode: ConstantIndex(299) },
+            lua51::Instruction::GetGlobal { a: 1, mode: ConstantIndex(298) },
+            lua51::Instruction::Add {
+                a: 0,
+                mode: BC(ConstantRegister(255, true), ConstantRegister(1, false)),
+            },
+        ];
+
+        assert_eq!(instructions, expected);
+        assert_eq!(constants.len(), 300);
+        Ok(())
+    }
+
+    #[test]
+    fn upcast_too_many_constants() {
+        let settings = test_settings();
+        let instructions = vec![lua50::Instruction::Add {
+            a: 0,
+            mode: BC(ConstantRegister(1, false), ConstantRegister(299, true)),
+        }];
+
+        // Every register is already in use, so the constant can't be spilled.
+        let result = upcast(instructions, vec![0], &mut many_constants(), &mut 250, &Default::default(), &settings);
+        let expected = LunifyError::TooManyConstants {
+            path: FunctionPath::root(),
+            constant_count: 300,
+        };
+
+        assert_eq!(result, Err(expected));
+    }
+
+    #[test]
+    fn upcast_t_for_loop_malformed() {
+        let settings = test_settings();
+        let t_for_loop = |c| lua50::Instruction::TForLoop {
+            a: 0,
+            mode: BC(Unused, Generic(c)),
+        };
+        let move_instruction = lua50::Instruction::Move {
+            a: 0,
+            mode: BC(Register(1), Unused),
+        };
+
+        for c in [0, 1] {
+            // No instruction after the loop, no jump, a forward jump and a jump that
+            // closes upvalues.
+            let malformed = [
+                (0, vec![t_for_loop(c)]),
+                (0, vec![t_for_loop(c), move_instruction]),
+                (0, vec![t_for_loop(c), lua50::Instruction::Jump { a: 0, mode: SignedBx(0) }]),
+                (1, vec![move_instruction, t_for_loop(c), lua50::Instruction::Jump { a: 1, mode: SignedBx(-2) }]),
+            ];
+
+            for (program_counter, instructions) in malformed {
+                let line_info = vec![0; instructions.len()];
+                let result = upcast(instructions, line_info, &mut Vec::new(), &mut 2, &Default::default(), &settings);
+                assert_eq!(result, Err(LunifyError::MalformedTForLoop { program_counter }));
+            }
+        }
+    }
+
+    #[test]
+    fn upcast_t_for_loop_closing_back_edge() -> Result<(), LunifyError> {
+        let mut settings = test_settings();
+        settings.output.jump_closes_upvalues = true;
+
+        let instructions = vec![
+            lua50::Instruction::TForLoop {
+                a: 0,
+                mode: BC(Unused, Generic(0)),
+            },
+            lua50::Instruction::Jump { a: 1, mode: SignedBx(-2) },
+        ];
+
+        let (instructions, _) = upcast(instructions, vec![0; 2], &mut Vec::new(), &mut 2, &Default::default(), &settings)?;
+        assert_eq!(instructions[1], lua51::Instruction::Jump { a: 1, mode: SignedBx(-2) });
+        Ok(())
+    }
+
+    #[test]
+    fn upcast_t_for_prep() -> Result<(), LunifyError> {
+        let settings = test_settings();
+        let instructions = vec![lua50::Instruction::TForPrep { a: 0, mode: SignedBx(-1) }];
+        let mut constants = Vec::new();
+
+        let (instructions, _) = upcast(instructions, vec![0; 1], &mut constants, &mut 2, &Default::default(), &settings)?;
+        let expected = vec![
+            lua51::Instruction::SetGlobal { a: 1, mode: ConstantIndex(0) },
+            lua51::Instruction::SetGlobal { a: 2, mode: ConstantIndex(1) },
+            lua51::Instruction::GetGlobal { a: 1, mode: ConstantIndex(2) },
+            lua51::Instruction::Move {
+                a: 2,
+                mode: BC(Register(0), Unused),
+            },
+            lua51::Instruction::Call {
+                a: 1,
+                mode: BC(Generic(2), Generic(2)),
+            },
+            lua51::Instruction::LoadK { a: 2, mode: ConstantIndex(3) },
+            lua51::Instruction::Equals {
+                a: 0,
+                mode: BC(ConstantRegister(1, false), ConstantRegister(2, false)),
+            },
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(2) },
+            lua51::Instruction::SetGlobal { a: 0, mode: ConstantIndex(0) },
+            lua51::Instruction::GetGlobal { a: 0, mode: ConstantIndex(4) },
+            lua51::Instruction::GetGlobal { a: 1, mode: ConstantIndex(0) },
+            lua51::Instruction::GetGlobal { a: 2, mode: ConstantIndex(1) },
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(-13) },
+        ];
+        let expected_constants = [
+            Constant::String(Cow::Borrowed(b"type\0")),
+            Constant::String(Cow::Borrowed(b"table\0")),
+            Constant::String(Cow::Borrowed(b"next\0")),
         ];
 
         assert_eq!(instructions, expected);
@@ -591,6 +1583,66 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn upcast_power_call() -> Result<(), LunifyError> {
+        let mut settings = test_settings();
+        settings.output.lower_power_to_call = true;
+
+        let instructions = vec![lua50::Instruction::Power {
+            a: 0,
+            mode: BC(ConstantRegister(1, false), ConstantRegister(0, true)),
+        }];
+        let mut constants = vec![Constant::Number(Number::Float(2.0))];
+        let mut maximum_stack_size = 2;
+
+        let (instructions, _) = upcast(instructions, vec![0; 1], &mut constants, &mut maximum_stack_size, &Default::default(), &settings)?;
+        let expected = vec![
+            lua51::Instruction::GetGlobal { a: 2, mode: ConstantIndex(1) },
+            lua51::Instruction::Move {
+                a: 3,
+                mode: BC(Register(1), Unused),
+            },
+            lua51::Instruction::LoadK { a: 4, mode: ConstantIndex(0) },
+            lua51::Instruction::Call {
+                a: 2,
+                mode: BC(Generic(3), Generic(2)),
+            },
+            lua51::Instruction::Move {
+                a: 0,
+                mode: BC(Register(2), Unused),
+            },
+        ];
+
+        assert_eq!(instructions, expected);
+        assert_eq!(constants[1], Constant::String(Cow::Borrowed(b"__pow\0")));
+        assert_eq!(maximum_stack_size, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn upcast_t_for_prep_assume_table() -> Result<(), LunifyError> {
+        let mut settings = test_settings();
+        settings.output.tforprep_assume_table = true;
+        settings.output.tforprep_next_global = "iterate";
+
+        let instructions = vec![lua50::Instruction::TForPrep { a: 0, mode: SignedBx(-1) }];
+        let mut constants = Vec::new();
+
+        let (instructions, _) = upcast(instructions, vec![0; 1], &mut constants, &mut 2, &Default::default(), &settings)?;
+        let expected = vec![
+            lua51::Instruction::Move {
+                a: 1,
+                mode: BC(Register(0), Unused),
+            },
+            lua51::Instruction::GetGlobal { a: 0, mode: ConstantIndex(0) },
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(-3) },
+        ];
+
+        assert_eq!(instructions, expected);
+        assert_eq!(constants, [Constant::String(Cow::Borrowed(b"iterate\0"))]);
+        Ok(())
+    }
+
     #[test]
     fn upcast_set_list() -> Result<(), LunifyError> {
         set_list_test(4)
@@ -611,16 +1663,39 @@ mod tests {
         set_list_test(20)
     }
 
+    #[test]
+    fn upcast_set_list_exactly_51_flush() -> Result<(), LunifyError> {
+        set_list_test(8)
+    }
+
+    #[test]
+    fn upcast_set_list_exact_multiple_of_51_flush() -> Result<(), LunifyError> {
+        set_list_test(16)
+    }
+
+    #[test]
+    fn upcast_set_list_scan_depth_exceeded() {
+        let mut settings = test_settings();
+        settings.limits.max_setlist_scan_depth = 4;
+
+        // The second `SETLIST` needs to look back five instructions to find the first one.
+        let instructions = lua50_setlist(10, settings);
+        let instruction_count = instructions.len();
+
+        let result = upcast(instructions, vec![0; instruction_count], &mut Vec::new(), &mut 2, &Default::default(), &settings);
+        assert_eq!(result, Err(LunifyError::LimitExceeded("max_setlist_scan_depth")));
+    }
+
     #[test]
     fn upcast_set_list_from_parameters() -> Result<(), LunifyError> {
         let settings = test_settings();
-        let instructions = vec![lua50::Instruction::LoadK { a: 5, mode: Bx(0) }, lua50::Instruction::SetList {
+        let instructions = vec![lua50::Instruction::LoadK { a: 5, mode: ConstantIndex(0) }, lua50::Instruction::SetList {
             a: 0,
             mode: Bx(4),
         }];
 
-        let (instructions, _) = upcast(instructions, vec![0; 2], &mut Vec::new(), &mut 2, 0, false, &settings)?;
-        let expected = vec![lua51::Instruction::LoadK { a: 5, mode: Bx(0) }, lua51::Instruction::SetList {
+        let (instructions, _) = upcast(instructions, vec![0; 2], &mut Vec::new(), &mut 2, &Default::default(), &settings)?;
+        let expected = vec![lua51::Instruction::LoadK { a: 5, mode: ConstantIndex(0) }, lua51::Instruction::SetList {
             a: 0,
             mode: BC(Generic(5), Generic(1)),
         }];
@@ -633,16 +1708,16 @@ mod tests {
     fn upcast_set_list_from_parameters_bigger_than_50_flush() -> Result<(), LunifyError> {
         let settings = test_settings();
         let instructions = vec![
-            lua50::Instruction::LoadK { a: 5, mode: Bx(0) },
+            lua50::Instruction::LoadK { a: 5, mode: ConstantIndex(0) },
             lua50::Instruction::SetList { a: 0, mode: Bx(4) },
-            lua50::Instruction::LoadK { a: 1, mode: Bx(0) },
+            lua50::Instruction::LoadK { a: 1, mode: ConstantIndex(0) },
             lua50::Instruction::SetList { a: 0, mode: Bx(5) },
         ];
 
-        let (instructions, _) = upcast(instructions, vec![0; 12], &mut Vec::new(), &mut 2, 0, false, &settings)?;
+        let (instructions, _) = upcast(instructions, vec![0; 12], &mut Vec::new(), &mut 2, &Default::default(), &settings)?;
         let expected = vec![
-            lua51::Instruction::LoadK { a: 5, mode: Bx(0) },
-            lua51::Instruction::LoadK { a: 6, mode: Bx(0) },
+            lua51::Instruction::LoadK { a: 5, mode: ConstantIndex(0) },
+            lua51::Instruction::LoadK { a: 6, mode: ConstantIndex(0) },
             lua51::Instruction::SetList {
                 a: 0,
                 mode: BC(Generic(6), Generic(1)),
@@ -654,15 +1729,95 @@ mod tests {
     }
 
     #[test]
-    fn variadic() -> Result<(), LunifyError> {
+    fn upcast_set_list_across_basic_blocks() -> Result<(), LunifyError> {
         let settings = test_settings();
-        let instructions = vec![lua50::Instruction::LoadK { a: 1, mode: Bx(0) }];
+        let load = |a: u64| lua50::Instruction::LoadK { a, mode: ConstantIndex(0) };
+        let load_output = |a: u64| lua51::Instruction::LoadK { a, mode: ConstantIndex(0) };
+        let set_list = |b: u64, c: u64| lua51::Instruction::SetList {
+            a: 0,
+            mode: BC(Generic(b), Generic(c)),
+        };
+
+        // `{ 9, 9, 9, 9, 9, 9, 9, 9, 9 or nil, 9 }`, where the ninth element is
+        // conditionally skipped.
+        let instructions = vec![
+            load(1),
+            load(2),
+            load(3),
+            load(4),
+            load(5),
+            lua50::Instruction::SetList { a: 0, mode: Bx(4) },
+            load(1),
+            load(2),
+            load(3),
+            load(4),
+            lua50::Instruction::Test {
+                a: 4,
+                mode: BC(Register(4), Generic(1)),
+            },
+            lua50::Instruction::Jump { a: 0, mode: SignedBx(1) },
+            lua50::Instruction::LoadNil {
+                a: 4,
+                mode: BC(Register(4), Unused),
+            },
+            load(5),
+            lua50::Instruction::SetList { a: 0, mode: Bx(9) },
+        ];
+        let instruction_count = instructions.len();
+
+        let (instructions, _) = upcast(instructions, vec![0; instruction_count], &mut Vec::new(), &mut 6, &Default::default(), &settings)?;
+        let expected = vec![
+            load_output(1),
+            load_output(2),
+            load_output(3),
+            load_output(4),
+            load_output(5),
+            load_output(6),
+            load_output(7),
+            load_output(8),
+            load_output(9),
+            lua51::Instruction::Test {
+                a: 9,
+                mode: BC(Register(0), Generic(1)),
+            },
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(1) },
+            lua51::Instruction::LoadNil {
+                a: 9,
+                mode: BC(Register(9), Unused),
+            },
+            load_output(10),
+            set_list(8, 1),
+            lua51::Instruction::Move {
+                a: 1,
+                mode: BC(Register(9), Unused),
+            },
+            lua51::Instruction::Move {
+                a: 2,
+                mode: BC(Register(10), Unused),
+            },
+            set_list(2, 2),
+        ];
+
+        assert_eq!(instructions, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn variadic() -> Result<(), LunifyError> {
+        let mut settings = test_settings();
+        settings.output.emit_vararg_count = false;
+        let instructions = vec![lua50::Instruction::LoadK { a: 1, mode: ConstantIndex(0) }];
+
+        let function = FunctionContext {
+            is_variadic: true,
+            ..Default::default()
+        };
 
-        let (instructions, _) = upcast(instructions, vec![0; 1], &mut Vec::new(), &mut 2, 0, true, &settings)?;
+        let (instructions, _) = upcast(instructions, vec![0; 1], &mut Vec::new(), &mut 2, &function, &settings)?;
         let expected = vec![
             lua51::Instruction::NewTable {
                 a: 1,
-                mode: BC(Unused, Unused),
+                mode: BC(Generic(0), Generic(0)),
             },
             lua51::Instruction::VarArg {
                 a: 2,
@@ -676,10 +1831,691 @@ mod tests {
                 a: 0,
                 mode: BC(Register(1), Unused),
             },
-            lua51::Instruction::LoadK { a: 1, mode: Bx(0) },
+            lua51::Instruction::LoadK { a: 1, mode: ConstantIndex(0) },
+        ];
+
+        assert_eq!(instructions, expected);
+        assert_eq!(variadic_prologue_length(&function, &settings), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn variadic_arg_register() -> Result<(), LunifyError> {
+        let mut settings = test_settings();
+        settings.output.emit_vararg_count = false;
+        let instructions = vec![lua50::Instruction::LoadK { a: 3, mode: ConstantIndex(0) }];
+
+        // The debug information declares another local in front of `arg`, and a
+        // later local that shadows it.
+        let local = |name: &'static [u8], start_program_counter: i64| LocalVariable {
+            name,
+            start_program_counter,
+            end_program_counter: 1,
+        };
+        let local_variables = [local(b"a\0", 0), local(b"b\0", 0), local(b"arg\0", 0), local(b"arg\0", 1)];
+        let function = FunctionContext {
+            parameter_count: 1,
+            is_variadic: true,
+            local_variables: &local_variables,
+            ..Default::default()
+        };
+
+        let (instructions, _) = upcast(instructions, vec![0; 1], &mut Vec::new(), &mut 4, &function, &settings)?;
+        assert_eq!(instructions[0], lua51::Instruction::NewTable {
+            a: 3,
+            mode: BC(Generic(0), Generic(0)),
+        });
+        assert_eq!(instructions[3], lua51::Instruction::Move {
+            a: 2,
+            mode: BC(Register(3), Unused),
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn variadic_count() -> Result<(), LunifyError> {
+        let settings = test_settings();
+        let instructions = vec![lua50::Instruction::LoadK { a: 1, mode: ConstantIndex(0) }];
+        let mut constants = vec![Constant::String(Cow::Borrowed(b"select\0"))];
+
+        let function = FunctionContext {
+            parameter_count: 1,
+            is_variadic: true,
+            ..Default::default()
+        };
+
+        let (instructions, _) = upcast(instructions, vec![0; 1], &mut constants, &mut 2, &function, &settings)?;
+        let expected = vec![
+            lua51::Instruction::NewTable {
+                a: 2,
+                mode: BC(Generic(0), Generic(0)),
+            },
+            lua51::Instruction::VarArg {
+                a: 3,
+                mode: BC(Generic(0), Unused),
+            },
+            lua51::Instruction::SetList {
+                a: 2,
+                mode: BC(Generic(0), Generic(1)),
+            },
+            lua51::Instruction::GetGlobal { a: 3, mode: ConstantIndex(0) },
+            lua51::Instruction::LoadK { a: 4, mode: ConstantIndex(1) },
+            lua51::Instruction::VarArg {
+                a: 5,
+                mode: BC(Generic(0), Unused),
+            },
+            lua51::Instruction::Call {
+                a: 3,
+                mode: BC(Generic(0), Generic(2)),
+            },
+            lua51::Instruction::LoadK { a: 4, mode: ConstantIndex(2) },
+            lua51::Instruction::SetTable {
+                a: 2,
+                mode: BC(ConstantRegister(4, false), ConstantRegister(3, false)),
+            },
+            lua51::Instruction::Move {
+                a: 1,
+                mode: BC(Register(2), Unused),
+            },
+            lua51::Instruction::LoadK { a: 1, mode: ConstantIndex(0) },
+        ];
+
+        assert_eq!(instructions, expected);
+        assert_eq!(constants, [
+            Constant::String(Cow::Borrowed(b"select\0")),
+            Constant::String(Cow::Borrowed(b"#\0")),
+            Constant::String(Cow::Borrowed(b"n\0")),
+        ]);
+        assert_eq!(variadic_prologue_length(&function, &settings), 10);
+        Ok(())
+    }
+
+    #[test]
+    fn variadic_for_loop_at_start() -> Result<(), LunifyError> {
+        let mut settings = test_settings();
+        settings.output.emit_vararg_count = false;
+        let instructions = vec![
+            lua50::Instruction::Add {
+                a: 4,
+                mode: BC(ConstantRegister(4, false), ConstantRegister(0, false)),
+            },
+            lua50::Instruction::ForLoop { a: 0, mode: SignedBx(-2) },
+            lua50::Instruction::Jump { a: 0, mode: SignedBx(-3) },
+        ];
+
+        let function = FunctionContext {
+            parameter_count: 3,
+            is_variadic: true,
+            ..Default::default()
+        };
+
+        // Both jumps to the first instruction need to land after the prologue. The
+        // `FORLOOP` lands on the `GETGLOBAL` restoring RA+3, the `JMP` on the original
+        // instruction. Falling through from the prologue saves RA+3 first, so the
+        // restore doesn't overwrite `arg`.
+        let (instructions, _) = upcast(instructions, vec![0; 3], &mut Vec::new(), &mut 5, &function, &settings)?;
+        let expected = vec![
+            lua51::Instruction::NewTable {
+                a: 4,
+                mode: BC(Generic(0), Generic(0)),
+            },
+            lua51::Instruction::VarArg {
+                a: 5,
+                mode: BC(Generic(0), Unused),
+            },
+            lua51::Instruction::SetList {
+                a: 4,
+                mode: BC(Generic(0), Generic(1)),
+            },
+            lua51::Instruction::Move {
+                a: 3,
+                mode: BC(Register(4), Unused),
+            },
+            lua51::Instruction::SetGlobal { a: 3, mode: ConstantIndex(0) },
+            lua51::Instruction::GetGlobal { a: 3, mode: ConstantIndex(0) },
+            lua51::Instruction::Add {
+                a: 4,
+                mode: BC(ConstantRegister(4, false), ConstantRegister(0, false)),
+            },
+            lua51::Instruction::SetGlobal { a: 3, mode: ConstantIndex(0) },
+            lua51::Instruction::ForLoop { a: 0, mode: SignedBx(-4) },
+            lua51::Instruction::Jump { a: 0, mode: SignedBx(-4) },
         ];
 
         assert_eq!(instructions, expected);
         Ok(())
     }
+
+    fn comparison_instructions(polarity: u64) -> Vec<lua50::Instruction> {
+        let mode = BC(ConstantRegister(0, false), ConstantRegister(1, false));
+
+        vec![
+            lua50::Instruction::Equals { a: polarity, mode },
+            lua50::Instruction::LessThan { a: polarity, mode },
+            lua50::Instruction::LessEquals { a: polarity, mode },
+            lua50::Instruction::Test {
+                a: 2,
+                mode: BC(Register(0), Generic(polarity)),
+            },
+        ]
+    }
+
+    fn expected_comparison_instructions(polarity: u64) -> Vec<lua51::Instruction> {
+        let mode = BC(ConstantRegister(0, false), ConstantRegister(1, false));
+
+        vec![
+            lua51::Instruction::Equals { a: polarity, mode },
+            lua51::Instruction::LessThan { a: polarity, mode },
+            lua51::Instruction::LessEquals { a: polarity, mode },
+            lua51::Instruction::TestSet {
+                a: 2,
+                mode: BC(ConstantRegister(0, false), Generic(polarity)),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_polarity() -> Result<(), LunifyError> {
+        let settings = test_settings();
+
+        for polarity in [0, 1] {
+            let (instructions, _) = upcast(
+                comparison_instructions(polarity),
+                vec![0; 4],
+                &mut Vec::new(),
+                &mut 3,
+                &Default::default(),
+                &settings,
+            )?;
+            assert_eq!(instructions, expected_comparison_instructions(polarity));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn inverted_test_polarity() -> Result<(), LunifyError> {
+        let mut settings = test_settings();
+        settings.lua50.inverted_test_polarity = true;
+
+        for polarity in [0, 1] {
+            let (instructions, _) = upcast(
+                comparison_instructions(polarity),
+                vec![0; 4],
+                &mut Vec::new(),
+                &mut 3,
+                &Default::default(),
+                &settings,
+            )?;
+            assert_eq!(instructions, expected_comparison_instructions(1 - polarity));
+        }
+
+        Ok(())
+    }
+
+    /// Small programs that together use every Lua 5.0 opcode, and the exact Lua
+    /// 5.1 instructions they are expected to be up-cast to.
+    fn coverage_table() -> Vec<(Vec<lua50::Instruction>, Vec<lua51::Instruction>)> {
+        use lua50::Instruction as I;
+        use lua51::Instruction as O;
+
+        let return_50 = I::Return {
+            a: 0,
+            mode: BC(Generic(1), Unused),
+        };
+        let return_51 = O::Return {
+            a: 0,
+            mode: BC(Generic(1), Unused),
+        };
+        let jump_50 = I::Jump { a: 0, mode: SignedBx(0) };
+        let jump_51 = O::Jump { a: 0, mode: SignedBx(0) };
+        let operands = BC(ConstantRegister(1, false), ConstantRegister(0, true));
+
+        vec![
+            (
+                vec![I::Move {
+                    a: 0,
+                    mode: BC(Register(1), Unused),
+                }],
+                vec![O::Move {
+                    a: 0,
+                    mode: BC(Register(1), Unused),
+                }],
+            ),
+            (
+                vec![I::LoadK {
+                    a: 0,
+                    mode: ConstantIndex(0),
+                }],
+                vec![O::LoadK {
+                    a: 0,
+                    mode: ConstantIndex(0),
+                }],
+            ),
+            (
+                vec![I::LoadBool {
+                    a: 0,
+                    mode: BC(Generic(1), Generic(1)),
+                }],
+                vec![O::LoadBool {
+                    a: 0,
+                    mode: BC(Generic(1), Generic(1)),
+                }],
+            ),
+            (
+                vec![I::LoadNil {
+                    a: 0,
+                    mode: BC(Register(2), Unused),
+                }],
+                vec![O::LoadNil {
+                    a: 0,
+                    mode: BC(Register(2), Unused),
+                }],
+            ),
+            (
+                vec![I::GetUpValue {
+                    a: 0,
+                    mode: BC(Generic(1), Unused),
+                }],
+                vec![O::GetUpValue {
+                    a: 0,
+                    mode: BC(Generic(1), Unused),
+                }],
+            ),
+            (
+                vec![I::GetGlobal {
+                    a: 0,
+                    mode: ConstantIndex(0),
+                }],
+                vec![O::GetGlobal {
+                    a: 0,
+                    mode: ConstantIndex(0),
+                }],
+            ),
+            (
+                vec![I::GetTable {
+                    a: 0,
+                    mode: BC(Register(1), ConstantRegister(0, true)),
+                }],
+                vec![O::GetTable {
+                    a: 0,
+                    mode: BC(Register(1), ConstantRegister(0, true)),
+                }],
+            ),
+            (
+                vec![I::SetGlobal {
+                    a: 0,
+                    mode: ConstantIndex(0),
+                }],
+                vec![O::SetGlobal {
+                    a: 0,
+                    mode: ConstantIndex(0),
+                }],
+            ),
+            (
+                vec![I::SetUpValue {
+                    a: 0,
+                    mode: BC(Generic(1), Unused),
+                }],
+                vec![O::SetUpValue {
+                    a: 0,
+                    mode: BC(Generic(1), Unused),
+                }],
+            ),
+            (vec![I::SetTable { a: 0, mode: operands }], vec![O::SetTable {
+                a: 0,
+                mode: operands,
+            }]),
+            // The size hints are encoded differently, so they are dropped.
+            (
+                vec![I::NewTable {
+                    a: 0,
+                    mode: BC(Generic(3), Generic(2)),
+                }],
+                vec![O::NewTable {
+                    a: 0,
+                    mode: BC(Generic(0), Generic(0)),
+                }],
+            ),
+            (
+                vec![I::_Self {
+                    a: 0,
+                    mode: BC(Register(1), ConstantRegister(0, true)),
+                }],
+                vec![O::_Self {
+                    a: 0,
+                    mode: BC(Register(1), ConstantRegister(0, true)),
+                }],
+            ),
+            (vec![I::Add { a: 0, mode: operands }], vec![O::Add { a: 0, mode: operands }]),
+            (vec![I::Subtract { a: 0, mode: operands }], vec![O::Subtract {
+                a: 0,
+                mode: operands,
+            }]),
+            (vec![I::Multiply { a: 0, mode: operands }], vec![O::Multiply {
+                a: 0,
+                mode: operands,
+            }]),
+            (vec![I::Divide { a: 0, mode: operands }], vec![O::Divide {
+                a: 0,
+                mode: operands,
+            }]),
+            (vec![I::Power { a: 0, mode: operands }], vec![O::Power { a: 0, mode: operands }]),
+            (
+                vec![I::Unary {
+                    a: 0,
+                    mode: BC(Register(1), ConstantRegister(0, false)),
+                }],
+                vec![O::Unary {
+                    a: 0,
+                    mode: BC(Register(1), ConstantRegister(0, false)),
+                }],
+            ),
+            (
+                vec![I::Not {
+                    a: 0,
+                    mode: BC(Register(1), Unused),
+                }],
+                vec![O::Not {
+                    a: 0,
+                    mode: BC(Register(1), Unused),
+                }],
+            ),
+            (
+                vec![I::Concatinate {
+                    a: 0,
+                    mode: BC(Register(1), Register(2)),
+                }],
+                vec![O::Concatinate {
+                    a: 0,
+                    mode: BC(Register(1), Register(2)),
+                }],
+            ),
+            (vec![jump_50, return_50], vec![jump_51, return_51]),
+            // A jump that closes upvalues.
+            (vec![I::Jump { a: 2, mode: SignedBx(-1) }], vec![
+                O::Close {
+                    a: 1,
+                    mode: BC(Unused, Unused),
+                },
+                O::Jump { a: 0, mode: SignedBx(-2) },
+            ]),
+            (vec![I::Equals { a: 1, mode: operands }, jump_50, return_50], vec![
+                O::Equals { a: 1, mode: operands },
+                jump_51,
+                return_51,
+            ]),
+            (vec![I::LessThan { a: 0, mode: operands }, jump_50, return_50], vec![
+                O::LessThan { a: 0, mode: operands },
+                jump_51,
+                return_51,
+            ]),
+            (vec![I::LessEquals { a: 1, mode: operands }, jump_50, return_50], vec![
+                O::LessEquals { a: 1, mode: operands },
+                jump_51,
+                return_51,
+            ]),
+            (
+                vec![
+                    I::Test {
+                        a: 0,
+                        mode: BC(Register(1), Generic(1)),
+                    },
+                    jump_50,
+                    return_50,
+                ],
+                vec![
+                    O::TestSet {
+                        a: 0,
+                        mode: BC(ConstantRegister(1, false), Generic(1)),
+                    },
+                    jump_51,
+                    return_51,
+                ],
+            ),
+            (
+                vec![I::Call {
+                    a: 0,
+                    mode: BC(Generic(2), Generic(0)),
+                }],
+                vec![O::Call {
+                    a: 0,
+                    mode: BC(Generic(2), Generic(0)),
+                }],
+            ),
+            (
+                vec![I::TailCall {
+                    a: 0,
+                    mode: BC(Generic(2), Generic(0)),
+                }],
+                vec![O::TailCall {
+                    a: 0,
+                    mode: BC(Generic(2), Generic(0)),
+                }],
+            ),
+            (vec![return_50], vec![return_51]),
+            // A numeric for loop, which starts with a subtraction and a jump in Lua 5.0.
+            (
+                vec![
+                    I::Subtract {
+                        a: 0,
+                        mode: BC(ConstantRegister(0, false), ConstantRegister(2, false)),
+                    },
+                    I::Jump { a: 0, mode: SignedBx(1) },
+                    I::Move {
+                        a: 3,
+                        mode: BC(Register(0), Unused),
+                    },
+                    I::ForLoop { a: 0, mode: SignedBx(-2) },
+                    return_50,
+                ],
+                vec![
+                    O::ForPrep { a: 0, mode: SignedBx(1) },
+                    O::Move {
+                        a: 3,
+                        mode: BC(Register(0), Unused),
+                    },
+                    O::ForLoop { a: 0, mode: SignedBx(-2) },
+                    return_51,
+                ],
+            ),
+            // A generic for loop. Without `tforprep_assume_table`, the preparation calls
+            // `next` if the iterator is a table.
+            (
+                vec![
+                    I::TForPrep { a: 0, mode: SignedBx(1) },
+                    I::Move {
+                        a: 5,
+                        mode: BC(Register(3), Unused),
+                    },
+                    I::TForLoop {
+                        a: 0,
+                        mode: BC(Unused, Generic(1)),
+                    },
+                    I::Jump { a: 0, mode: SignedBx(-3) },
+                    return_50,
+                ],
+                vec![
+                    O::SetGlobal {
+                        a: 1,
+                        mode: ConstantIndex(1),
+                    },
+                    O::SetGlobal {
+                        a: 2,
+                        mode: ConstantIndex(2),
+                    },
+                    O::GetGlobal {
+                        a: 1,
+                        mode: ConstantIndex(3),
+                    },
+                    O::Move {
+                        a: 2,
+                        mode: BC(Register(0), Unused),
+                    },
+                    O::Call {
+                        a: 1,
+                        mode: BC(Generic(2), Generic(2)),
+                    },
+                    O::LoadK {
+                        a: 2,
+                        mode: ConstantIndex(4),
+                    },
+                    O::Equals {
+                        a: 0,
+                        mode: BC(ConstantRegister(1, false), ConstantRegister(2, false)),
+                    },
+                    O::Jump { a: 0, mode: SignedBx(2) },
+                    O::SetGlobal {
+                        a: 0,
+                        mode: ConstantIndex(1),
+                    },
+                    O::GetGlobal {
+                        a: 0,
+                        mode: ConstantIndex(5),
+                    },
+                    O::GetGlobal {
+                        a: 1,
+                        mode: ConstantIndex(1),
+                    },
+                    O::GetGlobal {
+                        a: 2,
+                        mode: ConstantIndex(2),
+                    },
+                    O::Jump { a: 0, mode: SignedBx(1) },
+                    O::Move {
+                        a: 5,
+                        mode: BC(Register(3), Unused),
+                    },
+                    O::Move {
+                        a: 4,
+                        mode: BC(Register(0), Unused),
+                    },
+                    O::Move {
+                        a: 5,
+                        mode: BC(Register(1), Unused),
+                    },
+                    O::Move {
+                        a: 6,
+                        mode: BC(Register(2), Unused),
+                    },
+                    O::Call {
+                        a: 4,
+                        mode: BC(Generic(3), Generic(3)),
+                    },
+                    O::Move {
+                        a: 3,
+                        mode: BC(Register(5), Unused),
+                    },
+                    O::Move {
+                        a: 2,
+                        mode: BC(Register(4), Unused),
+                    },
+                    O::LoadK {
+                        a: 4,
+                        mode: ConstantIndex(6),
+                    },
+                    O::Equals {
+                        a: 0,
+                        mode: BC(ConstantRegister(2, false), ConstantRegister(4, false)),
+                    },
+                    O::Jump { a: 0, mode: SignedBx(-10) },
+                    return_51,
+                ],
+            ),
+            (
+                vec![
+                    I::LoadK {
+                        a: 1,
+                        mode: ConstantIndex(0),
+                    },
+                    I::SetList { a: 0, mode: Bx(0) },
+                ],
+                vec![
+                    O::LoadK {
+                        a: 1,
+                        mode: ConstantIndex(0),
+                    },
+                    O::SetList {
+                        a: 0,
+                        mode: BC(Generic(1), Generic(1)),
+                    },
+                ],
+            ),
+            (
+                vec![
+                    I::Call {
+                        a: 1,
+                        mode: BC(Generic(1), Generic(0)),
+                    },
+                    I::SetListO { a: 0, mode: Bx(0) },
+                ],
+                vec![
+                    O::Call {
+                        a: 1,
+                        mode: BC(Generic(1), Generic(0)),
+                    },
+                    O::SetList {
+                        a: 0,
+                        mode: BC(Generic(0), Generic(1)),
+                    },
+                ],
+            ),
+            (
+                vec![I::Close {
+                    a: 1,
+                    mode: BC(Unused, Unused),
+                }],
+                vec![O::Close {
+                    a: 1,
+                    mode: BC(Unused, Unused),
+                }],
+            ),
+            (
+                vec![
+                    I::Closure {
+                        a: 0,
+                        mode: PrototypeIndex(0),
+                    },
+                    I::Move {
+                        a: 0,
+                        mode: BC(Register(1), Unused),
+                    },
+                ],
+                vec![
+                    O::Closure {
+                        a: 0,
+                        mode: PrototypeIndex(0),
+                    },
+                    O::Move {
+                        a: 0,
+                        mode: BC(Register(1), Unused),
+                    },
+                ],
+            ),
+        ]
+    }
+
+    #[test]
+    fn every_opcode() -> Result<(), LunifyError> {
+        let function = FunctionContext {
+            upvalue_counts: &[1],
+            ..Default::default()
+        };
+        let mut covered = [false; lua50::Instruction::VARIANT_COUNT];
+
+        for (instructions, expected) in coverage_table() {
+            instructions
+                .iter()
+                .for_each(|instruction| covered[instruction.opcode() as usize] = true);
+
+            let line_info = vec![0; instructions.len()];
+            let mut constants = vec![Constant::String(Cow::Borrowed(b"result\0"))];
+            let (instructions, ..) = upcast(instructions, line_info, &mut constants, &mut 4, &function, &test_settings())?;
+            assert_eq!(instructions, expected);
+        }
+
+        let missing: Vec<usize> = (0..covered.len()).filter(|&opcode| !covered[opcode]).collect();
+        assert!(missing.is_empty(), "opcodes {missing:?} are missing from the coverage table");
+        Ok(())
+    }
 }
@@ -0,0 +1,218 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use super::constant::Constant;
+use super::instruction::{ConstantIndex, ConstantRegister, Generic, PrototypeIndex, SignedBx, BC};
+use super::{lua51, Function, Settings, VARARG_HASARG, VARARG_ISVARARG, VARARG_NEEDSARG};
+use crate::serialization::ByteStream;
+use crate::{FunctionPath, LunifyError};
+
+/// Problem in Lua 5.1 byte code found by [validate](crate::validate).
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ValidationIssue {
+    /// Indices of the nested functions that lead to the function with the
+    /// issue. The main function has an empty path.
+    pub path: FunctionPath,
+    /// The program counter of the offending instruction, if the issue is
+    /// caused by a single instruction.
+    pub program_counter: Option<usize>,
+    /// Description of the issue.
+    pub description: String,
+}
+
+impl<'a> Function<'a> {
+    /// Parse a Lua 5.1 function and run the static checks that the Lua 5.1
+    /// loader runs on untrusted byte code. Issues are collected rather than
+    /// returned, so all of them can be reported at once. Returns the upvalue
+    /// count of the function, which is needed to check the `CLOSURE`
+    /// instruction of the parent.
+    pub(crate) fn validate(
+        byte_stream: &mut ByteStream<'a>,
+        settings: &Settings,
+        path: &mut Vec<usize>,
+        issues: &mut Vec<ValidationIssue>,
+    ) -> Result<u8, LunifyError> {
+        let mut issue = |program_counter: Option<usize>, description: String| {
+            issues.push(ValidationIssue {
+                path: path.as_slice().into(),
+                program_counter,
+                description,
+            })
+        };
+
+        let _source_file = byte_stream.string()?;
+        let _line_defined = byte_stream.integer()?;
+        let _last_line_defined = byte_stream.integer()?;
+        let upvalue_count = byte_stream.byte()?;
+        let parameter_count = byte_stream.byte()?;
+        let is_variadic = byte_stream.byte()?;
+        let maximum_stack_size = byte_stream.byte()?;
+
+        if maximum_stack_size as u64 > settings.output.stack_limit {
+            issue(None, format!("stack size {maximum_stack_size} is bigger than {}", settings.output.stack_limit));
+        }
+
+        if parameter_count as u64 + (is_variadic & VARARG_HASARG) as u64 > maximum_stack_size as u64 {
+            issue(None, format!("{parameter_count} parameters don't fit into a stack of size {maximum_stack_size}"));
+        }
+
+        if is_variadic & VARARG_NEEDSARG != 0 && is_variadic & VARARG_HASARG == 0 {
+            issue(None, "function needs an `arg` table but doesn't have one".to_owned());
+        }
+
+        let (instructions, extended_instructions, _) = Self::get_instructions(
+            byte_stream,
+            settings,
+            &settings.lua51.layout,
+            lua51::Instruction::extended_argument,
+            None,
+        )?;
+
+        // Jumps are relative to the instruction slots, which includes the slots holding
+        // extended arguments, so we keep track of the slot of every instruction.
+        let mut slots = Vec::with_capacity(instructions.len());
+        let mut slot = 0;
+        for index in 0..instructions.len() {
+            slots.push(slot);
+            slot += 1 + extended_instructions.contains(&index) as usize;
+        }
+        let slot_count = slot;
+
+        let constants = Self::get_constants(byte_stream)?;
+        let function_count = byte_stream.count()? as usize;
+
+        let mut closures = Vec::new();
+
+        for (index, instruction) in instructions.iter().enumerate() {
+            let program_counter = slots.get(index).copied();
+            let next_instruction = instructions.get(index + 1);
+
+            let registers = [instruction.stack_destination(), instruction.stack_source()];
+            if let Some(register) = registers.into_iter().flatten().map(|range| range.end).max() {
+                if register >= maximum_stack_size as u64 {
+                    issue(program_counter, format!("register {register} is outside of the stack of size {maximum_stack_size}"));
+                }
+            }
+
+            let mut operands_instruction = *instruction;
+            let constant_operands = operands_instruction.constant_operands_mut();
+
+            for &mut ConstantRegister(constant, _) in constant_operands.into_iter().filter(|operand| operand.1) {
+                if constant as usize >= constants.len() {
+                    issue(program_counter, format!("constant {constant} doesn't exist"));
+                }
+            }
+
+            match *instruction {
+                lua51::Instruction::LoadK { mode: ConstantIndex(constant), .. } if constant as usize >= constants.len() => {
+                    issue(program_counter, format!("constant {constant} doesn't exist"));
+                }
+                lua51::Instruction::GetGlobal { mode: ConstantIndex(constant), .. }
+                | lua51::Instruction::SetGlobal { mode: ConstantIndex(constant), .. }
+                    if !matches!(constants.get(constant as usize), Some(Constant::String(_))) =>
+                {
+                    issue(program_counter, format!("constant {constant} is not a string"));
+                }
+                lua51::Instruction::GetUpValue { mode: BC(b, _), .. } | lua51::Instruction::SetUpValue { mode: BC(b, _), .. }
+                    if b.0 >= upvalue_count as u64 =>
+                {
+                    issue(program_counter, format!("upvalue {} doesn't exist", b.0));
+                }
+                lua51::Instruction::Jump { mode: SignedBx(offset), .. }
+                | lua51::Instruction::ForLoop { mode: SignedBx(offset), .. }
+                | lua51::Instruction::ForPrep { mode: SignedBx(offset), .. } => {
+                    let destination = program_counter.map(|program_counter| program_counter as i64 + 1 + offset);
+                    let is_valid = destination
+                        .and_then(|destination| usize::try_from(destination).ok())
+                        .is_some_and(|destination| slots.contains(&destination));
+
+                    if !is_valid {
+                        issue(program_counter, format!("jump by {offset} doesn't land on an instruction"));
+                    }
+                }
+                lua51::Instruction::Equals { .. }
+                | lua51::Instruction::LessThan { .. }
+                | lua51::Instruction::LessEquals { .. }
+                | lua51::Instruction::Test { .. }
+                | lua51::Instruction::TestSet { .. }
+                | lua51::Instruction::TForLoop { .. }
+                    if !matches!(next_instruction, Some(lua51::Instruction::Jump { .. })) =>
+                {
+                    issue(program_counter, format!("{} is not followed by a JMP", instruction.name()));
+                }
+                lua51::Instruction::LoadBool { mode: BC(_, Generic(c)), .. } if c != 0 && index + 2 >= instructions.len() => {
+                    issue(program_counter, "LOADBOOL skips past the end of the function".to_owned());
+                }
+                lua51::Instruction::Concatinate { mode: BC(b, c), .. } if b.0 >= c.0 => {
+                    issue(program_counter, format!("CONCAT of the empty range {}..{}", b.0, c.0));
+                }
+                lua51::Instruction::VarArg { .. } if is_variadic & VARARG_ISVARARG == 0 => {
+                    issue(program_counter, "VARARG in a function that is not variadic".to_owned());
+                }
+                lua51::Instruction::Closure {
+                    mode: PrototypeIndex(function_index),
+                    ..
+                } => match function_index as usize >= function_count {
+                    true => issue(program_counter, format!("function {function_index} doesn't exist")),
+                    false => closures.push((index, function_index as usize)),
+                },
+                _ => {}
+            }
+        }
+
+        if !matches!(instructions.last(), Some(lua51::Instruction::Return { .. })) {
+            issue(None, "function doesn't end with a RETURN".to_owned());
+        }
+
+        let mut function_upvalue_counts = Vec::with_capacity(function_count);
+        for index in 0..function_count {
+            path.push(index);
+            let function_upvalue_count = Self::validate(byte_stream, settings, path, issues)?;
+            path.pop();
+            function_upvalue_counts.push(function_upvalue_count);
+        }
+
+        let line_info = Self::get_line_info(byte_stream)?;
+        let _local_variables = Self::get_local_variables(byte_stream)?;
+        let upvalues = Self::get_upvalues(byte_stream)?;
+
+        let trailer_spec = settings.output.function_trailer.or(settings.lua51.function_trailer);
+        if let Some(spec) = trailer_spec.filter(|_| settings.output.function_trailer_mode != super::FunctionTrailerMode::Drop) {
+            spec.read(byte_stream)?;
+        }
+
+        let mut issue = |program_counter: Option<usize>, description: String| {
+            issues.push(ValidationIssue {
+                path: path.as_slice().into(),
+                program_counter,
+                description,
+            })
+        };
+
+        // A `CLOSURE` is followed by one `MOVE` or `GETUPVAL` for every upvalue of the
+        // new function.
+        for (index, function_index) in closures {
+            let expected = function_upvalue_counts.get(function_index).copied().unwrap_or(0) as usize;
+            let pseudo_instructions = instructions.iter().skip(index + 1).take(expected);
+            let found = pseudo_instructions
+                .take_while(|instruction| matches!(instruction, lua51::Instruction::Move { .. } | lua51::Instruction::GetUpValue { .. }))
+                .count();
+
+            if found != expected {
+                let description = format!("CLOSURE needs {expected} upvalue instructions but only {found} follow");
+                issue(slots.get(index).copied(), description);
+            }
+        }
+
+        if !line_info.is_empty() && line_info.len() != slot_count {
+            issue(None, format!("{} line numbers for {slot_count} instructions", line_info.len()));
+        }
+
+        if !upvalues.is_empty() && upvalues.len() != upvalue_count as usize {
+            issue(None, format!("{} upvalue names for {upvalue_count} upvalues", upvalues.len()));
+        }
+
+        Ok(upvalue_count)
+    }
+}
@@ -2,27 +2,683 @@
 #![deny(rustdoc::broken_intra_doc_links)]
 #![deny(missing_docs)]
 
+mod converter;
+mod describe;
+mod diagnostic;
+mod encoding;
 mod error;
 mod number;
+mod padding;
 #[macro_use]
 mod serialization;
 mod format;
+#[cfg(feature = "fs")]
+mod fs;
 mod function;
+mod metadata;
+mod path;
+mod progress;
+mod report;
+mod scan;
+#[cfg(feature = "test-utils")]
+pub mod testing;
 
-pub use error::LunifyError;
-pub use format::{BitWidth, Endianness, Format};
+use std::borrow::Cow;
+
+pub use converter::Converter;
+pub use describe::{FormatDescription, LayoutDescription, Lua50Description, Lua51Description, OperandDescription, SettingsDescription};
+pub use diagnostic::{Diagnostic, DiagnosticCallback, DiagnosticCode, Severity};
+pub use encoding::{decode_integer, decode_number, decode_string, encode_integer, encode_number, encode_string};
+pub use error::{FunctionError, InsertionReason, InstructionField, LunifyError};
+pub use format::{BitWidth, ChunkFacts, Endianness, Format, LuaVersion};
+#[cfg(feature = "fs")]
+pub use fs::{unify_dir, unify_file, FileOutcome, FileSummary, LunifyFileError, UnifySummary};
 use function::Function;
-pub use function::{lua50, lua51, InstructionLayout, OperandType, Settings};
+pub use function::{
+    lua50, lua51, transcode_instruction, validate_output, ConstantValue, ConversionLimits, FunctionInfo, FunctionTrailerMode,
+    FunctionTrailerSpec, InstructionLayout, InstructionView, LineOverflowPolicy, LuaconfReport, OperandType, OperandValue, RawChunk,
+    RawFunction, Settings, SettingsBuilder, SourceRewrite, ValidationIssue,
+};
+#[cfg(feature = "metadata")]
+pub use metadata::{read_metadata, Metadata};
+pub use padding::TrailingPadding;
+pub use path::FunctionPath;
+pub use progress::{Phase, Progress, ProgressCallback};
+pub use report::{ConversionReport, FunctionReport, FunctionSpan};
+pub use scan::{scan, ChunkLocation};
 
-use crate::format::LuaVersion;
+use crate::diagnostic::report_diagnostic;
+use crate::padding::{trailing_padding, write_padding};
+use crate::progress::report_progress;
 use crate::serialization::{ByteStream, ByteWriter};
 
 /// Takes Lua byte code in a supported format and converts it to byte code in
 /// the specified output [`Format`]. Returns [`LunifyError`] on error.
-pub fn unify(input_bytes: &[u8], output_format: &Format, settings: &Settings) -> Result<Vec<u8>, LunifyError> {
+///
+/// Functions are written as soon as they are converted, so only the function
+/// that is being converted and its ancestors are kept in memory.
+///
+/// The settings are validated on every call. Use a [`Converter`] to convert
+/// many chunks with the same settings.
+pub fn unify(input_bytes: impl AsRef<[u8]>, output_format: &Format, settings: &Settings) -> Result<Vec<u8>, LunifyError> {
+    Converter::new(*settings)?.unify(input_bytes, output_format)
+}
+
+/// Same as [`unify`], but returns the input as is instead of copying it if it
+/// is already in the output format and nothing needs to be rewritten.
+pub fn unify_cow<'a>(
+    input_bytes: &'a (impl AsRef<[u8]> + ?Sized),
+    output_format: &Format,
+    settings: &Settings,
+) -> Result<Cow<'a, [u8]>, LunifyError> {
+    Converter::new(*settings)?.unify_cow(input_bytes, output_format)
+}
+
+/// Same as [`unify`], but also returns a [`ConversionReport`] with information
+/// about the converted functions. If the input is already in the output
+/// format, it is returned as is and the report is empty.
+///
+/// Unlike [`unify`], all functions are parsed and converted before any of them
+/// is written, so the whole chunk is kept in memory.
+pub fn unify_with_report(
+    input_bytes: impl AsRef<[u8]>,
+    output_format: &Format,
+    settings: &Settings,
+) -> Result<(Vec<u8>, ConversionReport), LunifyError> {
+    Converter::new(*settings)?.unify_with_report(input_bytes, output_format)
+}
+
+/// Same as [`unify`], but nested functions that fail to parse or convert are
+/// replaced by a stub that returns right away, so the rest of the chunk can
+/// still be loaded. Every replaced function is reported as a
+/// [`FunctionError`]. If the main function or the chunk as a whole can't be
+/// converted, no bytes are returned.
+///
+/// Unlike [`unify`], all functions are parsed and converted before any of them
+/// is written, and the input is never returned as is.
+pub fn unify_lenient(input_bytes: impl AsRef<[u8]>, output_format: &Format, settings: &Settings) -> (Option<Vec<u8>>, Vec<FunctionError>) {
+    match Converter::new(*settings) {
+        Ok(converter) => converter.unify_lenient(input_bytes, output_format),
+        Err(error) => (None, vec![FunctionError {
+            path: FunctionPath::root(),
+            error,
+        }]),
+    }
+}
+
+/// Same as [`unify`], but returns the output without the prefix and the header,
+/// so only the main function is left, followed by anything written after it.
+/// This is useful for writing a single header in front of the bodies of many
+/// chunks. The header can be created with [`Format::lua51_header_bytes`], and
+/// a body can be parsed again with [`RawChunk::parse_body`].
+///
+/// # Example
+///
+/// ```rust
+/// use lunify::{unify, unify_body, Format, LunifyError, Settings};
+///
+/// # fn main() -> Result<(), LunifyError> {
+/// # let input_bytes = include_bytes!("../test_files/lua50.luab");
+/// let settings = Settings::default();
+/// let header = Format::default().lua51_header_bytes(settings.output.binary_signature);
+/// let body = unify_body(input_bytes, &Format::default(), &settings)?;
+///
+/// assert_eq!([header, body].concat(), unify(input_bytes, &Format::default(), &settings)?);
+/// # Ok(())
+/// # }
+/// ```
+pub fn unify_body(input_bytes: impl AsRef<[u8]>, output_format: &Format, settings: &Settings) -> Result<Vec<u8>, LunifyError> {
+    Converter::new(*settings)?.unify_body(input_bytes, output_format)
+}
+
+fn convert<'a>(
+    input_bytes: &'a [u8],
+    output_format: &Format,
+    settings: &Settings,
+    is_streaming: bool,
+    isolated_errors: Option<&mut Vec<FunctionError>>,
+) -> Result<(Cow<'a, [u8]>, ConversionReport), LunifyError> {
+    validate_output(output_format, &settings.output)?;
+
+    let (mut byte_stream, version, input_format) = read_header(input_bytes, settings)?;
+    let settings = &input_settings(input_bytes, version, settings)?;
+    validate_skipped_functions(version, &input_format, output_format, settings)?;
+
+    // If the input is already in the correct format and nothing needs to be
+    // rewritten, return it as is. Functions that don't need to be rewritten are
+    // copied as is otherwise, so only the affected functions are encoded again.
+    let is_rewritten = settings.lua51.fields_per_flush != settings.output.fields_per_flush
+        || settings.lua51.layout != settings.output.layout
+        || settings.output.rewrite_source.is_some()
+        || settings.output.root_source_override.is_some()
+        || settings.output.zero_root_lines
+        || settings.output.function_trailer.is_some()
+        || settings.output.function_trailer_mode != FunctionTrailerMode::Keep
+        || settings.output.fold_constants
+        || settings.output.max_output_size.is_some()
+        // The header might not match the encoding of the input.
+        || settings.endianness_override.is_some()
+        || settings.auto_detect_endianness
+        || !settings.output.disallowed_opcodes.is_empty()
+        || !settings.output.rename_globals.is_empty();
+
+    #[cfg(feature = "metadata")]
+    let is_rewritten = is_rewritten || settings.output.append_metadata;
+
+    // Functions are only isolated if they are converted, so a lenient conversion
+    // never passes the input through.
+    let is_lenient = isolated_errors.is_some();
+
+    // Counting the functions only skips over them, which is cheap compared to
+    // converting them, but there is no point in doing it if nobody is listening.
+    if settings.progress.is_some() {
+        let functions_total = Function::list(&mut byte_stream.clone(), version, settings).map(|functions| functions.len());
+        byte_stream.set_functions_total(functions_total.ok());
+    }
+    report_progress(&mut byte_stream, Phase::ParsingHeader, settings);
+
+    // Input that could be passed through is still decoded, so it is validated and
+    // repaired the same way as any other input, and its padding is checked against
+    // the policy. It is only passed through if no function had to be changed and
+    // the padding doesn't need to be dropped, otherwise the decoded functions are
+    // written.
+    let is_pass_through = input_format == *output_format && !is_rewritten && !is_lenient && is_pass_through_enabled();
+    let (decoded_function, decoded_padding) = match is_pass_through {
+        true => {
+            let root_function = Function::from_byte_stream(&mut byte_stream, version, settings)?;
+            let padding = trailing_padding(&byte_stream, settings)?;
+            (Some(root_function), Some(padding))
+        }
+        false => (None, None),
+    };
+
+    let is_padding_kept = decoded_padding.is_some_and(|padding| padding.is_empty() || settings.output.preserve_padding);
+    if is_padding_kept && decoded_function.as_ref().is_some_and(Function::is_untouched) {
+        #[cfg(feature = "debug")]
+        println!("\n======== Done ========\n");
+
+        if settings.output.verify {
+            validate(input_bytes, settings).map_err(LunifyError::InvalidOutput)?;
+        }
+
+        let output_bytes = match settings.output.preserve_prefix {
+            true => input_bytes,
+            false => split_prefix(input_bytes, settings).1,
+        };
+
+        return Ok((Cow::Borrowed(output_bytes), ConversionReport::default()));
+    }
+
+    // The output is usually about the same size as the input, so we reserve that
+    // much up front to avoid reallocating while writing.
+    let mut byte_writer = ByteWriter::with_capacity(output_format, input_bytes.len());
+    write_prefix(&mut byte_writer, input_bytes, settings);
+    write_header(&mut byte_writer, output_format, settings);
+
+    let mut report = ConversionReport::default();
+
+    match is_streaming && !is_lenient && decoded_function.is_none() {
+        true => Function::convert_function(
+            &mut byte_stream,
+            &mut byte_writer,
+            version,
+            settings,
+            None,
+            &mut Vec::new(),
+            &mut report.functions,
+        )?,
+        false => {
+            let root_function = match (decoded_function, isolated_errors) {
+                (Some(root_function), _) => root_function,
+                (None, Some(isolated_errors)) => {
+                    let root_function = Function::from_byte_stream_lenient(&mut byte_stream, version, settings)?;
+                    root_function.isolated_errors(&mut Vec::new(), isolated_errors);
+                    root_function
+                }
+                (None, None) => Function::from_byte_stream(&mut byte_stream, version, settings)?,
+            };
+            root_function.report(output_format, &mut Vec::new(), &mut report.functions, settings)?;
+
+            let mut sizes = Vec::new();
+            root_function.write(&mut byte_writer, &mut sizes)?;
+
+            for (function, (size, span)) in report.functions.iter_mut().zip(sizes) {
+                function.size = size;
+                function.span = span;
+            }
+        }
+    }
+
+    // The byte stream starts after the prefix, but the input spans count from the
+    // start of the input.
+    let prefix_length = split_prefix(input_bytes, settings).0.len();
+    for function in &mut report.functions {
+        if let Some(input) = &mut function.span.input {
+            *input = input.start + prefix_length..input.end + prefix_length;
+        }
+    }
+
+    let padding = match decoded_padding {
+        Some(padding) => padding,
+        None => trailing_padding(&byte_stream, settings)?,
+    };
+
+    report_progress(&mut byte_stream, Phase::Writing, settings);
+
+    #[cfg(feature = "metadata")]
+    if settings.output.append_metadata {
+        metadata::write_metadata(&mut byte_writer, version, settings)?;
+    }
+    write_padding(&mut byte_writer, padding, settings);
+
+    let output_bytes = byte_writer.finalize();
+
+    if let Some(limit) = settings.output.max_output_size {
+        if output_bytes.len() > limit {
+            let mut largest_functions: Vec<_> = report.functions.iter().map(|function| (function.path.clone(), function.size)).collect();
+            largest_functions.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+            largest_functions.truncate(5);
+
+            return Err(LunifyError::OutputTooLarge {
+                size: output_bytes.len(),
+                limit,
+                largest_functions,
+            });
+        }
+    }
+
+    if settings.output.verify {
+        validate(&output_bytes, settings).map_err(LunifyError::InvalidOutput)?;
+    }
+
+    #[cfg(feature = "debug")]
+    println!("======== Done ========\n");
+
+    Ok((Cow::Owned(output_bytes), report))
+}
+
+/// Tests convert every chunk, even if it is already in the output format, so
+/// the conversion is exercised. Tests of the pass through opt back in.
+#[cfg(not(test))]
+fn is_pass_through_enabled() -> bool {
+    true
+}
+
+#[cfg(test)]
+fn is_pass_through_enabled() -> bool {
+    tests::PASS_THROUGH.with(std::cell::Cell::get)
+}
+
+/// Takes Lua byte code in a supported format and converts the nested function
+/// at `path` to a standalone chunk in the specified output [`Format`], with
+/// the function as its main function. `path` holds the indices of the nested
+/// functions that lead to the function, like the paths returned by
+/// [`list_functions`]. Only the function and its nested functions are
+/// decoded and converted.
+///
+/// Functions that capture upvalues can't be extracted unless
+/// `extract_closures` is set in the settings, otherwise
+/// [`LunifyError::CannotExtractClosure`] is returned.
+pub fn extract(input_bytes: impl AsRef<[u8]>, path: &[usize], output_format: &Format, settings: &Settings) -> Result<Vec<u8>, LunifyError> {
+    let input_bytes = input_bytes.as_ref();
+    validate_output(output_format, &settings.output)?;
+
+    let (mut byte_stream, version, input_format) = read_header(input_bytes, settings)?;
+    let settings = &input_settings(input_bytes, version, settings)?;
+    validate_skipped_functions(version, &input_format, output_format, settings)?;
+    let function = Function::extract(&mut byte_stream, version, settings, path)?;
+
+    let mut byte_writer = ByteWriter::new(output_format);
+    write_prefix(&mut byte_writer, input_bytes, settings);
+    write_header(&mut byte_writer, output_format, settings);
+    function.write(&mut byte_writer, &mut Vec::new())?;
+
+    #[cfg(feature = "metadata")]
+    if settings.output.append_metadata {
+        metadata::write_metadata(&mut byte_writer, version, settings)?;
+    }
+
+    let output_bytes = byte_writer.finalize();
+
+    if settings.output.verify {
+        validate(&output_bytes, settings).map_err(LunifyError::InvalidOutput)?;
+    }
+
+    Ok(output_bytes)
+}
+
+/// Takes Lua byte code in a supported format, replaces the constant at `index`
+/// of the function at `path` with `value` and converts it to the specified
+/// output [`Format`]. `path` holds the indices of the nested functions that
+/// lead to the function, like the paths returned by [`list_functions`].
+///
+/// The constant is replaced after converting, so `index` refers to the
+/// constants of the output. Conversion only appends constants, so they are the
+/// same as the ones of the input, unless the output settings rearrange them,
+/// for example if `synthetic_constants_last` is unset.
+///
+/// The value needs to have the same type as the constant it replaces, unless
+/// `allow_constant_type_change` is set in the settings, otherwise
+/// [`LunifyError::ConstantTypeMismatch`] is returned.
+///
+/// # Example
+///
+/// ```rust
+/// use lunify::{set_constant, ConstantValue, Format, FunctionPath, LunifyError, Settings};
+///
+/// # fn main() -> Result<(), LunifyError> {
+/// # let input_bytes = include_bytes!("../test_files/constants.luab");
+/// let value = ConstantValue::String(b"https://example.com".to_vec());
+/// let _output_bytes = set_constant(input_bytes, &FunctionPath::root(), 0, value, &Format::default(), &Settings::default())?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn set_constant(
+    input_bytes: impl AsRef<[u8]>,
+    path: &FunctionPath,
+    index: usize,
+    value: ConstantValue,
+    output_format: &Format,
+    settings: &Settings,
+) -> Result<Vec<u8>, LunifyError> {
+    let input_bytes = input_bytes.as_ref();
+    validate_output(output_format, &settings.output)?;
+
+    let (mut byte_stream, version, input_format) = read_header(input_bytes, settings)?;
+    let settings = &input_settings(input_bytes, version, settings)?;
+    validate_skipped_functions(version, &input_format, output_format, settings)?;
+
+    let mut function = Function::from_byte_stream(&mut byte_stream, version, settings)?;
+    let padding = trailing_padding(&byte_stream, settings)?;
+    function.set_constant(path, index, value, settings)?;
+
+    let mut byte_writer = ByteWriter::new(output_format);
+    write_prefix(&mut byte_writer, input_bytes, settings);
+    write_header(&mut byte_writer, output_format, settings);
+    function.write(&mut byte_writer, &mut Vec::new())?;
+
+    #[cfg(feature = "metadata")]
+    if settings.output.append_metadata {
+        metadata::write_metadata(&mut byte_writer, version, settings)?;
+    }
+    write_padding(&mut byte_writer, padding, settings);
+
+    let output_bytes = byte_writer.finalize();
+
+    if settings.output.verify {
+        validate(&output_bytes, settings).map_err(LunifyError::InvalidOutput)?;
+    }
+
+    Ok(output_bytes)
+}
+
+/// Converts Lua byte code in a supported format and inserts the main function
+/// of `function_bytes` as the nested function at `index` of the function at
+/// `parent`. `parent` holds the indices of the nested functions that lead to
+/// the function, like the paths returned by [`list_functions`].
+///
+/// The inserted function is converted with the same settings as the rest of
+/// the byte code, so it can be in a different version or [`Format`] than the
+/// input. `CLOSURE` instructions of the parent that reference a nested
+/// function at or after `index` are updated to keep referencing the same
+/// function, but creating a closure of the inserted function is up to the
+/// caller.
+///
+/// # Example
+///
+/// ```rust
+/// use lunify::{insert_function, Format, FunctionPath, LunifyError, Settings};
+///
+/// # fn main() -> Result<(), LunifyError> {
+/// # let input_bytes = include_bytes!("../test_files/lua50.luab");
+/// # let function_bytes = include_bytes!("../test_files/big_endian.luab");
+/// let parent = FunctionPath::root();
+/// let _output_bytes = insert_function(input_bytes, &parent, 0, function_bytes, &Format::default(), &Settings::default())?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn insert_function(
+    input_bytes: impl AsRef<[u8]>,
+    parent: &FunctionPath,
+    index: usize,
+    function_bytes: impl AsRef<[u8]>,
+    output_format: &Format,
+    settings: &Settings,
+) -> Result<Vec<u8>, LunifyError> {
+    let input_bytes = input_bytes.as_ref();
+    let function_bytes = function_bytes.as_ref();
+    validate_output(output_format, &settings.output)?;
+
+    // The paths of skipped functions refer to the input, so they don't apply to
+    // the inserted function.
+    let (mut function_stream, function_version, _) = read_header(function_bytes, settings)?;
+    let function_settings = Settings {
+        skip_function_paths: &[],
+        ..input_settings(function_bytes, function_version, settings)?
+    };
+
+    let (mut byte_stream, version, input_format) = read_header(input_bytes, settings)?;
+    let settings = &input_settings(input_bytes, version, settings)?;
+    validate_skipped_functions(version, &input_format, output_format, settings)?;
+
+    let mut function = Function::from_byte_stream(&mut byte_stream, version, settings)?;
+    let padding = trailing_padding(&byte_stream, settings)?;
+
+    // The padding of the inserted function is never written to the output.
+    let inserted_function = Function::from_byte_stream(&mut function_stream, function_version, &function_settings)?;
+    trailing_padding(&function_stream, &function_settings)?;
+    function.insert_function(parent, index, inserted_function, settings)?;
+
+    let mut byte_writer = ByteWriter::new(output_format);
+    write_prefix(&mut byte_writer, input_bytes, settings);
+    write_header(&mut byte_writer, output_format, settings);
+    function.write(&mut byte_writer, &mut Vec::new())?;
+
+    #[cfg(feature = "metadata")]
+    if settings.output.append_metadata {
+        metadata::write_metadata(&mut byte_writer, version, settings)?;
+    }
+    write_padding(&mut byte_writer, padding, settings);
+
+    let output_bytes = byte_writer.finalize();
+
+    if settings.output.verify {
+        validate(&output_bytes, settings).map_err(LunifyError::InvalidOutput)?;
+    }
+
+    Ok(output_bytes)
+}
+
+/// Lists every function in Lua byte code in a supported format in depth-first
+/// order, starting with the main function, without decoding their
+/// instructions. The paths can be passed to [`extract`].
+pub fn list_functions(input_bytes: impl AsRef<[u8]>, settings: &Settings) -> Result<Vec<FunctionInfo>, LunifyError> {
+    let input_bytes = input_bytes.as_ref();
+    let (mut byte_stream, version, _) = read_header(input_bytes, settings)?;
+    let functions = Function::list(&mut byte_stream, version, settings)?;
+    trailing_padding(&byte_stream, settings)?;
+    Ok(functions)
+}
+
+/// Collects the [`ChunkFacts`] of Lua byte code in a supported format without
+/// decoding its instructions, to check if the chunk needs to be converted with
+/// [`Format::compatible_with`].
+pub fn chunk_facts(input_bytes: impl AsRef<[u8]>, settings: &Settings) -> Result<ChunkFacts, LunifyError> {
+    let (mut byte_stream, version, _) = read_header(input_bytes.as_ref(), settings)?;
+    let functions = Function::list(&mut byte_stream, version, settings)?;
+    trailing_padding(&byte_stream, settings)?;
+
+    Ok(ChunkFacts {
+        version,
+        has_number_constants: functions.iter().any(|function| function.number_constant_count > 0),
+    })
+}
+
+/// Runs the static checks of the Lua 5.1 loader on converted byte code without
+/// needing a Lua interpreter. The byte code is expected to match the output
+/// settings. Returns every [`ValidationIssue`] that was found, or a single
+/// issue if the byte code can't be parsed.
+pub fn validate(output_bytes: impl AsRef<[u8]>, settings: &Settings) -> Result<(), Vec<ValidationIssue>> {
+    let output_bytes = output_bytes.as_ref();
+    let settings = &output_reading_settings(settings);
+
+    let mut path = Vec::new();
+    let mut issues = Vec::new();
+
+    let result = read_header(output_bytes, settings).and_then(|(mut byte_stream, version, _)| {
+        if version != LuaVersion::Lua51 {
+            return Err(LunifyError::UnsupportedVersion(version.into()));
+        }
+
+        Function::validate(&mut byte_stream, settings, &mut path, &mut issues)?;
+        trailing_padding(&byte_stream, settings).map(|_| ())
+    });
+
+    if let Err(error) = result {
+        issues.push(ValidationIssue {
+            path: path.into(),
+            program_counter: None,
+            description: format!("failed to parse byte code: {error:?}"),
+        });
+    }
+
+    match issues.is_empty() {
+        true => Ok(()),
+        false => Err(issues),
+    }
+}
+
+/// Scans Lua 5.0 byte code for table constructors that span multiple
+/// `SETLIST` instructions to find out which `LFIELDS_PER_FLUSH` the compiler
+/// was built with. Returns `None` if the input is not Lua 5.0 byte code, if
+/// there are no such constructors, or if they don't agree on a value.
+pub fn detect_lua50_fields_per_flush(input_bytes: impl AsRef<[u8]>, settings: &Settings) -> Result<Option<u64>, LunifyError> {
+    let input_bytes = input_bytes.as_ref();
+    let (mut byte_stream, version, _) = read_header(input_bytes, settings)?;
+
+    if version != LuaVersion::Lua50 {
+        return Ok(None);
+    }
+
+    let mut flush_sizes = Vec::new();
+    Function::lua50_flush_sizes(&mut byte_stream, settings, &mut flush_sizes)?;
+
+    match flush_sizes.split_first() {
+        Some((first, rest)) if rest.iter().all(|flush_size| flush_size == first) => Ok(Some(*first)),
+        _ => Ok(None),
+    }
+}
+
+/// The settings used to read the input. If the Lua 5.0 `LFIELDS_PER_FLUSH`
+/// should be detected, we override the setting if we are confident about the
+/// value.
+fn input_settings<'a>(input_bytes: &[u8], version: LuaVersion, settings: &Settings<'a>) -> Result<Settings<'a>, LunifyError> {
+    let mut settings = *settings;
+    if version == LuaVersion::Lua50 && settings.lua50.auto_detect_fields_per_flush {
+        if let Some(fields_per_flush) = detect_lua50_fields_per_flush(input_bytes, &settings)? {
+            settings.lua50.fields_per_flush = fields_per_flush;
+        }
+    }
+    Ok(settings)
+}
+
+/// The settings used to read byte code produced by Lunify again. Instructions
+/// are decoded with the input settings, so we use the output settings in their
+/// place, and the output is always encoded like its header says.
+pub(crate) fn output_reading_settings<'a>(settings: &Settings<'a>) -> Settings<'a> {
+    Settings {
+        lua51: settings.output,
+        endianness_override: None,
+        auto_detect_endianness: false,
+        ..*settings
+    }
+}
+
+/// Check that the functions at the [`skip_function_paths`](Settings::skip_function_paths)
+/// can be copied to the output as they are in the input.
+fn validate_skipped_functions(
+    version: LuaVersion,
+    input_format: &Format,
+    output_format: &Format,
+    settings: &Settings,
+) -> Result<(), LunifyError> {
+    if settings.skip_function_paths.is_empty() {
+        return Ok(());
+    }
+
+    if version != LuaVersion::Lua51 {
+        return Err(LunifyError::IncompatibleOutputConfiguration(
+            "skipped functions can only be copied from Lua 5.1 byte code",
+        ));
+    }
+
+    if !input_format.has_same_encoding(output_format) || settings.lua51.layout != settings.output.layout {
+        return Err(LunifyError::IncompatibleOutputConfiguration(
+            "skipped functions can only be copied if the input is encoded like the output",
+        ));
+    }
+
+    let is_trailer_kept = settings.output.function_trailer_mode == FunctionTrailerMode::Keep
+        && settings.output.function_trailer.is_none_or(|spec| Some(spec) == settings.lua51.function_trailer);
+    if !is_trailer_kept {
+        return Err(LunifyError::IncompatibleOutputConfiguration(
+            "skipped functions can only be copied if the function trailers are kept",
+        ));
+    }
+
+    Ok(())
+}
+
+fn write_prefix(byte_writer: &mut ByteWriter, input_bytes: &[u8], settings: &Settings) {
+    if settings.output.preserve_prefix {
+        byte_writer.slice(split_prefix(input_bytes, settings).0);
+    }
+}
+
+fn write_header(byte_writer: &mut ByteWriter, output_format: &Format, settings: &Settings) {
+    byte_writer.slice(&output_format.lua51_header_bytes(settings.output.binary_signature));
+}
+
+/// Maximum length of the prefix that is skipped in front of the signature.
+const MAXIMUM_PREFIX_LENGTH: usize = 1024;
+
+/// Split the input into the prefix in front of the signature and the byte code.
+/// The prefix is an optional UTF-8 byte order mark followed by an optional
+/// first line starting with `#`, like a shebang line that makes the file
+/// executable. Input that starts with a signature is never split, and neither is
+/// a prefix that is longer than [`MAXIMUM_PREFIX_LENGTH`].
+fn split_prefix<'a>(input_bytes: &'a [u8], settings: &Settings) -> (&'a [u8], &'a [u8]) {
+    let signatures = [settings.lua50.binary_signature, settings.lua51.binary_signature, settings.output.binary_signature];
+    if signatures.iter().any(|signature| input_bytes.starts_with(signature.as_bytes())) {
+        return (&[], input_bytes);
+    }
+
+    let mut length = match input_bytes.starts_with(b"\xef\xbb\xbf") {
+        true => 3,
+        false => 0,
+    };
+
+    if input_bytes.get(length) == Some(&b'#') {
+        let bounded = &input_bytes[length..input_bytes.len().min(MAXIMUM_PREFIX_LENGTH)];
+        match bounded.iter().position(|&byte| byte == b'\n') {
+            Some(end) => length += end + 1,
+            None => return (&[], input_bytes),
+        }
+    }
+
+    input_bytes.split_at(length)
+}
+
+fn read_header<'a>(input_bytes: &'a [u8], settings: &Settings) -> Result<(ByteStream<'a>, LuaVersion, Format), LunifyError> {
+    let (_, input_bytes) = split_prefix(input_bytes, settings);
     let mut byte_stream = ByteStream::new(input_bytes);
 
-    if !byte_stream.remove_signature(settings.lua50.binary_signature) && !byte_stream.remove_signature(settings.lua51.binary_signature) {
+    // Byte code that was already converted by Lunify might use a custom output
+    // signature, so we accept it as well in order to make the conversion idempotent.
+    if !byte_stream.remove_signature(settings.lua50.binary_signature)
+        && !byte_stream.remove_signature(settings.lua51.binary_signature)
+        && !byte_stream.remove_signature(settings.output.binary_signature)
+    {
         return Err(LunifyError::IncorrectSignature);
     }
 
@@ -34,51 +690,114 @@ pub fn unify(input_bytes: &[u8], output_format: &Format, settings: &Settings) ->
         println!("version: {version}");
     }
 
-    let input_format = Format::from_byte_stream(&mut byte_stream, version, settings)?;
+    let header_stream = byte_stream.clone();
+    let mut format = Format::from_byte_stream(&mut byte_stream, version, settings)?;
+    byte_stream.set_format(format);
 
-    // If the input is already in the correct format, return it as is.
-    if input_format == *output_format && !cfg!(test) {
-        #[cfg(feature = "debug")]
-        println!("\n======== Done ========\n");
-
-        return Ok(input_bytes.to_vec());
-    }
+    // Tools that only patch the header might leave the rest of the byte code in the
+    // opposite endianness. The header is read again, since the Lua 5.0 header
+    // contains a number in that endianness as well.
+    if settings.endianness_override.is_none() && settings.auto_detect_endianness && !is_plausible_function(&byte_stream, version) {
+        let endianness = match format.endianness {
+            Endianness::Big => Endianness::Little,
+            Endianness::Little => Endianness::Big,
+        };
+        let swapped_settings = Settings {
+            endianness_override: Some(endianness),
+            diagnostics: None,
+            ..*settings
+        };
 
-    byte_stream.set_format(input_format);
+        let mut swapped_stream = header_stream;
+        let swapped_format = Format::from_byte_stream(&mut swapped_stream, version, &swapped_settings)?;
+        swapped_stream.set_format(swapped_format);
 
-    let root_function = Function::from_byte_stream(&mut byte_stream, version, settings)?;
+        if is_plausible_function(&swapped_stream, version) {
+            report_diagnostic(settings, DiagnosticCode::EndiannessMismatch, &[], None, || {
+                format!("header declares {} but the input is {endianness}", format.endianness)
+            });
 
-    if !byte_stream.is_empty() {
-        return Err(LunifyError::InputTooLong);
+            byte_stream = swapped_stream;
+            format = swapped_format;
+        }
     }
 
-    let mut byte_writer = ByteWriter::new(output_format);
+    Ok((byte_stream, version, format))
+}
 
-    byte_writer.slice(settings.output.binary_signature.as_bytes());
-    byte_writer.byte(LuaVersion::Lua51.into());
-    output_format.write(&mut byte_writer);
-    root_function.write(&mut byte_writer)?;
+/// Check that the source file and the first count of the function at the start
+/// of the byte stream fit into the remaining bytes. This is the instruction
+/// count in Lua 5.1 and the line info count in Lua 5.0.
+fn is_plausible_function(byte_stream: &ByteStream, version: LuaVersion) -> bool {
+    let mut byte_stream = byte_stream.clone();
+    let format = byte_stream.format();
 
-    #[cfg(feature = "debug")]
-    println!("======== Done ========\n");
+    let entry_width = match version {
+        LuaVersion::Lua51 => u8::from(format.instruction_width),
+        LuaVersion::Lua50 => u8::from(format.integer_width),
+    };
 
-    Ok(byte_writer.finalize())
+    // The line info in Lua 5.0 follows the line where the function is defined and
+    // four bytes, in Lua 5.1 the instructions follow two lines and four bytes.
+    let count = byte_stream.string_slice().and_then(|_| {
+        if version == LuaVersion::Lua51 {
+            byte_stream.integer()?;
+        }
+        byte_stream.integer()?;
+        byte_stream.slice(4)?;
+        byte_stream.count()
+    });
+
+    count.is_ok_and(|count| count.saturating_mul(entry_width as u64) <= byte_stream.remaining().len() as u64)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{unify, Format, LunifyError};
-    use crate::{lua51, BitWidth, Endianness, Settings};
+    use std::borrow::Cow;
+    use std::cell::{Cell, RefCell};
+
+    use super::{
+        chunk_facts, convert, detect_lua50_fields_per_flush, encode_string, extract, list_functions, unify, unify_body, unify_cow,
+        unify_lenient, unify_with_report, validate, ConversionReport, Format, FunctionError, FunctionPath, LunifyError,
+    };
+    use crate::number::Number;
+    use crate::serialization::{ByteStream, ByteWriter};
+    use crate::{
+        lua50, lua51, BitWidth, ConversionLimits, Diagnostic, DiagnosticCallback, DiagnosticCode, Endianness, FunctionInfo,
+        FunctionTrailerMode, FunctionTrailerSpec, InstructionField, InstructionLayout, InstructionView, LuaVersion, OperandType,
+        OperandValue, Phase, Progress, ProgressCallback, RawChunk, Settings, Severity, TrailingPadding, ValidationIssue,
+    };
 
     #[cfg(feature = "integration")]
     fn test_output(byte_code: &[u8]) {
+        test_output_with_setup("", byte_code);
+    }
+
+    /// Same as `test_output`, but runs the script `setup` first, for example to
+    /// remove globals like a sandbox does.
+    #[cfg(feature = "integration")]
+    fn test_output_with_setup(setup: &str, byte_code: &[u8]) {
         use mlua::prelude::*;
 
         let lua = Lua::new();
+        lua.load(setup).exec().unwrap();
         lua.load(byte_code).exec().unwrap();
         assert_eq!(lua.globals().get::<_, LuaNumber>("result").unwrap(), 9.0);
     }
 
+    thread_local! {
+        /// Whether chunks that are already in the output format are returned as
+        /// is. See `is_pass_through_enabled`.
+        pub(super) static PASS_THROUGH: Cell<bool> = const { Cell::new(false) };
+    }
+
+    fn with_pass_through<T>(function: impl FnOnce() -> T) -> T {
+        PASS_THROUGH.with(|pass_through| pass_through.set(true));
+        let result = function();
+        PASS_THROUGH.with(|pass_through| pass_through.set(false));
+        result
+    }
+
     #[test]
     fn _32bit_to_64bit() -> Result<(), LunifyError> {
         let input_bytes = include_bytes!("../test_files/32bit.luab");
@@ -102,6 +821,77 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_files_within_limits() -> Result<(), LunifyError> {
+        // The test files should stay well below the default limits.
+        let settings = Settings {
+            limits: ConversionLimits {
+                max_instructions_per_function: 1 << 12,
+                max_total_instructions: 1 << 14,
+                max_setlist_scan_depth: 1 << 8,
+            },
+            ..Default::default()
+        };
+
+        let inputs: [&[u8]; 10] = [
+            include_bytes!("../test_files/32bit.luab"),
+            include_bytes!("../test_files/big_endian.luab"),
+            include_bytes!("../test_files/constants.luab"),
+            include_bytes!("../test_files/dynamic_table.luab"),
+            include_bytes!("../test_files/empty.luab"),
+            include_bytes!("../test_files/for_loop.luab"),
+            include_bytes!("../test_files/large_table.luab"),
+            include_bytes!("../test_files/little_endian.luab"),
+            include_bytes!("../test_files/lua50.luab"),
+            include_bytes!("../test_files/variadic.luab"),
+        ];
+
+        for input_bytes in inputs {
+            unify(input_bytes, &Format::default(), &settings)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn streaming_matches_tree() -> Result<(), LunifyError> {
+        let inputs: [&[u8]; 10] = [
+            include_bytes!("../test_files/32bit.luab"),
+            include_bytes!("../test_files/big_endian.luab"),
+            include_bytes!("../test_files/constants.luab"),
+            include_bytes!("../test_files/dynamic_table.luab"),
+            include_bytes!("../test_files/empty.luab"),
+            include_bytes!("../test_files/for_loop.luab"),
+            include_bytes!("../test_files/large_table.luab"),
+            include_bytes!("../test_files/little_endian.luab"),
+            include_bytes!("../test_files/lua50.luab"),
+            include_bytes!("../test_files/variadic.luab"),
+        ];
+        let (prototypes_bytes, _) = lua51_prototypes_bytes()?;
+
+        let mut rewriting_settings = prototypes_settings();
+        rewriting_settings.output.deduplicate_source = false;
+        let output_formats = [
+            Format::default(),
+            Format {
+                size_t_width: BitWidth::Bit32,
+                ..Default::default()
+            },
+        ];
+
+        for input_bytes in inputs.into_iter().chain([prototypes_bytes.as_slice()]) {
+            for settings in [Settings::default(), prototypes_settings(), rewriting_settings] {
+                for output_format in &output_formats {
+                    let streaming = convert(input_bytes, output_format, &settings, true, None)?;
+                    let tree = convert(input_bytes, output_format, &settings, false, None)?;
+                    assert_eq!(streaming, tree);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn matching_format_remains_unchanged() -> Result<(), LunifyError> {
         let input_bytes = include_bytes!("../test_files/32bit.luab");
@@ -138,6 +928,173 @@ mod tests {
         Ok(())
     }
 
+    /// Byte code whose header declares the opposite endianness of its payload,
+    /// together with the original byte code and the endianness of the payload.
+    fn mismatched_endianness_inputs() -> [(Vec<u8>, &'static [u8], Endianness); 2] {
+        let lua50_bytes: &[u8] = include_bytes!("../test_files/lua50.luab");
+        let lua51_bytes: &[u8] = include_bytes!("../test_files/big_endian.luab");
+
+        let mut lua50_mismatched = lua50_bytes.to_vec();
+        lua50_mismatched[5] = u8::from(Endianness::Big);
+        let mut lua51_mismatched = lua51_bytes.to_vec();
+        lua51_mismatched[6] = u8::from(Endianness::Little);
+
+        [
+            (lua50_mismatched, lua50_bytes, Endianness::Little),
+            (lua51_mismatched, lua51_bytes, Endianness::Big),
+        ]
+    }
+
+    #[test]
+    fn endianness_override() -> Result<(), LunifyError> {
+        for (input_bytes, original_bytes, endianness) in mismatched_endianness_inputs() {
+            let expected = unify(original_bytes, &Format::default(), &Settings::default())?;
+            assert!(unify(&input_bytes, &Format::default(), &Settings::default()).is_err());
+
+            let settings = Settings::builder().endianness_override(Some(endianness)).build()?;
+            assert_eq!(unify(&input_bytes, &Format::default(), &settings)?, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn auto_detect_endianness() -> Result<(), LunifyError> {
+        for (input_bytes, original_bytes, _) in mismatched_endianness_inputs() {
+            let diagnostics = RefCell::new(Vec::new());
+            let record = |diagnostic: Diagnostic| diagnostics.borrow_mut().push(diagnostic.code);
+            let settings = Settings::builder()
+                .auto_detect_endianness(true)
+                .diagnostics(Some(DiagnosticCallback(&record)))
+                .build()?;
+
+            let expected = unify(original_bytes, &Format::default(), &Settings::default())?;
+            assert_eq!(unify(&input_bytes, &Format::default(), &settings)?, expected);
+            assert_eq!(diagnostics.borrow().as_slice(), [DiagnosticCode::EndiannessMismatch]);
+
+            // Byte code that matches its header is left alone.
+            diagnostics.borrow_mut().clear();
+            assert_eq!(unify(original_bytes, &Format::default(), &settings)?, expected);
+            assert!(diagnostics.borrow().is_empty());
+        }
+        Ok(())
+    }
+
+    /// Lua 5.1 byte code whose main function has a single number constant.
+    fn lua51_number_bytes(format: &Format, number: f64) -> Result<Vec<u8>, LunifyError> {
+        // `RETURN 0 1`.
+        lua51_chunk_bytes(format, &TestFunction {
+            header: [0, 0, 2, 2],
+            instructions: &[lua51_abc(30, 0, 1, 0)],
+            constants: &[TestConstant::Number(number)],
+            ..Default::default()
+        })
+    }
+
+    /// Read the number constant of [`lua51_number_bytes`].
+    fn read_number_constant(bytes: &[u8], format: &Format) -> Result<Number, LunifyError> {
+        let mut byte_stream = ByteStream::new(bytes);
+        byte_stream.set_format(*format);
+
+        byte_stream.slice(12)?;
+        byte_stream.string()?;
+        byte_stream.slice(u8::from(format.integer_width) as usize * 2 + 4)?;
+        byte_stream.count()?;
+        byte_stream.instruction()?;
+        byte_stream.count()?;
+        byte_stream.byte()?;
+        byte_stream.number()
+    }
+
+    #[test]
+    fn body_round_trip() -> Result<(), LunifyError> {
+        let inputs: [&[u8]; 2] = [include_bytes!("../test_files/lua50.luab"), include_bytes!("../test_files/32bit.luab")];
+        let settings = Settings::builder().output_binary_signature("\x1bLuX").build()?;
+
+        for input_bytes in inputs {
+            let header = Format::default().lua51_header_bytes(settings.output.binary_signature);
+            let body = unify_body(input_bytes, &Format::default(), &settings)?;
+            let output_bytes = unify(input_bytes, &Format::default(), &settings)?;
+            assert_eq!([header, body.clone()].concat(), output_bytes);
+
+            let chunk = RawChunk::parse_body(&body, &Format::default(), LuaVersion::Lua51, &settings)?;
+            assert_eq!(chunk, RawChunk::parse(&output_bytes, &settings)?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn number_key_constant_to_integral() -> Result<(), LunifyError> {
+        let input_format = Format::default();
+
+        // `NEWTABLE 0 0 0`, `GETTABLE 1 0 K(0)`, `ADD 1 1 K(0)` and `RETURN 0 1`.
+        let input_bytes = lua51_chunk_bytes(&input_format, &TestFunction {
+            header: [0, 0, 2, 2],
+            instructions: &[lua51_abc(10, 0, 0, 0), lua51_abc(6, 1, 0, 256), lua51_abc(12, 1, 1, 256), lua51_abc(30, 0, 1, 0)],
+            constants: &[TestConstant::Number(1.5)],
+            ..Default::default()
+        })?;
+
+        // Rounding the key would make it differ from keys computed at runtime, but no
+        // number is ever rounded, so the conversion fails no matter how the constant is
+        // used.
+        let output_format = Format {
+            is_number_integral: true,
+            ..Format::default()
+        };
+        assert!(unify(&input_bytes, &input_format, &Settings::default()).is_ok());
+        assert_eq!(unify(&input_bytes, &output_format, &Settings::default()), Err(LunifyError::FloatPrecisionLoss));
+        Ok(())
+    }
+
+    #[test]
+    fn number_constants_across_formats() -> Result<(), LunifyError> {
+        let formats: Vec<Format> = [Endianness::Little, Endianness::Big]
+            .into_iter()
+            .flat_map(|endianness| [BitWidth::Bit32, BitWidth::Bit64].map(|number_width| (endianness, number_width)))
+            .flat_map(|(endianness, number_width)| {
+                [false, true].map(|is_number_integral| Format {
+                    endianness,
+                    number_width,
+                    is_number_integral,
+                    ..Format::default()
+                })
+            })
+            .collect();
+
+        for value in [1.5, -(2f64.powi(40)), 0.1] {
+            for input_format in &formats {
+                // Skip the values that the input can't represent.
+                let Ok(input_bytes) = lua51_number_bytes(input_format, value) else {
+                    continue;
+                };
+                let input_value = match read_number_constant(&input_bytes, input_format)? {
+                    Number::Float(value) => value,
+                    Number::Integer(value) => value as f64,
+                };
+
+                for output_format in &formats {
+                    let expected = match (output_format.is_number_integral, output_format.number_width) {
+                        (false, BitWidth::Bit64) => Ok(Number::Float(input_value)),
+                        (false, BitWidth::Bit32) => Ok(Number::Float(input_value as f32 as f64)),
+                        (true, _) if input_value.fract() != 0.0 => Err(LunifyError::FloatPrecisionLoss),
+                        (true, BitWidth::Bit32) if i32::try_from(input_value as i64).is_err() => Err(LunifyError::ValueTooBigForWidth {
+                            value: input_value as i128,
+                            width: BitWidth::Bit32,
+                        }),
+                        (true, _) => Ok(Number::Integer(input_value as i64)),
+                    };
+
+                    let result = unify(&input_bytes, output_format, &Settings::default())
+                        .and_then(|output_bytes| read_number_constant(&output_bytes, output_format));
+                    assert_eq!(result, expected, "{value} from {input_format:?} to {output_format:?}");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn large_table() -> Result<(), LunifyError> {
         let input_bytes = include_bytes!("../test_files/large_table.luab");
@@ -194,49 +1151,2738 @@ mod tests {
     }
 
     #[test]
-    fn custom_signature() -> Result<(), LunifyError> {
-        let input_bytes = include_bytes!("../test_files/custom_signature.luab").to_vec();
+    fn peephole() -> Result<(), LunifyError> {
         let output_format = Format::default();
-
         let settings = Settings {
-            lua51: lua51::Settings {
-                binary_signature: "\x1bLul",
+            output: lua51::Settings {
+                peephole: true,
                 ..Default::default()
             },
             ..Default::default()
         };
 
-        let _output_bytes = unify(&input_bytes, &output_format, &settings)?;
+        let inputs: [&[u8]; 8] = [
+            include_bytes!("../test_files/lua50.luab"),
+            include_bytes!("../test_files/little_endian.luab"),
+            include_bytes!("../test_files/big_endian.luab"),
+            include_bytes!("../test_files/large_table.luab"),
+            include_bytes!("../test_files/dynamic_table.luab"),
+            include_bytes!("../test_files/variadic.luab"),
+            include_bytes!("../test_files/for_loop.luab"),
+            include_bytes!("../test_files/constants.luab"),
+        ];
 
-        #[cfg(feature = "integration")]
-        test_output(&_output_bytes);
-        Ok(())
-    }
+        for input_bytes in inputs {
+            let _output_bytes = unify(input_bytes, &output_format, &settings)?;
+
+            #[cfg(feature = "integration")]
+            test_output(&_output_bytes);
+        }
 
-    #[test]
-    fn empty() -> Result<(), LunifyError> {
-        let input_bytes = include_bytes!("../test_files/empty.luab");
-        let output_format = Format::default();
-        unify(input_bytes, &output_format, &Default::default())?;
         Ok(())
     }
 
-    #[test]
-    fn incorrect_signature() {
-        let output_format = Format::default();
-        let result = unify(b"\x1bLuo", &output_format, &Default::default());
+    const LUA50_FORMAT: Format = Format {
+        format: 0,
+        endianness: Endianness::Little,
+        integer_width: BitWidth::Bit32,
+        size_t_width: BitWidth::Bit64,
+        instruction_width: BitWidth::Bit32,
+        number_width: BitWidth::Bit64,
+        is_number_integral: false,
+    };
 
-        assert_eq!(result, Err(LunifyError::IncorrectSignature));
+    fn write_lua50_header(byte_writer: &mut ByteWriter) {
+        byte_writer.slice(b"\x1bLua");
+        byte_writer.slice(&[0x50, 1, 4, 8, 4, 6, 8, 9, 9, 8]);
+        byte_writer.slice(&31415926.535897933f64.to_le_bytes());
     }
 
-    #[test]
-    fn input_too_long() {
-        let mut input_bytes = include_bytes!("../test_files/empty.luab").to_vec();
-        input_bytes.extend_from_slice(b"extra bytes");
+    /// The bit of RK operands of Lua 5.0 that marks a constant index.
+    const LUA50_CONSTANT: u64 = 250;
 
-        let output_format = Format::default();
-        let result = unify(&input_bytes, &output_format, &Default::default());
+    /// Encode a Lua 5.0 instruction with the operands A, B and C.
+    fn lua50_abc(opcode: u64, a: u64, b: u64, c: u64) -> u64 {
+        opcode | (c << 6) | (b << 15) | (a << 24)
+    }
 
+    /// Encode a Lua 5.0 instruction with the operands A and Bx.
+    fn lua50_abx(opcode: u64, a: u64, bx: u64) -> u64 {
+        opcode | (bx << 6) | (a << 24)
+    }
+
+    /// Encode a Lua 5.0 instruction with the operands A and sBx.
+    fn lua50_asbx(opcode: u64, a: u64, sbx: i64) -> u64 {
+        lua50_abx(opcode, a, (sbx + 131071) as u64)
+    }
+
+    /// Encode a Lua 5.1 instruction with the operands A, B and C.
+    pub(crate) fn lua51_abc(opcode: u64, a: u64, b: u64, c: u64) -> u64 {
+        opcode | (a << 6) | (c << 14) | (b << 23)
+    }
+
+    /// Encode a Lua 5.1 instruction with the operands A and Bx.
+    pub(crate) fn lua51_abx(opcode: u64, a: u64, bx: u64) -> u64 {
+        opcode | (a << 6) | (bx << 14)
+    }
+
+    /// Encode a Lua 5.1 instruction with the operands A and sBx.
+    fn lua51_asbx(opcode: u64, a: u64, sbx: i64) -> u64 {
+        lua51_abx(opcode, a, (sbx + 131071) as u64)
+    }
+
+    /// A constant of a [`TestFunction`].
+    #[derive(Clone, Copy)]
+    pub(crate) enum TestConstant<'a> {
+        Number(f64),
+        /// A string, without the terminating zero.
+        String(&'a str),
+    }
+
+    /// A hand-assembled function. Everything that isn't set is empty, and upvalue
+    /// names are never written.
+    #[derive(Default)]
+    pub(crate) struct TestFunction<'a> {
+        /// The source file, without the terminating zero.
+        pub(crate) source_file: &'a str,
+        pub(crate) line_defined: i64,
+        /// Only written for Lua 5.1.
+        pub(crate) last_line_defined: i64,
+        /// The upvalue count, the parameter count, the variadic flag and the
+        /// maximum stack size.
+        pub(crate) header: [u8; 4],
+        pub(crate) instructions: &'a [u64],
+        /// The line of every instruction, or `None` if the line info is
+        /// stripped.
+        pub(crate) line: Option<i64>,
+        pub(crate) constants: &'a [TestConstant<'a>],
+        /// The bytes of the nested functions, encoded for the same version.
+        pub(crate) functions: &'a [&'a [u8]],
+        /// The name, the start program counter and the end program counter of
+        /// every local variable.
+        pub(crate) local_variables: &'a [(&'a str, i64, i64)],
+        /// Bytes written after the upvalue names, like a function trailer.
+        pub(crate) trailer: &'a [u8],
+    }
+
+    impl TestFunction<'_> {
+        /// Encode the function like Lua 5.0 does, in [`LUA50_FORMAT`].
+        fn lua50_bytes(&self) -> Result<Vec<u8>, LunifyError> {
+            let mut byte_writer = ByteWriter::new(&LUA50_FORMAT);
+            self.write_source(&mut byte_writer)?;
+            byte_writer.slice(&self.header);
+            self.write_debug_information(&mut byte_writer)?;
+            self.write_constants(&mut byte_writer)?;
+            self.write_functions(&mut byte_writer)?;
+            self.write_instructions(&mut byte_writer)?;
+            Ok(byte_writer.finalize())
+        }
+
+        /// Encode the function like Lua 5.1 does, in the given format.
+        pub(crate) fn lua51_bytes(&self, format: &Format) -> Result<Vec<u8>, LunifyError> {
+            let mut byte_writer = ByteWriter::new(format);
+            self.write_source(&mut byte_writer)?;
+            byte_writer.integer(self.last_line_defined)?;
+            byte_writer.slice(&self.header);
+            self.write_instructions(&mut byte_writer)?;
+            self.write_constants(&mut byte_writer)?;
+            self.write_functions(&mut byte_writer)?;
+            self.write_debug_information(&mut byte_writer)?;
+            byte_writer.slice(self.trailer);
+            Ok(byte_writer.finalize())
+        }
+
+        fn write_source(&self, byte_writer: &mut ByteWriter) -> Result<(), LunifyError> {
+            match self.source_file.is_empty() {
+                true => byte_writer.string("")?,
+                false => byte_writer.string(format!("{}\0", self.source_file))?,
+            }
+            byte_writer.integer(self.line_defined)
+        }
+
+        fn write_instructions(&self, byte_writer: &mut ByteWriter) -> Result<(), LunifyError> {
+            byte_writer.count(self.instructions.len())?;
+            self.instructions.iter().for_each(|instruction| byte_writer.instruction(*instruction));
+            Ok(())
+        }
+
+        fn write_constants(&self, byte_writer: &mut ByteWriter) -> Result<(), LunifyError> {
+            byte_writer.count(self.constants.len())?;
+            for constant in self.constants {
+                match constant {
+                    TestConstant::Number(number) => {
+                        byte_writer.byte(3);
+                        byte_writer.number(Number::Float(*number))?;
+                    }
+                    TestConstant::String(string) => {
+                        byte_writer.byte(4);
+                        byte_writer.string(format!("{string}\0"))?;
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        fn write_functions(&self, byte_writer: &mut ByteWriter) -> Result<(), LunifyError> {
+            byte_writer.count(self.functions.len())?;
+            self.functions.iter().for_each(|function| byte_writer.slice(function));
+            Ok(())
+        }
+
+        /// Write the line info, the local variables and an empty list of upvalue
+        /// names.
+        fn write_debug_information(&self, byte_writer: &mut ByteWriter) -> Result<(), LunifyError> {
+            match self.line {
+                Some(line) => {
+                    byte_writer.count(self.instructions.len())?;
+                    byte_writer.integer_batch(&vec![line; self.instructions.len()])?;
+                }
+                None => byte_writer.count(0)?,
+            }
+
+            byte_writer.count(self.local_variables.len())?;
+            for (name, start_program_counter, end_program_counter) in self.local_variables {
+                byte_writer.string(format!("{name}\0"))?;
+                byte_writer.integer(*start_program_counter)?;
+                byte_writer.integer(*end_program_counter)?;
+            }
+
+            byte_writer.count(0)
+        }
+    }
+
+    /// Lua 5.0 byte code with the given main function.
+    fn lua50_chunk_bytes(main_function: &TestFunction) -> Result<Vec<u8>, LunifyError> {
+        let mut byte_writer = ByteWriter::new(&LUA50_FORMAT);
+        write_lua50_header(&mut byte_writer);
+        byte_writer.slice(&main_function.lua50_bytes()?);
+        Ok(byte_writer.finalize())
+    }
+
+    /// Lua 5.1 byte code in the given format with the given main function.
+    pub(crate) fn lua51_chunk_bytes(format: &Format, main_function: &TestFunction) -> Result<Vec<u8>, LunifyError> {
+        let mut byte_writer = ByteWriter::new(format);
+        byte_writer.slice(b"\x1bLua");
+        byte_writer.byte(0x51);
+        format.write(&mut byte_writer);
+        byte_writer.slice(&main_function.lua51_bytes(format)?);
+        Ok(byte_writer.finalize())
+    }
+
+    /// Lua 5.0 byte code for a table constructor with `element_count` elements,
+    /// compiled with the given `LFIELDS_PER_FLUSH`, that returns the table.
+    fn lua50_table_bytes(element_count: u64, fields_per_flush: u64) -> Result<Vec<u8>, LunifyError> {
+        let mut instructions = vec![lua50_abc(10, 0, 0, 0)];
+
+        for index in 0..element_count {
+            let stack_position = (index % fields_per_flush) + 1;
+
+            // `LOADK stack_position 0`
+            instructions.push(lua50_abx(1, stack_position, 0));
+
+            // `SETLIST 0 index`
+            if stack_position == fields_per_flush || index + 1 == element_count {
+                instructions.push(lua50_abx(31, 0, index));
+            }
+        }
+
+        // `RETURN 0 2`
+        instructions.push(lua50_abc(27, 0, 2, 0));
+
+        lua50_function_bytes(fields_per_flush as u8 + 1, &instructions)
+    }
+
+    /// Lua 5.0 byte code for a single function with the given instructions and
+    /// the constant `9`.
+    fn lua50_function_bytes(maximum_stack_size: u8, instructions: &[u64]) -> Result<Vec<u8>, LunifyError> {
+        lua50_chunk_bytes(&TestFunction {
+            header: [0, 0, 0, maximum_stack_size],
+            instructions,
+            line: Some(1),
+            constants: &[TestConstant::Number(9.0)],
+            ..Default::default()
+        })
+    }
+
+    /// Lua 5.0 byte code for a single function with the given instructions and
+    /// the constants `"result"` and `9`.
+    fn lua50_result_bytes(instructions: &[u64]) -> Result<Vec<u8>, LunifyError> {
+        lua50_strings_bytes(&["result"], instructions)
+    }
+
+    /// Lua 5.0 byte code for a single function with the given instructions, the
+    /// given string constants and the constant `9` after them.
+    fn lua50_strings_bytes(strings: &[&str], instructions: &[u64]) -> Result<Vec<u8>, LunifyError> {
+        let mut constants: Vec<TestConstant> = strings.iter().map(|string| TestConstant::String(string)).collect();
+        constants.push(TestConstant::Number(9.0));
+
+        lua50_chunk_bytes(&TestFunction {
+            header: [0, 0, 0, 4],
+            instructions,
+            line: Some(1),
+            constants: &constants,
+            ..Default::default()
+        })
+    }
+
+    /// Lua 5.1 byte code for a function with a nested function, where both
+    /// functions end with the given trailers.
+    fn lua51_trailer_bytes(spec: Option<FunctionTrailerSpec>, root_trailer: &[u8], child_trailer: &[u8]) -> Result<Vec<u8>, LunifyError> {
+        let format = LUA50_FORMAT;
+        let encode_trailer = |trailer: &[u8]| -> Result<Vec<u8>, LunifyError> {
+            let mut byte_writer = ByteWriter::new(&format);
+            match spec {
+                Some(FunctionTrailerSpec::FixedLength(_)) => byte_writer.slice(trailer),
+                Some(FunctionTrailerSpec::LengthPrefixed { size_t: true }) => byte_writer.string(trailer)?,
+                Some(FunctionTrailerSpec::LengthPrefixed { size_t: false }) => {
+                    byte_writer.count(trailer.len())?;
+                    byte_writer.slice(trailer);
+                }
+                None => {}
+            }
+            Ok(byte_writer.finalize())
+        };
+
+        // Nested function with `RETURN 0 1`.
+        let child_function = TestFunction {
+            line_defined: 1,
+            last_line_defined: 1,
+            header: [0, 0, 0, 2],
+            instructions: &[lua51_abc(30, 0, 1, 0)],
+            line: Some(1),
+            trailer: &encode_trailer(child_trailer)?,
+            ..Default::default()
+        };
+
+        // Main function with `CLOSURE 0 0`, `RETURN 0 1`.
+        lua51_chunk_bytes(&format, &TestFunction {
+            source_file: "@trailer.lua",
+            header: [0, 0, 2, 2],
+            instructions: &[lua51_abx(36, 0, 0), lua51_abc(30, 0, 1, 0)],
+            line: Some(1),
+            functions: &[&child_function.lua51_bytes(&format)?],
+            trailer: &encode_trailer(root_trailer)?,
+            ..Default::default()
+        })
+    }
+
+    fn trailer_settings(spec: FunctionTrailerSpec, mode: FunctionTrailerMode) -> Settings {
+        Settings {
+            lua51: lua51::Settings {
+                function_trailer: Some(spec),
+                ..Default::default()
+            },
+            output: lua51::Settings {
+                function_trailer_mode: mode,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn function_trailer_fixed_length() -> Result<(), LunifyError> {
+        let spec = FunctionTrailerSpec::FixedLength(4);
+        let input_bytes = lua51_trailer_bytes(Some(spec), b"root", b"chld")?;
+        let settings = trailer_settings(spec, FunctionTrailerMode::Keep);
+
+        let output_bytes = unify(&input_bytes, &LUA50_FORMAT, &settings)?;
+        assert_eq!(output_bytes, input_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn function_trailer_length_prefixed() -> Result<(), LunifyError> {
+        for spec in [
+            FunctionTrailerSpec::LengthPrefixed { size_t: true },
+            FunctionTrailerSpec::LengthPrefixed { size_t: false },
+        ] {
+            let input_bytes = lua51_trailer_bytes(Some(spec), b"checksum", b"")?;
+            let settings = trailer_settings(spec, FunctionTrailerMode::Keep);
+
+            let output_bytes = unify(&input_bytes, &LUA50_FORMAT, &settings)?;
+            assert_eq!(output_bytes, input_bytes);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn function_trailer_drop() -> Result<(), LunifyError> {
+        let spec = FunctionTrailerSpec::FixedLength(4);
+        let input_bytes = lua51_trailer_bytes(Some(spec), b"root", b"chld")?;
+        let settings = trailer_settings(spec, FunctionTrailerMode::Drop);
+
+        let output_bytes = unify(&input_bytes, &LUA50_FORMAT, &settings)?;
+        assert_eq!(output_bytes, lua51_trailer_bytes(None, b"", b"")?);
+        Ok(())
+    }
+
+    #[test]
+    fn function_trailer_replace() -> Result<(), LunifyError> {
+        let spec = FunctionTrailerSpec::FixedLength(4);
+        let input_bytes = lua51_trailer_bytes(Some(spec), b"root", b"chld")?;
+        let settings = trailer_settings(spec, FunctionTrailerMode::Replace(b"sign"));
+
+        let output_bytes = unify(&input_bytes, &LUA50_FORMAT, &settings)?;
+        assert_eq!(output_bytes, lua51_trailer_bytes(Some(spec), b"sign", b"sign")?);
+        Ok(())
+    }
+
+    #[test]
+    fn function_trailer_missing_spec() -> Result<(), LunifyError> {
+        let input_bytes = lua51_trailer_bytes(Some(FunctionTrailerSpec::FixedLength(4)), b"root", b"chld")?;
+        let result = unify(&input_bytes, &LUA50_FORMAT, &Default::default());
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    /// Lua 5.1 byte code for `result = 29 % 10`.
+    fn lua51_modulo_bytes() -> Result<Vec<u8>, LunifyError> {
+        lua51_modulo_bytes_with(LUA50_FORMAT, 0)
+    }
+
+    /// Same as [`lua51_modulo_bytes`], but in the given format and with
+    /// `padding` set on every instruction.
+    fn lua51_modulo_bytes_with(format: Format, padding: u64) -> Result<Vec<u8>, LunifyError> {
+        // `LOADK 0 1`, `MOD 0 0 K2`, `SETGLOBAL 0 0`, `RETURN 0 1`.
+        let instructions = [lua51_abx(1, 0, 1), lua51_abc(16, 0, 0, 256 | 2), lua51_abx(7, 0, 0), lua51_abc(30, 0, 1, 0)];
+
+        lua51_chunk_bytes(&format, &TestFunction {
+            source_file: "@modulo.lua",
+            header: [0, 0, 2, 2],
+            instructions: &instructions.map(|instruction| instruction | padding),
+            line: Some(1),
+            constants: &[TestConstant::String("result"), TestConstant::Number(29.0), TestConstant::Number(10.0)],
+            ..Default::default()
+        })
+    }
+
+    /// A Lua 5.1 function with the given instructions, `constants` times the
+    /// number `9` and the given nested functions.
+    fn lua51_prototype_bytes(
+        maximum_stack_size: u8,
+        instructions: &[u64],
+        constants: usize,
+        functions: &[&[u8]],
+    ) -> Result<Vec<u8>, LunifyError> {
+        let function = TestFunction {
+            header: [0, 0, 2, maximum_stack_size],
+            instructions,
+            line: Some(1),
+            constants: &vec![TestConstant::Number(9.0); constants],
+            functions,
+            ..Default::default()
+        };
+        function.lua51_bytes(&LUA50_FORMAT)
+    }
+
+    /// A Lua 5.1 function that creates a table with 60 elements, so the
+    /// `SETLIST` instructions are rewritten if the fields per flush change.
+    fn lua51_table_prototype_bytes() -> Result<Vec<u8>, LunifyError> {
+        // `NEWTABLE 0 0 0`, 50 times `LOADK`, `SETLIST 0 50 1`, 10 times `LOADK`,
+        // `SETLIST 0 10 2`, `RETURN 0 1`.
+        let mut table_instructions = vec![lua51_abc(10, 0, 0, 0)];
+        table_instructions.extend((1..=50).map(|register| lua51_abx(1, register, 0)));
+        table_instructions.push(lua51_abc(34, 0, 50, 1));
+        table_instructions.extend((1..=10).map(|register| lua51_abx(1, register, 0)));
+        table_instructions.push(lua51_abc(34, 0, 10, 2));
+        table_instructions.push(lua51_abc(30, 0, 1, 0));
+        lua51_prototype_bytes(51, &table_instructions, 1, &[])
+    }
+
+    /// Lua 5.1 byte code whose main function creates a closure for every given
+    /// function.
+    fn lua51_closures_bytes(functions: &[&[u8]]) -> Result<Vec<u8>, LunifyError> {
+        // `CLOSURE 0 n` for every function, `RETURN 0 1`.
+        let mut main_instructions: Vec<u64> = (0..functions.len() as u64).map(|index| lua51_abx(36, 0, index)).collect();
+        main_instructions.push(lua51_abc(30, 0, 1, 0));
+
+        lua51_chunk_bytes(&LUA50_FORMAT, &TestFunction {
+            header: [0, 0, 2, 2],
+            instructions: &main_instructions,
+            line: Some(1),
+            functions,
+            ..Default::default()
+        })
+    }
+
+    /// Lua 5.1 byte code with two nested functions. The first one fills a table
+    /// with 60 elements, the second one has a `MOVE` with bits set in its
+    /// unused C operand. Also returns the bytes of the second function.
+    fn lua51_prototypes_bytes() -> Result<(Vec<u8>, Vec<u8>), LunifyError> {
+        // `MOVE 0 0` with C set to 5, `RETURN 0 1`.
+        let untouched_function = lua51_prototype_bytes(2, &[lua51_abc(0, 0, 0, 5), lua51_abc(30, 0, 1, 0)], 0, &[])?;
+        let input_bytes = lua51_closures_bytes(&[&lua51_table_prototype_bytes()?, &untouched_function])?;
+        Ok((input_bytes, untouched_function))
+    }
+
+    fn prototypes_settings() -> Settings<'static> {
+        let mut settings = Settings::default();
+        settings.output.fields_per_flush = 64;
+        settings
+    }
+
+    #[test]
+    fn splice_untouched_prototypes() -> Result<(), LunifyError> {
+        let (input_bytes, untouched_function) = lua51_prototypes_bytes()?;
+        let (output_bytes, report) = unify_with_report(&input_bytes, &LUA50_FORMAT, &prototypes_settings())?;
+
+        assert!(report.functions[1].is_modified);
+        assert!(!report.functions[2].is_modified);
+        assert_eq!(report.functions[2].size, untouched_function.len());
+        assert!(output_bytes.windows(untouched_function.len()).any(|window| window == untouched_function));
+        assert_eq!(validate(&output_bytes, &Default::default()), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn skip_function_paths() -> Result<(), LunifyError> {
+        let table_function = lua51_table_prototype_bytes()?;
+        let input_bytes = lua51_closures_bytes(&[&table_function, &table_function])?;
+        let count = |output_bytes: &[u8]| output_bytes.windows(table_function.len()).filter(|window| window == &table_function).count();
+
+        let mut settings = prototypes_settings();
+        settings.skip_function_paths = &[&[1]];
+        let (output_bytes, report) = unify_with_report(&input_bytes, &LUA50_FORMAT, &settings)?;
+
+        // Only the skipped function is copied as is, its sibling is converted.
+        assert!(report.functions[1].is_modified);
+        assert!(!report.functions[2].is_modified);
+        assert_eq!(count(&output_bytes), 1);
+        assert_eq!(unify(&input_bytes, &LUA50_FORMAT, &settings)?, output_bytes);
+        assert_eq!(count(&unify(&input_bytes, &LUA50_FORMAT, &prototypes_settings())?), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn skip_function_paths_different_encoding() {
+        let (input_bytes, _) = lua51_prototypes_bytes().expect("input is valid");
+        let output_format = Format {
+            size_t_width: BitWidth::Bit32,
+            ..LUA50_FORMAT
+        };
+        let mut settings = prototypes_settings();
+        settings.skip_function_paths = &[&[0]];
+
+        let expected = Err(LunifyError::IncompatibleOutputConfiguration(
+            "skipped functions can only be copied if the input is encoded like the output",
+        ));
+        assert_eq!(unify(&input_bytes, &output_format, &settings), expected);
+        assert_eq!(extract(&input_bytes, &[0], &output_format, &settings), expected);
+    }
+
+    #[test]
+    fn skip_input_validation() -> Result<(), LunifyError> {
+        // `LOADK 0 3` with only one constant, `RETURN 0 1`.
+        let function = lua51_prototype_bytes(2, &[lua51_abx(1, 0, 3), lua51_abc(30, 0, 1, 0)], 1, &[])?;
+        let input_bytes = lua51_closures_bytes(&[&function])?;
+
+        let result = unify(&input_bytes, &LUA50_FORMAT, &Settings::default());
+        assert_eq!(result, Err(LunifyError::InvalidConstantReference {
+            program_counter: 0,
+            index: 3,
+            available: 1,
+        }));
+
+        let settings = Settings::builder().skip_input_validation(true).build()?;
+        assert!(unify(&input_bytes, &LUA50_FORMAT, &settings).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn validate_passed_through_input() -> Result<(), LunifyError> {
+        // `LOADK 0 5` without any constants, `RETURN 0 1`.
+        let input_bytes = lua51_chunk_bytes(&Format::default(), &TestFunction {
+            header: [0, 0, 2, 2],
+            instructions: &[lua51_abx(1, 0, 5), lua51_abc(30, 0, 1, 0)],
+            ..Default::default()
+        })?;
+
+        let result = with_pass_through(|| unify_cow(&input_bytes, &Format::default(), &Settings::default()));
+        assert_eq!(result, Err(LunifyError::InvalidConstantReference {
+            program_counter: 0,
+            index: 5,
+            available: 0,
+        }));
+
+        let settings = Settings::builder().skip_input_validation(true).build()?;
+        let output_bytes = with_pass_through(|| unify_cow(&input_bytes, &Format::default(), &settings))?;
+        assert!(matches!(output_bytes, Cow::Borrowed(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn splice_different_encoding() -> Result<(), LunifyError> {
+        let (input_bytes, untouched_function) = lua51_prototypes_bytes()?;
+        let output_format = Format {
+            size_t_width: BitWidth::Bit32,
+            ..LUA50_FORMAT
+        };
+        let output_bytes = unify(&input_bytes, &output_format, &prototypes_settings())?;
+
+        // The function is encoded again, which also clears the unused operand.
+        assert!(!output_bytes.windows(untouched_function.len()).any(|window| window == untouched_function));
+        assert_eq!(validate(&output_bytes, &Settings::default()), Ok(()));
+        Ok(())
+    }
+
+    /// Lua 5.1 byte code with two levels of nested functions. The main
+    /// function has the functions `[0]`, which sets `result` to 9, and `[1]`,
+    /// which takes one parameter and returns the function `[1, 0]`. That one
+    /// captures the parameter as an upvalue and returns it.
+    fn lua51_nested_bytes() -> Result<Vec<u8>, LunifyError> {
+        fn function<'a>(
+            source_file: &'a str,
+            line_defined: i64,
+            header: [u8; 4],
+            instructions: &'a [u64],
+            functions: &'a [&'a [u8]],
+        ) -> TestFunction<'a> {
+            TestFunction {
+                source_file,
+                line_defined,
+                last_line_defined: line_defined + 1,
+                header,
+                instructions,
+                line: Some(line_defined),
+                constants: &[TestConstant::Number(9.0), TestConstant::String("result")],
+                functions,
+                ..Default::default()
+            }
+        }
+
+        // `LOADK 0 0`, `SETGLOBAL 0 1`, `RETURN 0 1`.
+        let result_instructions = [lua51_abx(1, 0, 0), lua51_abx(7, 0, 1), lua51_abc(30, 0, 1, 0)];
+        let result_function = function("", 1, [0, 0, 0, 2], &result_instructions, &[]).lua51_bytes(&LUA50_FORMAT)?;
+
+        // `GETUPVAL 0 0`, `RETURN 0 2`.
+        let upvalue_instructions = [lua51_abc(4, 0, 0, 0), lua51_abc(30, 0, 2, 0)];
+        let upvalue_function = function("", 4, [1, 0, 0, 2], &upvalue_instructions, &[]).lua51_bytes(&LUA50_FORMAT)?;
+
+        // `CLOSURE 1 0`, `MOVE 0 0`, `RETURN 1 2`.
+        let closure_instructions = [lua51_abx(36, 1, 0), lua51_abc(0, 0, 0, 0), lua51_abc(30, 1, 2, 0)];
+        let closure_function = function("", 3, [0, 1, 0, 2], &closure_instructions, &[&upvalue_function]).lua51_bytes(&LUA50_FORMAT)?;
+
+        // `CLOSURE 0 0`, `CALL 0 1 1`, `RETURN 0 1`.
+        let main_instructions = [lua51_abx(36, 0, 0), lua51_abc(28, 0, 1, 1), lua51_abc(30, 0, 1, 0)];
+        let main_functions: [&[u8]; 2] = [&result_function, &closure_function];
+        lua51_chunk_bytes(&LUA50_FORMAT, &function("@main.lua", 0, [0, 0, 2, 2], &main_instructions, &main_functions))
+    }
+
+    /// Lua 5.1 byte code with a nested function that has `constant_count`
+    /// numbers followed by the name `result`. The nested function loads every
+    /// constant at the given indices and stores the last one in `result`. The
+    /// number at every index ending in 9 is 9.
+    fn lua51_many_constants_bytes(constant_count: usize, loaded_constants: &[u64]) -> Result<Vec<u8>, LunifyError> {
+        let mut constants: Vec<TestConstant> = (0..constant_count).map(|index| TestConstant::Number((index % 10) as f64)).collect();
+        constants.push(TestConstant::String("result"));
+
+        // `LOADK 0 constant` for every constant, `SETGLOBAL 0 constant_count`, `RETURN 0 1`.
+        let mut instructions: Vec<u64> = loaded_constants.iter().map(|&constant| lua51_abx(1, 0, constant)).collect();
+        instructions.push(lua51_abx(7, 0, constant_count as u64));
+        instructions.push(lua51_abc(30, 0, 1, 0));
+
+        let constants_function = TestFunction {
+            header: [0, 0, 2, 2],
+            instructions: &instructions,
+            line: Some(1),
+            constants: &constants,
+            ..Default::default()
+        };
+
+        // `CLOSURE 0 0`, `CALL 0 1 1`, `RETURN 0 1`.
+        lua51_chunk_bytes(&LUA50_FORMAT, &TestFunction {
+            header: [0, 0, 2, 2],
+            instructions: &[lua51_abx(36, 0, 0), lua51_abc(28, 0, 1, 1), lua51_abc(30, 0, 1, 0)],
+            line: Some(1),
+            constants: &[TestConstant::String("result")],
+            functions: &[&constants_function.lua51_bytes(&LUA50_FORMAT)?],
+            ..Default::default()
+        })
+    }
+
+    /// A layout with the smallest Bx operand possible. The constant bit of RK
+    /// operands is 128, so the stack limit needs to be lowered.
+    fn small_bx_layout() -> InstructionLayout {
+        InstructionLayout::from_specification([OperandType::Opcode(6), OperandType::A(10), OperandType::C(8), OperandType::B(8)])
+            .expect("layout is valid")
+    }
+
+    #[test]
+    fn global_names_fit_small_bx() -> Result<(), LunifyError> {
+        let input_bytes = lua51_many_constants_bytes(70_000, &[69_999])?;
+
+        let mut settings = Settings::default();
+        settings.output.layout = small_bx_layout();
+        settings.output.stack_limit = 128;
+        let output_bytes = unify(&input_bytes, &LUA50_FORMAT, &settings)?;
+
+        // Convert back to the default layout so the result can be run.
+        let mut settings = Settings::default();
+        settings.lua51.layout = small_bx_layout();
+        let _output_bytes = unify(&output_bytes, &Format::default(), &settings)?;
+
+        #[cfg(feature = "integration")]
+        test_output(&_output_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn too_many_constants() -> Result<(), LunifyError> {
+        let loaded_constants: Vec<u64> = (0..1 << 16).collect();
+        let input_bytes = lua51_many_constants_bytes(loaded_constants.len() + 1, &loaded_constants)?;
+
+        let mut settings = Settings::default();
+        settings.output.layout = small_bx_layout();
+        settings.output.stack_limit = 128;
+
+        let expected = LunifyError::TooManyConstants {
+            path: FunctionPath::from(vec![0]),
+            constant_count: loaded_constants.len() + 2,
+        };
+        assert_eq!(unify(&input_bytes, &LUA50_FORMAT, &settings), Err(expected));
+        Ok(())
+    }
+
+    #[test]
+    fn root_source_override() -> Result<(), LunifyError> {
+        let input_bytes = lua51_nested_bytes()?;
+        let mut settings = Settings::default();
+        settings.output.root_source_override = Some("@bundle:build-1234");
+        settings.output.zero_root_lines = true;
+
+        let output_bytes = unify(&input_bytes, &Format::default(), &settings)?;
+        let (report_bytes, report) = unify_with_report(&input_bytes, &Format::default(), &settings)?;
+        assert_eq!(output_bytes, report_bytes);
+
+        // Both nested functions of the main function inherited its source file, so they
+        // now write it, while the function nested inside of them still inherits it.
+        let count = |pattern: &[u8]| output_bytes.windows(pattern.len()).filter(|window| window == &pattern).count();
+        assert_eq!(count(b"@bundle:build-1234\0"), 1);
+        assert_eq!(count(b"@main.lua\0"), 2);
+
+        // Debug information is not part of the content hash.
+        let (_, default_report) = unify_with_report(&input_bytes, &Format::default(), &Settings::default())?;
+        let hashes = |report: &ConversionReport| report.functions.iter().map(|function| function.content_hash).collect::<Vec<_>>();
+        assert_eq!(hashes(&report), hashes(&default_report));
+
+        #[cfg(feature = "integration")]
+        test_output(&output_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn list_nested_functions() -> Result<(), LunifyError> {
+        let functions = list_functions(&lua51_nested_bytes()?, &Settings::default())?;
+        let paths: Vec<_> = functions.iter().map(|function| function.path.to_string()).collect();
+
+        assert_eq!(paths, ["root", "root.0", "root.1", "root.1.0"]);
+        assert_eq!(functions.iter().map(|function| function.line_defined).collect::<Vec<_>>(), [0, 1, 3, 4]);
+        assert_eq!(functions[0].function_count, 2);
+        assert_eq!(functions[2].parameter_count, 1);
+        assert_eq!(functions[3].upvalue_count, 1);
+        assert_eq!(functions[3].number_constant_count, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn chunk_facts_of_inputs() -> Result<(), LunifyError> {
+        let inputs: [(&[u8], LuaVersion, bool); 3] = [
+            (include_bytes!("../test_files/empty.luab"), LuaVersion::Lua50, false),
+            (include_bytes!("../test_files/constants.luab"), LuaVersion::Lua51, true),
+            (include_bytes!("../test_files/lua50.luab"), LuaVersion::Lua50, true),
+        ];
+
+        for (input_bytes, version, has_number_constants) in inputs {
+            let facts = chunk_facts(input_bytes, &Settings::default())?;
+            assert_eq!(facts.version, version);
+            assert_eq!(facts.has_number_constants, has_number_constants);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn extract_nested_function() -> Result<(), LunifyError> {
+        let _output_bytes = extract(&lua51_nested_bytes()?, &[0], &LUA50_FORMAT, &Settings::default())?;
+        let functions = list_functions(&_output_bytes, &Settings::default())?;
+
+        // The source file is inherited from the main function.
+        assert_eq!(functions.len(), 1);
+        assert_eq!(functions[0].line_defined, 1);
+        assert!(_output_bytes.windows(9).any(|window| window == b"@main.lua"));
+        assert_eq!(validate(&_output_bytes, &Settings::default()), Ok(()));
+
+        #[cfg(feature = "integration")]
+        test_output(&_output_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn extract_two_levels() -> Result<(), LunifyError> {
+        let output_bytes = extract(&lua51_nested_bytes()?, &[1], &Format::default(), &Settings::default())?;
+        let functions = list_functions(&output_bytes, &Settings::default())?;
+
+        assert_eq!(functions.iter().map(|function| function.line_defined).collect::<Vec<_>>(), [3, 4]);
+        assert_eq!(validate(&output_bytes, &Settings::default()), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn extract_closure() -> Result<(), LunifyError> {
+        let input_bytes = lua51_nested_bytes()?;
+        let result = extract(&input_bytes, &[1, 0], &LUA50_FORMAT, &Settings::default());
+        assert_eq!(result, Err(LunifyError::CannotExtractClosure { upvalue_count: 1 }));
+
+        let settings = Settings {
+            extract_closures: true,
+            ..Default::default()
+        };
+        let output_bytes = extract(&input_bytes, &[1, 0], &LUA50_FORMAT, &settings)?;
+        assert_eq!(validate(&output_bytes, &settings), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn extract_invalid_path() -> Result<(), LunifyError> {
+        let input_bytes = lua51_nested_bytes()?;
+        let settings = Settings::default();
+
+        assert_eq!(
+            extract(&input_bytes, &[2], &LUA50_FORMAT, &settings),
+            Err(LunifyError::InvalidFunctionPath(FunctionPath::from(vec![2])))
+        );
+        assert_eq!(
+            extract(&input_bytes, &[1, 1], &LUA50_FORMAT, &settings),
+            Err(LunifyError::InvalidFunctionPath(FunctionPath::from(vec![1, 1])))
+        );
+        Ok(())
+    }
+
+    /// `lua51_nested_bytes` with the opcode of the first instruction of the
+    /// function at `path` replaced by 63, which isn't a Lua 5.1 opcode.
+    fn corrupted_nested_bytes(path: &[usize]) -> Result<Vec<u8>, LunifyError> {
+        let first_instructions = match path {
+            [] => [lua51_abx(36, 0, 0), lua51_abc(28, 0, 1, 1)],
+            [0] => [lua51_abx(1, 0, 0), lua51_abx(7, 0, 1)],
+            [1] => [lua51_abx(36, 1, 0), lua51_abc(0, 0, 0, 0)],
+            _ => [lua51_abc(4, 0, 0, 0), lua51_abc(30, 0, 2, 0)],
+        };
+        let code: Vec<u8> = first_instructions.iter().flat_map(|&instruction| (instruction as u32).to_le_bytes()).collect();
+
+        let mut input_bytes = lua51_nested_bytes()?;
+        let offset = input_bytes.windows(code.len()).position(|window| window == code).unwrap();
+        input_bytes[offset] = 63;
+        Ok(input_bytes)
+    }
+
+    #[test]
+    fn unify_lenient_matches_unify() -> Result<(), LunifyError> {
+        let input_bytes = lua51_nested_bytes()?;
+        let output_bytes = unify(&input_bytes, &Format::default(), &Settings::default())?;
+
+        assert_eq!(unify_lenient(&input_bytes, &Format::default(), &Settings::default()), (Some(output_bytes), Vec::new()));
+        Ok(())
+    }
+
+    #[test]
+    fn unify_lenient_stub() -> Result<(), LunifyError> {
+        // Function 1 has a nested function that is replaced as well, but 1.0
+        // captures an upvalue that the stub needs to keep.
+        for (path, function_count, upvalue_count) in [(vec![1], 3, 0), (vec![1, 0], 4, 1)] {
+            let input_bytes = corrupted_nested_bytes(&path)?;
+            let (output_bytes, errors) = unify_lenient(&input_bytes, &Format::default(), &Settings::default());
+            let output_bytes = output_bytes.unwrap();
+
+            assert_eq!(unify(&input_bytes, &Format::default(), &Settings::default()), Err(LunifyError::InvalidOpcode(63)));
+            assert_eq!(errors, [FunctionError {
+                path: FunctionPath::from(path.clone()),
+                error: LunifyError::InvalidOpcode(63),
+            }]);
+            assert_eq!(validate(&output_bytes, &Settings::default()), Ok(()));
+
+            let functions = list_functions(&output_bytes, &Settings::default())?;
+            assert_eq!(functions.len(), function_count);
+            assert_eq!(functions.last().unwrap().upvalue_count, upvalue_count);
+
+            // The sibling is converted as usual.
+            let input_sibling = extract(lua51_nested_bytes()?, &[0], &Format::default(), &Settings::default())?;
+            let output_sibling = extract(&output_bytes, &[0], &Format::default(), &Settings::default())?;
+            assert_eq!(input_sibling, output_sibling);
+
+            #[cfg(feature = "integration")]
+            test_output(&output_bytes);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn progress() -> Result<(), LunifyError> {
+        let updates = RefCell::new(Vec::new());
+        let record = |progress: Progress| updates.borrow_mut().push(progress);
+        let settings = Settings::builder().progress(Some(ProgressCallback(&record))).build()?;
+
+        // Stubs skip their nested functions, so those are counted without being
+        // reported on their own.
+        let check_updates = |minimum_function_updates: usize| {
+            let updates = updates.take();
+            let (first, last) = (updates.first().unwrap(), updates.last().unwrap());
+            let function_updates = updates.iter().filter(|progress| progress.phase == Phase::ConvertingFunction).count();
+
+            assert_eq!(first.phase, Phase::ParsingHeader);
+            assert_eq!(last.phase, Phase::Writing);
+            assert!(function_updates >= minimum_function_updates);
+            assert!(updates.iter().all(|progress| progress.functions_total == Some(4)));
+            assert!(updates.windows(2).all(|pair| pair[0].bytes_read <= pair[1].bytes_read));
+            assert!(updates.windows(2).all(|pair| pair[0].functions_done <= pair[1].functions_done));
+            assert_eq!(last.functions_done, 4);
+            assert_eq!(last.bytes_read, last.bytes_total);
+        };
+
+        let input_bytes = lua51_nested_bytes()?;
+        let output_bytes = unify(&input_bytes, &Format::default(), &settings)?;
+        check_updates(4);
+
+        assert_eq!(unify_with_report(&input_bytes, &Format::default(), &settings)?.0, output_bytes);
+        check_updates(4);
+
+        for (path, minimum_function_updates) in [(vec![1], 3), (vec![1, 0], 4)] {
+            let (output_bytes, _) = unify_lenient(corrupted_nested_bytes(&path)?, &Format::default(), &settings);
+            assert!(output_bytes.is_some());
+            check_updates(minimum_function_updates);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn unify_lenient_root_error() -> Result<(), LunifyError> {
+        let input_bytes = corrupted_nested_bytes(&[])?;
+        let (output_bytes, errors) = unify_lenient(&input_bytes, &Format::default(), &Settings::default());
+
+        assert_eq!(output_bytes, None);
+        assert_eq!(errors, [FunctionError {
+            path: FunctionPath::root(),
+            error: LunifyError::InvalidOpcode(63),
+        }]);
+        Ok(())
+    }
+
+    #[test]
+    fn disallowed_modulo() -> Result<(), LunifyError> {
+        let input_bytes = lua51_modulo_bytes()?;
+        let mut settings = Settings::default();
+        settings.output.disallowed_opcodes = lua51::OpcodeSet::from_names(&["MOD"])?;
+
+        let _output_bytes = unify(&input_bytes, &LUA50_FORMAT, &settings)?;
+        assert_ne!(_output_bytes, input_bytes);
+
+        #[cfg(feature = "integration")]
+        test_output(&_output_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn preserve_instruction_padding() -> Result<(), LunifyError> {
+        let format = Format {
+            instruction_width: BitWidth::Bit64,
+            ..LUA50_FORMAT
+        };
+        let big_endian_format = Format {
+            endianness: Endianness::Big,
+            ..format
+        };
+        let input_bytes = lua51_modulo_bytes_with(format, 1 << 40)?;
+        let settings = Settings::builder().preserve_instruction_padding(true).build()?;
+
+        let big_endian_bytes = unify(&input_bytes, &big_endian_format, &settings)?;
+        assert_eq!(unify(big_endian_bytes, &format, &settings)?, input_bytes);
+
+        let big_endian_bytes = unify(&input_bytes, &big_endian_format, &Settings::default())?;
+        assert_eq!(unify(big_endian_bytes, &format, &Settings::default())?, lua51_modulo_bytes_with(format, 0)?);
+
+        // The instructions that replace the `MOD` have no padding.
+        let settings = Settings::builder()
+            .preserve_instruction_padding(true)
+            .output_disallowed_opcodes(lua51::OpcodeSet::from_names(&["MOD"])?)
+            .build()?;
+        let output_bytes = unify(&input_bytes, &format, &settings)?;
+        let load_constant: u64 = 1 | (1 << 14) | (1 << 40);
+        let start = output_bytes.windows(8).position(|window| window == load_constant.to_le_bytes()).unwrap();
+        let padding: Vec<u64> = output_bytes[start..start + 80]
+            .chunks(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()) >> 32)
+            .collect();
+
+        assert_eq!(padding, [1 << 8, 0, 0, 0, 0, 0, 0, 0, 1 << 8, 1 << 8]);
+        Ok(())
+    }
+
+    /// Lua 5.1 byte code for `local sequence = { 4, 5 }` followed by
+    /// `result = #sequence + 7 % 10`. If `length_first` is not set, the modulo is
+    /// computed before the length.
+    fn lua51_length_modulo_bytes(length_first: bool) -> Result<Vec<u8>, LunifyError> {
+        // `LEN` into one register and `LOADK`, `MOD` into the other one.
+        let (length_register, modulo_register) = if length_first { (1, 2) } else { (2, 1) };
+        let length = [lua51_abc(20, length_register, 0, 0)];
+        let modulo = [lua51_abx(1, modulo_register, 3), lua51_abc(16, modulo_register, modulo_register, 256 | 4)];
+
+        // `NEWTABLE 0 2 0`, `LOADK 1 1`, `LOADK 2 2`, `SETLIST 0 2 1`, the length and
+        // the modulo, `ADD 1 1 2`, `SETGLOBAL 1 0`, `RETURN 0 1`.
+        let mut instructions = vec![lua51_abc(10, 0, 2, 0), lua51_abx(1, 1, 1), lua51_abx(1, 2, 2), lua51_abc(34, 0, 2, 1)];
+        match length_first {
+            true => instructions.extend(length.iter().chain(&modulo)),
+            false => instructions.extend(modulo.iter().chain(&length)),
+        }
+        instructions.extend([lua51_abc(12, 1, 1, 2), lua51_abx(7, 1, 0), lua51_abc(30, 0, 1, 0)]);
+
+        lua51_chunk_bytes(&LUA50_FORMAT, &TestFunction {
+            source_file: "@length_modulo.lua",
+            header: [0, 0, 2, 3],
+            instructions: &instructions,
+            line: Some(1),
+            constants: &[
+                TestConstant::String("result"),
+                TestConstant::Number(4.0),
+                TestConstant::Number(5.0),
+                TestConstant::Number(7.0),
+                TestConstant::Number(10.0),
+            ],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn canonicalize_constants() -> Result<(), LunifyError> {
+        let mut settings = Settings::default();
+        settings.output.disallowed_opcodes = lua51::OpcodeSet::from_names(&["LEN", "MOD"])?;
+
+        // Everything from the first constant to the end of the function.
+        let result_constant = encode_string(b"result", &LUA50_FORMAT)?;
+        let constants = |output_bytes: &[u8]| {
+            let offset = output_bytes.windows(result_constant.len()).position(|window| window == result_constant).unwrap();
+            output_bytes[offset..].to_vec()
+        };
+
+        // Lowering creates the constants for the shim and for `math.floor` in the order
+        // of the instructions.
+        let length_first = unify(&lua51_length_modulo_bytes(true)?, &LUA50_FORMAT, &settings)?;
+        let modulo_first = unify(&lua51_length_modulo_bytes(false)?, &LUA50_FORMAT, &settings)?;
+        assert_ne!(constants(&length_first), constants(&modulo_first));
+
+        settings.output.canonicalize_constants = true;
+        let length_first = unify(&lua51_length_modulo_bytes(true)?, &LUA50_FORMAT, &settings)?;
+        let modulo_first = unify(&lua51_length_modulo_bytes(false)?, &LUA50_FORMAT, &settings)?;
+        assert_eq!(constants(&length_first), constants(&modulo_first));
+        assert_eq!(validate(&length_first, &settings), Ok(()));
+        assert_eq!(validate(&modulo_first, &settings), Ok(()));
+
+        #[cfg(feature = "integration")]
+        for output_bytes in [length_first, modulo_first] {
+            test_output_with_setup("function __len_shim(value) return #value end", &output_bytes);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn synthetic_constants_first() -> Result<(), LunifyError> {
+        let mut settings = Settings::default();
+        settings.output.disallowed_opcodes = lua51::OpcodeSet::from_names(&["LEN", "MOD"])?;
+        settings.output.synthetic_constants_last = false;
+
+        let output_bytes = unify(&lua51_length_modulo_bytes(true)?, &LUA50_FORMAT, &settings)?;
+        assert_eq!(validate(&output_bytes, &settings), Ok(()));
+
+        // The shim is created first, so it is the first constant now.
+        let shim_constant = encode_string(b"__len_shim", &LUA50_FORMAT)?;
+        let result_constant = encode_string(b"result", &LUA50_FORMAT)?;
+        let position = |constant: &[u8]| output_bytes.windows(constant.len()).position(|window| window == constant);
+        assert!(position(&shim_constant) < position(&result_constant));
+
+        #[cfg(feature = "integration")]
+        test_output_with_setup("function __len_shim(value) return #value end", &output_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn disallowed_vararg() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../test_files/variadic.luab");
+        let mut settings = Settings::default();
+        settings.output.disallowed_opcodes = lua51::OpcodeSet::from_names(&["VARARG"])?;
+
+        let result = unify(input_bytes, &Format::default(), &settings);
+        assert!(matches!(result, Err(LunifyError::DisallowedOpcode { name: "VARARG", .. })));
+        Ok(())
+    }
+
+    #[test]
+    fn strict_decoding() -> Result<(), LunifyError> {
+        let settings = Settings {
+            strict_decoding: true,
+            ..Default::default()
+        };
+
+        // Table size hints are stored in B and C of `NEWTABLE`, so they must not be
+        // rejected.
+        unify(include_bytes!("../test_files/large_table.luab"), &Format::default(), &settings)?;
+        unify(include_bytes!("../test_files/little_endian.luab"), &Format::default(), &settings)?;
+        Ok(())
+    }
+
+    #[test]
+    fn repair_passed_through_input() -> Result<(), LunifyError> {
+        // `LOADNIL 0 0` and `RETURN 0 1`, with a local variable that ends after the
+        // last instruction.
+        let chunk_bytes = |end_program_counter| {
+            lua51_chunk_bytes(&Format::default(), &TestFunction {
+                header: [0, 0, 2, 2],
+                instructions: &[lua51_abc(3, 0, 0, 0), lua51_abc(30, 0, 1, 0)],
+                local_variables: &[("x", 0, end_program_counter)],
+                ..Default::default()
+            })
+        };
+        let input_bytes = chunk_bytes(9)?;
+
+        let output_bytes = with_pass_through(|| unify_cow(&input_bytes, &Format::default(), &Settings::default()))?;
+        assert!(matches!(output_bytes, Cow::Owned(_)));
+        assert_eq!(output_bytes, chunk_bytes(2)?);
+
+        let settings = Settings::builder().repair_debug_info(false).build()?;
+        let result = with_pass_through(|| unify_cow(&input_bytes, &Format::default(), &settings));
+        assert_eq!(result, Err(LunifyError::InvalidLocalVariable {
+            index: 0,
+            start_program_counter: 0,
+            end_program_counter: 9,
+        }));
+        Ok(())
+    }
+
+    #[test]
+    fn repair_stack_size_of_passed_through_input() -> Result<(), LunifyError> {
+        // `RETURN 0 1` in a function that declares a stack size of 0.
+        let chunk_bytes = |maximum_stack_size| {
+            lua51_chunk_bytes(&Format::default(), &TestFunction {
+                header: [0, 0, 2, maximum_stack_size],
+                instructions: &[lua51_abc(30, 0, 1, 0)],
+                ..Default::default()
+            })
+        };
+        let input_bytes = chunk_bytes(0)?;
+
+        let output_bytes = with_pass_through(|| unify_cow(&input_bytes, &Format::default(), &Settings::default()))?;
+        assert!(matches!(output_bytes, Cow::Owned(_)));
+        assert_eq!(output_bytes, chunk_bytes(2)?);
+        Ok(())
+    }
+
+    #[test]
+    fn strict_decoding_passed_through_input() -> Result<(), LunifyError> {
+        // `MOVE 0 1` with C set to 5, `RETURN 0 1`.
+        let input_bytes = lua51_chunk_bytes(&Format::default(), &TestFunction {
+            header: [0, 0, 2, 2],
+            instructions: &[lua51_abc(0, 0, 1, 5), lua51_abc(30, 0, 1, 0)],
+            ..Default::default()
+        })?;
+        let settings = Settings {
+            strict_decoding: true,
+            ..Default::default()
+        };
+
+        let result = with_pass_through(|| unify_cow(&input_bytes, &Format::default(), &settings));
+        assert_eq!(result, Err(LunifyError::UnexpectedOperandBits {
+            program_counter: 0,
+            opcode: 0,
+            field: InstructionField::C,
+        }));
+
+        let output_bytes = with_pass_through(|| unify_cow(&input_bytes, &Format::default(), &Settings::default()))?;
+        assert!(matches!(output_bytes, Cow::Borrowed(_)));
+        Ok(())
+    }
+
+    /// Convert `input_bytes` and collect the diagnostics.
+    fn diagnostics(input_bytes: &[u8]) -> Result<Vec<Diagnostic>, LunifyError> {
+        let diagnostics = RefCell::new(Vec::new());
+        let record = |diagnostic: Diagnostic| diagnostics.borrow_mut().push(diagnostic);
+        let settings = Settings::builder().diagnostics(Some(DiagnosticCallback(&record))).build()?;
+
+        unify(input_bytes, &Format::default(), &settings)?;
+        Ok(diagnostics.into_inner())
+    }
+
+    #[test]
+    fn no_diagnostics() -> Result<(), LunifyError> {
+        let inputs: [&[u8]; 4] = [
+            include_bytes!("../test_files/for_loop.luab"),
+            include_bytes!("../test_files/large_table.luab"),
+            include_bytes!("../test_files/lua50.luab"),
+            include_bytes!("../test_files/variadic.luab"),
+        ];
+
+        for input_bytes in inputs {
+            assert_eq!(diagnostics(input_bytes)?, []);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn declared_stack_too_small_diagnostic() -> Result<(), LunifyError> {
+        // `LOADK 5 0` and `RETURN 0 1` with a declared stack size of 2.
+        let input_bytes = lua50_function_bytes(2, &[lua50_abx(1, 5, 0), lua50_abc(27, 0, 1, 0)])?;
+        let diagnostics = diagnostics(&input_bytes)?;
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, DiagnosticCode::DeclaredStackTooSmall);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(diagnostics[0].function_path, FunctionPath::root());
+        Ok(())
+    }
+
+    #[test]
+    fn unexpected_operand_bits_diagnostic() -> Result<(), LunifyError> {
+        // `MOVE 0 1` with C set to 5.
+        let input_bytes = corrupted_modulo_bytes(0, lua51_abc(0, 0, 1, 5))?;
+        let diagnostics = diagnostics(&input_bytes)?;
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, DiagnosticCode::UnexpectedOperandBits);
+        assert_eq!(diagnostics[0].program_counter, Some(0));
+        Ok(())
+    }
+
+    /// Convert [`lua51_modulo_bytes`] and replace one of its instructions.
+    fn corrupted_modulo_bytes(index: usize, instruction: u64) -> Result<Vec<u8>, LunifyError> {
+        let instructions = [lua51_abx(1, 0, 1), lua51_abc(16, 0, 0, 256 | 2), lua51_abx(7, 0, 0), lua51_abc(30, 0, 1, 0)];
+        let code: Vec<u8> = instructions.iter().flat_map(|&instruction| (instruction as u32).to_le_bytes()).collect();
+
+        let mut output_bytes = unify(&lua51_modulo_bytes()?, &LUA50_FORMAT, &Default::default())?;
+        let offset = output_bytes.windows(code.len()).position(|window| window == code).unwrap() + index * 4;
+        output_bytes[offset..offset + 4].copy_from_slice(&(instruction as u32).to_le_bytes());
+        Ok(output_bytes)
+    }
+
+    fn validation_issue(output_bytes: &[u8]) -> ValidationIssue {
+        let mut issues = validate(output_bytes, &Default::default()).unwrap_err();
+        assert_eq!(issues.len(), 1);
+        issues.remove(0)
+    }
+
+    #[test]
+    fn validate_converted() -> Result<(), LunifyError> {
+        let input_files: [&[u8]; 9] = [
+            include_bytes!("../test_files/32bit.luab"),
+            include_bytes!("../test_files/big_endian.luab"),
+            include_bytes!("../test_files/constants.luab"),
+            include_bytes!("../test_files/dynamic_table.luab"),
+            include_bytes!("../test_files/for_loop.luab"),
+            include_bytes!("../test_files/large_table.luab"),
+            include_bytes!("../test_files/little_endian.luab"),
+            include_bytes!("../test_files/lua50.luab"),
+            include_bytes!("../test_files/variadic.luab"),
+        ];
+
+        for input_bytes in input_files {
+            let output_bytes = unify(input_bytes, &Format::default(), &Default::default())?;
+            assert_eq!(validate(&output_bytes, &Default::default()), Ok(()));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn validate_constant_out_of_range() -> Result<(), LunifyError> {
+        // `MOD 0 0 K9`
+        let issue = validation_issue(&corrupted_modulo_bytes(1, lua51_abc(16, 0, 0, 256 | 9))?);
+        assert_eq!(issue.program_counter, Some(1));
+        assert_eq!(issue.description, "constant 9 doesn't exist");
+        Ok(())
+    }
+
+    #[test]
+    fn validate_register_out_of_range() -> Result<(), LunifyError> {
+        // `SETGLOBAL 5 0`
+        let issue = validation_issue(&corrupted_modulo_bytes(2, lua51_abx(7, 5, 0))?);
+        assert_eq!(issue.program_counter, Some(2));
+        assert_eq!(issue.description, "register 5 is outside of the stack of size 2");
+        Ok(())
+    }
+
+    #[test]
+    fn validate_jump_out_of_range() -> Result<(), LunifyError> {
+        // `JMP 10`
+        let issue = validation_issue(&corrupted_modulo_bytes(0, lua51_asbx(22, 0, 10))?);
+        assert_eq!(issue.program_counter, Some(0));
+        assert_eq!(issue.description, "jump by 10 doesn't land on an instruction");
+        Ok(())
+    }
+
+    #[test]
+    fn validate_closure_upvalues() -> Result<(), LunifyError> {
+        let mut input_bytes = lua51_trailer_bytes(None, b"", b"")?;
+
+        // Give the nested function an upvalue that the `CLOSURE` doesn't provide.
+        let header = [1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 2];
+        let offset = input_bytes.windows(header.len()).position(|window| window == header).unwrap() + 8;
+        input_bytes[offset] = 1;
+
+        let issue = validation_issue(&unify(&input_bytes, &LUA50_FORMAT, &Default::default())?);
+        assert!(issue.path.is_empty());
+        assert_eq!(issue.program_counter, Some(0));
+        assert_eq!(issue.description, "CLOSURE needs 1 upvalue instructions but only 0 follow");
+        Ok(())
+    }
+
+    #[test]
+    fn validate_malformed() {
+        let issue = validation_issue(b"\x1bLua\x51");
+        assert_eq!(issue.program_counter, None);
+    }
+
+    #[test]
+    fn verify() -> Result<(), LunifyError> {
+        let mut settings = Settings::default();
+        settings.output.verify = true;
+        unify(&lua51_modulo_bytes()?, &LUA50_FORMAT, &settings)?;
+
+        // Broken input is passed through as long as nothing needs to be converted.
+        let input_bytes = corrupted_modulo_bytes(2, lua51_abx(7, 5, 0))?;
+        let result = unify(&input_bytes, &LUA50_FORMAT, &settings);
+        assert!(matches!(result, Err(LunifyError::InvalidOutput(issues)) if issues.len() == 1));
+        Ok(())
+    }
+
+    #[test]
+    fn for_loop_forward_jump() -> Result<(), LunifyError> {
+        // `LOADK 0 0`, `LOADK 1 0`, `LOADK 2 0`, `FORLOOP 0 1`, `RETURN 0 1`.
+        let input_bytes = lua50_function_bytes(3, &[
+            lua50_abx(1, 0, 0),
+            lua50_abx(1, 1, 0),
+            lua50_abx(1, 2, 0),
+            lua50_asbx(28, 0, 1),
+            lua50_abc(27, 0, 1, 0),
+        ])?;
+        let result = unify(&input_bytes, &Format::default(), &Default::default());
+        assert_eq!(result, Err(LunifyError::UnexpectedForwardJump));
+        Ok(())
+    }
+
+    #[test]
+    fn for_loop_jump_before_start() -> Result<(), LunifyError> {
+        // `LOADK 0 0`, `LOADK 1 0`, `LOADK 2 0`, `FORLOOP 0 -10`, `RETURN 0 1`.
+        let input_bytes = lua50_function_bytes(3, &[
+            lua50_abx(1, 0, 0),
+            lua50_abx(1, 1, 0),
+            lua50_abx(1, 2, 0),
+            lua50_asbx(28, 0, -10),
+            lua50_abc(27, 0, 1, 0),
+        ])?;
+        let result = unify(&input_bytes, &Format::default(), &Default::default());
+        assert_eq!(result, Err(LunifyError::JumpOutOfBounds));
+        Ok(())
+    }
+
+    #[test]
+    fn conditions() -> Result<(), LunifyError> {
+        // R0 is `y`, R1 is `z` and R2 is `x`. Every chunk sets `result` to 9 if the
+        // condition behaves correctly.
+        let load_nine = |register: u64| lua50_abx(1, register, 1);
+        let jump = lua50_asbx(20, 0, 1);
+        let set_result = [lua50_abx(7, 2, 0), lua50_abc(27, 0, 1, 0)];
+
+        for y in [load_nine(0), lua50_abc(2, 0, 0, 0)] {
+            let is_truthy = y == load_nine(0);
+
+            // `x = y or z`.
+            let or = [y, load_nine(1), lua50_abc(24, 2, 0, 1), jump, lua50_abc(0, 2, 1, 0)];
+
+            // `x = y and z`, followed by `if x == false then x = 9 end`.
+            let and = [
+                y,
+                load_nine(1),
+                lua50_abc(24, 2, 0, 0),
+                jump,
+                lua50_abc(0, 2, 1, 0),
+                lua50_abc(2, 3, 0, 0),
+                lua50_abc(21, 0, 2, 3),
+                jump,
+                load_nine(2),
+            ];
+
+            // `if y then x = 9 end` if `y` is truthy, otherwise `x = 9` followed by
+            // `if y then x = nil end`.
+            let (initial, then) = match is_truthy {
+                true => (lua50_abc(3, 2, 2, 0), load_nine(2)),
+                false => (load_nine(2), lua50_abc(3, 2, 2, 0)),
+            };
+            let condition = [y, initial, lua50_abc(24, 0, 0, 0), jump, then];
+
+            for instructions in [&or[..], &and, &condition] {
+                let input_bytes = lua50_result_bytes(&[instructions, &set_result].concat())?;
+                let _output_bytes = unify(&input_bytes, &Format::default(), &Default::default())?;
+
+                #[cfg(feature = "integration")]
+                test_output(&_output_bytes);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn table_length(element_count: u64) -> Result<(), LunifyError> {
+        let input_bytes = lua50_table_bytes(element_count, 32)?;
+        let _output_bytes = unify(&input_bytes, &Format::default(), &Default::default())?;
+
+        #[cfg(feature = "integration")]
+        {
+            use mlua::prelude::*;
+
+            let lua = Lua::new();
+            let table: LuaTable = lua.load(&_output_bytes).eval().unwrap();
+            assert_eq!(table.raw_len(), element_count as i64);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn table_exactly_one_page() -> Result<(), LunifyError> {
+        table_length(50)
+    }
+
+    #[test]
+    fn table_exactly_two_pages() -> Result<(), LunifyError> {
+        table_length(100)
+    }
+
+    /// Lua 5.0 byte code for a table constructor with 54 elements that are all
+    /// `9`, except for the 51st one, which is `9 == 9 and 9 or false`. That is
+    /// the first element of the second page in the output, so the flush falls
+    /// into a branch. If `is_false` is set, the comparison fails.
+    fn conditional_table_bytes(is_false: bool) -> Result<Vec<u8>, LunifyError> {
+        let load = |a: u64| lua50_abx(1, a, 0);
+
+        let mut instructions = vec![lua50_abc(10, 0, 0, 0)];
+        instructions.extend((1..=32).map(load));
+        instructions.push(lua50_abx(31, 0, 31));
+        instructions.extend((1..=18).map(load));
+        instructions.extend([
+            lua50_abc(21, is_false as u64, LUA50_CONSTANT, LUA50_CONSTANT),
+            lua50_asbx(20, 0, 2),
+            load(19),
+            lua50_asbx(20, 0, 1),
+            lua50_abc(2, 19, 0, 0),
+        ]);
+        instructions.extend((20..=22).map(load));
+        instructions.extend([lua50_abx(31, 0, 53), lua50_abc(27, 0, 2, 0)]);
+
+        lua50_function_bytes(33, &instructions)
+    }
+
+    #[test]
+    fn table_with_condition_across_flush() -> Result<(), LunifyError> {
+        for is_false in [false, true] {
+            let input_bytes = conditional_table_bytes(is_false)?;
+            let output_bytes = unify(&input_bytes, &Format::default(), &Default::default())?;
+            assert_eq!(validate(&output_bytes, &Default::default()), Ok(()));
+
+            #[cfg(feature = "integration")]
+            {
+                use mlua::prelude::*;
+
+                let lua = Lua::new();
+                let table: LuaTable = lua.load(&output_bytes).eval().unwrap();
+                assert_eq!(table.raw_len(), 54);
+
+                for index in 1..=54 {
+                    let expected = match index == 51 && is_false {
+                        true => LuaValue::Boolean(false),
+                        false => LuaValue::Number(9.0),
+                    };
+                    assert_eq!(table.raw_get::<_, LuaValue>(index).unwrap(), expected);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn detect_fields_per_flush_32() -> Result<(), LunifyError> {
+        let input_bytes = lua50_table_bytes(70, 32)?;
+        assert_eq!(detect_lua50_fields_per_flush(&input_bytes, &Default::default())?, Some(32));
+        Ok(())
+    }
+
+    #[test]
+    fn detect_fields_per_flush_50() -> Result<(), LunifyError> {
+        let input_bytes = lua50_table_bytes(70, 50)?;
+        assert_eq!(detect_lua50_fields_per_flush(&input_bytes, &Default::default())?, Some(50));
+        Ok(())
+    }
+
+    #[test]
+    fn detect_fields_per_flush_single_flush() -> Result<(), LunifyError> {
+        let input_bytes = lua50_table_bytes(10, 50)?;
+        assert_eq!(detect_lua50_fields_per_flush(&input_bytes, &Default::default())?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn detect_fields_per_flush_lua51() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../test_files/32bit.luab");
+        assert_eq!(detect_lua50_fields_per_flush(input_bytes, &Default::default())?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn auto_detect_fields_per_flush() -> Result<(), LunifyError> {
+        let input_bytes = lua50_table_bytes(70, 50)?;
+        let output_format = Format::default();
+
+        let explicit_settings = Settings {
+            lua50: lua50::Settings {
+                fields_per_flush: 50,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let detecting_settings = Settings {
+            lua50: lua50::Settings {
+                auto_detect_fields_per_flush: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let expected = unify(&input_bytes, &output_format, &explicit_settings)?;
+        let output_bytes = unify(&input_bytes, &output_format, &detecting_settings)?;
+
+        assert_eq!(output_bytes, expected);
+        Ok(())
+    }
+
+    /// Lua 5.0 byte code for the following script, assembled by hand since it
+    /// requires closures that capture the loop variable.
+    ///
+    /// ```lua
+    /// local f = {}
+    /// for i = 1, 3 do
+    ///     f[i] = function() return i end
+    /// end
+    /// result = f[1]() + f[2]() + f[3]() * 2
+    /// ```
+    fn closures_in_for_loop_bytes() -> Result<Vec<u8>, LunifyError> {
+        let instructions = [
+            lua50_abc(10, 0, 0, 0),
+            lua50_abx(1, 1, 1),
+            lua50_abx(1, 2, 3),
+            lua50_abx(1, 3, 1),
+            lua50_abc(13, 1, 1, 3),
+            lua50_asbx(20, 0, 4),
+            lua50_abx(34, 4, 0),
+            lua50_abc(0, 0, 1, 0),
+            lua50_abc(9, 0, 1, 4),
+            lua50_abc(33, 1, 0, 0),
+            lua50_asbx(28, 1, -5),
+            lua50_abc(6, 4, 0, LUA50_CONSTANT + 1),
+            lua50_abc(25, 4, 1, 2),
+            lua50_abc(6, 5, 0, LUA50_CONSTANT + 2),
+            lua50_abc(25, 5, 1, 2),
+            lua50_abc(12, 4, 4, 5),
+            lua50_abc(6, 5, 0, LUA50_CONSTANT + 3),
+            lua50_abc(25, 5, 1, 2),
+            lua50_abc(14, 5, 5, LUA50_CONSTANT + 2),
+            lua50_abc(12, 4, 4, 5),
+            lua50_abx(7, 4, 0),
+            lua50_abc(27, 0, 1, 0),
+        ];
+
+        // Closure returning its upvalue.
+        let closure_function = TestFunction {
+            header: [1, 0, 0, 2],
+            instructions: &[lua50_abc(4, 0, 0, 0), lua50_abc(27, 0, 2, 0)],
+            line: Some(1),
+            ..Default::default()
+        };
+
+        lua50_chunk_bytes(&TestFunction {
+            header: [0, 0, 0, 6],
+            instructions: &instructions,
+            line: Some(1),
+            constants: &[
+                TestConstant::String("result"),
+                TestConstant::Number(1.0),
+                TestConstant::Number(2.0),
+                TestConstant::Number(3.0),
+            ],
+            functions: &[&closure_function.lua50_bytes()?],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn closures_in_for_loop() -> Result<(), LunifyError> {
+        let input_bytes = closures_in_for_loop_bytes()?;
+        let output_format = Format::default();
+        let _output_bytes = unify(&input_bytes, &output_format, &Default::default())?;
+
+        // The result is 9 only if every closure captured its own loop value.
+        #[cfg(feature = "integration")]
+        test_output(&_output_bytes);
+        Ok(())
+    }
+
+    /// Lua 5.0 byte code for the following script, which records every value of
+    /// the loop index as a digit of the result.
+    ///
+    /// ```lua
+    /// result = 0
+    /// for i = start, limit, step do
+    ///     result = result * 10 + i
+    /// end
+    /// ```
+    fn numeric_for_bytes(start: f64, limit: f64, step: f64) -> Result<Vec<u8>, LunifyError> {
+        let instructions = [
+            lua50_abx(1, 0, 1),
+            lua50_abx(1, 1, 3),
+            lua50_abx(1, 2, 4),
+            lua50_abx(1, 3, 5),
+            lua50_abc(13, 1, 1, 3),
+            lua50_asbx(20, 0, 2),
+            lua50_abc(14, 0, 0, LUA50_CONSTANT + 2),
+            lua50_abc(12, 0, 0, 1),
+            lua50_asbx(28, 1, -3),
+            lua50_abx(7, 0, 0),
+            lua50_abc(27, 0, 1, 0),
+        ];
+
+        lua50_chunk_bytes(&TestFunction {
+            header: [0, 0, 0, 4],
+            instructions: &instructions,
+            constants: &[
+                TestConstant::String("result"),
+                TestConstant::Number(0.0),
+                TestConstant::Number(10.0),
+                TestConstant::Number(start),
+                TestConstant::Number(limit),
+                TestConstant::Number(step),
+            ],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn numeric_for_preparation() -> Result<(), LunifyError> {
+        // The start, limit and step of the loop, and the values of the index.
+        let loops = [(1.0, 3.0, 1.0, 123.0), (3.0, 1.0, -1.0, 321.0), (1.0, 0.0, 1.0, 0.0)];
+
+        for (start, limit, step, _expected) in loops {
+            let output_bytes = unify(numeric_for_bytes(start, limit, step)?, &Format::default(), &Settings::default())?;
+            assert_eq!(validate(&output_bytes, &Settings::default()), Ok(()));
+
+            // The `SUB` and `JMP` preparing the loop are merged into a `FORPREP`.
+            let chunk = RawChunk::parse(&output_bytes, &Settings::default())?;
+            let opcodes: Vec<u64> = chunk.main.instructions.iter().map(|instruction| instruction & 0x3F).collect();
+            assert!(opcodes.contains(&32));
+            assert!(!opcodes.contains(&13));
+
+            #[cfg(feature = "integration")]
+            {
+                use mlua::prelude::*;
+
+                let lua = Lua::new();
+                lua.load(&output_bytes).exec().unwrap();
+                assert_eq!(lua.globals().get::<_, LuaNumber>("result").unwrap(), _expected);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn for_loop_empty_body() -> Result<(), LunifyError> {
+        // An empty loop from 9 to 9, where register 3 holds 81 across the loop and
+        // `result = R3 / 9` afterwards.
+        let input_bytes = lua50_result_bytes(&[
+            lua50_abc(14, 3, LUA50_CONSTANT + 1, LUA50_CONSTANT + 1),
+            lua50_abx(1, 0, 1),
+            lua50_abx(1, 1, 1),
+            lua50_abx(1, 2, 1),
+            lua50_abc(13, 0, 0, 2),
+            lua50_asbx(20, 0, 0),
+            lua50_asbx(28, 0, -1),
+            lua50_abc(15, 3, 3, LUA50_CONSTANT + 1),
+            lua50_abx(7, 3, 0),
+            lua50_abc(27, 0, 1, 0),
+        ])?;
+
+        let output_bytes = unify(&input_bytes, &Format::default(), &Settings::default())?;
+        assert_eq!(validate(&output_bytes, &Settings::default()), Ok(()));
+
+        // The `FORPREP` lands on the `SETGLOBAL` saving RA+3, and RA+3 is only restored
+        // once the loop is done.
+        let chunk = RawChunk::parse(&output_bytes, &Settings::default())?;
+        let opcodes: Vec<u64> = chunk.main.instructions.iter().map(|instruction| instruction & 0x3F).collect();
+        assert_eq!(opcodes[4..8], [32, 7, 31, 5]);
+
+        #[cfg(feature = "integration")]
+        test_output(&output_bytes);
+
+        Ok(())
+    }
+
+    /// Lua 5.0 byte code for the following script, where the constants are
+    /// placed above index 255, so they can't be encoded in an RK operand of
+    /// Lua 5.1.
+    ///
+    /// ```lua
+    /// local t = { inner = {} }
+    /// t.inner.get = function(self) return 1 end
+    /// local value = t.inner:get() + (4 + 4)
+    /// if value == 9 then
+    ///     result = value
+    /// end
+    /// ```
+    fn large_constant_indices_bytes() -> Result<Vec<u8>, LunifyError> {
+        let instructions = [
+            lua50_abc(10, 0, 0, 0),
+            lua50_abc(10, 1, 0, 0),
+            lua50_abc(9, 0, LUA50_CONSTANT + 256, 1),
+            lua50_abx(34, 1, 0),
+            lua50_abc(6, 2, 0, LUA50_CONSTANT + 256),
+            // The jump has to land on the first inserted `LOADK`.
+            lua50_asbx(20, 0, 1),
+            lua50_abc(3, 2, 2, 0),
+            lua50_abc(9, 2, LUA50_CONSTANT + 257, 1),
+            lua50_abc(6, 2, 0, LUA50_CONSTANT + 256),
+            lua50_abc(11, 2, 2, LUA50_CONSTANT + 257),
+            lua50_abc(25, 2, 2, 2),
+            lua50_abc(12, 3, LUA50_CONSTANT + 258, LUA50_CONSTANT + 258),
+            lua50_abc(12, 2, 2, 3),
+            lua50_abc(21, 0, 2, LUA50_CONSTANT + 259),
+            lua50_asbx(20, 0, 1),
+            lua50_abx(7, 2, 0),
+            lua50_abc(27, 0, 1, 0),
+        ];
+
+        // Constants, with unused numbers to push the others above index 255.
+        let mut constants = vec![TestConstant::String("result")];
+        constants.extend((1..256).map(|number| TestConstant::Number(number as f64)));
+        constants.extend([
+            TestConstant::String("inner"),
+            TestConstant::String("get"),
+            TestConstant::Number(4.0),
+            TestConstant::Number(9.0),
+        ]);
+
+        // Method returning 1.
+        let method_function = TestFunction {
+            header: [0, 1, 0, 2],
+            instructions: &[lua50_abx(1, 1, 0), lua50_abc(27, 1, 2, 0)],
+            line: Some(1),
+            constants: &[TestConstant::Number(1.0)],
+            ..Default::default()
+        };
+
+        lua50_chunk_bytes(&TestFunction {
+            header: [0, 0, 0, 4],
+            instructions: &instructions,
+            line: Some(1),
+            constants: &constants,
+            functions: &[&method_function.lua50_bytes()?],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn large_constant_indices() -> Result<(), LunifyError> {
+        let input_bytes = large_constant_indices_bytes()?;
+        let output_format = Format::default();
+        let (output_bytes, report) = unify_with_report(&input_bytes, &output_format, &Default::default())?;
+
+        // Two registers are needed for the two constants of the `ADD`.
+        assert_eq!(report.functions[0].maximum_stack_size, 6);
+        assert_eq!(validate(&output_bytes, &Default::default()), Ok(()));
+
+        // The result is 9 only if every constant was loaded and the jump landed on
+        // the `LOADK` in front of the `SETTABLE`.
+        #[cfg(feature = "integration")]
+        test_output(&output_bytes);
+        Ok(())
+    }
+
+    /// Lua 5.0 byte code for the following script, assembled by hand since the
+    /// generic for loop iterates over the table directly, which Lua 5.1 doesn't
+    /// support.
+    ///
+    /// ```lua
+    /// local sequence = { 4, 5 }
+    /// result = 0
+    /// for _, value in sequence do
+    ///     result = result + value
+    /// end
+    /// ```
+    fn generic_for_bytes() -> Result<Vec<u8>, LunifyError> {
+        let instructions = [
+            lua50_abc(10, 0, 2, 0),
+            lua50_abx(1, 1, 1),
+            lua50_abx(1, 2, 2),
+            lua50_abx(31, 0, 1),
+            lua50_abx(1, 1, 3),
+            lua50_abx(7, 1, 0),
+            lua50_abc(0, 1, 0, 0),
+            lua50_abc(3, 2, 3, 0),
+            lua50_asbx(30, 1, 3),
+            lua50_abx(5, 5, 0),
+            lua50_abc(12, 5, 5, 4),
+            lua50_abx(7, 5, 0),
+            lua50_abc(29, 1, 0, 1),
+            lua50_asbx(20, 0, -5),
+            lua50_abc(27, 0, 1, 0),
+        ];
+
+        lua50_chunk_bytes(&TestFunction {
+            header: [0, 0, 0, 8],
+            instructions: &instructions,
+            line: Some(1),
+            constants: &[
+                TestConstant::String("result"),
+                TestConstant::Number(4.0),
+                TestConstant::Number(5.0),
+                TestConstant::Number(0.0),
+            ],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn tforprep_assume_table() -> Result<(), LunifyError> {
+        let input_bytes = generic_for_bytes()?;
+        let mut settings = Settings::default();
+        settings.output.tforprep_assume_table = true;
+
+        let output_bytes = unify(&input_bytes, &Format::default(), &settings)?;
+        assert_eq!(validate(&output_bytes, &settings), Ok(()));
+        assert!(!output_bytes.windows(5).any(|window| window == b"type\0"));
+
+        // Sandboxes commonly remove `type`, which the type check would call.
+        #[cfg(feature = "integration")]
+        test_output_with_setup("type = nil", &output_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn tforprep_renamed_globals() -> Result<(), LunifyError> {
+        let mut settings = Settings::default();
+        settings.output.tforprep_type_global = "typeof";
+        settings.output.tforprep_next_global = "iterate";
+
+        // The first input iterates over a table and the second one calls `ipairs`, so
+        // both branches of the type check are taken.
+        for input_bytes in [generic_for_bytes()?, include_bytes!("../test_files/for_loop.luab").to_vec()] {
+            let output_bytes = unify(&input_bytes, &Format::default(), &settings)?;
+            assert_eq!(validate(&output_bytes, &settings), Ok(()));
+            assert!(output_bytes.windows(7).any(|window| window == b"typeof\0"));
+            assert!(!output_bytes.windows(5).any(|window| window == b"type\0"));
+
+            #[cfg(feature = "integration")]
+            test_output_with_setup("typeof, iterate, type, next = type, next, nil, nil", &output_bytes);
+        }
+
+        Ok(())
+    }
+
+    /// Lua 5.0 byte code for `result = 2 ^ 3`.
+    fn power_bytes() -> Result<Vec<u8>, LunifyError> {
+        lua50_chunk_bytes(&TestFunction {
+            header: [0, 0, 0, 2],
+            instructions: &[lua50_abc(16, 0, LUA50_CONSTANT + 1, LUA50_CONSTANT + 2), lua50_abx(7, 0, 0), lua50_abc(27, 0, 1, 0)],
+            constants: &[TestConstant::String("result"), TestConstant::Number(2.0), TestConstant::Number(3.0)],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn lower_power_to_call() -> Result<(), LunifyError> {
+        let input_bytes = power_bytes()?;
+
+        for lower_power_to_call in [false, true] {
+            let mut settings = Settings::default();
+            settings.output.lower_power_to_call = lower_power_to_call;
+
+            let output_bytes = unify(&input_bytes, &Format::default(), &settings)?;
+            assert_eq!(validate(&output_bytes, &settings), Ok(()));
+            assert_eq!(output_bytes.windows(6).any(|window| window == b"__pow\0"), lower_power_to_call);
+
+            // The global is only called if the `POW` is lowered, otherwise the power is
+            // computed natively.
+            #[cfg(feature = "integration")]
+            {
+                use mlua::prelude::*;
+
+                let lua = Lua::new();
+                lua.load("function __pow(a, b) return 9 end").exec().unwrap();
+                lua.load(&output_bytes).exec().unwrap();
+
+                let expected = if lower_power_to_call { 9.0 } else { 8.0 };
+                assert_eq!(lua.globals().get::<_, LuaNumber>("result").unwrap(), expected);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn rename_field() -> Result<(), LunifyError> {
+        // `result = table.getn({ 9 }) * 9`
+        let input_bytes = lua50_strings_bytes(&["result", "table", "getn"], &[
+            lua50_abc(10, 0, 0, 0),
+            lua50_abx(1, 1, 3),
+            lua50_abx(31, 0, 0),
+            lua50_abx(5, 1, 1),
+            lua50_abc(6, 1, 1, LUA50_CONSTANT + 2),
+            lua50_abc(0, 2, 0, 0),
+            lua50_abc(25, 1, 2, 2),
+            lua50_abc(14, 1, 1, LUA50_CONSTANT + 3),
+            lua50_abx(7, 1, 0),
+            lua50_abc(27, 0, 1, 0),
+        ])?;
+
+        let mut settings = Settings::default();
+        settings.output.rename_globals = lua51::Settings::lua50_stdlib_renames();
+
+        let output_bytes = unify(&input_bytes, &Format::default(), &settings)?;
+        assert_eq!(validate(&output_bytes, &settings), Ok(()));
+        assert!(output_bytes.windows(5).any(|window| window == b"maxn\0"));
+        assert!(!output_bytes.windows(5).any(|window| window == b"getn\0"));
+
+        #[cfg(feature = "integration")]
+        test_output(&output_bytes);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rename_shared_field() -> Result<(), LunifyError> {
+        // `result = tonumber(string.gfind("9", "%d+")())` followed by `name = "gfind"`,
+        // so the constant holding the name of the field is also used as a string.
+        let input_bytes = lua50_strings_bytes(&["result", "string", "gfind", "9", "%d+", "tonumber", "name"], &[
+            lua50_abx(5, 0, 1),
+            lua50_abc(6, 0, 0, LUA50_CONSTANT + 2),
+            lua50_abx(1, 1, 3),
+            lua50_abx(1, 2, 4),
+            lua50_abc(25, 0, 3, 2),
+            lua50_abc(25, 0, 1, 2),
+            lua50_abx(5, 1, 5),
+            lua50_abc(0, 2, 0, 0),
+            lua50_abc(25, 1, 2, 2),
+            lua50_abx(7, 1, 0),
+            lua50_abx(1, 0, 2),
+            lua50_abx(7, 0, 6),
+            lua50_abc(27, 0, 1, 0),
+        ])?;
+
+        let mut settings = Settings::default();
+        settings.output.rename_globals = lua51::Settings::lua50_stdlib_renames();
+
+        let output_bytes = unify(&input_bytes, &Format::default(), &settings)?;
+        assert_eq!(validate(&output_bytes, &settings), Ok(()));
+        assert!(output_bytes.windows(7).any(|window| window == b"gmatch\0"));
+        assert!(output_bytes.windows(6).any(|window| window == b"gfind\0"));
+
+        #[cfg(feature = "integration")]
+        {
+            use mlua::prelude::*;
+
+            let lua = Lua::new();
+            lua.load(&output_bytes).exec().unwrap();
+            assert_eq!(lua.globals().get::<_, LuaNumber>("result").unwrap(), 9.0);
+            assert_eq!(lua.globals().get::<_, String>("name").unwrap(), "gfind");
+        }
+
+        Ok(())
+    }
+
+    /// Lua 5.0 byte code for the following script, assembled by hand since it
+    /// relies on the `n` field of the implicit `arg` table.
+    ///
+    /// ```lua
+    /// local function sum(...)
+    ///     local result = 0
+    ///     for i = 1, arg.n do
+    ///         result = result + arg[i]
+    ///     end
+    ///     return result
+    /// end
+    /// result = sum(2, 3, 4)
+    /// ```
+    ///
+    /// If `is_stripped` is set, the functions have no line info.
+    fn vararg_count_bytes(is_stripped: bool) -> Result<Vec<u8>, LunifyError> {
+        let line = (!is_stripped).then_some(1);
+
+        let instructions = [
+            lua50_abx(34, 0, 0),
+            lua50_abx(1, 1, 1),
+            lua50_abx(1, 2, 2),
+            lua50_abx(1, 3, 3),
+            lua50_abc(25, 0, 4, 2),
+            lua50_abx(7, 0, 0),
+            lua50_abc(27, 0, 1, 0),
+        ];
+
+        // Variadic function summing its arguments.
+        let sum_instructions = [
+            lua50_abx(1, 1, 0),
+            lua50_abx(1, 2, 1),
+            lua50_abc(6, 3, 0, LUA50_CONSTANT + 2),
+            lua50_abx(1, 4, 1),
+            lua50_abc(13, 2, 2, 4),
+            lua50_asbx(20, 0, 2),
+            lua50_abc(6, 5, 0, 2),
+            lua50_abc(12, 1, 1, 5),
+            lua50_asbx(28, 2, -3),
+            lua50_abc(27, 1, 2, 0),
+            lua50_abc(27, 0, 1, 0),
+        ];
+
+        let sum_function = TestFunction {
+            header: [0, 0, 1, 6],
+            instructions: &sum_instructions,
+            line,
+            constants: &[TestConstant::Number(0.0), TestConstant::Number(1.0), TestConstant::String("n")],
+            ..Default::default()
+        };
+
+        lua50_chunk_bytes(&TestFunction {
+            header: [0, 0, 0, 4],
+            instructions: &instructions,
+            line,
+            constants: &[
+                TestConstant::String("result"),
+                TestConstant::Number(2.0),
+                TestConstant::Number(3.0),
+                TestConstant::Number(4.0),
+            ],
+            functions: &[&sum_function.lua50_bytes()?],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn vararg_count() -> Result<(), LunifyError> {
+        let input_bytes = vararg_count_bytes(false)?;
+        let output_format = Format::default();
+        let _output_bytes = unify(&input_bytes, &output_format, &Default::default())?;
+
+        // The result is 9 only if `arg.n` holds the number of arguments.
+        #[cfg(feature = "integration")]
+        test_output(&_output_bytes);
+        Ok(())
+    }
+
+    /// Lua 5.0 byte code for the following code, where the loop of `sum` starts at
+    /// the very first instruction, so the loop jumps back to where the variadic
+    /// prologue is inserted. The loop can't be written in Lua, because the control
+    /// variables are initialized by the caller.
+    ///
+    /// ```lua
+    /// local function sum(i, limit, step, ...)
+    ///     repeat
+    ///         result = result + arg[i]
+    ///         i = i + step
+    ///     until i > limit
+    /// end
+    /// result = 0
+    /// sum(1, 3, 1, 2, 3, 4)
+    /// ```
+    fn variadic_for_loop_bytes() -> Result<Vec<u8>, LunifyError> {
+        let instructions = [
+            lua50_abx(1, 0, 1),
+            lua50_abx(7, 0, 0),
+            lua50_abx(34, 0, 0),
+            lua50_abx(1, 1, 2),
+            lua50_abx(1, 2, 3),
+            lua50_abx(1, 3, 2),
+            lua50_abx(1, 4, 4),
+            lua50_abx(1, 5, 3),
+            lua50_abx(1, 6, 5),
+            lua50_abc(25, 0, 7, 1),
+            lua50_abc(27, 0, 1, 0),
+        ];
+
+        // Variadic function adding its arguments to `result`.
+        let sum_instructions = [
+            lua50_abx(5, 4, 0),
+            lua50_abc(6, 5, 3, 0),
+            lua50_abc(12, 4, 4, 5),
+            lua50_abx(7, 4, 0),
+            lua50_asbx(28, 0, -5),
+            lua50_abc(27, 0, 1, 0),
+        ];
+
+        let sum_function = TestFunction {
+            header: [0, 3, 1, 6],
+            instructions: &sum_instructions,
+            constants: &[TestConstant::String("result")],
+            ..Default::default()
+        };
+
+        let mut constants = vec![TestConstant::String("result")];
+        constants.extend([0.0, 1.0, 3.0, 2.0, 4.0].map(TestConstant::Number));
+
+        lua50_chunk_bytes(&TestFunction {
+            header: [0, 0, 0, 7],
+            instructions: &instructions,
+            constants: &constants,
+            functions: &[&sum_function.lua50_bytes()?],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn variadic_for_loop_at_start() -> Result<(), LunifyError> {
+        let input_bytes = variadic_for_loop_bytes()?;
+        let output_bytes = unify(&input_bytes, &Format::default(), &Default::default())?;
+        assert_eq!(validate(&output_bytes, &Default::default()), Ok(()));
+
+        // The result is 9 only if the loop restores `arg` in RA+3 on every iteration.
+        #[cfg(feature = "integration")]
+        test_output(&output_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn lua50_stripped() -> Result<(), LunifyError> {
+        let input_bytes = vararg_count_bytes(true)?;
+        let output_format = Format::default();
+        let output_bytes = unify(&input_bytes, &output_format, &Default::default())?;
+        let unstripped_bytes = unify(&vararg_count_bytes(false)?, &output_format, &Default::default())?;
+
+        // The instructions are the same, only the line info is missing in the output.
+        assert_eq!(validate(&output_bytes, &Default::default()), Ok(()));
+        assert!(output_bytes.len() < unstripped_bytes.len());
+        assert_eq!(unify(&output_bytes, &output_format, &Default::default())?, output_bytes);
+
+        #[cfg(feature = "integration")]
+        test_output(&output_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn variadic_needsarg() -> Result<(), LunifyError> {
+        let mut settings = Settings::default();
+        settings.output.use_needsarg_flag = true;
+
+        // The VM creates `arg` including the field `n`, so both need to work without a
+        // prologue.
+        for input_bytes in [include_bytes!("../test_files/variadic.luab").to_vec(), vararg_count_bytes(false)?] {
+            let output_bytes = unify(&input_bytes, &Format::default(), &settings)?;
+            assert_eq!(validate(&output_bytes, &settings), Ok(()));
+
+            #[cfg(feature = "integration")]
+            test_output(&output_bytes);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn custom_signature() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../test_files/custom_signature.luab").to_vec();
+        let output_format = Format::default();
+
+        let settings = Settings {
+            lua51: lua51::Settings {
+                binary_signature: "\x1bLul",
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let _output_bytes = unify(&input_bytes, &output_format, &settings)?;
+
+        #[cfg(feature = "integration")]
+        test_output(&_output_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn custom_output_signature_round_trip() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../test_files/lua50.luab");
+        let output_format = Format::default();
+
+        let settings = Settings {
+            output: lua51::Settings {
+                binary_signature: "\x1bLul",
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let output_bytes = unify(input_bytes, &output_format, &settings)?;
+        let round_trip_bytes = unify(&output_bytes, &output_format, &settings)?;
+
+        assert!(output_bytes.starts_with(b"\x1bLul"));
+        assert_eq!(output_bytes, round_trip_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn report() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../test_files/for_loop.luab");
+        let output_format = Format::default();
+        let (_, report) = unify_with_report(input_bytes, &output_format, &Default::default())?;
+
+        assert_eq!(report.functions.len(), 1);
+        assert!(report.functions[0].path.is_empty());
+        assert_eq!(report.peak_stack_size(), report.functions[0].maximum_stack_size);
+        Ok(())
+    }
+
+    #[test]
+    fn report_unmodified() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../test_files/little_endian.luab");
+        let (_, report) = unify_with_report(input_bytes, &LUA50_FORMAT, &Default::default())?;
+        let (_, second_report) = unify_with_report(input_bytes, &LUA50_FORMAT, &Default::default())?;
+
+        // The hash is pinned, since it is meant to be compared across runs and versions.
+        assert!(!report.functions[0].is_modified);
+        assert_eq!(report.functions[0].content_hash, 7213496472583139721);
+        assert_eq!(report, second_report);
+        Ok(())
+    }
+
+    #[test]
+    fn report_instructions() -> Result<(), LunifyError> {
+        // `FORLOOP 0 -1` and `RETURN 0 1`, where the `FORLOOP` is up-cast like in
+        // `upcast_for_loop`.
+        let input_bytes = lua50_function_bytes(4, &[lua50_asbx(28, 0, -1), lua50_abc(27, 0, 1, 0)])?;
+
+        let (_, report) = unify_with_report(&input_bytes, &Format::default(), &Settings::default())?;
+        assert!(report.functions[0].instructions.is_empty());
+
+        let settings = Settings::builder().retain_typed_instructions(true).build()?;
+        let (output_bytes, report) = unify_with_report(&input_bytes, &Format::default(), &settings)?;
+        let view = |opcode: u8, a: u64| InstructionView {
+            opcode,
+            a,
+            b: None,
+            c: None,
+            bx: None,
+            sbx: None,
+        };
+
+        assert_eq!(report.functions[0].instructions, [
+            InstructionView {
+                bx: Some(OperandValue::Constant(1)),
+                ..view(7, 3)
+            },
+            InstructionView {
+                sbx: Some(-1),
+                ..view(31, 0)
+            },
+            InstructionView {
+                bx: Some(OperandValue::Constant(1)),
+                ..view(5, 3)
+            },
+            InstructionView {
+                b: Some(OperandValue::Value(1)),
+                ..view(30, 0)
+            },
+        ]);
+
+        // The views describe the instructions that are written.
+        let chunk = RawChunk::parse(&output_bytes, &Settings::default())?;
+        let opcodes: Vec<u64> = chunk.main.instructions.iter().map(|instruction| instruction & 0x3F).collect();
+        assert_eq!(opcodes, [7, 31, 5, 30]);
+        Ok(())
+    }
+
+    #[test]
+    fn report_modified() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../test_files/lua50.luab");
+        let output_format = Format::default();
+        let (_, report) = unify_with_report(input_bytes, &output_format, &Default::default())?;
+
+        assert!(report.functions.iter().all(|function| function.is_modified));
+        Ok(())
+    }
+
+    #[test]
+    fn report_size() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../test_files/large_table.luab");
+        let output_format = Format::default();
+        let (output_bytes, report) = unify_with_report(input_bytes, &output_format, &Default::default())?;
+
+        // The header is not part of any function.
+        let function_bytes: usize = report.functions.iter().map(|function| function.size).sum();
+        assert!(report.functions.iter().all(|function| function.size > 0));
+        assert!(function_bytes < output_bytes.len());
+        assert!(function_bytes > output_bytes.len() - 32);
+        Ok(())
+    }
+
+    #[test]
+    fn report_spans() -> Result<(), LunifyError> {
+        for input_bytes in [lua51_nested_bytes()?, vararg_count_bytes(false)?] {
+            let (output_bytes, report) = unify_with_report(&input_bytes, &Format::default(), &Default::default())?;
+            let output_functions = list_functions(&output_bytes, &Default::default())?;
+            let functions = &report.functions;
+
+            // The main function takes up everything after the header.
+            assert_eq!(functions[0].span.output, 12..output_bytes.len());
+            assert_eq!(functions[0].span.input.as_ref().map(|input| input.end), Some(input_bytes.len()));
+            assert_eq!(functions.iter().map(|function| function.size).sum::<usize>(), output_bytes.len() - 12);
+
+            // Nested functions lie within their parent and come after their previous
+            // sibling.
+            for (previous, function) in functions.iter().zip(&functions[1..]) {
+                let parent_path = function.path.parent().unwrap();
+                let parent = functions.iter().find(|parent| parent.path == parent_path).unwrap();
+                assert!(parent.span.output.start < function.span.output.start && function.span.output.end < parent.span.output.end);
+                assert!(previous.path == parent_path || previous.span.output.end <= function.span.output.start);
+            }
+
+            // Every span holds a complete function that can be parsed with the header.
+            for function in functions {
+                let chunk_bytes = [&output_bytes[..12], &output_bytes[function.span.output.clone()]].concat();
+                let chunk_functions = list_functions(&chunk_bytes, &Default::default())?;
+
+                let expected_functions: Vec<_> = output_functions
+                    .iter()
+                    .filter(|info| info.path.starts_with(&function.path))
+                    .map(|info| FunctionInfo {
+                        path: FunctionPath::from(&info.path[function.path.len()..]),
+                        ..info.clone()
+                    })
+                    .collect();
+                assert_eq!(chunk_functions, expected_functions);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn report_spans_wider_size_t() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../test_files/32bit.luab");
+        let output_format = Format {
+            size_t_width: BitWidth::Bit64,
+            ..Format::default()
+        };
+        let (output_bytes, report) = unify_with_report(input_bytes, &output_format, &Default::default())?;
+        let function_count = |chunk_bytes: &[u8]| list_functions(chunk_bytes, &Default::default()).map(|functions| functions.len());
+
+        for function in &report.functions {
+            let input = function.span.input.clone().unwrap();
+
+            // Every string has a wider length in the output, so the function grows.
+            assert!(input.len() < function.span.output.len());
+
+            // Both spans hold a complete function that can be parsed with the header of
+            // their chunk.
+            let input_chunk_bytes = [&input_bytes[..12], &input_bytes[input]].concat();
+            let output_chunk_bytes = [&output_bytes[..12], &output_bytes[function.span.output.clone()]].concat();
+            assert_eq!(function_count(&input_chunk_bytes)?, function_count(&output_chunk_bytes)?);
+        }
+
+        assert_eq!(report.functions.len(), function_count(&output_bytes)?);
+        Ok(())
+    }
+
+    #[test]
+    fn report_spans_prefix() -> Result<(), LunifyError> {
+        let input_bytes = [b"#!/usr/bin/lua\n".as_slice(), include_bytes!("../test_files/32bit.luab")].concat();
+        let (_, report) = unify_with_report(&input_bytes, &Format::default(), &Default::default())?;
+
+        // The input spans count from the start of the input, including the prefix.
+        assert_eq!(report.functions[0].span.input, Some(27..input_bytes.len()));
+        Ok(())
+    }
+
+    #[test]
+    fn max_output_size() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../test_files/large_table.luab");
+        let output_format = Format::default();
+        let output_bytes = unify(input_bytes, &output_format, &Default::default())?;
+
+        let mut settings = Settings::default();
+        settings.output.max_output_size = Some(output_bytes.len());
+        unify(input_bytes, &output_format, &settings)?;
+
+        settings.output.max_output_size = Some(64);
+        let result = unify(input_bytes, &output_format, &settings);
+
+        let Err(LunifyError::OutputTooLarge { size, limit, largest_functions }) = result else {
+            panic!("expected OutputTooLarge, got {result:?}");
+        };
+        assert_eq!(size, output_bytes.len());
+        assert_eq!(limit, 64);
+        assert_eq!(largest_functions.first().map(|(path, _)| path), Some(&FunctionPath::root()));
+        Ok(())
+    }
+
+    #[test]
+    fn empty() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../test_files/empty.luab");
+        let output_format = Format::default();
+        unify(input_bytes, &output_format, &Default::default())?;
+        Ok(())
+    }
+
+    #[test]
+    fn incorrect_signature() {
+        let output_format = Format::default();
+        let result = unify(b"\x1bLuo", &output_format, &Default::default());
+
+        assert_eq!(result, Err(LunifyError::IncorrectSignature));
+    }
+
+    #[test]
+    fn shebang_prefix() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../test_files/lua50.luab");
+        let output_format = Format::default();
+        let expected_bytes = unify(input_bytes, &output_format, &Default::default())?;
+
+        let prefixed_bytes = [b"#!/usr/bin/env lua\n".as_slice(), input_bytes].concat();
+        let _output_bytes = unify(&prefixed_bytes, &output_format, &Default::default())?;
+        assert_eq!(_output_bytes, expected_bytes);
+
+        #[cfg(feature = "integration")]
+        test_output(&_output_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn byte_order_mark_prefix() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../test_files/lua50.luab");
+        let output_format = Format::default();
+        let expected_bytes = unify(input_bytes, &output_format, &Default::default())?;
+
+        let prefixed_bytes = [b"\xef\xbb\xbf".as_slice(), input_bytes].concat();
+        assert_eq!(unify(&prefixed_bytes, &output_format, &Default::default())?, expected_bytes);
+
+        let prefixed_bytes = [b"\xef\xbb\xbf#!/usr/bin/lua\n".as_slice(), input_bytes].concat();
+        assert_eq!(unify(&prefixed_bytes, &output_format, &Default::default())?, expected_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn preserve_prefix() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../test_files/lua50.luab");
+        let output_format = Format::default();
+        let expected_bytes = unify(input_bytes, &output_format, &Default::default())?;
+
+        let mut settings = Settings::default();
+        settings.output.preserve_prefix = true;
+        let prefix = b"\xef\xbb\xbf#!/usr/bin/env lua\n";
+        let prefixed_bytes = [prefix.as_slice(), input_bytes].concat();
+        let output_bytes = unify(&prefixed_bytes, &output_format, &settings)?;
+
+        assert_eq!(output_bytes, [prefix.as_slice(), &expected_bytes].concat());
+        assert_eq!(unify(&output_bytes, &output_format, &settings)?, output_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn prefix_too_long() {
+        let input_bytes = include_bytes!("../test_files/lua50.luab");
+        let prefixed_bytes = [format!("#{}\n", "!".repeat(1024)).as_bytes(), input_bytes].concat();
+        let result = unify(&prefixed_bytes, &Format::default(), &Default::default());
+
+        assert_eq!(result, Err(LunifyError::IncorrectSignature));
+    }
+
+    #[test]
+    fn signature_starting_with_hash() -> Result<(), LunifyError> {
+        // A signature that looks like a shebang line must not be skipped.
+        let mut input_bytes = include_bytes!("../test_files/little_endian.luab").to_vec();
+        input_bytes[..4].copy_from_slice(b"#Lu\n");
+
+        let mut settings = Settings::default();
+        settings.lua51.binary_signature = "#Lu\n";
+        unify(&input_bytes, &LUA50_FORMAT, &settings)?;
+        Ok(())
+    }
+
+    #[test]
+    fn input_too_long() {
+        let mut input_bytes = include_bytes!("../test_files/empty.luab").to_vec();
+        input_bytes.extend_from_slice(b"extra bytes");
+
+        let output_format = Format::default();
+        let result = unify(&input_bytes, &output_format, &Default::default());
+
+        assert_eq!(result, Err(LunifyError::InputTooLong));
+    }
+
+    #[test]
+    fn input_too_long_passed_through() {
+        let mut input_bytes = include_bytes!("../test_files/little_endian.luab").to_vec();
+        input_bytes.extend_from_slice(b"extra bytes");
+
+        let result = with_pass_through(|| unify(&input_bytes, &Format::default(), &Default::default()));
+        assert_eq!(result, Err(LunifyError::InputTooLong));
+    }
+
+    #[test]
+    fn input_too_long_after_metadata_magic() {
+        // The length of the record doesn't match, so this is not our metadata.
+        let mut input_bytes = include_bytes!("../test_files/empty.luab").to_vec();
+        input_bytes.extend_from_slice(b"LUNIFY\0\x05\0\0\0{}");
+
+        let result = unify(&input_bytes, &Format::default(), &Default::default());
+        assert_eq!(result, Err(LunifyError::InputTooLong));
+    }
+
+    /// Convert `input_bytes` with the given padding policy.
+    fn unify_padded(input_bytes: &[u8], allow_trailing_padding: TrailingPadding, preserve_padding: bool) -> Result<Vec<u8>, LunifyError> {
+        let settings = Settings::builder()
+            .allow_trailing_padding(allow_trailing_padding)
+            .output_preserve_padding(preserve_padding)
+            .build()?;
+        unify(input_bytes, &Format::default(), &settings)
+    }
+
+    #[test]
+    fn zero_padding() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../test_files/lua50.luab");
+        let expected_bytes = unify(input_bytes, &Format::default(), &Settings::default())?;
+        let padded_bytes = [input_bytes.as_slice(), &[0; 13]].concat();
+
+        assert_eq!(unify_padded(&padded_bytes, TrailingPadding::None, false), Err(LunifyError::InputTooLong));
+        assert_eq!(unify_padded(&padded_bytes, TrailingPadding::Zeros, false)?, expected_bytes);
+        assert_eq!(unify_padded(&padded_bytes, TrailingPadding::Any(13), false)?, expected_bytes);
+        assert_eq!(unify_padded(&padded_bytes, TrailingPadding::Any(12), false), Err(LunifyError::InputTooLong));
+        assert_eq!(unify_padded(&padded_bytes, TrailingPadding::Zeros, true)?, [expected_bytes.as_slice(), &[0; 13]].concat());
+        Ok(())
+    }
+
+    #[test]
+    fn garbage_padding() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../test_files/lua50.luab");
+        let expected_bytes = unify(input_bytes, &Format::default(), &Settings::default())?;
+        let padded_bytes = [input_bytes.as_slice(), b"\0garbage"].concat();
+
+        assert_eq!(unify_padded(&padded_bytes, TrailingPadding::None, false), Err(LunifyError::InputTooLong));
+        assert_eq!(unify_padded(&padded_bytes, TrailingPadding::Zeros, false), Err(LunifyError::InputTooLong));
+        assert_eq!(unify_padded(&padded_bytes, TrailingPadding::Any(8), false)?, expected_bytes);
+        assert_eq!(unify_padded(&padded_bytes, TrailingPadding::Any(7), false), Err(LunifyError::InputTooLong));
+        assert_eq!(unify_padded(&padded_bytes, TrailingPadding::Any(8), true)?, [expected_bytes.as_slice(), b"\0garbage"].concat());
+        Ok(())
+    }
+
+    #[test]
+    fn padding_of_unchanged_input() -> Result<(), LunifyError> {
+        // The input is already in the output format, so it is only passed through if
+        // the padding is kept.
+        let input_bytes = include_bytes!("../test_files/little_endian.luab");
+        let padded_bytes = [input_bytes.as_slice(), &[0; 16]].concat();
+
+        let output_bytes = with_pass_through(|| unify_padded(&padded_bytes, TrailingPadding::Zeros, false))?;
+        assert_eq!(output_bytes, input_bytes);
+
+        let output_bytes = with_pass_through(|| unify_padded(&padded_bytes, TrailingPadding::Zeros, true))?;
+        assert_eq!(output_bytes, padded_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn padding_diagnostic() -> Result<(), LunifyError> {
+        let diagnostics = RefCell::new(Vec::new());
+        let record = |diagnostic: Diagnostic| diagnostics.borrow_mut().push(diagnostic);
+        let settings = Settings::builder()
+            .allow_trailing_padding(TrailingPadding::Zeros)
+            .diagnostics(Some(DiagnosticCallback(&record)))
+            .build()?;
+
+        let input_bytes = [include_bytes!("../test_files/lua50.luab").as_slice(), &[0; 512]].concat();
+        unify(&input_bytes, &Format::default(), &settings)?;
+        list_functions(&input_bytes, &settings)?;
+
+        let diagnostics = diagnostics.into_inner();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].code, DiagnosticCode::TrailingPadding);
+        assert_eq!(diagnostics[0].severity, Severity::Note);
+        assert_eq!(diagnostics[0].message, "ignored 512 bytes of padding after the main function");
+        Ok(())
+    }
+
+    #[cfg(feature = "metadata")]
+    #[test]
+    fn padding_after_metadata() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../test_files/lua50.luab");
+        let mut settings = Settings::default();
+        settings.output.append_metadata = true;
+
+        let output_bytes = unify(input_bytes, &Format::default(), &settings)?;
+        let padded_bytes = [output_bytes.as_slice(), &[0; 7]].concat();
+
+        // The metadata is skipped before looking for padding, and is replaced like
+        // without padding.
+        settings.allow_trailing_padding = TrailingPadding::Zeros;
+        assert_eq!(unify(&padded_bytes, &Format::default(), &settings)?, unify(&output_bytes, &Format::default(), &settings)?);
+
+        settings.allow_trailing_padding = TrailingPadding::None;
+        assert_eq!(unify(&padded_bytes, &Format::default(), &settings), Err(LunifyError::InputTooLong));
+        Ok(())
+    }
+
+    #[cfg(feature = "metadata")]
+    #[test]
+    fn metadata_round_trip() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../test_files/lua50.luab");
+        let mut settings = Settings::default();
+        settings.output.append_metadata = true;
+
+        let output_bytes = unify(input_bytes, &Format::default(), &settings)?;
+        let metadata = crate::read_metadata(&output_bytes).unwrap();
+        assert_eq!(metadata.lunify_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(metadata.input_version, crate::LuaVersion::Lua50);
+
+        // Converting our own output skips the metadata and replaces it.
+        let reconverted_bytes = unify(&output_bytes, &Format::default(), &settings)?;
+        let reconverted_metadata = crate::read_metadata(&reconverted_bytes).unwrap();
+        assert_eq!(reconverted_metadata.input_version, crate::LuaVersion::Lua51);
+        assert_eq!(reconverted_metadata.settings_digest, metadata.settings_digest);
+
+        assert_eq!(list_functions(&output_bytes, &settings)?.len(), list_functions(input_bytes, &settings)?.len());
+        assert_eq!(validate(&output_bytes, &settings), Ok(()));
+
+        #[cfg(feature = "integration")]
+        test_output(&output_bytes);
+        Ok(())
+    }
+
+    #[cfg(feature = "metadata")]
+    #[test]
+    fn metadata_after_byte_code() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../test_files/lua50.luab");
+        let mut settings = Settings::default();
+        settings.output.append_metadata = true;
+
+        let output_bytes = unify(input_bytes, &Format::default(), &settings)?;
+        let plain_bytes = unify(input_bytes, &Format::default(), &Settings::default())?;
+
+        assert_eq!(output_bytes[..plain_bytes.len()], plain_bytes);
+        assert!(output_bytes[plain_bytes.len()..].starts_with(b"LUNIFY\0"));
+        assert_eq!(crate::read_metadata(&plain_bytes), None);
+
+        settings.output.peephole = true;
+        let other_bytes = unify(input_bytes, &Format::default(), &settings)?;
+        assert_ne!(crate::read_metadata(&other_bytes), crate::read_metadata(&output_bytes));
+        Ok(())
+    }
+
+    #[cfg(feature = "metadata")]
+    #[test]
+    fn input_too_long_after_metadata() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../test_files/lua50.luab");
+        let mut settings = Settings::default();
+        settings.output.append_metadata = true;
+
+        let mut output_bytes = unify(input_bytes, &Format::default(), &settings)?;
+        output_bytes.extend_from_slice(b"extra bytes");
+
+        let result = unify(&output_bytes, &Format::default(), &settings);
         assert_eq!(result, Err(LunifyError::InputTooLong));
+        assert_eq!(crate::read_metadata(&output_bytes), None);
+        Ok(())
+    }
+
+    #[test]
+    fn unify_cow_borrows_unchanged_input() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../test_files/little_endian.luab");
+        let output_bytes = with_pass_through(|| unify_cow(input_bytes, &Format::default(), &Settings::default()))?;
+
+        assert!(matches!(output_bytes, Cow::Borrowed(_)));
+        assert_eq!(output_bytes.as_ptr(), input_bytes.as_ptr());
+        assert_eq!(output_bytes.len(), input_bytes.len());
+        Ok(())
+    }
+
+    #[test]
+    fn unify_cow_owns_converted_output() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../test_files/lua50.luab");
+        let output_bytes = with_pass_through(|| unify_cow(input_bytes, &Format::default(), &Settings::default()))?;
+
+        assert!(matches!(output_bytes, Cow::Owned(_)));
+        assert_eq!(output_bytes, unify(input_bytes, &Format::default(), &Settings::default())?);
+        Ok(())
+    }
+
+    #[test]
+    fn owned_and_borrowed_input() -> Result<(), LunifyError> {
+        let input_bytes = include_bytes!("../test_files/lua50.luab").to_vec();
+        let expected_bytes = unify(input_bytes.as_slice(), &Format::default(), &Settings::default())?;
+
+        assert_eq!(unify(&input_bytes, &Format::default(), &Settings::default())?, expected_bytes);
+        assert_eq!(list_functions(&input_bytes, &Settings::default())?, list_functions(input_bytes.clone(), &Settings::default())?);
+        assert_eq!(unify(input_bytes, &Format::default(), &Settings::default())?, expected_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn incompatible_output_configuration() {
+        let input_bytes = include_bytes!("../test_files/lua50.luab");
+        let mut settings = Settings::default();
+        settings.output.layout = small_bx_layout();
+
+        let expected = Err(LunifyError::IncompatibleOutputConfiguration(
+            "registers below the stack limit overlap the constant bit of RK operands",
+        ));
+        assert_eq!(unify(input_bytes, &Format::default(), &settings), expected);
+        assert_eq!(extract(input_bytes, &[], &Format::default(), &settings), expected);
+    }
+
+    #[test]
+    fn fields_per_flush_too_large() {
+        let input_bytes = include_bytes!("../test_files/lua50.luab");
+        let mut settings = Settings::default();
+        settings.output.fields_per_flush = 300;
+
+        let result = unify(input_bytes, &Format::default(), &settings);
+        assert_eq!(result, Err(LunifyError::InvalidSettings("output.fields_per_flush")));
+    }
+
+    #[test]
+    fn raised_output_stack_limit() -> Result<(), LunifyError> {
+        // `LOADK 252 0`, `RETURN 0 1`, which needs more than the default 250 registers.
+        let function = lua51_prototype_bytes(253, &[lua51_abx(1, 252, 0), lua51_abc(30, 0, 1, 0)], 1, &[])?;
+        let input_bytes = lua51_closures_bytes(&[&function])?;
+
+        let result = unify(&input_bytes, &LUA50_FORMAT, &Settings::default());
+        assert_eq!(result, Err(LunifyError::StackTooLarge(253)));
+
+        let settings = Settings::builder().output_stack_limit(255).build()?;
+        let output_bytes = unify(&input_bytes, &LUA50_FORMAT, &settings)?;
+        assert_eq!(validate(&output_bytes, &settings), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn stack_limit_above_stack_size_byte() {
+        // Even with a wider A operand, the stack size of a function is stored in a
+        // single byte.
+        let layout = InstructionLayout::from_specification([
+            OperandType::Opcode(6),
+            OperandType::A(9),
+            OperandType::C(10),
+            OperandType::B(10),
+        ])
+        .expect("layout is valid");
+        let result = Settings::builder().output_layout(layout).output_stack_limit(500).build();
+        assert_eq!(result, Err(LunifyError::InvalidSettings("output.stack_limit")));
+    }
+
+    #[test]
+    fn invalid_rename_globals() {
+        for rename_globals in [&[("string.", "gmatch")], &[("string.gfind", "string.gmatch")]] {
+            let result = Settings::builder().output_rename_globals(rename_globals).build();
+            assert_eq!(result, Err(LunifyError::InvalidSettings("output.rename_globals")));
+        }
     }
 }
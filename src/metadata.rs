@@ -0,0 +1,83 @@
+#[cfg(feature = "metadata")]
+use serde::{Deserialize, Serialize};
+
+use crate::serialization::ByteStream;
+#[cfg(feature = "metadata")]
+use crate::serialization::{fnv1a_hash, ByteWriter};
+#[cfg(feature = "metadata")]
+use crate::{LuaVersion, LunifyError, Settings};
+
+/// Marks the start of the metadata that is appended after the main function.
+const MAGIC: &[u8] = b"LUNIFY\0";
+
+/// Information about the conversion that produced a chunk, appended after the
+/// main function if `append_metadata` is set in the output settings. Use
+/// [`read_metadata`] to read it back.
+#[cfg(feature = "metadata")]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Metadata {
+    /// Version of Lunify that converted the chunk.
+    pub lunify_version: String,
+    /// The Lua version of the input.
+    pub input_version: LuaVersion,
+    /// 64-bit FNV-1a hash of the settings that were used, serialized as JSON.
+    pub settings_digest: u64,
+}
+
+/// Read the [`Metadata`] appended to byte code that was converted with
+/// `append_metadata` set in the output settings. Returns `None` if the byte
+/// code doesn't end with valid metadata.
+#[cfg(feature = "metadata")]
+pub fn read_metadata(bytes: impl AsRef<[u8]>) -> Option<Metadata> {
+    let bytes = bytes.as_ref();
+
+    // The record is JSON, which can't contain the null byte of the magic, so the
+    // last occurrence is the start of the metadata.
+    let start = bytes.windows(MAGIC.len()).rposition(|window| window == MAGIC)?;
+    let record = metadata_record(&bytes[start..])?;
+
+    serde_json::from_slice(record).ok()
+}
+
+#[cfg(feature = "metadata")]
+pub(crate) fn write_metadata(byte_writer: &mut ByteWriter, input_version: LuaVersion, settings: &Settings) -> Result<(), LunifyError> {
+    let serialization_error = |_| LunifyError::InternalInconsistency("failed to serialize metadata");
+
+    let metadata = Metadata {
+        lunify_version: env!("CARGO_PKG_VERSION").to_owned(),
+        input_version,
+        settings_digest: fnv1a_hash(&serde_json::to_vec(settings).map_err(serialization_error)?),
+    };
+
+    let record = serde_json::to_vec(&metadata).map_err(serialization_error)?;
+    let length = u32::try_from(record.len()).map_err(|_| LunifyError::InternalInconsistency("metadata too long"))?;
+
+    byte_writer.slice(MAGIC);
+    byte_writer.slice(&length.to_le_bytes());
+    byte_writer.slice(&record);
+    Ok(())
+}
+
+/// Get the bytes that were not read from the byte stream. Metadata appended by
+/// Lunify is skipped, so converting our own output again works.
+pub(crate) fn trailing_bytes<'a>(byte_stream: &ByteStream<'a>) -> &'a [u8] {
+    let remaining = byte_stream.remaining();
+    split_metadata(remaining).map_or(remaining, |(_, rest)| rest)
+}
+
+/// Split `bytes` into the record of the metadata and the bytes after it, if
+/// `bytes` start with the magic, followed by the length of the record as a
+/// little endian `u32` and at least that many bytes.
+fn split_metadata(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    let bytes = bytes.strip_prefix(MAGIC)?;
+    let length = usize::try_from(u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?)).ok()?;
+    let end = length.checked_add(4)?;
+
+    Some((bytes.get(4..end)?, &bytes[end..]))
+}
+
+/// Get the record of the metadata, if `bytes` are exactly the metadata.
+#[cfg(feature = "metadata")]
+fn metadata_record(bytes: &[u8]) -> Option<&[u8]> {
+    split_metadata(bytes).and_then(|(record, rest)| rest.is_empty().then_some(record))
+}
@@ -0,0 +1,76 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::diagnostic::report_diagnostic;
+use crate::metadata::trailing_bytes;
+use crate::serialization::{ByteStream, ByteWriter};
+use crate::{DiagnosticCode, LunifyError, Settings};
+
+/// Which bytes are accepted after the main function of the input, for example
+/// because a packer aligned the chunk to a block size. Metadata appended by
+/// Lunify is always accepted and doesn't count as padding.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum TrailingPadding {
+    /// Return [`InputTooLong`](LunifyError::InputTooLong) if there are any
+    /// bytes after the main function.
+    #[default]
+    None,
+    /// Accept any number of zero bytes.
+    Zeros,
+    /// Accept up to the given number of arbitrary bytes.
+    Any(usize),
+}
+
+impl TrailingPadding {
+    fn accepts(self, padding: &[u8]) -> bool {
+        match self {
+            TrailingPadding::None => padding.is_empty(),
+            TrailingPadding::Zeros => padding.iter().all(|&byte| byte == 0),
+            TrailingPadding::Any(maximum_length) => padding.len() <= maximum_length,
+        }
+    }
+}
+
+/// Check that the byte stream has been read to the end, apart from the
+/// padding allowed by [`allow_trailing_padding`](Settings::allow_trailing_padding).
+/// Returns the padding.
+pub(crate) fn trailing_padding<'a>(byte_stream: &ByteStream<'a>, settings: &Settings) -> Result<&'a [u8], LunifyError> {
+    let padding = trailing_bytes(byte_stream);
+
+    if !settings.allow_trailing_padding.accepts(padding) {
+        return Err(LunifyError::InputTooLong);
+    }
+
+    if !padding.is_empty() {
+        report_diagnostic(settings, DiagnosticCode::TrailingPadding, &[], None, || {
+            format!("ignored {} bytes of padding after the main function", padding.len())
+        });
+    }
+
+    Ok(padding)
+}
+
+/// Write the padding of the input to the output, if
+/// [`preserve_padding`](crate::lua51::Settings::preserve_padding) is set in the
+/// output settings.
+pub(crate) fn write_padding(byte_writer: &mut ByteWriter, padding: &[u8], settings: &Settings) {
+    if settings.output.preserve_padding {
+        byte_writer.slice(padding);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TrailingPadding;
+
+    #[test]
+    fn accepts() {
+        assert!(TrailingPadding::None.accepts(&[]));
+        assert!(!TrailingPadding::None.accepts(&[0]));
+        assert!(TrailingPadding::Zeros.accepts(&[0; 512]));
+        assert!(!TrailingPadding::Zeros.accepts(&[0, 0, 1]));
+        assert!(TrailingPadding::Any(3).accepts(&[1, 2, 3]));
+        assert!(!TrailingPadding::Any(3).accepts(&[0; 4]));
+    }
+}
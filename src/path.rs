@@ -0,0 +1,158 @@
+use std::fmt::Display;
+use std::ops::Deref;
+use std::str::FromStr;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::LunifyError;
+
+/// Indices of the nested functions that lead to a function, starting at the
+/// main function, which has an empty path. Paths are displayed like `root.2.0`
+/// for the first nested function of the third nested function of the main
+/// function, and can be parsed from the same representation. Paths are
+/// ordered like the functions in the byte code.
+///
+/// ```rust
+/// use lunify::{FunctionPath, LunifyError};
+///
+/// # fn main() -> Result<(), LunifyError> {
+/// let path: FunctionPath = "root.2.0".parse()?;
+/// assert_eq!(*path, [2, 0]);
+/// assert_eq!(path.to_string(), "root.2.0");
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(transparent))]
+pub struct FunctionPath(Vec<usize>);
+
+impl FunctionPath {
+    /// The path of the main function.
+    pub fn root() -> Self {
+        Self::default()
+    }
+
+    /// The path of the nested function at `index` of this function.
+    pub fn child(&self, index: usize) -> Self {
+        let mut indices = self.0.clone();
+        indices.push(index);
+        Self(indices)
+    }
+
+    /// The path of the function this function is nested in, or `None` for the
+    /// main function.
+    pub fn parent(&self) -> Option<Self> {
+        self.0.split_last().map(|(_, indices)| Self(indices.to_vec()))
+    }
+
+    /// Check if this function is nested in the function at `other`, directly
+    /// or indirectly, or is the same function.
+    pub fn starts_with(&self, other: &FunctionPath) -> bool {
+        self.0.starts_with(&other.0)
+    }
+
+    pub(crate) fn prepend(mut self, index: usize) -> Self {
+        self.0.insert(0, index);
+        self
+    }
+}
+
+impl Deref for FunctionPath {
+    type Target = [usize];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<Vec<usize>> for FunctionPath {
+    fn from(value: Vec<usize>) -> Self {
+        Self(value)
+    }
+}
+
+impl From<&[usize]> for FunctionPath {
+    fn from(value: &[usize]) -> Self {
+        Self(value.to_vec())
+    }
+}
+
+impl From<FunctionPath> for Vec<usize> {
+    fn from(value: FunctionPath) -> Self {
+        value.0
+    }
+}
+
+impl Display for FunctionPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "root")?;
+        self.0.iter().try_for_each(|index| write!(f, ".{index}"))
+    }
+}
+
+impl FromStr for FunctionPath {
+    type Err = LunifyError;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let malformed = || LunifyError::MalformedFunctionPath(string.to_owned());
+        let mut segments = string.split('.');
+
+        if segments.next() != Some("root") {
+            return Err(malformed());
+        }
+
+        segments
+            .map(|segment| match segment.bytes().all(|byte| byte.is_ascii_digit()) {
+                true => segment.parse().map_err(|_| malformed()),
+                false => Err(malformed()),
+            })
+            .collect::<Result<Vec<usize>, LunifyError>>()
+            .map(Self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FunctionPath;
+    use crate::LunifyError;
+
+    #[test]
+    fn display() {
+        assert_eq!(FunctionPath::root().to_string(), "root");
+        assert_eq!(FunctionPath::from(vec![2, 0]).to_string(), "root.2.0");
+    }
+
+    #[test]
+    fn parse() -> Result<(), LunifyError> {
+        assert_eq!("root".parse::<FunctionPath>()?, FunctionPath::root());
+        assert_eq!("root.2.0".parse::<FunctionPath>()?, FunctionPath::from(vec![2, 0]));
+        assert_eq!("root.12".parse::<FunctionPath>()?.child(3), FunctionPath::from(vec![12, 3]));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_malformed() {
+        for string in ["", "2.0", "root.", "root..1", "root.-1", "root.+1", "root.x", "main.1"] {
+            let expected = Err(LunifyError::MalformedFunctionPath(string.to_owned()));
+            assert_eq!(string.parse::<FunctionPath>(), expected);
+        }
+    }
+
+    #[test]
+    fn ordering() {
+        let mut paths: Vec<FunctionPath> = [vec![1], vec![0, 1], vec![], vec![0], vec![0, 0]].into_iter().map(FunctionPath::from).collect();
+        paths.sort();
+
+        let expected: Vec<FunctionPath> = [vec![], vec![0], vec![0, 0], vec![0, 1], vec![1]].into_iter().map(FunctionPath::from).collect();
+        assert_eq!(paths, expected);
+    }
+
+    #[test]
+    fn parent() {
+        let path = FunctionPath::from(vec![2, 0]);
+        assert_eq!(path.parent(), Some(FunctionPath::from(vec![2])));
+        assert_eq!(FunctionPath::root().parent(), None);
+        assert!(path.starts_with(&FunctionPath::from(vec![2])));
+    }
+}
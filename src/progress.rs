@@ -0,0 +1,107 @@
+use std::fmt::{Debug, Formatter};
+use std::hash::{Hash, Hasher};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::serialization::ByteStream;
+use crate::Settings;
+
+/// What the conversion is doing when [`Progress`] is reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Phase {
+    /// The header was read and the functions are about to be converted.
+    ParsingHeader,
+    /// A function and all of its nested functions were converted.
+    ConvertingFunction,
+    /// All functions were converted and the output is being finished.
+    Writing,
+}
+
+/// Progress of a conversion, passed to the [`ProgressCallback`] in the
+/// settings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Progress {
+    /// The number of functions that were converted so far.
+    pub functions_done: usize,
+    /// The number of functions in the chunk. The functions are counted before
+    /// converting, which is cheap compared to the conversion, so this is only
+    /// `None` if counting failed or for operations that don't convert the
+    /// whole chunk.
+    pub functions_total: Option<usize>,
+    /// The number of bytes of the input that were read so far. This never
+    /// decreases, even if parts of the input are read more than once.
+    pub bytes_read: usize,
+    /// The number of bytes of the input.
+    pub bytes_total: usize,
+    /// What the conversion is doing.
+    pub phase: Phase,
+}
+
+/// User supplied function that is called with the [`Progress`] of long
+/// conversions, at least once for every function. Callbacks are compared and
+/// hashed by address.
+///
+/// # Example
+///
+/// ```rust
+/// use lunify::{unify, Format, LunifyError, Progress, ProgressCallback, Settings};
+///
+/// # fn main() -> Result<(), LunifyError> {
+/// let report = |progress: Progress| println!("{} of {} bytes", progress.bytes_read, progress.bytes_total);
+/// let settings = Settings::builder().progress(Some(ProgressCallback(&report))).build()?;
+///
+/// let input_bytes = include_bytes!("../test_files/lua50.luab");
+/// let _output_bytes = unify(input_bytes, &Format::default(), &settings)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Copy)]
+pub struct ProgressCallback<'a>(pub &'a dyn Fn(Progress));
+
+impl ProgressCallback<'_> {
+    fn address(&self) -> usize {
+        self.0 as *const _ as *const () as usize
+    }
+}
+
+impl Debug for ProgressCallback<'_> {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(formatter, "ProgressCallback({:#x})", self.address())
+    }
+}
+
+impl PartialEq for ProgressCallback<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.address() == other.address()
+    }
+}
+
+impl Eq for ProgressCallback<'_> {}
+
+impl PartialOrd for ProgressCallback<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ProgressCallback<'_> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.address().cmp(&other.address())
+    }
+}
+
+impl Hash for ProgressCallback<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.address().hash(state);
+    }
+}
+
+/// Call the progress callback of the settings, if there is one.
+pub(crate) fn report_progress(byte_stream: &mut ByteStream, phase: Phase, settings: &Settings) {
+    if let Some(callback) = settings.progress {
+        (callback.0)(byte_stream.progress(phase));
+    }
+}
@@ -0,0 +1,129 @@
+use std::ops::Range;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{FunctionPath, InstructionView};
+
+/// Information collected while converting byte code with
+/// [unify_with_report](super::unify_with_report).
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ConversionReport {
+    /// One entry for every function in the chunk, in depth-first order
+    /// starting with the main function.
+    pub functions: Vec<FunctionReport>,
+}
+
+impl ConversionReport {
+    /// The highest number of registers used by any function in the chunk.
+    /// Functions close to the stack limit are likely to fail when converting
+    /// with different settings.
+    pub fn peak_stack_size(&self) -> u8 {
+        self.functions.iter().map(|function| function.maximum_stack_size).max().unwrap_or(0)
+    }
+}
+
+/// Information about a single converted function.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FunctionReport {
+    /// Indices of the nested functions that lead to this function. The main
+    /// function has an empty path.
+    pub path: FunctionPath,
+    /// The number of registers used by the function after conversion.
+    pub maximum_stack_size: u8,
+    /// Whether any instruction of the function was changed during conversion.
+    /// Functions that are up-cast from Lua 5.0 are always modified.
+    pub is_modified: bool,
+    /// Stable 64-bit FNV-1a hash of the emitted function body. Debug
+    /// information and nested functions are not included, so the hash can be
+    /// used to check if a function changed between two conversions.
+    pub content_hash: u64,
+    /// The number of line numbers of the function that don't fit into the
+    /// integers of the output format and were replaced according to the
+    /// [`LineOverflowPolicy`](crate::LineOverflowPolicy) in the output
+    /// settings.
+    pub overflowing_lines: usize,
+    /// The number of arithmetic instructions that were replaced by loading
+    /// their result if `fold_constants` is set in the output settings.
+    pub folded_constants: usize,
+    /// The converted instructions of the function, right before they are
+    /// encoded. This is only filled in if
+    /// [`retain_typed_instructions`](crate::Settings::retain_typed_instructions)
+    /// is set and the function wasn't skipped.
+    pub instructions: Vec<InstructionView>,
+    /// The number of bytes written for the function, excluding nested
+    /// functions.
+    pub size: usize,
+    /// The position of the function in the input and the output, including
+    /// its nested functions.
+    pub span: FunctionSpan,
+}
+
+/// The bytes of a function, e.g. to sign every function separately. The
+/// offsets count from the start of the input or output, so the header and any
+/// prefix are accounted for.
+///
+/// Strings and integers can be wider in the output than in the input, so the
+/// input and output ranges of a function usually differ even if the function
+/// was copied as is.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FunctionSpan {
+    /// The bytes of the function in the input, or `None` if the function was
+    /// replaced by a stub because it couldn't be converted.
+    pub input: Option<Range<usize>>,
+    /// The bytes of the function in the output.
+    pub output: Range<usize>,
+}
+
+impl FunctionSpan {
+    fn key(&self) -> (Option<(usize, usize)>, usize, usize) {
+        let input = self.input.as_ref().map(|input| (input.start, input.end));
+        (input, self.output.start, self.output.end)
+    }
+}
+
+impl PartialOrd for FunctionSpan {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FunctionSpan {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConversionReport, FunctionReport};
+    use crate::FunctionPath;
+
+    #[test]
+    fn peak_stack_size() {
+        let report = ConversionReport {
+            functions: vec![
+                FunctionReport {
+                    path: FunctionPath::root(),
+                    maximum_stack_size: 4,
+                    ..Default::default()
+                },
+                FunctionReport {
+                    path: FunctionPath::from(vec![0]),
+                    maximum_stack_size: 9,
+                    ..Default::default()
+                },
+            ],
+        };
+
+        assert_eq!(report.peak_stack_size(), 9);
+    }
+
+    #[test]
+    fn peak_stack_size_empty() {
+        assert_eq!(ConversionReport::default().peak_stack_size(), 0);
+    }
+}
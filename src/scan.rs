@@ -0,0 +1,125 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::function::Function;
+use crate::{read_header, LuaVersion, Settings};
+
+/// A chunk of Lua byte code found by [`scan`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ChunkLocation {
+    /// Offset of the signature in the scanned bytes.
+    pub offset: usize,
+    /// The Lua version of the chunk.
+    pub version: LuaVersion,
+    /// The number of bytes of the chunk, including the header. This is only
+    /// known if the whole chunk was parsed.
+    pub length: Option<usize>,
+}
+
+/// Find chunks of Lua byte code inside of a larger file, like an archive.
+/// Every occurrence of one of the binary signatures in the settings is a
+/// candidate, and candidates that don't start with a valid header are
+/// discarded. If `deep` is set, all functions of the candidate are parsed as
+/// well, without decoding their instructions, which also discards candidates
+/// that are cut off and gives the length of every chunk.
+///
+/// Chunks are returned in the order they appear in. Since every offset is
+/// checked, chunks that are stored inside the string constants of another
+/// chunk are found as well.
+pub fn scan(haystack: &[u8], deep: bool, settings: &Settings) -> Vec<ChunkLocation> {
+    let signatures = [settings.lua50.binary_signature, settings.lua51.binary_signature, settings.output.binary_signature];
+    let mut locations = Vec::new();
+
+    for offset in 0..haystack.len() {
+        let candidate = &haystack[offset..];
+
+        if !signatures.iter().any(|signature| candidate.starts_with(signature.as_bytes())) {
+            continue;
+        }
+
+        let Ok((mut byte_stream, version, _)) = read_header(candidate, settings) else {
+            continue;
+        };
+
+        let length = match deep {
+            true => match Function::list(&mut byte_stream, version, settings) {
+                Ok(_) => Some(byte_stream.offset()),
+                Err(_) => continue,
+            },
+            false => None,
+        };
+
+        locations.push(ChunkLocation { offset, version, length });
+    }
+
+    locations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{scan, ChunkLocation};
+    use crate::{LuaVersion, Settings};
+
+    /// Bytes that look random but are the same on every run, with a Lua
+    /// signature that isn't followed by a valid header.
+    fn noise(length: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        let mut bytes: Vec<u8> = (0..length)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect();
+
+        bytes[length / 2..length / 2 + 5].copy_from_slice(b"\x1bLua\x50");
+        bytes
+    }
+
+    #[test]
+    fn embedded_chunks() {
+        let chunk = include_bytes!("../test_files/for_loop.luab");
+        let haystack = [noise(101, 1), chunk.to_vec(), noise(37, 2), chunk.to_vec(), noise(64, 3)].concat();
+        let second_offset = 101 + chunk.len() + 37;
+
+        let locations = scan(&haystack, true, &Settings::default());
+
+        assert_eq!(locations, [
+            ChunkLocation {
+                offset: 101,
+                version: LuaVersion::Lua50,
+                length: Some(chunk.len()),
+            },
+            ChunkLocation {
+                offset: second_offset,
+                version: LuaVersion::Lua50,
+                length: Some(chunk.len()),
+            },
+        ]);
+    }
+
+    #[test]
+    fn shallow_scan() {
+        let chunk = include_bytes!("../test_files/for_loop.luab");
+        let haystack = [noise(64, 4), chunk.to_vec()].concat();
+
+        let locations = scan(&haystack, false, &Settings::default());
+
+        assert_eq!(locations, [ChunkLocation {
+            offset: 64,
+            version: LuaVersion::Lua50,
+            length: None,
+        }]);
+    }
+
+    #[test]
+    fn truncated_chunk() {
+        let chunk = include_bytes!("../test_files/for_loop.luab");
+        let haystack = [noise(64, 5), chunk[..chunk.len() / 2].to_vec()].concat();
+
+        // The header is intact, so only the deep scan can tell that the chunk is cut
+        // off.
+        assert_eq!(scan(&haystack, false, &Settings::default()).len(), 1);
+        assert!(scan(&haystack, true, &Settings::default()).is_empty());
+    }
+}
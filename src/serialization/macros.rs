@@ -1,15 +1,45 @@
-/// Workaround until `*_le_bytes` and `*_be_bytes` are part of a trait.
+/// Workaround until `*_le_bytes` and `*_be_bytes` are part of a trait. The
+/// value is cast to the type of the width it is written with, so callers need
+/// to make sure that it fits.
 macro_rules! to_slice {
-    ($writer:expr, $value:expr, $width:ident, $type32:ty) => {
+    ($writer:expr, $value:expr, $width:ident, $type32:ty, $type64:ty) => {
         match ($writer.format.$width, $writer.format.endianness) {
             (BitWidth::Bit32, Endianness::Little) => $writer.slice(&($value as $type32).to_le_bytes()),
             (BitWidth::Bit32, Endianness::Big) => $writer.slice(&($value as $type32).to_be_bytes()),
-            (BitWidth::Bit64, Endianness::Little) => $writer.slice(&$value.to_le_bytes()),
-            (BitWidth::Bit64, Endianness::Big) => $writer.slice(&$value.to_be_bytes()),
+            (BitWidth::Bit64, Endianness::Little) => $writer.slice(&($value as $type64).to_le_bytes()),
+            (BitWidth::Bit64, Endianness::Big) => $writer.slice(&($value as $type64).to_be_bytes()),
         }
     };
 }
 
+/// Same as `to_slice` but for multiple values. The width and endianness are only
+/// matched once instead of once per value, and the output is grown once and
+/// filled in place.
+macro_rules! to_slice_batch {
+    ($writer:expr, $values:expr, $width:ident, $type32:ty, $type64:ty) => {{
+        let values = $values;
+        let width = u8::from($writer.format.$width) as usize;
+        let start = $writer.data.len();
+        $writer.data.resize(start + values.len() * width, 0);
+        let chunks = $writer.data[start..].chunks_exact_mut(width).zip(values);
+
+        match ($writer.format.$width, $writer.format.endianness) {
+            (BitWidth::Bit32, Endianness::Little) => {
+                chunks.for_each(|(chunk, value)| chunk.copy_from_slice(&(*value as $type32).to_le_bytes()))
+            }
+            (BitWidth::Bit32, Endianness::Big) => {
+                chunks.for_each(|(chunk, value)| chunk.copy_from_slice(&(*value as $type32).to_be_bytes()))
+            }
+            (BitWidth::Bit64, Endianness::Little) => {
+                chunks.for_each(|(chunk, value)| chunk.copy_from_slice(&(*value as $type64).to_le_bytes()))
+            }
+            (BitWidth::Bit64, Endianness::Big) => {
+                chunks.for_each(|(chunk, value)| chunk.copy_from_slice(&(*value as $type64).to_be_bytes()))
+            }
+        }
+    }};
+}
+
 /// Workaround until `*_le_bytes` and `*_be_bytes` are part of a trait.
 macro_rules! from_slice {
     ($stream:expr, $width:expr, $endianness:expr, $type32:ty, $type64:ty) => {{
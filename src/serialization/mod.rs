@@ -5,3 +5,12 @@ mod writer;
 
 pub(crate) use self::stream::ByteStream;
 pub(crate) use self::writer::ByteWriter;
+
+/// 64-bit FNV-1a hash of `bytes`. The hash is the same on every platform and
+/// across Rust versions, so it can be compared between runs.
+pub(crate) fn fnv1a_hash(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    bytes.iter().fold(OFFSET_BASIS, |hash, byte| (hash ^ *byte as u64).wrapping_mul(PRIME))
+}
@@ -1,20 +1,48 @@
 use std::convert::TryInto;
 
 use crate::number::Number;
-use crate::{Endianness, Format, LunifyError};
-
+use crate::{BitWidth, Endianness, Format, LunifyError, Phase, Progress};
+
+/// The format of a stream until [`ByteStream::set_format`] is called with the
+/// format of the input. Only single bytes of the header are read before that,
+/// but unlike [`Format::default`] it doesn't depend on the host, so the host
+/// can never change how the input is read.
+const INITIAL_FORMAT: Format = Format {
+    format: 0,
+    endianness: Endianness::Little,
+    integer_width: BitWidth::Bit32,
+    size_t_width: BitWidth::Bit64,
+    instruction_width: BitWidth::Bit32,
+    number_width: BitWidth::Bit64,
+    is_number_integral: false,
+};
+
+#[derive(Clone)]
 pub(crate) struct ByteStream<'a> {
     data: &'a [u8],
     offset: usize,
     format: Format,
+    instruction_total: u64,
+    functions_done: usize,
+    functions_total: Option<usize>,
+    bytes_read: usize,
 }
 
 impl<'a> ByteStream<'a> {
     pub fn new(data: &'a [u8]) -> Self {
         let offset = 0;
-        let format = Format::default();
-
-        Self { data, offset, format }
+        let format = INITIAL_FORMAT;
+        let instruction_total = 0;
+
+        Self {
+            data,
+            offset,
+            format,
+            instruction_total,
+            functions_done: 0,
+            functions_total: None,
+            bytes_read: 0,
+        }
     }
 
     pub fn remove_signature(&mut self, signature: &str) -> bool {
@@ -40,6 +68,61 @@ impl<'a> ByteStream<'a> {
         self.format = format;
     }
 
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn set_offset(&mut self, offset: usize) {
+        self.offset = offset;
+    }
+
+    /// Add to the number of instructions of all functions read from the stream
+    /// so far, and return the new total.
+    pub fn add_instructions(&mut self, count: u64) -> u64 {
+        self.instruction_total = self.instruction_total.saturating_add(count);
+        self.instruction_total
+    }
+
+    pub fn functions_done(&self) -> usize {
+        self.functions_done
+    }
+
+    pub fn set_functions_done(&mut self, functions_done: usize) {
+        self.functions_done = functions_done;
+    }
+
+    /// Add to the number of functions read from the stream so far.
+    pub fn add_functions(&mut self, count: usize) {
+        self.functions_done = self.functions_done.saturating_add(count);
+    }
+
+    pub fn set_functions_total(&mut self, functions_total: Option<usize>) {
+        self.functions_total = functions_total;
+    }
+
+    /// Get the progress of reading the stream. The stream can jump back, so the
+    /// furthest offset is used as the number of bytes read.
+    pub fn progress(&mut self, phase: Phase) -> Progress {
+        self.bytes_read = self.bytes_read.max(self.offset.min(self.data.len()));
+
+        Progress {
+            functions_done: self.functions_done,
+            functions_total: self.functions_total,
+            bytes_read: self.bytes_read,
+            bytes_total: self.data.len(),
+            phase,
+        }
+    }
+
+    /// Get the bytes between `start` and the current offset.
+    pub fn slice_from(&self, start: usize) -> &'a [u8] {
+        self.data.get(start..self.offset).unwrap_or_default()
+    }
+
     pub fn byte(&mut self) -> Result<u8, LunifyError> {
         let offset = self.offset;
         self.offset += 1;
@@ -50,8 +133,14 @@ impl<'a> ByteStream<'a> {
         Ok(from_slice!(self, self.format.integer_width, self.format.endianness, i32, i64))
     }
 
-    pub fn size_t(&mut self) -> Result<i64, LunifyError> {
-        Ok(from_slice!(self, self.format.size_t_width, self.format.endianness, i32, i64))
+    /// Read an integer that represents a count. Counts can never be negative, so
+    /// they are read as unsigned.
+    pub fn count(&mut self) -> Result<u64, LunifyError> {
+        Ok(from_slice!(self, self.format.integer_width, self.format.endianness, u32, u64))
+    }
+
+    pub fn size_t(&mut self) -> Result<u64, LunifyError> {
+        Ok(from_slice!(self, self.format.size_t_width, self.format.endianness, u32, u64))
     }
 
     pub fn instruction(&mut self) -> Result<u64, LunifyError> {
@@ -83,9 +172,9 @@ impl<'a> ByteStream<'a> {
         }
     }
 
-    pub fn slice(&mut self, length: usize) -> Result<&[u8], LunifyError> {
+    pub fn slice(&mut self, length: usize) -> Result<&'a [u8], LunifyError> {
         let start = self.offset;
-        self.offset += length;
+        self.offset = self.offset.saturating_add(length);
 
         if self.offset > self.data.len() {
             return Err(LunifyError::InputTooShort);
@@ -95,16 +184,17 @@ impl<'a> ByteStream<'a> {
     }
 
     pub fn string(&mut self) -> Result<String, LunifyError> {
-        let length = self.size_t()? as usize;
-        let start = self.offset;
-
-        self.offset += length;
+        Ok(self.string_slice()?.iter().map(|&byte| byte as char).collect())
+    }
 
-        if self.offset > self.data.len() {
-            return Err(LunifyError::InputTooShort);
-        }
+    pub fn string_slice(&mut self) -> Result<&'a [u8], LunifyError> {
+        let length = usize::try_from(self.size_t()?).map_err(|_| LunifyError::InputTooShort)?;
+        self.slice(length)
+    }
 
-        Ok(self.data[start..self.offset].iter().map(|&byte| byte as char).collect())
+    /// Get the bytes after the current offset.
+    pub fn remaining(&self) -> &'a [u8] {
+        self.data.get(self.offset..).unwrap_or_default()
     }
 
     pub fn is_empty(&self) -> bool {
@@ -211,6 +301,44 @@ mod tests {
         }
     }
 
+    #[test]
+    fn count() {
+        let configurations = [
+            configuration!(Little, BitWidth::Bit32, 9, [9, 0, 0, 0]),
+            configuration!(Big, BitWidth::Bit32, 9, [0, 0, 0, 9]),
+            configuration!(Little, BitWidth::Bit32, 0xFFFFFFFF, [0xFF, 0xFF, 0xFF, 0xFF]),
+            configuration!(Little, BitWidth::Bit64, 9, [9, 0, 0, 0, 0, 0, 0, 0]),
+            configuration!(Big, BitWidth::Bit64, 9, [0, 0, 0, 0, 0, 0, 0, 9]),
+        ];
+
+        for configuration in configurations {
+            let mut stream = ByteStream::new(configuration.bytes);
+            stream.set_format(configuration.format());
+
+            assert_eq!(stream.count(), Ok(configuration.expected));
+            assert!(stream.is_empty());
+        }
+    }
+
+    #[test]
+    fn size_t_not_sign_extended() {
+        let configurations = [
+            configuration!(Little, BitWidth::Bit32, 0x80000000, [0, 0, 0, 0x80]),
+            configuration!(Big, BitWidth::Bit32, 0x80000000, [0x80, 0, 0, 0]),
+            configuration!(Little, BitWidth::Bit32, 0xFFFFFFFF, [0xFF, 0xFF, 0xFF, 0xFF]),
+            configuration!(Big, BitWidth::Bit32, 0xFFFFFFFF, [0xFF, 0xFF, 0xFF, 0xFF]),
+            configuration!(Little, BitWidth::Bit64, u64::MAX, [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]),
+        ];
+
+        for configuration in configurations {
+            let mut stream = ByteStream::new(configuration.bytes);
+            stream.set_format(configuration.format());
+
+            assert_eq!(stream.size_t(), Ok(configuration.expected));
+            assert!(stream.is_empty());
+        }
+    }
+
     #[test]
     fn size_t() {
         let configurations = [
@@ -311,6 +439,33 @@ mod tests {
         assert!(stream.is_empty());
     }
 
+    #[test]
+    fn string_slice() {
+        let bytes = [3, 0, 0, 0, 0, 0, 0, 0, b'L', 0xFF, b'A'];
+        let mut stream = ByteStream::new(&bytes);
+        stream.set_format(TEST_FORMAT);
+        assert_eq!(stream.string_slice(), Ok([b'L', 0xFF, b'A'].as_slice()));
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn string_slice_huge_length() {
+        let mut stream = ByteStream::new(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, b'L']);
+        stream.set_format(TEST_FORMAT);
+        assert_eq!(stream.string_slice(), Err(LunifyError::InputTooShort));
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn slice_from() -> Result<(), LunifyError> {
+        let mut stream = ByteStream::new(&[7, 8, 9]);
+        stream.byte()?;
+        let start = stream.offset();
+        stream.slice(2)?;
+        assert_eq!(stream.slice_from(start), &[8, 9]);
+        Ok(())
+    }
+
     #[test]
     fn is_empty() {
         let stream = ByteStream::new(&[]);
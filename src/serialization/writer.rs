@@ -12,6 +12,12 @@ impl<'a> ByteWriter<'a> {
         Self { data, format }
     }
 
+    pub fn with_capacity(format: &'a Format, capacity: usize) -> Self {
+        let mut writer = Self::new(format);
+        writer.data.reserve(capacity);
+        writer
+    }
+
     pub fn byte(&mut self, byte: u8) {
         self.data.push(byte);
     }
@@ -20,29 +26,71 @@ impl<'a> ByteWriter<'a> {
         self.data.extend_from_slice(slice);
     }
 
-    pub fn integer(&mut self, value: i64) {
-        to_slice!(self, value, integer_width, i32)
+    pub fn integer(&mut self, value: i64) -> Result<(), LunifyError> {
+        check_width(value, i32::try_from(value).is_ok(), self.format.integer_width)?;
+        to_slice!(self, value, integer_width, i32, i64);
+        Ok(())
     }
 
-    pub fn size_t(&mut self, value: i64) {
-        to_slice!(self, value, size_t_width, i32)
+    pub fn integer_batch(&mut self, values: &[i64]) -> Result<(), LunifyError> {
+        for &value in values {
+            check_width(value, i32::try_from(value).is_ok(), self.format.integer_width)?;
+        }
+
+        to_slice_batch!(self, values, integer_width, i32, i64);
+        Ok(())
     }
 
+    pub fn count(&mut self, value: usize) -> Result<(), LunifyError> {
+        // Counts are read as signed integers, so they need to fit into an `i32` as well.
+        let value = value as u64;
+        check_width(value, i32::try_from(value).is_ok(), self.format.integer_width)?;
+        to_slice!(self, value, integer_width, u32, u64);
+        Ok(())
+    }
+
+    pub fn size_t(&mut self, value: u64) -> Result<(), LunifyError> {
+        check_width(value, u32::try_from(value).is_ok(), self.format.size_t_width)?;
+        to_slice!(self, value, size_t_width, u32, u64);
+        Ok(())
+    }
+
+    #[cfg(test)]
     pub fn instruction(&mut self, instruction: u64) {
-        to_slice!(self, instruction, instruction_width, u32)
+        to_slice!(self, instruction, instruction_width, u32, u64)
+    }
+
+    pub fn instruction_batch(&mut self, instructions: &[u64]) {
+        to_slice_batch!(self, instructions, instruction_width, u32, u64)
     }
 
     pub fn number(&mut self, value: Number) -> Result<(), LunifyError> {
         match self.format.is_number_integral {
-            true => to_slice!(self, value.as_integer()?, number_width, i32),
-            false => to_slice!(self, value.as_float()?, number_width, f32),
+            true => {
+                let value = value.as_integer()?;
+                check_width(value, i32::try_from(value).is_ok(), self.format.number_width)?;
+                to_slice!(self, value, number_width, i32, i64)
+            }
+            // Narrowing a float only loses precision, like compiling the source with
+            // `float` numbers would.
+            false => to_slice!(self, value.as_float()?, number_width, f32, f64),
         }
         Ok(())
     }
 
-    pub fn string(&mut self, value: &str) {
-        self.size_t(value.len() as i64);
-        self.slice(value.as_bytes());
+    pub fn string(&mut self, value: impl AsRef<[u8]>) -> Result<(), LunifyError> {
+        let value = value.as_ref();
+        self.size_t(value.len() as u64)?;
+        self.slice(value);
+        Ok(())
+    }
+
+    pub fn offset(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn format(&self) -> &Format {
+        self.format
     }
 
     pub fn finalize(self) -> Vec<u8> {
@@ -50,6 +98,15 @@ impl<'a> ByteWriter<'a> {
     }
 }
 
+/// Make sure a value fits into the width it is written with, instead of
+/// silently truncating it.
+fn check_width(value: impl Into<i128>, fits_32_bit: bool, width: BitWidth) -> Result<(), LunifyError> {
+    match width {
+        BitWidth::Bit32 if !fits_32_bit => Err(LunifyError::ValueTooBigForWidth { value: value.into(), width }),
+        _ => Ok(()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::ByteWriter;
@@ -110,6 +167,13 @@ mod tests {
         }
     }
 
+    #[test]
+    fn with_capacity() {
+        let writer = ByteWriter::with_capacity(&TEST_FORMAT, 64);
+        assert!(writer.data.is_empty());
+        assert!(writer.data.capacity() >= 64);
+    }
+
     #[test]
     fn byte() {
         let mut writer = ByteWriter::new(&TEST_FORMAT);
@@ -125,7 +189,45 @@ mod tests {
     }
 
     #[test]
-    fn integer() {
+    fn integer() -> Result<(), LunifyError> {
+        let configurations = [
+            configuration!(Little, BitWidth::Bit32, 9, [9, 0, 0, 0]),
+            configuration!(Big, BitWidth::Bit32, 9, [0, 0, 0, 9]),
+            configuration!(Little, BitWidth::Bit64, 9, [9, 0, 0, 0, 0, 0, 0, 0]),
+            configuration!(Big, BitWidth::Bit64, 9, [0, 0, 0, 0, 0, 0, 0, 9]),
+        ];
+
+        for configuration in configurations {
+            let format = configuration.format();
+            let mut writer = ByteWriter::new(&format);
+            writer.integer(configuration.value)?;
+            assert_eq!(writer.data, configuration.expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn integer_batch() -> Result<(), LunifyError> {
+        let configurations = [
+            configuration!(Little, BitWidth::Bit32, [7, 9], [7, 0, 0, 0, 9, 0, 0, 0]),
+            configuration!(Big, BitWidth::Bit32, [7, 9], [0, 0, 0, 7, 0, 0, 0, 9]),
+            configuration!(Little, BitWidth::Bit64, [7, 9], [7, 0, 0, 0, 0, 0, 0, 0, 9, 0, 0, 0, 0, 0, 0, 0]),
+            configuration!(Big, BitWidth::Bit64, [7, 9], [0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 9]),
+        ];
+
+        for configuration in configurations {
+            let format = configuration.format();
+            let mut writer = ByteWriter::new(&format);
+            writer.integer_batch(&configuration.value)?;
+            assert_eq!(writer.data, configuration.expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn count() -> Result<(), LunifyError> {
         let configurations = [
             configuration!(Little, BitWidth::Bit32, 9, [9, 0, 0, 0]),
             configuration!(Big, BitWidth::Bit32, 9, [0, 0, 0, 9]),
@@ -136,13 +238,15 @@ mod tests {
         for configuration in configurations {
             let format = configuration.format();
             let mut writer = ByteWriter::new(&format);
-            writer.integer(configuration.value);
+            writer.count(configuration.value)?;
             assert_eq!(writer.data, configuration.expected);
         }
+
+        Ok(())
     }
 
     #[test]
-    fn size_t() {
+    fn size_t() -> Result<(), LunifyError> {
         let configurations = [
             configuration!(Little, BitWidth::Bit32, 9, [9, 0, 0, 0]),
             configuration!(Big, BitWidth::Bit32, 9, [0, 0, 0, 9]),
@@ -153,9 +257,113 @@ mod tests {
         for configuration in configurations {
             let format = configuration.format();
             let mut writer = ByteWriter::new(&format);
-            writer.size_t(configuration.value);
+            writer.size_t(configuration.value)?;
             assert_eq!(writer.data, configuration.expected);
         }
+
+        Ok(())
+    }
+
+    #[test]
+    fn integer_too_big_for_width() {
+        let mut writer = ByteWriter::new(&TEST_FORMAT);
+
+        assert_eq!(writer.integer(i32::MAX as i64), Ok(()));
+        assert_eq!(
+            writer.integer(i32::MAX as i64 + 1),
+            Err(LunifyError::ValueTooBigForWidth {
+                value: 1 << 31,
+                width: BitWidth::Bit32,
+            })
+        );
+        assert_eq!(
+            writer.integer(i32::MIN as i64 - 1),
+            Err(LunifyError::ValueTooBigForWidth {
+                value: -(1 << 31) - 1,
+                width: BitWidth::Bit32,
+            })
+        );
+        assert_eq!(
+            writer.integer_batch(&[1, i32::MAX as i64 + 1]),
+            Err(LunifyError::ValueTooBigForWidth {
+                value: 1 << 31,
+                width: BitWidth::Bit32,
+            })
+        );
+
+        // Nothing is written for values that don't fit.
+        assert_eq!(writer.data, [255, 255, 255, 127]);
+    }
+
+    #[test]
+    fn count_too_big_for_width() {
+        let mut writer = ByteWriter::new(&TEST_FORMAT);
+
+        assert_eq!(writer.count(i32::MAX as usize), Ok(()));
+        assert_eq!(
+            writer.count(i32::MAX as usize + 1),
+            Err(LunifyError::ValueTooBigForWidth {
+                value: 1 << 31,
+                width: BitWidth::Bit32,
+            })
+        );
+        assert_eq!(writer.data, [255, 255, 255, 127]);
+    }
+
+    #[test]
+    fn size_t_too_big_for_width() {
+        let mut writer = ByteWriter::new(&TEST_FORMAT);
+
+        assert_eq!(writer.size_t(u32::MAX as u64), Ok(()));
+        assert_eq!(
+            writer.size_t(u32::MAX as u64 + 1),
+            Err(LunifyError::ValueTooBigForWidth {
+                value: 1 << 32,
+                width: BitWidth::Bit32,
+            })
+        );
+        assert_eq!(writer.data, [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn number_too_big_for_width() {
+        let format = Format {
+            is_number_integral: true,
+            ..TEST_FORMAT
+        };
+        let mut writer = ByteWriter::new(&format);
+
+        assert_eq!(
+            writer.number(Number::Integer(-(1 << 40))),
+            Err(LunifyError::ValueTooBigForWidth {
+                value: -(1 << 40),
+                width: BitWidth::Bit32,
+            })
+        );
+        assert_eq!(
+            writer.number(Number::Float(2f64.powi(40))),
+            Err(LunifyError::ValueTooBigForWidth {
+                value: 1 << 40,
+                width: BitWidth::Bit32,
+            })
+        );
+        assert!(writer.data.is_empty());
+    }
+
+    #[test]
+    fn wide_values_with_64_bit_width() -> Result<(), LunifyError> {
+        let format = Format {
+            integer_width: BitWidth::Bit64,
+            size_t_width: BitWidth::Bit64,
+            ..TEST_FORMAT
+        };
+        let mut writer = ByteWriter::new(&format);
+
+        writer.integer(i32::MAX as i64 + 1)?;
+        writer.count(i32::MAX as usize + 1)?;
+        writer.size_t(u32::MAX as u64 + 1)?;
+        assert_eq!(writer.offset(), 24);
+        Ok(())
     }
 
     #[test]
@@ -175,6 +383,25 @@ mod tests {
         }
     }
 
+    #[test]
+    fn instruction_batch() {
+        let configurations = [
+            configuration!(Little, BitWidth::Bit32, [7, 9], [1, 7, 0, 0, 0, 9, 0, 0, 0]),
+            configuration!(Big, BitWidth::Bit32, [7, 9], [1, 0, 0, 0, 7, 0, 0, 0, 9]),
+            configuration!(Little, BitWidth::Bit64, [7, 9], [1, 7, 0, 0, 0, 0, 0, 0, 0, 9, 0, 0, 0, 0, 0, 0, 0]),
+            configuration!(Big, BitWidth::Bit64, [7, 9], [1, 0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 9]),
+        ];
+
+        for configuration in configurations {
+            let format = configuration.format();
+            let mut writer = ByteWriter::new(&format);
+            // The batch is appended after data that is already written.
+            writer.byte(1);
+            writer.instruction_batch(&configuration.value);
+            assert_eq!(writer.data, configuration.expected);
+        }
+    }
+
     #[test]
     fn number() -> Result<(), LunifyError> {
         let configurations = [
@@ -201,10 +428,18 @@ mod tests {
     }
 
     #[test]
-    fn string() {
+    fn string() -> Result<(), LunifyError> {
         let mut writer = ByteWriter::new(&TEST_FORMAT);
-        writer.string("LUA");
+        writer.string("LUA")?;
         assert_eq!(writer.data, &[3, 0, 0, 0, b'L', b'U', b'A']);
+        Ok(())
+    }
+
+    #[test]
+    fn offset() {
+        let mut writer = ByteWriter::new(&TEST_FORMAT);
+        writer.slice(&[7, 8, 9]);
+        assert_eq!(writer.offset(), 3);
     }
 
     #[test]
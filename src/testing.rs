@@ -0,0 +1,178 @@
+//! Helpers for testing conversions against golden files, which hold the
+//! expected output of a conversion and are checked into the repository next to
+//! the tests.
+
+use std::path::Path;
+
+use crate::serialization::ByteWriter;
+use crate::{
+    input_settings, read_header, unify, validate_output, write_header, write_prefix, BitWidth, Endianness, Format, Function, LunifyError,
+    Settings,
+};
+
+/// Environment variable that makes [`assert_golden`] write the output to the
+/// golden file instead of comparing it. Any value other than `0` or an empty
+/// string enables it.
+pub const BLESS_VARIABLE: &str = "LUNIFY_BLESS";
+
+/// The output format of [`assert_golden`]. It is fixed instead of using
+/// [`Format::default`], so the golden files are the same on every host.
+pub const GOLDEN_FORMAT: Format = Format {
+    format: 0,
+    endianness: Endianness::Little,
+    integer_width: BitWidth::Bit32,
+    size_t_width: BitWidth::Bit64,
+    instruction_width: BitWidth::Bit32,
+    number_width: BitWidth::Bit64,
+    is_number_integral: false,
+};
+
+/// Convert the input to the [`GOLDEN_FORMAT`] and assert that the output is
+/// the same as the content of the golden file.
+///
+/// If the [`BLESS_VARIABLE`] is set, the golden file and its parent
+/// directories are created or overwritten with the output instead.
+///
+/// # Panics
+///
+/// Panics if the input can't be converted, the golden file can't be read or
+/// written, or the output differs from the golden file.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use lunify::testing::assert_golden;
+/// use lunify::Settings;
+///
+/// let input_bytes = include_bytes!("../test_files/lua50.luab");
+/// assert_golden(input_bytes, &Settings::default(), "test_files/golden/lua50.default.luab");
+/// ```
+#[track_caller]
+pub fn assert_golden(input_bytes: impl AsRef<[u8]>, settings: &Settings, golden_path: impl AsRef<Path>) {
+    let golden_path = golden_path.as_ref();
+    let output_bytes = match unify(input_bytes, &GOLDEN_FORMAT, settings) {
+        Ok(output_bytes) => output_bytes,
+        Err(error) => panic!("failed to convert the input for {}: {error:?}", golden_path.display()),
+    };
+
+    if is_blessing() {
+        if let Some(directory) = golden_path.parent() {
+            std::fs::create_dir_all(directory).unwrap_or_else(|error| panic!("failed to create {}: {error}", directory.display()));
+        }
+
+        std::fs::write(golden_path, &output_bytes).unwrap_or_else(|error| panic!("failed to write {}: {error}", golden_path.display()));
+        return;
+    }
+
+    let golden_bytes = std::fs::read(golden_path).unwrap_or_else(|error| {
+        panic!(
+            "failed to read {}: {error}, run the test with {BLESS_VARIABLE}=1 to create it",
+            golden_path.display()
+        )
+    });
+
+    if output_bytes != golden_bytes {
+        let offset = output_bytes
+            .iter()
+            .zip(&golden_bytes)
+            .position(|(output, golden)| output != golden)
+            .unwrap_or(output_bytes.len().min(golden_bytes.len()));
+
+        panic!(
+            "output differs from {} at byte {offset} (output has {} bytes, golden file has {} bytes), run the test with {BLESS_VARIABLE}=1 \
+             to update it",
+            golden_path.display(),
+            output_bytes.len(),
+            golden_bytes.len()
+        );
+    }
+}
+
+/// Decode and convert the input like [`unify`], but stop before writing the
+/// output. The returned closure writes the output, so the time spent writing
+/// can be measured on its own.
+///
+/// # Example
+///
+/// ```rust
+/// use lunify::testing::prepare_write;
+/// use lunify::{unify, Format, LunifyError, Settings};
+///
+/// # fn main() -> Result<(), LunifyError> {
+/// let input_bytes = include_bytes!("../test_files/lua50.luab");
+/// let output_format = Format::default();
+/// let write = prepare_write(input_bytes, &output_format, &Settings::default())?;
+///
+/// assert_eq!(write()?, unify(input_bytes, &output_format, &Settings::default())?);
+/// # Ok(())
+/// # }
+/// ```
+pub fn prepare_write<'a>(
+    input_bytes: &'a [u8],
+    output_format: &'a Format,
+    settings: &Settings<'a>,
+) -> Result<impl FnOnce() -> Result<Vec<u8>, LunifyError> + 'a, LunifyError> {
+    validate_output(output_format, &settings.output)?;
+
+    let (mut byte_stream, version, _) = read_header(input_bytes, settings)?;
+    let settings = input_settings(input_bytes, version, settings)?;
+    let function = Function::from_byte_stream(&mut byte_stream, version, &settings)?;
+
+    Ok(move || {
+        let mut byte_writer = ByteWriter::with_capacity(output_format, input_bytes.len());
+        write_prefix(&mut byte_writer, input_bytes, &settings);
+        write_header(&mut byte_writer, output_format, &settings);
+        function.write(&mut byte_writer, &mut Vec::new())?;
+        Ok(byte_writer.finalize())
+    })
+}
+
+fn is_blessing() -> bool {
+    std::env::var_os(BLESS_VARIABLE).is_some_and(|value| !value.is_empty() && value != "0")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::assert_golden;
+    use crate::{lua51, Settings};
+
+    const INPUTS: [(&str, &[u8]); 10] = [
+        ("32bit", include_bytes!("../test_files/32bit.luab")),
+        ("big_endian", include_bytes!("../test_files/big_endian.luab")),
+        ("constants", include_bytes!("../test_files/constants.luab")),
+        ("dynamic_table", include_bytes!("../test_files/dynamic_table.luab")),
+        ("empty", include_bytes!("../test_files/empty.luab")),
+        ("for_loop", include_bytes!("../test_files/for_loop.luab")),
+        ("large_table", include_bytes!("../test_files/large_table.luab")),
+        ("little_endian", include_bytes!("../test_files/little_endian.luab")),
+        ("lua50", include_bytes!("../test_files/lua50.luab")),
+        ("variadic", include_bytes!("../test_files/variadic.luab")),
+    ];
+
+    fn assert_goldens(preset: &str, settings: &Settings) {
+        for (name, input_bytes) in INPUTS {
+            let golden_path = format!("{}/test_files/golden/{name}.{preset}.luab", env!("CARGO_MANIFEST_DIR"));
+            assert_golden(input_bytes, settings, golden_path);
+        }
+    }
+
+    #[test]
+    fn default_goldens() {
+        assert_goldens("default", &Settings::default());
+    }
+
+    #[test]
+    fn optimized_goldens() {
+        let settings = Settings {
+            output: lua51::Settings {
+                peephole: true,
+                fold_constants: true,
+                canonicalize_constants: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert_goldens("optimized", &settings);
+    }
+}